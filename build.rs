@@ -1,14 +1,25 @@
 use bootloader::DiskImageBuilder;
-use std::{env, path::PathBuf};
+use std::{env, fs, path::PathBuf};
+
+#[path = "src/cpio.rs"]
+mod cpio;
 
 fn main() {
     let kernel_path = env::var("CARGO_BIN_FILE_KERNEL").unwrap();
-    let disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
+    let mut disk_builder = DiskImageBuilder::new(PathBuf::from(kernel_path));
 
     let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
     let uefi_path = out_dir.join("os-uefi.img");
     let bios_path = out_dir.join("os-bios.img");
 
+    // the kernel unpacks this into its root filesystem at boot
+    let mut initramfs = cpio::CpioWriter::default();
+    initramfs.add_tree("initramfs".as_ref(), "").unwrap();
+    let initramfs_path = out_dir.join("initramfs.cpio");
+    fs::write(&initramfs_path, initramfs.finish()).unwrap();
+    disk_builder.set_ramdisk(initramfs_path);
+    println!("cargo:rerun-if-changed=initramfs");
+
     disk_builder.create_uefi_image(&uefi_path).unwrap();
     disk_builder.create_bios_image(&bios_path).unwrap();
 
@@ -0,0 +1,154 @@
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader_api::BootInfo;
+
+use crate::memory;
+
+// Just enough ACPI to find static tables: the RSDP handed over by the
+// bootloader points at the RSDT (32-bit entries) or, from revision 2 on, the
+// XSDT (64-bit entries), which list every other table.
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+
+/// Physical address of the RSDT or XSDT, or 0 without ACPI.
+static ROOT_TABLE: AtomicU64 = AtomicU64::new(0);
+/// Size of one root table entry: 4 for the RSDT, 8 for the XSDT.
+static ROOT_ENTRY_SIZE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    BadSignature,
+    BadChecksum,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => write!(f, "bootloader found no RSDP"),
+            AcpiError::BadSignature => write!(f, "invalid RSDP signature"),
+            AcpiError::BadChecksum => write!(f, "RSDP checksum mismatch"),
+        }
+    }
+}
+
+/// Header shared by every system description table.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+pub const SDT_HEADER_LEN: usize = size_of::<SdtHeader>();
+
+/// A table found through the RSDT/XSDT.
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub phys: u64,
+    pub header: SdtHeader,
+}
+
+impl Table {
+    unsafe fn at(phys: u64) -> Table {
+        let header = unsafe { (memory::phys_to_virt(phys) as *const SdtHeader).read_unaligned() };
+        Table { phys, header }
+    }
+
+    pub fn signature(&self) -> &str {
+        core::str::from_utf8(&self.header.signature).unwrap_or("????")
+    }
+
+    /// The whole table, header included.
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe {
+            core::slice::from_raw_parts(
+                memory::phys_to_virt(self.phys),
+                self.header.length as usize,
+            )
+        }
+    }
+
+    /// The table contents after the header.
+    pub fn body(&self) -> &'static [u8] {
+        &self.bytes()[SDT_HEADER_LEN..]
+    }
+
+    pub fn is_valid(&self) -> bool {
+        checksum(self.bytes()) == 0
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Locates the root table through the RSDP the bootloader found.
+pub fn init(boot_info: &BootInfo) -> Result<(), AcpiError> {
+    let rsdp = boot_info.rsdp_addr.into_option().ok_or(AcpiError::NoRsdp)?;
+    let bytes = unsafe { core::slice::from_raw_parts(memory::phys_to_virt(rsdp), RSDP_V2_LEN) };
+    if &bytes[..8] != RSDP_SIGNATURE {
+        return Err(AcpiError::BadSignature);
+    }
+    if checksum(&bytes[..RSDP_V1_LEN]) != 0 {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    let revision = bytes[15];
+    let rsdt = u32::from_le_bytes(bytes[16..20].try_into().unwrap()) as u64;
+    let xsdt = u64::from_le_bytes(bytes[24..32].try_into().unwrap());
+    let (root, entry_size) = if revision >= 2 && xsdt != 0 && checksum(bytes) == 0 {
+        (xsdt, 8)
+    } else {
+        (rsdt, 4)
+    };
+    ROOT_TABLE.store(root, Ordering::Relaxed);
+    ROOT_ENTRY_SIZE.store(entry_size, Ordering::Relaxed);
+
+    for table in tables() {
+        log::info!(
+            "ACPI: {} at {:#x}, {} bytes, rev {}{}",
+            table.signature(),
+            table.phys,
+            { table.header.length },
+            table.header.revision,
+            if table.is_valid() {
+                ""
+            } else {
+                ", bad checksum"
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Every table listed in the root table, valid or not.
+pub fn tables() -> impl Iterator<Item = Table> {
+    let (entries, entry_size) = match ROOT_TABLE.load(Ordering::Relaxed) {
+        0 => (&[][..], 8),
+        root => (
+            unsafe { Table::at(root) }.body(),
+            ROOT_ENTRY_SIZE.load(Ordering::Relaxed) as usize,
+        ),
+    };
+    entries.chunks_exact(entry_size).map(|entry| {
+        let mut phys = [0; 8];
+        phys[..entry.len()].copy_from_slice(entry);
+        unsafe { Table::at(u64::from_le_bytes(phys)) }
+    })
+}
+
+/// First table with a matching signature and a valid checksum.
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    tables().find(|table| &table.header.signature == signature && table.is_valid())
+}
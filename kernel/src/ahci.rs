@@ -0,0 +1,896 @@
+use core::fmt;
+use core::time::Duration;
+
+use crate::ata::{ata_string, trimmed};
+use crate::block::{self, BlockDevice, BlockError, Bounce};
+use crate::memory::{self, FRAME_SIZE};
+use crate::msi::{self, MsiVectors};
+use crate::pci::{self, PciDevice};
+use crate::sync::{self, IrqSpinLock};
+use crate::time;
+
+// AHCI puts each SATA port behind a small DMA engine. Commands are built in
+// memory: a command list of 32 headers per port, each pointing at a command
+// table holding the FIS to send and a PRDT scattering the data. Setting a
+// bit in PxCI issues the matching slot; the HBA clears it when done and
+// copies the device's FISes into the port's receive area. NCQ commands
+// additionally carry their slot as tag and are tracked through PxSACT.
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// Programming interface of AHCI 1.x controllers.
+const PROG_IF_AHCI: u8 = 0x01;
+
+mod hba {
+    pub const CAP: usize = 0x00;
+    pub const GHC: usize = 0x04;
+    pub const IS: usize = 0x08;
+    pub const PI: usize = 0x0C;
+    pub const VS: usize = 0x10;
+    pub const CAP2: usize = 0x24;
+    pub const BOHC: usize = 0x28;
+    pub const PORTS: usize = 0x100;
+    pub const PORT_SIZE: usize = 0x80;
+
+    pub const CAP_NCS_SHIFT: u32 = 8;
+    pub const CAP_SSS: u32 = 1 << 27;
+    pub const CAP_SNCQ: u32 = 1 << 30;
+    pub const CAP_S64A: u32 = 1 << 31;
+    pub const GHC_HR: u32 = 1 << 0;
+    pub const GHC_IE: u32 = 1 << 1;
+    pub const GHC_AE: u32 = 1 << 31;
+    pub const CAP2_BOH: u32 = 1 << 0;
+    pub const BOHC_BOS: u32 = 1 << 0;
+    pub const BOHC_OOS: u32 = 1 << 1;
+}
+
+mod port {
+    pub const CLB: usize = 0x00;
+    pub const CLBU: usize = 0x04;
+    pub const FB: usize = 0x08;
+    pub const FBU: usize = 0x0C;
+    pub const IS: usize = 0x10;
+    pub const IE: usize = 0x14;
+    pub const CMD: usize = 0x18;
+    pub const TFD: usize = 0x20;
+    pub const SIG: usize = 0x24;
+    pub const SSTS: usize = 0x28;
+    pub const SCTL: usize = 0x2C;
+    pub const SERR: usize = 0x30;
+    pub const SACT: usize = 0x34;
+    pub const CI: usize = 0x38;
+
+    pub const CMD_ST: u32 = 1 << 0;
+    pub const CMD_SUD: u32 = 1 << 1;
+    pub const CMD_POD: u32 = 1 << 2;
+    pub const CMD_FRE: u32 = 1 << 4;
+    pub const CMD_FR: u32 = 1 << 14;
+    pub const CMD_CR: u32 = 1 << 15;
+
+    pub const IS_DHRS: u32 = 1 << 0;
+    pub const IS_PSS: u32 = 1 << 1;
+    pub const IS_SDBS: u32 = 1 << 3;
+    pub const IS_TFES: u32 = 1 << 30;
+
+    pub const TFD_DRQ: u32 = 1 << 3;
+    pub const TFD_BSY: u32 = 1 << 7;
+
+    pub const SSTS_DET_MASK: u32 = 0xF;
+    pub const SSTS_DET_PRESENT: u32 = 3;
+
+    pub const SCTL_DET_MASK: u32 = 0xF;
+    /// Holding DET at 1 sends COMRESET down the link.
+    pub const SCTL_DET_INIT: u32 = 1;
+
+    pub const SIG_ATA: u32 = 0x0000_0101;
+}
+
+mod ata_command {
+    pub const READ_DMA_EXT: u8 = 0x25;
+    pub const WRITE_DMA_EXT: u8 = 0x35;
+    pub const READ_FPDMA_QUEUED: u8 = 0x60;
+    pub const WRITE_FPDMA_QUEUED: u8 = 0x61;
+    pub const FLUSH_CACHE_EXT: u8 = 0xEA;
+    pub const IDENTIFY: u8 = 0xEC;
+}
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+/// H2D FIS flag: the FIS carries a command, not a control update.
+const FIS_COMMAND: u8 = 1 << 7;
+const DEVICE_LBA: u8 = 1 << 6;
+
+const COMMAND_HEADER_SIZE: usize = 32;
+const COMMAND_LIST_SIZE: usize = 32 * COMMAND_HEADER_SIZE;
+/// Command FIS, ATAPI command and reserved area before the PRDT.
+const COMMAND_TABLE_HEADER: usize = 0x80;
+const PRDT_ENTRIES: usize = 8;
+const PRDT_ENTRY_SIZE: usize = 16;
+const COMMAND_TABLE_SIZE: usize = COMMAND_TABLE_HEADER + PRDT_ENTRIES * PRDT_ENTRY_SIZE;
+/// Largest byte count of one PRDT entry.
+const PRD_MAX: u64 = 4 << 20;
+/// Command FIS length in dwords, as the command header wants it.
+const H2D_FIS_DWORDS: u32 = 5;
+const HEADER_WRITE: u32 = 1 << 6;
+const HEADER_PRDTL_SHIFT: u32 = 16;
+
+const MAX_PORTS: usize = 32;
+/// Sectors one non-NCQ EXT command transfers at most.
+const MAX_SECTORS: usize = 65536;
+/// Largest transfer the blocking helpers put into one command.
+const MAX_TRANSFER: usize = 64 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciError {
+    /// Task file error; carries PxTFD.
+    Device(u32),
+    Timeout,
+    OutOfRange,
+    /// All command slots are taken, or NCQ commands are still running.
+    Busy,
+    /// The buffer needs more PRDT entries than a command table has.
+    TooFragmented,
+    OutOfMemory,
+}
+
+impl fmt::Display for AhciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AhciError::Device(tfd) => write!(
+                f,
+                "device error, status {:#04x} error {:#04x}",
+                tfd & 0xFF,
+                (tfd >> 8) & 0xFF
+            ),
+            AhciError::Timeout => write!(f, "command timed out"),
+            AhciError::OutOfRange => write!(f, "sector out of range"),
+            AhciError::Busy => write!(f, "no free command slot"),
+            AhciError::TooFragmented => write!(f, "too many PRDT entries"),
+            AhciError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+/// A request on a physically contiguous buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AhciOp {
+    Read { lba: u64, phys: u64, len: u32 },
+    Write { lba: u64, phys: u64, len: u32 },
+    Flush,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SataInfo {
+    pub port: usize,
+    pub model: [u8; 40],
+    pub serial: [u8; 20],
+    pub sectors: u64,
+    /// Commands the drive queues, or 0 without NCQ.
+    pub ncq_depth: usize,
+}
+
+impl SataInfo {
+    pub fn model(&self) -> &str {
+        trimmed(&self.model)
+    }
+
+    pub fn serial(&self) -> &str {
+        trimmed(&self.serial)
+    }
+}
+
+impl fmt::Display for SataInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}), {} sectors ({} MiB)",
+            self.model(),
+            self.serial(),
+            self.sectors,
+            (self.sectors * SECTOR_SIZE as u64) >> 20
+        )?;
+        match self.ncq_depth {
+            0 => write!(f, ", no NCQ"),
+            depth => write!(f, ", NCQ depth {}", depth),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registers {
+    base: *mut u8,
+}
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { self.base.add(offset).cast::<u32>().read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { self.base.add(offset).cast::<u32>().write_volatile(value) }
+    }
+
+    fn port(&self, index: usize) -> Registers {
+        Registers {
+            base: unsafe { self.base.add(hba::PORTS + index * hba::PORT_SIZE) },
+        }
+    }
+
+    /// Waits until `(register & mask) == value`.
+    fn wait(&self, offset: usize, mask: u32, value: u32, timeout: Duration) -> bool {
+        let deadline = time::uptime() + timeout;
+        while self.read(offset) & mask != value {
+            if time::uptime() > deadline {
+                return false;
+            }
+            core::hint::spin_loop();
+        }
+        true
+    }
+}
+
+struct Port {
+    regs: Registers,
+    info: SataInfo,
+    slots: usize,
+    /// Command list and received FIS area in one frame.
+    list_phys: u64,
+    /// Command tables of all slots.
+    tables_phys: u64,
+    /// Slots issued and not yet collected.
+    active: u32,
+    /// Active slots issued as NCQ commands.
+    queued: u32,
+    /// Finished slots waiting for their result to be collected.
+    done: u32,
+    failed: u32,
+    /// PxTFD of the last error.
+    last_error: u32,
+}
+
+// The register pointer is MMIO only the owning lock touches.
+unsafe impl Send for Registers {}
+
+static HBA: IrqSpinLock<Option<Registers>> = IrqSpinLock::new(None);
+static PORTS: [IrqSpinLock<Option<Port>>; MAX_PORTS] =
+    [const { IrqSpinLock::new(None) }; MAX_PORTS];
+/// Keeps the controller's MSI vector alive; `None` means polling.
+static VECTORS: IrqSpinLock<Option<MsiVectors>> = IrqSpinLock::new(None);
+
+impl Port {
+    fn stop(&self) -> bool {
+        let cmd = self.regs.read(port::CMD);
+        self.regs.write(port::CMD, cmd & !port::CMD_ST);
+        let stopped = self
+            .regs
+            .wait(port::CMD, port::CMD_CR, 0, Duration::from_millis(500));
+        let cmd = self.regs.read(port::CMD);
+        self.regs.write(port::CMD, cmd & !port::CMD_FRE);
+        stopped
+            && self
+                .regs
+                .wait(port::CMD, port::CMD_FR, 0, Duration::from_millis(500))
+    }
+
+    /// Resets the link, which also stops a port that ignored [`Port::stop`].
+    fn comreset(&self) {
+        let sctl = self.regs.read(port::SCTL) & !port::SCTL_DET_MASK;
+        self.regs.write(port::SCTL, sctl | port::SCTL_DET_INIT);
+        // COMRESET has to be held for at least 1ms
+        time::delay(Duration::from_millis(1));
+        self.regs.write(port::SCTL, sctl);
+        self.regs.wait(
+            port::SSTS,
+            port::SSTS_DET_MASK,
+            port::SSTS_DET_PRESENT,
+            Duration::from_secs(1),
+        );
+        self.regs.write(port::SERR, u32::MAX);
+        self.regs
+            .wait(port::CMD, port::CMD_CR, 0, Duration::from_millis(500));
+    }
+
+    fn start(&self) {
+        self.regs.wait(
+            port::TFD,
+            port::TFD_BSY | port::TFD_DRQ,
+            0,
+            Duration::from_secs(1),
+        );
+        let cmd = self.regs.read(port::CMD);
+        self.regs
+            .write(port::CMD, cmd | port::CMD_FRE | port::CMD_ST);
+    }
+
+    fn header(&self, slot: usize) -> *mut u32 {
+        memory::phys_to_virt(self.list_phys + (slot * COMMAND_HEADER_SIZE) as u64).cast()
+    }
+
+    fn table_phys(&self, slot: usize) -> u64 {
+        self.tables_phys + (slot * COMMAND_TABLE_SIZE) as u64
+    }
+
+    /// Builds the command table and header of `slot`.
+    fn build(
+        &self,
+        slot: usize,
+        fis: [u8; 20],
+        data: Option<(u64, u32)>,
+        write: bool,
+    ) -> Result<(), AhciError> {
+        let table = memory::phys_to_virt(self.table_phys(slot));
+        let mut entries = 0;
+        unsafe {
+            table.write_bytes(0, COMMAND_TABLE_SIZE);
+            table.copy_from_nonoverlapping(fis.as_ptr(), fis.len());
+            if let Some((phys, len)) = data {
+                let end = phys + len as u64;
+                let mut start = phys;
+                while start < end {
+                    if entries == PRDT_ENTRIES {
+                        return Err(AhciError::TooFragmented);
+                    }
+                    let chunk = (end - start).min(PRD_MAX);
+                    let entry = table
+                        .add(COMMAND_TABLE_HEADER + entries * PRDT_ENTRY_SIZE)
+                        .cast::<u32>();
+                    entry.write_volatile(start as u32);
+                    entry.add(1).write_volatile((start >> 32) as u32);
+                    entry.add(3).write_volatile(chunk as u32 - 1);
+                    start += chunk;
+                    entries += 1;
+                }
+            }
+            let header = self.header(slot);
+            let mut flags = H2D_FIS_DWORDS | (entries as u32) << HEADER_PRDTL_SHIFT;
+            if write {
+                flags |= HEADER_WRITE;
+            }
+            header.write_volatile(flags);
+            header.add(1).write_volatile(0);
+            header.add(2).write_volatile(self.table_phys(slot) as u32);
+            header
+                .add(3)
+                .write_volatile((self.table_phys(slot) >> 32) as u32);
+        }
+        Ok(())
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        let busy = self.active | self.done;
+        (0..self.slots).find(|slot| busy & (1 << slot) == 0)
+    }
+
+    fn submit(&mut self, op: AhciOp) -> Result<usize, AhciError> {
+        let (lba, data, write) = match op {
+            AhciOp::Read { lba, phys, len } => (lba, Some((phys, len)), false),
+            AhciOp::Write { lba, phys, len } => (lba, Some((phys, len)), true),
+            AhciOp::Flush => (0, None, false),
+        };
+        let sectors = data.map_or(0, |(_, len)| len as usize / SECTOR_SIZE);
+        if let Some((_, len)) = data
+            && (!(len as usize).is_multiple_of(SECTOR_SIZE)
+                || sectors == 0
+                || sectors > MAX_SECTORS
+                || lba + sectors as u64 > self.info.sectors)
+        {
+            return Err(AhciError::OutOfRange);
+        }
+
+        // NCQ and non-queued commands must not be mixed on the link
+        let ncq = self.info.ncq_depth > 0 && data.is_some();
+        if (ncq && self.active & !self.queued != 0) || (!ncq && self.queued != 0) {
+            return Err(AhciError::Busy);
+        }
+        let slot = self
+            .free_slot()
+            .filter(|slot| !ncq || *slot < self.info.ncq_depth)
+            .ok_or(AhciError::Busy)?;
+
+        let command = match (op, ncq) {
+            (AhciOp::Read { .. }, true) => ata_command::READ_FPDMA_QUEUED,
+            (AhciOp::Write { .. }, true) => ata_command::WRITE_FPDMA_QUEUED,
+            (AhciOp::Read { .. }, false) => ata_command::READ_DMA_EXT,
+            (AhciOp::Write { .. }, false) => ata_command::WRITE_DMA_EXT,
+            (AhciOp::Flush, _) => ata_command::FLUSH_CACHE_EXT,
+        };
+        let mut fis = h2d_fis(command, lba, sectors as u16);
+        if ncq {
+            // the sector count moves to the feature fields, the tag takes
+            // its place
+            fis[3] = sectors as u8;
+            fis[11] = (sectors >> 8) as u8;
+            fis[12] = (slot as u8) << 3;
+            fis[13] = 0;
+        }
+        self.build(slot, fis, data, write)?;
+
+        let bit = 1 << slot;
+        self.active |= bit;
+        if ncq {
+            self.queued |= bit;
+            self.regs.write(port::SACT, bit);
+        }
+        self.regs.write(port::CI, bit);
+        Ok(slot)
+    }
+
+    /// Moves slots the HBA has finished from `active` to `done`.
+    fn update(&mut self) {
+        let status = self.regs.read(port::IS);
+        self.regs.write(port::IS, status);
+        if status & port::IS_TFES != 0 {
+            self.recover();
+            return;
+        }
+        let running = self.regs.read(port::CI) | self.regs.read(port::SACT);
+        let finished = self.active & !running;
+        self.active &= !finished;
+        self.queued &= !finished;
+        self.done |= finished;
+    }
+
+    /// After a task file error the port halts; everything outstanding is
+    /// failed and the port restarted.
+    fn recover(&mut self) {
+        self.last_error = self.regs.read(port::TFD);
+        self.restart();
+    }
+
+    /// Gives up on `slot` after it timed out and frees it. Anything else
+    /// outstanding fails along with it: stopping the port is the only way
+    /// to make sure the HBA no longer touches the command's buffer.
+    fn abort(&mut self, slot: usize) {
+        self.last_error = self.regs.read(port::TFD);
+        self.restart();
+        let bit = 1 << slot;
+        self.done &= !bit;
+        self.failed &= !bit;
+    }
+
+    /// Stops the port, which clears CI and SACT, fails whatever was
+    /// outstanding and starts it again.
+    fn restart(&mut self) {
+        if !self.stop() {
+            self.comreset();
+        }
+        self.regs.write(port::SERR, u32::MAX);
+        self.regs.write(port::IS, u32::MAX);
+        self.start();
+        self.failed |= self.active;
+        self.done |= self.active;
+        self.active = 0;
+        self.queued = 0;
+    }
+
+    /// Takes the result of a finished slot.
+    fn collect(&mut self, slot: usize) -> Option<Result<(), AhciError>> {
+        let bit = 1 << slot;
+        if self.done & bit == 0 {
+            return None;
+        }
+        self.done &= !bit;
+        if self.failed & bit != 0 {
+            self.failed &= !bit;
+            return Some(Err(AhciError::Device(self.last_error)));
+        }
+        Some(Ok(()))
+    }
+
+    fn identify(&mut self, index: usize) -> Result<SataInfo, AhciError> {
+        let buffer = memory::allocate_frame().ok_or(AhciError::OutOfMemory)?;
+        let fis = h2d_fis(ata_command::IDENTIFY, 0, 0);
+        let result = self.build(0, fis, Some((buffer, SECTOR_SIZE as u32)), false);
+        let result = result.and_then(|()| {
+            self.active |= 1;
+            self.regs.write(port::CI, 1);
+            let deadline = time::uptime() + TIMEOUT;
+            loop {
+                self.update();
+                if let Some(result) = self.collect(0) {
+                    break result;
+                }
+                if time::uptime() > deadline {
+                    break Err(AhciError::Timeout);
+                }
+                core::hint::spin_loop();
+            }
+        });
+        let mut words = [0u16; 256];
+        let bytes = unsafe { memory::phys_slice_mut(buffer, SECTOR_SIZE) };
+        for (word, pair) in words.iter_mut().zip(bytes.as_chunks::<2>().0) {
+            *word = u16::from_le_bytes(*pair);
+        }
+        memory::free_frames(buffer, 1);
+        result?;
+
+        let sectors = (0..4).fold(0, |sum, i| sum | (words[100 + i] as u64) << (16 * i));
+        let ncq = words[76] & (1 << 8) != 0;
+        Ok(SataInfo {
+            port: index,
+            model: ata_string(&words[27..47]),
+            serial: ata_string(&words[10..20]),
+            sectors,
+            ncq_depth: match ncq {
+                true => ((words[75] & 0x1F) as usize + 1).min(self.slots),
+                false => 0,
+            },
+        })
+    }
+}
+
+fn h2d_fis(command: u8, lba: u64, count: u16) -> [u8; 20] {
+    let mut fis = [0; 20];
+    fis[0] = FIS_TYPE_REG_H2D;
+    fis[1] = FIS_COMMAND;
+    fis[2] = command;
+    fis[4] = lba as u8;
+    fis[5] = (lba >> 8) as u8;
+    fis[6] = (lba >> 16) as u8;
+    fis[7] = DEVICE_LBA;
+    fis[8] = (lba >> 24) as u8;
+    fis[9] = (lba >> 32) as u8;
+    fis[10] = (lba >> 40) as u8;
+    fis[12] = count as u8;
+    fis[13] = (count >> 8) as u8;
+    fis
+}
+
+fn handle_interrupt(_: usize) {
+    for port in PORTS.iter() {
+        if let Some(port) = port.lock().as_mut() {
+            port.update();
+        }
+    }
+    // port bits in the HBA status are cleared after the ports' own
+    if let Some(regs) = *HBA.lock() {
+        let status = regs.read(hba::IS);
+        regs.write(hba::IS, status);
+    }
+}
+
+fn init_controller(controller: PciDevice) -> Option<Registers> {
+    let bar = controller.bar(5)?;
+    let abar = memory::map_mmio(bar.memory_base()?, bar.size())?;
+    controller
+        .address
+        .enable(pci::command::MEMORY_SPACE | pci::command::BUS_MASTER);
+    let regs = Registers { base: abar };
+
+    // take the controller over from the firmware
+    if regs.read(hba::CAP2) & hba::CAP2_BOH != 0 {
+        regs.write(hba::BOHC, regs.read(hba::BOHC) | hba::BOHC_OOS);
+        regs.wait(hba::BOHC, hba::BOHC_BOS, 0, Duration::from_secs(2));
+    }
+    regs.write(hba::GHC, hba::GHC_AE);
+    regs.write(hba::GHC, hba::GHC_AE | hba::GHC_HR);
+    if !regs.wait(hba::GHC, hba::GHC_HR, 0, Duration::from_secs(1)) {
+        log::warn!("AHCI {}: reset timed out", controller.address);
+        return None;
+    }
+    regs.write(hba::GHC, hba::GHC_AE);
+    let cap = regs.read(hba::CAP);
+    if cap & hba::CAP_S64A == 0 && memory::total_memory() > (1 << 32) {
+        log::warn!("AHCI {}: 32-bit DMA only", controller.address);
+    }
+    let version = regs.read(hba::VS);
+    log::info!(
+        "AHCI {}: version {}.{}, {} slots, ports {:#x}{}",
+        controller.address,
+        version >> 16,
+        (version >> 8) & 0xFF,
+        ((cap >> hba::CAP_NCS_SHIFT) & 0x1F) + 1,
+        regs.read(hba::PI),
+        if cap & hba::CAP_SNCQ != 0 {
+            ", NCQ"
+        } else {
+            ""
+        }
+    );
+    Some(regs)
+}
+
+fn init_port(regs: Registers, index: usize, cap: u32) -> Result<Option<Port>, AhciError> {
+    let port_regs = regs.port(index);
+    let slots = ((cap >> hba::CAP_NCS_SHIFT) & 0x1F) as usize + 1;
+    let list_phys = memory::allocate_frame().ok_or(AhciError::OutOfMemory)?;
+    let table_frames = (slots * COMMAND_TABLE_SIZE).div_ceil(FRAME_SIZE as usize);
+    let Some(tables_phys) = memory::allocate_frames(table_frames) else {
+        memory::free_frames(list_phys, 1);
+        return Err(AhciError::OutOfMemory);
+    };
+    let mut port = Port {
+        regs: port_regs,
+        info: SataInfo {
+            port: index,
+            model: [0; 40],
+            serial: [0; 20],
+            sectors: 0,
+            ncq_depth: 0,
+        },
+        slots,
+        list_phys,
+        tables_phys,
+        active: 0,
+        queued: 0,
+        done: 0,
+        failed: 0,
+        last_error: 0,
+    };
+    let release = |port: Port| {
+        memory::free_frames(port.list_phys, 1);
+        memory::free_frames(port.tables_phys, table_frames);
+    };
+
+    if !port.stop() {
+        release(port);
+        return Err(AhciError::Timeout);
+    }
+    let fis_phys = list_phys + COMMAND_LIST_SIZE as u64;
+    port_regs.write(port::CLB, list_phys as u32);
+    port_regs.write(port::CLBU, (list_phys >> 32) as u32);
+    port_regs.write(port::FB, fis_phys as u32);
+    port_regs.write(port::FBU, (fis_phys >> 32) as u32);
+    port_regs.write(port::SERR, u32::MAX);
+    port_regs.write(port::IS, u32::MAX);
+    if cap & hba::CAP_SSS != 0 {
+        let cmd = port_regs.read(port::CMD);
+        port_regs.write(port::CMD, cmd | port::CMD_SUD | port::CMD_POD);
+    }
+    port_regs.write(port::CMD, port_regs.read(port::CMD) | port::CMD_FRE);
+
+    // wait for the link, then for the drive's signature FIS
+    let linked = port_regs.wait(
+        port::SSTS,
+        port::SSTS_DET_MASK,
+        port::SSTS_DET_PRESENT,
+        Duration::from_millis(50),
+    );
+    port_regs.wait(port::TFD, port::TFD_BSY, 0, Duration::from_secs(1));
+    if !linked || port_regs.read(port::SIG) != port::SIG_ATA {
+        port.stop();
+        release(port);
+        return Ok(None);
+    }
+    port_regs.write(port::SERR, u32::MAX);
+    port.start();
+
+    match port.identify(index) {
+        Ok(info) => {
+            port.info = info;
+            port_regs.write(
+                port::IE,
+                port::IS_DHRS | port::IS_PSS | port::IS_SDBS | port::IS_TFES,
+            );
+            Ok(Some(port))
+        }
+        Err(err) => {
+            port.stop();
+            release(port);
+            Err(err)
+        }
+    }
+}
+
+/// Finds the first AHCI controller and brings up every port with an ATA
+/// drive behind it.
+pub fn init() {
+    let Some(controller) = pci::find_class(
+        pci::class::MASS_STORAGE,
+        pci::subclass::SATA,
+        Some(PROG_IF_AHCI),
+    )
+    .next() else {
+        return;
+    };
+    let Some(regs) = init_controller(controller) else {
+        return;
+    };
+    *HBA.lock() = Some(regs);
+    let cap = regs.read(hba::CAP);
+    let implemented = regs.read(hba::PI);
+    for index in (0..MAX_PORTS).filter(|index| implemented & (1 << index) != 0) {
+        match init_port(regs, index, cap) {
+            Ok(Some(port)) => {
+                log::info!("AHCI port {}: {}", index, port.info);
+                *PORTS[index].lock() = Some(port);
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("AHCI port {}: {}", index, err),
+        }
+    }
+
+    match msi::allocate(&controller, 1) {
+        Ok(vectors) => {
+            vectors.set_handler(0, handle_interrupt, 0);
+            vectors.unmask(0);
+            *VECTORS.lock() = Some(vectors);
+            regs.write(hba::IS, u32::MAX);
+            regs.write(hba::GHC, regs.read(hba::GHC) | hba::GHC_IE);
+        }
+        Err(err) => log::info!("AHCI {}: polling, {}", controller.address, err),
+    }
+}
+
+/// Handle to a SATA drive brought up by [`init`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AhciDisk {
+    port: usize,
+}
+
+/// Every SATA drive found, by port number.
+pub fn disks() -> impl Iterator<Item = AhciDisk> {
+    (0..MAX_PORTS)
+        .filter(|port| PORTS[*port].lock().is_some())
+        .map(|port| AhciDisk { port })
+}
+
+/// An outstanding command, see [`AhciDisk::submit`].
+#[must_use = "commands hold their slot until completed"]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    disk: AhciDisk,
+    slot: usize,
+}
+
+impl AhciDisk {
+    fn with_port<R>(&self, f: impl FnOnce(&mut Port) -> R) -> R {
+        f(PORTS[self.port]
+            .lock()
+            .as_mut()
+            .expect("AHCI port vanished"))
+    }
+
+    pub fn info(&self) -> SataInfo {
+        self.with_port(|port| port.info)
+    }
+
+    /// Issues `op` without waiting. With NCQ, up to the drive's queue depth
+    /// of reads and writes run at once; flushes need the queue drained.
+    pub fn submit(&self, op: AhciOp) -> Result<Request, AhciError> {
+        let slot = self.with_port(|port| port.submit(op))?;
+        Ok(Request { disk: *self, slot })
+    }
+
+    /// Takes the result of a finished command; hands it back if it is still
+    /// running.
+    pub fn try_complete(&self, request: Request) -> Result<Result<(), AhciError>, Request> {
+        assert_eq!(request.disk, *self, "request belongs to another disk");
+        let result = self.with_port(|port| {
+            port.update();
+            port.collect(request.slot)
+        });
+        result.ok_or(request)
+    }
+
+    /// Blocks until `request` has finished.
+    pub fn wait(&self, request: Request) -> Result<(), AhciError> {
+        let interrupts = VECTORS.lock().is_some();
+        let mut request = Some(request);
+        block::wait_for(interrupts, Some(TIMEOUT), || {
+            match self.try_complete(request.take()?) {
+                Ok(result) => Some(result),
+                Err(pending) => {
+                    request = Some(pending);
+                    None
+                }
+            }
+        })
+        .unwrap_or_else(|| {
+            let request = request.expect("timed out without a request");
+            log::error!("AHCI port {}: slot {} timed out", self.port, request.slot);
+            self.with_port(|port| port.abort(request.slot));
+            Err(AhciError::Timeout)
+        })
+    }
+
+    /// Issues `op` once a slot is free and waits for it.
+    pub fn run(&self, op: AhciOp) -> Result<(), AhciError> {
+        let deadline = time::uptime() + TIMEOUT;
+        loop {
+            match self.submit(op) {
+                Ok(request) => return self.wait(request),
+                Err(AhciError::Busy) if time::uptime() < deadline => {
+                    self.with_port(|port| port.update());
+                    core::hint::spin_loop();
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Reads whole sectors into `buf` through a bounce buffer.
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), AhciError> {
+        let bounce = self.bounce(lba, buf.len())?;
+        sync::block_on(bounce.read(lba, buf, async |lba, phys, len| {
+            self.run(AhciOp::Read { lba, phys, len })
+        }))
+    }
+
+    /// Writes whole sectors from `buf` through a bounce buffer.
+    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<(), AhciError> {
+        let mut bounce = self.bounce(lba, buf.len())?;
+        sync::block_on(bounce.write(lba, buf, async |lba, phys, len| {
+            self.run(AhciOp::Write { lba, phys, len })
+        }))
+    }
+
+    /// A bounce buffer for `len` bytes from `lba` on, if they are on the
+    /// drive.
+    fn bounce(&self, lba: u64, len: usize) -> Result<Bounce, AhciError> {
+        if !len.is_multiple_of(SECTOR_SIZE)
+            || lba + (len / SECTOR_SIZE) as u64 > self.info().sectors
+        {
+            return Err(AhciError::OutOfRange);
+        }
+        Bounce::new(len, MAX_TRANSFER, SECTOR_SIZE).ok_or(AhciError::OutOfMemory)
+    }
+
+    pub fn flush(&self) -> Result<(), AhciError> {
+        self.run(AhciOp::Flush)
+    }
+}
+
+impl From<AhciError> for BlockError {
+    fn from(err: AhciError) -> BlockError {
+        match err {
+            AhciError::Device(_) | AhciError::Busy | AhciError::TooFragmented => BlockError::Io,
+            AhciError::Timeout => BlockError::Timeout,
+            AhciError::OutOfRange => BlockError::OutOfRange,
+            AhciError::OutOfMemory => BlockError::OutOfMemory,
+        }
+    }
+}
+
+impl AhciDisk {
+    /// Issues `op` and resolves once the HBA has finished it.
+    async fn execute(&self, op: AhciOp) -> Result<(), AhciError> {
+        let mut request = Some(self.submit(op)?);
+        block::poll_completion(|| {
+            match self.try_complete(request.take().expect("request completed twice")) {
+                Ok(result) => Some(result),
+                Err(pending) => {
+                    request = Some(pending);
+                    None
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info().sectors
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let bounce =
+            Bounce::new(buf.len(), MAX_TRANSFER, SECTOR_SIZE).ok_or(BlockError::OutOfMemory)?;
+        bounce
+            .read(lba, buf, async |lba, phys, len| {
+                self.execute(AhciOp::Read { lba, phys, len }).await
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let mut bounce =
+            Bounce::new(buf.len(), MAX_TRANSFER, SECTOR_SIZE).ok_or(BlockError::OutOfMemory)?;
+        bounce
+            .write(lba, buf, async |lba, phys, len| {
+                self.execute(AhciOp::Write { lba, phys, len }).await
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        Ok(self.execute(AhciOp::Flush).await?)
+    }
+}
@@ -0,0 +1,147 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::acpi;
+use crate::memory;
+
+// The PICs stay in charge of legacy IRQs, which reach the CPU through LINT0
+// in virtual wire mode. The local APIC only has to be switched on so that it
+// accepts message signalled interrupts, and those need an EOI written to the
+// APIC rather than the PIC.
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+mod register {
+    pub const ID: u32 = 0x020;
+    pub const VERSION: u32 = 0x030;
+    pub const END_OF_INTERRUPT: u32 = 0x0B0;
+    pub const SPURIOUS_VECTOR: u32 = 0x0F0;
+    pub const LVT_LINT0: u32 = 0x350;
+    pub const LVT_LINT1: u32 = 0x360;
+}
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const DELIVERY_EXTINT: u32 = 0b111 << 8;
+const DELIVERY_NMI: u32 = 0b100 << 8;
+
+/// Vector for spurious APIC interrupts, which need no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/// Physical address MSIs are written to; the destination APIC ID goes in
+/// bits 12-19.
+pub const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+/// MADT entries follow the local APIC address and flags.
+const MADT_ENTRIES_OFFSET: usize = 8;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const MADT_LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// Physical base of the local APIC registers, or 0 before [`init`].
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+unsafe fn read_msr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    (high as u64) << 32 | low as u64
+}
+
+unsafe fn write_msr(msr: u32, value: u64) {
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+    }
+}
+
+fn register(offset: u32) -> *mut u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    assert!(base != 0, "apic::init has not run");
+    memory::phys_to_virt(base + offset as u64) as *mut u32
+}
+
+fn read(offset: u32) -> u32 {
+    unsafe { register(offset).read_volatile() }
+}
+
+fn write(offset: u32, value: u32) {
+    unsafe { register(offset).write_volatile(value) }
+}
+
+/// Enables the local APIC of the boot CPU, keeping the PICs wired through
+/// LINT0.
+pub fn init() {
+    let base = unsafe { read_msr(IA32_APIC_BASE) };
+    if base & APIC_BASE_ENABLE == 0 {
+        unsafe { write_msr(IA32_APIC_BASE, base | APIC_BASE_ENABLE) };
+    }
+    BASE.store(base & APIC_BASE_MASK, Ordering::Relaxed);
+
+    write(register::LVT_LINT0, DELIVERY_EXTINT);
+    write(register::LVT_LINT1, DELIVERY_NMI);
+    write(
+        register::SPURIOUS_VECTOR,
+        SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+
+    log::info!(
+        "APIC: local APIC {} at {:#x}, version {:#x}",
+        id(),
+        base & APIC_BASE_MASK,
+        read(register::VERSION) & 0xFF
+    );
+}
+
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// APIC ID of the running CPU.
+pub fn id() -> u8 {
+    (read(register::ID) >> 24) as u8
+}
+
+/// APIC IDs of the CPUs the firmware lists in the MADT, boot CPU included.
+/// Without ACPI only the running CPU is known.
+pub fn cpu_ids() -> impl Iterator<Item = u8> {
+    let entries = acpi::find_table(MADT_SIGNATURE)
+        .and_then(|madt| madt.body().get(MADT_ENTRIES_OFFSET..))
+        .unwrap_or(&[]);
+    let mut rest = entries;
+    let listed = core::iter::from_fn(move || {
+        loop {
+            let [kind, len, ..] = *rest else {
+                return None;
+            };
+            let len = len as usize;
+            if len < 2 || len > rest.len() {
+                return None;
+            }
+            let (entry, next) = rest.split_at(len);
+            rest = next;
+            if kind == MADT_LOCAL_APIC && len >= 8 {
+                let flags = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+                if flags & (MADT_LOCAL_APIC_ENABLED | MADT_LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                    return Some(entry[3]);
+                }
+            }
+        }
+    });
+    let fallback = entries.is_empty().then(id);
+    listed.chain(fallback)
+}
+
+pub fn end_of_interrupt() {
+    write(register::END_OF_INTERRUPT, 0);
+}
+
+/// Address/data pair that makes a message signalled interrupt arrive on
+/// `vector` at the APIC with ID `destination`: fixed delivery, edge
+/// triggered, physical destination mode.
+pub fn msi_message(destination: u8, vector: u8) -> (u64, u32) {
+    let address = MSI_ADDRESS_BASE | (destination as u32) << 12;
+    (address as u64, vector as u32)
+}
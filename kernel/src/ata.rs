@@ -0,0 +1,613 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::time::Duration;
+
+use crate::block::{BlockDevice, BlockError};
+use crate::interrupts;
+use crate::pci;
+use crate::port::Port;
+use crate::sync::SpinLock;
+use crate::time;
+
+// Parallel ATA through programmed I/O. Each IDE channel has a block of eight
+// command registers and one control register, and up to two drives selected
+// through the drive/head register. Every sector moves through the 16-bit data
+// port; the drive raises its IRQ whenever it wants the next sector or has
+// finished, unless nIEN masks it for polling.
+
+pub const SECTOR_SIZE: usize = 512;
+
+mod reg {
+    pub const DATA: u16 = 0;
+    pub const ERROR: u16 = 1;
+    pub const SECTOR_COUNT: u16 = 2;
+    pub const LBA_LOW: u16 = 3;
+    pub const LBA_MID: u16 = 4;
+    pub const LBA_HIGH: u16 = 5;
+    pub const DRIVE: u16 = 6;
+    pub const STATUS: u16 = 7;
+    pub const COMMAND: u16 = 7;
+}
+
+mod status {
+    pub const ERR: u8 = 1 << 0;
+    pub const DRQ: u8 = 1 << 3;
+    pub const DF: u8 = 1 << 5;
+    pub const BSY: u8 = 1 << 7;
+}
+
+mod control {
+    pub const NIEN: u8 = 1 << 1;
+    pub const SRST: u8 = 1 << 2;
+}
+
+mod command {
+    pub const READ_SECTORS: u8 = 0x20;
+    pub const READ_SECTORS_EXT: u8 = 0x24;
+    pub const WRITE_SECTORS: u8 = 0x30;
+    pub const WRITE_SECTORS_EXT: u8 = 0x34;
+    pub const FLUSH_CACHE: u8 = 0xE7;
+    pub const FLUSH_CACHE_EXT: u8 = 0xEA;
+    pub const IDENTIFY: u8 = 0xEC;
+}
+
+/// Drive/head register: LBA addressing plus the two obsolete bits that must
+/// be set.
+const DRIVE_LBA: u8 = 0xE0;
+const SLAVE_BIT: u8 = 1 << 4;
+
+const LBA28_LIMIT: u64 = 1 << 28;
+const LBA28_MAX_SECTORS: usize = 256;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Legacy ports and IRQs of the compatibility-mode channels.
+const LEGACY_CHANNELS: [(u16, u16, u8); 2] = [(0x1F0, 0x3F6, 14), (0x170, 0x376, 15)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    Timeout,
+    /// The drive set ERR; carries the error register.
+    Device(u8),
+    DeviceFault,
+    OutOfRange,
+}
+
+impl fmt::Display for AtaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AtaError::Timeout => write!(f, "drive timed out"),
+            AtaError::Device(error) => write!(f, "drive error {:#04x}", error),
+            AtaError::DeviceFault => write!(f, "drive fault"),
+            AtaError::OutOfRange => write!(f, "sector out of range"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Primary,
+    Secondary,
+}
+
+impl Channel {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    Master,
+    Slave,
+}
+
+/// How a channel waits for its drives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaMode {
+    Polling,
+    Interrupt,
+}
+
+/// What IDENTIFY DEVICE reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriveInfo {
+    pub channel: Channel,
+    pub position: Position,
+    pub model: [u8; 40],
+    pub serial: [u8; 20],
+    pub sectors: u64,
+    pub lba48: bool,
+}
+
+impl DriveInfo {
+    fn parse(channel: Channel, position: Position, identify: &[u16; 256]) -> DriveInfo {
+        let lba48 = identify[83] & (1 << 10) != 0;
+        let sectors = match lba48 {
+            true => (0..4).fold(0, |sum, i| sum | (identify[100 + i] as u64) << (16 * i)),
+            false => identify[60] as u64 | (identify[61] as u64) << 16,
+        };
+        DriveInfo {
+            channel,
+            position,
+            model: ata_string(&identify[27..47]),
+            serial: ata_string(&identify[10..20]),
+            sectors,
+            lba48,
+        }
+    }
+
+    pub fn model(&self) -> &str {
+        trimmed(&self.model)
+    }
+
+    pub fn serial(&self) -> &str {
+        trimmed(&self.serial)
+    }
+}
+
+/// IDENTIFY strings hold two characters per word, first one in the high byte.
+pub fn ata_string<const N: usize>(words: &[u16]) -> [u8; N] {
+    let mut bytes = [0; N];
+    for (pair, word) in bytes.as_chunks_mut::<2>().0.iter_mut().zip(words) {
+        pair.copy_from_slice(&word.to_be_bytes());
+    }
+    bytes
+}
+
+/// An identify string without the space padding.
+pub fn trimmed(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?").trim()
+}
+
+impl fmt::Display for DriveInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}), {} sectors ({} MiB), LBA{}",
+            self.model(),
+            self.serial(),
+            self.sectors,
+            (self.sectors * SECTOR_SIZE as u64) >> 20,
+            if self.lba48 { 48 } else { 28 }
+        )
+    }
+}
+
+struct ChannelState {
+    index: usize,
+    base: u16,
+    control: u16,
+    mode: AtaMode,
+    /// Whether the channel's IRQ is hooked up, which interrupt mode needs.
+    has_irq: bool,
+    drives: [Option<DriveInfo>; 2],
+    /// Drive the channel last selected, to skip needless reselection.
+    selected: Option<Position>,
+}
+
+/// Plain spinlocks: transfers wait for IRQs while holding them, and the IRQ
+/// handlers only touch the atomics below.
+static CHANNELS: [SpinLock<Option<ChannelState>>; 2] = [const { SpinLock::new(None) }; 2];
+static IRQ_FIRED: [AtomicBool; 2] = [const { AtomicBool::new(false) }; 2];
+/// Command block base per channel, for the IRQ handlers.
+static IRQ_BASE: [AtomicU16; 2] = [const { AtomicU16::new(0) }; 2];
+
+fn handle_irq(channel: usize) {
+    let base = IRQ_BASE[channel].load(Ordering::Relaxed);
+    if base != 0 {
+        // reading the status register acknowledges the interrupt
+        unsafe { Port::<u8>::new(base + reg::STATUS).read() };
+        IRQ_FIRED[channel].store(true, Ordering::Release);
+    }
+}
+
+fn primary_irq() {
+    handle_irq(0);
+}
+
+fn secondary_irq() {
+    handle_irq(1);
+}
+
+impl ChannelState {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.base + register).write(value) }
+    }
+
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    fn set_control(&self, value: u8) {
+        unsafe { Port::<u8>::new(self.control).write(value) }
+    }
+
+    fn interrupt_control(&self) -> u8 {
+        match self.mode {
+            AtaMode::Polling => control::NIEN,
+            AtaMode::Interrupt => 0,
+        }
+    }
+
+    /// Gives the drive the 400ns it needs to put up a valid status.
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn select(&mut self, position: Position, lba_top: u8) {
+        let mut value = DRIVE_LBA | (lba_top & 0x0F);
+        if position == Position::Slave {
+            value |= SLAVE_BIT;
+        }
+        self.write(reg::DRIVE, value);
+        if self.selected != Some(position) {
+            self.delay();
+            self.selected = Some(position);
+        }
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, AtaError> {
+        let deadline = time::uptime() + TIMEOUT;
+        loop {
+            let status = self.alt_status();
+            if status & status::BSY == 0 {
+                return Ok(status);
+            }
+            if time::uptime() > deadline {
+                return Err(AtaError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Waits until the drive is done with the current step of a command,
+    /// through its IRQ or by polling.
+    ///
+    /// There is nothing else to run yet, so waiting for the IRQ spins on its
+    /// flag too; it just leaves the status register alone meanwhile. No
+    /// `hlt` either, as nothing would wake us from a lost IRQ.
+    fn wait_ready(&self) -> Result<u8, AtaError> {
+        if self.mode == AtaMode::Interrupt {
+            let deadline = time::uptime() + TIMEOUT;
+            while !IRQ_FIRED[self.index].swap(false, Ordering::Acquire) {
+                if time::uptime() > deadline {
+                    return Err(AtaError::Timeout);
+                }
+                core::hint::spin_loop();
+            }
+        } else {
+            self.delay();
+        }
+        let status = self.wait_not_busy()?;
+        check(self, status)
+    }
+
+    fn identify(&mut self, position: Position) -> Option<[u16; 256]> {
+        self.select(position, 0);
+        self.write(reg::SECTOR_COUNT, 0);
+        self.write(reg::LBA_LOW, 0);
+        self.write(reg::LBA_MID, 0);
+        self.write(reg::LBA_HIGH, 0);
+        self.write(reg::COMMAND, command::IDENTIFY);
+        self.delay();
+        if self.alt_status() == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // ATAPI and SATA bridges abort IDENTIFY and leave their signature
+        if self.read(reg::LBA_MID) != 0 || self.read(reg::LBA_HIGH) != 0 {
+            return None;
+        }
+        let status = self.wait_drq().ok()?;
+        if status & status::ERR != 0 {
+            return None;
+        }
+        let mut words = [0; 256];
+        self.read_data(&mut words);
+        Some(words)
+    }
+
+    fn wait_drq(&self) -> Result<u8, AtaError> {
+        let deadline = time::uptime() + TIMEOUT;
+        loop {
+            let status = check(self, self.alt_status())?;
+            if status & status::BSY == 0 && status & status::DRQ != 0 {
+                return Ok(status);
+            }
+            if time::uptime() > deadline {
+                return Err(AtaError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn read_data(&self, words: &mut [u16]) {
+        let data = Port::<u16>::new(self.base + reg::DATA);
+        for word in words {
+            *word = unsafe { data.read() };
+        }
+    }
+
+    fn write_data(&self, words: impl Iterator<Item = u16>) {
+        let data = Port::<u16>::new(self.base + reg::DATA);
+        for word in words {
+            unsafe { data.write(word) };
+        }
+    }
+
+    fn issue(&mut self, position: Position, lba: u64, count: usize, lba48: bool, cmd: u8) {
+        IRQ_FIRED[self.index].store(false, Ordering::Relaxed);
+        if lba48 {
+            self.select(position, 0);
+            // high-order bytes first, the registers are two-deep FIFOs
+            self.write(reg::SECTOR_COUNT, (count >> 8) as u8);
+            self.write(reg::LBA_LOW, (lba >> 24) as u8);
+            self.write(reg::LBA_MID, (lba >> 32) as u8);
+            self.write(reg::LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.select(position, (lba >> 24) as u8);
+        }
+        self.write(reg::SECTOR_COUNT, count as u8);
+        self.write(reg::LBA_LOW, lba as u8);
+        self.write(reg::LBA_MID, (lba >> 8) as u8);
+        self.write(reg::LBA_HIGH, (lba >> 16) as u8);
+        self.write(reg::COMMAND, cmd);
+    }
+}
+
+fn check(channel: &ChannelState, status: u8) -> Result<u8, AtaError> {
+    if status & status::DF != 0 {
+        return Err(AtaError::DeviceFault);
+    }
+    if status & status::ERR != 0 {
+        return Err(AtaError::Device(channel.read(reg::ERROR)));
+    }
+    Ok(status)
+}
+
+/// Finds the IDE controller and identifies the drives on both channels.
+///
+/// Channels in PCI native mode use the I/O BARs, others the legacy ports.
+/// Compatibility channels get IRQ 14/15 and run interrupt driven; native
+/// channels are polled.
+pub fn init() {
+    let controller = pci::find_class(pci::class::MASS_STORAGE, pci::subclass::IDE, None).next();
+    if let Some(controller) = controller {
+        controller.address.enable(pci::command::IO_SPACE);
+    }
+    for (index, channel) in [Channel::Primary, Channel::Secondary]
+        .into_iter()
+        .enumerate()
+    {
+        let (legacy_base, legacy_control, irq) = LEGACY_CHANNELS[index];
+        // prog_if bit 0/2: primary/secondary channel in native mode
+        let native = controller.filter(|c| c.prog_if & (1 << (2 * index)) != 0);
+        let (base, control, mode) = match native {
+            Some(c) => match (c.io_bar(2 * index), c.io_bar(2 * index + 1)) {
+                (Some(base), Some(control)) => (base, control + 2, AtaMode::Polling),
+                _ => continue,
+            },
+            None => (legacy_base, legacy_control, AtaMode::Interrupt),
+        };
+        let mut state = ChannelState {
+            index,
+            base,
+            control,
+            mode: AtaMode::Polling,
+            has_irq: false,
+            drives: [None; 2],
+            selected: None,
+        };
+        // a floating bus reads all ones
+        if state.alt_status() == 0xFF {
+            continue;
+        }
+        // SRST has to be held for at least 5us, and BSY may take 2ms to
+        // show up after it's released
+        state.set_control(control::SRST | control::NIEN);
+        time::delay(Duration::from_micros(5));
+        state.set_control(control::NIEN);
+        time::delay(Duration::from_millis(2));
+        if state.wait_not_busy().is_err() {
+            continue;
+        }
+        state.selected = None;
+
+        for (slot, position) in [Position::Master, Position::Slave].into_iter().enumerate() {
+            if let Some(identify) = state.identify(position) {
+                let info = DriveInfo::parse(channel, position, &identify);
+                log::info!("ATA {:?} {:?}: {}", channel, position, info);
+                state.drives[slot] = Some(info);
+            }
+        }
+        if state.drives.iter().all(Option::is_none) {
+            continue;
+        }
+        IRQ_BASE[index].store(base, Ordering::Relaxed);
+        if mode == AtaMode::Interrupt {
+            interrupts::register_irq(irq, [primary_irq, secondary_irq][index]);
+            state.has_irq = true;
+        }
+        state.mode = mode;
+        state.set_control(state.interrupt_control());
+        *CHANNELS[index].lock() = Some(state);
+    }
+}
+
+/// Switches a channel between IRQ-driven and polled transfers. Returns
+/// false if the channel is absent or has no IRQ to use.
+pub fn set_mode(channel: Channel, mode: AtaMode) -> bool {
+    match CHANNELS[channel.index()].lock().as_mut() {
+        Some(state) if mode == AtaMode::Polling || state.has_irq => {
+            state.mode = mode;
+            state.set_control(state.interrupt_control());
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn mode(channel: Channel) -> Option<AtaMode> {
+    CHANNELS[channel.index()]
+        .lock()
+        .as_ref()
+        .map(|state| state.mode)
+}
+
+/// Handle to a drive found by [`init`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtaDrive {
+    info: DriveInfo,
+}
+
+/// Every ATA drive on both channels, primary master first.
+pub fn drives() -> impl Iterator<Item = AtaDrive> {
+    CHANNELS.iter().flat_map(|channel| {
+        let drives = channel
+            .lock()
+            .as_ref()
+            .map_or([None; 2], |state| state.drives);
+        drives.into_iter().flatten().map(|info| AtaDrive { info })
+    })
+}
+
+impl AtaDrive {
+    pub fn info(&self) -> &DriveInfo {
+        &self.info
+    }
+
+    fn with_channel<R>(&self, f: impl FnOnce(&mut ChannelState) -> R) -> R {
+        let mut channel = CHANNELS[self.info.channel.index()].lock();
+        f(channel.as_mut().expect("ATA channel vanished"))
+    }
+
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), AtaError> {
+        let sectors = (len / SECTOR_SIZE) as u64;
+        if !len.is_multiple_of(SECTOR_SIZE) || lba + sectors > self.info.sectors {
+            return Err(AtaError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Splits a transfer into commands, picking LBA28 where it suffices.
+    fn commands(&self, lba: u64, len: usize) -> impl Iterator<Item = (u64, usize, bool)> + use<> {
+        let lba48 = self.info.lba48;
+        let sectors = len / SECTOR_SIZE;
+        (0..sectors).step_by(LBA28_MAX_SECTORS).map(move |done| {
+            let count = (sectors - done).min(LBA28_MAX_SECTORS);
+            let start = lba + done as u64;
+            let extended = lba48 && start + count as u64 > LBA28_LIMIT;
+            (start, count, extended)
+        })
+    }
+
+    /// Reads whole sectors starting at `lba` into `buf`.
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), AtaError> {
+        self.check_range(lba, buf.len())?;
+        let position = self.info.position;
+        let commands = self.commands(lba, buf.len());
+        let mut sectors = buf.as_chunks_mut::<SECTOR_SIZE>().0.iter_mut();
+        self.with_channel(|channel| {
+            for (start, count, extended) in commands {
+                let cmd = match extended {
+                    true => command::READ_SECTORS_EXT,
+                    false => command::READ_SECTORS,
+                };
+                channel.issue(position, start, count, extended, cmd);
+                for sector in sectors.by_ref().take(count) {
+                    channel.wait_ready()?;
+                    channel.wait_drq()?;
+                    let mut words = [0u16; SECTOR_SIZE / 2];
+                    channel.read_data(&mut words);
+                    for (bytes, word) in sector.as_chunks_mut::<2>().0.iter_mut().zip(words) {
+                        bytes.copy_from_slice(&word.to_le_bytes());
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes whole sectors from `buf` starting at `lba`.
+    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<(), AtaError> {
+        self.check_range(lba, buf.len())?;
+        let position = self.info.position;
+        let mut sectors = buf.as_chunks::<SECTOR_SIZE>().0.iter();
+        self.with_channel(|channel| {
+            for (start, count, extended) in self.commands(lba, buf.len()) {
+                let cmd = match extended {
+                    true => command::WRITE_SECTORS_EXT,
+                    false => command::WRITE_SECTORS,
+                };
+                channel.issue(position, start, count, extended, cmd);
+                for sector in sectors.by_ref().take(count) {
+                    // the first sector goes out without an IRQ, the others
+                    // after the IRQ for the previous one
+                    channel.wait_drq()?;
+                    let words = sector
+                        .as_chunks::<2>()
+                        .0
+                        .iter()
+                        .map(|pair| u16::from_le_bytes(*pair));
+                    channel.write_data(words);
+                    channel.wait_ready()?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Flushes the drive's write cache.
+    pub fn flush(&self) -> Result<(), AtaError> {
+        let position = self.info.position;
+        let cmd = match self.info.lba48 {
+            true => command::FLUSH_CACHE_EXT,
+            false => command::FLUSH_CACHE,
+        };
+        self.with_channel(|channel| {
+            channel.issue(position, 0, 0, false, cmd);
+            channel.wait_ready().map(|_| ())
+        })
+    }
+}
+
+impl From<AtaError> for BlockError {
+    fn from(err: AtaError) -> BlockError {
+        match err {
+            AtaError::Timeout => BlockError::Timeout,
+            AtaError::Device(_) | AtaError::DeviceFault => BlockError::Io,
+            AtaError::OutOfRange => BlockError::OutOfRange,
+        }
+    }
+}
+
+// PIO moves every word through the CPU anyway, so the futures complete on
+// their first poll.
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.sectors
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        Ok(AtaDrive::read(self, lba, buf)?)
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        Ok(AtaDrive::write(self, lba, buf)?)
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        Ok(AtaDrive::flush(self)?)
+    }
+}
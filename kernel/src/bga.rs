@@ -0,0 +1,328 @@
+use core::fmt;
+
+use bootloader_api::info::{FrameBufferInfo, PixelFormat};
+
+use crate::logger;
+use crate::memory;
+use crate::pci::{self, PciAddress};
+use crate::port::Port;
+
+// Bochs Graphics Adapter, as emulated by QEMU's `-vga std` and
+// `-device bochs-display`. Modes are programmed through the DISPI register
+// file, which is reachable through the 0x1CE/0x1CF index/data ports on
+// `-vga std` and through a window at BAR2 + 0x500 on both devices.
+
+pub const VENDOR_ID: u16 = 0x1234;
+pub const DEVICE_ID: u16 = 0x1111;
+
+const DISPI_IOPORT_INDEX: u16 = 0x01CE;
+const DISPI_IOPORT_DATA: u16 = 0x01CF;
+const DISPI_MMIO_OFFSET: u64 = 0x500;
+
+mod index {
+    pub const ID: u16 = 0x0;
+    pub const XRES: u16 = 0x1;
+    pub const YRES: u16 = 0x2;
+    pub const BPP: u16 = 0x3;
+    pub const ENABLE: u16 = 0x4;
+    pub const VIRT_WIDTH: u16 = 0x6;
+    pub const X_OFFSET: u16 = 0x8;
+    pub const Y_OFFSET: u16 = 0x9;
+    pub const VIDEO_MEMORY_64K: u16 = 0xA;
+}
+
+mod enable {
+    pub const ENABLED: u16 = 0x01;
+    /// While set, XRES/YRES/BPP read back the maximum supported values.
+    pub const GETCAPS: u16 = 0x02;
+    pub const LFB_ENABLED: u16 = 0x40;
+}
+
+const ID_MIN: u16 = 0xB0C0;
+const ID_MAX: u16 = 0xB0CF;
+
+pub const DEPTHS: [u8; 5] = [8, 15, 16, 24, 32];
+
+const RESOLUTIONS: [(u16, u16); 10] = [
+    (640, 480),
+    (800, 600),
+    (1024, 768),
+    (1280, 720),
+    (1280, 800),
+    (1280, 1024),
+    (1440, 900),
+    (1600, 1200),
+    (1920, 1080),
+    (2560, 1440),
+];
+
+/// Largest mode [`Bga::sensible_mode`] will pick, so text stays readable.
+const SENSIBLE_MAX: (u16, u16) = (1280, 1024);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    pub width: u16,
+    pub height: u16,
+    pub bpp: u8,
+}
+
+impl Mode {
+    pub const fn new(width: u16, height: u16, bpp: u8) -> Self {
+        Mode { width, height, bpp }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        (self.bpp as usize).div_ceil(8)
+    }
+
+    fn pixel_format(&self) -> PixelFormat {
+        match self.bpp {
+            8 => PixelFormat::U8,
+            15 => PixelFormat::Unknown {
+                red_position: 10,
+                green_position: 5,
+                blue_position: 0,
+            },
+            16 => PixelFormat::Unknown {
+                red_position: 11,
+                green_position: 5,
+                blue_position: 0,
+            },
+            _ => PixelFormat::Bgr,
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}x{}", self.width, self.height, self.bpp)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BgaError {
+    UnsupportedMode(Mode),
+    /// The adapter came back with different settings than requested.
+    ModeRejected(Mode),
+    /// The console can only render 24 and 32 bit modes.
+    UnsupportedConsoleDepth(u8),
+}
+
+impl fmt::Display for BgaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BgaError::UnsupportedMode(mode) => write!(f, "mode {mode} is not supported"),
+            BgaError::ModeRejected(mode) => write!(f, "adapter rejected mode {mode}"),
+            BgaError::UnsupportedConsoleDepth(bpp) => {
+                write!(f, "console cannot render {bpp} bits per pixel")
+            }
+        }
+    }
+}
+
+enum Registers {
+    Io,
+    Mmio(*mut u16),
+}
+
+pub struct Bga {
+    pci: PciAddress,
+    registers: Registers,
+    version: u16,
+    framebuffer_phys: u64,
+    vram_size: usize,
+    max: Mode,
+}
+
+unsafe impl Send for Bga {}
+
+/// Looks for a BGA and moves a framebuffer console on it to
+/// [`Bga::sensible_mode`], whatever the bootloader picked.
+pub fn init() {
+    let Some(bga) = probe() else {
+        return;
+    };
+    log::info!(
+        "BGA at {}: DISPI {:#x}, {} KiB VRAM, up to {}",
+        bga.pci_address(),
+        bga.version(),
+        bga.vram_size() / 1024,
+        bga.max_mode()
+    );
+    if logger::with_framebuffer(|_| ()).is_none() {
+        return;
+    }
+    if let Some(mode) = bga.sensible_mode()
+        && mode != bga.current_mode()
+        && let Err(err) = bga.switch_console_mode(mode)
+    {
+        log::warn!("BGA: {}", err);
+    }
+}
+
+/// Looks for a BGA on the PCI bus and reads its capabilities.
+pub fn probe() -> Option<Bga> {
+    let device = pci::find(VENDOR_ID, DEVICE_ID)?;
+    let pci = device.address;
+    pci.enable(pci::command::MEMORY_SPACE | pci::command::IO_SPACE);
+
+    let framebuffer_phys = device.memory_bar(0)?;
+    let registers = match device.memory_bar(2) {
+        Some(mmio) => Registers::Mmio(memory::phys_to_virt(mmio + DISPI_MMIO_OFFSET).cast()),
+        None => Registers::Io,
+    };
+
+    let mut bga = Bga {
+        pci,
+        registers,
+        version: 0,
+        framebuffer_phys,
+        vram_size: 0,
+        max: Mode::new(0, 0, 0),
+    };
+    bga.version = bga.read(index::ID);
+    if !(ID_MIN..=ID_MAX).contains(&bga.version) {
+        log::warn!("BGA at {}: unknown DISPI id {:#x}", pci, bga.version);
+        return None;
+    }
+
+    bga.vram_size = match bga.read(index::VIDEO_MEMORY_64K) {
+        0 => device.bar(0).map_or(0, |bar| bar.size()) as usize,
+        blocks => blocks as usize * 64 * 1024,
+    };
+
+    let enabled = bga.read(index::ENABLE);
+    bga.write(index::ENABLE, enabled | enable::GETCAPS);
+    bga.max = Mode::new(
+        bga.read(index::XRES),
+        bga.read(index::YRES),
+        bga.read(index::BPP) as u8,
+    );
+    bga.write(index::ENABLE, enabled);
+
+    Some(bga)
+}
+
+impl Bga {
+    fn read(&self, index: u16) -> u16 {
+        match self.registers {
+            Registers::Io => unsafe {
+                Port::<u16>::new(DISPI_IOPORT_INDEX).write(index);
+                Port::<u16>::new(DISPI_IOPORT_DATA).read()
+            },
+            Registers::Mmio(base) => unsafe { base.add(index as usize).read_volatile() },
+        }
+    }
+
+    fn write(&self, index: u16, value: u16) {
+        match self.registers {
+            Registers::Io => unsafe {
+                Port::<u16>::new(DISPI_IOPORT_INDEX).write(index);
+                Port::<u16>::new(DISPI_IOPORT_DATA).write(value);
+            },
+            Registers::Mmio(base) => unsafe { base.add(index as usize).write_volatile(value) },
+        }
+    }
+
+    pub fn pci_address(&self) -> PciAddress {
+        self.pci
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn vram_size(&self) -> usize {
+        self.vram_size
+    }
+
+    pub fn max_mode(&self) -> Mode {
+        self.max
+    }
+
+    pub fn supports(&self, mode: Mode) -> bool {
+        DEPTHS.contains(&mode.bpp)
+            && mode.bpp <= self.max.bpp
+            && mode.width <= self.max.width
+            && mode.height <= self.max.height
+            && mode.width as usize * mode.height as usize * mode.bytes_per_pixel() <= self.vram_size
+    }
+
+    /// Every common resolution and depth the adapter can display.
+    pub fn modes(&self) -> impl Iterator<Item = Mode> + '_ {
+        RESOLUTIONS
+            .iter()
+            .flat_map(|&(width, height)| {
+                DEPTHS.iter().map(move |&bpp| Mode::new(width, height, bpp))
+            })
+            .filter(|mode| self.supports(*mode))
+    }
+
+    /// Largest supported 32-bit mode no bigger than 1280x1024.
+    pub fn sensible_mode(&self) -> Option<Mode> {
+        self.modes()
+            .filter(|mode| mode.bpp == 32)
+            .filter(|mode| mode.width <= SENSIBLE_MAX.0 && mode.height <= SENSIBLE_MAX.1)
+            .max_by_key(|mode| mode.width as u32 * mode.height as u32)
+    }
+
+    pub fn current_mode(&self) -> Mode {
+        Mode::new(
+            self.read(index::XRES),
+            self.read(index::YRES),
+            self.read(index::BPP) as u8,
+        )
+    }
+
+    /// Programs `mode` and returns the layout of the resulting framebuffer.
+    pub fn set_mode(&self, mode: Mode) -> Result<FrameBufferInfo, BgaError> {
+        if !self.supports(mode) {
+            return Err(BgaError::UnsupportedMode(mode));
+        }
+
+        self.write(index::ENABLE, 0);
+        self.write(index::XRES, mode.width);
+        self.write(index::YRES, mode.height);
+        self.write(index::BPP, mode.bpp as u16);
+        self.write(index::ENABLE, enable::ENABLED | enable::LFB_ENABLED);
+        self.write(index::X_OFFSET, 0);
+        self.write(index::Y_OFFSET, 0);
+
+        if self.current_mode() != mode {
+            return Err(BgaError::ModeRejected(mode));
+        }
+
+        let stride = self.read(index::VIRT_WIDTH) as usize;
+        Ok(FrameBufferInfo {
+            byte_len: stride * mode.height as usize * mode.bytes_per_pixel(),
+            width: mode.width as usize,
+            height: mode.height as usize,
+            pixel_format: mode.pixel_format(),
+            bytes_per_pixel: mode.bytes_per_pixel(),
+            stride,
+        })
+    }
+
+    /// Returns the linear framebuffer laid out as described by `info`.
+    ///
+    /// # Safety
+    ///
+    /// Any earlier slice over the framebuffer, including the one from
+    /// `BootInfo`, must no longer be used.
+    pub unsafe fn framebuffer(&self, info: &FrameBufferInfo) -> &'static mut [u8] {
+        unsafe { memory::phys_slice_mut(self.framebuffer_phys, info.byte_len) }
+    }
+
+    /// Switches to `mode` and rebuilds the framebuffer console on the new
+    /// buffer.
+    pub fn switch_console_mode(&self, mode: Mode) -> Result<FrameBufferInfo, BgaError> {
+        if mode.bytes_per_pixel() < 3 {
+            return Err(BgaError::UnsupportedConsoleDepth(mode.bpp));
+        }
+        let info = self.set_mode(mode)?;
+        // the console still holds the old slice, so it goes first
+        logger::replace_framebuffer(unsafe { self.framebuffer(&info) }, info);
+        log::info!("BGA: console switched to {}", mode);
+        Ok(info)
+    }
+}
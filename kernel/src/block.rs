@@ -0,0 +1,624 @@
+use alloc::sync::Arc;
+use core::fmt;
+use core::task::Poll;
+use core::time::Duration;
+
+use crate::ahci::{self, AhciDisk};
+use crate::ata::{self, AtaDrive};
+use crate::buffer_cache;
+use crate::devfs::{self, DeviceNumber, ioctl};
+use crate::interrupts;
+use crate::memory::DmaBuffer;
+use crate::nvme::{self, Namespace};
+use crate::partition::{self, PartitionType};
+use crate::sync::{self, SpinLock};
+use crate::time;
+use crate::vfs::{self, FileType, VfsError};
+use crate::virtio_blk::{self, VirtioBlk};
+
+// Every disk driver and every partition sits behind the same trait, and
+// [`init`] puts them into one registry under Linux-style names (vda, hda,
+// sda, nvme0n1 and their numbered partitions). Filesystems only ever see a
+// [`DeviceId`]. Each registered device also shows up in devfs, under the
+// same name.
+
+/// Linux's major for block devices numbered on the fly; the minor is the
+/// registry slot.
+const DEVFS_MAJOR: u32 = 259;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The device reported a failed transfer.
+    Io,
+    Timeout,
+    OutOfRange,
+    ReadOnly,
+    Unsupported,
+    OutOfMemory,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::Io => write!(f, "I/O error"),
+            BlockError::Timeout => write!(f, "timed out"),
+            BlockError::OutOfRange => write!(f, "block out of range"),
+            BlockError::ReadOnly => write!(f, "read-only device"),
+            BlockError::Unsupported => write!(f, "not supported"),
+            BlockError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+/// A disk addressed in whole blocks. Buffers have to be a multiple of
+/// [`block_size`](Self::block_size) long.
+pub trait BlockDevice {
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn read<'a>(
+        &'a self,
+        lba: u64,
+        buf: &'a mut [u8],
+    ) -> impl Future<Output = Result<(), BlockError>> + 'a;
+
+    fn write<'a>(
+        &'a self,
+        lba: u64,
+        buf: &'a [u8],
+    ) -> impl Future<Output = Result<(), BlockError>> + 'a;
+
+    /// Makes completed writes durable.
+    fn flush(&self) -> impl Future<Output = Result<(), BlockError>> + '_;
+}
+
+/// Resolves once `poll` returns a value, for drivers whose completions are
+/// only noticed by asking the hardware.
+pub fn poll_completion<R>(mut poll: impl FnMut() -> Option<R>) -> impl Future<Output = R> {
+    core::future::poll_fn(move |cx| match poll() {
+        Some(result) => Poll::Ready(result),
+        None => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+}
+
+/// Blocks until `poll` returns a value, for the drivers' synchronous
+/// paths. With `interrupt_driven` and interrupts on, the CPU halts between
+/// polls; otherwise it spins. Gives up with `None` once `timeout` has
+/// passed. The caller's interrupt flag is left as it was.
+pub fn wait_for<R>(
+    interrupt_driven: bool,
+    timeout: Option<Duration>,
+    mut poll: impl FnMut() -> Option<R>,
+) -> Option<R> {
+    let halt = interrupt_driven && interrupts::are_enabled();
+    let deadline = timeout.map(|timeout| time::uptime() + timeout);
+    loop {
+        if halt {
+            // check and halt atomically so the completion can't slip in between
+            interrupts::disable();
+        }
+        let result = poll();
+        if result.is_some() || deadline.is_some_and(|deadline| time::uptime() > deadline) {
+            if halt {
+                interrupts::enable();
+            }
+            return result;
+        }
+        match halt {
+            true => interrupts::enable_and_wait(),
+            false => core::hint::spin_loop(),
+        }
+    }
+}
+
+/// A DMA buffer that transfers go through in chunks, for devices that
+/// take only so much at once or need memory they can address.
+pub struct Bounce {
+    buffer: DmaBuffer,
+    chunk_len: usize,
+    block_size: usize,
+}
+
+impl Bounce {
+    /// For transfers of up to `len` bytes in chunks of at most `max_chunk`,
+    /// a multiple of `block_size`.
+    pub fn new(len: usize, max_chunk: usize, block_size: usize) -> Option<Bounce> {
+        let chunk_len = len.clamp(block_size, max_chunk);
+        Some(Bounce {
+            buffer: DmaBuffer::new(chunk_len)?,
+            chunk_len,
+            block_size,
+        })
+    }
+
+    /// Fills `buf` from block `lba` on. `read(lba, phys, len)` brings one
+    /// chunk into the buffer.
+    pub async fn read<E>(
+        &self,
+        lba: u64,
+        buf: &mut [u8],
+        mut read: impl AsyncFnMut(u64, u64, u32) -> Result<(), E>,
+    ) -> Result<(), E> {
+        for (index, chunk) in buf.chunks_mut(self.chunk_len).enumerate() {
+            read(self.lba(lba, index), self.buffer.phys(), chunk.len() as u32).await?;
+            chunk.copy_from_slice(&self.buffer[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` from block `lba` on. `write(lba, phys, len)` takes one
+    /// chunk out of the buffer.
+    pub async fn write<E>(
+        &mut self,
+        lba: u64,
+        buf: &[u8],
+        mut write: impl AsyncFnMut(u64, u64, u32) -> Result<(), E>,
+    ) -> Result<(), E> {
+        for (index, chunk) in buf.chunks(self.chunk_len).enumerate() {
+            self.buffer[..chunk.len()].copy_from_slice(chunk);
+            write(self.lba(lba, index), self.buffer.phys(), chunk.len() as u32).await?;
+        }
+        Ok(())
+    }
+
+    fn lba(&self, start: u64, chunk: usize) -> u64 {
+        start + (chunk * self.chunk_len / self.block_size) as u64
+    }
+}
+
+/// A whole disk on any of the drivers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disk {
+    Virtio(VirtioBlk),
+    Ata(AtaDrive),
+    Ahci(AhciDisk),
+    Nvme(Namespace),
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        match self {
+            Disk::Virtio(disk) => disk.block_size(),
+            Disk::Ata(drive) => drive.block_size(),
+            Disk::Ahci(disk) => disk.block_size(),
+            Disk::Nvme(namespace) => namespace.block_size(),
+        }
+    }
+
+    fn block_count(&self) -> u64 {
+        match self {
+            Disk::Virtio(disk) => disk.block_count(),
+            Disk::Ata(drive) => drive.block_count(),
+            Disk::Ahci(disk) => disk.block_count(),
+            Disk::Nvme(namespace) => namespace.block_count(),
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        match self {
+            Disk::Virtio(disk) => BlockDevice::read_only(disk),
+            _ => false,
+        }
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        match self {
+            Disk::Virtio(disk) => BlockDevice::read(disk, lba, buf).await,
+            Disk::Ata(drive) => BlockDevice::read(drive, lba, buf).await,
+            Disk::Ahci(disk) => BlockDevice::read(disk, lba, buf).await,
+            Disk::Nvme(namespace) => BlockDevice::read(namespace, lba, buf).await,
+        }
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        match self {
+            Disk::Virtio(disk) => BlockDevice::write(disk, lba, buf).await,
+            Disk::Ata(drive) => BlockDevice::write(drive, lba, buf).await,
+            Disk::Ahci(disk) => BlockDevice::write(disk, lba, buf).await,
+            Disk::Nvme(namespace) => BlockDevice::write(namespace, lba, buf).await,
+        }
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        match self {
+            Disk::Virtio(disk) => BlockDevice::flush(disk).await,
+            Disk::Ata(drive) => BlockDevice::flush(drive).await,
+            Disk::Ahci(disk) => BlockDevice::flush(disk).await,
+            Disk::Nvme(namespace) => BlockDevice::flush(namespace).await,
+        }
+    }
+}
+
+/// A window of a disk found by the partition scanner.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    pub disk: Disk,
+    /// First block, in the disk's blocks.
+    pub start: u64,
+    pub blocks: u64,
+    pub kind: PartitionType,
+    /// 1-based entry number in the partition table.
+    pub number: usize,
+}
+
+impl Partition {
+    fn check(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        let blocks = len.div_ceil(self.disk.block_size()) as u64;
+        if lba + blocks > self.blocks {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(self.start + lba)
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let lba = self.check(lba, buf.len())?;
+        self.disk.read(lba, buf).await
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let lba = self.check(lba, buf.len())?;
+        self.disk.write(lba, buf).await
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush().await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Disk(Disk),
+    Partition(Partition),
+}
+
+impl Device {
+    fn disk(&self) -> &Disk {
+        match self {
+            Device::Disk(disk) => disk,
+            Device::Partition(partition) => &partition.disk,
+        }
+    }
+}
+
+impl BlockDevice for Device {
+    fn block_size(&self) -> usize {
+        self.disk().block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        match self {
+            Device::Disk(disk) => disk.block_count(),
+            Device::Partition(partition) => partition.blocks,
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk().read_only()
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        match self {
+            Device::Disk(disk) => disk.read(lba, buf).await,
+            Device::Partition(partition) => partition.read(lba, buf).await,
+        }
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        match self {
+            Device::Disk(disk) => disk.write(lba, buf).await,
+            Device::Partition(partition) => partition.write(lba, buf).await,
+        }
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        self.disk().flush().await
+    }
+}
+
+const NAME_LEN: usize = 16;
+
+/// A device name like `vda` or `nvme0n1p2`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Name {
+    bytes: [u8; NAME_LEN],
+    len: usize,
+}
+
+impl Name {
+    fn new(args: fmt::Arguments) -> Name {
+        let mut name = Name {
+            bytes: [0; NAME_LEN],
+            len: 0,
+        };
+        // longer names are cut short; ours never are
+        let _ = fmt::write(&mut name, args);
+        name
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("?")
+    }
+}
+
+impl fmt::Write for Name {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > NAME_LEN {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+const MAX_DEVICES: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    name: Name,
+    device: Device,
+    parent: Option<DeviceId>,
+}
+
+static DEVICES: SpinLock<[Option<Entry>; MAX_DEVICES]> = SpinLock::new([None; MAX_DEVICES]);
+
+/// A registered block device. Ids of removed devices are reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceId(usize);
+
+impl DeviceId {
+    fn entry(&self) -> Entry {
+        DEVICES.lock()[self.0].expect("block device vanished")
+    }
+
+    pub fn name(&self) -> Name {
+        self.entry().name
+    }
+
+    pub fn device(&self) -> Device {
+        self.entry().device
+    }
+
+    /// The whole disk a partition lives on.
+    pub fn parent(&self) -> Option<DeviceId> {
+        self.entry().parent
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl BlockDevice for DeviceId {
+    fn block_size(&self) -> usize {
+        self.device().block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device().block_count()
+    }
+
+    fn read_only(&self) -> bool {
+        self.device().read_only()
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.device().read(lba, buf).await
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.device().write(lba, buf).await
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        self.device().flush().await
+    }
+}
+
+/// The device as `/dev` has it: read and written at any byte offset
+/// through the buffer cache.
+impl devfs::Device for DeviceId {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let block_size = self.block_size() as u64;
+        let len = buf.len().min(self.size().saturating_sub(offset) as usize);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = (position % block_size) as usize;
+            let count = (block_size as usize - within).min(len - done);
+            let chunk = &mut buf[done..done + count];
+            buffer_cache::read(*self, position / block_size, |block| {
+                chunk.copy_from_slice(&block[within..within + count])
+            })?;
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        let block_size = self.block_size() as u64;
+        let len = buf.len().min(self.size().saturating_sub(offset) as usize);
+        if len == 0 && !buf.is_empty() {
+            return Err(VfsError::NoSpace);
+        }
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = (position % block_size) as usize;
+            let count = (block_size as usize - within).min(len - done);
+            let chunk = &buf[done..done + count];
+            let copy = |block: &mut [u8]| block[within..within + count].copy_from_slice(chunk);
+            match count as u64 == block_size {
+                true => buffer_cache::overwrite(*self, position / block_size, copy)?,
+                false => buffer_cache::write(*self, position / block_size, copy)?,
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> vfs::Result<usize> {
+        match request {
+            ioctl::BLKGETSIZE64 => devfs::put_arg(arg, &self.size().to_le_bytes()),
+            ioctl::BLKSSZGET => devfs::put_arg(arg, &(self.block_size() as i32).to_le_bytes()),
+            _ => Err(VfsError::NotTty),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+fn register(name: Name, device: Device, parent: Option<DeviceId>) -> Option<DeviceId> {
+    let mut devices = DEVICES.lock();
+    let index = devices.iter().position(Option::is_none)?;
+    devices[index] = Some(Entry {
+        name,
+        device,
+        parent,
+    });
+    drop(devices);
+    let id = DeviceId(index);
+    let number = DeviceNumber::new(DEVFS_MAJOR, index as u32);
+    devfs::register(
+        name.as_str(),
+        FileType::BlockDevice,
+        number,
+        0o660,
+        Arc::new(id),
+    );
+    Some(id)
+}
+
+/// Adds a partition of `disk`, named after it: `vda1`, `nvme0n1p1`.
+pub fn register_partition(disk: DeviceId, partition: Partition) -> Option<DeviceId> {
+    let parent = disk.name();
+    let separator = match parent.as_str().ends_with(|c: char| c.is_ascii_digit()) {
+        true => "p",
+        false => "",
+    };
+    let name = Name::new(format_args!("{}{}{}", parent, separator, partition.number));
+    register(name, Device::Partition(partition), Some(disk))
+}
+
+/// Drops the partitions registered for `disk`, before a rescan.
+pub fn remove_partitions(disk: DeviceId) {
+    let mut devices = DEVICES.lock();
+    for slot in devices.iter_mut() {
+        if let Some(entry) = slot
+            && entry.parent == Some(disk)
+        {
+            devfs::unregister(entry.name.as_str());
+            *slot = None;
+        }
+    }
+}
+
+/// Every registered device, disks and partitions alike.
+pub fn devices() -> impl Iterator<Item = DeviceId> {
+    let devices = *DEVICES.lock();
+    (0..MAX_DEVICES)
+        .filter(move |index| devices[*index].is_some())
+        .map(DeviceId)
+}
+
+pub fn find(name: &str) -> Option<DeviceId> {
+    devices().find(|id| id.name().as_str() == name)
+}
+
+/// Synchronous read, for code that doesn't run as a task.
+pub fn read(device: &impl BlockDevice, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    sync::block_on(device.read(lba, buf))
+}
+
+/// Synchronous write, for code that doesn't run as a task.
+pub fn write(device: &impl BlockDevice, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+    sync::block_on(device.write(lba, buf))
+}
+
+fn register_disk(name: Name, disk: Disk) {
+    let Some(id) = register(name, Device::Disk(disk), None) else {
+        log::warn!("block: no room for {}", name);
+        return;
+    };
+    log::info!(
+        "block: {}: {} blocks of {} bytes",
+        name,
+        disk.block_count(),
+        disk.block_size()
+    );
+    match partition::scan(id) {
+        Ok(0) => {}
+        Ok(count) => log::info!("block: {}: {} partition(s)", name, count),
+        Err(err) => log::warn!("block: {}: partition scan failed, {}", name, err),
+    }
+}
+
+/// Registers the disks of every driver and scans them for partitions. Runs
+/// after the drivers' own `init`.
+pub fn init() {
+    for (index, disk) in virtio_blk::disks().enumerate() {
+        register_disk(
+            Name::new(format_args!("vd{}", (b'a' + index as u8) as char)),
+            Disk::Virtio(disk),
+        );
+    }
+    for drive in ata::drives() {
+        let info = drive.info();
+        let index = 2 * info.channel as usize + info.position as usize;
+        register_disk(
+            Name::new(format_args!("hd{}", (b'a' + index as u8) as char)),
+            Disk::Ata(drive),
+        );
+    }
+    for (index, disk) in ahci::disks().enumerate() {
+        register_disk(
+            Name::new(format_args!("sd{}", (b'a' + index as u8) as char)),
+            Disk::Ahci(disk),
+        );
+    }
+    for namespace in nvme::namespaces() {
+        register_disk(
+            Name::new(format_args!("nvme0n{}", namespace.info().nsid)),
+            Disk::Nvme(namespace),
+        );
+    }
+}
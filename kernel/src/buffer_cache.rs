@@ -0,0 +1,208 @@
+use crate::block::{self, BlockDevice, BlockError, DeviceId};
+use crate::sync::SpinLock;
+
+// A fixed pool of block buffers shared by all devices. Writes only dirty the
+// buffer; dirty blocks reach the disk when they are evicted or synced. The
+// least recently used buffer is the one recycled. A partition and its whole
+// disk are cached separately, so writing through both at once is not
+// coherent.
+
+pub const CACHE_BLOCKS: usize = 64;
+/// Largest block size the cache holds.
+pub const MAX_BLOCK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub writebacks: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    device: Option<DeviceId>,
+    lba: u64,
+    dirty: bool,
+    last_use: u64,
+}
+
+struct Cache {
+    slots: [Slot; CACHE_BLOCKS],
+    data: [[u8; MAX_BLOCK_SIZE]; CACHE_BLOCKS],
+    clock: u64,
+    stats: CacheStats,
+}
+
+// Held across disk I/O, which may wait for interrupts.
+static CACHE: SpinLock<Cache> = SpinLock::new(Cache {
+    slots: [Slot {
+        device: None,
+        lba: 0,
+        dirty: false,
+        last_use: 0,
+    }; CACHE_BLOCKS],
+    data: [[0; MAX_BLOCK_SIZE]; CACHE_BLOCKS],
+    clock: 0,
+    stats: CacheStats {
+        hits: 0,
+        misses: 0,
+        writebacks: 0,
+    },
+});
+
+impl Cache {
+    fn write_back(&mut self, index: usize) -> Result<(), BlockError> {
+        let slot = self.slots[index];
+        if let Some(device) = slot.device
+            && slot.dirty
+        {
+            let block_size = device.block_size();
+            block::write(&device, slot.lba, &self.data[index][..block_size])?;
+            self.slots[index].dirty = false;
+            self.stats.writebacks += 1;
+        }
+        Ok(())
+    }
+
+    /// Finds the buffer of `lba`, loading it unless `load` is false, in
+    /// which case a new buffer comes zeroed.
+    fn get(&mut self, device: DeviceId, lba: u64, load: bool) -> Result<usize, BlockError> {
+        let block_size = device.block_size();
+        if block_size > MAX_BLOCK_SIZE {
+            return Err(BlockError::Unsupported);
+        }
+        if lba >= device.block_count() {
+            return Err(BlockError::OutOfRange);
+        }
+        self.clock += 1;
+        let hit = self
+            .slots
+            .iter()
+            .position(|slot| slot.device == Some(device) && slot.lba == lba);
+        if let Some(index) = hit {
+            self.stats.hits += 1;
+            self.slots[index].last_use = self.clock;
+            return Ok(index);
+        }
+
+        self.stats.misses += 1;
+        let victim = (0..CACHE_BLOCKS)
+            .min_by_key(|index| {
+                let slot = &self.slots[*index];
+                (slot.device.is_some(), slot.last_use)
+            })
+            .unwrap();
+        self.write_back(victim)?;
+        self.slots[victim].device = None;
+        let data = &mut self.data[victim][..block_size];
+        match load {
+            true => block::read(&device, lba, data)?,
+            false => data.fill(0),
+        }
+        self.slots[victim] = Slot {
+            device: Some(device),
+            lba,
+            dirty: false,
+            last_use: self.clock,
+        };
+        Ok(victim)
+    }
+}
+
+/// Runs `f` on the cached contents of block `lba`. `f` must not call back
+/// into the cache.
+pub fn read<R>(device: DeviceId, lba: u64, f: impl FnOnce(&[u8]) -> R) -> Result<R, BlockError> {
+    let mut cache = CACHE.lock();
+    let index = cache.get(device, lba, true)?;
+    Ok(f(&cache.data[index][..device.block_size()]))
+}
+
+/// Lets `f` modify block `lba` in the cache; the change is written back
+/// later.
+pub fn write<R>(
+    device: DeviceId,
+    lba: u64,
+    f: impl FnOnce(&mut [u8]) -> R,
+) -> Result<R, BlockError> {
+    modify(device, lba, true, f)
+}
+
+/// Like [`write`], for blocks that are rewritten in full: the old contents
+/// are not read, `f` starts from zeroes.
+pub fn overwrite<R>(
+    device: DeviceId,
+    lba: u64,
+    f: impl FnOnce(&mut [u8]) -> R,
+) -> Result<R, BlockError> {
+    modify(device, lba, false, f)
+}
+
+fn modify<R>(
+    device: DeviceId,
+    lba: u64,
+    load: bool,
+    f: impl FnOnce(&mut [u8]) -> R,
+) -> Result<R, BlockError> {
+    if device.read_only() {
+        return Err(BlockError::ReadOnly);
+    }
+    let mut cache = CACHE.lock();
+    let index = cache.get(device, lba, load)?;
+    cache.slots[index].dirty = true;
+    Ok(f(&mut cache.data[index][..device.block_size()]))
+}
+
+/// Writes back the dirty blocks of `device` and flushes it.
+pub fn sync(device: DeviceId) -> Result<(), BlockError> {
+    let mut cache = CACHE.lock();
+    for index in 0..CACHE_BLOCKS {
+        if cache.slots[index].device == Some(device) {
+            cache.write_back(index)?;
+        }
+    }
+    drop(cache);
+    crate::sync::block_on(device.flush())
+}
+
+/// Writes back every dirty block, flushing each device touched.
+pub fn sync_all() -> Result<(), BlockError> {
+    let mut result = Ok(());
+    for device in block::devices() {
+        let cached = CACHE
+            .lock()
+            .slots
+            .iter()
+            .any(|slot| slot.device == Some(device) && slot.dirty);
+        if cached && let Err(err) = sync(device) {
+            result = Err(err);
+        }
+    }
+    result
+}
+
+/// Drops the cached blocks of `device`, writing back dirty ones first.
+pub fn invalidate(device: DeviceId) -> Result<(), BlockError> {
+    let mut cache = CACHE.lock();
+    for index in 0..CACHE_BLOCKS {
+        if cache.slots[index].device == Some(device) {
+            cache.write_back(index)?;
+            cache.slots[index].device = None;
+        }
+    }
+    Ok(())
+}
+
+/// Drops the cached copies of `count` blocks from `lba` on without writing
+/// them back, for blocks about to be written around the cache.
+pub fn forget(device: DeviceId, lba: u64, count: u64) {
+    let mut cache = CACHE.lock();
+    for slot in cache.slots.iter_mut() {
+        if slot.device == Some(device) && (lba..lba + count).contains(&slot.lba) {
+            slot.device = None;
+        }
+    }
+}
+
+pub fn stats() -> CacheStats {
+    CACHE.lock().stats
+}
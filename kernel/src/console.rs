@@ -0,0 +1,328 @@
+use core::fmt;
+
+use bootloader_api::BootInfo;
+use bootloader_api::info::{MemoryRegionKind, PixelFormat};
+
+use crate::cursor::Cursor;
+use crate::framebuffer::{
+    CHAR_RASTER_HEIGHT, CHAR_RASTER_WIDTH, Color, FramebufferDisplay, Pixel, Position,
+};
+use crate::memory;
+use crate::port::Port;
+
+/// Additional vertical space between lines.
+const LINE_SPACING: usize = 2;
+/// Keeps text off the very edge of the screen.
+const BORDER_PADDING: usize = 1;
+
+const LINE_HEIGHT: usize = CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
+
+/// Scrolling text console drawn onto a [`FramebufferDisplay`].
+pub struct FramebufferConsole {
+    display: FramebufferDisplay<'static>,
+    x_pos: usize,
+    y_pos: usize,
+    foreground: Color,
+    /// Mouse pointer, drawn on top of the text.
+    cursor: Cursor,
+}
+
+impl FramebufferConsole {
+    pub fn new(mut display: FramebufferDisplay<'static>) -> Self {
+        display.clear(Color::BLACK);
+        FramebufferConsole {
+            display,
+            x_pos: BORDER_PADDING,
+            y_pos: BORDER_PADDING,
+            foreground: Color::WHITE,
+            cursor: Cursor::new(),
+        }
+    }
+
+    /// Runs `f` on the display with the mouse pointer taken off it.
+    pub fn with_display<R>(&mut self, f: impl FnOnce(&mut FramebufferDisplay<'static>) -> R) -> R {
+        let shown = self.cursor.is_visible();
+        self.cursor.hide(&mut self.display);
+        let result = f(&mut self.display);
+        if shown {
+            self.cursor.show(&mut self.display);
+        }
+        result
+    }
+
+    /// Moves the mouse pointer by `dx`/`dy` pixels, keeping its hotspot on
+    /// screen. The first move shows it, starting from the centre.
+    pub fn move_pointer(&mut self, dx: isize, dy: isize) {
+        let (x, y) = match self.cursor.is_visible() {
+            true => (self.cursor.position().x, self.cursor.position().y),
+            false => (self.display.width() / 2, self.display.height() / 2),
+        };
+        let clamp = |value: usize, delta: isize, limit: usize| {
+            value
+                .saturating_add_signed(delta)
+                .min(limit.saturating_sub(1))
+        };
+        let position = Position {
+            x: clamp(x, dx, self.display.width()),
+            y: clamp(y, dy, self.display.height()),
+        };
+        self.cursor.move_to(&mut self.display, position);
+        self.cursor.show(&mut self.display);
+    }
+
+    pub fn hide_pointer(&mut self) {
+        self.cursor.hide(&mut self.display);
+    }
+
+    pub fn pointer_position(&self) -> Option<Position> {
+        self.cursor.is_visible().then(|| self.cursor.position())
+    }
+
+    fn newline(&mut self) {
+        self.x_pos = BORDER_PADDING;
+        let next = self.y_pos + LINE_HEIGHT;
+        if next + LINE_HEIGHT > self.display.height() {
+            self.display.scroll_up(LINE_HEIGHT);
+        } else {
+            self.y_pos = next;
+        }
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.x_pos = BORDER_PADDING,
+            c => {
+                if self.x_pos + CHAR_RASTER_WIDTH > self.display.width() {
+                    self.newline();
+                }
+                let pixel = Pixel {
+                    position: Position {
+                        x: self.x_pos,
+                        y: self.y_pos,
+                    },
+                    color: self.foreground,
+                };
+                self.display.draw_char(c, pixel);
+                self.x_pos += CHAR_RASTER_WIDTH;
+            }
+        }
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let shown = self.cursor.is_visible();
+        self.cursor.hide(&mut self.display);
+        for c in s.chars() {
+            self.write_char(c);
+        }
+        if shown {
+            self.cursor.show(&mut self.display);
+        }
+        Ok(())
+    }
+}
+
+const VGA_TEXT_BUFFER: u64 = 0xB8000;
+const VGA_COLUMNS: usize = 80;
+const VGA_ROWS: usize = 25;
+/// Light gray on black.
+const VGA_ATTRIBUTE: u16 = 0x07 << 8;
+
+const VGA_MISC_OUTPUT_READ: u16 = 0x3CC;
+const VGA_CRTC_INDEX: u16 = 0x3D4;
+const VGA_CRTC_DATA: u16 = 0x3D5;
+const CRTC_CURSOR_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOW: u8 = 0x0F;
+
+/// Scrolling 80x25 console in VGA text mode.
+pub struct VgaTextConsole {
+    buffer: *mut u16,
+    column: usize,
+    row: usize,
+}
+
+unsafe impl Send for VgaTextConsole {}
+
+impl VgaTextConsole {
+    /// Takes over the text buffer at 0xB8000 and clears it.
+    ///
+    /// # Safety
+    ///
+    /// The adapter must be in text mode and nothing else may use the buffer.
+    pub unsafe fn new() -> Self {
+        let mut console = VgaTextConsole {
+            buffer: memory::phys_to_virt(VGA_TEXT_BUFFER).cast(),
+            column: 0,
+            row: 0,
+        };
+        for row in 0..VGA_ROWS {
+            console.clear_row(row);
+        }
+        console.update_cursor();
+        console
+    }
+
+    fn put(&mut self, row: usize, column: usize, cell: u16) {
+        unsafe {
+            self.buffer
+                .add(row * VGA_COLUMNS + column)
+                .write_volatile(cell)
+        };
+    }
+
+    fn get(&self, row: usize, column: usize) -> u16 {
+        unsafe { self.buffer.add(row * VGA_COLUMNS + column).read_volatile() }
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        for column in 0..VGA_COLUMNS {
+            self.put(row, column, VGA_ATTRIBUTE | b' ' as u16);
+        }
+    }
+
+    fn newline(&mut self) {
+        self.column = 0;
+        if self.row + 1 < VGA_ROWS {
+            self.row += 1;
+            return;
+        }
+        for row in 1..VGA_ROWS {
+            for column in 0..VGA_COLUMNS {
+                let cell = self.get(row, column);
+                self.put(row - 1, column, cell);
+            }
+        }
+        self.clear_row(VGA_ROWS - 1);
+    }
+
+    fn update_cursor(&self) {
+        let position = (self.row * VGA_COLUMNS + self.column) as u16;
+        let index = Port::<u8>::new(VGA_CRTC_INDEX);
+        let data = Port::<u8>::new(VGA_CRTC_DATA);
+        unsafe {
+            index.write(CRTC_CURSOR_HIGH);
+            data.write((position >> 8) as u8);
+            index.write(CRTC_CURSOR_LOW);
+            data.write(position as u8);
+        }
+    }
+
+    fn write_char(&mut self, c: char) {
+        match c {
+            '\n' => self.newline(),
+            '\r' => self.column = 0,
+            c => {
+                if self.column >= VGA_COLUMNS {
+                    self.newline();
+                }
+                // code page 437 shares printable ASCII; everything else is a box
+                let byte = if c.is_ascii() && !c.is_ascii_control() {
+                    c as u8
+                } else {
+                    0xFE
+                };
+                self.put(self.row, self.column, VGA_ATTRIBUTE | byte as u16);
+                self.column += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Write for VgaTextConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_char(c);
+        }
+        self.update_cursor();
+        Ok(())
+    }
+}
+
+/// Where kernel text output is shown, besides the serial port.
+// only ever one of these, and there is no heap to box the big variant on
+#[allow(clippy::large_enum_variant)]
+pub enum Console {
+    Framebuffer(FramebufferConsole),
+    VgaText(VgaTextConsole),
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self {
+            Console::Framebuffer(console) => console.write_str(s),
+            Console::VgaText(console) => console.write_str(s),
+        }
+    }
+}
+
+impl Console {
+    pub fn kind(&self) -> ConsoleKind {
+        match self {
+            Console::Framebuffer(console) => {
+                let info = console.display.info();
+                ConsoleKind::Framebuffer {
+                    width: info.width,
+                    height: info.height,
+                    pixel_format: info.pixel_format,
+                }
+            }
+            Console::VgaText(_) => ConsoleKind::VgaText {
+                columns: VGA_COLUMNS,
+                rows: VGA_ROWS,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleKind {
+    Framebuffer {
+        width: usize,
+        height: usize,
+        pixel_format: PixelFormat,
+    },
+    VgaText {
+        columns: usize,
+        rows: usize,
+    },
+}
+
+impl fmt::Display for ConsoleKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConsoleKind::Framebuffer {
+                width,
+                height,
+                pixel_format,
+            } => write!(f, "framebuffer {}x{} {:?}", width, height, pixel_format),
+            ConsoleKind::VgaText { columns, rows } => write!(f, "VGA text {}x{}", columns, rows),
+        }
+    }
+}
+
+/// Picks the best local console: the bootloader's framebuffer if there is
+/// one, VGA text mode on BIOS boots with a VGA adapter, otherwise none, in
+/// which case output only goes to serial.
+///
+/// Takes the framebuffer out of `boot_info`.
+pub fn detect(boot_info: &mut BootInfo) -> Option<Console> {
+    if let Some(framebuffer) = boot_info.framebuffer.take() {
+        let info = framebuffer.info();
+        let display = FramebufferDisplay::new(framebuffer.into_buffer(), info);
+        return Some(Console::Framebuffer(FramebufferConsole::new(display)));
+    }
+
+    let bios_boot = boot_info
+        .memory_regions
+        .iter()
+        .any(|region| matches!(region.kind, MemoryRegionKind::UnknownBios(_)));
+    // the misc output register floats high without a VGA adapter
+    let vga_present = unsafe { Port::<u8>::new(VGA_MISC_OUTPUT_READ).read() } != 0xFF;
+    if bios_boot && vga_present {
+        return Some(Console::VgaText(unsafe { VgaTextConsole::new() }));
+    }
+
+    None
+}
@@ -0,0 +1,113 @@
+use crate::framebuffer::{Color, FramebufferDisplay, Position};
+
+pub const WIDTH: usize = 12;
+pub const HEIGHT: usize = 19;
+
+/// Arrow with its hotspot in the top-left corner. `B` is outline, `W` is
+/// fill, anything else is transparent.
+const SPRITE: [&[u8; WIDTH]; HEIGHT] = [
+    b"B           ",
+    b"BB          ",
+    b"BWB         ",
+    b"BWWB        ",
+    b"BWWWB       ",
+    b"BWWWWB      ",
+    b"BWWWWWB     ",
+    b"BWWWWWWB    ",
+    b"BWWWWWWWB   ",
+    b"BWWWWWWWWB  ",
+    b"BWWWWWWWWWB ",
+    b"BWWWWWWWWWWB",
+    b"BWWWWWWBBBBB",
+    b"BWWWBWWB    ",
+    b"BWWBBWWB    ",
+    b"BWB  BWWB   ",
+    b"BB   BWWB   ",
+    b"B     BWWB  ",
+    b"       BB   ",
+];
+
+/// Mouse cursor sprite drawn over a [`FramebufferDisplay`].
+///
+/// Whatever the sprite covers is saved when it is drawn and put back when it
+/// is hidden, so the picture underneath survives. Anything else drawing to
+/// the display has to hide the cursor first.
+pub struct Cursor {
+    position: Position,
+    visible: bool,
+    saved: [Color; WIDTH * HEIGHT],
+}
+
+impl Cursor {
+    pub const fn new() -> Self {
+        Cursor {
+            position: Position { x: 0, y: 0 },
+            visible: false,
+            saved: [Color::BLACK; WIDTH * HEIGHT],
+        }
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    fn sprite_pixels(&self) -> impl Iterator<Item = (usize, Position, u8)> + use<> {
+        let origin = self.position;
+        (0..HEIGHT).flat_map(move |row| {
+            (0..WIDTH).map(move |column| {
+                let position = Position {
+                    x: origin.x + column,
+                    y: origin.y + row,
+                };
+                (row * WIDTH + column, position, SPRITE[row][column])
+            })
+        })
+    }
+
+    pub fn show(&mut self, display: &mut FramebufferDisplay) {
+        if self.visible {
+            return;
+        }
+        for (index, position, _) in self.sprite_pixels() {
+            self.saved[index] = display.get_pixel(position).unwrap_or(Color::BLACK);
+        }
+        for (_, position, pixel) in self.sprite_pixels() {
+            match pixel {
+                b'B' => display.set_pixel(position, Color::BLACK),
+                b'W' => display.set_pixel(position, Color::WHITE),
+                _ => {}
+            }
+        }
+        self.visible = true;
+    }
+
+    pub fn hide(&mut self, display: &mut FramebufferDisplay) {
+        if !self.visible {
+            return;
+        }
+        for (index, position, _) in self.sprite_pixels() {
+            display.set_pixel(position, self.saved[index]);
+        }
+        self.visible = false;
+    }
+
+    /// Moves the hotspot to `position`, redrawing the sprite if it is shown.
+    pub fn move_to(&mut self, display: &mut FramebufferDisplay, position: Position) {
+        let visible = self.visible;
+        self.hide(display);
+        self.position = position;
+        if visible {
+            self.show(display);
+        }
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
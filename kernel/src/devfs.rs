@@ -0,0 +1,363 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::random;
+use crate::sync::SpinLock;
+use crate::vfs::{
+    self, DirEntry, File, FileFlags, FileSystem, FileType, Inode, Metadata, OpenFlags, SeekFrom,
+    VfsError,
+};
+
+// Drivers register their devices here as they find them, under Linux names,
+// and devfs shows whatever is registered as one flat directory, usually
+// mounted on /dev. Opening a node hands out a file that passes reads,
+// writes and ioctls straight to the driver. A node whose device has been
+// unregistered stays usable for those who have it open, but fails with
+// NoDevice; the name itself goes at once, and a new device may take it.
+
+/// A Linux device number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceNumber {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl DeviceNumber {
+    pub const fn new(major: u32, minor: u32) -> DeviceNumber {
+        DeviceNumber { major, minor }
+    }
+
+    /// Packed the way Linux reports it in `st_rdev`.
+    pub const fn encode(self) -> u32 {
+        (self.minor & 0xff) | (self.major << 8) | ((self.minor & !0xff) << 12)
+    }
+}
+
+/// ioctl requests, numbered as on Linux.
+pub mod ioctl {
+    /// Bytes waiting to be read, as an i32.
+    pub const FIONREAD: u32 = 0x541B;
+    /// Logical block size of a disk, as an i32.
+    pub const BLKSSZGET: u32 = 0x1268;
+    /// Size of a disk in bytes, as a u64.
+    pub const BLKGETSIZE64: u32 = 0x8008_1272;
+    /// Mode of a framebuffer, as a `struct fb_var_screeninfo`.
+    pub const FBIOGET_VSCREENINFO: u32 = 0x4600;
+    /// Memory layout of a framebuffer, as a `struct fb_fix_screeninfo`.
+    pub const FBIOGET_FSCREENINFO: u32 = 0x4602;
+}
+
+/// Copies `value` to the start of an ioctl argument, for requests that
+/// return one.
+pub fn put_arg(arg: &mut [u8], value: &[u8]) -> vfs::Result<usize> {
+    arg.get_mut(..value.len())
+        .ok_or(VfsError::InvalidArgument)?
+        .copy_from_slice(value);
+    Ok(0)
+}
+
+/// The driver side of a device node. `offset` is the file position, which
+/// devices without one, such as serial ports, ignore.
+pub trait Device: Send + Sync {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize>;
+
+    /// Reads for a file opened with [`OpenFlags::NON_BLOCK`]. Devices whose
+    /// reads wait for input fail with [`VfsError::WouldBlock`] instead.
+    fn try_read(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        self.read(offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize>;
+
+    /// See [`File::ioctl`].
+    fn ioctl(&self, _request: u32, _arg: &mut [u8]) -> vfs::Result<usize> {
+        Err(VfsError::NotTty)
+    }
+
+    /// Bytes the device holds, for those with a size.
+    fn size(&self) -> u64 {
+        0
+    }
+}
+
+struct Node {
+    ino: u64,
+    kind: FileType,
+    number: DeviceNumber,
+    mode: u16,
+    device: Arc<dyn Device>,
+}
+
+static NODES: SpinLock<BTreeMap<String, Arc<Node>>> = SpinLock::new(BTreeMap::new());
+/// 1 is the root directory.
+static NEXT_INO: AtomicU64 = AtomicU64::new(2);
+
+/// Adds a character or block device as `name`, replacing any device
+/// registered under that name before.
+pub fn register(
+    name: &str,
+    kind: FileType,
+    number: DeviceNumber,
+    mode: u16,
+    device: Arc<dyn Device>,
+) {
+    debug_assert!(matches!(kind, FileType::CharDevice | FileType::BlockDevice));
+    let node = Arc::new(Node {
+        ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+        kind,
+        number,
+        mode,
+        device,
+    });
+    NODES.lock().insert(name.into(), node);
+}
+
+pub fn unregister(name: &str) {
+    NODES.lock().remove(name);
+}
+
+/// A registered device, found by name.
+struct DeviceNode {
+    name: String,
+    node: Arc<Node>,
+}
+
+impl DeviceNode {
+    /// The device, if it is still registered.
+    fn device(&self) -> vfs::Result<&Arc<dyn Device>> {
+        let registered = NODES
+            .lock()
+            .get(&self.name)
+            .is_some_and(|node| Arc::ptr_eq(node, &self.node));
+        match registered {
+            true => Ok(&self.node.device),
+            false => Err(VfsError::NoDevice),
+        }
+    }
+}
+
+impl Inode for DeviceNode {
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        Ok(Metadata {
+            ino: self.node.ino,
+            kind: self.node.kind,
+            mode: self.node.mode,
+            size: self.device().map_or(0, |device| device.size()),
+            links: 1,
+            device: self.node.number.encode(),
+            ..Metadata::default()
+        })
+    }
+
+    fn open(&self, flags: OpenFlags) -> vfs::Result<Option<Arc<dyn File>>> {
+        self.device()?;
+        let node = DeviceNode {
+            name: self.name.clone(),
+            node: self.node.clone(),
+        };
+        Ok(Some(Arc::new(DeviceFile {
+            node,
+            flags: FileFlags::new(flags),
+            position: SpinLock::new(0),
+        })))
+    }
+}
+
+/// An open device node.
+struct DeviceFile {
+    node: DeviceNode,
+    flags: FileFlags,
+    position: SpinLock<u64>,
+}
+
+impl File for DeviceFile {
+    fn read(&self, buf: &mut [u8]) -> vfs::Result<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(VfsError::BadDescriptor);
+        }
+        let device = self.node.device()?;
+        // devices may block, so the position isn't locked across the call
+        let position = *self.position.lock();
+        let read = if self.flags.contains(OpenFlags::NON_BLOCK) {
+            device.try_read(position, buf)?
+        } else {
+            device.read(position, buf)?
+        };
+        *self.position.lock() += read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> vfs::Result<usize> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(VfsError::BadDescriptor);
+        }
+        let device = self.node.device()?;
+        let position = *self.position.lock();
+        let written = device.write(position, buf)?;
+        *self.position.lock() += written as u64;
+        Ok(written)
+    }
+
+    fn seek(&self, from: SeekFrom) -> vfs::Result<u64> {
+        let device = self.node.device()?;
+        let mut position = self.position.lock();
+        let (base, delta) = match from {
+            SeekFrom::Start(offset) => (0, offset as i128),
+            SeekFrom::Current(delta) => (*position, delta as i128),
+            SeekFrom::End(delta) => (device.size(), delta as i128),
+        };
+        let target = base as i128 + delta;
+        if !(0..=i64::MAX as i128).contains(&target) {
+            return Err(VfsError::InvalidArgument);
+        }
+        *position = target as u64;
+        Ok(*position)
+    }
+
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> vfs::Result<usize> {
+        self.node.device()?.ioctl(request, arg)
+    }
+
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        self.node.metadata()
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+}
+
+/// The one directory of devfs.
+struct Root;
+
+impl Inode for Root {
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        Ok(Metadata {
+            ino: 1,
+            kind: FileType::Directory,
+            mode: 0o755,
+            size: NODES.lock().len() as u64,
+            links: 2,
+            ..Metadata::default()
+        })
+    }
+
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let node = NODES.lock().get(name).cloned().ok_or(VfsError::NotFound)?;
+        Ok(Arc::new(DeviceNode {
+            name: name.into(),
+            node,
+        }))
+    }
+
+    fn revalidate(&self, name: &str, inode: &Arc<dyn Inode>) -> bool {
+        let Ok(metadata) = inode.metadata() else {
+            return false;
+        };
+        NODES
+            .lock()
+            .get(name)
+            .is_some_and(|node| node.ino == metadata.ino)
+    }
+
+    // the cursor counts entries, so names registered or removed during a
+    // read may shift the rest
+    fn read_dir(&self, cursor: u64) -> vfs::Result<Option<(DirEntry, u64)>> {
+        let nodes = NODES.lock();
+        let Some((name, node)) = nodes.iter().nth(cursor as usize) else {
+            return Ok(None);
+        };
+        let entry = DirEntry {
+            ino: node.ino,
+            kind: node.kind,
+            name: name.clone(),
+        };
+        Ok(Some((entry, cursor + 1)))
+    }
+}
+
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+/// `/dev/null`: swallows writes, reads nothing.
+struct Null;
+
+impl Device for Null {
+    fn read(&self, _offset: u64, _buf: &mut [u8]) -> vfs::Result<usize> {
+        Ok(0)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/zero`: swallows writes, reads zeroes.
+struct Zero;
+
+impl Device for Zero {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// `/dev/random` and `/dev/urandom`. Writes are accepted and dropped, as
+/// there is no pool to mix them into.
+struct Random;
+
+impl Device for Random {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        random::fill(buf);
+        Ok(buf.len())
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// Registers the memory devices and mounts devfs on `/dev`.
+pub fn init() {
+    let memory_devices: [(&str, u32, Arc<dyn Device>); 4] = [
+        ("null", 3, Arc::new(Null)),
+        ("zero", 5, Arc::new(Zero)),
+        ("random", 8, Arc::new(Random)),
+        ("urandom", 9, Arc::new(Random)),
+    ];
+    for (name, minor, device) in memory_devices {
+        let number = DeviceNumber::new(1, minor);
+        register(name, FileType::CharDevice, number, 0o666, device);
+    }
+
+    match vfs::mkdir("/dev", 0o755) {
+        Ok(()) | Err(VfsError::Exists) => {}
+        Err(err) => {
+            log::error!("devfs: cannot create /dev: {}", err);
+            return;
+        }
+    }
+    match vfs::mount("/dev", "devfs", Arc::new(DevFs)) {
+        Ok(()) => log::info!("devfs: {} devices", NODES.lock().len()),
+        Err(err) => log::error!("devfs: cannot mount /dev: {}", err),
+    }
+}
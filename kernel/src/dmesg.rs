@@ -0,0 +1,227 @@
+use core::fmt;
+use core::time::Duration;
+
+use crate::sync::IrqSpinLock;
+
+// ```text
+//        tail (oldest)                     head (next write)
+//          │                                 │
+//   ┌──────▼──────┬──────────┬───────────────▼──────┬──────┐
+//   │ hdr │ text  │ hdr │ txt│ ...                  │ wrap │
+//   └─────────────┴──────────┴──────────────────────┴──────┘
+// ```
+//
+// Records are stored back to back in a static byte array and never straddle
+// the end of it: a record that does not fit is preceded by a wrap marker (or
+// by nothing, if not even a header fits) and starts again at offset 0. The
+// oldest records are evicted to make room. Positions are monotonic byte
+// counters, so a reader can tell whether its cursor has been overwritten.
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Longest text kept per record; anything beyond is cut off.
+pub const MAX_TEXT_LEN: usize = 512;
+
+const HEADER_SIZE: usize = 20;
+const WRAP_MARKER: u16 = u16::MAX;
+
+static LOG_BUFFER: IrqSpinLock<LogBuffer> = IrqSpinLock::new(LogBuffer::new());
+
+/// A copy of one log record.
+#[derive(Clone)]
+pub struct Record {
+    pub seq: u64,
+    /// Time since boot when the record was logged.
+    pub timestamp: Duration,
+    pub level: log::Level,
+    text: [u8; MAX_TEXT_LEN],
+    len: usize,
+}
+
+impl Record {
+    pub fn text(&self) -> &str {
+        unsafe { core::str::from_utf8_unchecked(&self.text[..self.len]) }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}] {:5}: {}",
+            self.timestamp.as_secs(),
+            self.timestamp.subsec_micros(),
+            self.level,
+            self.text()
+        )
+    }
+}
+
+struct LogBuffer {
+    data: [u8; BUFFER_SIZE],
+    head: u64,
+    tail: u64,
+    next_seq: u64,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        LogBuffer {
+            data: [0; BUFFER_SIZE],
+            head: 0,
+            tail: 0,
+            next_seq: 0,
+        }
+    }
+
+    fn offset(pos: u64) -> usize {
+        (pos % BUFFER_SIZE as u64) as usize
+    }
+
+    /// Bytes from `pos` to the physical end of the buffer.
+    fn room_to_end(pos: u64) -> u64 {
+        (BUFFER_SIZE - Self::offset(pos)) as u64
+    }
+
+    /// Returns the text length of the record at `pos`, or `None` if the
+    /// reader has to skip to the start of the buffer.
+    fn text_len_at(&self, pos: u64) -> Option<usize> {
+        let offset = Self::offset(pos);
+        if BUFFER_SIZE - offset < HEADER_SIZE {
+            return None;
+        }
+        let len = u16::from_le_bytes([self.data[offset + 18], self.data[offset + 19]]);
+        (len != WRAP_MARKER).then_some(len as usize)
+    }
+
+    fn evict_oldest(&mut self) {
+        match self.text_len_at(self.tail) {
+            Some(len) => self.tail += (HEADER_SIZE + len) as u64,
+            None => self.tail += Self::room_to_end(self.tail),
+        }
+    }
+
+    fn push(&mut self, timestamp: Duration, level: log::Level, text: &str) -> u64 {
+        let mut len = text.len().min(MAX_TEXT_LEN);
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        let size = (HEADER_SIZE + len) as u64;
+
+        let wraps = Self::room_to_end(self.head) < size;
+        let start = if wraps {
+            self.head + Self::room_to_end(self.head)
+        } else {
+            self.head
+        };
+        while start + size - self.tail > BUFFER_SIZE as u64 {
+            self.evict_oldest();
+        }
+
+        if wraps && Self::room_to_end(self.head) >= HEADER_SIZE as u64 {
+            let offset = Self::offset(self.head);
+            self.data[offset + 18..offset + 20].copy_from_slice(&WRAP_MARKER.to_le_bytes());
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let offset = Self::offset(start);
+        let header = &mut self.data[offset..offset + HEADER_SIZE];
+        header[0..8].copy_from_slice(&seq.to_le_bytes());
+        header[8..16].copy_from_slice(&(timestamp.as_nanos() as u64).to_le_bytes());
+        header[16] = level as u8;
+        header[17] = 0;
+        header[18..20].copy_from_slice(&(len as u16).to_le_bytes());
+        self.data[offset + HEADER_SIZE..offset + HEADER_SIZE + len]
+            .copy_from_slice(&text.as_bytes()[..len]);
+
+        self.head = start + size;
+        seq
+    }
+
+    /// Copies out the first record at or after `pos` and returns it together
+    /// with the position of the one after it.
+    fn read(&self, mut pos: u64) -> Option<(Record, u64)> {
+        pos = pos.max(self.tail);
+        while pos < self.head {
+            let Some(len) = self.text_len_at(pos) else {
+                pos += Self::room_to_end(pos);
+                continue;
+            };
+            let offset = Self::offset(pos);
+            let header = &self.data[offset..offset + HEADER_SIZE];
+            let mut record = Record {
+                seq: u64::from_le_bytes(header[0..8].try_into().unwrap()),
+                timestamp: Duration::from_nanos(u64::from_le_bytes(
+                    header[8..16].try_into().unwrap(),
+                )),
+                level: level_from_u8(header[16]),
+                text: [0; MAX_TEXT_LEN],
+                len,
+            };
+            record.text[..len]
+                .copy_from_slice(&self.data[offset + HEADER_SIZE..offset + HEADER_SIZE + len]);
+            return Some((record, pos + (HEADER_SIZE + len) as u64));
+        }
+        None
+    }
+}
+
+pub(crate) fn level_from_u8(level: u8) -> log::Level {
+    match level {
+        1 => log::Level::Error,
+        2 => log::Level::Warn,
+        3 => log::Level::Info,
+        4 => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
+
+/// Appends a record and returns its sequence number.
+///
+/// Returns `None` without blocking if the buffer is locked, which can only
+/// happen when an NMI or exception interrupts a reader.
+pub fn try_push(timestamp: Duration, level: log::Level, text: &str) -> Option<u64> {
+    let mut buffer = LOG_BUFFER.try_lock()?;
+    Some(buffer.push(timestamp, level, text))
+}
+
+/// Iterator over every record still in the buffer, oldest first.
+///
+/// The lock is only taken while copying out a single record, so logging from
+/// the loop body is fine. Records evicted while iterating are skipped.
+pub fn records() -> Records {
+    Records { pos: 0 }
+}
+
+pub struct Records {
+    pos: u64,
+}
+
+impl Iterator for Records {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let (record, next) = LOG_BUFFER.lock().read(self.pos)?;
+        self.pos = next;
+        Some(record)
+    }
+}
+
+/// Writes every record, one per line. This is what `/proc/dmesg` shows.
+pub fn print_all(out: &mut impl fmt::Write) -> fmt::Result {
+    for record in records() {
+        writeln!(out, "{}", record)?;
+    }
+    Ok(())
+}
+
+/// Releases the buffer lock for the panic-time dump.
+///
+/// # Safety
+///
+/// Whoever held the lock must never run again.
+pub unsafe fn force_unlock() {
+    unsafe { LOG_BUFFER.force_unlock() };
+}
@@ -0,0 +1,1523 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block::{self, BlockDevice, DeviceId};
+use crate::buffer_cache;
+use crate::page_cache::{Backing, PAGE_SIZE, Page, PageCache};
+use crate::sync::SpinLock;
+use crate::time;
+use crate::vfs::{self, Attributes, DirEntry, FileSystem, FileType, Inode, Metadata, VfsError};
+
+// The second extended filesystem, revisions 0 and 1, as far as mke2fs
+// formats it by default. Inodes, bitmaps and directory blocks are read and
+// written field by field through the buffer cache; only the superblock
+// geometry is kept in memory. The data of regular files goes through the
+// page cache instead, and from there straight to the disk. Blocks are
+// allocated when a write reaches them, so that a full disk fails the write,
+// and otherwise when a page written through a mapping is written back.
+// Files map their blocks through 12 direct pointers and a single, double
+// and triple indirect block. Directories are linked lists of entries;
+// directories that carry an htree index lose the flag when they are
+// changed, which makes Linux fall back to a linear scan.
+//
+// One lock serialises all operations on a volume. An inode whose last link
+// goes while it is open moves to the orphan list, as on ext3, and is freed
+// once its last node is dropped; a mount frees what a crash left there.
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xEF53;
+const ROOT_INO: u32 = 2;
+const GOOD_OLD_REVISION: u32 = 0;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INO: u32 = 11;
+const DESCRIPTOR_SIZE: u64 = 32;
+const DIRECT_BLOCKS: u64 = 12;
+/// Symlink targets shorter than this live in the block pointers.
+const FAST_SYMLINK_MAX: usize = 60;
+const MAX_NAME: usize = 255;
+/// The most names an inode can have, as on Linux.
+const MAX_LINKS: u16 = 65000;
+
+/// Features the driver understands; a volume with other incompatible ones
+/// is refused, one with other read-only compatible ones mounted read-only.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+
+const INDEX_FLAG: u32 = 0x1000;
+
+mod mode {
+    pub const TYPE_MASK: u16 = 0xF000;
+    pub const FIFO: u16 = 0x1000;
+    pub const CHAR_DEVICE: u16 = 0x2000;
+    pub const DIRECTORY: u16 = 0x4000;
+    pub const BLOCK_DEVICE: u16 = 0x6000;
+    pub const REGULAR: u16 = 0x8000;
+    pub const SYMLINK: u16 = 0xA000;
+}
+
+/// Inode field offsets.
+mod field {
+    pub const MODE: usize = 0;
+    pub const UID: usize = 2;
+    pub const SIZE: usize = 4;
+    pub const ATIME: usize = 8;
+    pub const CTIME: usize = 12;
+    pub const MTIME: usize = 16;
+    pub const DTIME: usize = 20;
+    pub const GID: usize = 24;
+    pub const LINKS: usize = 26;
+    pub const BLOCKS: usize = 28;
+    pub const FLAGS: usize = 32;
+    pub const BLOCK: usize = 40;
+    pub const SIZE_HIGH: usize = 108;
+    pub const UID_HIGH: usize = 120;
+    pub const GID_HIGH: usize = 122;
+}
+
+/// Group descriptor field offsets.
+mod descriptor {
+    pub const BLOCK_BITMAP: u64 = 0;
+    pub const INODE_BITMAP: u64 = 4;
+    pub const INODE_TABLE: u64 = 8;
+    pub const FREE_BLOCKS: u64 = 12;
+    pub const FREE_INODES: u64 = 14;
+    pub const USED_DIRS: u64 = 16;
+}
+
+/// Superblock field offsets.
+mod superblock {
+    pub const FREE_BLOCKS: u64 = 12;
+    pub const FREE_INODES: u64 = 16;
+    /// First inode of the orphan list, chained through their DTIME.
+    pub const LAST_ORPHAN: u64 = 232;
+}
+
+fn file_type(mode: u16) -> FileType {
+    match mode & mode::TYPE_MASK {
+        mode::DIRECTORY => FileType::Directory,
+        mode::SYMLINK => FileType::Symlink,
+        mode::CHAR_DEVICE => FileType::CharDevice,
+        mode::BLOCK_DEVICE => FileType::BlockDevice,
+        mode::FIFO => FileType::Fifo,
+        _ => FileType::Regular,
+    }
+}
+
+/// The type byte of a directory entry.
+fn entry_type(kind: FileType) -> u8 {
+    match kind {
+        FileType::Regular => 1,
+        FileType::Directory => 2,
+        FileType::CharDevice => 3,
+        FileType::BlockDevice => 4,
+        FileType::Fifo => 5,
+        FileType::Symlink => 7,
+    }
+}
+
+fn entry_kind(kind: u8) -> FileType {
+    match kind {
+        2 => FileType::Directory,
+        3 => FileType::CharDevice,
+        4 => FileType::BlockDevice,
+        5 => FileType::Fifo,
+        7 => FileType::Symlink,
+        _ => FileType::Regular,
+    }
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+/// Length a directory entry with a `name_len` byte name needs.
+fn entry_len(name_len: usize) -> usize {
+    (8 + name_len).next_multiple_of(4)
+}
+
+/// The fixed part of an inode, the first 128 bytes.
+#[derive(Clone, Copy)]
+struct RawInode([u8; GOOD_OLD_INODE_SIZE]);
+
+impl RawInode {
+    fn u16(&self, offset: usize) -> u16 {
+        le16(&self.0[offset..])
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        le32(&self.0[offset..])
+    }
+
+    fn set_u16(&mut self, offset: usize, value: u16) {
+        self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn set_u32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn mode(&self) -> u16 {
+        self.u16(field::MODE)
+    }
+
+    fn kind(&self) -> FileType {
+        file_type(self.mode())
+    }
+
+    fn size(&self) -> u64 {
+        let high = match self.kind() {
+            FileType::Regular => self.u32(field::SIZE_HIGH) as u64,
+            _ => 0,
+        };
+        high << 32 | self.u32(field::SIZE) as u64
+    }
+
+    fn set_size(&mut self, size: u64) {
+        self.set_u32(field::SIZE, size as u32);
+        if self.kind() == FileType::Regular {
+            self.set_u32(field::SIZE_HIGH, (size >> 32) as u32);
+        }
+    }
+
+    fn links(&self) -> u16 {
+        self.u16(field::LINKS)
+    }
+
+    fn block(&self, slot: usize) -> u32 {
+        self.u32(field::BLOCK + 4 * slot)
+    }
+
+    fn set_block(&mut self, slot: usize, block: u32) {
+        self.set_u32(field::BLOCK + 4 * slot, block);
+    }
+
+    /// Adds `delta` 512-byte sectors to the space used.
+    fn add_sectors(&mut self, delta: i64) {
+        let sectors = self.u32(field::BLOCKS) as i64 + delta;
+        self.set_u32(field::BLOCKS, sectors as u32);
+    }
+
+    /// Sets the timestamps at `offsets` to the current time.
+    fn stamp(&mut self, offsets: &[usize]) {
+        let now = time::now() as u32;
+        for offset in offsets {
+            self.set_u32(*offset, now);
+        }
+    }
+
+    /// Whether the symlink target is stored in the block pointers.
+    fn is_fast_symlink(&self) -> bool {
+        self.kind() == FileType::Symlink && self.u32(field::BLOCKS) == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    block_size: u64,
+    /// Block holding the superblock, 1 with 1 KiB blocks and 0 otherwise.
+    first_data_block: u32,
+    blocks: u32,
+    inodes: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    groups: u32,
+    inode_size: u64,
+    first_ino: u32,
+    /// First block of the group descriptor table.
+    descriptors: u64,
+}
+
+struct State {
+    /// Group the last block came from, where the next search starts.
+    block_goal: u32,
+}
+
+/// A mounted ext2 volume.
+pub struct Ext2Fs {
+    device: DeviceId,
+    geometry: Geometry,
+    read_only: bool,
+    state: SpinLock<State>,
+    /// Nodes handed out, by inode number, so that an inode has only one.
+    nodes: SpinLock<BTreeMap<u32, Weak<Ext2Node>>>,
+    this: Weak<Ext2Fs>,
+}
+
+impl Ext2Fs {
+    /// Opens the ext2 volume on `device`.
+    pub fn new(device: DeviceId) -> vfs::Result<Arc<Ext2Fs>> {
+        let mut raw = [0u8; 1024];
+        read_bytes(device, SUPERBLOCK_OFFSET, &mut raw)?;
+        if le16(&raw[56..]) != MAGIC {
+            return Err(VfsError::InvalidArgument);
+        }
+        let revision = le32(&raw[76..]);
+        let (inode_size, first_ino, incompat, ro_compat) = match revision {
+            GOOD_OLD_REVISION => (GOOD_OLD_INODE_SIZE as u64, GOOD_OLD_FIRST_INO, 0, 0),
+            _ => (
+                le16(&raw[88..]) as u64,
+                le32(&raw[84..]),
+                le32(&raw[96..]),
+                le32(&raw[100..]),
+            ),
+        };
+        if incompat & !INCOMPAT_FILETYPE != 0 {
+            log::warn!("{}: ext2 features {:#x} not supported", device, incompat);
+            return Err(VfsError::Unsupported);
+        }
+        let log_block_size = le32(&raw[24..]);
+        let blocks = le32(&raw[4..]);
+        let first_data_block = le32(&raw[20..]);
+        let blocks_per_group = le32(&raw[32..]);
+        let inodes_per_group = le32(&raw[40..]);
+        if log_block_size > 2
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < GOOD_OLD_INODE_SIZE as u64
+            || !inode_size.is_power_of_two()
+        {
+            return Err(VfsError::InvalidArgument);
+        }
+        let block_size = 1024 << log_block_size;
+        if blocks as u64 * block_size > device.block_count() * device.block_size() as u64 {
+            return Err(VfsError::InvalidArgument);
+        }
+        let geometry = Geometry {
+            block_size,
+            first_data_block,
+            blocks,
+            inodes: le32(&raw[0..]),
+            blocks_per_group,
+            inodes_per_group,
+            groups: blocks
+                .checked_sub(first_data_block)
+                .ok_or(VfsError::Corrupted)?
+                .div_ceil(blocks_per_group),
+            inode_size,
+            first_ino,
+            descriptors: first_data_block as u64 + 1,
+        };
+        let read_only =
+            device.read_only() || ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE) != 0;
+        if read_only && !device.read_only() {
+            log::info!(
+                "{}: ext2 features {:#x}, mounting read-only",
+                device,
+                ro_compat
+            );
+        }
+        log::info!(
+            "{}: ext2 volume, {} blocks of {} bytes in {} groups",
+            device,
+            blocks,
+            block_size,
+            geometry.groups
+        );
+        let fs = Arc::new_cyclic(|this| Ext2Fs {
+            device,
+            geometry,
+            read_only,
+            state: SpinLock::new(State { block_goal: 0 }),
+            nodes: SpinLock::new(BTreeMap::new()),
+            this: this.clone(),
+        });
+        if !read_only {
+            fs.release_orphans()?;
+        }
+        Ok(fs)
+    }
+
+    /// Free blocks and inodes, from the superblock.
+    pub fn free_counts(&self) -> vfs::Result<(u32, u32)> {
+        let _state = self.state.lock();
+        Ok((
+            self.read_u32(SUPERBLOCK_OFFSET + superblock::FREE_BLOCKS)?,
+            self.read_u32(SUPERBLOCK_OFFSET + superblock::FREE_INODES)?,
+        ))
+    }
+
+    fn read_u32(&self, position: u64) -> vfs::Result<u32> {
+        let mut bytes = [0u8; 4];
+        read_bytes(self.device, position, &mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn write_u32(&self, position: u64, value: u32) -> vfs::Result<()> {
+        write_bytes(self.device, position, &value.to_le_bytes())
+    }
+
+    /// Adds `delta` to the 16-bit field at `position`.
+    fn adjust_u16(&self, position: u64, delta: i32) -> vfs::Result<()> {
+        let mut bytes = [0u8; 2];
+        read_bytes(self.device, position, &mut bytes)?;
+        let value = (u16::from_le_bytes(bytes) as i32 + delta) as u16;
+        write_bytes(self.device, position, &value.to_le_bytes())
+    }
+
+    fn adjust_u32(&self, position: u64, delta: i64) -> vfs::Result<()> {
+        let value = self.read_u32(position)? as i64 + delta;
+        self.write_u32(position, value as u32)
+    }
+
+    fn block_position(&self, block: u32) -> u64 {
+        block as u64 * self.geometry.block_size
+    }
+
+    fn descriptor(&self, group: u32) -> u64 {
+        self.block_position(self.geometry.descriptors as u32) + group as u64 * DESCRIPTOR_SIZE
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> vfs::Result<()> {
+        read_bytes(self.device, self.block_position(block), buf)
+    }
+
+    fn write_block(&self, block: u32, data: &[u8]) -> vfs::Result<()> {
+        write_bytes(self.device, self.block_position(block), data)
+    }
+
+    /// The disk blocks of filesystem block `block`, unless the disk's
+    /// blocks are bigger.
+    fn device_blocks(&self, block: u32) -> Option<(u64, u64)> {
+        let device_block = self.device.block_size() as u64;
+        let block_size = self.geometry.block_size;
+        block_size.is_multiple_of(device_block).then(|| {
+            (
+                self.block_position(block) / device_block,
+                block_size / device_block,
+            )
+        })
+    }
+
+    /// Reads a block of file data, bypassing the buffer cache where the
+    /// disk allows.
+    fn read_file_block(&self, block: u32, buf: &mut [u8]) -> vfs::Result<()> {
+        match self.device_blocks(block) {
+            Some((lba, _)) => Ok(block::read(&self.device, lba, buf)?),
+            None => self.read_block(block, buf),
+        }
+    }
+
+    /// Writes a block of file data, dropping any copy the buffer cache has
+    /// from when the block held metadata.
+    fn write_file_block(&self, block: u32, data: &[u8]) -> vfs::Result<()> {
+        match self.device_blocks(block) {
+            Some((lba, count)) => {
+                buffer_cache::forget(self.device, lba, count);
+                Ok(block::write(&self.device, lba, data)?)
+            }
+            None => self.write_block(block, data),
+        }
+    }
+
+    fn inode_position(&self, ino: u32) -> vfs::Result<u64> {
+        if ino == 0 || ino > self.geometry.inodes {
+            return Err(VfsError::Io);
+        }
+        let group = (ino - 1) / self.geometry.inodes_per_group;
+        let index = (ino - 1) % self.geometry.inodes_per_group;
+        let table = self.read_u32(self.descriptor(group) + descriptor::INODE_TABLE)?;
+        Ok(self.block_position(table) + index as u64 * self.geometry.inode_size)
+    }
+
+    fn read_inode(&self, ino: u32) -> vfs::Result<RawInode> {
+        let mut inode = RawInode([0; GOOD_OLD_INODE_SIZE]);
+        read_bytes(self.device, self.inode_position(ino)?, &mut inode.0)?;
+        Ok(inode)
+    }
+
+    fn write_inode(&self, ino: u32, inode: &RawInode) -> vfs::Result<()> {
+        write_bytes(self.device, self.inode_position(ino)?, &inode.0)
+    }
+
+    /// Takes a clear bit of the bitmap in `block`, among its first `count`.
+    fn take_bit(&self, block: u32, count: u32) -> vfs::Result<Option<u32>> {
+        let mut bitmap = vec![0u8; self.geometry.block_size as usize];
+        self.read_block(block, &mut bitmap)?;
+        let Some(bit) = (0..count).find(|bit| bitmap[*bit as usize / 8] & 1 << (bit % 8) == 0)
+        else {
+            return Ok(None);
+        };
+        let byte = bitmap[bit as usize / 8] | 1 << (bit % 8);
+        write_bytes(
+            self.device,
+            self.block_position(block) + bit as u64 / 8,
+            &[byte],
+        )?;
+        Ok(Some(bit))
+    }
+
+    fn clear_bit(&self, block: u32, bit: u32) -> vfs::Result<()> {
+        let position = self.block_position(block) + bit as u64 / 8;
+        let mut byte = [0u8];
+        read_bytes(self.device, position, &mut byte)?;
+        if byte[0] & 1 << (bit % 8) == 0 {
+            log::warn!("{}: freeing a free ext2 object", self.device);
+            return Err(VfsError::Io);
+        }
+        write_bytes(self.device, position, &[byte[0] & !(1 << (bit % 8))])
+    }
+
+    /// Allocates a zeroed block, preferring the group of the last one.
+    fn allocate_block(&self, state: &mut State) -> vfs::Result<u32> {
+        let groups = self.geometry.groups;
+        for group in (state.block_goal..groups).chain(0..state.block_goal) {
+            let descriptor = self.descriptor(group);
+            if self.read_u32(descriptor + descriptor::FREE_BLOCKS)? & 0xFFFF == 0 {
+                continue;
+            }
+            let first = self.geometry.first_data_block + group * self.geometry.blocks_per_group;
+            let count = self
+                .geometry
+                .blocks_per_group
+                .min(self.geometry.blocks - first);
+            let bitmap = self.read_u32(descriptor + descriptor::BLOCK_BITMAP)?;
+            let Some(bit) = self.take_bit(bitmap, count)? else {
+                continue;
+            };
+            self.adjust_u16(descriptor + descriptor::FREE_BLOCKS, -1)?;
+            self.adjust_u32(SUPERBLOCK_OFFSET + superblock::FREE_BLOCKS, -1)?;
+            state.block_goal = group;
+            let block = first + bit;
+            self.write_block(block, &vec![0; self.geometry.block_size as usize])?;
+            return Ok(block);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_block(&self, block: u32) -> vfs::Result<()> {
+        let index = block - self.geometry.first_data_block;
+        let group = index / self.geometry.blocks_per_group;
+        let descriptor = self.descriptor(group);
+        let bitmap = self.read_u32(descriptor + descriptor::BLOCK_BITMAP)?;
+        self.clear_bit(bitmap, index % self.geometry.blocks_per_group)?;
+        self.adjust_u16(descriptor + descriptor::FREE_BLOCKS, 1)?;
+        self.adjust_u32(SUPERBLOCK_OFFSET + superblock::FREE_BLOCKS, 1)
+    }
+
+    /// Allocates an inode near `parent`, spreading directories over the
+    /// groups with the most free inodes.
+    fn allocate_inode(&self, parent: u32, directory: bool) -> vfs::Result<u32> {
+        let groups = self.geometry.groups;
+        let parent_group = (parent - 1) / self.geometry.inodes_per_group;
+        let mut order: Vec<u32> = (parent_group..groups).chain(0..parent_group).collect();
+        if directory {
+            let mut free = Vec::with_capacity(order.len());
+            for group in &order {
+                let descriptor = self.descriptor(*group);
+                free.push((
+                    self.read_u32(descriptor + descriptor::FREE_INODES)? & 0xFFFF,
+                    *group,
+                ));
+            }
+            free.sort_by_key(|(count, _)| core::cmp::Reverse(*count));
+            order = free.into_iter().map(|(_, group)| group).collect();
+        }
+        for group in order {
+            let descriptor = self.descriptor(group);
+            if self.read_u32(descriptor + descriptor::FREE_INODES)? & 0xFFFF == 0 {
+                continue;
+            }
+            let bitmap = self.read_u32(descriptor + descriptor::INODE_BITMAP)?;
+            let Some(bit) = self.take_bit(bitmap, self.geometry.inodes_per_group)? else {
+                continue;
+            };
+            let ino = group * self.geometry.inodes_per_group + bit + 1;
+            if ino < self.geometry.first_ino {
+                // reserved inodes are marked used by mkfs; this one wasn't
+                log::warn!("{}: reserved inode {} was free", self.device, ino);
+                continue;
+            }
+            self.adjust_u16(descriptor + descriptor::FREE_INODES, -1)?;
+            if directory {
+                self.adjust_u16(descriptor + descriptor::USED_DIRS, 1)?;
+            }
+            self.adjust_u32(SUPERBLOCK_OFFSET + superblock::FREE_INODES, -1)?;
+            let zero = vec![0u8; self.geometry.inode_size as usize];
+            write_bytes(self.device, self.inode_position(ino)?, &zero)?;
+            return Ok(ino);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_inode(&self, ino: u32, directory: bool) -> vfs::Result<()> {
+        let group = (ino - 1) / self.geometry.inodes_per_group;
+        let descriptor = self.descriptor(group);
+        let bitmap = self.read_u32(descriptor + descriptor::INODE_BITMAP)?;
+        self.clear_bit(bitmap, (ino - 1) % self.geometry.inodes_per_group)?;
+        self.adjust_u16(descriptor + descriptor::FREE_INODES, 1)?;
+        if directory {
+            self.adjust_u16(descriptor + descriptor::USED_DIRS, -1)?;
+        }
+        self.adjust_u32(SUPERBLOCK_OFFSET + superblock::FREE_INODES, 1)
+    }
+
+    fn pointers_per_block(&self) -> u64 {
+        self.geometry.block_size / 4
+    }
+
+    /// The block holding block `index` of `inode`, allocating it and the
+    /// indirect blocks on the way if `allocate` is set. None is a hole.
+    fn map(
+        &self,
+        state: &mut State,
+        inode: &mut RawInode,
+        index: u64,
+        allocate: bool,
+    ) -> vfs::Result<Option<u32>> {
+        let per = self.pointers_per_block();
+        let (slot, depth, mut rest) = if index < DIRECT_BLOCKS {
+            (index as usize, 0, 0)
+        } else if index - DIRECT_BLOCKS < per {
+            (12, 1, index - DIRECT_BLOCKS)
+        } else if index - DIRECT_BLOCKS - per < per * per {
+            (13, 2, index - DIRECT_BLOCKS - per)
+        } else if index - DIRECT_BLOCKS - per - per * per < per * per * per {
+            (14, 3, index - DIRECT_BLOCKS - per - per * per)
+        } else {
+            return Err(VfsError::NoSpace);
+        };
+        let sectors = (self.geometry.block_size / 512) as i64;
+
+        let mut block = inode.block(slot);
+        if block == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+            block = self.allocate_block(state)?;
+            inode.set_block(slot, block);
+            inode.add_sectors(sectors);
+        }
+        for level in (0..depth).rev() {
+            let span = per.pow(level);
+            let position = self.block_position(block) + rest / span * 4;
+            rest %= span;
+            let mut next = self.read_u32(position)?;
+            if next == 0 {
+                if !allocate {
+                    return Ok(None);
+                }
+                next = self.allocate_block(state)?;
+                self.write_u32(position, next)?;
+                inode.add_sectors(sectors);
+            }
+            block = next;
+        }
+        Ok(Some(block))
+    }
+
+    /// Frees what the pointer block `block` of the given depth maps from
+    /// block `start` of its range on, and `block` itself if that is all.
+    fn free_tree(
+        &self,
+        inode: &mut RawInode,
+        block: u32,
+        depth: u32,
+        start: u64,
+    ) -> vfs::Result<()> {
+        let per = self.pointers_per_block();
+        let span = per.pow(depth - 1);
+        let sectors = (self.geometry.block_size / 512) as i64;
+        let mut pointers = vec![0u8; self.geometry.block_size as usize];
+        self.read_block(block, &mut pointers)?;
+        for (index, pointer) in pointers.as_chunks_mut::<4>().0.iter_mut().enumerate() {
+            let entry = u32::from_le_bytes(*pointer);
+            let first = index as u64 * span;
+            if entry == 0 || first + span <= start {
+                continue;
+            }
+            let from = start.saturating_sub(first);
+            if depth > 1 {
+                self.free_tree(inode, entry, depth - 1, from)?;
+            } else {
+                self.free_block(entry)?;
+                inode.add_sectors(-sectors);
+            }
+            if from == 0 {
+                *pointer = [0; 4];
+            }
+        }
+        if start == 0 {
+            self.free_block(block)?;
+            inode.add_sectors(-sectors);
+        } else {
+            self.write_block(block, &pointers)?;
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks of `inode` from block `keep` on.
+    fn free_blocks(&self, inode: &mut RawInode, keep: u64) -> vfs::Result<()> {
+        if inode.is_fast_symlink() {
+            return Ok(());
+        }
+        let sectors = (self.geometry.block_size / 512) as i64;
+        for slot in keep.min(DIRECT_BLOCKS)..DIRECT_BLOCKS {
+            let block = inode.block(slot as usize);
+            if block != 0 {
+                self.free_block(block)?;
+                inode.add_sectors(-sectors);
+                inode.set_block(slot as usize, 0);
+            }
+        }
+        let per = self.pointers_per_block();
+        let mut first = DIRECT_BLOCKS;
+        for (slot, depth) in [(12, 1), (13, 2), (14, 3)] {
+            let span = per.pow(depth);
+            let block = inode.block(slot);
+            if block != 0 && keep < first + span {
+                let from = keep.saturating_sub(first);
+                self.free_tree(inode, block, depth, from)?;
+                if from == 0 {
+                    inode.set_block(slot, 0);
+                }
+            }
+            first += span;
+        }
+        Ok(())
+    }
+
+    /// Reads bytes of a file, holes reading as zeroes.
+    fn read_data(
+        &self,
+        state: &mut State,
+        inode: &mut RawInode,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> vfs::Result<usize> {
+        let size = inode.size();
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let block_size = self.geometry.block_size;
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let count = ((block_size - within) as usize).min(len - done);
+            let chunk = &mut buf[done..done + count];
+            match self.map(state, inode, position / block_size, false)? {
+                Some(block) => read_bytes(self.device, self.block_position(block) + within, chunk)?,
+                None => chunk.fill(0),
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn write_data(
+        &self,
+        state: &mut State,
+        inode: &mut RawInode,
+        offset: u64,
+        data: &[u8],
+    ) -> vfs::Result<()> {
+        let block_size = self.geometry.block_size;
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let within = position % block_size;
+            let count = ((block_size - within) as usize).min(data.len() - done);
+            let block = self
+                .map(state, inode, position / block_size, true)?
+                .ok_or(VfsError::Io)?;
+            write_bytes(
+                self.device,
+                self.block_position(block) + within,
+                &data[done..done + count],
+            )?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with each entry of the directory `inode` from byte
+    /// `start` on: its position, inode, type byte and name.
+    fn scan<R>(
+        &self,
+        state: &mut State,
+        inode: &mut RawInode,
+        start: u64,
+        mut f: impl FnMut(u64, u32, u8, &[u8]) -> Option<R>,
+    ) -> vfs::Result<Option<R>> {
+        let block_size = self.geometry.block_size;
+        let mut data = vec![0u8; block_size as usize];
+        let mut index = start / block_size;
+        while index * block_size < inode.size() {
+            let Some(block) = self.map(state, inode, index, false)? else {
+                index += 1;
+                continue;
+            };
+            self.read_block(block, &mut data)?;
+            let mut offset = 0;
+            while offset + 8 <= data.len() {
+                let record = le16(&data[offset + 4..]) as usize;
+                let name_len = data[offset + 6] as usize;
+                if record < 8 || offset + record > data.len() || 8 + name_len > record {
+                    log::warn!("{}: corrupt ext2 directory block {}", self.device, block);
+                    return Err(VfsError::Io);
+                }
+                let position = index * block_size + offset as u64;
+                let ino = le32(&data[offset..]);
+                if position >= start && ino != 0 {
+                    let name = &data[offset + 8..offset + 8 + name_len];
+                    if let Some(result) = f(position, ino, data[offset + 7], name) {
+                        return Ok(Some(result));
+                    }
+                }
+                offset += record;
+            }
+            index += 1;
+        }
+        Ok(None)
+    }
+
+    fn find(&self, state: &mut State, dir: &mut RawInode, name: &str) -> vfs::Result<Option<u32>> {
+        self.scan(state, dir, 0, |_, ino, _, entry| {
+            (entry == name.as_bytes()).then_some(ino)
+        })
+    }
+
+    /// Adds an entry for `ino` to the directory `dir`, splitting the slack
+    /// off an entry or growing the directory by a block.
+    fn add_entry(
+        &self,
+        state: &mut State,
+        dir: &mut RawInode,
+        name: &str,
+        ino: u32,
+        kind: FileType,
+    ) -> vfs::Result<()> {
+        let block_size = self.geometry.block_size;
+        let needed = entry_len(name.len());
+        let mut data = vec![0u8; block_size as usize];
+        let write_entry = |data: &mut [u8], offset: usize, record: usize| {
+            data[offset..offset + 4].copy_from_slice(&ino.to_le_bytes());
+            data[offset + 4..offset + 6].copy_from_slice(&(record as u16).to_le_bytes());
+            data[offset + 6] = name.len() as u8;
+            data[offset + 7] = entry_type(kind);
+            data[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+        };
+
+        let blocks = dir.size().div_ceil(block_size);
+        for index in 0..blocks {
+            let Some(block) = self.map(state, dir, index, false)? else {
+                continue;
+            };
+            self.read_block(block, &mut data)?;
+            let mut offset = 0;
+            while offset + 8 <= data.len() {
+                let record = le16(&data[offset + 4..]) as usize;
+                if record < 8 || offset + record > data.len() {
+                    return Err(VfsError::Io);
+                }
+                let used = match le32(&data[offset..]) {
+                    0 => 0,
+                    _ => entry_len(data[offset + 6] as usize),
+                };
+                if record - used >= needed {
+                    if used > 0 {
+                        data[offset + 4..offset + 6].copy_from_slice(&(used as u16).to_le_bytes());
+                    }
+                    write_entry(&mut data, offset + used, record - used);
+                    return self.write_block(block, &data);
+                }
+                offset += record;
+            }
+        }
+
+        let block = self.map(state, dir, blocks, true)?.ok_or(VfsError::Io)?;
+        data.fill(0);
+        write_entry(&mut data, 0, block_size as usize);
+        self.write_block(block, &data)?;
+        dir.set_size((blocks + 1) * block_size);
+        Ok(())
+    }
+
+    /// Removes the entry `name` from the directory `dir`, merging its space
+    /// into the entry before it.
+    fn remove_entry(&self, state: &mut State, dir: &mut RawInode, name: &str) -> vfs::Result<()> {
+        let block_size = self.geometry.block_size;
+        let mut data = vec![0u8; block_size as usize];
+        for index in 0..dir.size().div_ceil(block_size) {
+            let Some(block) = self.map(state, dir, index, false)? else {
+                continue;
+            };
+            self.read_block(block, &mut data)?;
+            let (mut offset, mut previous) = (0, None);
+            while offset + 8 <= data.len() {
+                let record = le16(&data[offset + 4..]) as usize;
+                let name_len = data[offset + 6] as usize;
+                if record < 8 || offset + record > data.len() || 8 + name_len > record {
+                    return Err(VfsError::Io);
+                }
+                let ino = le32(&data[offset..]);
+                if ino != 0 && &data[offset + 8..offset + 8 + name_len] == name.as_bytes() {
+                    match previous {
+                        Some(previous) => {
+                            let merged = le16(&data[previous + 4..]) as usize + record;
+                            data[previous + 4..previous + 6]
+                                .copy_from_slice(&(merged as u16).to_le_bytes());
+                        }
+                        None => data[offset..offset + 4].fill(0),
+                    }
+                    return self.write_block(block, &data);
+                }
+                previous = Some(offset);
+                offset += record;
+            }
+        }
+        Err(VfsError::NotFound)
+    }
+
+    /// Puts `ino`, which lost its last link while open, at the head of the
+    /// orphan list.
+    fn add_orphan(&self, ino: u32, inode: &mut RawInode) -> vfs::Result<()> {
+        let head = self.read_u32(SUPERBLOCK_OFFSET + superblock::LAST_ORPHAN)?;
+        inode.set_u32(field::DTIME, head);
+        self.write_u32(SUPERBLOCK_OFFSET + superblock::LAST_ORPHAN, ino)
+    }
+
+    /// Takes `ino` off the orphan list; `next` is the orphan after it.
+    fn remove_orphan(&self, ino: u32, next: u32) -> vfs::Result<()> {
+        let mut link = SUPERBLOCK_OFFSET + superblock::LAST_ORPHAN;
+        for _ in 0..=self.geometry.inodes {
+            match self.read_u32(link)? {
+                0 => break,
+                orphan if orphan == ino => return self.write_u32(link, next),
+                orphan => link = self.inode_position(orphan)? + field::DTIME as u64,
+            }
+        }
+        Err(VfsError::Corrupted)
+    }
+
+    /// Frees the inodes a crash left on the orphan list.
+    fn release_orphans(&self) -> vfs::Result<()> {
+        let _state = self.state.lock();
+        let mut ino = self.read_u32(SUPERBLOCK_OFFSET + superblock::LAST_ORPHAN)?;
+        let mut released = 0;
+        while ino != 0 {
+            if released == self.geometry.inodes {
+                return Err(VfsError::Corrupted);
+            }
+            let mut inode = self.read_inode(ino)?;
+            let next = inode.u32(field::DTIME);
+            if inode.links() == 0 {
+                self.release(ino, &mut inode)?;
+                self.write_inode(ino, &inode)?;
+            }
+            ino = next;
+            released += 1;
+        }
+        if released > 0 {
+            log::info!("{}: freed {} orphaned inode(s)", self.device, released);
+            self.write_u32(SUPERBLOCK_OFFSET + superblock::LAST_ORPHAN, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks of `ino` and the inode itself once nothing refers
+    /// to it; the caller writes `inode` back.
+    fn release(&self, ino: u32, inode: &mut RawInode) -> vfs::Result<()> {
+        self.free_blocks(inode, 0)?;
+        inode.set_size(0);
+        // fsck takes a deletion time of 0 for corruption, and one that
+        // could be an inode number for a link in the orphan list
+        let now = time::now() as u32;
+        inode.set_u32(field::DTIME, now.max(self.geometry.inodes + 1));
+        self.free_inode(ino, inode.kind() == FileType::Directory)
+    }
+
+    fn node(&self, ino: u32) -> vfs::Result<Arc<Ext2Node>> {
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes.get(&ino).and_then(Weak::upgrade) {
+            return Ok(node);
+        }
+        nodes.retain(|_, node| node.strong_count() > 0);
+        let kind = self.read_inode(ino)?.kind();
+        let node = Arc::new(Ext2Node {
+            fs: self.this.upgrade().unwrap(),
+            ino,
+            kind,
+            pages: PageCache::new(),
+        });
+        nodes.insert(ino, Arc::downgrade(&node));
+        Ok(node)
+    }
+}
+
+/// Reads bytes at byte `position` of `device`, across its blocks.
+fn read_bytes(device: DeviceId, position: u64, buf: &mut [u8]) -> vfs::Result<()> {
+    let block_size = device.block_size() as u64;
+    let mut done = 0;
+    while done < buf.len() {
+        let at = position + done as u64;
+        let within = (at % block_size) as usize;
+        let count = (block_size as usize - within).min(buf.len() - done);
+        buffer_cache::read(device, at / block_size, |data| {
+            buf[done..done + count].copy_from_slice(&data[within..within + count])
+        })?;
+        done += count;
+    }
+    Ok(())
+}
+
+fn write_bytes(device: DeviceId, position: u64, bytes: &[u8]) -> vfs::Result<()> {
+    let block_size = device.block_size() as u64;
+    let mut done = 0;
+    while done < bytes.len() {
+        let at = position + done as u64;
+        let within = (at % block_size) as usize;
+        let count = (block_size as usize - within).min(bytes.len() - done);
+        let copy = |data: &mut [u8]| {
+            data[within..within + count].copy_from_slice(&bytes[done..done + count])
+        };
+        match count == block_size as usize {
+            true => buffer_cache::overwrite(device, at / block_size, copy)?,
+            false => buffer_cache::write(device, at / block_size, copy)?,
+        }
+        done += count;
+    }
+    Ok(())
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.node(ROOT_INO).expect("ext2 root inode unreadable")
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn sync(&self) -> vfs::Result<()> {
+        let nodes: Vec<_> = self
+            .nodes
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for node in nodes {
+            node.pages.write_back(&*node)?;
+        }
+        let _state = self.state.lock();
+        buffer_cache::sync(self.device)?;
+        Ok(())
+    }
+}
+
+/// An inode of an ext2 volume.
+pub struct Ext2Node {
+    fs: Arc<Ext2Fs>,
+    ino: u32,
+    kind: FileType,
+    /// Cached data of a regular file.
+    pages: PageCache,
+}
+
+impl Ext2Node {
+    /// The inode of this directory, checking that it still is one.
+    fn directory(&self) -> vfs::Result<RawInode> {
+        let inode = self.fs.read_inode(self.ino)?;
+        match inode.kind() {
+            _ if inode.links() == 0 => Err(VfsError::NotFound),
+            FileType::Directory => Ok(inode),
+            _ => Err(VfsError::NotDirectory),
+        }
+    }
+
+    /// Drops the htree flag of a directory about to change.
+    fn unindex(dir: &mut RawInode) {
+        let flags = dir.u32(field::FLAGS);
+        dir.set_u32(field::FLAGS, flags & !INDEX_FLAG);
+    }
+
+    fn new_inode(
+        &self,
+        state: &mut State,
+        dir: &mut RawInode,
+        name: &str,
+        kind: FileType,
+        permissions: u16,
+    ) -> vfs::Result<(u32, RawInode)> {
+        let fs = &self.fs;
+        if name.len() > MAX_NAME {
+            return Err(VfsError::NameTooLong);
+        }
+        if fs.find(state, dir, name)?.is_some() {
+            return Err(VfsError::Exists);
+        }
+        let type_bits = match kind {
+            FileType::Regular => mode::REGULAR,
+            FileType::Directory => mode::DIRECTORY,
+            FileType::Symlink => mode::SYMLINK,
+            _ => return Err(VfsError::Unsupported),
+        };
+        let directory = kind == FileType::Directory;
+        if directory && dir.links() >= MAX_LINKS {
+            return Err(VfsError::NoSpace);
+        }
+        let ino = fs.allocate_inode(self.ino, directory)?;
+        let mut inode = RawInode([0; GOOD_OLD_INODE_SIZE]);
+        inode.set_u16(field::MODE, type_bits | (permissions & 0o7777));
+        inode.set_u16(field::LINKS, 1);
+        inode.stamp(&[field::ATIME, field::CTIME, field::MTIME]);
+        if directory {
+            let block_size = fs.geometry.block_size as usize;
+            let mut data = vec![0u8; block_size];
+            data[0..4].copy_from_slice(&ino.to_le_bytes());
+            data[4..6].copy_from_slice(&12u16.to_le_bytes());
+            data[6] = 1;
+            data[7] = entry_type(FileType::Directory);
+            data[8] = b'.';
+            data[12..16].copy_from_slice(&self.ino.to_le_bytes());
+            data[16..18].copy_from_slice(&((block_size - 12) as u16).to_le_bytes());
+            data[18] = 2;
+            data[19] = entry_type(FileType::Directory);
+            data[20..22].copy_from_slice(b"..");
+            let block = fs.map(state, &mut inode, 0, true)?.ok_or(VfsError::Io)?;
+            fs.write_block(block, &data)?;
+            inode.set_size(block_size as u64);
+            inode.set_u16(field::LINKS, 2);
+            dir.set_u16(field::LINKS, dir.links() + 1);
+        }
+        Ext2Node::unindex(dir);
+        fs.add_entry(state, dir, name, ino, kind)?;
+        dir.stamp(&[field::CTIME, field::MTIME]);
+        Ok((ino, inode))
+    }
+
+    fn remove(&self, name: &str, directory: bool) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.directory()?;
+        let ino = fs
+            .find(&mut state, &mut dir, name)?
+            .ok_or(VfsError::NotFound)?;
+        let mut inode = fs.read_inode(ino)?;
+        match (directory, inode.kind()) {
+            (true, FileType::Directory) => {
+                let other = fs.scan(&mut state, &mut inode, 0, |_, _, _, entry| {
+                    (entry != b"." && entry != b"..").then_some(())
+                })?;
+                if other.is_some() {
+                    return Err(VfsError::NotEmpty);
+                }
+            }
+            (true, _) => return Err(VfsError::NotDirectory),
+            (false, FileType::Directory) => return Err(VfsError::IsDirectory),
+            (false, _) => {}
+        }
+        Ext2Node::unindex(&mut dir);
+        fs.remove_entry(&mut state, &mut dir, name)?;
+        dir.stamp(&[field::CTIME, field::MTIME]);
+        inode.stamp(&[field::CTIME]);
+
+        let links = match directory {
+            true => {
+                dir.set_u16(field::LINKS, dir.links() - 1);
+                0
+            }
+            false => inode.links().saturating_sub(1),
+        };
+        inode.set_u16(field::LINKS, links);
+        // an open node keeps the inode until it is dropped, see its Drop
+        let open = fs
+            .nodes
+            .lock()
+            .get(&ino)
+            .is_some_and(|node| node.strong_count() > 0);
+        match (links, open) {
+            (0, true) => fs.add_orphan(ino, &mut inode)?,
+            (0, false) => fs.release(ino, &mut inode)?,
+            _ => {}
+        }
+        fs.write_inode(ino, &inode)?;
+        fs.write_inode(self.ino, &dir)
+    }
+}
+
+impl Backing for Ext2Node {
+    fn read_page(&self, index: u64, buf: &mut [u8]) -> vfs::Result<bool> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut inode = fs.read_inode(self.ino)?;
+        let (start, size) = (index * PAGE_SIZE as u64, inode.size());
+        if start >= size {
+            return Ok(false);
+        }
+        let block_size = fs.geometry.block_size;
+        let mut present = false;
+        for (n, chunk) in buf.chunks_mut(block_size as usize).enumerate() {
+            let position = start + n as u64 * block_size;
+            if position >= size {
+                break;
+            }
+            if let Some(block) = fs.map(&mut state, &mut inode, position / block_size, false)? {
+                fs.read_file_block(block, chunk)?;
+                present = true;
+            }
+        }
+        if size - start < PAGE_SIZE as u64 {
+            buf[(size - start) as usize..].fill(0);
+        }
+        Ok(present)
+    }
+
+    fn write_page(&self, index: u64, data: &[u8]) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut inode = fs.read_inode(self.ino)?;
+        let (start, size) = (index * PAGE_SIZE as u64, inode.size());
+        if inode.links() == 0 || start >= size {
+            return Ok(());
+        }
+        let block_size = fs.geometry.block_size as usize;
+        let len = (size - start).min(PAGE_SIZE as u64) as usize;
+        let sectors = inode.u32(field::BLOCKS);
+        let mut write = || {
+            for (n, chunk) in data[..len.next_multiple_of(block_size)]
+                .chunks(block_size)
+                .enumerate()
+            {
+                let block = fs
+                    .map(
+                        &mut state,
+                        &mut inode,
+                        start / block_size as u64 + n as u64,
+                        true,
+                    )?
+                    .ok_or(VfsError::Io)?;
+                // what a mapping wrote past the end stays off the disk
+                let end = len - n * block_size;
+                if end < block_size {
+                    let mut last = vec![0u8; block_size];
+                    last[..end].copy_from_slice(&chunk[..end]);
+                    fs.write_file_block(block, &last)?;
+                } else {
+                    fs.write_file_block(block, chunk)?;
+                }
+            }
+            Ok(())
+        };
+        let result = write();
+        // blocks allocated before a failure are recorded either way
+        if inode.u32(field::BLOCKS) != sectors {
+            fs.write_inode(self.ino, &inode)?;
+        }
+        result
+    }
+}
+
+impl Ext2Node {
+    /// Frees the inode if it was only kept for this node; true if it was.
+    fn release_orphan(&self) -> vfs::Result<bool> {
+        let fs = &self.fs;
+        let _state = fs.state.lock();
+        let mut inode = fs.read_inode(self.ino)?;
+        if inode.links() != 0 {
+            return Ok(false);
+        }
+        // dirty pages must not reach the blocks about to be freed
+        self.pages.discard();
+        fs.remove_orphan(self.ino, inode.u32(field::DTIME))?;
+        fs.release(self.ino, &mut inode)?;
+        fs.write_inode(self.ino, &inode)?;
+        Ok(true)
+    }
+}
+
+impl Drop for Ext2Node {
+    fn drop(&mut self) {
+        if self.fs.read_only {
+            return;
+        }
+        match self.release_orphan() {
+            Ok(true) => return,
+            Ok(false) => {}
+            Err(err) => {
+                log::warn!("ext2: inode {} not freed: {}", self.ino, err);
+                return;
+            }
+        }
+        if self.pages.is_dirty()
+            && let Err(err) = self.pages.write_back(self)
+        {
+            log::warn!("ext2: inode {} lost data: {}", self.ino, err);
+        }
+    }
+}
+
+impl Inode for Ext2Node {
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        let inode = self.fs.read_inode(self.ino)?;
+        let device = match inode.kind() {
+            FileType::CharDevice | FileType::BlockDevice => match inode.block(0) {
+                0 => inode.block(1),
+                old => old,
+            },
+            _ => 0,
+        };
+        let uid = (inode.u16(field::UID_HIGH) as u32) << 16 | inode.u16(field::UID) as u32;
+        let gid = (inode.u16(field::GID_HIGH) as u32) << 16 | inode.u16(field::GID) as u32;
+        Ok(Metadata {
+            ino: self.ino as u64,
+            kind: inode.kind(),
+            mode: inode.mode() & 0o7777,
+            uid,
+            gid,
+            size: inode.size(),
+            links: inode.links() as u32,
+            blocks: inode.u32(field::BLOCKS) as u64,
+            device,
+            accessed: inode.u32(field::ATIME) as u64,
+            modified: inode.u32(field::MTIME) as u64,
+            changed: inode.u32(field::CTIME) as u64,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.directory()?;
+        let ino = fs
+            .find(&mut state, &mut dir, name)?
+            .ok_or(VfsError::NotFound)?;
+        Ok(fs.node(ino)?)
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u16) -> vfs::Result<Arc<dyn Inode>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.directory()?;
+        let (ino, inode) = self.new_inode(&mut state, &mut dir, name, kind, mode)?;
+        fs.write_inode(ino, &inode)?;
+        fs.write_inode(self.ino, &dir)?;
+        Ok(fs.node(ino)?)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.directory()?;
+        let (ino, mut inode) =
+            self.new_inode(&mut state, &mut dir, name, FileType::Symlink, 0o777)?;
+        if target.len() < FAST_SYMLINK_MAX {
+            let start = field::BLOCK;
+            inode.0[start..start + target.len()].copy_from_slice(target.as_bytes());
+        } else {
+            fs.write_data(&mut state, &mut inode, 0, target.as_bytes())?;
+        }
+        inode.set_size(target.len() as u64);
+        fs.write_inode(ino, &inode)?;
+        fs.write_inode(self.ino, &dir)?;
+        Ok(fs.node(ino)?)
+    }
+
+    fn link(&self, name: &str, target: &Arc<dyn Inode>) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.directory()?;
+        if name.len() > MAX_NAME {
+            return Err(VfsError::NameTooLong);
+        }
+        if fs.find(&mut state, &mut dir, name)?.is_some() {
+            return Err(VfsError::Exists);
+        }
+        let ino = target.metadata()?.ino as u32;
+        let mut inode = fs.read_inode(ino)?;
+        match inode.links() {
+            0 => return Err(VfsError::NotFound),
+            MAX_LINKS.. => return Err(VfsError::NoSpace),
+            links => inode.set_u16(field::LINKS, links + 1),
+        }
+        Ext2Node::unindex(&mut dir);
+        fs.add_entry(&mut state, &mut dir, name, ino, inode.kind())?;
+        dir.stamp(&[field::CTIME, field::MTIME]);
+        inode.stamp(&[field::CTIME]);
+        fs.write_inode(ino, &inode)?;
+        fs.write_inode(self.ino, &dir)
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.remove(name, false)
+    }
+
+    fn rmdir(&self, name: &str) -> vfs::Result<()> {
+        self.remove(name, true)
+    }
+
+    fn read_dir(&self, cursor: u64) -> vfs::Result<Option<(DirEntry, u64)>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut dir = self.directory()?;
+        let block_size = fs.geometry.block_size;
+        let mut next = None;
+        let found = fs.scan(&mut state, &mut dir, cursor, |position, ino, kind, name| {
+            if name == b"." || name == b".." {
+                return None;
+            }
+            next = Some(position);
+            Some(DirEntry {
+                ino: ino as u64,
+                kind: entry_kind(kind),
+                name: String::from_utf8_lossy(name).into(),
+            })
+        })?;
+        let Some(entry) = found else {
+            return Ok(None);
+        };
+        // the entry after this one starts where its record ends
+        let position = next.unwrap();
+        let mut record = [0u8; 2];
+        let block = fs
+            .map(&mut state, &mut dir, position / block_size, false)?
+            .ok_or(VfsError::Io)?;
+        read_bytes(
+            fs.device,
+            fs.block_position(block) + position % block_size + 4,
+            &mut record,
+        )?;
+        Ok(Some((entry, position + u16::from_le_bytes(record) as u64)))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let size = self.fs.read_inode(self.ino)?.size();
+        let len = buf.len().min(size.saturating_sub(offset) as usize);
+        self.pages.read(self, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        let fs = &self.fs;
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(VfsError::InvalidArgument)?;
+        // pages only partly overwritten are read in before their blocks are
+        // allocated, which would otherwise be read with whatever they held
+        let page = PAGE_SIZE as u64;
+        if !offset.is_multiple_of(page) {
+            self.pages.get(self, offset / page)?;
+        }
+        if !end.is_multiple_of(page) {
+            self.pages.get(self, end / page)?;
+        }
+
+        let mut state = fs.state.lock();
+        let mut inode = fs.read_inode(self.ino)?;
+        let block_size = fs.geometry.block_size;
+        let mut mapped = offset / block_size;
+        let result = (mapped..end.div_ceil(block_size)).try_for_each(|index| {
+            fs.map(&mut state, &mut inode, index, true)?;
+            mapped = index + 1;
+            Ok(())
+        });
+        // keep what got blocks, like a short write to a full disk
+        let written = (mapped * block_size).min(end).saturating_sub(offset);
+        if written > 0 && offset + written > inode.size() {
+            inode.set_size(offset + written);
+        }
+        inode.stamp(&[field::CTIME, field::MTIME]);
+        // blocks allocated before a failure are recorded either way
+        fs.write_inode(self.ino, &inode)?;
+        drop(state);
+        if let Err(err) = result
+            && written == 0
+        {
+            return Err(err);
+        }
+        self.pages.write(self, offset, &buf[..written as usize])?;
+        Ok(written as usize)
+    }
+
+    fn truncate(&self, size: u64) -> vfs::Result<()> {
+        let fs = &self.fs;
+        if self.kind == FileType::Directory {
+            return Err(VfsError::IsDirectory);
+        }
+        if size < fs.read_inode(self.ino)?.size() {
+            // the rest of the last page has to read as zeroes if the file
+            // grows again, and reach the disk as such
+            if !size.is_multiple_of(PAGE_SIZE as u64) {
+                self.pages.get(self, size / PAGE_SIZE as u64)?;
+            }
+            self.pages.truncate(size);
+        }
+        let _state = fs.state.lock();
+        let mut inode = fs.read_inode(self.ino)?;
+        if size < inode.size() {
+            fs.free_blocks(&mut inode, size.div_ceil(fs.geometry.block_size))?;
+        }
+        inode.set_size(size);
+        inode.stamp(&[field::CTIME, field::MTIME]);
+        fs.write_inode(self.ino, &inode)
+    }
+
+    fn page(&self, index: u64) -> vfs::Result<Arc<Page>> {
+        if self.kind != FileType::Regular {
+            return Err(VfsError::InvalidArgument);
+        }
+        self.pages.get(self, index)
+    }
+
+    fn write_back(&self) -> vfs::Result<()> {
+        self.pages.write_back(self)
+    }
+
+    fn read_link(&self) -> vfs::Result<String> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut inode = fs.read_inode(self.ino)?;
+        if inode.kind() != FileType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+        let len = inode.size() as usize;
+        let target = match inode.is_fast_symlink() {
+            true => inode.0[field::BLOCK..field::BLOCK + len.min(FAST_SYMLINK_MAX)].to_vec(),
+            false => {
+                let mut target = vec![0u8; len];
+                fs.read_data(&mut state, &mut inode, 0, &mut target)?;
+                target
+            }
+        };
+        String::from_utf8(target).map_err(|_| VfsError::InvalidArgument)
+    }
+
+    fn set_attributes(&self, attributes: &Attributes) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let _state = fs.state.lock();
+        let mut inode = fs.read_inode(self.ino)?;
+        if let Some(mode) = attributes.mode {
+            let mode = inode.mode() & mode::TYPE_MASK | mode & 0o7777;
+            inode.set_u16(field::MODE, mode);
+        }
+        if let Some(uid) = attributes.uid {
+            inode.set_u16(field::UID, uid as u16);
+            inode.set_u16(field::UID_HIGH, (uid >> 16) as u16);
+        }
+        if let Some(gid) = attributes.gid {
+            inode.set_u16(field::GID, gid as u16);
+            inode.set_u16(field::GID_HIGH, (gid >> 16) as u16);
+        }
+        inode.stamp(&[field::CTIME]);
+        if let Some(accessed) = attributes.accessed {
+            inode.set_u32(field::ATIME, accessed as u32);
+        }
+        if let Some(modified) = attributes.modified {
+            inode.set_u32(field::MTIME, modified as u32);
+        }
+        fs.write_inode(self.ino, &inode)
+    }
+}
@@ -0,0 +1,1309 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+use crate::block::{BlockDevice, DeviceId};
+use crate::buffer_cache::{self, MAX_BLOCK_SIZE};
+use crate::sync::SpinLock;
+use crate::time;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, Metadata, VfsError};
+
+// FAT12, FAT16 and FAT32 volumes, all of their sectors going through the
+// buffer cache. A directory is an array of 32-byte entries, the FAT12/16
+// root a fixed one between the FATs and the data area, every other one a
+// cluster chain like a file's. Long names precede their 8.3 entry as runs
+// of UCS-2 entries tied to it by a checksum.
+//
+// One lock serialises all operations on a volume. Entries are dated from
+// the RTC, taken as local time. Unlike on Unix filesystems, the clusters of
+// a removed file are freed at once, and open handles to it stop working.
+
+const ENTRY_SIZE: usize = 32;
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// Attribute byte of a long name entry.
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_MASK: u8 = 0x3F;
+
+const END_OF_DIRECTORY: u8 = 0x00;
+const DELETED: u8 = 0xE5;
+/// Stands for a leading 0xE5 byte in an 8.3 name.
+const KANJI_E5: u8 = 0x05;
+/// Case bits Windows NT keeps in byte 12 of an 8.3 entry.
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+const LONG_NAME_LAST: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+/// Offsets of the UCS-2 characters in a long name entry.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_UNITS: usize = 255;
+const MAX_LONG_ENTRIES: usize = MAX_NAME_UNITS.div_ceil(LONG_NAME_CHARS);
+/// Entries a directory may have.
+const MAX_DIRECTORY_ENTRIES: u32 = 65536;
+
+const FAT12_MAX_CLUSTERS: u32 = 4084;
+const FAT16_MAX_CLUSTERS: u32 = 65524;
+
+const FSINFO_SIGNATURE: &[u8; 4] = b"RRaA";
+const FSINFO_FREE_COUNT: usize = 488;
+
+/// 1980-01-01.
+const DOS_EPOCH_DATE: u16 = (1 << 5) | 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    kind: FatType,
+    sector_size: usize,
+    cluster_sectors: u64,
+    /// First sector of the first FAT.
+    reserved: u64,
+    fats: u64,
+    fat_sectors: u64,
+    /// The fixed FAT12/16 root directory.
+    root_sector: u64,
+    root_sectors: u64,
+    data_sector: u64,
+    clusters: u32,
+    /// First cluster of the FAT32 root directory.
+    root_cluster: u32,
+    fsinfo: Option<u64>,
+}
+
+fn le16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+impl Geometry {
+    /// Reads the BIOS parameter block of a boot sector.
+    fn parse(boot: &[u8], block_size: usize, blocks: u64) -> Option<Geometry> {
+        if boot.len() < 512 || boot[510..512] != [0x55, 0xAA] {
+            return None;
+        }
+        let sector_size = le16(&boot[11..]) as usize;
+        let cluster_sectors = boot[13] as u64;
+        let reserved = le16(&boot[14..]) as u64;
+        let fats = boot[16] as u64;
+        let root_entries = le16(&boot[17..]) as u64;
+        let total = match le16(&boot[19..]) {
+            0 => le32(&boot[32..]) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match le16(&boot[22..]) {
+            0 => le32(&boot[36..]) as u64,
+            sectors => sectors as u64,
+        };
+        if sector_size != block_size
+            || !cluster_sectors.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || fat_sectors == 0
+            || total > blocks
+        {
+            return None;
+        }
+
+        let root_sector = reserved + fats * fat_sectors;
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(sector_size as u64);
+        let data_sector = root_sector + root_sectors;
+        let clusters = u32::try_from(total.checked_sub(data_sector)? / cluster_sectors).ok()?;
+        let kind = match clusters {
+            count if count <= FAT12_MAX_CLUSTERS => FatType::Fat12,
+            count if count <= FAT16_MAX_CLUSTERS => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        if (kind == FatType::Fat32) != (root_entries == 0) {
+            return None;
+        }
+        let entries = clusters as u64 + 2;
+        let fat_bytes = match kind {
+            FatType::Fat12 => (entries * 3).div_ceil(2),
+            FatType::Fat16 => entries * 2,
+            FatType::Fat32 => entries * 4,
+        };
+        if fat_bytes > fat_sectors * sector_size as u64 {
+            return None;
+        }
+        let (root_cluster, fsinfo) = match kind {
+            FatType::Fat32 => {
+                let fsinfo = match le16(&boot[48..]) {
+                    0 | 0xFFFF => None,
+                    sector => Some(sector as u64),
+                };
+                (le32(&boot[44..]), fsinfo)
+            }
+            _ => (0, None),
+        };
+        Some(Geometry {
+            kind,
+            sector_size,
+            cluster_sectors,
+            reserved,
+            fats,
+            fat_sectors,
+            root_sector,
+            root_sectors,
+            data_sector,
+            clusters,
+            root_cluster,
+            fsinfo,
+        })
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.cluster_sectors * self.sector_size as u64
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_sector + (cluster as u64 - 2) * self.cluster_sectors
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.kind {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        value >= self.end_of_chain() - 7
+    }
+}
+
+/// Where the entries of a directory live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    FixedRoot,
+    Chain(u32),
+}
+
+/// A position in a cluster chain, so that walking it in order doesn't start
+/// over at every step.
+struct Chain {
+    first: u32,
+    index: u64,
+    cluster: u32,
+}
+
+impl Chain {
+    fn new(first: u32) -> Chain {
+        Chain {
+            first,
+            index: 0,
+            cluster: first,
+        }
+    }
+
+    fn of(dir: Dir) -> Chain {
+        match dir {
+            Dir::FixedRoot => Chain::new(0),
+            Dir::Chain(first) => Chain::new(first),
+        }
+    }
+}
+
+/// An 8.3 entry with the long name in front of it.
+struct Item {
+    /// Index of the 8.3 entry.
+    index: u32,
+    /// Index of the first long name entry, or the 8.3 one without any.
+    start: u32,
+    name: String,
+    entry: [u8; ENTRY_SIZE],
+}
+
+/// A long name being put together from its entries, which come last part
+/// first.
+struct LongName {
+    units: [u16; MAX_LONG_ENTRIES * LONG_NAME_CHARS],
+    len: usize,
+    /// Ordinal of the entry expected next, 0 once complete.
+    expect: u8,
+    in_progress: bool,
+    checksum: u8,
+    start: u32,
+}
+
+impl LongName {
+    fn new() -> LongName {
+        LongName {
+            units: [0; MAX_LONG_ENTRIES * LONG_NAME_CHARS],
+            len: 0,
+            expect: 0,
+            in_progress: false,
+            checksum: 0,
+            start: 0,
+        }
+    }
+
+    fn push(&mut self, index: u32, entry: &[u8; ENTRY_SIZE]) {
+        let ordinal = entry[0] & 0x1F;
+        if entry[0] & LONG_NAME_LAST != 0 {
+            if ordinal == 0 || ordinal as usize > MAX_LONG_ENTRIES {
+                self.in_progress = false;
+                return;
+            }
+            self.in_progress = true;
+            self.expect = ordinal;
+            self.checksum = entry[13];
+            self.start = index;
+            self.len = ordinal as usize * LONG_NAME_CHARS;
+        }
+        if !self.in_progress || ordinal != self.expect || entry[13] != self.checksum {
+            self.in_progress = false;
+            return;
+        }
+        let base = (ordinal as usize - 1) * LONG_NAME_CHARS;
+        for (unit, offset) in self.units[base..].iter_mut().zip(LONG_NAME_OFFSETS) {
+            *unit = le16(&entry[offset..]);
+        }
+        self.expect -= 1;
+    }
+
+    /// Returns the long name and where it starts if it belongs to the 8.3
+    /// entry `entry`, and starts over.
+    fn take(&mut self, entry: &[u8; ENTRY_SIZE]) -> Option<(u32, String)> {
+        let complete = self.in_progress && self.expect == 0;
+        self.in_progress = false;
+        if !complete || self.checksum != short_name_checksum(&entry[..11]) {
+            return None;
+        }
+        let units = &self.units[..self.len];
+        let end = units
+            .iter()
+            .position(|unit| *unit == 0)
+            .unwrap_or(units.len());
+        Some((self.start, String::from_utf16_lossy(&units[..end])))
+    }
+}
+
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte))
+}
+
+/// The name of an 8.3 entry, with the NT case bits applied.
+fn short_name(entry: &[u8; ENTRY_SIZE]) -> String {
+    let decode = |bytes: &[u8], lower: bool, name: &mut String| {
+        let len = bytes
+            .iter()
+            .rposition(|byte| *byte != b' ')
+            .map_or(0, |last| last + 1);
+        for byte in &bytes[..len] {
+            let c = *byte as char;
+            name.push(if lower { c.to_ascii_lowercase() } else { c });
+        }
+    };
+    let mut base = [0u8; 8];
+    base.copy_from_slice(&entry[..8]);
+    if base[0] == KANJI_E5 {
+        base[0] = DELETED;
+    }
+    let mut name = String::new();
+    decode(&base, entry[12] & LOWER_CASE_BASE != 0, &mut name);
+    if entry[8] != b' ' {
+        name.push('.');
+        decode(
+            &entry[8..11],
+            entry[12] & LOWER_CASE_EXTENSION != 0,
+            &mut name,
+        );
+    }
+    name
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// `name` as an 8.3 entry name and the case bits that bring it back, if it
+/// is a valid one whose base and extension are each in a single case.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+    if !(1..=8).contains(&base.len())
+        || extension.len() > 3
+        || (name.contains('.') && extension.is_empty())
+    {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut case = 0;
+    let (base_slot, extension_slot) = short.split_at_mut(8);
+    for (part, slot, lower) in [
+        (base, base_slot, LOWER_CASE_BASE),
+        (extension, extension_slot, LOWER_CASE_EXTENSION),
+    ] {
+        if part.bytes().any(|c| c.is_ascii_lowercase()) {
+            if part.bytes().any(|c| c.is_ascii_uppercase()) {
+                return None;
+            }
+            case |= lower;
+        }
+        for (slot, c) in slot.iter_mut().zip(part.bytes()) {
+            *slot = c.to_ascii_uppercase();
+        }
+    }
+    short
+        .iter()
+        .all(|c| *c == b' ' || is_short_name_char(*c))
+        .then_some((short, case))
+}
+
+/// The 8.3 name `name` is stored under along with its long name: the first
+/// characters of its base and extension in upper case, and a `~n` tail.
+fn numbered_short_name(name: &str, number: u32) -> [u8; 11] {
+    let convert = |c: char| match c.to_ascii_uppercase() {
+        c if c.is_ascii() && is_short_name_char(c as u8) => c as u8,
+        _ => b'_',
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, extension) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None => (trimmed, ""),
+    };
+    let mut short = [b' '; 11];
+    let tail = alloc::format!("~{}", number);
+    let base: Vec<u8> = base
+        .chars()
+        .filter(|c| *c != ' ' && *c != '.')
+        .map(convert)
+        .take(8 - tail.len())
+        .collect();
+    short[..base.len()].copy_from_slice(&base);
+    short[base.len()..base.len() + tail.len()].copy_from_slice(tail.as_bytes());
+    for (slot, c) in short[8..]
+        .iter_mut()
+        .zip(extension.chars().filter(|c| *c != ' '))
+    {
+        *slot = convert(c);
+    }
+    short
+}
+
+fn validate_name(name: &str) -> vfs::Result<()> {
+    if name.encode_utf16().count() > MAX_NAME_UNITS {
+        return Err(VfsError::NameTooLong);
+    }
+    let invalid = |c: char| c.is_control() || "\"*/:<>?\\|".contains(c);
+    if name.is_empty() || name.ends_with(['.', ' ']) || name.chars().any(invalid) {
+        return Err(VfsError::InvalidArgument);
+    }
+    Ok(())
+}
+
+/// The long name entries for `name`, in the order they are stored.
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LONG_NAME_CHARS);
+    (1..=count)
+        .rev()
+        .map(|ordinal| {
+            let mut entry = [0u8; ENTRY_SIZE];
+            entry[0] = ordinal as u8 | if ordinal == count { LONG_NAME_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (k, offset) in LONG_NAME_OFFSETS.into_iter().enumerate() {
+                let position = (ordinal - 1) * LONG_NAME_CHARS + k;
+                let unit = match position.cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[position],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+fn entry_cluster(entry: &[u8; ENTRY_SIZE]) -> u32 {
+    (le16(&entry[20..]) as u32) << 16 | le16(&entry[26..]) as u32
+}
+
+fn set_entry_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// A new 8.3 entry, created and modified now.
+fn new_entry(name: &[u8; 11], attributes: u8, cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    let (date, time) = dos_now();
+    for offset in [14, 22] {
+        entry[offset..offset + 2].copy_from_slice(&time.to_le_bytes());
+    }
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&date.to_le_bytes());
+    }
+    set_entry_cluster(&mut entry, cluster);
+    entry
+}
+
+/// Seconds since the Unix epoch of a DOS date and time. Those are local
+/// time; they are taken as UTC.
+fn dos_time(date: u16, time: u16) -> u64 {
+    let year = 1980 + (date >> 9) as u64;
+    let month = ((date >> 5) & 0xF).clamp(1, 12) as u64;
+    let day = (date & 0x1F).max(1) as u64;
+    let seconds = (time >> 11) as u64 * 3600 + ((time >> 5) & 0x3F) as u64 * 60;
+    time::days_from_civil(year, month, day) * 86400 + seconds + (time & 0x1F) as u64 * 2
+}
+
+/// The DOS date and time of now, kept within the years DOS can count.
+fn dos_now() -> (u16, u16) {
+    let now = time::now();
+    let (year, month, day) = time::civil_from_days(now / 86400);
+    if !(1980..2108).contains(&year) {
+        return (DOS_EPOCH_DATE, 0);
+    }
+    let seconds = now % 86400;
+    let date = (((year - 1980) << 9) | (month << 5) | day) as u16;
+    let time = (((seconds / 3600) << 11) | ((seconds / 60 % 60) << 5) | (seconds % 60 / 2)) as u16;
+    (date, time)
+}
+
+struct State {
+    /// Where the search for a free cluster starts.
+    next_free: u32,
+    /// Whether the FAT32 free cluster count was marked unknown.
+    fsinfo_stale: bool,
+}
+
+/// A mounted FAT volume.
+pub struct FatFs {
+    device: DeviceId,
+    geometry: Geometry,
+    state: SpinLock<State>,
+    /// Nodes handed out, by inode number, so that a file has only one.
+    nodes: SpinLock<BTreeMap<u64, Weak<FatNode>>>,
+    this: Weak<FatFs>,
+}
+
+impl FatFs {
+    /// Opens the FAT volume on `device`.
+    pub fn new(device: DeviceId) -> vfs::Result<Arc<FatFs>> {
+        let block_size = device.block_size();
+        if block_size > MAX_BLOCK_SIZE {
+            return Err(VfsError::Unsupported);
+        }
+        let mut boot = [0u8; MAX_BLOCK_SIZE];
+        buffer_cache::read(device, 0, |data| boot[..block_size].copy_from_slice(data))?;
+        let geometry = Geometry::parse(&boot[..block_size], block_size, device.block_count())
+            .ok_or(VfsError::InvalidArgument)?;
+        log::info!(
+            "{}: {:?} volume, {} clusters of {} bytes",
+            device,
+            geometry.kind,
+            geometry.clusters,
+            geometry.cluster_bytes()
+        );
+        Ok(Arc::new_cyclic(|this| FatFs {
+            device,
+            geometry,
+            state: SpinLock::new(State {
+                next_free: 2,
+                fsinfo_stale: false,
+            }),
+            nodes: SpinLock::new(BTreeMap::new()),
+            this: this.clone(),
+        }))
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.geometry.kind
+    }
+
+    fn read_sector(&self, lba: u64, buf: &mut [u8]) -> vfs::Result<()> {
+        let len = self.geometry.sector_size;
+        buffer_cache::read(self.device, lba, |data| buf[..len].copy_from_slice(data))?;
+        Ok(())
+    }
+
+    /// Byte `offset` of the FATs, counted from the start of the first.
+    fn fat_location(&self, offset: u64) -> (u64, usize) {
+        let sector_size = self.geometry.sector_size as u64;
+        (
+            self.geometry.reserved + offset / sector_size,
+            (offset % sector_size) as usize,
+        )
+    }
+
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        let cluster = cluster as u64;
+        match self.geometry.kind {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    fn fat_width(&self) -> usize {
+        match self.geometry.kind {
+            FatType::Fat32 => 4,
+            _ => 2,
+        }
+    }
+
+    fn fat_get(&self, cluster: u32) -> vfs::Result<u32> {
+        let mut bytes = [0u8; 4];
+        let offset = self.fat_offset(cluster);
+        for (i, byte) in bytes[..self.fat_width()].iter_mut().enumerate() {
+            let (lba, within) = self.fat_location(offset + i as u64);
+            *byte = buffer_cache::read(self.device, lba, |data| data[within])?;
+        }
+        let value = u32::from_le_bytes(bytes);
+        Ok(match self.geometry.kind {
+            FatType::Fat12 if cluster % 2 == 1 => value >> 4,
+            FatType::Fat12 => value & 0xFFF,
+            FatType::Fat16 => value,
+            FatType::Fat32 => value & 0x0FFF_FFFF,
+        })
+    }
+
+    /// Sets the FAT entry of `cluster` in every copy of the FAT.
+    fn fat_set(&self, cluster: u32, value: u32) -> vfs::Result<()> {
+        let fat_bytes = self.geometry.fat_sectors * self.geometry.sector_size as u64;
+        let kind = self.geometry.kind;
+        for copy in 0..self.geometry.fats {
+            let offset = copy * fat_bytes + self.fat_offset(cluster);
+            let mut bytes = [0u8; 4];
+            let width = self.fat_width();
+            for (i, byte) in bytes[..width].iter_mut().enumerate() {
+                let (lba, within) = self.fat_location(offset + i as u64);
+                *byte = buffer_cache::read(self.device, lba, |data| data[within])?;
+            }
+            let old = u32::from_le_bytes(bytes);
+            let new = match kind {
+                FatType::Fat12 if cluster % 2 == 1 => (old & 0x000F) | (value << 4),
+                FatType::Fat12 => (old & 0xF000) | (value & 0xFFF),
+                FatType::Fat16 => value,
+                FatType::Fat32 => (old & 0xF000_0000) | (value & 0x0FFF_FFFF),
+            };
+            for (i, byte) in new.to_le_bytes()[..width].iter().enumerate() {
+                let (lba, within) = self.fat_location(offset + i as u64);
+                buffer_cache::write(self.device, lba, |data| data[within] = *byte)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn next_cluster(&self, cluster: u32) -> vfs::Result<Option<u32>> {
+        let next = self.fat_get(cluster)?;
+        if self.geometry.is_end_of_chain(next) {
+            return Ok(None);
+        }
+        if next < 2 || next >= self.geometry.clusters + 2 {
+            log::warn!("{}: broken cluster chain at {}", self.device, cluster);
+            return Err(VfsError::Io);
+        }
+        Ok(Some(next))
+    }
+
+    /// The `index`th cluster of `chain`, None past its end.
+    fn seek(&self, chain: &mut Chain, index: u64) -> vfs::Result<Option<u32>> {
+        if chain.first == 0 {
+            return Ok(None);
+        }
+        if index < chain.index {
+            *chain = Chain::new(chain.first);
+        }
+        while chain.index < index {
+            match self.next_cluster(chain.cluster)? {
+                Some(next) => {
+                    chain.cluster = next;
+                    chain.index += 1;
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(chain.cluster))
+    }
+
+    /// The sector holding byte `position` of a file.
+    fn data_sector(&self, chain: &mut Chain, position: u64) -> vfs::Result<Option<u64>> {
+        let cluster_bytes = self.geometry.cluster_bytes();
+        let cluster = self.seek(chain, position / cluster_bytes)?;
+        let sector = (position % cluster_bytes) / self.geometry.sector_size as u64;
+        Ok(cluster.map(|cluster| self.geometry.cluster_sector(cluster) + sector))
+    }
+
+    /// The `n`th sector of a directory.
+    fn dir_sector(&self, dir: Dir, chain: &mut Chain, n: u64) -> vfs::Result<Option<u64>> {
+        match dir {
+            Dir::FixedRoot => {
+                let geometry = &self.geometry;
+                Ok((n < geometry.root_sectors).then(|| geometry.root_sector + n))
+            }
+            Dir::Chain(_) => self.data_sector(chain, n * self.geometry.sector_size as u64),
+        }
+    }
+
+    fn entries_per_sector(&self) -> u32 {
+        (self.geometry.sector_size / ENTRY_SIZE) as u32
+    }
+
+    /// Walks the entries of `dir` from index `start` on, pairing 8.3 entries
+    /// with their long names, until `f` returns Some or the directory ends.
+    fn scan<R>(
+        &self,
+        dir: Dir,
+        start: u32,
+        mut f: impl FnMut(&Item) -> Option<R>,
+    ) -> vfs::Result<Option<R>> {
+        let per_sector = self.entries_per_sector();
+        let mut chain = Chain::of(dir);
+        let mut long_name = LongName::new();
+        let mut sector = [0u8; MAX_BLOCK_SIZE];
+        let mut n = (start / per_sector) as u64;
+        while let Some(lba) = self.dir_sector(dir, &mut chain, n)? {
+            self.read_sector(lba, &mut sector)?;
+            let entries = sector[..self.geometry.sector_size]
+                .as_chunks::<ENTRY_SIZE>()
+                .0;
+            for (index, entry) in (n as u32 * per_sector..).zip(entries) {
+                if index < start {
+                    continue;
+                }
+                match entry[0] {
+                    END_OF_DIRECTORY => return Ok(None),
+                    DELETED => {
+                        long_name.in_progress = false;
+                        continue;
+                    }
+                    _ => {}
+                }
+                if entry[11] & ATTR_MASK == ATTR_LONG_NAME {
+                    long_name.push(index, entry);
+                    continue;
+                }
+                if entry[11] & ATTR_VOLUME_ID != 0 {
+                    long_name.in_progress = false;
+                    continue;
+                }
+                let (start, name) = long_name
+                    .take(entry)
+                    .unwrap_or_else(|| (index, short_name(entry)));
+                let item = Item {
+                    index,
+                    start,
+                    name,
+                    entry: *entry,
+                };
+                if let Some(result) = f(&item) {
+                    return Ok(Some(result));
+                }
+            }
+            n += 1;
+        }
+        Ok(None)
+    }
+
+    /// Finds `name` in `dir`, ignoring ASCII case as Windows does.
+    fn find(&self, dir: Dir, name: &str) -> vfs::Result<Option<Item>> {
+        self.scan(dir, 0, |item| {
+            let matches = item.name.eq_ignore_ascii_case(name)
+                || short_name(&item.entry).eq_ignore_ascii_case(name);
+            matches.then(|| Item {
+                name: item.name.clone(),
+                ..*item
+            })
+        })
+    }
+
+    /// The 8.3 names of everything in `dir`.
+    fn short_names(&self, dir: Dir) -> vfs::Result<BTreeSet<[u8; 11]>> {
+        let mut names = BTreeSet::new();
+        self.scan(dir, 0, |item| {
+            names.insert(*item.entry.first_chunk::<11>().unwrap());
+            None::<()>
+        })?;
+        Ok(names)
+    }
+
+    /// Finds room for `count` consecutive entries in `dir`, growing it if
+    /// need be.
+    fn find_free(&self, state: &mut State, dir: Dir, count: u32) -> vfs::Result<u32> {
+        let per_sector = self.entries_per_sector();
+        let mut chain = Chain::of(dir);
+        let mut sector = [0u8; MAX_BLOCK_SIZE];
+        let (mut run_start, mut run) = (0, 0);
+        let mut n = 0;
+        loop {
+            if n as u32 * per_sector >= MAX_DIRECTORY_ENTRIES {
+                return Err(VfsError::NoSpace);
+            }
+            let Some(lba) = self.dir_sector(dir, &mut chain, n)? else {
+                let Dir::Chain(_) = dir else {
+                    return Err(VfsError::NoSpace);
+                };
+                let last = chain.cluster;
+                let cluster = self.allocate_cluster(state)?;
+                self.fat_set(last, cluster)?;
+                continue;
+            };
+            self.read_sector(lba, &mut sector)?;
+            let entries = sector[..self.geometry.sector_size]
+                .as_chunks::<ENTRY_SIZE>()
+                .0;
+            for (index, entry) in (n as u32 * per_sector..).zip(entries) {
+                if entry[0] != END_OF_DIRECTORY && entry[0] != DELETED {
+                    run = 0;
+                    continue;
+                }
+                if run == 0 {
+                    run_start = index;
+                }
+                run += 1;
+                if run == count {
+                    return Ok(run_start);
+                }
+            }
+            n += 1;
+        }
+    }
+
+    /// Lets `f` change entry `index` of `dir`.
+    fn update_entry(&self, dir: Dir, index: u32, f: impl FnOnce(&mut [u8])) -> vfs::Result<()> {
+        let per_sector = self.entries_per_sector();
+        let n = (index / per_sector) as u64;
+        let lba = self
+            .dir_sector(dir, &mut Chain::of(dir), n)?
+            .ok_or(VfsError::Io)?;
+        let offset = (index % per_sector) as usize * ENTRY_SIZE;
+        buffer_cache::write(self.device, lba, |data| {
+            f(&mut data[offset..offset + ENTRY_SIZE])
+        })?;
+        Ok(())
+    }
+
+    fn write_entries(&self, dir: Dir, first: u32, entries: &[[u8; ENTRY_SIZE]]) -> vfs::Result<()> {
+        for (index, entry) in (first..).zip(entries) {
+            self.update_entry(dir, index, |slot| slot.copy_from_slice(entry))?;
+        }
+        Ok(())
+    }
+
+    /// Marks the FAT32 free cluster count unknown before the first change
+    /// that would make it wrong.
+    fn touch_fsinfo(&self, state: &mut State) -> vfs::Result<()> {
+        if let Some(sector) = self.geometry.fsinfo
+            && !state.fsinfo_stale
+        {
+            buffer_cache::write(self.device, sector, |data| {
+                if &data[..4] == FSINFO_SIGNATURE {
+                    data[FSINFO_FREE_COUNT..FSINFO_FREE_COUNT + 4].fill(0xFF);
+                }
+            })?;
+            state.fsinfo_stale = true;
+        }
+        Ok(())
+    }
+
+    /// Takes a free cluster, zeroes it and ends a chain with it.
+    fn allocate_cluster(&self, state: &mut State) -> vfs::Result<u32> {
+        let end = self.geometry.clusters + 2;
+        let start = state.next_free.clamp(2, end);
+        for cluster in (start..end).chain(2..start) {
+            if self.fat_get(cluster)? != 0 {
+                continue;
+            }
+            self.touch_fsinfo(state)?;
+            self.fat_set(cluster, self.geometry.end_of_chain())?;
+            let first = self.geometry.cluster_sector(cluster);
+            for lba in first..first + self.geometry.cluster_sectors {
+                buffer_cache::overwrite(self.device, lba, |_| {})?;
+            }
+            state.next_free = cluster + 1;
+            return Ok(cluster);
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_chain(&self, state: &mut State, first: u32) -> vfs::Result<()> {
+        self.touch_fsinfo(state)?;
+        let mut cluster = Some(first).filter(|first| *first >= 2);
+        while let Some(current) = cluster {
+            cluster = self.next_cluster(current)?;
+            self.fat_set(current, 0)?;
+        }
+        Ok(())
+    }
+
+    fn ino(dir: Dir, index: u32) -> u64 {
+        let key = match dir {
+            Dir::FixedRoot => 0,
+            Dir::Chain(cluster) => cluster as u64,
+        };
+        (key + 1) << 32 | index as u64
+    }
+
+    /// The node of `item` in `dir`, the one already handed out if there is.
+    fn node(&self, dir: Dir, item: &Item) -> Arc<FatNode> {
+        let ino = FatFs::ino(dir, item.index);
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes.get(&ino).and_then(Weak::upgrade) {
+            return node;
+        }
+        nodes.retain(|_, node| node.strong_count() > 0);
+        let entry = &item.entry;
+        let kind = match entry[11] & ATTR_DIRECTORY {
+            0 => FileType::Regular,
+            _ => FileType::Directory,
+        };
+        let node = Arc::new(FatNode {
+            fs: self.this.upgrade().unwrap(),
+            ino,
+            kind,
+            entry: Some(Location {
+                dir,
+                index: item.index,
+            }),
+            state: SpinLock::new(NodeState {
+                first_cluster: entry_cluster(entry),
+                size: le32(&entry[28..]),
+                attributes: entry[11],
+                modified: (le16(&entry[24..]), le16(&entry[22..])),
+                removed: false,
+            }),
+        });
+        nodes.insert(ino, Arc::downgrade(&node));
+        node
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let first_cluster = match self.geometry.kind {
+            FatType::Fat32 => self.geometry.root_cluster,
+            _ => 0,
+        };
+        Arc::new(FatNode {
+            fs: self.this.upgrade().unwrap(),
+            ino: 1,
+            kind: FileType::Directory,
+            entry: None,
+            state: SpinLock::new(NodeState {
+                first_cluster,
+                size: 0,
+                attributes: ATTR_DIRECTORY,
+                modified: (DOS_EPOCH_DATE, 0),
+                removed: false,
+            }),
+        })
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+
+    fn sync(&self) -> vfs::Result<()> {
+        let _state = self.state.lock();
+        buffer_cache::sync(self.device)?;
+        Ok(())
+    }
+}
+
+/// Where the entries of a file are.
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    dir: Dir,
+    /// The 8.3 entry.
+    index: u32,
+}
+
+struct NodeState {
+    first_cluster: u32,
+    size: u32,
+    attributes: u8,
+    /// Date and time of the last write.
+    modified: (u16, u16),
+    removed: bool,
+}
+
+/// A file or directory on a FAT volume.
+pub struct FatNode {
+    fs: Arc<FatFs>,
+    ino: u64,
+    kind: FileType,
+    /// None for the root directory, which has no entry.
+    entry: Option<Location>,
+    state: SpinLock<NodeState>,
+}
+
+impl FatNode {
+    fn dir(&self, node: &NodeState) -> Dir {
+        match (self.entry, self.fs.geometry.kind) {
+            (None, FatType::Fat12 | FatType::Fat16) => Dir::FixedRoot,
+            _ => Dir::Chain(node.first_cluster),
+        }
+    }
+
+    /// The directory entries of this directory, checking that it is one.
+    fn entries(&self, node: &NodeState) -> vfs::Result<Dir> {
+        if node.removed {
+            return Err(VfsError::NotFound);
+        }
+        match self.kind {
+            FileType::Directory => Ok(self.dir(node)),
+            _ => Err(VfsError::NotDirectory),
+        }
+    }
+
+    /// Writes the size and first cluster back to the entry.
+    fn store(&self, node: &NodeState) -> vfs::Result<()> {
+        let Some(location) = self.entry else {
+            return Ok(());
+        };
+        let size = match self.kind {
+            FileType::Directory => 0,
+            _ => node.size,
+        };
+        self.fs.update_entry(location.dir, location.index, |entry| {
+            entry[11] |= node.attributes & ATTR_ARCHIVE;
+            set_entry_cluster(entry, node.first_cluster);
+            entry[22..24].copy_from_slice(&node.modified.1.to_le_bytes());
+            entry[24..26].copy_from_slice(&node.modified.0.to_le_bytes());
+            entry[28..32].copy_from_slice(&size.to_le_bytes());
+        })
+    }
+
+    /// Makes the chain long enough for `end` bytes.
+    fn reserve(&self, state: &mut State, node: &mut NodeState, end: u64) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let needed = end.div_ceil(fs.geometry.cluster_bytes());
+        if needed == 0 {
+            return Ok(());
+        }
+        if node.first_cluster == 0 {
+            node.first_cluster = fs.allocate_cluster(state)?;
+        }
+        let mut chain = Chain::new(node.first_cluster);
+        while fs.seek(&mut chain, needed - 1)?.is_none() {
+            let cluster = fs.allocate_cluster(state)?;
+            fs.fat_set(chain.cluster, cluster)?;
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to byte `offset` of the file, or zeroes if it is None.
+    fn write_data(
+        &self,
+        state: &mut State,
+        node: &mut NodeState,
+        offset: u64,
+        len: usize,
+        data: Option<&[u8]>,
+    ) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let sector_size = fs.geometry.sector_size;
+        self.reserve(state, node, offset + len as u64)?;
+        let mut chain = Chain::new(node.first_cluster);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let lba = fs.data_sector(&mut chain, position)?.ok_or(VfsError::Io)?;
+            let within = (position % sector_size as u64) as usize;
+            let count = (sector_size - within).min(len - done);
+            let copy = |sector: &mut [u8]| match data {
+                Some(data) => {
+                    sector[within..within + count].copy_from_slice(&data[done..done + count])
+                }
+                None => sector[within..within + count].fill(0),
+            };
+            match count == sector_size {
+                true => buffer_cache::overwrite(fs.device, lba, copy)?,
+                false => buffer_cache::write(fs.device, lba, copy)?,
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Changes the size, zeroing what a file grows by and freeing the
+    /// clusters it no longer needs.
+    fn resize(&self, state: &mut State, node: &mut NodeState, size: u64) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let old = node.size as u64;
+        if size > u32::MAX as u64 {
+            return Err(VfsError::NoSpace);
+        }
+        if size > old {
+            self.write_data(state, node, old, (size - old) as usize, None)?;
+        } else {
+            let keep = size.div_ceil(fs.geometry.cluster_bytes());
+            if keep == 0 {
+                fs.free_chain(state, node.first_cluster)?;
+                node.first_cluster = 0;
+            } else {
+                let mut chain = Chain::new(node.first_cluster);
+                if let Some(last) = fs.seek(&mut chain, keep - 1)?
+                    && let Some(rest) = fs.next_cluster(last)?
+                {
+                    fs.fat_set(last, fs.geometry.end_of_chain())?;
+                    fs.free_chain(state, rest)?;
+                }
+            }
+        }
+        node.size = size as u32;
+        node.attributes |= ATTR_ARCHIVE;
+        self.store(node)
+    }
+
+    fn is_empty_directory(&self, dir: Dir) -> vfs::Result<bool> {
+        let other = self.fs.scan(dir, 0, |item| {
+            (item.name != "." && item.name != "..").then_some(())
+        })?;
+        Ok(other.is_none())
+    }
+
+    fn remove(&self, name: &str, directory: bool) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let node = self.state.lock();
+        let dir = self.entries(&node)?;
+        let item = fs.find(dir, name)?.ok_or(VfsError::NotFound)?;
+        let child = fs.node(dir, &item);
+        let mut child_state = child.state.lock();
+        match (directory, child.kind) {
+            (true, FileType::Directory) => {
+                if !self.is_empty_directory(child.dir(&child_state))? {
+                    return Err(VfsError::NotEmpty);
+                }
+            }
+            (true, _) => return Err(VfsError::NotDirectory),
+            (false, FileType::Directory) => return Err(VfsError::IsDirectory),
+            (false, _) => {}
+        }
+        for index in item.start..=item.index {
+            fs.update_entry(dir, index, |entry| entry[0] = DELETED)?;
+        }
+        fs.free_chain(&mut state, child_state.first_cluster)?;
+        child_state.removed = true;
+        drop(child_state);
+        fs.nodes.lock().remove(&child.ino);
+        Ok(())
+    }
+}
+
+impl Inode for FatNode {
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        let node = self.state.lock();
+        let mode = match (self.kind, node.attributes & ATTR_READ_ONLY) {
+            (FileType::Directory, _) => 0o755,
+            (_, 0) => 0o644,
+            _ => 0o444,
+        };
+        let cluster_bytes = self.fs.geometry.cluster_bytes();
+        let modified = dos_time(node.modified.0, node.modified.1);
+        Ok(Metadata {
+            ino: self.ino,
+            kind: self.kind,
+            mode,
+            uid: 0,
+            gid: 0,
+            size: node.size as u64,
+            links: 1,
+            blocks: (node.size as u64).next_multiple_of(cluster_bytes) / 512,
+            device: 0,
+            // there is no change time, and the access time is only a date
+            accessed: modified,
+            modified,
+            changed: modified,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let fs = &self.fs;
+        let _state = fs.state.lock();
+        let dir = self.entries(&self.state.lock())?;
+        let item = fs.find(dir, name)?.ok_or(VfsError::NotFound)?;
+        Ok(fs.node(dir, &item))
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u16) -> vfs::Result<Arc<dyn Inode>> {
+        let attributes = match kind {
+            FileType::Regular => ATTR_ARCHIVE,
+            FileType::Directory => ATTR_DIRECTORY,
+            _ => return Err(VfsError::Unsupported),
+        };
+        let attributes = match mode & 0o222 {
+            0 => attributes | ATTR_READ_ONLY,
+            _ => attributes,
+        };
+        validate_name(name)?;
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let node = self.state.lock();
+        let dir = self.entries(&node)?;
+        if fs.find(dir, name)?.is_some() {
+            return Err(VfsError::Exists);
+        }
+
+        let (short, case, mut entries) = match exact_short_name(name) {
+            Some((short, case)) => (short, case, Vec::new()),
+            None => {
+                let taken = fs.short_names(dir)?;
+                let short = (1..1_000_000)
+                    .map(|number| numbered_short_name(name, number))
+                    .find(|short| !taken.contains(short))
+                    .ok_or(VfsError::NoSpace)?;
+                let entries = long_name_entries(name, short_name_checksum(&short));
+                (short, 0, entries)
+            }
+        };
+        let first = fs.find_free(&mut state, dir, entries.len() as u32 + 1)?;
+
+        let cluster = match kind {
+            FileType::Directory => fs.allocate_cluster(&mut state)?,
+            _ => 0,
+        };
+        let mut short_entry = new_entry(&short, attributes, cluster);
+        short_entry[12] = case;
+        entries.push(short_entry);
+        let written = match kind {
+            FileType::Directory => {
+                let parent = match (self.entry, dir) {
+                    (None, _) | (_, Dir::FixedRoot) => 0,
+                    (Some(_), Dir::Chain(parent)) => parent,
+                };
+                let dots = [
+                    new_entry(b".          ", ATTR_DIRECTORY, cluster),
+                    new_entry(b"..         ", ATTR_DIRECTORY, parent),
+                ];
+                fs.write_entries(Dir::Chain(cluster), 0, &dots)
+            }
+            _ => Ok(()),
+        }
+        .and_then(|_| fs.write_entries(dir, first, &entries));
+        if let Err(err) = written {
+            // a long name left without its 8.3 entry is ignored
+            if cluster != 0 {
+                let _ = fs.free_chain(&mut state, cluster);
+            }
+            return Err(err);
+        }
+
+        let index = first + entries.len() as u32 - 1;
+        let item = Item {
+            index,
+            start: first,
+            name: name.into(),
+            entry: short_entry,
+        };
+        Ok(fs.node(dir, &item))
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.remove(name, false)
+    }
+
+    fn rmdir(&self, name: &str) -> vfs::Result<()> {
+        self.remove(name, true)
+    }
+
+    fn read_dir(&self, cursor: u64) -> vfs::Result<Option<(DirEntry, u64)>> {
+        let fs = &self.fs;
+        let _state = fs.state.lock();
+        let dir = self.entries(&self.state.lock())?;
+        let start = u32::try_from(cursor).map_err(|_| VfsError::InvalidArgument)?;
+        fs.scan(dir, start, |item| {
+            if item.name == "." || item.name == ".." {
+                return None;
+            }
+            let kind = match item.entry[11] & ATTR_DIRECTORY {
+                0 => FileType::Regular,
+                _ => FileType::Directory,
+            };
+            let entry = DirEntry {
+                ino: FatFs::ino(dir, item.index),
+                kind,
+                name: item.name.clone(),
+            };
+            Some((entry, item.index as u64 + 1))
+        })
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let fs = &self.fs;
+        let _state = fs.state.lock();
+        let node = self.state.lock();
+        if node.removed {
+            return Err(VfsError::NotFound);
+        }
+        let size = node.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let sector_size = fs.geometry.sector_size;
+        let mut chain = Chain::new(node.first_cluster);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let lba = fs.data_sector(&mut chain, position)?.ok_or(VfsError::Io)?;
+            let within = (position % sector_size as u64) as usize;
+            let count = (sector_size - within).min(len - done);
+            buffer_cache::read(fs.device, lba, |sector| {
+                buf[done..done + count].copy_from_slice(&sector[within..within + count])
+            })?;
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut node = self.state.lock();
+        if node.removed {
+            return Err(VfsError::NotFound);
+        }
+        let end = offset + buf.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(VfsError::NoSpace);
+        }
+        if offset > node.size as u64 {
+            self.resize(&mut state, &mut node, offset)?;
+        }
+        self.write_data(&mut state, &mut node, offset, buf.len(), Some(buf))?;
+        node.size = node.size.max(end as u32);
+        node.attributes |= ATTR_ARCHIVE;
+        node.modified = dos_now();
+        self.store(&node)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> vfs::Result<()> {
+        let mut state = self.fs.state.lock();
+        let mut node = self.state.lock();
+        if node.removed {
+            return Err(VfsError::NotFound);
+        }
+        if self.kind == FileType::Directory {
+            return Err(VfsError::IsDirectory);
+        }
+        node.modified = dos_now();
+        self.resize(&mut state, &mut node, size)
+    }
+}
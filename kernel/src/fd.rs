@@ -0,0 +1,204 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::pipe;
+use crate::sync::SpinLock;
+use crate::task;
+use crate::vfs::{self, File, OpenFlags, VfsError};
+
+// Each task has a table of file descriptors, the small numbers it knows its
+// open files by. Descriptors duplicated from one another, or copied along
+// with the table into a new task, share the open file and with it the
+// position and flags; the file is closed when its last descriptor is.
+// Close-on-exec belongs to the descriptor alone.
+//
+// The functions at the bottom work on the current task's table, as the
+// system calls will.
+
+/// Descriptors a table holds, like Linux's default `RLIMIT_NOFILE`.
+pub const MAX_FDS: usize = 1024;
+
+#[derive(Clone)]
+struct Descriptor {
+    file: Arc<dyn File>,
+    close_on_exec: bool,
+}
+
+/// A task's file descriptors.
+pub struct FdTable {
+    slots: SpinLock<Vec<Option<Descriptor>>>,
+}
+
+impl Default for FdTable {
+    fn default() -> FdTable {
+        FdTable::new()
+    }
+}
+
+impl FdTable {
+    pub const fn new() -> FdTable {
+        FdTable {
+            slots: SpinLock::new(Vec::new()),
+        }
+    }
+
+    /// A table with the same descriptors on the same open files, as fork
+    /// makes for the child.
+    pub fn duplicate(&self) -> FdTable {
+        FdTable {
+            slots: SpinLock::new(self.slots.lock().clone()),
+        }
+    }
+
+    /// Puts `descriptor` in the lowest free slot from `min` on.
+    fn insert_from(
+        slots: &mut Vec<Option<Descriptor>>,
+        min: usize,
+        descriptor: Descriptor,
+    ) -> vfs::Result<usize> {
+        let free = (min..slots.len()).find(|fd| slots[*fd].is_none());
+        let fd = free.unwrap_or(slots.len().max(min));
+        if fd >= MAX_FDS {
+            return Err(VfsError::TooManyFiles);
+        }
+        if fd >= slots.len() {
+            slots.resize(fd + 1, None);
+        }
+        slots[fd] = Some(descriptor);
+        Ok(fd)
+    }
+
+    /// Gives `file` the lowest free descriptor.
+    pub fn insert(&self, file: Arc<dyn File>, close_on_exec: bool) -> vfs::Result<usize> {
+        let descriptor = Descriptor {
+            file,
+            close_on_exec,
+        };
+        Self::insert_from(&mut self.slots.lock(), 0, descriptor)
+    }
+
+    pub fn get(&self, fd: usize) -> vfs::Result<Arc<dyn File>> {
+        match self.slots.lock().get(fd) {
+            Some(Some(descriptor)) => Ok(descriptor.file.clone()),
+            _ => Err(VfsError::BadDescriptor),
+        }
+    }
+
+    pub fn close(&self, fd: usize) -> vfs::Result<()> {
+        let descriptor = self.slots.lock().get_mut(fd).and_then(Option::take);
+        // dropped past the lock, as closing the file may write it back
+        match descriptor {
+            Some(_) => Ok(()),
+            None => Err(VfsError::BadDescriptor),
+        }
+    }
+
+    /// A new descriptor, the lowest free one, for the open file of `fd`.
+    /// Close-on-exec is clear on it.
+    pub fn dup(&self, fd: usize) -> vfs::Result<usize> {
+        self.dup_from(fd, 0)
+    }
+
+    /// Like [`dup`](Self::dup), but the new descriptor is at least `min`,
+    /// as `fcntl(F_DUPFD)` does.
+    pub fn dup_from(&self, fd: usize, min: usize) -> vfs::Result<usize> {
+        let mut slots = self.slots.lock();
+        let file = match slots.get(fd) {
+            Some(Some(descriptor)) => descriptor.file.clone(),
+            _ => return Err(VfsError::BadDescriptor),
+        };
+        let descriptor = Descriptor {
+            file,
+            close_on_exec: false,
+        };
+        Self::insert_from(&mut slots, min, descriptor)
+    }
+
+    /// Makes `new` a descriptor for the open file of `fd`, closing what
+    /// `new` was before. Does nothing if the two are the same.
+    pub fn dup2(&self, fd: usize, new: usize) -> vfs::Result<usize> {
+        if new >= MAX_FDS {
+            return Err(VfsError::BadDescriptor);
+        }
+        let mut slots = self.slots.lock();
+        let file = match slots.get(fd) {
+            Some(Some(descriptor)) => descriptor.file.clone(),
+            _ => return Err(VfsError::BadDescriptor),
+        };
+        if fd == new {
+            return Ok(new);
+        }
+        if new >= slots.len() {
+            slots.resize(new + 1, None);
+        }
+        let replaced = slots[new].replace(Descriptor {
+            file,
+            close_on_exec: false,
+        });
+        drop(slots);
+        drop(replaced);
+        Ok(new)
+    }
+
+    pub fn close_on_exec(&self, fd: usize) -> vfs::Result<bool> {
+        match self.slots.lock().get(fd) {
+            Some(Some(descriptor)) => Ok(descriptor.close_on_exec),
+            _ => Err(VfsError::BadDescriptor),
+        }
+    }
+
+    pub fn set_close_on_exec(&self, fd: usize, on: bool) -> vfs::Result<()> {
+        match self.slots.lock().get_mut(fd) {
+            Some(Some(descriptor)) => {
+                descriptor.close_on_exec = on;
+                Ok(())
+            }
+            _ => Err(VfsError::BadDescriptor),
+        }
+    }
+
+    /// Closes every descriptor marked close-on-exec, as exec does.
+    pub fn close_for_exec(&self) {
+        let closed: Vec<_> = self
+            .slots
+            .lock()
+            .iter_mut()
+            .filter(|slot| {
+                slot.as_ref()
+                    .is_some_and(|descriptor| descriptor.close_on_exec)
+            })
+            .filter_map(Option::take)
+            .collect();
+        drop(closed);
+    }
+
+    /// Descriptors in use.
+    pub fn count(&self) -> usize {
+        self.slots.lock().iter().flatten().count()
+    }
+}
+
+/// Opens `path` on a new descriptor of the current task, close-on-exec if
+/// `flags` has CLOSE_ON_EXEC.
+pub fn open(path: &str, flags: OpenFlags) -> vfs::Result<usize> {
+    let file = vfs::open(path, flags)?;
+    let close_on_exec = flags.contains(OpenFlags::CLOSE_ON_EXEC);
+    task::current().files().insert(file, close_on_exec)
+}
+
+/// Makes a pipe on two new descriptors of the current task, like `pipe2`;
+/// returns the read end's, then the write end's.
+pub fn pipe(flags: OpenFlags) -> vfs::Result<[usize; 2]> {
+    let (reader, writer) = pipe::pipe(flags);
+    let close_on_exec = flags.contains(OpenFlags::CLOSE_ON_EXEC);
+    let task = task::current();
+    let files = task.files();
+    let read = files.insert(reader, close_on_exec)?;
+    match files.insert(writer, close_on_exec) {
+        Ok(write) => Ok([read, write]),
+        Err(err) => {
+            let _ = files.close(read);
+            Err(err)
+        }
+    }
+}
@@ -2,7 +2,20 @@
 
 use core::char;
 
-use bootloader_api::info::{FrameBuffer, PixelFormat};
+use bootloader_api::info::{FrameBufferInfo, PixelFormat};
+use noto_sans_mono_bitmap::{
+    FontWeight, RasterHeight, RasterizedChar, get_raster, get_raster_width,
+};
+
+use crate::devfs::{self, Device, ioctl};
+use crate::logger;
+use crate::vfs::{self, VfsError};
+
+pub const CHAR_RASTER_HEIGHT: RasterHeight = RasterHeight::Size16;
+pub const CHAR_RASTER_WIDTH: usize = get_raster_width(FontWeight::Regular, CHAR_RASTER_HEIGHT);
+
+/// Drawn for characters the font does not cover.
+const BACKUP_CHAR: char = '�';
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Position {
@@ -17,38 +30,72 @@ pub struct Color {
     pub blue: u8,
 }
 
+impl Color {
+    pub const BLACK: Color = Color::new(0, 0, 0);
+    pub const WHITE: Color = Color::new(255, 255, 255);
+
+    pub const fn new(red: u8, green: u8, blue: u8) -> Self {
+        Color { red, green, blue }
+    }
+
+    /// Scales every channel by `intensity / 255`.
+    fn scaled(self, intensity: u8) -> Self {
+        let scale = |channel: u8| (channel as u16 * intensity as u16 / 255) as u8;
+        Color::new(scale(self.red), scale(self.green), scale(self.blue))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Pixel {
     pub position: Position,
     pub color: Color,
 }
 
-pub(crate) struct FramebufferDisplay<'f> {
-    framebuffer: &'f mut FrameBuffer,
+pub struct FramebufferDisplay<'f> {
+    buffer: &'f mut [u8],
+    info: FrameBufferInfo,
 }
 
 impl<'f> FramebufferDisplay<'f> {
-    pub fn new(framebuffer: &mut FrameBuffer) -> FramebufferDisplay {
-        FramebufferDisplay { framebuffer }
+    pub fn new(buffer: &'f mut [u8], info: FrameBufferInfo) -> FramebufferDisplay<'f> {
+        FramebufferDisplay { buffer, info }
     }
 
-    fn set_pixel(&mut self, position: Position, color: Color) {
-        let info = self.framebuffer.info();
+    /// The raw pixel memory.
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        self.buffer
+    }
 
-        let byte_offset = {
-            let y_offset = position.y * info.stride;
-            let pixel_offset = y_offset + position.x;
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
+    }
 
-            pixel_offset * info.bytes_per_pixel
-        };
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    fn byte_offset(&self, position: Position) -> usize {
+        (position.y * self.info.stride + position.x) * self.info.bytes_per_pixel
+    }
 
-        let pixel_buffer = &mut self.framebuffer.buffer_mut()[byte_offset..];
+    /// Writes one pixel. Positions outside the visible area are ignored.
+    pub fn set_pixel(&mut self, position: Position, color: Color) {
+        if position.x >= self.info.width || position.y >= self.info.height {
+            return;
+        }
+        let byte_offset = self.byte_offset(position);
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let pixel_buffer = &mut self.buffer[byte_offset..byte_offset + bytes_per_pixel];
 
-        match info.pixel_format {
+        match self.info.pixel_format {
             PixelFormat::Rgb => {
                 pixel_buffer[0] = color.red;
-                pixel_buffer[1] = color.blue;
-                pixel_buffer[2] = color.green;
+                pixel_buffer[1] = color.green;
+                pixel_buffer[2] = color.blue;
             }
             PixelFormat::Bgr => {
                 pixel_buffer[0] = color.blue;
@@ -56,12 +103,244 @@ impl<'f> FramebufferDisplay<'f> {
                 pixel_buffer[2] = color.red;
             }
             PixelFormat::U8 => {
-                let gray = (color.red + color.green + color.blue) / 3;
-                pixel_buffer[0] = gray;
+                let gray = (color.red as u16 + color.green as u16 + color.blue as u16) / 3;
+                pixel_buffer[0] = gray as u8;
+            }
+            PixelFormat::Unknown {
+                red_position,
+                green_position,
+                blue_position,
+            } => {
+                let channel = |value: u8, position: u8| {
+                    let width = channel_width(
+                        position,
+                        [red_position, green_position, blue_position],
+                        bytes_per_pixel,
+                    );
+                    ((value as u32) >> (8 - width)) << position
+                };
+                let packed = channel(color.red, red_position)
+                    | channel(color.green, green_position)
+                    | channel(color.blue, blue_position);
+                pixel_buffer.copy_from_slice(&packed.to_le_bytes()[..bytes_per_pixel]);
             }
             other => panic!("Unknown pixel format: {other:?}"),
         }
     }
 
-    fn draw_char(&mut self, char: char, pixel: Pixel) {}
+    /// Reads back one pixel, or `None` outside the visible area.
+    pub fn get_pixel(&self, position: Position) -> Option<Color> {
+        if position.x >= self.info.width || position.y >= self.info.height {
+            return None;
+        }
+        let byte_offset = self.byte_offset(position);
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let pixel_buffer = &self.buffer[byte_offset..byte_offset + bytes_per_pixel];
+
+        let color = match self.info.pixel_format {
+            PixelFormat::Rgb => Color::new(pixel_buffer[0], pixel_buffer[1], pixel_buffer[2]),
+            PixelFormat::Bgr => Color::new(pixel_buffer[2], pixel_buffer[1], pixel_buffer[0]),
+            PixelFormat::U8 => Color::new(pixel_buffer[0], pixel_buffer[0], pixel_buffer[0]),
+            PixelFormat::Unknown {
+                red_position,
+                green_position,
+                blue_position,
+            } => {
+                let mut bytes = [0; 4];
+                bytes[..bytes_per_pixel].copy_from_slice(pixel_buffer);
+                let packed = u32::from_le_bytes(bytes);
+                let channel = |position: u8| {
+                    let width = channel_width(
+                        position,
+                        [red_position, green_position, blue_position],
+                        bytes_per_pixel,
+                    );
+                    (((packed >> position) & ((1 << width) - 1)) << (8 - width)) as u8
+                };
+                Color::new(
+                    channel(red_position),
+                    channel(green_position),
+                    channel(blue_position),
+                )
+            }
+            other => panic!("Unknown pixel format: {other:?}"),
+        };
+        Some(color)
+    }
+
+    pub fn fill_rect(&mut self, origin: Position, width: usize, height: usize, color: Color) {
+        for y in origin.y..(origin.y + height).min(self.info.height) {
+            for x in origin.x..(origin.x + width).min(self.info.width) {
+                self.set_pixel(Position { x, y }, color);
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        if color == Color::BLACK {
+            self.buffer.fill(0);
+        } else {
+            self.fill_rect(
+                Position { x: 0, y: 0 },
+                self.info.width,
+                self.info.height,
+                color,
+            );
+        }
+    }
+
+    /// Moves the picture up by `rows` pixel rows and blanks the freed rows.
+    pub fn scroll_up(&mut self, rows: usize) {
+        let rows = rows.min(self.info.height);
+        let row_bytes = self.info.stride * self.info.bytes_per_pixel;
+        let visible = self.info.height * row_bytes;
+        self.buffer.copy_within(rows * row_bytes..visible, 0);
+        self.buffer[visible - rows * row_bytes..visible].fill(0);
+    }
+
+    /// Renders `char` with its top-left corner at `pixel.position`, blending
+    /// the glyph's anti-aliasing against black.
+    pub fn draw_char(&mut self, char: char, pixel: Pixel) {
+        let raster = char_raster(char);
+        for (row, intensities) in raster.raster().iter().enumerate() {
+            for (column, intensity) in intensities.iter().enumerate() {
+                let position = Position {
+                    x: pixel.position.x + column,
+                    y: pixel.position.y + row,
+                };
+                self.set_pixel(position, pixel.color.scaled(*intensity));
+            }
+        }
+    }
+}
+
+/// Bits used by the channel starting at `position` in a packed pixel: each
+/// channel runs up to the next one, capped at 8.
+fn channel_width(position: u8, positions: [u8; 3], bytes_per_pixel: usize) -> u8 {
+    let total_bits = (bytes_per_pixel * 8) as u8;
+    positions
+        .into_iter()
+        .filter(|&other| other > position)
+        .min()
+        .unwrap_or(total_bits)
+        .min(position + 8)
+        - position
+}
+
+/// `/dev/fb0`, the display of the framebuffer console as raw pixel memory.
+/// Drawing through it and logging to the console both go to the screen.
+pub struct FramebufferDevice;
+
+impl FramebufferDevice {
+    fn with_buffer<R>(f: impl FnOnce(&mut [u8], FrameBufferInfo) -> R) -> vfs::Result<R> {
+        logger::with_framebuffer(|display| {
+            let info = display.info();
+            f(display.buffer_mut(), info)
+        })
+        .ok_or(VfsError::NoDevice)
+    }
+}
+
+impl Device for FramebufferDevice {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        FramebufferDevice::with_buffer(|buffer, _| {
+            let start = (offset as usize).min(buffer.len());
+            let len = buf.len().min(buffer.len() - start);
+            buf[..len].copy_from_slice(&buffer[start..start + len]);
+            len
+        })
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        let written = FramebufferDevice::with_buffer(|buffer, _| {
+            let start = (offset as usize).min(buffer.len());
+            let len = buf.len().min(buffer.len() - start);
+            buffer[start..start + len].copy_from_slice(&buf[..len]);
+            len
+        })?;
+        match written {
+            0 if !buf.is_empty() => Err(VfsError::NoSpace),
+            written => Ok(written),
+        }
+    }
+
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> vfs::Result<usize> {
+        let (len, info) = FramebufferDevice::with_buffer(|buffer, info| (buffer.len(), info))?;
+        match request {
+            ioctl::FBIOGET_VSCREENINFO => devfs::put_arg(arg, &var_screeninfo(info)),
+            ioctl::FBIOGET_FSCREENINFO => devfs::put_arg(arg, &fix_screeninfo(info, len)),
+            _ => Err(VfsError::NotTty),
+        }
+    }
+
+    fn size(&self) -> u64 {
+        FramebufferDevice::with_buffer(|buffer, _| buffer.len() as u64).unwrap_or(0)
+    }
+}
+
+/// Linux's `struct fb_var_screeninfo`: 40 u32 fields, of which the
+/// resolution, the pixel depth and the channel layout are filled in.
+fn var_screeninfo(info: FrameBufferInfo) -> [u8; 160] {
+    let bits = (info.bytes_per_pixel * 8) as u32;
+    let mut fields = [0u32; 40];
+    fields[..7].copy_from_slice(&[
+        info.width as u32,
+        info.height as u32,
+        info.stride as u32,
+        info.height as u32,
+        0,
+        0,
+        bits,
+    ]);
+    // bit offset and width of red, green and blue
+    let channels = match info.pixel_format {
+        PixelFormat::Rgb => [(0, 8), (8, 8), (16, 8)],
+        PixelFormat::Bgr => [(16, 8), (8, 8), (0, 8)],
+        PixelFormat::U8 => {
+            fields[7] = 1; // grayscale
+            [(0, 8); 3]
+        }
+        PixelFormat::Unknown {
+            red_position,
+            green_position,
+            blue_position,
+        } => {
+            let positions = [red_position, green_position, blue_position];
+            positions.map(|position| {
+                let width = channel_width(position, positions, info.bytes_per_pixel);
+                (position as u32, width as u32)
+            })
+        }
+        other => panic!("Unknown pixel format: {other:?}"),
+    };
+    for (index, (offset, length)) in channels.into_iter().enumerate() {
+        fields[8 + 3 * index] = offset;
+        fields[9 + 3 * index] = length;
+    }
+    // height and width of the picture in millimetres, unknown
+    fields[22] = u32::MAX;
+    fields[23] = u32::MAX;
+
+    let mut bytes = [0; 160];
+    for (chunk, field) in bytes.as_chunks_mut::<4>().0.iter_mut().zip(fields) {
+        *chunk = field.to_le_bytes();
+    }
+    bytes
+}
+
+/// Linux's `struct fb_fix_screeninfo` for packed true colour pixels.
+fn fix_screeninfo(info: FrameBufferInfo, len: usize) -> [u8; 80] {
+    const FB_VISUAL_TRUECOLOR: u32 = 2;
+    let mut bytes = [0; 80];
+    bytes[..8].copy_from_slice(b"karkinos");
+    bytes[24..28].copy_from_slice(&(len as u32).to_le_bytes());
+    bytes[36..40].copy_from_slice(&FB_VISUAL_TRUECOLOR.to_le_bytes());
+    let line_length = (info.stride * info.bytes_per_pixel) as u32;
+    bytes[48..52].copy_from_slice(&line_length.to_le_bytes());
+    bytes
+}
+
+fn char_raster(char: char) -> RasterizedChar {
+    let get = |char| get_raster(char, FontWeight::Regular, CHAR_RASTER_HEIGHT);
+    get(char).unwrap_or_else(|| get(BACKUP_CHAR).expect("backup char is always rasterized"))
 }
@@ -0,0 +1,150 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::memory::{self, FRAME_SIZE};
+use crate::sync::IrqSpinLock;
+
+// A first-fit allocator over a list of free blocks kept in address order, so
+// that a freed block merges with free neighbours. Each free block stores its
+// size and the next pointer in itself. Sizes and addresses are multiples of
+// BLOCK_ALIGN, which makes any leftover piece big enough to hold that header.
+// The heap grows by contiguous frames taken from the frame allocator and
+// never gives them back.
+
+const BLOCK_ALIGN: usize = 16;
+/// Frames the heap grows by at least.
+const GROW_FRAMES: usize = 256;
+
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes taken from the frame allocator.
+    pub size: usize,
+    /// Bytes handed out, rounded up to the block granularity.
+    pub used: usize,
+}
+
+struct Heap {
+    head: *mut FreeBlock,
+    stats: HeapStats,
+}
+
+// The free list is only reached through the lock.
+unsafe impl Send for Heap {}
+
+static HEAP: IrqSpinLock<Heap> = IrqSpinLock::new(Heap {
+    head: ptr::null_mut(),
+    stats: HeapStats { size: 0, used: 0 },
+});
+
+fn block_size(layout: Layout) -> usize {
+    layout.size().max(1).next_multiple_of(BLOCK_ALIGN)
+}
+
+impl Heap {
+    /// Puts `[start, start + size)` on the free list, merging it with the
+    /// blocks around it.
+    unsafe fn insert(&mut self, start: usize, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = unsafe { (*next).next };
+        }
+
+        let block = start as *mut FreeBlock;
+        unsafe { block.write(FreeBlock { size, next }) };
+        if !next.is_null() && start + size == next as usize {
+            unsafe {
+                (*block).size += (*next).size;
+                (*block).next = (*next).next;
+            }
+        }
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + unsafe { (*prev).size } == start {
+            unsafe {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            }
+        } else {
+            unsafe { (*prev).next = block };
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let size = block_size(layout);
+        let align = layout.align().max(BLOCK_ALIGN);
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() {
+            let start = current as usize;
+            let FreeBlock { size: free, next } = unsafe { current.read() };
+            let end = start + free;
+            let aligned = start.next_multiple_of(align);
+            if aligned + size <= end {
+                match prev.is_null() {
+                    true => self.head = next,
+                    false => unsafe { (*prev).next = next },
+                }
+                unsafe {
+                    if aligned > start {
+                        self.insert(start, aligned - start);
+                    }
+                    if aligned + size < end {
+                        self.insert(aligned + size, end - aligned - size);
+                    }
+                }
+                self.stats.used += size;
+                return aligned as *mut u8;
+            }
+            prev = current;
+            current = next;
+        }
+        ptr::null_mut()
+    }
+
+    /// Adds enough frames for `layout` to the heap.
+    fn grow(&mut self, layout: Layout) -> bool {
+        let needed = block_size(layout) + layout.align().max(BLOCK_ALIGN);
+        let frames = needed.div_ceil(FRAME_SIZE as usize).max(GROW_FRAMES);
+        let Some(phys) = memory::allocate_frames(frames) else {
+            return false;
+        };
+        let size = frames * FRAME_SIZE as usize;
+        unsafe { self.insert(memory::phys_to_virt(phys) as usize, size) };
+        self.stats.size += size;
+        true
+    }
+}
+
+pub struct Allocator;
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = HEAP.lock();
+        let block = heap.allocate(layout);
+        if block.is_null() && heap.grow(layout) {
+            return heap.allocate(layout);
+        }
+        block
+    }
+
+    unsafe fn dealloc(&self, block: *mut u8, layout: Layout) {
+        let mut heap = HEAP.lock();
+        let size = block_size(layout);
+        heap.stats.used -= size;
+        unsafe { heap.insert(block as usize, size) };
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator;
+
+pub fn stats() -> HeapStats {
+    HEAP.lock().stats
+}
@@ -0,0 +1,357 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use crate::port::Port;
+use crate::sync::IrqSpinLock;
+use crate::time;
+
+// Intel 8042 PS/2 controller. Both devices share the data port; the status
+// register tells whether the byte waiting in the output buffer came from the
+// first (keyboard) or the second (aux, usually a mouse) port.
+
+const DATA: u16 = 0x60;
+const STATUS: u16 = 0x64;
+const COMMAND: u16 = 0x64;
+
+mod status {
+    pub const OUTPUT_FULL: u8 = 1 << 0;
+    pub const INPUT_FULL: u8 = 1 << 1;
+    /// The byte in the output buffer is from the second port.
+    pub const AUX_DATA: u8 = 1 << 5;
+}
+
+mod cmd {
+    pub const READ_CONFIG: u8 = 0x20;
+    pub const WRITE_CONFIG: u8 = 0x60;
+    pub const DISABLE_SECOND: u8 = 0xA7;
+    pub const ENABLE_SECOND: u8 = 0xA8;
+    pub const TEST_SECOND: u8 = 0xA9;
+    pub const SELF_TEST: u8 = 0xAA;
+    pub const TEST_FIRST: u8 = 0xAB;
+    pub const DISABLE_FIRST: u8 = 0xAD;
+    pub const ENABLE_FIRST: u8 = 0xAE;
+    /// The next byte written to the data port goes to the second port.
+    pub const WRITE_SECOND: u8 = 0xD4;
+}
+
+mod config {
+    pub const FIRST_IRQ: u8 = 1 << 0;
+    pub const SECOND_IRQ: u8 = 1 << 1;
+    pub const FIRST_CLOCK_DISABLED: u8 = 1 << 4;
+    pub const SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+    /// Translate scancode set 2 from the keyboard into set 1.
+    pub const TRANSLATION: u8 = 1 << 6;
+}
+
+/// Replies PS/2 devices send to commands.
+pub mod response {
+    pub const SELF_TEST_PASSED: u8 = 0xAA;
+    pub const ECHO: u8 = 0xEE;
+    pub const ACK: u8 = 0xFA;
+    pub const RESEND: u8 = 0xFE;
+}
+
+const CONTROLLER_SELF_TEST_OK: u8 = 0x55;
+const PORT_TEST_OK: u8 = 0x00;
+
+const TIMEOUT: Duration = Duration::from_millis(50);
+const RETRIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    First,
+    Second,
+}
+
+impl Ps2Port {
+    pub fn irq(self) -> u8 {
+        match self {
+            Ps2Port::First => 1,
+            Ps2Port::Second => 12,
+        }
+    }
+
+    fn irq_enable(self) -> u8 {
+        match self {
+            Ps2Port::First => config::FIRST_IRQ,
+            Ps2Port::Second => config::SECOND_IRQ,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I8042Error {
+    NotPresent,
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(Ps2Port, u8),
+    /// The device did not come back from a reset with a passed self test.
+    DeviceSelfTestFailed(Ps2Port, u8),
+    /// The port is missing or failed its interface test.
+    PortUnavailable(Ps2Port),
+    /// The device answered a command with something other than ACK.
+    NoAck(u8),
+}
+
+impl fmt::Display for I8042Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            I8042Error::NotPresent => write!(f, "no controller"),
+            I8042Error::Timeout => write!(f, "timed out"),
+            I8042Error::SelfTestFailed(code) => write!(f, "self test failed ({code:#04x})"),
+            I8042Error::PortTestFailed(port, code) => {
+                write!(f, "{port:?} port failed its test ({code:#04x})")
+            }
+            I8042Error::DeviceSelfTestFailed(port, code) => {
+                write!(
+                    f,
+                    "device on {port:?} port failed its self test ({code:#04x})"
+                )
+            }
+            I8042Error::PortUnavailable(port) => write!(f, "{port:?} port is not available"),
+            I8042Error::NoAck(byte) => write!(f, "device replied {byte:#04x} instead of ACK"),
+        }
+    }
+}
+
+/// Serializes command sequences, which take several port accesses.
+static LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+static FIRST_AVAILABLE: AtomicBool = AtomicBool::new(false);
+static SECOND_AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(STATUS).read() }
+}
+
+fn wait_for(mask: u8, set: bool) -> Result<(), I8042Error> {
+    let deadline = time::uptime() + TIMEOUT;
+    while (status() & mask != 0) != set {
+        if time::uptime() > deadline {
+            return Err(I8042Error::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+fn write_command(command: u8) -> Result<(), I8042Error> {
+    wait_for(status::INPUT_FULL, false)?;
+    unsafe { Port::<u8>::new(COMMAND).write(command) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), I8042Error> {
+    wait_for(status::INPUT_FULL, false)?;
+    unsafe { Port::<u8>::new(DATA).write(byte) };
+    Ok(())
+}
+
+fn read_data_polled() -> Result<u8, I8042Error> {
+    wait_for(status::OUTPUT_FULL, true)?;
+    Ok(unsafe { Port::<u8>::new(DATA).read() })
+}
+
+fn flush_output() {
+    while status() & status::OUTPUT_FULL != 0 {
+        unsafe { Port::<u8>::new(DATA).read() };
+    }
+}
+
+fn read_config() -> Result<u8, I8042Error> {
+    write_command(cmd::READ_CONFIG)?;
+    read_data_polled()
+}
+
+fn write_config(value: u8) -> Result<(), I8042Error> {
+    write_command(cmd::WRITE_CONFIG)?;
+    write_data(value)
+}
+
+/// Resets and tests the controller and enables every working port.
+///
+/// Device IRQs stay off; drivers turn them on with [`enable_irq`] once their
+/// device is set up. Scancode translation is disabled.
+pub fn init() -> Result<(), I8042Error> {
+    let _guard = LOCK.lock();
+
+    // nothing drives the bus without a controller
+    if status() == 0xFF {
+        return Err(I8042Error::NotPresent);
+    }
+
+    write_command(cmd::DISABLE_FIRST)?;
+    write_command(cmd::DISABLE_SECOND)?;
+    flush_output();
+
+    let mut value =
+        read_config()? & !(config::FIRST_IRQ | config::SECOND_IRQ | config::TRANSLATION);
+    write_config(value)?;
+
+    write_command(cmd::SELF_TEST)?;
+    match read_data_polled()? {
+        CONTROLLER_SELF_TEST_OK => {}
+        code => return Err(I8042Error::SelfTestFailed(code)),
+    }
+    // some controllers reset themselves during the self test
+    write_config(value)?;
+
+    // a second port starts clocking when enabled
+    write_command(cmd::ENABLE_SECOND)?;
+    let dual = read_config()? & config::SECOND_CLOCK_DISABLED == 0;
+    write_command(cmd::DISABLE_SECOND)?;
+
+    write_command(cmd::TEST_FIRST)?;
+    let first = read_data_polled()?;
+    if first == PORT_TEST_OK {
+        write_command(cmd::ENABLE_FIRST)?;
+        value &= !config::FIRST_CLOCK_DISABLED;
+        FIRST_AVAILABLE.store(true, Ordering::Release);
+    } else {
+        log::warn!(
+            "i8042: {}",
+            I8042Error::PortTestFailed(Ps2Port::First, first)
+        );
+    }
+
+    if dual {
+        write_command(cmd::TEST_SECOND)?;
+        let second = read_data_polled()?;
+        if second == PORT_TEST_OK {
+            write_command(cmd::ENABLE_SECOND)?;
+            value &= !config::SECOND_CLOCK_DISABLED;
+            SECOND_AVAILABLE.store(true, Ordering::Release);
+        } else {
+            log::warn!(
+                "i8042: {}",
+                I8042Error::PortTestFailed(Ps2Port::Second, second)
+            );
+        }
+    }
+
+    write_config(value)?;
+    flush_output();
+    log::info!(
+        "i8042: first port {}, second port {}",
+        if first == PORT_TEST_OK {
+            "ok"
+        } else {
+            "failed"
+        },
+        match dual {
+            false => "absent",
+            true if SECOND_AVAILABLE.load(Ordering::Relaxed) => "ok",
+            true => "failed",
+        }
+    );
+    Ok(())
+}
+
+pub fn is_available(port: Ps2Port) -> bool {
+    match port {
+        Ps2Port::First => FIRST_AVAILABLE.load(Ordering::Acquire),
+        Ps2Port::Second => SECOND_AVAILABLE.load(Ordering::Acquire),
+    }
+}
+
+fn check_available(port: Ps2Port) -> Result<(), I8042Error> {
+    if is_available(port) {
+        Ok(())
+    } else {
+        Err(I8042Error::PortUnavailable(port))
+    }
+}
+
+fn write_device(port: Ps2Port, byte: u8) -> Result<(), I8042Error> {
+    if port == Ps2Port::Second {
+        write_command(cmd::WRITE_SECOND)?;
+    }
+    write_data(byte)
+}
+
+/// Reads a byte from `port`, discarding anything the other port sends in
+/// the meantime.
+fn read_device_polled(port: Ps2Port, timeout: Duration) -> Result<u8, I8042Error> {
+    let deadline = time::uptime() + timeout;
+    loop {
+        let status = status();
+        if status & status::OUTPUT_FULL != 0 {
+            let byte = unsafe { Port::<u8>::new(DATA).read() };
+            if (status & status::AUX_DATA != 0) == (port == Ps2Port::Second) {
+                return Ok(byte);
+            }
+        } else if time::uptime() > deadline {
+            return Err(I8042Error::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Sends a command byte to the device on `port` and waits for its ACK,
+/// repeating it if the device asks for a resend.
+///
+/// Polls the controller, so it must not be used once the port's IRQ is
+/// enabled.
+pub fn command(port: Ps2Port, byte: u8) -> Result<(), I8042Error> {
+    check_available(port)?;
+    let _guard = LOCK.lock();
+    for _ in 0..RETRIES {
+        write_device(port, byte)?;
+        match read_device_polled(port, TIMEOUT)? {
+            response::ACK => return Ok(()),
+            response::RESEND => continue,
+            other => return Err(I8042Error::NoAck(other)),
+        }
+    }
+    Err(I8042Error::NoAck(response::RESEND))
+}
+
+/// Waits for the next byte from the device on `port`, e.g. a command's
+/// reply after its ACK. Same restrictions as [`command`].
+pub fn read(port: Ps2Port, timeout: Duration) -> Result<u8, I8042Error> {
+    check_available(port)?;
+    let _guard = LOCK.lock();
+    read_device_polled(port, timeout)
+}
+
+/// Sends a byte to the device on `port` without waiting for a reply, which
+/// then arrives through the port's IRQ.
+pub fn send(port: Ps2Port, byte: u8) -> Result<(), I8042Error> {
+    check_available(port)?;
+    let _guard = LOCK.lock();
+    write_device(port, byte)
+}
+
+/// For IRQ handlers: takes the pending byte if it came from `port`.
+pub fn receive(port: Ps2Port) -> Option<u8> {
+    let _guard = LOCK.lock();
+    let status = status();
+    let from_second = status & status::AUX_DATA != 0;
+    if status & status::OUTPUT_FULL == 0 || from_second != (port == Ps2Port::Second) {
+        return None;
+    }
+    Some(unsafe { Port::<u8>::new(DATA).read() })
+}
+
+/// Lets the controller raise `port`'s IRQ whenever the device sends a byte.
+pub fn enable_irq(port: Ps2Port) -> Result<(), I8042Error> {
+    check_available(port)?;
+    let _guard = LOCK.lock();
+    let value = read_config()?;
+    write_config(value | port.irq_enable())
+}
+
+pub fn disable_irq(port: Ps2Port) -> Result<(), I8042Error> {
+    let _guard = LOCK.lock();
+    let value = read_config()?;
+    write_config(value & !port.irq_enable())
+}
+
+/// Turns scancode translation on, for keyboards stuck in set 2 that cannot
+/// be switched, so the driver sees set 1.
+pub fn enable_translation() -> Result<(), I8042Error> {
+    let _guard = LOCK.lock();
+    let value = read_config()?;
+    write_config(value | config::TRANSLATION)
+}
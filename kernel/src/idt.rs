@@ -2,6 +2,7 @@
 
 use crate::gdt::{PrivilegeLevel, SegmentSelector};
 use core::marker::PhantomData;
+use core::ops::{Index, IndexMut};
 
 #[repr(C)]
 #[repr(align(16))]
@@ -82,6 +83,28 @@ impl InterruptDescriptorTable {
     }
 }
 
+/// Hardware and software interrupt vectors (32..=255). Exceptions are only
+/// reachable through their named fields.
+impl Index<u8> for InterruptDescriptorTable {
+    type Output = IDTEntry<HandlerFunc>;
+
+    fn index(&self, vector: u8) -> &Self::Output {
+        match vector {
+            32..=255 => &self.interrupts[vector as usize - 32],
+            _ => panic!("vector {vector} is an exception, use its named field"),
+        }
+    }
+}
+
+impl IndexMut<u8> for InterruptDescriptorTable {
+    fn index_mut(&mut self, vector: u8) -> &mut Self::Output {
+        match vector {
+            32..=255 => &mut self.interrupts[vector as usize - 32],
+            _ => panic!("vector {vector} is an exception, use its named field"),
+        }
+    }
+}
+
 #[repr(C, packed)]
 struct IDTPointer {
     limit: u16,
@@ -209,3 +232,13 @@ pub struct InterruptStackFrame {
     stack_pointer: u64,
     stack_segment: u64,
 }
+
+impl InterruptStackFrame {
+    pub fn instruction_pointer(&self) -> u64 {
+        self.instruction_pointer
+    }
+
+    pub fn cpu_flags(&self) -> u64 {
+        self.cpu_flags
+    }
+}
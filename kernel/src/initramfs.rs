@@ -0,0 +1,270 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::fmt;
+
+use bootloader_api::BootInfo;
+
+use crate::tmpfs::TmpFs;
+use crate::vfs::{self, OpenFlags, VfsError};
+
+// The bootloader loads a ramdisk next to the kernel and maps it; build.rs
+// and the test runner fill it with a cpio archive in the "new ASCII" format
+// that Linux initramfs images use. Each member is a 110-byte header of hex
+// fields, the NUL-terminated name and the data, the last two padded to 4
+// bytes. A member named TRAILER!!! ends the archive.
+//
+// The archive is unpacked into a tmpfs mounted at `/`. Members that share
+// an inode number become hard links to the first of them; newc stores the
+// data only with the last name of a file, so it is written when it comes.
+// Device nodes and fifos are skipped.
+
+const MAGIC: &[u8; 6] = b"070701";
+/// The same layout with a checksum of the data, which isn't checked.
+const MAGIC_CRC: &[u8; 6] = b"070702";
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+mod mode {
+    pub const TYPE_MASK: u32 = 0o170000;
+    pub const DIRECTORY: u32 = 0o040000;
+    pub const REGULAR: u32 = 0o100000;
+    pub const SYMLINK: u32 = 0o120000;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    BadMagic,
+    BadHeader,
+    Truncated,
+    BadName,
+}
+
+impl fmt::Display for CpioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpioError::BadMagic => write!(f, "not a newc cpio archive"),
+            CpioError::BadHeader => write!(f, "malformed cpio header"),
+            CpioError::Truncated => write!(f, "cpio archive truncated"),
+            CpioError::BadName => write!(f, "cpio member name is not UTF-8"),
+        }
+    }
+}
+
+/// One member of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// Path relative to the root of the archive, without a leading `./`.
+    pub name: &'a str,
+    pub ino: u32,
+    /// File type and permission bits, as in `st_mode`.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub links: u32,
+    pub mtime: u32,
+    pub data: &'a [u8],
+}
+
+/// Walks the members of a newc archive, up to the trailer.
+pub struct Archive<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Archive<'a> {
+    pub fn new(bytes: &'a [u8]) -> Archive<'a> {
+        Archive {
+            bytes,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let bytes = self.bytes;
+        let header = bytes
+            .get(self.offset..self.offset + HEADER_LEN)
+            .ok_or(CpioError::Truncated)?;
+        if &header[..6] != MAGIC && &header[..6] != MAGIC_CRC {
+            return Err(CpioError::BadMagic);
+        }
+        // ino, mode, uid, gid, nlink, mtime, filesize, devmajor, devminor,
+        // rdevmajor, rdevminor, namesize, check
+        let field = |index: usize| {
+            let hex = &header[6 + index * 8..6 + (index + 1) * 8];
+            core::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .ok_or(CpioError::BadHeader)
+        };
+        let size = field(6)? as usize;
+        let name_len = field(11)? as usize;
+        if name_len == 0 {
+            return Err(CpioError::BadHeader);
+        }
+
+        let name_start = self.offset + HEADER_LEN;
+        let data_start = (name_start + name_len).next_multiple_of(4);
+        let data_end = data_start + size;
+        let name = bytes
+            .get(name_start..name_start + name_len - 1)
+            .ok_or(CpioError::Truncated)?;
+        let data = bytes
+            .get(data_start..data_end)
+            .ok_or(CpioError::Truncated)?;
+        let name = core::str::from_utf8(name).map_err(|_| CpioError::BadName)?;
+        self.offset = data_end.next_multiple_of(4);
+        if name == TRAILER {
+            return Ok(None);
+        }
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        Ok(Some(Entry {
+            name: if name == "." { "" } else { name },
+            ino: field(0)?,
+            mode: field(1)?,
+            uid: field(2)?,
+            gid: field(3)?,
+            links: field(4)?,
+            mtime: field(5)?,
+            data,
+        }))
+    }
+}
+
+impl<'a> Iterator for Archive<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        // stop after an error rather than reading garbage as headers
+        let entry = self.next_entry().transpose();
+        self.done = matches!(entry, Some(Err(_)) | None);
+        entry
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnpackError {
+    Archive(CpioError),
+    Vfs(VfsError),
+}
+
+impl fmt::Display for UnpackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnpackError::Archive(err) => write!(f, "{}", err),
+            UnpackError::Vfs(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<CpioError> for UnpackError {
+    fn from(err: CpioError) -> UnpackError {
+        UnpackError::Archive(err)
+    }
+}
+
+impl From<VfsError> for UnpackError {
+    fn from(err: VfsError) -> UnpackError {
+        UnpackError::Vfs(err)
+    }
+}
+
+/// Creates the directories leading to `path`, for archives that list a
+/// file before its directory or leave directories out.
+fn make_parents(path: &str) -> vfs::Result<()> {
+    let Some((parent, _)) = path
+        .rsplit_once('/')
+        .filter(|(parent, _)| !parent.is_empty())
+    else {
+        return Ok(());
+    };
+    match vfs::stat(parent) {
+        Ok(_) => Ok(()),
+        Err(VfsError::NotFound) => {
+            make_parents(parent)?;
+            vfs::mkdir(parent, 0o755)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn write_file(path: &str, data: &[u8]) -> vfs::Result<()> {
+    let file = vfs::open(path, OpenFlags::WRITE | OpenFlags::TRUNCATE)?;
+    match file.write(data)? {
+        written if written == data.len() => Ok(()),
+        _ => Err(VfsError::NoSpace),
+    }
+}
+
+/// Unpacks `archive` below the directory `base`, returning the number of
+/// members created.
+pub fn unpack(archive: &[u8], base: &str) -> Result<usize, UnpackError> {
+    let mut count = 0;
+    // first names of files with more than one, by inode
+    let mut links: BTreeMap<u32, String> = BTreeMap::new();
+    for entry in Archive::new(archive) {
+        let entry = entry?;
+        if entry.name.is_empty() {
+            continue;
+        }
+        let path = alloc::format!("{}/{}", base.trim_end_matches('/'), entry.name);
+        make_parents(&path)?;
+        let permissions = (entry.mode & 0o7777) as u16;
+        match entry.mode & mode::TYPE_MASK {
+            mode::DIRECTORY => match vfs::mkdir(&path, permissions) {
+                Ok(()) | Err(VfsError::Exists) => {}
+                Err(err) => return Err(err.into()),
+            },
+            mode::REGULAR => match links.get(&entry.ino) {
+                Some(first) => {
+                    vfs::link(first, &path)?;
+                    if !entry.data.is_empty() {
+                        write_file(&path, entry.data)?;
+                    }
+                }
+                None => {
+                    vfs::create(&path, permissions)?;
+                    write_file(&path, entry.data)?;
+                    if entry.links > 1 {
+                        links.insert(entry.ino, path);
+                    }
+                }
+            },
+            mode::SYMLINK => {
+                let target = core::str::from_utf8(entry.data).map_err(|_| CpioError::BadName)?;
+                vfs::symlink(target, &path)?;
+            }
+            _ => {
+                log::warn!("initramfs: skipping special file {}", entry.name);
+                continue;
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Mounts a tmpfs at `/` and unpacks the ramdisk the bootloader loaded
+/// into it, if there is one.
+pub fn init(boot_info: &BootInfo) {
+    vfs::mount("/", "rootfs", TmpFs::new()).expect("cannot mount the root tmpfs");
+    let Some(addr) = boot_info.ramdisk_addr.into_option() else {
+        log::info!("initramfs: no ramdisk");
+        return;
+    };
+    let len = boot_info.ramdisk_len as usize;
+    let archive = unsafe { core::slice::from_raw_parts(addr as *const u8, len) };
+    match unpack(archive, "/") {
+        Ok(count) => log::info!(
+            "initramfs: {} files from {} KiB at {:#x}",
+            count,
+            len / 1024,
+            addr
+        ),
+        Err(err) => log::error!("initramfs: {}", err),
+    }
+}
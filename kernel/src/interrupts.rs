@@ -1,18 +1,318 @@
+use crate::apic;
 use crate::idt::*;
+use crate::mmap;
+use crate::pic::{self, IRQ_LINES, PIC_OFFSET};
+use crate::sync::IrqSpinLock;
 use conquer_once::spin::Lazy;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 pub fn init_idt() -> InterruptDescriptorTable {
     let mut idt = InterruptDescriptorTable::new();
+    idt.nonmaskable_interrupt.set_handler_fn(nmi_handler);
     idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    for (irq, stub) in IRQ_STUBS.iter().enumerate() {
+        idt[PIC_OFFSET + irq as u8].set_handler_fn(*stub);
+    }
+    for (index, stub) in VECTOR_STUBS.iter().enumerate() {
+        idt[FIRST_DYNAMIC_VECTOR + index as u8].set_handler_fn(*stub);
+    }
+    idt[apic::SPURIOUS_VECTOR].set_handler_fn(spurious_handler);
     idt
 }
 
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(init_idt);
 
+/// Loads the IDT, remaps the PICs and enables the local APIC. Maskable
+/// interrupts stay disabled until [`enable`] is called.
 pub fn init() {
     IDT.load();
+    pic::init();
+    apic::init();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+/// Interrupts taken, per vector.
+static COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+fn count_vector(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How often `vector` has fired since boot. Spurious PIC interrupts are
+/// counted on the APIC spurious vector rather than on their IRQ.
+pub fn count(vector: u8) -> u64 {
+    COUNTS[vector as usize].load(Ordering::Relaxed)
+}
+
+/// Handlers that can share one legacy IRQ line.
+const HANDLERS_PER_IRQ: usize = 4;
+
+pub type IrqHandler = fn();
+
+static IRQ_HANDLERS: IrqSpinLock<[[Option<IrqHandler>; HANDLERS_PER_IRQ]; IRQ_LINES as usize]> =
+    IrqSpinLock::new([[None; HANDLERS_PER_IRQ]; IRQ_LINES as usize]);
+
+/// Attaches `handler` to a legacy PIC line and unmasks it.
+///
+/// Lines can be shared, so every handler has to cope with being called for an
+/// interrupt its device did not raise.
+pub fn register_irq(irq: u8, handler: IrqHandler) {
+    assert!(irq < IRQ_LINES, "invalid IRQ line {irq}");
+    {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = handlers[irq as usize]
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("too many handlers on one IRQ line");
+        *slot = Some(handler);
+    }
+    pic::unmask(irq);
+}
+
+fn dispatch_irq(irq: u8) {
+    if pic::is_spurious(irq) {
+        count_vector(apic::SPURIOUS_VECTOR);
+        return;
+    }
+    count_vector(PIC_OFFSET + irq);
+    let handlers = IRQ_HANDLERS.lock()[irq as usize];
+    for handler in handlers.iter().flatten() {
+        handler();
+    }
+    pic::end_of_interrupt(irq);
+}
+
+macro_rules! irq_stubs {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+
+        const IRQ_STUBS: [HandlerFunc; IRQ_LINES as usize] = [$($name),*];
+    };
+}
+
+irq_stubs!(
+    0 => irq0_handler,
+    1 => irq1_handler,
+    2 => irq2_handler,
+    3 => irq3_handler,
+    4 => irq4_handler,
+    5 => irq5_handler,
+    6 => irq6_handler,
+    7 => irq7_handler,
+    8 => irq8_handler,
+    9 => irq9_handler,
+    10 => irq10_handler,
+    11 => irq11_handler,
+    12 => irq12_handler,
+    13 => irq13_handler,
+    14 => irq14_handler,
+    15 => irq15_handler,
+);
+
+/// First vector handed out by [`allocate_vectors`], right after the PIC range.
+pub const FIRST_DYNAMIC_VECTOR: u8 = PIC_OFFSET + IRQ_LINES;
+/// Vectors 0x30-0xEF; the top 16 are kept for the APIC itself.
+pub const DYNAMIC_VECTORS: usize = 0xF0 - FIRST_DYNAMIC_VECTOR as usize;
+
+/// Handler for a dynamically allocated vector, called with the context it
+/// was registered with.
+pub type VectorHandler = fn(usize);
+
+#[derive(Debug, Clone, Copy)]
+enum VectorSlot {
+    Free,
+    Reserved,
+    Handler(VectorHandler, usize),
+}
+
+static VECTORS: IrqSpinLock<[VectorSlot; DYNAMIC_VECTORS]> =
+    IrqSpinLock::new([VectorSlot::Free; DYNAMIC_VECTORS]);
+
+fn vector_index(vector: u8) -> usize {
+    let index = vector.wrapping_sub(FIRST_DYNAMIC_VECTOR) as usize;
+    assert!(index < DYNAMIC_VECTORS, "vector {vector:#x} is not dynamic");
+    index
+}
+
+/// Reserves `count` consecutive vectors, the first of them a multiple of
+/// `align` (a power of two), and returns the first.
+///
+/// Multi-message MSI needs such an aligned block because the device ORs the
+/// message number into the low bits of the vector.
+pub fn allocate_vectors(count: usize, align: usize) -> Option<u8> {
+    assert!(count > 0 && align.is_power_of_two());
+    let mut vectors = VECTORS.lock();
+    let mut first = (FIRST_DYNAMIC_VECTOR as usize).next_multiple_of(align);
+    while first + count <= FIRST_DYNAMIC_VECTOR as usize + DYNAMIC_VECTORS {
+        let start = first - FIRST_DYNAMIC_VECTOR as usize;
+        let block = &mut vectors[start..start + count];
+        if block.iter().all(|slot| matches!(slot, VectorSlot::Free)) {
+            block.fill(VectorSlot::Reserved);
+            return Some(first as u8);
+        }
+        first += align;
+    }
+    None
+}
+
+/// Releases vectors from [`allocate_vectors`], dropping their handlers.
+pub fn free_vectors(first: u8, count: usize) {
+    let start = vector_index(first);
+    VECTORS.lock()[start..start + count].fill(VectorSlot::Free);
+}
+
+/// Routes an allocated vector to `handler`.
+pub fn set_vector_handler(vector: u8, handler: VectorHandler, context: usize) {
+    let mut vectors = VECTORS.lock();
+    let slot = &mut vectors[vector_index(vector)];
+    assert!(
+        !matches!(slot, VectorSlot::Free),
+        "vector {vector:#x} is not allocated"
+    );
+    *slot = VectorSlot::Handler(handler, context);
+}
+
+/// Detaches the handler of an allocated vector without freeing it.
+pub fn clear_vector_handler(vector: u8) {
+    let mut vectors = VECTORS.lock();
+    let slot = &mut vectors[vector_index(vector)];
+    if let VectorSlot::Handler(..) = slot {
+        *slot = VectorSlot::Reserved;
+    }
+}
+
+fn dispatch_vector(vector: u8) {
+    count_vector(vector);
+    let slot = VECTORS.lock()[vector_index(vector)];
+    if let VectorSlot::Handler(handler, context) = slot {
+        handler(context);
+    }
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn vector_stub<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch_vector(VECTOR);
+}
+
+macro_rules! vector_stubs {
+    ($($row:literal),* $(,)?) => {
+        const VECTOR_STUBS: [HandlerFunc; DYNAMIC_VECTORS] = [$(
+            vector_stub::<{ $row }>,
+            vector_stub::<{ $row + 0x1 }>,
+            vector_stub::<{ $row + 0x2 }>,
+            vector_stub::<{ $row + 0x3 }>,
+            vector_stub::<{ $row + 0x4 }>,
+            vector_stub::<{ $row + 0x5 }>,
+            vector_stub::<{ $row + 0x6 }>,
+            vector_stub::<{ $row + 0x7 }>,
+            vector_stub::<{ $row + 0x8 }>,
+            vector_stub::<{ $row + 0x9 }>,
+            vector_stub::<{ $row + 0xA }>,
+            vector_stub::<{ $row + 0xB }>,
+            vector_stub::<{ $row + 0xC }>,
+            vector_stub::<{ $row + 0xD }>,
+            vector_stub::<{ $row + 0xE }>,
+            vector_stub::<{ $row + 0xF }>,
+        )*];
+    };
+}
+
+vector_stubs!(
+    0x30, 0x40, 0x50, 0x60, 0x70, 0x80, 0x90, 0xA0, 0xB0, 0xC0, 0xD0, 0xE0
+);
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
+    count_vector(apic::SPURIOUS_VECTOR);
+}
+
+extern "x86-interrupt" fn nmi_handler(_stack_frame: InterruptStackFrame) {
+    count_vector(2);
+    log::warn!("EXCEPTION: NON-MASKABLE INTERRUPT");
+}
+
+extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
+    count_vector(3);
     log::info!("EXCEPTION: BREAKPOINT");
 }
+
+/// Page fault error code bit set if the access was a write.
+const PAGE_FAULT_WRITE: u64 = 1 << 1;
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    count_vector(14);
+    let address: u64;
+    unsafe {
+        asm!("mov {}, cr2", out(reg) address, options(nomem, nostack, preserves_flags));
+    }
+    // faulting in a file page may wait for the disk
+    if stack_frame.cpu_flags() & RFLAGS_IF != 0 {
+        enable();
+    }
+    if !mmap::handle_fault(address, error_code & PAGE_FAULT_WRITE != 0) {
+        panic!(
+            "EXCEPTION: PAGE FAULT at {:#x}, error {:#x}, ip {:#x}",
+            address,
+            error_code,
+            stack_frame.instruction_pointer()
+        );
+    }
+}
+
+/// Halts until the next interrupt arrives.
+pub fn wait() {
+    unsafe {
+        asm!("hlt", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Atomically enables interrupts and halts, so a wakeup that fires in between
+/// cannot be missed.
+pub fn enable_and_wait() {
+    unsafe {
+        asm!("sti; hlt", options(nomem, nostack));
+    }
+}
+
+pub fn int3() {
+    unsafe {
+        asm!("int3", options(nomem, nostack));
+    }
+}
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags & RFLAGS_IF != 0
+}
+
+pub fn enable() {
+    unsafe {
+        asm!("sti", options(nomem, nostack));
+    }
+}
+
+pub fn disable() {
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+    }
+}
+
+/// Runs `f` with maskable interrupts disabled, restoring the previous state afterwards.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let were_enabled = are_enabled();
+    disable();
+    let result = f();
+    if were_enabled {
+        enable();
+    }
+    result
+}
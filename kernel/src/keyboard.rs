@@ -0,0 +1,525 @@
+use alloc::sync::Arc;
+use core::future::Future;
+use core::ops::BitOr;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use crate::devfs::{self, Device, DeviceNumber, ioctl};
+use crate::i8042::{self, I8042Error, Ps2Port, response};
+use crate::interrupts;
+use crate::ringbuf::RingBuffer;
+use crate::sync::{IrqSpinLock, WakerSlot};
+use crate::vfs::{self, FileType, VfsError};
+
+pub use crate::keymap::Keymap;
+use crate::scancode::Decoder;
+pub use crate::scancode::{KeyCode, KeyState, ScancodeSet};
+
+// PS/2 keyboard on the first i8042 port. The IRQ handler decodes scancodes,
+// tracks modifiers and lock LEDs and queues one event per key transition.
+
+const PORT: Ps2Port = Ps2Port::First;
+
+mod cmd {
+    pub const SET_LEDS: u8 = 0xED;
+    pub const SCANCODE_SET: u8 = 0xF0;
+    pub const ENABLE_SCANNING: u8 = 0xF4;
+    pub const DISABLE_SCANNING: u8 = 0xF5;
+    pub const RESET: u8 = 0xFF;
+}
+
+mod led {
+    pub const SCROLL_LOCK: u8 = 1 << 0;
+    pub const NUM_LOCK: u8 = 1 << 1;
+    pub const CAPS_LOCK: u8 = 1 << 2;
+}
+
+/// Reported instead of a scancode on key detection errors and overruns.
+const ERROR_CODES: [u8; 2] = [0x00, 0xFF];
+
+/// Self test after a reset takes up to about 750ms.
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+const REPLY_TIMEOUT: Duration = Duration::from_millis(50);
+
+const EVENT_BUFFER_SIZE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers(u16);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const LEFT_SHIFT: Modifiers = Modifiers(1 << 0);
+    pub const RIGHT_SHIFT: Modifiers = Modifiers(1 << 1);
+    pub const LEFT_CTRL: Modifiers = Modifiers(1 << 2);
+    pub const RIGHT_CTRL: Modifiers = Modifiers(1 << 3);
+    pub const LEFT_ALT: Modifiers = Modifiers(1 << 4);
+    /// AltGr on most non-US layouts.
+    pub const RIGHT_ALT: Modifiers = Modifiers(1 << 5);
+    pub const LEFT_GUI: Modifiers = Modifiers(1 << 6);
+    pub const RIGHT_GUI: Modifiers = Modifiers(1 << 7);
+    pub const CAPS_LOCK: Modifiers = Modifiers(1 << 8);
+    pub const NUM_LOCK: Modifiers = Modifiers(1 << 9);
+    pub const SCROLL_LOCK: Modifiers = Modifiers(1 << 10);
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersects(self, other: Modifiers) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub fn set(&mut self, other: Modifiers, on: bool) {
+        if on {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+
+    pub fn toggle(&mut self, other: Modifiers) {
+        self.0 ^= other.0;
+    }
+
+    pub fn shift(self) -> bool {
+        self.intersects(Modifiers::LEFT_SHIFT | Modifiers::RIGHT_SHIFT)
+    }
+
+    pub fn ctrl(self) -> bool {
+        self.intersects(Modifiers::LEFT_CTRL | Modifiers::RIGHT_CTRL)
+    }
+
+    pub fn alt(self) -> bool {
+        self.contains(Modifiers::LEFT_ALT)
+    }
+
+    pub fn altgr(self) -> bool {
+        self.contains(Modifiers::RIGHT_ALT)
+    }
+
+    pub fn gui(self) -> bool {
+        self.intersects(Modifiers::LEFT_GUI | Modifiers::RIGHT_GUI)
+    }
+
+    pub fn caps_lock(self) -> bool {
+        self.contains(Modifiers::CAPS_LOCK)
+    }
+
+    pub fn num_lock(self) -> bool {
+        self.contains(Modifiers::NUM_LOCK)
+    }
+
+    pub fn scroll_lock(self) -> bool {
+        self.contains(Modifiers::SCROLL_LOCK)
+    }
+
+    fn leds(self) -> u8 {
+        let mut leds = 0;
+        if self.scroll_lock() {
+            leds |= led::SCROLL_LOCK;
+        }
+        if self.num_lock() {
+            leds |= led::NUM_LOCK;
+        }
+        if self.caps_lock() {
+            leds |= led::CAPS_LOCK;
+        }
+        leds
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, other: Modifiers) -> Modifiers {
+        Modifiers(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub state: KeyState,
+    /// Modifiers in effect after this event.
+    pub modifiers: Modifiers,
+    /// What the key types in the current keymap; only set on presses.
+    pub char: Option<char>,
+}
+
+/// Length of a [`KeyEvent`] read from `/dev/keyboard`.
+pub const EVENT_SIZE: usize = 8;
+
+impl KeyEvent {
+    pub fn is_pressed(&self) -> bool {
+        self.state == KeyState::Pressed
+    }
+
+    /// The event as `/dev/keyboard` hands it out: the key code, 1 for a
+    /// press or 0 for a release, the modifier bits and the typed character
+    /// or 0, little endian.
+    pub fn to_bytes(&self) -> [u8; EVENT_SIZE] {
+        let mut bytes = [0; EVENT_SIZE];
+        bytes[0] = self.key as u8;
+        bytes[1] = self.is_pressed() as u8;
+        bytes[2..4].copy_from_slice(&self.modifiers.bits().to_le_bytes());
+        bytes[4..].copy_from_slice(&(self.char.map_or(0, u32::from)).to_le_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedUpdate {
+    Idle,
+    AwaitingCommandAck,
+    AwaitingValueAck,
+}
+
+struct State {
+    decoder: Decoder,
+    modifiers: Modifiers,
+    /// One bit per [`KeyCode`], to tell presses from typematic repeats.
+    held: [u64; KeyCode::COUNT.div_ceil(64)],
+    leds: LedUpdate,
+    /// The lock state changed while an LED update was in flight.
+    leds_dirty: bool,
+}
+
+impl State {
+    fn is_held(&self, key: KeyCode) -> bool {
+        self.held[key as usize / 64] & (1 << (key as usize % 64)) != 0
+    }
+
+    fn set_held(&mut self, key: KeyCode, held: bool) {
+        let bit = 1 << (key as usize % 64);
+        if held {
+            self.held[key as usize / 64] |= bit;
+        } else {
+            self.held[key as usize / 64] &= !bit;
+        }
+    }
+
+    /// Applies one key transition and returns the event to report.
+    fn key_event(&mut self, key: KeyCode, key_state: KeyState) -> KeyEvent {
+        let pressed = key_state == KeyState::Pressed;
+        let repeat = pressed && self.is_held(key);
+        self.set_held(key, pressed);
+
+        let modifier = match key {
+            KeyCode::LeftShift => Some(Modifiers::LEFT_SHIFT),
+            KeyCode::RightShift => Some(Modifiers::RIGHT_SHIFT),
+            KeyCode::LeftCtrl => Some(Modifiers::LEFT_CTRL),
+            KeyCode::RightCtrl => Some(Modifiers::RIGHT_CTRL),
+            KeyCode::LeftAlt => Some(Modifiers::LEFT_ALT),
+            KeyCode::RightAlt => Some(Modifiers::RIGHT_ALT),
+            KeyCode::LeftGui => Some(Modifiers::LEFT_GUI),
+            KeyCode::RightGui => Some(Modifiers::RIGHT_GUI),
+            _ => None,
+        };
+        let lock = match key {
+            KeyCode::CapsLock => Some(Modifiers::CAPS_LOCK),
+            KeyCode::NumLock => Some(Modifiers::NUM_LOCK),
+            KeyCode::ScrollLock => Some(Modifiers::SCROLL_LOCK),
+            _ => None,
+        };
+        if let Some(modifier) = modifier {
+            self.modifiers.set(modifier, pressed);
+        }
+        if let Some(lock) = lock
+            && pressed
+            && !repeat
+        {
+            self.modifiers.toggle(lock);
+            self.update_leds();
+        }
+
+        let char = match pressed {
+            true => keymap().translate(key, self.modifiers),
+            false => None,
+        };
+        KeyEvent {
+            key,
+            state: key_state,
+            modifiers: self.modifiers,
+            char,
+        }
+    }
+
+    /// Starts sending the lock state to the keyboard. The rest of the
+    /// exchange is driven by ACKs arriving in the IRQ handler.
+    fn update_leds(&mut self) {
+        if self.leds != LedUpdate::Idle {
+            self.leds_dirty = true;
+            return;
+        }
+        if i8042::send(PORT, cmd::SET_LEDS).is_ok() {
+            self.leds = LedUpdate::AwaitingCommandAck;
+        }
+    }
+
+    fn acknowledged(&mut self) {
+        match self.leds {
+            LedUpdate::Idle => {}
+            LedUpdate::AwaitingCommandAck => {
+                self.leds = match i8042::send(PORT, self.modifiers.leds()) {
+                    Ok(()) => LedUpdate::AwaitingValueAck,
+                    Err(_) => LedUpdate::Idle,
+                };
+            }
+            LedUpdate::AwaitingValueAck => {
+                self.leds = LedUpdate::Idle;
+                if self.leds_dirty {
+                    self.leds_dirty = false;
+                    self.update_leds();
+                }
+            }
+        }
+    }
+}
+
+static STATE: IrqSpinLock<State> = IrqSpinLock::new(State {
+    decoder: Decoder::new(ScancodeSet::Set2),
+    modifiers: Modifiers::NONE,
+    held: [0; KeyCode::COUNT.div_ceil(64)],
+    leds: LedUpdate::Idle,
+    leds_dirty: false,
+});
+
+static KEYMAP: AtomicU8 = AtomicU8::new(Keymap::Us as u8);
+static EVENTS: RingBuffer<KeyEvent, EVENT_BUFFER_SIZE> = RingBuffer::new();
+static WAKER: WakerSlot = WakerSlot::new();
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static IRQ_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Resets the keyboard, selects scancode set 2 and starts interrupt-driven
+/// input. Needs [`i8042::init`] to have succeeded.
+///
+/// Keyboards that refuse to switch sets are read through the controller's
+/// set 1 translation instead.
+pub fn init() -> Result<(), I8042Error> {
+    i8042::command(PORT, cmd::RESET)?;
+    match i8042::read(PORT, RESET_TIMEOUT)? {
+        response::SELF_TEST_PASSED => {}
+        code => return Err(I8042Error::DeviceSelfTestFailed(PORT, code)),
+    }
+    i8042::command(PORT, cmd::DISABLE_SCANNING)?;
+
+    let set = match select_set2() {
+        Ok(true) => ScancodeSet::Set2,
+        _ => {
+            i8042::enable_translation()?;
+            ScancodeSet::Set1
+        }
+    };
+    {
+        let mut state = STATE.lock();
+        state.decoder = Decoder::new(set);
+        state.modifiers = Modifiers::NONE;
+        state.held = [0; KeyCode::COUNT.div_ceil(64)];
+        state.leds = LedUpdate::Idle;
+        state.leds_dirty = false;
+    }
+    EVENTS.clear();
+
+    i8042::command(PORT, cmd::SET_LEDS)?;
+    i8042::command(PORT, 0)?;
+    i8042::command(PORT, cmd::ENABLE_SCANNING)?;
+
+    if !IRQ_REGISTERED.swap(true, Ordering::AcqRel) {
+        interrupts::register_irq(PORT.irq(), irq_handler);
+    }
+    i8042::enable_irq(PORT)?;
+    log::info!("PS/2 keyboard: scancode {:?}, keymap {}", set, keymap());
+    let number = DeviceNumber::new(13, 64);
+    devfs::register(
+        "keyboard",
+        FileType::CharDevice,
+        number,
+        0o640,
+        Arc::new(EventQueue),
+    );
+    Ok(())
+}
+
+/// Asks for scancode set 2 and reads back the active set.
+fn select_set2() -> Result<bool, I8042Error> {
+    i8042::command(PORT, cmd::SCANCODE_SET)?;
+    i8042::command(PORT, 2)?;
+    i8042::command(PORT, cmd::SCANCODE_SET)?;
+    i8042::command(PORT, 0)?;
+    Ok(i8042::read(PORT, REPLY_TIMEOUT)? == 2)
+}
+
+fn irq_handler() {
+    let mut queued = false;
+    while let Some(byte) = i8042::receive(PORT) {
+        queued |= handle_byte(byte);
+    }
+    if queued {
+        WAKER.wake();
+    }
+}
+
+/// Returns whether an event was queued.
+fn handle_byte(byte: u8) -> bool {
+    let mut state = STATE.lock();
+    match byte {
+        response::ACK => {
+            state.acknowledged();
+            return false;
+        }
+        response::RESEND => {
+            state.leds = LedUpdate::Idle;
+            return false;
+        }
+        byte if ERROR_CODES.contains(&byte) => {
+            state.decoder.reset();
+            return false;
+        }
+        _ => {}
+    }
+
+    let mut decoded = [None; 2];
+    let mut count = 0;
+    state.decoder.feed(byte, |key, key_state| {
+        decoded[count] = Some((key, key_state));
+        count += 1;
+    });
+
+    let mut queued = false;
+    for (key, key_state) in decoded.into_iter().flatten() {
+        let event = state.key_event(key, key_state);
+        if EVENTS.push(event).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        queued = true;
+    }
+    queued
+}
+
+pub fn keymap() -> Keymap {
+    Keymap::from_u8(KEYMAP.load(Ordering::Relaxed))
+}
+
+/// Switches the layout used to turn keys into characters.
+pub fn set_keymap(keymap: Keymap) {
+    KEYMAP.store(keymap as u8, Ordering::Relaxed);
+}
+
+pub fn modifiers() -> Modifiers {
+    STATE.lock().modifiers
+}
+
+/// Events thrown away because the queue was full.
+pub fn dropped_events() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+// There must only be one consumer of the event queue at a time.
+
+/// Returns the next key event without blocking.
+pub fn read_event() -> Option<KeyEvent> {
+    EVENTS.pop()
+}
+
+/// Waits for the next key event, halting between interrupts.
+pub fn read_event_blocking() -> KeyEvent {
+    loop {
+        if let Some(event) = read_event() {
+            return event;
+        }
+        interrupts::enable_and_wait();
+    }
+}
+
+/// Returns the next typed character, skipping events that do not produce one.
+pub fn read_char() -> Option<char> {
+    while let Some(event) = read_event() {
+        if let Some(c) = event.char {
+            return Some(c);
+        }
+    }
+    None
+}
+
+/// Fills records from the queue until either runs out, returning how many.
+fn read_events<'a>(records: impl Iterator<Item = &'a mut [u8; EVENT_SIZE]>) -> usize {
+    let mut count = 0;
+    for record in records {
+        let Some(event) = read_event() else {
+            break;
+        };
+        *record = event.to_bytes();
+        count += 1;
+    }
+    count
+}
+
+/// `/dev/keyboard`, the event queue as a stream of [`EVENT_SIZE`] records.
+/// A read waits for the first event, unless non-blocking, and returns whole
+/// events only.
+struct EventQueue;
+
+impl Device for EventQueue {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let mut records = buf.as_chunks_mut::<EVENT_SIZE>().0.iter_mut();
+        let Some(first) = records.next() else {
+            return Err(VfsError::InvalidArgument);
+        };
+        *first = read_event_blocking().to_bytes();
+        Ok((1 + read_events(records)) * EVENT_SIZE)
+    }
+
+    fn try_read(&self, _offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let records = buf.as_chunks_mut::<EVENT_SIZE>().0;
+        if records.is_empty() {
+            return Err(VfsError::InvalidArgument);
+        }
+        match read_events(records.iter_mut()) {
+            0 => Err(VfsError::WouldBlock),
+            count => Ok(count * EVENT_SIZE),
+        }
+    }
+
+    fn write(&self, _offset: u64, _buf: &[u8]) -> vfs::Result<usize> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> vfs::Result<usize> {
+        match request {
+            ioctl::FIONREAD => {
+                devfs::put_arg(arg, &((EVENTS.len() * EVENT_SIZE) as i32).to_le_bytes())
+            }
+            _ => Err(VfsError::NotTty),
+        }
+    }
+}
+
+/// Resolves to the next key event.
+pub fn next_event() -> NextEvent {
+    NextEvent { _private: () }
+}
+
+pub struct NextEvent {
+    _private: (),
+}
+
+impl Future for NextEvent {
+    type Output = KeyEvent;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<KeyEvent> {
+        if let Some(event) = read_event() {
+            return Poll::Ready(event);
+        }
+        WAKER.register(cx.waker());
+        match read_event() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
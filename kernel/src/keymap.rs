@@ -0,0 +1,217 @@
+use core::fmt;
+
+use crate::keyboard::Modifiers;
+use crate::scancode::KeyCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Keymap {
+    Us,
+    Uk,
+    De,
+}
+
+/// What a printable key produces: unshifted, shifted and with AltGr.
+type Layer = (char, char, Option<char>);
+
+impl Keymap {
+    pub const ALL: [Keymap; 3] = [Keymap::Us, Keymap::Uk, Keymap::De];
+
+    pub(crate) fn from_u8(value: u8) -> Keymap {
+        Keymap::ALL
+            .into_iter()
+            .find(|keymap| *keymap as u8 == value)
+            .unwrap_or(Keymap::Us)
+    }
+
+    /// Character produced by pressing `key` with `modifiers` held, if any.
+    ///
+    /// Caps lock only affects keys with an upper-case variant, and ctrl
+    /// turns letters and `@[\]^_` into ASCII control characters. Alt is
+    /// ignored, except that right alt is AltGr on the UK and DE layouts.
+    pub fn translate(self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        if let Some(c) = self.fixed(key, modifiers) {
+            return Some(c);
+        }
+
+        let (plain, shifted, altgr) = self.layer(key)?;
+        if modifiers.altgr() && self != Keymap::Us {
+            return altgr;
+        }
+        let has_case = plain.is_lowercase() && shifted.is_uppercase();
+        let shift = modifiers.shift() ^ (has_case && modifiers.caps_lock());
+        let c = if shift { shifted } else { plain };
+        if modifiers.ctrl() {
+            return control_char(c);
+        }
+        Some(c)
+    }
+
+    /// Keys that do not depend much on the layout.
+    fn fixed(self, key: KeyCode, modifiers: Modifiers) -> Option<char> {
+        use KeyCode::*;
+
+        let numbers = modifiers.num_lock() && !modifiers.shift();
+        let c = match key {
+            Escape => '\x1b',
+            Backspace => '\x08',
+            Tab => '\t',
+            Enter | KeypadEnter => '\n',
+            Space => ' ',
+            Delete => '\x7f',
+            KeypadDivide => '/',
+            KeypadMultiply => '*',
+            KeypadMinus => '-',
+            KeypadPlus => '+',
+            KeypadPeriod if numbers => match self {
+                Keymap::De => ',',
+                _ => '.',
+            },
+            Keypad0 if numbers => '0',
+            Keypad1 if numbers => '1',
+            Keypad2 if numbers => '2',
+            Keypad3 if numbers => '3',
+            Keypad4 if numbers => '4',
+            Keypad5 if numbers => '5',
+            Keypad6 if numbers => '6',
+            Keypad7 if numbers => '7',
+            Keypad8 if numbers => '8',
+            Keypad9 if numbers => '9',
+            _ => return None,
+        };
+        Some(c)
+    }
+
+    fn layer(self, key: KeyCode) -> Option<Layer> {
+        let layer = match self {
+            Keymap::Us => None,
+            Keymap::Uk => uk_layer(key),
+            Keymap::De => de_layer(key),
+        };
+        layer.or_else(|| us_layer(key))
+    }
+}
+
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Keymap::Us => "US",
+            Keymap::Uk => "UK",
+            Keymap::De => "DE",
+        };
+        f.write_str(name)
+    }
+}
+
+fn control_char(c: char) -> Option<char> {
+    match c {
+        'a'..='z' | 'A'..='Z' | '@' | '[' | '\\' | ']' | '^' | '_' => {
+            Some((c as u8 & 0x1F) as char)
+        }
+        _ => None,
+    }
+}
+
+fn us_layer(key: KeyCode) -> Option<Layer> {
+    use KeyCode::*;
+
+    let (plain, shifted) = match key {
+        Grave => ('`', '~'),
+        Digit1 => ('1', '!'),
+        Digit2 => ('2', '@'),
+        Digit3 => ('3', '#'),
+        Digit4 => ('4', '$'),
+        Digit5 => ('5', '%'),
+        Digit6 => ('6', '^'),
+        Digit7 => ('7', '&'),
+        Digit8 => ('8', '*'),
+        Digit9 => ('9', '('),
+        Digit0 => ('0', ')'),
+        Minus => ('-', '_'),
+        Equals => ('=', '+'),
+        Q => ('q', 'Q'),
+        W => ('w', 'W'),
+        E => ('e', 'E'),
+        R => ('r', 'R'),
+        T => ('t', 'T'),
+        Y => ('y', 'Y'),
+        U => ('u', 'U'),
+        I => ('i', 'I'),
+        O => ('o', 'O'),
+        P => ('p', 'P'),
+        LeftBracket => ('[', '{'),
+        RightBracket => (']', '}'),
+        Backslash => ('\\', '|'),
+        A => ('a', 'A'),
+        S => ('s', 'S'),
+        D => ('d', 'D'),
+        F => ('f', 'F'),
+        G => ('g', 'G'),
+        H => ('h', 'H'),
+        J => ('j', 'J'),
+        K => ('k', 'K'),
+        L => ('l', 'L'),
+        Semicolon => (';', ':'),
+        Apostrophe => ('\'', '"'),
+        NonUsBackslash => ('\\', '|'),
+        Z => ('z', 'Z'),
+        X => ('x', 'X'),
+        C => ('c', 'C'),
+        V => ('v', 'V'),
+        B => ('b', 'B'),
+        N => ('n', 'N'),
+        M => ('m', 'M'),
+        Comma => (',', '<'),
+        Period => ('.', '>'),
+        Slash => ('/', '?'),
+        _ => return None,
+    };
+    Some((plain, shifted, None))
+}
+
+fn uk_layer(key: KeyCode) -> Option<Layer> {
+    use KeyCode::*;
+
+    Some(match key {
+        Grave => ('`', '¬', Some('¦')),
+        Digit2 => ('2', '"', None),
+        Digit3 => ('3', '£', None),
+        Digit4 => ('4', '$', Some('€')),
+        Apostrophe => ('\'', '@', None),
+        Backslash => ('#', '~', None),
+        NonUsBackslash => ('\\', '|', None),
+        _ => return None,
+    })
+}
+
+fn de_layer(key: KeyCode) -> Option<Layer> {
+    use KeyCode::*;
+
+    Some(match key {
+        Grave => ('^', '°', None),
+        Digit2 => ('2', '"', Some('²')),
+        Digit3 => ('3', '§', Some('³')),
+        Digit6 => ('6', '&', None),
+        Digit7 => ('7', '/', Some('{')),
+        Digit8 => ('8', '(', Some('[')),
+        Digit9 => ('9', ')', Some(']')),
+        Digit0 => ('0', '=', Some('}')),
+        Minus => ('ß', '?', Some('\\')),
+        Equals => ('´', '`', None),
+        Q => ('q', 'Q', Some('@')),
+        E => ('e', 'E', Some('€')),
+        Y => ('z', 'Z', None),
+        LeftBracket => ('ü', 'Ü', None),
+        RightBracket => ('+', '*', Some('~')),
+        Backslash => ('#', '\'', None),
+        Semicolon => ('ö', 'Ö', None),
+        Apostrophe => ('ä', 'Ä', None),
+        NonUsBackslash => ('<', '>', Some('|')),
+        Z => ('y', 'Y', None),
+        M => ('m', 'M', Some('µ')),
+        Comma => (',', ';', None),
+        Period => ('.', ':', None),
+        Slash => ('-', '_', None),
+        _ => return None,
+    })
+}
@@ -0,0 +1,1620 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::block::{self, BlockDevice, DeviceId};
+use crate::buffer_cache;
+use crate::kfs_layout::{
+    self as layout, BITS_PER_BLOCK, BLOCK_SIZE, EXTENT_SIZE, EXTENTS_PER_BLOCK, Extent,
+    INLINE_EXTENTS, INLINE_SYMLINK, INODE_SIZE, JournalHeader, MAX_EXTENTS, MAX_FILE_BLOCKS,
+    MAX_NAME, PAYLOAD, ROOT_INO, RawInode, Superblock, entry_kind, kind, mode,
+};
+use crate::page_cache::{Backing, PAGE_SIZE, Page, PageCache};
+use crate::sync::SpinLock;
+use crate::time;
+use crate::vfs::{self, Attributes, DirEntry, FileSystem, FileType, Inode, Metadata, VfsError};
+
+// kfs, the kernel's native filesystem; kfs_layout has the disk format.
+//
+// Metadata is never changed in place directly. An operation gathers the
+// blocks it changes in a transaction of its own and, once it has succeeded,
+// folds them into the running transaction, which goes to the journal and is
+// flushed before any of its blocks are written where they belong. A crash
+// at any point leaves either the old metadata or a complete transaction to
+// bring it up to date at the next mount, so the tree never tears. The
+// running transaction commits when the volume or a file is synced, when it
+// would outgrow the journal, and at unmount.
+//
+// File data goes around the journal, ordered instead: a block is allocated
+// when its page is first written back, the data is written to it, and only
+// then is the extent that makes it part of the file committed. Blocks freed
+// by the running transaction are not handed out again before it commits,
+// so new data never lands on blocks the committed metadata still gives to
+// another file. A crash loses what was written since the last sync; where
+// a file grew, the blocks that didn't make it read as zeroes.
+//
+// One lock serialises all operations on a volume. An inode whose last link
+// goes while it is open stays allocated, with no links, until its node is
+// dropped; a mount frees any that a crash left behind.
+
+/// The most names an inode can have, as on Linux.
+const MAX_LINKS: u16 = 65000;
+
+const _: () = assert!(PAGE_SIZE == BLOCK_SIZE);
+
+/// Points a commit can be stopped at, as if the power went there, for
+/// testing recovery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashPoint {
+    /// The transaction is in the journal, its commit block is not.
+    BeforeCommitBlock,
+    /// The commit block is written, nothing is in place yet.
+    AfterCommitBlock,
+    /// Half the blocks are in place.
+    MidCheckpoint,
+}
+
+fn file_type(mode: u16) -> FileType {
+    match mode & mode::TYPE_MASK {
+        mode::DIRECTORY => FileType::Directory,
+        mode::SYMLINK => FileType::Symlink,
+        mode::CHAR_DEVICE => FileType::CharDevice,
+        mode::BLOCK_DEVICE => FileType::BlockDevice,
+        mode::FIFO => FileType::Fifo,
+        _ => FileType::Regular,
+    }
+}
+
+fn entry_type(kind: u8) -> FileType {
+    match kind {
+        entry_kind::DIRECTORY => FileType::Directory,
+        entry_kind::CHAR_DEVICE => FileType::CharDevice,
+        entry_kind::BLOCK_DEVICE => FileType::BlockDevice,
+        entry_kind::FIFO => FileType::Fifo,
+        entry_kind::SYMLINK => FileType::Symlink,
+        _ => FileType::Regular,
+    }
+}
+
+/// The block holding block `index` of a file mapped by `extents`, None
+/// for a hole.
+fn find_block(extents: &[Extent], index: u64) -> Option<u64> {
+    let next = extents.partition_point(|extent| extent.logical <= index);
+    let extent = extents.get(next.checked_sub(1)?)?;
+    (index < extent.end()).then(|| extent.physical + (index - extent.logical))
+}
+
+/// Extent blocks a file of `extents` extents keeps them in.
+fn leaf_blocks(extents: usize) -> usize {
+    match extents {
+        0..=INLINE_EXTENTS => 0,
+        count => count.div_ceil(EXTENTS_PER_BLOCK),
+    }
+}
+
+/// Maps block `index` to `physical` in `extents`, growing a neighbouring
+/// extent where the two line up.
+fn add_block(extents: &mut Vec<Extent>, index: u64, physical: u64) {
+    let next = extents.partition_point(|extent| extent.logical <= index);
+    let joins = |before: &Extent, logical: u64, physical: u64| {
+        before.end() == logical
+            && before.physical + before.length as u64 == physical
+            && before.length < u32::MAX
+    };
+    if next > 0 && joins(&extents[next - 1], index, physical) {
+        extents[next - 1].length += 1;
+        if next < extents.len() {
+            let (before, after) = (extents[next - 1], extents[next]);
+            if joins(&before, after.logical, after.physical)
+                && (before.length as u64 + after.length as u64) <= u32::MAX as u64
+            {
+                extents[next - 1].length += after.length;
+                extents.remove(next);
+            }
+        }
+        return;
+    }
+    if let Some(after) = extents.get_mut(next)
+        && after.logical == index + 1
+        && after.physical == physical + 1
+        && after.length < u32::MAX
+    {
+        after.logical = index;
+        after.physical = physical;
+        after.length += 1;
+        return;
+    }
+    extents.insert(
+        next,
+        Extent {
+            logical: index,
+            length: 1,
+            physical,
+        },
+    );
+}
+
+struct State {
+    /// Blocks changed since the last commit, sealed, by block number.
+    running: BTreeMap<u64, Vec<u8>>,
+    /// Blocks freed since the last commit, not to be reused before it.
+    pending_free: BTreeSet<u64>,
+    /// Sequence number of the next transaction.
+    sequence: u64,
+    free_blocks: u64,
+    free_inodes: u64,
+    /// Blocks promised to pages not written back yet, by inode and page,
+    /// so that a full disk fails the write rather than the writeback.
+    reserved: BTreeSet<(u64, u64)>,
+    /// Extent blocks promised to the writeback of those pages, by inode.
+    reserved_leaves: BTreeMap<u64, u64>,
+    /// Where the next block search starts.
+    block_goal: u64,
+    /// Set when a commit failed partway; the volume takes no more changes.
+    failed: bool,
+    crash_at: Option<CrashPoint>,
+}
+
+impl State {
+    /// Free blocks not promised to pages yet.
+    fn available(&self) -> u64 {
+        let leaves: u64 = self.reserved_leaves.values().sum();
+        self.free_blocks
+            .saturating_sub(self.reserved.len() as u64 + leaves)
+    }
+
+    /// Pages of inode `ino` with blocks set aside.
+    fn pending(&self, ino: u64) -> usize {
+        self.reserved.range((ino, 0)..=(ino, u64::MAX)).count()
+    }
+
+    /// Sets aside the extent blocks that writing back the pending pages of
+    /// inode `ino`, now of `extents` extents, could take, each page adding
+    /// an extent at worst.
+    fn reserve_leaves(&mut self, ino: u64, extents: usize) {
+        match leaf_blocks(extents + self.pending(ino)) - leaf_blocks(extents) {
+            0 => self.reserved_leaves.remove(&ino),
+            wanted => self.reserved_leaves.insert(ino, wanted as u64),
+        };
+    }
+
+    /// Gives back the blocks set aside for the pages of inode `ino` that
+    /// `keep` turns down, and its extent blocks with the last of them.
+    fn unreserve(&mut self, ino: u64, keep: impl Fn(u64) -> bool) {
+        self.reserved
+            .retain(|(owner, index)| *owner != ino || keep(*index));
+        if self.pending(ino) == 0 {
+            self.reserved_leaves.remove(&ino);
+        }
+    }
+}
+
+/// A mounted kfs volume.
+pub struct KfsFs {
+    device: DeviceId,
+    superblock: Superblock,
+    read_only: bool,
+    state: SpinLock<State>,
+    /// Nodes handed out, by inode number, so that an inode has only one.
+    nodes: SpinLock<BTreeMap<u64, Weak<KfsNode>>>,
+    this: Weak<KfsFs>,
+}
+
+impl KfsFs {
+    /// Opens the kfs volume on `device`, replaying its journal.
+    pub fn new(device: DeviceId) -> vfs::Result<Arc<KfsFs>> {
+        if !BLOCK_SIZE.is_multiple_of(device.block_size()) {
+            return Err(VfsError::Unsupported);
+        }
+        let mut block = vec![0u8; BLOCK_SIZE];
+        read_block(device, 0, &mut block)?;
+        let superblock = Superblock::decode(&block).ok_or(VfsError::InvalidArgument)?;
+        let size = device.block_count() * device.block_size() as u64;
+        if superblock.block_count * BLOCK_SIZE as u64 > size {
+            return Err(VfsError::InvalidArgument);
+        }
+        let read_only = device.read_only();
+        let sequence = recover(device, &superblock, read_only)?;
+
+        let count_free = |start: u64, count: u64, block: &mut [u8]| -> vfs::Result<u64> {
+            let mut free = 0;
+            for n in 0..count.div_ceil(BITS_PER_BLOCK) {
+                read_verified(device, start + n, kind::BITMAP, block)?;
+                let bits = (count - n * BITS_PER_BLOCK).min(BITS_PER_BLOCK) as usize;
+                free += (0..bits)
+                    .filter(|bit| !layout::test_bit(block, *bit))
+                    .count() as u64;
+            }
+            Ok(free)
+        };
+        let free_blocks = count_free(superblock.block_bitmap, superblock.block_count, &mut block)?;
+        let free_inodes = count_free(superblock.inode_bitmap, superblock.inode_count, &mut block)?;
+        log::info!(
+            "{}: kfs volume \"{}\", {} of {} blocks free",
+            device,
+            superblock.label(),
+            free_blocks,
+            superblock.block_count
+        );
+        let fs = Arc::new_cyclic(|this| KfsFs {
+            device,
+            superblock,
+            read_only,
+            state: SpinLock::new(State {
+                running: BTreeMap::new(),
+                pending_free: BTreeSet::new(),
+                sequence,
+                free_blocks,
+                free_inodes,
+                reserved: BTreeSet::new(),
+                reserved_leaves: BTreeMap::new(),
+                block_goal: superblock.data_start,
+                failed: false,
+                crash_at: None,
+            }),
+            nodes: SpinLock::new(BTreeMap::new()),
+            this: this.clone(),
+        });
+        if !read_only {
+            fs.release_orphans()?;
+        }
+        Ok(fs)
+    }
+
+    /// Makes an empty kfs volume labelled `label` on all of `device`.
+    pub fn format(device: DeviceId, label: &str) -> vfs::Result<()> {
+        if !BLOCK_SIZE.is_multiple_of(device.block_size()) {
+            return Err(VfsError::Unsupported);
+        }
+        let blocks = device.block_count() * device.block_size() as u64 / BLOCK_SIZE as u64;
+        let superblock = Superblock::new(
+            blocks,
+            Superblock::default_inode_count(blocks),
+            Superblock::default_journal_blocks(blocks),
+            label,
+            time::now(),
+        )
+        .ok_or(VfsError::InvalidArgument)?;
+        let mut block = vec![0u8; BLOCK_SIZE];
+        layout::format(&superblock, &mut block, |number, data| {
+            write_direct(device, number, data)
+        })?;
+        Ok(buffer_cache::sync(device)?)
+    }
+
+    pub fn label(&self) -> &str {
+        self.superblock.label()
+    }
+
+    /// Free blocks and inodes.
+    pub fn free_counts(&self) -> (u64, u64) {
+        let state = self.state.lock();
+        (state.free_blocks, state.free_inodes)
+    }
+
+    /// Makes the next commit stop at `point` and fail, leaving the disk as
+    /// a crash there would. The volume takes no more changes after that;
+    /// mounting it again recovers it.
+    pub fn crash_at(&self, point: Option<CrashPoint>) {
+        self.state.lock().crash_at = point;
+    }
+
+    /// Frees the inodes that were open when their last link went and
+    /// weren't closed before the volume stopped.
+    fn release_orphans(&self) -> vfs::Result<()> {
+        let superblock = self.superblock;
+        let mut state = self.state.lock();
+        let mut orphans = Vec::new();
+        let tx = self.transaction(&mut state);
+        for n in 0..superblock.inode_bitmap_blocks() {
+            let bitmap = tx.read(superblock.inode_bitmap + n, kind::BITMAP)?;
+            let first = n * BITS_PER_BLOCK;
+            let bits = (superblock.inode_count - first).min(BITS_PER_BLOCK) as usize;
+            let mut table: Option<(u64, Option<Vec<u8>>)> = None;
+            for bit in (0..bits).filter(|bit| layout::test_bit(&bitmap, *bit)) {
+                let ino = first + bit as u64 + 1;
+                let (number, offset) = superblock.inode_location(ino).unwrap();
+                if table.as_ref().is_none_or(|(held, _)| *held != number) {
+                    // a damaged block fails its inodes when they are used,
+                    // not the mount
+                    let data = match tx.read(number, kind::INODES) {
+                        Err(VfsError::Corrupted) => None,
+                        result => Some(result?),
+                    };
+                    table = Some((number, data));
+                }
+                if let Some((_, Some(data))) = &table
+                    && RawInode::decode(&data[offset..offset + INODE_SIZE]).links == 0
+                {
+                    orphans.push(ino);
+                }
+            }
+        }
+        drop(tx);
+        // one at a time, as a big file alone may fill the journal
+        for ino in &orphans {
+            let mut tx = self.transaction(&mut state);
+            let mut inode = tx.read_inode(*ino)?;
+            tx.release(*ino, &mut inode)?;
+            tx.finish()?;
+        }
+        if !orphans.is_empty() {
+            log::info!("{}: freed {} orphaned inode(s)", self.device, orphans.len());
+        }
+        Ok(())
+    }
+
+    fn transaction<'a>(&'a self, state: &'a mut State) -> Transaction<'a> {
+        Transaction {
+            fs: self,
+            state,
+            blocks: BTreeMap::new(),
+            freed: BTreeSet::new(),
+            free_blocks: 0,
+            free_inodes: 0,
+        }
+    }
+
+    /// Commits the running transaction, and makes the file data written so
+    /// far durable either way.
+    fn commit(&self, state: &mut State) -> vfs::Result<()> {
+        if state.failed {
+            return Err(VfsError::Io);
+        }
+        if state.running.is_empty() {
+            return Ok(buffer_cache::sync(self.device)?);
+        }
+        let result = self.write_transaction(state);
+        if let Err(err) = result {
+            state.failed = true;
+            log::error!(
+                "{}: kfs commit failed ({}), no more changes will be made",
+                self.device,
+                err
+            );
+        }
+        result
+    }
+
+    fn write_transaction(&self, state: &mut State) -> vfs::Result<()> {
+        let start = self.superblock.journal_start;
+        let sequence = state.sequence;
+        let targets: Vec<u64> = state.running.keys().copied().collect();
+        let mut log = vec![0u8; (targets.len() + 1) * BLOCK_SIZE];
+        let (descriptor, logged) = log.split_at_mut(BLOCK_SIZE);
+        layout::encode_descriptor(descriptor, sequence, &targets);
+        layout::seal(descriptor, start + 1, kind::DESCRIPTOR);
+        let mut checksum = layout::crc32c(0, descriptor);
+        for (to, data) in logged.chunks_mut(BLOCK_SIZE).zip(state.running.values()) {
+            to.copy_from_slice(data);
+            checksum = layout::crc32c(checksum, data);
+        }
+        write_direct(self.device, start + 1, &log)?;
+
+        let commit_at = start + 2 + targets.len() as u64;
+        let mut commit = vec![0u8; BLOCK_SIZE];
+        layout::encode_commit(&mut commit, sequence, checksum);
+        layout::seal(&mut commit, commit_at, kind::COMMIT);
+        let crash = |point| {
+            log::warn!("{}: kfs stopping at {:?} as asked", self.device, point);
+            VfsError::Io
+        };
+        if state.crash_at == Some(CrashPoint::BeforeCommitBlock) {
+            return Err(crash(CrashPoint::BeforeCommitBlock));
+        }
+        // the transaction, the file data it refers to and the journal header
+        // of the last commit all go before the commit block
+        buffer_cache::sync(self.device)?;
+        write_direct(self.device, commit_at, &commit)?;
+        buffer_cache::sync(self.device)?;
+        if state.crash_at == Some(CrashPoint::AfterCommitBlock) {
+            return Err(crash(CrashPoint::AfterCommitBlock));
+        }
+
+        for (n, (block, data)) in state.running.iter().enumerate() {
+            if n == targets.len() / 2 && state.crash_at == Some(CrashPoint::MidCheckpoint) {
+                return Err(crash(CrashPoint::MidCheckpoint));
+            }
+            write_block(self.device, *block, data)?;
+        }
+        buffer_cache::sync(self.device)?;
+        // past this the journal is free for the next transaction, whose
+        // first flush makes the new header durable
+        write_header(self.device, &self.superblock, sequence + 1)?;
+        state.sequence = sequence + 1;
+        state.running.clear();
+        state.pending_free.clear();
+        Ok(())
+    }
+
+    /// Writes back the dirty pages of every file.
+    fn write_back(&self) -> vfs::Result<()> {
+        let nodes: Vec<_> = self
+            .nodes
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for node in nodes {
+            node.pages.write_back(&*node)?;
+        }
+        Ok(())
+    }
+
+    fn node(&self, ino: u64, kind: FileType) -> Arc<KfsNode> {
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes.get(&ino).and_then(Weak::upgrade) {
+            return node;
+        }
+        nodes.retain(|_, node| node.strong_count() > 0);
+        let node = Arc::new(KfsNode {
+            fs: self.this.upgrade().unwrap(),
+            ino,
+            kind,
+            pages: PageCache::new(),
+        });
+        nodes.insert(ino, Arc::downgrade(&node));
+        node
+    }
+}
+
+/// Reads block `number` of the volume on `device` through the buffer
+/// cache.
+fn read_block(device: DeviceId, number: u64, buf: &mut [u8]) -> vfs::Result<()> {
+    let sector = device.block_size();
+    let first = number * (BLOCK_SIZE / sector) as u64;
+    for (n, chunk) in buf[..BLOCK_SIZE].chunks_mut(sector).enumerate() {
+        buffer_cache::read(device, first + n as u64, |data| chunk.copy_from_slice(data))?;
+    }
+    Ok(())
+}
+
+/// Reads metadata block `number`, which has to be intact and of `kind`.
+fn read_verified(device: DeviceId, number: u64, kind: u32, buf: &mut [u8]) -> vfs::Result<()> {
+    read_block(device, number, buf)?;
+    if !layout::verify(buf, number, kind) {
+        log::warn!("{}: kfs block {} is corrupt", device, number);
+        return Err(VfsError::Corrupted);
+    }
+    Ok(())
+}
+
+fn write_block(device: DeviceId, number: u64, data: &[u8]) -> vfs::Result<()> {
+    let sector = device.block_size();
+    let first = number * (BLOCK_SIZE / sector) as u64;
+    for (n, chunk) in data[..BLOCK_SIZE].chunks(sector).enumerate() {
+        buffer_cache::overwrite(device, first + n as u64, |data| data.copy_from_slice(chunk))?;
+    }
+    Ok(())
+}
+
+/// Reads blocks from `number` on around the buffer cache.
+fn read_direct(device: DeviceId, number: u64, buf: &mut [u8]) -> vfs::Result<()> {
+    let lba = number * (BLOCK_SIZE / device.block_size()) as u64;
+    Ok(block::read(&device, lba, buf)?)
+}
+
+/// Writes blocks from `number` on around the buffer cache, dropping what
+/// it had of them.
+fn write_direct(device: DeviceId, number: u64, data: &[u8]) -> vfs::Result<()> {
+    let per_block = (BLOCK_SIZE / device.block_size()) as u64;
+    let count = (data.len() / BLOCK_SIZE) as u64 * per_block;
+    buffer_cache::forget(device, number * per_block, count);
+    Ok(block::write(&device, number * per_block, data)?)
+}
+
+fn write_header(device: DeviceId, superblock: &Superblock, sequence: u64) -> vfs::Result<()> {
+    let mut block = vec![0u8; BLOCK_SIZE];
+    JournalHeader { sequence }.encode(&mut block);
+    write_direct(device, superblock.journal_start, &block)
+}
+
+/// Replays the transaction in the journal if it committed, and returns the
+/// sequence number of the next one.
+fn recover(device: DeviceId, superblock: &Superblock, read_only: bool) -> vfs::Result<u64> {
+    let mut block = vec![0u8; BLOCK_SIZE];
+    read_direct(device, superblock.journal_start, &mut block)?;
+    let Some(header) = JournalHeader::decode(&block) else {
+        log::warn!("{}: kfs journal header is corrupt", device);
+        return Err(VfsError::Corrupted);
+    };
+    let sequence = header.sequence;
+    let Some(logged) = committed(device, superblock, sequence)? else {
+        return Ok(sequence);
+    };
+    if read_only {
+        log::warn!(
+            "{}: kfs journal needs replaying on a read-only disk",
+            device
+        );
+        return Err(VfsError::ReadOnly);
+    }
+    log::info!(
+        "{}: replaying kfs transaction {}, {} blocks",
+        device,
+        sequence,
+        logged.len()
+    );
+    for (target, data) in &logged {
+        write_direct(device, *target, data)?;
+    }
+    buffer_cache::sync(device)?;
+    write_header(device, superblock, sequence + 1)?;
+    buffer_cache::sync(device)?;
+    Ok(sequence + 1)
+}
+
+/// Blocks of a transaction, each with the block it belongs in.
+type Logged = Vec<(u64, Vec<u8>)>;
+
+/// The blocks of transaction `sequence`, if the journal holds all of it.
+fn committed(
+    device: DeviceId,
+    superblock: &Superblock,
+    sequence: u64,
+) -> vfs::Result<Option<Logged>> {
+    let start = superblock.journal_start;
+    let mut descriptor = vec![0u8; BLOCK_SIZE];
+    read_direct(device, start + 1, &mut descriptor)?;
+    if !layout::verify(&descriptor, start + 1, kind::DESCRIPTOR) {
+        return Ok(None);
+    }
+    let Some((logged_sequence, count)) = layout::decode_descriptor(&descriptor) else {
+        return Ok(None);
+    };
+    if logged_sequence != sequence || count > superblock.journal_capacity() {
+        return Ok(None);
+    }
+    let mut checksum = layout::crc32c(0, &descriptor);
+    let mut logged = Vec::with_capacity(count);
+    for n in 0..count {
+        let target = layout::descriptor_target(&descriptor, n);
+        if target < superblock.block_bitmap || target >= superblock.block_count {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; BLOCK_SIZE];
+        read_direct(device, start + 2 + n as u64, &mut data)?;
+        checksum = layout::crc32c(checksum, &data);
+        logged.push((target, data));
+    }
+    let commit_at = start + 2 + count as u64;
+    let mut commit = vec![0u8; BLOCK_SIZE];
+    read_direct(device, commit_at, &mut commit)?;
+    let complete = layout::verify(&commit, commit_at, kind::COMMIT)
+        && layout::decode_commit(&commit) == (sequence, checksum);
+    Ok(complete.then_some(logged))
+}
+
+/// The metadata blocks one operation changes, folded into the running
+/// transaction when it succeeds. Dropped unfinished, it leaves no trace.
+struct Transaction<'a> {
+    fs: &'a KfsFs,
+    state: &'a mut State,
+    /// Changed blocks, sealed, by block number.
+    blocks: BTreeMap<u64, Vec<u8>>,
+    freed: BTreeSet<u64>,
+    free_blocks: i64,
+    free_inodes: i64,
+}
+
+impl Transaction<'_> {
+    /// Block `number` as this transaction sees it. Blocks from the disk
+    /// have to be intact and of `kind`.
+    fn read(&self, number: u64, kind: u32) -> vfs::Result<Vec<u8>> {
+        if let Some(data) = self
+            .blocks
+            .get(&number)
+            .or_else(|| self.state.running.get(&number))
+        {
+            return Ok(data.clone());
+        }
+        let mut data = vec![0u8; BLOCK_SIZE];
+        match kind {
+            kind::DATA => read_block(self.fs.device, number, &mut data)?,
+            _ => read_verified(self.fs.device, number, kind, &mut data)?,
+        }
+        Ok(data)
+    }
+
+    /// Fails unless the volume can take changes.
+    fn check_writable(&self) -> vfs::Result<()> {
+        match (self.fs.read_only, self.state.failed) {
+            (true, _) => Err(VfsError::ReadOnly),
+            (_, true) => Err(VfsError::Io),
+            _ => Ok(()),
+        }
+    }
+
+    fn write(&mut self, number: u64, kind: u32, mut data: Vec<u8>) -> vfs::Result<()> {
+        self.check_writable()?;
+        if kind != kind::DATA {
+            layout::seal(&mut data, number, kind);
+        }
+        self.blocks.insert(number, data);
+        Ok(())
+    }
+
+    fn modify<R>(
+        &mut self,
+        number: u64,
+        kind: u32,
+        f: impl FnOnce(&mut [u8]) -> R,
+    ) -> vfs::Result<R> {
+        let mut data = self.read(number, kind)?;
+        let result = f(&mut data);
+        self.write(number, kind, data)?;
+        Ok(result)
+    }
+
+    /// Folds the changes into the running transaction, committing that
+    /// first if the two wouldn't fit in the journal together.
+    fn finish(self) -> vfs::Result<()> {
+        let Transaction {
+            fs,
+            state,
+            blocks,
+            freed,
+            free_blocks,
+            free_inodes,
+        } = self;
+        let capacity = fs.superblock.journal_capacity();
+        if blocks.len() > capacity {
+            log::warn!("{}: kfs operation too big for the journal", fs.device);
+            return Err(VfsError::NoSpace);
+        }
+        let new = blocks
+            .keys()
+            .filter(|number| !state.running.contains_key(number))
+            .count();
+        if state.running.len() + new > capacity {
+            fs.commit(state)?;
+        }
+        state.running.extend(blocks);
+        state.pending_free.extend(freed);
+        state.free_blocks = (state.free_blocks as i64 + free_blocks) as u64;
+        state.free_inodes = (state.free_inodes as i64 + free_inodes) as u64;
+        Ok(())
+    }
+
+    fn corrupt(&self, what: &str, number: u64) -> VfsError {
+        log::warn!("{}: kfs {} {} is corrupt", self.fs.device, what, number);
+        VfsError::Corrupted
+    }
+
+    fn read_inode(&self, ino: u64) -> vfs::Result<RawInode> {
+        let (number, offset) = self
+            .fs
+            .superblock
+            .inode_location(ino)
+            .ok_or_else(|| self.corrupt("inode", ino))?;
+        let data = self.read(number, kind::INODES)?;
+        Ok(RawInode::decode(&data[offset..offset + INODE_SIZE]))
+    }
+
+    fn write_inode(&mut self, ino: u64, inode: &RawInode) -> vfs::Result<()> {
+        let (number, offset) = self
+            .fs
+            .superblock
+            .inode_location(ino)
+            .ok_or_else(|| self.corrupt("inode", ino))?;
+        self.modify(number, kind::INODES, |data| {
+            inode.encode(&mut data[offset..offset + INODE_SIZE])
+        })
+    }
+
+    /// Takes a free block, the first from `goal` on.
+    fn allocate_block(&mut self, goal: u64) -> vfs::Result<u64> {
+        let superblock = self.fs.superblock;
+        let (first, count) = (superblock.data_start, superblock.block_count);
+        let goal = goal.clamp(first, count - 1);
+        for (from, to) in [(goal, count), (first, goal)] {
+            let mut index = from;
+            while index < to {
+                let (number, bit) = layout::bit_location(superblock.block_bitmap, index);
+                let bitmap = self.read(number, kind::BITMAP)?;
+                let end = (index + (BITS_PER_BLOCK - bit as u64)).min(to);
+                let free = (index..end).find(|block| {
+                    !layout::test_bit(&bitmap, bit + (block - index) as usize)
+                        && !self.state.pending_free.contains(block)
+                        && !self.freed.contains(block)
+                });
+                if let Some(block) = free {
+                    let bit = bit + (block - index) as usize;
+                    self.modify(number, kind::BITMAP, |data| {
+                        layout::set_bit(data, bit, true)
+                    })?;
+                    self.free_blocks -= 1;
+                    self.state.block_goal = block + 1;
+                    return Ok(block);
+                }
+                index = end;
+            }
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    /// Frees `count` blocks from `start` on.
+    fn free_run(&mut self, start: u64, count: u64) -> vfs::Result<()> {
+        let superblock = self.fs.superblock;
+        if start < superblock.data_start || start + count > superblock.block_count {
+            return Err(self.corrupt("extent at", start));
+        }
+        let mut next = start;
+        while next < start + count {
+            let (number, bit) = layout::bit_location(superblock.block_bitmap, next);
+            let bits = ((BITS_PER_BLOCK as usize) - bit).min((start + count - next) as usize);
+            let all_used = self.modify(number, kind::BITMAP, |data| {
+                let used = (bit..bit + bits).all(|bit| layout::test_bit(data, bit));
+                (bit..bit + bits).for_each(|bit| layout::set_bit(data, bit, false));
+                used
+            })?;
+            if !all_used {
+                return Err(self.corrupt("block bitmap, freeing free block", next));
+            }
+            next += bits as u64;
+        }
+        for block in start..start + count {
+            self.blocks.remove(&block);
+            self.freed.insert(block);
+        }
+        self.free_blocks += count as i64;
+        Ok(())
+    }
+
+    fn allocate_inode(&mut self) -> vfs::Result<u64> {
+        let superblock = self.fs.superblock;
+        for n in 0..superblock.inode_bitmap_blocks() {
+            let number = superblock.inode_bitmap + n;
+            let bitmap = self.read(number, kind::BITMAP)?;
+            let first = n * BITS_PER_BLOCK;
+            let bits = (superblock.inode_count - first).min(BITS_PER_BLOCK) as usize;
+            if let Some(bit) = (0..bits).find(|bit| !layout::test_bit(&bitmap, *bit)) {
+                self.modify(number, kind::BITMAP, |data| {
+                    layout::set_bit(data, bit, true)
+                })?;
+                self.free_inodes -= 1;
+                return Ok(first + bit as u64 + 1);
+            }
+        }
+        Err(VfsError::NoSpace)
+    }
+
+    fn free_inode(&mut self, ino: u64) -> vfs::Result<()> {
+        let (number, bit) = layout::bit_location(self.fs.superblock.inode_bitmap, ino - 1);
+        let used = self.modify(number, kind::BITMAP, |data| {
+            let used = layout::test_bit(data, bit);
+            layout::set_bit(data, bit, false);
+            used
+        })?;
+        if !used {
+            return Err(self.corrupt("inode bitmap, freeing free inode", ino));
+        }
+        self.free_inodes += 1;
+        Ok(())
+    }
+
+    /// The extents of `inode`, from the inode or its extent blocks.
+    fn extents(&self, inode: &RawInode) -> vfs::Result<Vec<Extent>> {
+        let count = inode.extent_count as usize;
+        if inode.is_inline_symlink() || count == 0 {
+            return Ok(Vec::new());
+        }
+        let slot = |n: usize| Extent::decode(&inode.slots[n * EXTENT_SIZE..]);
+        if inode.flags & RawInode::EXTENT_BLOCKS == 0 {
+            if count > INLINE_EXTENTS {
+                return Err(self.corrupt("extent count", count as u64));
+            }
+            return Ok((0..count).map(slot).collect());
+        }
+        if count > MAX_EXTENTS {
+            return Err(self.corrupt("extent count", count as u64));
+        }
+        let mut extents = Vec::with_capacity(count);
+        for n in 0..inode.slot_count() {
+            let pointer = slot(n);
+            let data = self.read(pointer.physical, kind::EXTENTS)?;
+            let held = (pointer.length as usize).min(EXTENTS_PER_BLOCK);
+            extents.extend((0..held).map(|e| Extent::decode(&data[e * EXTENT_SIZE..])));
+        }
+        if extents.len() != count {
+            return Err(self.corrupt("extent count", count as u64));
+        }
+        Ok(extents)
+    }
+
+    /// Makes `extents` those of `inode`: in the inode if they fit and in
+    /// extent blocks otherwise, allocated or freed to match.
+    fn store_extents(&mut self, inode: &mut RawInode, extents: &[Extent]) -> vfs::Result<()> {
+        if extents.len() > MAX_EXTENTS {
+            return Err(VfsError::NoSpace);
+        }
+        let mut leaves: Vec<u64> = match inode.flags & RawInode::EXTENT_BLOCKS {
+            0 => Vec::new(),
+            _ => (0..inode.slot_count())
+                .map(|n| Extent::decode(&inode.slots[n * EXTENT_SIZE..]).physical)
+                .collect(),
+        };
+        let needed = leaf_blocks(extents.len());
+        let old = leaves.len().min(needed);
+        while leaves.len() > needed {
+            let leaf = leaves.pop().unwrap();
+            self.free_run(leaf, 1)?;
+            inode.blocks -= 1;
+        }
+        while leaves.len() < needed {
+            let goal = leaves.last().map_or(self.state.block_goal, |leaf| leaf + 1);
+            leaves.push(self.allocate_block(goal)?);
+            inode.blocks += 1;
+        }
+
+        inode.slots.fill(0);
+        inode.extent_count = extents.len() as u32;
+        if needed == 0 {
+            inode.flags &= !RawInode::EXTENT_BLOCKS;
+            for (n, extent) in extents.iter().enumerate() {
+                extent.encode(&mut inode.slots[n * EXTENT_SIZE..]);
+            }
+            return Ok(());
+        }
+        inode.flags |= RawInode::EXTENT_BLOCKS;
+        for (n, (chunk, leaf)) in extents.chunks(EXTENTS_PER_BLOCK).zip(&leaves).enumerate() {
+            let mut data = vec![0u8; BLOCK_SIZE];
+            for (e, extent) in chunk.iter().enumerate() {
+                extent.encode(&mut data[e * EXTENT_SIZE..]);
+            }
+            // extent blocks that stay the same are left out of the journal
+            if n >= old || self.read(*leaf, kind::EXTENTS)?[..PAYLOAD] != data[..PAYLOAD] {
+                self.write(*leaf, kind::EXTENTS, data)?;
+            }
+            let pointer = Extent {
+                logical: chunk[0].logical,
+                length: chunk.len() as u32,
+                physical: *leaf,
+            };
+            pointer.encode(&mut inode.slots[n * EXTENT_SIZE..]);
+        }
+        Ok(())
+    }
+
+    /// Frees the blocks of `inode` from block `keep` on.
+    fn free_from(&mut self, inode: &mut RawInode, keep: u64) -> vfs::Result<()> {
+        if inode.is_inline_symlink() {
+            return Ok(());
+        }
+        let extents = self.extents(inode)?;
+        let mut kept = Vec::with_capacity(extents.len());
+        for mut extent in extents {
+            if extent.end() <= keep {
+                kept.push(extent);
+                continue;
+            }
+            let from = keep.max(extent.logical);
+            let count = extent.end() - from;
+            self.free_run(extent.physical + (from - extent.logical), count)?;
+            inode.blocks -= count;
+            if from > extent.logical {
+                extent.length = (from - extent.logical) as u32;
+                kept.push(extent);
+            }
+        }
+        self.store_extents(inode, &kept)
+    }
+
+    /// Frees inode `ino`, which has no links left, and all it holds.
+    fn release(&mut self, ino: u64, inode: &mut RawInode) -> vfs::Result<()> {
+        self.free_from(inode, 0)?;
+        inode.size = 0;
+        inode.mode = 0;
+        self.free_inode(ino)?;
+        self.write_inode(ino, inode)
+    }
+
+    /// Calls `f` with each used record of the directory `dir` from byte
+    /// `start` on: its position and the record.
+    fn scan<R>(
+        &self,
+        dir: &RawInode,
+        start: u64,
+        mut f: impl FnMut(u64, &layout::DirRecord) -> Option<R>,
+    ) -> vfs::Result<Option<R>> {
+        let extents = self.extents(dir)?;
+        for index in start / BLOCK_SIZE as u64..dir.size / BLOCK_SIZE as u64 {
+            let number = find_block(&extents, index)
+                .ok_or_else(|| self.corrupt("directory block", index))?;
+            let data = self.read(number, kind::DIRECTORY)?;
+            let mut offset = 0;
+            while offset < PAYLOAD {
+                let record = layout::dir_record(&data, offset)
+                    .ok_or_else(|| self.corrupt("directory block", number))?;
+                let position = index * BLOCK_SIZE as u64 + offset as u64;
+                if position >= start
+                    && record.ino != 0
+                    && let Some(result) = f(position, &record)
+                {
+                    return Ok(Some(result));
+                }
+                offset += record.len;
+            }
+        }
+        Ok(None)
+    }
+
+    fn find(&self, dir: &RawInode, name: &str) -> vfs::Result<Option<u64>> {
+        self.scan(dir, 0, |_, record| {
+            (record.name == name.as_bytes()).then_some(record.ino)
+        })
+    }
+
+    /// Adds an entry for `ino` to the directory `dir`, splitting the slack
+    /// off a record or growing the directory by a block.
+    fn add_entry(&mut self, dir: &mut RawInode, name: &str, ino: u64, kind: u8) -> vfs::Result<()> {
+        let needed = layout::entry_len(name.len());
+        let mut extents = self.extents(dir)?;
+        let blocks = dir.size / BLOCK_SIZE as u64;
+        for index in 0..blocks {
+            let number = find_block(&extents, index)
+                .ok_or_else(|| self.corrupt("directory block", index))?;
+            let mut data = self.read(number, kind::DIRECTORY)?;
+            let mut offset = 0;
+            while offset < PAYLOAD {
+                let record = layout::dir_record(&data, offset)
+                    .ok_or_else(|| self.corrupt("directory block", number))?;
+                let len = record.len;
+                let used = match record.ino {
+                    0 => 0,
+                    _ => layout::entry_len(record.name.len()),
+                };
+                if len - used >= needed {
+                    if used > 0 {
+                        layout::set_dir_record_len(&mut data, offset, used);
+                    }
+                    layout::put_dir_record(
+                        &mut data,
+                        offset + used,
+                        ino,
+                        len - used,
+                        kind,
+                        name.as_bytes(),
+                    );
+                    return self.write(number, kind::DIRECTORY, data);
+                }
+                offset += len;
+            }
+        }
+
+        let goal = match blocks {
+            0 => self.state.block_goal,
+            _ => find_block(&extents, blocks - 1).map_or(self.state.block_goal, |last| last + 1),
+        };
+        let number = self.allocate_block(goal)?;
+        let mut data = vec![0u8; BLOCK_SIZE];
+        layout::put_dir_record(&mut data, 0, ino, PAYLOAD, kind, name.as_bytes());
+        self.write(number, kind::DIRECTORY, data)?;
+        add_block(&mut extents, blocks, number);
+        self.store_extents(dir, &extents)?;
+        dir.blocks += 1;
+        dir.size += BLOCK_SIZE as u64;
+        Ok(())
+    }
+
+    /// Removes the entry `name` from the directory `dir`, merging its space
+    /// into the record before it.
+    fn remove_entry(&mut self, dir: &RawInode, name: &str) -> vfs::Result<()> {
+        let extents = self.extents(dir)?;
+        for index in 0..dir.size / BLOCK_SIZE as u64 {
+            let number = find_block(&extents, index)
+                .ok_or_else(|| self.corrupt("directory block", index))?;
+            let mut data = self.read(number, kind::DIRECTORY)?;
+            let (mut offset, mut previous) = (0, None);
+            while offset < PAYLOAD {
+                let record = layout::dir_record(&data, offset)
+                    .ok_or_else(|| self.corrupt("directory block", number))?;
+                let len = record.len;
+                if record.ino != 0 && record.name == name.as_bytes() {
+                    match previous {
+                        Some((at, previous_len)) => {
+                            layout::set_dir_record_len(&mut data, at, previous_len + len)
+                        }
+                        None => layout::put_dir_record(&mut data, offset, 0, len, 0, b""),
+                    }
+                    return self.write(number, kind::DIRECTORY, data);
+                }
+                previous = Some((offset, len));
+                offset += len;
+            }
+        }
+        Err(VfsError::NotFound)
+    }
+}
+
+impl FileSystem for KfsFs {
+    fn name(&self) -> &'static str {
+        "kfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.node(ROOT_INO, FileType::Directory)
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn sync(&self) -> vfs::Result<()> {
+        self.write_back()?;
+        if self.read_only {
+            return Ok(());
+        }
+        self.commit(&mut self.state.lock())
+    }
+}
+
+/// An inode of a kfs volume.
+pub struct KfsNode {
+    fs: Arc<KfsFs>,
+    ino: u64,
+    kind: FileType,
+    /// Cached data of a regular file.
+    pages: PageCache,
+}
+
+impl KfsNode {
+    /// The inode of this directory, checking that it still is one.
+    fn directory(&self, tx: &Transaction) -> vfs::Result<RawInode> {
+        let inode = tx.read_inode(self.ino)?;
+        match inode.is_directory() {
+            _ if inode.links == 0 => Err(VfsError::NotFound),
+            true => Ok(inode),
+            false => Err(VfsError::NotDirectory),
+        }
+    }
+
+    fn new_inode(
+        &self,
+        tx: &mut Transaction,
+        dir: &mut RawInode,
+        name: &str,
+        kind: FileType,
+        permissions: u16,
+    ) -> vfs::Result<(u64, RawInode)> {
+        if name.len() > MAX_NAME {
+            return Err(VfsError::NameTooLong);
+        }
+        if tx.find(dir, name)?.is_some() {
+            return Err(VfsError::Exists);
+        }
+        let type_bits = match kind {
+            FileType::Regular => mode::REGULAR,
+            FileType::Directory => mode::DIRECTORY,
+            FileType::Symlink => mode::SYMLINK,
+            _ => return Err(VfsError::Unsupported),
+        };
+        let ino = tx.allocate_inode()?;
+        let now = time::now();
+        let mut inode = RawInode {
+            mode: type_bits | (permissions & 0o7777),
+            links: 1,
+            accessed: now,
+            modified: now,
+            changed: now,
+            ..RawInode::EMPTY
+        };
+        if kind == FileType::Directory {
+            if dir.links >= MAX_LINKS {
+                return Err(VfsError::NoSpace);
+            }
+            let number = tx.allocate_block(tx.state.block_goal)?;
+            let mut data = vec![0u8; BLOCK_SIZE];
+            layout::init_dir_block(&mut data, ino, self.ino);
+            tx.write(number, kind::DIRECTORY, data)?;
+            let extent = Extent {
+                logical: 0,
+                length: 1,
+                physical: number,
+            };
+            tx.store_extents(&mut inode, &[extent])?;
+            inode.size = BLOCK_SIZE as u64;
+            inode.blocks = 1;
+            inode.links = 2;
+            dir.links += 1;
+        }
+        tx.add_entry(dir, name, ino, entry_kind::of_mode(inode.mode))?;
+        dir.modified = now;
+        dir.changed = now;
+        Ok((ino, inode))
+    }
+
+    fn remove(&self, name: &str, directory: bool) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut tx = fs.transaction(&mut state);
+        let mut dir = self.directory(&tx)?;
+        let ino = tx.find(&dir, name)?.ok_or(VfsError::NotFound)?;
+        let mut inode = tx.read_inode(ino)?;
+        match (directory, inode.is_directory()) {
+            (true, true) => {
+                let other = tx.scan(&inode, 0, |_, record| {
+                    (record.name != b"." && record.name != b"..").then_some(())
+                })?;
+                if other.is_some() {
+                    return Err(VfsError::NotEmpty);
+                }
+            }
+            (true, false) => return Err(VfsError::NotDirectory),
+            (false, true) => return Err(VfsError::IsDirectory),
+            (false, false) => {}
+        }
+        tx.remove_entry(&dir, name)?;
+        let now = time::now();
+        dir.modified = now;
+        dir.changed = now;
+        inode.changed = now;
+        inode.links = match directory {
+            true => {
+                dir.links -= 1;
+                0
+            }
+            false => inode.links.saturating_sub(1),
+        };
+        // an open inode is freed when its node goes
+        let open = fs
+            .nodes
+            .lock()
+            .get(&ino)
+            .is_some_and(|node| node.strong_count() > 0);
+        let released = inode.links == 0 && !open;
+        match released {
+            true => tx.release(ino, &mut inode)?,
+            false => tx.write_inode(ino, &inode)?,
+        }
+        tx.write_inode(self.ino, &dir)?;
+        tx.finish()?;
+        if released {
+            state.unreserve(ino, |_| false);
+        }
+        Ok(())
+    }
+
+    /// Frees the inode if its last link went while it was open, and
+    /// writes back its pages otherwise.
+    fn close(&self) -> vfs::Result<()> {
+        let fs = &self.fs;
+        if !fs.read_only {
+            let mut state = fs.state.lock();
+            let mut tx = fs.transaction(&mut state);
+            let mut inode = tx.read_inode(self.ino)?;
+            // a node made for the inode since this one's last reference
+            // went frees it instead
+            let other = fs
+                .nodes
+                .lock()
+                .get(&self.ino)
+                .is_some_and(|node| node.strong_count() > 0);
+            if inode.links == 0 && inode.mode != 0 && !other {
+                // dirty pages must not reach the blocks about to be freed
+                self.pages.discard();
+                tx.release(self.ino, &mut inode)?;
+                tx.finish()?;
+                state.unreserve(self.ino, |_| false);
+                return Ok(());
+            }
+        }
+        if self.pages.is_dirty() {
+            self.pages.write_back(self)?;
+        }
+        Ok(())
+    }
+}
+
+impl Backing for KfsNode {
+    fn read_page(&self, index: u64, buf: &mut [u8]) -> vfs::Result<bool> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let tx = fs.transaction(&mut state);
+        let inode = tx.read_inode(self.ino)?;
+        let (start, size) = (index * PAGE_SIZE as u64, inode.size);
+        if start >= size {
+            return Ok(false);
+        }
+        let Some(number) = find_block(&tx.extents(&inode)?, index) else {
+            return Ok(false);
+        };
+        read_direct(fs.device, number, buf)?;
+        if size - start < PAGE_SIZE as u64 {
+            buf[(size - start) as usize..].fill(0);
+        }
+        Ok(true)
+    }
+
+    fn write_page(&self, index: u64, data: &[u8]) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut tx = fs.transaction(&mut state);
+        let mut inode = tx.read_inode(self.ino)?;
+        let (start, size) = (index * PAGE_SIZE as u64, inode.size);
+        if start >= size {
+            return Ok(());
+        }
+        let mut extents = tx.extents(&inode)?;
+        let number = match find_block(&extents, index) {
+            Some(number) => number,
+            None => {
+                let goal = match index {
+                    0 => tx.state.block_goal,
+                    _ => find_block(&extents, index - 1).map_or(tx.state.block_goal, |n| n + 1),
+                };
+                let number = tx.allocate_block(goal)?;
+                add_block(&mut extents, index, number);
+                tx.store_extents(&mut inode, &extents)?;
+                inode.blocks += 1;
+                tx.write_inode(self.ino, &inode)?;
+                number
+            }
+        };
+        tx.check_writable()?;
+        // the data goes first, so that the extent giving the block to the
+        // file never commits ahead of it; what a mapping wrote past the end
+        // stays off the disk
+        let len = (size - start).min(PAGE_SIZE as u64) as usize;
+        if len < PAGE_SIZE {
+            let mut last = vec![0u8; PAGE_SIZE];
+            last[..len].copy_from_slice(&data[..len]);
+            write_direct(fs.device, number, &last)?;
+        } else {
+            write_direct(fs.device, number, data)?;
+        }
+        tx.finish()?;
+        state.unreserve(self.ino, |pending| pending != index);
+        state.reserve_leaves(self.ino, extents.len());
+        Ok(())
+    }
+}
+
+/// Blocks set aside by a write, given back if it fails before its pages
+/// are in the file. A page past the size is never written back, so its
+/// block would stay set aside for good; one the write left dirty within
+/// the size keeps its block until it is written back.
+struct Reservation<'a> {
+    node: &'a KfsNode,
+    indices: Vec<u64>,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let pages = &self.node.pages;
+        self.indices
+            .retain(|index| pages.find(*index).is_none_or(|page| !page.is_dirty()));
+        if self.indices.is_empty() {
+            return;
+        }
+        let indices = &self.indices;
+        let mut state = self.node.fs.state.lock();
+        state.unreserve(self.node.ino, |index| !indices.contains(&index));
+    }
+}
+
+impl Drop for KfsNode {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            log::warn!("kfs: closing inode {} failed: {}", self.ino, err);
+        }
+    }
+}
+
+impl Inode for KfsNode {
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        let mut state = self.fs.state.lock();
+        let inode = self.fs.transaction(&mut state).read_inode(self.ino)?;
+        let kind = file_type(inode.mode);
+        let device = match kind {
+            FileType::CharDevice | FileType::BlockDevice => inode.device,
+            _ => 0,
+        };
+        Ok(Metadata {
+            ino: self.ino,
+            kind,
+            mode: inode.mode & 0o7777,
+            uid: inode.uid,
+            gid: inode.gid,
+            size: inode.size,
+            links: inode.links as u32,
+            blocks: inode.blocks * (BLOCK_SIZE / 512) as u64,
+            device,
+            accessed: inode.accessed,
+            modified: inode.modified,
+            changed: inode.changed,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let tx = fs.transaction(&mut state);
+        let dir = self.directory(&tx)?;
+        let ino = tx.find(&dir, name)?.ok_or(VfsError::NotFound)?;
+        let kind = file_type(tx.read_inode(ino)?.mode);
+        Ok(fs.node(ino, kind))
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u16) -> vfs::Result<Arc<dyn Inode>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut tx = fs.transaction(&mut state);
+        let mut dir = self.directory(&tx)?;
+        let (ino, inode) = self.new_inode(&mut tx, &mut dir, name, kind, mode)?;
+        tx.write_inode(ino, &inode)?;
+        tx.write_inode(self.ino, &dir)?;
+        tx.finish()?;
+        Ok(fs.node(ino, kind))
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let fs = &self.fs;
+        if target.len() > BLOCK_SIZE {
+            return Err(VfsError::NameTooLong);
+        }
+        let mut state = fs.state.lock();
+        let mut tx = fs.transaction(&mut state);
+        let mut dir = self.directory(&tx)?;
+        let (ino, mut inode) = self.new_inode(&mut tx, &mut dir, name, FileType::Symlink, 0o777)?;
+        inode.size = target.len() as u64;
+        if target.len() <= INLINE_SYMLINK {
+            inode.slots[..target.len()].copy_from_slice(target.as_bytes());
+        } else {
+            let number = tx.allocate_block(tx.state.block_goal)?;
+            let mut data = vec![0u8; BLOCK_SIZE];
+            data[..target.len()].copy_from_slice(target.as_bytes());
+            tx.write(number, kind::DATA, data)?;
+            let extent = Extent {
+                logical: 0,
+                length: 1,
+                physical: number,
+            };
+            tx.store_extents(&mut inode, &[extent])?;
+            inode.blocks = 1;
+        }
+        tx.write_inode(ino, &inode)?;
+        tx.write_inode(self.ino, &dir)?;
+        tx.finish()?;
+        Ok(fs.node(ino, FileType::Symlink))
+    }
+
+    fn link(&self, name: &str, target: &Arc<dyn Inode>) -> vfs::Result<()> {
+        let fs = &self.fs;
+        if name.len() > MAX_NAME {
+            return Err(VfsError::NameTooLong);
+        }
+        let ino = target.metadata()?.ino;
+        let mut state = fs.state.lock();
+        let mut tx = fs.transaction(&mut state);
+        let mut dir = self.directory(&tx)?;
+        if tx.find(&dir, name)?.is_some() {
+            return Err(VfsError::Exists);
+        }
+        let mut inode = tx.read_inode(ino)?;
+        match inode.links {
+            0 => return Err(VfsError::NotFound),
+            MAX_LINKS.. => return Err(VfsError::NoSpace),
+            links => inode.links = links + 1,
+        }
+        tx.add_entry(&mut dir, name, ino, entry_kind::of_mode(inode.mode))?;
+        let now = time::now();
+        dir.modified = now;
+        dir.changed = now;
+        inode.changed = now;
+        tx.write_inode(ino, &inode)?;
+        tx.write_inode(self.ino, &dir)?;
+        tx.finish()
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.remove(name, false)
+    }
+
+    fn rmdir(&self, name: &str) -> vfs::Result<()> {
+        self.remove(name, true)
+    }
+
+    fn read_dir(&self, cursor: u64) -> vfs::Result<Option<(DirEntry, u64)>> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let tx = fs.transaction(&mut state);
+        let dir = self.directory(&tx)?;
+        let found = tx.scan(&dir, cursor, |position, record| {
+            if record.name == b"." || record.name == b".." {
+                return None;
+            }
+            let entry = DirEntry {
+                ino: record.ino,
+                kind: entry_type(record.kind),
+                name: String::from_utf8_lossy(record.name).into(),
+            };
+            // the entry after this one starts where its record ends
+            Some((entry, position + record.len as u64))
+        })?;
+        Ok(found)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let size = self.metadata()?.size;
+        let len = buf.len().min(size.saturating_sub(offset) as usize);
+        self.pages.read(self, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        let fs = &self.fs;
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(VfsError::InvalidArgument)?;
+        if end.div_ceil(BLOCK_SIZE as u64) > MAX_FILE_BLOCKS {
+            return Err(VfsError::NoSpace);
+        }
+        let pages = offset / PAGE_SIZE as u64..end.div_ceil(PAGE_SIZE as u64);
+
+        // blocks for the pages are taken when they are written back, but
+        // set aside now
+        let mut flushed = false;
+        let (mut reservation, size) = loop {
+            let mut state = fs.state.lock();
+            let tx = fs.transaction(&mut state);
+            tx.check_writable()?;
+            let inode = tx.read_inode(self.ino)?;
+            let extents = tx.extents(&inode)?;
+            drop(tx);
+            let wanted: Vec<u64> = pages
+                .clone()
+                .filter(|index| {
+                    find_block(&extents, *index).is_none()
+                        && !state.reserved.contains(&(self.ino, *index))
+                })
+                .collect();
+            // and so are the extent blocks they may need
+            let leaves = leaf_blocks(extents.len() + state.pending(self.ino) + wanted.len())
+                - leaf_blocks(extents.len());
+            let held = state.reserved_leaves.get(&self.ino).copied().unwrap_or(0);
+            let needed = wanted.len() as u64 + (leaves as u64).saturating_sub(held);
+            if needed > state.available() {
+                // that counts an extent for every pending page; writing
+                // them back shows how many they really take
+                if !flushed && !state.reserved.is_empty() {
+                    drop(state);
+                    fs.write_back()?;
+                    flushed = true;
+                    continue;
+                }
+                return Err(VfsError::NoSpace);
+            }
+            state
+                .reserved
+                .extend(wanted.iter().map(|index| (self.ino, *index)));
+            state.reserve_leaves(self.ino, extents.len());
+            let reservation = Reservation {
+                node: self,
+                indices: wanted,
+            };
+            break (reservation, inode.size);
+        };
+        let written = self.pages.write(self, offset, buf);
+        let grown = written.and_then(|()| {
+            let mut state = fs.state.lock();
+            let mut tx = fs.transaction(&mut state);
+            let mut inode = tx.read_inode(self.ino)?;
+            inode.size = inode.size.max(end);
+            inode.modified = time::now();
+            inode.changed = inode.modified;
+            tx.write_inode(self.ino, &inode)?;
+            tx.finish()
+        });
+        if let Err(err) = grown {
+            // what went past the end goes, for nobody to read or write
+            // back; what landed within it is written, as far as it got
+            self.pages.truncate(size);
+            return match written {
+                Ok(()) if offset < size => Ok(buf.len().min((size - offset) as usize)),
+                _ => Err(err),
+            };
+        }
+        // write-back takes them from here
+        reservation.indices.clear();
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> vfs::Result<()> {
+        let fs = &self.fs;
+        if self.kind == FileType::Directory {
+            return Err(VfsError::IsDirectory);
+        }
+        if size.div_ceil(BLOCK_SIZE as u64) > MAX_FILE_BLOCKS {
+            return Err(VfsError::NoSpace);
+        }
+        if size < self.metadata()?.size {
+            // the rest of the last page has to read as zeroes if the file
+            // grows again, and reach the disk as such
+            if !size.is_multiple_of(PAGE_SIZE as u64) {
+                self.pages.get(self, size / PAGE_SIZE as u64)?;
+            }
+            self.pages.truncate(size);
+        }
+        let mut state = fs.state.lock();
+        let mut tx = fs.transaction(&mut state);
+        let mut inode = tx.read_inode(self.ino)?;
+        let keep = size.div_ceil(BLOCK_SIZE as u64);
+        if size < inode.size {
+            tx.free_from(&mut inode, keep)?;
+        }
+        inode.size = size;
+        inode.modified = time::now();
+        inode.changed = inode.modified;
+        tx.write_inode(self.ino, &inode)?;
+        tx.finish()?;
+        state.unreserve(self.ino, |index| index < keep);
+        Ok(())
+    }
+
+    fn page(&self, index: u64) -> vfs::Result<Arc<Page>> {
+        if self.kind != FileType::Regular {
+            return Err(VfsError::InvalidArgument);
+        }
+        self.pages.get(self, index)
+    }
+
+    /// Writes back the file's pages and commits, like `fsync`.
+    fn write_back(&self) -> vfs::Result<()> {
+        self.pages.write_back(self)?;
+        if self.fs.read_only {
+            return Ok(());
+        }
+        self.fs.commit(&mut self.fs.state.lock())
+    }
+
+    fn read_link(&self) -> vfs::Result<String> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let tx = fs.transaction(&mut state);
+        let inode = tx.read_inode(self.ino)?;
+        if file_type(inode.mode) != FileType::Symlink {
+            return Err(VfsError::InvalidArgument);
+        }
+        let len = inode.size as usize;
+        let target = match inode.is_inline_symlink() {
+            true => inode.slots[..len].to_vec(),
+            false => {
+                let number = find_block(&tx.extents(&inode)?, 0)
+                    .filter(|_| len <= BLOCK_SIZE)
+                    .ok_or_else(|| tx.corrupt("symlink", self.ino))?;
+                let mut data = tx.read(number, kind::DATA)?;
+                data.truncate(len);
+                data
+            }
+        };
+        String::from_utf8(target).map_err(|_| VfsError::InvalidArgument)
+    }
+
+    fn set_attributes(&self, attributes: &Attributes) -> vfs::Result<()> {
+        let fs = &self.fs;
+        let mut state = fs.state.lock();
+        let mut tx = fs.transaction(&mut state);
+        let mut inode = tx.read_inode(self.ino)?;
+        if let Some(mode) = attributes.mode {
+            inode.mode = inode.mode & mode::TYPE_MASK | mode & 0o7777;
+        }
+        if let Some(uid) = attributes.uid {
+            inode.uid = uid;
+        }
+        if let Some(gid) = attributes.gid {
+            inode.gid = gid;
+        }
+        inode.changed = time::now();
+        if let Some(accessed) = attributes.accessed {
+            inode.accessed = accessed;
+        }
+        if let Some(modified) = attributes.modified {
+            inode.modified = modified;
+        }
+        tx.write_inode(self.ino, &inode)?;
+        tx.finish()
+    }
+}
@@ -0,0 +1,666 @@
+// On-disk layout of kfs, the kernel's native filesystem. The host-side
+// mkfs-kfs and fsck-kfs build on this same file, so nothing here needs
+// more than `core`.
+//
+// A volume is an array of 4 KiB blocks:
+//
+//   superblock    block 0
+//   journal       a header block, then room for one transaction
+//   block bitmap  a bit per block of the volume
+//   inode bitmap  a bit per inode
+//   inode table   256-byte inodes, 15 to a block
+//   data          file data, directory blocks and extent blocks
+//
+// Every metadata block ends in an 8-byte tail holding its kind and a
+// CRC-32C over its block number and everything before the checksum, so a
+// torn, misdirected or stale write shows when the block is read. The
+// superblock and the journal header fit in their first sector, which a disk
+// writes whole, and keep their checksum at its end.
+//
+// Files map their data through extents: up to 12 fit in the inode, and a
+// file with more keeps them in extent blocks the inode points to instead.
+// Directories are chains of variable-length records, as on ext2, and count
+// as metadata. The journal holds one transaction at a time: a descriptor
+// block listing where each logged block belongs, the blocks, and a commit
+// block with a checksum over all of them. A transaction whose commit block
+// checks out with the sequence number the header expects is replayed at
+// mount, after which the header moves on to the next number.
+
+pub const BLOCK_SIZE: usize = 4096;
+pub const MAGIC: [u8; 8] = *b"KARKFS01";
+pub const JOURNAL_MAGIC: [u8; 8] = *b"KFSJRNL1";
+pub const ROOT_INO: u64 = 1;
+pub const MAX_NAME: usize = 255;
+
+const TAIL_SIZE: usize = 8;
+/// Bytes of a metadata block in front of its tail.
+pub const PAYLOAD: usize = BLOCK_SIZE - TAIL_SIZE;
+/// Bytes the superblock and journal header keep to.
+const SECTOR: usize = 512;
+
+pub const INODE_SIZE: usize = 256;
+pub const INODES_PER_BLOCK: u64 = (PAYLOAD / INODE_SIZE) as u64;
+pub const BITS_PER_BLOCK: u64 = PAYLOAD as u64 * 8;
+pub const EXTENT_SIZE: usize = 16;
+/// Extents, or extent block pointers, an inode holds.
+pub const INLINE_EXTENTS: usize = 12;
+pub const EXTENTS_PER_BLOCK: usize = PAYLOAD / EXTENT_SIZE;
+/// The most extents a file can have.
+pub const MAX_EXTENTS: usize = INLINE_EXTENTS * EXTENTS_PER_BLOCK;
+/// Symlink targets up to this long live in the inode.
+pub const INLINE_SYMLINK: usize = INLINE_EXTENTS * EXTENT_SIZE;
+/// Blocks a transaction can log, as many as a descriptor block lists.
+pub const MAX_TRANSACTION: usize = (PAYLOAD - 16) / 8;
+
+/// Kinds of metadata block, as their tails record them.
+pub mod kind {
+    /// File data and long symlink targets, which have no tail.
+    pub const DATA: u32 = 0;
+    pub const BITMAP: u32 = 1;
+    pub const INODES: u32 = 2;
+    pub const DIRECTORY: u32 = 3;
+    pub const EXTENTS: u32 = 4;
+    pub const DESCRIPTOR: u32 = 5;
+    pub const COMMIT: u32 = 6;
+}
+
+/// Type and permission bits of an inode's mode, as on Linux.
+pub mod mode {
+    pub const TYPE_MASK: u16 = 0o170000;
+    pub const FIFO: u16 = 0o010000;
+    pub const CHAR_DEVICE: u16 = 0o020000;
+    pub const DIRECTORY: u16 = 0o040000;
+    pub const BLOCK_DEVICE: u16 = 0o060000;
+    pub const REGULAR: u16 = 0o100000;
+    pub const SYMLINK: u16 = 0o120000;
+}
+
+/// Type bytes of directory entries, as on ext2.
+pub mod entry_kind {
+    pub const REGULAR: u8 = 1;
+    pub const DIRECTORY: u8 = 2;
+    pub const CHAR_DEVICE: u8 = 3;
+    pub const BLOCK_DEVICE: u8 = 4;
+    pub const FIFO: u8 = 5;
+    pub const SYMLINK: u8 = 7;
+
+    /// The entry type for an inode of `mode`.
+    pub fn of_mode(mode: u16) -> u8 {
+        match mode & super::mode::TYPE_MASK {
+            super::mode::DIRECTORY => DIRECTORY,
+            super::mode::CHAR_DEVICE => CHAR_DEVICE,
+            super::mode::BLOCK_DEVICE => BLOCK_DEVICE,
+            super::mode::FIFO => FIFO,
+            super::mode::SYMLINK => SYMLINK,
+            _ => REGULAR,
+        }
+    }
+}
+
+pub fn le16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes(bytes[..2].try_into().unwrap())
+}
+
+pub fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+pub fn le64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+pub fn put16(bytes: &mut [u8], value: u16) {
+    bytes[..2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put32(bytes: &mut [u8], value: u32) {
+    bytes[..4].copy_from_slice(&value.to_le_bytes());
+}
+
+pub fn put64(bytes: &mut [u8], value: u64) {
+    bytes[..8].copy_from_slice(&value.to_le_bytes());
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0x82F6_3B78,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues the CRC-32C `crc` over `data`; a checksum starts from 0.
+pub fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc = CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn block_checksum(block: &[u8], number: u64) -> u32 {
+    crc32c(crc32c(0, &number.to_le_bytes()), &block[..BLOCK_SIZE - 4])
+}
+
+/// Fills in the tail of metadata block `number`.
+pub fn seal(block: &mut [u8], number: u64, kind: u32) {
+    put32(&mut block[PAYLOAD..], kind);
+    let checksum = block_checksum(block, number);
+    put32(&mut block[PAYLOAD + 4..], checksum);
+}
+
+/// Whether metadata block `number` is intact and of `kind`.
+pub fn verify(block: &[u8], number: u64, kind: u32) -> bool {
+    le32(&block[PAYLOAD..]) == kind && le32(&block[PAYLOAD + 4..]) == block_checksum(block, number)
+}
+
+fn sector_checksum(block: &[u8]) -> u32 {
+    crc32c(0, &block[..SECTOR - 4])
+}
+
+/// Bitmap block and bit of item `index` of the bitmap starting at `start`.
+pub fn bit_location(start: u64, index: u64) -> (u64, usize) {
+    (
+        start + index / BITS_PER_BLOCK,
+        (index % BITS_PER_BLOCK) as usize,
+    )
+}
+
+pub fn test_bit(block: &[u8], bit: usize) -> bool {
+    block[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+pub fn set_bit(block: &mut [u8], bit: usize, on: bool) {
+    match on {
+        true => block[bit / 8] |= 1 << (bit % 8),
+        false => block[bit / 8] &= !(1 << (bit % 8)),
+    }
+}
+
+/// Where everything is on a volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Superblock {
+    pub block_count: u64,
+    pub inode_count: u64,
+    pub journal_start: u64,
+    pub journal_blocks: u64,
+    pub block_bitmap: u64,
+    pub inode_bitmap: u64,
+    pub inode_table: u64,
+    /// First block past the inode table.
+    pub data_start: u64,
+    /// Seconds since the epoch.
+    pub created: u64,
+    pub label: [u8; 32],
+}
+
+impl Superblock {
+    /// Lays out a volume of `block_count` blocks with `inode_count` inodes
+    /// and a journal of `journal_blocks`. None if that leaves no room for
+    /// data, or the label is too long.
+    pub fn new(
+        block_count: u64,
+        inode_count: u64,
+        journal_blocks: u64,
+        label: &str,
+        created: u64,
+    ) -> Option<Superblock> {
+        if label.len() > 32 || inode_count == 0 || journal_blocks < 4 {
+            return None;
+        }
+        let mut superblock = Superblock {
+            block_count,
+            inode_count,
+            journal_start: 1,
+            journal_blocks,
+            block_bitmap: 0,
+            inode_bitmap: 0,
+            inode_table: 0,
+            data_start: 0,
+            created,
+            label: [0; 32],
+        };
+        superblock.label[..label.len()].copy_from_slice(label.as_bytes());
+        superblock.block_bitmap = 1 + journal_blocks;
+        superblock.inode_bitmap = superblock.block_bitmap + superblock.block_bitmap_blocks();
+        superblock.inode_table = superblock.inode_bitmap + superblock.inode_bitmap_blocks();
+        superblock.data_start = superblock.inode_table + superblock.inode_table_blocks();
+        (superblock.data_start < block_count).then_some(superblock)
+    }
+
+    /// A journal of 1/32 of the volume, within what one transaction uses.
+    pub fn default_journal_blocks(block_count: u64) -> u64 {
+        (block_count / 32).clamp(64, MAX_TRANSACTION as u64 + 3)
+    }
+
+    /// An inode per 16 KiB, as mke2fs makes.
+    pub fn default_inode_count(block_count: u64) -> u64 {
+        (block_count / 4).max(INODES_PER_BLOCK)
+    }
+
+    pub fn block_bitmap_blocks(&self) -> u64 {
+        self.block_count.div_ceil(BITS_PER_BLOCK)
+    }
+
+    pub fn inode_bitmap_blocks(&self) -> u64 {
+        self.inode_count.div_ceil(BITS_PER_BLOCK)
+    }
+
+    pub fn inode_table_blocks(&self) -> u64 {
+        self.inode_count.div_ceil(INODES_PER_BLOCK)
+    }
+
+    /// Blocks one transaction can log.
+    pub fn journal_capacity(&self) -> usize {
+        ((self.journal_blocks - 3) as usize).min(MAX_TRANSACTION)
+    }
+
+    pub fn label(&self) -> &str {
+        let len = self.label.iter().position(|byte| *byte == 0).unwrap_or(32);
+        core::str::from_utf8(&self.label[..len]).unwrap_or("")
+    }
+
+    /// Inode table block and byte offset of inode `ino`.
+    pub fn inode_location(&self, ino: u64) -> Option<(u64, usize)> {
+        if ino == 0 || ino > self.inode_count {
+            return None;
+        }
+        let index = ino - 1;
+        Some((
+            self.inode_table + index / INODES_PER_BLOCK,
+            (index % INODES_PER_BLOCK) as usize * INODE_SIZE,
+        ))
+    }
+
+    pub fn encode(&self, block: &mut [u8]) {
+        block[..BLOCK_SIZE].fill(0);
+        block[..8].copy_from_slice(&MAGIC);
+        let fields = [
+            self.block_count,
+            self.inode_count,
+            self.journal_start,
+            self.journal_blocks,
+            self.block_bitmap,
+            self.inode_bitmap,
+            self.inode_table,
+            self.data_start,
+            self.created,
+        ];
+        for (n, field) in fields.iter().enumerate() {
+            put64(&mut block[8 + n * 8..], *field);
+        }
+        block[80..112].copy_from_slice(&self.label);
+        let checksum = sector_checksum(block);
+        put32(&mut block[SECTOR - 4..], checksum);
+    }
+
+    /// The superblock in `block`, if it is one and describes a layout that
+    /// holds together.
+    pub fn decode(block: &[u8]) -> Option<Superblock> {
+        if block[..8] != MAGIC || le32(&block[SECTOR - 4..]) != sector_checksum(block) {
+            return None;
+        }
+        let field = |n: usize| le64(&block[8 + n * 8..]);
+        let superblock = Superblock {
+            block_count: field(0),
+            inode_count: field(1),
+            journal_start: field(2),
+            journal_blocks: field(3),
+            block_bitmap: field(4),
+            inode_bitmap: field(5),
+            inode_table: field(6),
+            data_start: field(7),
+            created: field(8),
+            label: block[80..112].try_into().unwrap(),
+        };
+        let expected = Superblock::new(
+            superblock.block_count,
+            superblock.inode_count,
+            superblock.journal_blocks,
+            "",
+            superblock.created,
+        )?;
+        let same = Superblock {
+            label: superblock.label,
+            ..expected
+        };
+        (same == superblock).then_some(superblock)
+    }
+}
+
+/// The first block of the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JournalHeader {
+    /// Sequence number of the next transaction.
+    pub sequence: u64,
+}
+
+impl JournalHeader {
+    pub fn encode(&self, block: &mut [u8]) {
+        block[..BLOCK_SIZE].fill(0);
+        block[..8].copy_from_slice(&JOURNAL_MAGIC);
+        put64(&mut block[8..], self.sequence);
+        let checksum = sector_checksum(block);
+        put32(&mut block[SECTOR - 4..], checksum);
+    }
+
+    pub fn decode(block: &[u8]) -> Option<JournalHeader> {
+        (block[..8] == JOURNAL_MAGIC && le32(&block[SECTOR - 4..]) == sector_checksum(block)).then(
+            || JournalHeader {
+                sequence: le64(&block[8..]),
+            },
+        )
+    }
+}
+
+/// Fills in the descriptor block of transaction `sequence`, which logs
+/// blocks for `targets`. The caller seals it.
+pub fn encode_descriptor(block: &mut [u8], sequence: u64, targets: &[u64]) {
+    block[..BLOCK_SIZE].fill(0);
+    put64(block, sequence);
+    put32(&mut block[8..], targets.len() as u32);
+    for (n, target) in targets.iter().enumerate() {
+        put64(&mut block[16 + n * 8..], *target);
+    }
+}
+
+/// Sequence number and target count of a sealed descriptor block.
+pub fn decode_descriptor(block: &[u8]) -> Option<(u64, usize)> {
+    let count = le32(&block[8..]) as usize;
+    (count <= MAX_TRANSACTION).then(|| (le64(block), count))
+}
+
+/// Where logged block `n` of a descriptor belongs.
+pub fn descriptor_target(block: &[u8], n: usize) -> u64 {
+    le64(&block[16 + n * 8..])
+}
+
+/// Fills in the commit block of transaction `sequence`; `checksum` is the
+/// CRC-32C of its descriptor and logged blocks, one after the other. The
+/// caller seals it.
+pub fn encode_commit(block: &mut [u8], sequence: u64, checksum: u32) {
+    block[..BLOCK_SIZE].fill(0);
+    put64(block, sequence);
+    put32(&mut block[8..], checksum);
+}
+
+/// Sequence number and checksum of a sealed commit block.
+pub fn decode_commit(block: &[u8]) -> (u64, u32) {
+    (le64(block), le32(&block[8..]))
+}
+
+/// The 256-byte form of an inode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawInode {
+    pub mode: u16,
+    pub links: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub flags: u32,
+    pub size: u64,
+    /// Blocks held, extent blocks included.
+    pub blocks: u64,
+    pub accessed: u64,
+    pub modified: u64,
+    pub changed: u64,
+    /// Device number of a device node.
+    pub device: u32,
+    pub extent_count: u32,
+    /// Extents, extent block pointers with EXTENT_BLOCKS, or a short
+    /// symlink's target.
+    pub slots: [u8; INLINE_SYMLINK],
+}
+
+impl RawInode {
+    /// Flag: the slots point to extent blocks.
+    pub const EXTENT_BLOCKS: u32 = 1;
+
+    pub const EMPTY: RawInode = RawInode {
+        mode: 0,
+        links: 0,
+        uid: 0,
+        gid: 0,
+        flags: 0,
+        size: 0,
+        blocks: 0,
+        accessed: 0,
+        modified: 0,
+        changed: 0,
+        device: 0,
+        extent_count: 0,
+        slots: [0; INLINE_SYMLINK],
+    };
+
+    pub fn decode(bytes: &[u8]) -> RawInode {
+        RawInode {
+            mode: le16(bytes),
+            links: le16(&bytes[2..]),
+            uid: le32(&bytes[4..]),
+            gid: le32(&bytes[8..]),
+            flags: le32(&bytes[12..]),
+            size: le64(&bytes[16..]),
+            blocks: le64(&bytes[24..]),
+            accessed: le64(&bytes[32..]),
+            modified: le64(&bytes[40..]),
+            changed: le64(&bytes[48..]),
+            device: le32(&bytes[56..]),
+            extent_count: le32(&bytes[60..]),
+            slots: bytes[64..INODE_SIZE].try_into().unwrap(),
+        }
+    }
+
+    pub fn encode(&self, bytes: &mut [u8]) {
+        put16(bytes, self.mode);
+        put16(&mut bytes[2..], self.links);
+        put32(&mut bytes[4..], self.uid);
+        put32(&mut bytes[8..], self.gid);
+        put32(&mut bytes[12..], self.flags);
+        put64(&mut bytes[16..], self.size);
+        put64(&mut bytes[24..], self.blocks);
+        put64(&mut bytes[32..], self.accessed);
+        put64(&mut bytes[40..], self.modified);
+        put64(&mut bytes[48..], self.changed);
+        put32(&mut bytes[56..], self.device);
+        put32(&mut bytes[60..], self.extent_count);
+        bytes[64..INODE_SIZE].copy_from_slice(&self.slots);
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.mode & mode::TYPE_MASK == mode::DIRECTORY
+    }
+
+    /// A symlink whose target is in the slots rather than in a block.
+    pub fn is_inline_symlink(&self) -> bool {
+        self.mode & mode::TYPE_MASK == mode::SYMLINK && self.size as usize <= INLINE_SYMLINK
+    }
+
+    /// Extents, or extent block pointers, in the slots.
+    pub fn slot_count(&self) -> usize {
+        match self.flags & Self::EXTENT_BLOCKS {
+            0 => self.extent_count as usize,
+            _ => (self.extent_count as usize).div_ceil(EXTENTS_PER_BLOCK),
+        }
+    }
+}
+
+/// A run of `length` blocks of a file from block `logical` on, stored from
+/// block `physical` on. An extent block pointer has the same form: the
+/// first logical block it maps, the extents it holds as the length, and
+/// the extent block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u64,
+    pub length: u32,
+    pub physical: u64,
+}
+
+impl Extent {
+    pub fn decode(bytes: &[u8]) -> Extent {
+        Extent {
+            logical: le32(bytes) as u64,
+            length: le32(&bytes[4..]),
+            physical: le64(&bytes[8..]),
+        }
+    }
+
+    pub fn encode(&self, bytes: &mut [u8]) {
+        put32(bytes, self.logical as u32);
+        put32(&mut bytes[4..], self.length);
+        put64(&mut bytes[8..], self.physical);
+    }
+
+    /// The logical block past the extent.
+    pub fn end(&self) -> u64 {
+        self.logical + self.length as u64
+    }
+}
+
+/// The highest logical block an extent can map, past which files can't
+/// grow.
+pub const MAX_FILE_BLOCKS: u64 = u32::MAX as u64;
+
+/// Bytes in front of the name of a directory record.
+const ENTRY_HEADER: usize = 12;
+
+/// Length a directory record with a `name_len` byte name needs.
+pub fn entry_len(name_len: usize) -> usize {
+    (ENTRY_HEADER + name_len).next_multiple_of(8)
+}
+
+/// A record of a directory block. Records cover the payload of the block
+/// end to end; free space is an unused record, with inode 0, or the slack
+/// at the end of a used one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirRecord<'a> {
+    pub ino: u64,
+    pub len: usize,
+    pub kind: u8,
+    pub name: &'a [u8],
+}
+
+/// The record at `offset` of a directory block, None if it is malformed.
+pub fn dir_record(block: &[u8], offset: usize) -> Option<DirRecord<'_>> {
+    if offset + ENTRY_HEADER > PAYLOAD {
+        return None;
+    }
+    let ino = le64(&block[offset..]);
+    let len = le16(&block[offset + 8..]) as usize;
+    let name_len = block[offset + 10] as usize;
+    if len < entry_len(name_len) || !len.is_multiple_of(8) || offset + len > PAYLOAD {
+        return None;
+    }
+    Some(DirRecord {
+        ino,
+        len,
+        kind: block[offset + 11],
+        name: &block[offset + ENTRY_HEADER..offset + ENTRY_HEADER + name_len],
+    })
+}
+
+pub fn put_dir_record(
+    block: &mut [u8],
+    offset: usize,
+    ino: u64,
+    len: usize,
+    kind: u8,
+    name: &[u8],
+) {
+    put64(&mut block[offset..], ino);
+    put16(&mut block[offset + 8..], len as u16);
+    block[offset + 10] = name.len() as u8;
+    block[offset + 11] = kind;
+    block[offset + ENTRY_HEADER..offset + ENTRY_HEADER + name.len()].copy_from_slice(name);
+}
+
+pub fn set_dir_record_len(block: &mut [u8], offset: usize, len: usize) {
+    put16(&mut block[offset + 8..], len as u16);
+}
+
+/// Fills in the first block of a new directory: its `.` and `..` entries.
+pub fn init_dir_block(block: &mut [u8], ino: u64, parent: u64) {
+    block[..BLOCK_SIZE].fill(0);
+    let dot = entry_len(1);
+    put_dir_record(block, 0, ino, dot, entry_kind::DIRECTORY, b".");
+    put_dir_record(
+        block,
+        dot,
+        parent,
+        PAYLOAD - dot,
+        entry_kind::DIRECTORY,
+        b"..",
+    );
+}
+
+/// Writes an empty volume laid out as `superblock` says, a block at a time
+/// through `put`, building each in `block`. The root directory belongs to
+/// 0:0 with permissions 0755.
+pub fn format<E>(
+    superblock: &Superblock,
+    block: &mut [u8],
+    mut put: impl FnMut(u64, &[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    let root_block = superblock.data_start;
+    superblock.encode(block);
+    put(0, block)?;
+    JournalHeader { sequence: 1 }.encode(block);
+    put(superblock.journal_start, block)?;
+    // a descriptor left over from an earlier volume must not be replayed
+    block[..BLOCK_SIZE].fill(0);
+    put(superblock.journal_start + 1, block)?;
+
+    // the blocks up to the root directory's are taken
+    for n in 0..superblock.block_bitmap_blocks() {
+        block[..BLOCK_SIZE].fill(0);
+        let first = n * BITS_PER_BLOCK;
+        for index in first..(first + BITS_PER_BLOCK).min(root_block + 1) {
+            set_bit(block, (index - first) as usize, true);
+        }
+        seal(block, superblock.block_bitmap + n, kind::BITMAP);
+        put(superblock.block_bitmap + n, block)?;
+    }
+    for n in 0..superblock.inode_bitmap_blocks() {
+        block[..BLOCK_SIZE].fill(0);
+        set_bit(block, 0, n == 0);
+        seal(block, superblock.inode_bitmap + n, kind::BITMAP);
+        put(superblock.inode_bitmap + n, block)?;
+    }
+    for n in 0..superblock.inode_table_blocks() {
+        block[..BLOCK_SIZE].fill(0);
+        if n == 0 {
+            let mut root = RawInode {
+                mode: mode::DIRECTORY | 0o755,
+                links: 2,
+                size: BLOCK_SIZE as u64,
+                blocks: 1,
+                accessed: superblock.created,
+                modified: superblock.created,
+                changed: superblock.created,
+                extent_count: 1,
+                ..RawInode::EMPTY
+            };
+            let extent = Extent {
+                logical: 0,
+                length: 1,
+                physical: root_block,
+            };
+            extent.encode(&mut root.slots);
+            root.encode(block);
+        }
+        seal(block, superblock.inode_table + n, kind::INODES);
+        put(superblock.inode_table + n, block)?;
+    }
+    init_dir_block(block, ROOT_INO, ROOT_INO);
+    seal(block, root_block, kind::DIRECTORY);
+    put(root_block, block)
+}
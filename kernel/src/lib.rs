@@ -1,11 +1,58 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
 
+extern crate alloc;
+
+pub mod acpi;
+pub mod ahci;
+pub mod apic;
+pub mod ata;
+pub mod bga;
+pub mod block;
+pub mod buffer_cache;
+pub mod console;
+pub mod cursor;
+pub mod devfs;
+pub mod dmesg;
+pub mod ext2;
+pub mod fat;
+pub mod fd;
 pub mod framebuffer;
 pub mod gdt;
+pub mod heap;
+pub mod i8042;
 pub mod idt;
+pub mod initramfs;
 pub mod interrupts;
+pub mod keyboard;
+pub mod keymap;
+pub mod kfs;
+pub mod kfs_layout;
 pub mod logger;
+pub mod memory;
+pub mod mmap;
+pub mod mouse;
+pub mod msi;
+pub mod nvme;
+pub mod page_cache;
+pub mod partition;
+pub mod pci;
+pub mod pic;
+pub mod pipe;
+pub mod port;
+pub mod procfs;
+pub mod random;
+pub mod ringbuf;
+pub mod scancode;
+pub mod sync;
+pub mod task;
+pub mod time;
+pub mod tmpfs;
+pub mod uart;
+pub mod vfs;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtqueue;
 
 #[cfg(feature = "kerntest")]
 pub mod tests;
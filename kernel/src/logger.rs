@@ -1,12 +1,398 @@
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicIsize, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+
 use bootloader_api::info::FrameBufferInfo;
-use bootloader_x86_64_common::logger::LockedLogger;
 use conquer_once::spin::OnceCell;
 
-pub(crate) static LOGGER: OnceCell<LockedLogger> = OnceCell::uninit();
+use crate::console::{Console, ConsoleKind, FramebufferConsole};
+use crate::devfs::{self, DeviceNumber};
+use crate::dmesg;
+use crate::framebuffer::{FramebufferDevice, FramebufferDisplay, Position};
+use crate::sync::IrqSpinLock;
+use crate::time;
+use crate::uart::{self, LineConfig, Uart};
+use crate::vfs::FileType;
+
+pub(crate) static LOGGER: OnceCell<KernelLogger> = OnceCell::uninit();
+
+/// Records logged while the sinks are busy, e.g. from an NMI or exception
+/// that interrupted the lock holder. The holder drains them on release.
+static STAGING: StagingBuffer = StagingBuffer::new();
+
+/// Pointer movement that came in while the sinks were busy, applied along
+/// with the staged records.
+static POINTER_DX: AtomicIsize = AtomicIsize::new(0);
+static POINTER_DY: AtomicIsize = AtomicIsize::new(0);
 
-pub fn init(buffer: &'static mut [u8], info: FrameBufferInfo) {
-    let logger = LOGGER.get_or_init(move || LockedLogger::new(buffer, info, true, true));
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Sets up logging to `console` (if any) and COM1.
+pub fn init(console: Option<Console>) {
+    let kind = console.as_ref().map(Console::kind);
+    let logger = LOGGER.get_or_init(move || KernelLogger::new(console));
     log::set_logger(logger).expect("Logger already set");
     log::set_max_level(log::LevelFilter::Trace);
-    log::info!("Hello, Kernel Mode!");
+    if let Some(ConsoleKind::Framebuffer { .. }) = kind {
+        let number = DeviceNumber::new(29, 0);
+        devfs::register(
+            "fb0",
+            FileType::CharDevice,
+            number,
+            0o660,
+            Arc::new(FramebufferDevice),
+        );
+    }
+
+    let serial = logger.sinks.lock().serial.is_some();
+    match (kind, serial) {
+        (Some(console), true) => log::info!("Hello, Kernel Mode! Console: {}, COM1", console),
+        (Some(console), false) => log::info!("Hello, Kernel Mode! Console: {}", console),
+        (None, true) => log::info!("Hello, Kernel Mode! Console: serial only (COM1)"),
+        (None, false) => log::info!("Hello, Kernel Mode! Console: none"),
+    }
+}
+
+/// Switches the logger into panic mode.
+///
+/// From here on records bypass every lock and are polled out of COM1, plus the
+/// console after force-unlocking it. The CPU that panicked is the only one
+/// left running, so whoever held the sinks will never finish.
+///
+/// Records still staged go into the kernel log and onto the console, but
+/// not to COM1: the panic handler dumps the log there next.
+pub fn enter_panic_mode() {
+    crate::interrupts::disable();
+    PANICKING.store(true, Ordering::SeqCst);
+    unsafe { dmesg::force_unlock() };
+    if let Some(logger) = LOGGER.get() {
+        unsafe { logger.sinks.force_unlock() };
+        if let Some(mut sinks) = logger.sinks.try_lock() {
+            let serial = sinks.serial.take();
+            STAGING.drain(|level, timestamp, text| sinks.emit(level, timestamp, text));
+            sinks.serial = serial;
+        }
+    }
+}
+
+/// Runs `f` while holding the sink lock, as if a record were being written.
+///
+/// Anything logged from inside `f` (or from an exception raised by it) ends up
+/// in the staging buffer and is printed once the lock is released.
+pub fn with_sinks_locked<R>(f: impl FnOnce() -> R) -> R {
+    let logger = LOGGER.get().expect("logger not initialized");
+    let sinks = logger.sinks.lock();
+    let result = f();
+    drop(sinks);
+    logger.drain_staged();
+    result
+}
+
+/// Moves the console onto a new framebuffer, e.g. after a mode switch.
+///
+/// The previous console is dropped before `buffer` is first written, so both
+/// may alias the same memory.
+pub fn replace_framebuffer(buffer: &'static mut [u8], info: FrameBufferInfo) {
+    let logger = LOGGER.get().expect("logger not initialized");
+    let mut sinks = logger.sinks.lock();
+    sinks.console = None;
+    let console = FramebufferConsole::new(FramebufferDisplay::new(buffer, info));
+    sinks.console = Some(Console::Framebuffer(console));
+    drop(sinks);
+    logger.drain_staged();
+}
+
+/// Gives `f` the display behind the framebuffer console, with the sinks
+/// locked so no log output can interleave. `None` without a framebuffer.
+pub fn with_framebuffer<R>(f: impl FnOnce(&mut FramebufferDisplay<'static>) -> R) -> Option<R> {
+    let logger = LOGGER.get().expect("logger not initialized");
+    let mut sinks = logger.sinks.lock();
+    let result = match &mut sinks.console {
+        Some(Console::Framebuffer(console)) => Some(console.with_display(f)),
+        _ => None,
+    };
+    drop(sinks);
+    logger.drain_staged();
+    result
+}
+
+/// Moves the mouse pointer on the framebuffer console.
+///
+/// Safe to call from IRQ handlers: if the console is busy the movement is
+/// kept, and made by whoever holds it once they let go.
+pub fn move_pointer(dx: isize, dy: isize) {
+    POINTER_DX.fetch_add(dx, Ordering::Relaxed);
+    POINTER_DY.fetch_add(dy, Ordering::Relaxed);
+    if let Some(logger) = LOGGER.get() {
+        logger.drain_staged();
+    }
+}
+
+/// Where the mouse pointer's hotspot is, if it is on screen.
+pub fn pointer_position() -> Option<Position> {
+    let logger = LOGGER.get()?;
+    match &logger.sinks.lock().console {
+        Some(Console::Framebuffer(console)) => console.pointer_position(),
+        _ => None,
+    }
+}
+
+fn pointer_pending() -> bool {
+    POINTER_DX.load(Ordering::Relaxed) != 0 || POINTER_DY.load(Ordering::Relaxed) != 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Records that had to go through the staging buffer.
+    pub staged: usize,
+    /// Records lost because the staging buffer was full.
+    pub dropped: usize,
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        staged: STAGING.staged.load(Ordering::Relaxed),
+        dropped: STAGING.dropped.load(Ordering::Relaxed),
+    }
+}
+
+struct Sinks {
+    console: Option<Console>,
+    serial: Option<&'static Uart>,
+}
+
+impl Sinks {
+    /// Records `text` in the kernel log buffer and prints it on every console.
+    fn emit(&mut self, level: log::Level, timestamp: Duration, text: &str) {
+        dmesg::try_push(timestamp, level, text);
+        if let Some(console) = &mut self.console {
+            let _ = writeln!(console, "{:5}: {}", level, text);
+        }
+        if let Some(mut serial) = self.serial {
+            let _ = writeln!(serial, "{:5}: {}", level, text);
+        }
+    }
+}
+
+pub struct KernelLogger {
+    sinks: IrqSpinLock<Sinks>,
+}
+
+impl KernelLogger {
+    fn new(console: Option<Console>) -> Self {
+        KernelLogger {
+            sinks: IrqSpinLock::new(Sinks {
+                console,
+                serial: uart::COM1
+                    .init(LineConfig::default())
+                    .ok()
+                    .map(|_| &uart::COM1),
+            }),
+        }
+    }
+
+    /// Writes out staged records and moves the pointer as far as it has
+    /// been pushed, if nobody else holds the sinks.
+    fn drain_staged(&self) {
+        while !STAGING.is_empty() || pointer_pending() {
+            let Some(mut sinks) = self.sinks.try_lock() else {
+                // the current holder drains on release
+                return;
+            };
+            STAGING.drain(|level, timestamp, text| sinks.emit(level, timestamp, text));
+            let dx = POINTER_DX.swap(0, Ordering::Relaxed);
+            let dy = POINTER_DY.swap(0, Ordering::Relaxed);
+            if let Some(Console::Framebuffer(console)) = &mut sinks.console
+                && (dx, dy) != (0, 0)
+            {
+                console.move_pointer(dx, dy);
+            }
+        }
+    }
+
+    /// Prints straight to COM1, as the panic handler's dump of the kernel
+    /// log comes before the first record logged in panic mode.
+    fn log_panic(&self, record: &log::Record, timestamp: Duration) {
+        let mut text = [0; dmesg::MAX_TEXT_LEN];
+        dmesg::try_push(
+            timestamp,
+            record.level(),
+            format_text(&mut text, record.args()),
+        );
+
+        let mut serial = uart::COM1.panic_writer();
+        let _ = writeln!(serial, "{:5}: {}", record.level(), record.args());
+
+        unsafe { self.sinks.force_unlock() };
+        if let Some(mut sinks) = self.sinks.try_lock()
+            && let Some(console) = &mut sinks.console
+        {
+            let _ = writeln!(console, "{:5}: {}", record.level(), record.args());
+        }
+    }
+}
+
+impl log::Log for KernelLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let timestamp = time::uptime();
+        if PANICKING.load(Ordering::Relaxed) {
+            self.log_panic(record, timestamp);
+            return;
+        }
+
+        match self.sinks.try_lock() {
+            Some(mut sinks) => {
+                STAGING.drain(|level, timestamp, text| sinks.emit(level, timestamp, text));
+                let mut text = [0; dmesg::MAX_TEXT_LEN];
+                sinks.emit(
+                    record.level(),
+                    timestamp,
+                    format_text(&mut text, record.args()),
+                );
+            }
+            None => STAGING.push(record.level(), timestamp, record.args()),
+        }
+        self.drain_staged();
+    }
+
+    fn flush(&self) {}
+}
+
+const STAGING_SLOTS: usize = 16;
+const STAGING_SLOT_LEN: usize = 192;
+
+const SLOT_EMPTY: u8 = 0;
+const SLOT_WRITING: u8 = 1;
+const SLOT_READY: u8 = 2;
+
+struct StagingSlot {
+    state: AtomicU8,
+    level: AtomicU8,
+    timestamp_ns: AtomicU64,
+    len: UnsafeCell<usize>,
+    buf: UnsafeCell<[u8; STAGING_SLOT_LEN]>,
+}
+
+/// Lock-free multi-producer, single-consumer queue of formatted log lines.
+///
+/// Producers reserve a slot by bumping `head`; the consumer is whoever holds
+/// the sink lock, so there is never more than one.
+struct StagingBuffer {
+    slots: [StagingSlot; STAGING_SLOTS],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    staged: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+unsafe impl Sync for StagingBuffer {}
+
+impl StagingBuffer {
+    const fn new() -> Self {
+        StagingBuffer {
+            slots: [const {
+                StagingSlot {
+                    state: AtomicU8::new(SLOT_EMPTY),
+                    level: AtomicU8::new(0),
+                    timestamp_ns: AtomicU64::new(0),
+                    len: UnsafeCell::new(0),
+                    buf: UnsafeCell::new([0; STAGING_SLOT_LEN]),
+                }
+            }; STAGING_SLOTS],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            staged: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire) == self.tail.load(Ordering::Acquire)
+    }
+
+    fn push(&self, level: log::Level, timestamp: Duration, args: &fmt::Arguments) {
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            if head.wrapping_sub(self.tail.load(Ordering::Acquire)) >= STAGING_SLOTS {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            match self.head.compare_exchange_weak(
+                head,
+                head.wrapping_add(1),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+
+        let slot = &self.slots[head % STAGING_SLOTS];
+        slot.state.store(SLOT_WRITING, Ordering::Relaxed);
+        slot.level.store(level as u8, Ordering::Relaxed);
+        slot.timestamp_ns
+            .store(timestamp.as_nanos() as u64, Ordering::Relaxed);
+        let text = format_text(unsafe { &mut *slot.buf.get() }, args);
+        unsafe { *slot.len.get() = text.len() };
+        slot.state.store(SLOT_READY, Ordering::Release);
+        self.staged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Hands every completed record to `f` in order. Must only be called by
+    /// the sink lock holder.
+    fn drain(&self, mut f: impl FnMut(log::Level, Duration, &str)) {
+        loop {
+            let tail = self.tail.load(Ordering::Relaxed);
+            if tail == self.head.load(Ordering::Acquire) {
+                return;
+            }
+            let slot = &self.slots[tail % STAGING_SLOTS];
+            if slot.state.load(Ordering::Acquire) != SLOT_READY {
+                // a producer on another CPU is still filling it in
+                return;
+            }
+            let text = unsafe {
+                let len = *slot.len.get();
+                let buf = &*slot.buf.get();
+                core::str::from_utf8_unchecked(&buf[..len])
+            };
+            let level = dmesg::level_from_u8(slot.level.load(Ordering::Relaxed));
+            let timestamp = Duration::from_nanos(slot.timestamp_ns.load(Ordering::Relaxed));
+            f(level, timestamp, text);
+            slot.state.store(SLOT_EMPTY, Ordering::Relaxed);
+            self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        }
+    }
+}
+
+/// Formats `args` into `buf`, truncating at a character boundary.
+fn format_text<'a>(buf: &'a mut [u8], args: &fmt::Arguments) -> &'a str {
+    struct Truncating<'a> {
+        buf: &'a mut [u8],
+        len: usize,
+    }
+
+    impl Write for Truncating<'_> {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let mut take = s.len().min(self.buf.len() - self.len);
+            while !s.is_char_boundary(take) {
+                take -= 1;
+            }
+            self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+            self.len += take;
+            Ok(())
+        }
+    }
+
+    let mut writer = Truncating { buf, len: 0 };
+    let _ = writer.write_fmt(*args);
+    let len = writer.len;
+    unsafe { core::str::from_utf8_unchecked(&writer.buf[..len]) }
 }
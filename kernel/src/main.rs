@@ -1,21 +1,62 @@
 #![no_std]
 #![no_main]
 
-use bootloader_api::BootInfo;
-use core::arch::asm;
+use bootloader_api::config::Mapping;
+use bootloader_api::{BootInfo, BootloaderConfig};
+use core::fmt::Write;
 use core::panic::PanicInfo;
 
 use kernel::*;
 
-bootloader_api::entry_point!(kernel_main);
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config
+};
+
+bootloader_api::entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    let frame_buffer_optional = &mut boot_info.framebuffer;
-    let frame_buffer_option = frame_buffer_optional.as_mut();
-    let frame_buffer_struct = frame_buffer_option.unwrap();
-    let frame_buffer_info = frame_buffer_struct.info().clone();
-    let raw_frame_buffer = frame_buffer_struct.buffer_mut();
-    logger::init(raw_frame_buffer, frame_buffer_info);
+    time::init();
+    memory::init(boot_info);
+
+    let console = console::detect(boot_info);
+    logger::init(console);
+    task::init();
+
+    interrupts::init();
+    uart::init();
+    match i8042::init() {
+        Ok(()) => {
+            if let Err(err) = keyboard::init() {
+                log::warn!("PS/2 keyboard: {}", err);
+            }
+            if i8042::is_available(i8042::Ps2Port::Second)
+                && let Err(err) = mouse::init()
+            {
+                log::warn!("PS/2 mouse: {}", err);
+            }
+        }
+        Err(err) => log::warn!("i8042: {}", err),
+    }
+    interrupts::enable();
+
+    initramfs::init(boot_info);
+    tmpfs::init();
+    devfs::init();
+    procfs::init();
+
+    if let Err(err) = acpi::init(boot_info) {
+        log::warn!("ACPI: {}", err);
+    }
+    pci::init();
+    ata::init();
+    ahci::init();
+    nvme::init();
+    virtio_blk::init();
+    block::init();
+
+    bga::init();
 
     #[cfg(feature = "kerntest")]
     {
@@ -23,21 +64,21 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         tests::run_all();
     }
 
-    interrupts::init();
-
-    int3();
+    interrupts::int3();
 
     loop {}
 }
 
-pub fn int3() {
-    unsafe {
-        asm!("int3", options(nomem, nostack));
-    }
-}
-
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
+    logger::enter_panic_mode();
+
+    // the panic itself comes last, once, and whole even if the log would
+    // cut it short
+    let mut serial = uart::COM1.panic_writer();
+    let _ = writeln!(serial, "---- kernel log ----");
+    let _ = dmesg::print_all(&mut serial);
+    let _ = writeln!(serial, "---- end of kernel log ----");
     log::error!("{}", _info);
     loop {}
 }
@@ -0,0 +1,374 @@
+use core::arch::asm;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader_api::BootInfo;
+use bootloader_api::info::MemoryRegionKind;
+
+use crate::sync::IrqSpinLock;
+
+pub const FRAME_SIZE: u64 = 4096;
+
+/// Virtual address at which the bootloader mapped all of physical memory
+/// (at least the first 4 GiB, so MMIO below that is reachable as well).
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// End of the physical memory mapping.
+static MAPPED_PHYS_END: AtomicU64 = AtomicU64::new(1 << 32);
+
+pub fn init(boot_info: &BootInfo) {
+    let offset = boot_info
+        .physical_memory_offset
+        .into_option()
+        .expect("bootloader did not map physical memory");
+    PHYSICAL_MEMORY_OFFSET.store(offset, Ordering::Relaxed);
+
+    let mut frames = FRAMES.lock();
+    let mut mapped_end = 1 << 32;
+    for region in boot_info.memory_regions.iter() {
+        mapped_end = mapped_end.max(region.end);
+        if region.kind == MemoryRegionKind::Usable {
+            frames.add_region(region.start, region.end);
+        }
+    }
+    MAPPED_PHYS_END.store(mapped_end, Ordering::Relaxed);
+}
+
+pub fn physical_memory_offset() -> u64 {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Translates a physical address into its alias in the physical memory mapping.
+pub fn phys_to_virt(phys: u64) -> *mut u8 {
+    let offset = physical_memory_offset();
+    assert!(offset != 0, "memory::init has not run");
+    (offset + phys) as *mut u8
+}
+
+/// Returns a mutable view of `len` bytes of physical memory.
+///
+/// # Safety
+///
+/// The range must be mapped and nobody else may access it through another
+/// reference for as long as the returned slice lives.
+pub unsafe fn phys_slice_mut(phys: u64, len: usize) -> &'static mut [u8] {
+    unsafe { core::slice::from_raw_parts_mut(phys_to_virt(phys), len) }
+}
+
+// Physical frames are tracked in a bitmap, one bit per 4 KiB frame, set while
+// the frame is free, so that the bitmap starts out zeroed in .bss. Only memory below MAX_PHYS is managed; that is plenty
+// for the machines the kernel runs on and keeps the bitmap static.
+
+const MAX_PHYS: u64 = 8 << 30;
+const MAX_FRAMES: usize = (MAX_PHYS / FRAME_SIZE) as usize;
+
+struct FrameBitmap {
+    available: [u64; MAX_FRAMES / 64],
+    total: usize,
+    free: usize,
+    /// Where the next search starts.
+    hint: usize,
+}
+
+static FRAMES: IrqSpinLock<FrameBitmap> = IrqSpinLock::new(FrameBitmap {
+    available: [0; MAX_FRAMES / 64],
+    total: 0,
+    free: 0,
+    hint: 0,
+});
+
+impl FrameBitmap {
+    fn is_used(&self, frame: usize) -> bool {
+        self.available[frame / 64] & 1 << (frame % 64) == 0
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        match used {
+            true => self.available[frame / 64] &= !(1 << (frame % 64)),
+            false => self.available[frame / 64] |= 1 << (frame % 64),
+        }
+    }
+
+    fn add_region(&mut self, start: u64, end: u64) {
+        // frame 0 stays reserved so that 0 never looks like a valid frame
+        let first = start.div_ceil(FRAME_SIZE).max(1) as usize;
+        let last = (end.min(MAX_PHYS) / FRAME_SIZE) as usize;
+        for frame in first..last {
+            if self.is_used(frame) {
+                self.set_used(frame, false);
+                self.total += 1;
+                self.free += 1;
+            }
+        }
+    }
+
+    fn allocate(&mut self, count: usize) -> Option<usize> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+        let mut start = self.hint;
+        let mut wrapped = false;
+        loop {
+            if start + count > MAX_FRAMES {
+                if wrapped {
+                    return None;
+                }
+                (start, wrapped) = (0, true);
+            }
+            if wrapped && start >= self.hint {
+                return None;
+            }
+            match (start..start + count).find(|frame| self.is_used(*frame)) {
+                Some(used) => start = used + 1,
+                None => break,
+            }
+        }
+        for frame in start..start + count {
+            self.set_used(frame, true);
+        }
+        self.free -= count;
+        self.hint = start + count;
+        Some(start)
+    }
+
+    fn deallocate(&mut self, first: usize, count: usize) {
+        for frame in first..first + count {
+            assert!(self.is_used(frame), "double free of frame {frame:#x}");
+            self.set_used(frame, false);
+        }
+        self.free += count;
+    }
+}
+
+/// Allocates `count` physically contiguous, zeroed frames and returns the
+/// physical address of the first.
+pub fn allocate_frames(count: usize) -> Option<u64> {
+    let first = FRAMES.lock().allocate(count)?;
+    let phys = first as u64 * FRAME_SIZE;
+    unsafe { phys_to_virt(phys).write_bytes(0, count * FRAME_SIZE as usize) };
+    Some(phys)
+}
+
+pub fn allocate_frame() -> Option<u64> {
+    allocate_frames(1)
+}
+
+/// Returns frames from [`allocate_frames`].
+pub fn free_frames(phys: u64, count: usize) {
+    assert!(phys.is_multiple_of(FRAME_SIZE), "unaligned frame {phys:#x}");
+    FRAMES
+        .lock()
+        .deallocate((phys / FRAME_SIZE) as usize, count);
+}
+
+/// Zeroed, physically contiguous frames for device transfers, returned to
+/// the allocator on drop.
+pub struct DmaBuffer {
+    phys: u64,
+    len: usize,
+}
+
+impl DmaBuffer {
+    /// Allocates at least `len` bytes, rounded up to whole frames.
+    pub fn new(len: usize) -> Option<DmaBuffer> {
+        let len = len.max(1).next_multiple_of(FRAME_SIZE as usize);
+        let phys = allocate_frames(len / FRAME_SIZE as usize)?;
+        Some(DmaBuffer { phys, len })
+    }
+
+    pub fn phys(&self) -> u64 {
+        self.phys
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Deref for DmaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(phys_to_virt(self.phys), self.len) }
+    }
+}
+
+impl DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { phys_slice_mut(self.phys, self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        free_frames(self.phys, self.len / FRAME_SIZE as usize);
+    }
+}
+
+/// Usable RAM in bytes.
+pub fn total_memory() -> u64 {
+    FRAMES.lock().total as u64 * FRAME_SIZE
+}
+
+/// RAM not handed out by the frame allocator, in bytes.
+pub fn free_memory() -> u64 {
+    FRAMES.lock().free as u64 * FRAME_SIZE
+}
+
+// MMIO past the end of the physical memory mapping, like 64-bit BARs placed
+// high up, gets uncached 4 KiB mappings in a window of its own: an unused
+// top-level page table slot. Other windows, such as the one of file
+// mappings, take further slots the same way. Page tables made for a window
+// are never freed.
+
+mod page {
+    pub const PRESENT: u64 = 1 << 0;
+    pub const WRITABLE: u64 = 1 << 1;
+    pub const WRITE_THROUGH: u64 = 1 << 3;
+    pub const NO_CACHE: u64 = 1 << 4;
+    pub const NO_EXECUTE: u64 = 1 << 63;
+    pub const ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+}
+
+/// Bytes covered by one top-level entry.
+pub const PML4_ENTRY_SPAN: u64 = 1 << 39;
+
+struct MmioWindow {
+    /// Next free virtual address, or 0 before the window is set up.
+    next: u64,
+    end: u64,
+}
+
+static MMIO_WINDOW: IrqSpinLock<MmioWindow> = IrqSpinLock::new(MmioWindow { next: 0, end: 0 });
+
+fn page_table(phys: u64) -> *mut u64 {
+    phys_to_virt(phys & page::ADDRESS_MASK).cast()
+}
+
+fn pml4_phys() -> u64 {
+    let cr3: u64;
+    unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) };
+    cr3 & page::ADDRESS_MASK
+}
+
+fn canonical(address: u64) -> u64 {
+    ((address << 16) as i64 >> 16) as u64
+}
+
+static WINDOWS: IrqSpinLock<()> = IrqSpinLock::new(());
+
+/// Takes an unused top-level slot of the page tables for a window of
+/// [`PML4_ENTRY_SPAN`] bytes and returns its start. The slot gets its table
+/// at once, so that it no longer looks unused.
+pub fn reserve_window() -> Option<u64> {
+    let _windows = WINDOWS.lock();
+    let pml4 = page_table(pml4_phys());
+    // the upper half keeps clear of anything identity mapped
+    let slot = (256..512).find(|slot| unsafe { *pml4.add(*slot) } & page::PRESENT == 0)?;
+    let table = allocate_frame()?;
+    unsafe { *pml4.add(slot) = table | page::PRESENT | page::WRITABLE };
+    Some(canonical(slot as u64 * PML4_ENTRY_SPAN))
+}
+
+impl MmioWindow {
+    fn reserve(&mut self, pages: u64) -> Option<u64> {
+        if self.next == 0 {
+            self.next = reserve_window()?;
+            self.end = self.next + PML4_ENTRY_SPAN;
+        }
+        let start = self.next;
+        let end = start.checked_add(pages * FRAME_SIZE)?;
+        if end > self.end {
+            return None;
+        }
+        self.next = end;
+        Some(start)
+    }
+}
+
+/// The last-level entry of `virt`, creating intermediate tables as needed
+/// if `create` is set.
+fn page_entry(virt: u64, create: bool) -> Option<&'static mut u64> {
+    let mut table = page_table(pml4_phys());
+    for level in [39, 30, 21] {
+        let entry = unsafe { &mut *table.add((virt >> level) as usize & 511) };
+        if *entry & page::PRESENT == 0 {
+            if !create {
+                return None;
+            }
+            *entry = allocate_frame()? | page::PRESENT | page::WRITABLE;
+        }
+        table = page_table(*entry);
+    }
+    Some(unsafe { &mut *table.add((virt >> 12) as usize & 511) })
+}
+
+fn set_page_entry(virt: u64, entry: &mut u64, value: u64) {
+    *entry = value;
+    unsafe { asm!("invlpg [{}]", in(reg) virt, options(nostack, preserves_flags)) };
+}
+
+/// Maps the 4 KiB page at `virt` to the frame at `phys`, never executable.
+pub fn map_page(virt: u64, phys: u64, writable: bool) -> Option<()> {
+    let entry = page_entry(virt, true)?;
+    let write = if writable { page::WRITABLE } else { 0 };
+    set_page_entry(virt, entry, phys | page::PRESENT | write | page::NO_EXECUTE);
+    Some(())
+}
+
+/// Makes a page mapped with [`map_page`] writable or read-only.
+pub fn protect_page(virt: u64, writable: bool) {
+    if let Some(entry) = page_entry(virt, false)
+        && *entry & page::PRESENT != 0
+    {
+        let value = match writable {
+            true => *entry | page::WRITABLE,
+            false => *entry & !page::WRITABLE,
+        };
+        set_page_entry(virt, entry, value);
+    }
+}
+
+/// Removes the mapping of the page at `virt` and returns the frame it was
+/// mapped to.
+pub fn unmap_page(virt: u64) -> Option<u64> {
+    let entry = page_entry(virt, false)?;
+    if *entry & page::PRESENT == 0 {
+        return None;
+    }
+    let phys = *entry & page::ADDRESS_MASK;
+    set_page_entry(virt, entry, 0);
+    Some(phys)
+}
+
+/// Maps one uncached 4 KiB page of MMIO.
+fn map_mmio_page(virt: u64, phys: u64) -> Option<()> {
+    let entry = page_entry(virt, true)?;
+    let flags =
+        page::PRESENT | page::WRITABLE | page::WRITE_THROUGH | page::NO_CACHE | page::NO_EXECUTE;
+    set_page_entry(virt, entry, phys | flags);
+    Some(())
+}
+
+/// Makes `len` bytes of MMIO at `phys` accessible and returns their address.
+///
+/// Regions inside the physical memory mapping just get their alias there;
+/// anything beyond gets a fresh mapping that is never torn down.
+pub fn map_mmio(phys: u64, len: u64) -> Option<*mut u8> {
+    let end = phys.checked_add(len)?;
+    if end <= MAPPED_PHYS_END.load(Ordering::Relaxed) {
+        return Some(phys_to_virt(phys));
+    }
+    let first = phys & !(FRAME_SIZE - 1);
+    let pages = (end - first).div_ceil(FRAME_SIZE);
+    let mut window = MMIO_WINDOW.lock();
+    let virt = window.reserve(pages)?;
+    for page in 0..pages {
+        let offset = page * FRAME_SIZE;
+        map_mmio_page(virt + offset, first + offset)?;
+    }
+    Some((virt + (phys - first)) as *mut u8)
+}
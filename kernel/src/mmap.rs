@@ -0,0 +1,264 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memory::{self, PML4_ENTRY_SPAN};
+use crate::page_cache::{PAGE_SIZE, Page};
+use crate::sync::SpinLock;
+use crate::vfs::{self, Dentry, FileType, Inode, VfsError};
+
+// Files mapped into the kernel's address space. Mappings take their
+// addresses from a window of their own, a guard page apart, and start out
+// with no pages at all: the first access to a page faults, and the fault
+// handler maps the file's page from the page cache.
+//
+// A shared mapping maps that very page, so it sees the file's reads and
+// writes and they see its changes. The page is mapped read-only until the
+// first write, which marks it dirty for writeback. A private mapping maps
+// the page read-only as well, and the first write copies it; from then on
+// the copy no longer follows the file, as on Linux.
+//
+// Touching a page past the end of the file is an error, like the SIGBUS
+// Linux sends. A file truncated under a mapping leaves it the pages it
+// already has.
+
+/// What a mapping may be used for. Pages are always readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Whether writes reach the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sharing {
+    Shared,
+    /// Written pages are copied.
+    Private,
+}
+
+enum Mapped {
+    /// The file's page, read-only unless shared and written.
+    File(Arc<Page>),
+    /// The frame of a private copy.
+    Copy(u64),
+}
+
+struct Region {
+    id: u64,
+    pages: usize,
+    inode: Arc<dyn Inode>,
+    /// Page of the file the mapping starts at.
+    first: u64,
+    access: Access,
+    sharing: Sharing,
+    /// Pages faulted in, by page number in the mapping.
+    mapped: BTreeMap<usize, Mapped>,
+}
+
+struct Regions {
+    /// Start of the window, or 0 before the first mapping.
+    base: u64,
+    by_start: BTreeMap<u64, Region>,
+}
+
+impl Regions {
+    /// First-fit search for room for `pages` pages and a guard page.
+    fn find_room(&mut self, pages: usize) -> Option<u64> {
+        if self.base == 0 {
+            self.base = memory::reserve_window()?;
+        }
+        let need = (pages as u64 + 1) * PAGE_SIZE as u64;
+        let mut start = self.base;
+        for (region_start, region) in &self.by_start {
+            if region_start - start >= need {
+                break;
+            }
+            start = region_start + (region.pages as u64 + 1) * PAGE_SIZE as u64;
+        }
+        (start + need <= self.base + PML4_ENTRY_SPAN).then_some(start)
+    }
+}
+
+static REGIONS: SpinLock<Regions> = SpinLock::new(Regions {
+    base: 0,
+    by_start: BTreeMap::new(),
+});
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A file mapped into memory, unmapped on drop.
+pub struct Mapping {
+    start: u64,
+    len: usize,
+    inode: Arc<dyn Inode>,
+}
+
+impl Mapping {
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.start as *mut u8
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Writes back what has been written through the mapping, like
+    /// `msync`. The file's other dirty pages go along.
+    pub fn sync(&self) -> vfs::Result<()> {
+        self.inode.write_back()
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let Some(region) = REGIONS.lock().by_start.remove(&self.start) else {
+            return;
+        };
+        for (n, mapped) in region.mapped {
+            let virt = self.start + (n * PAGE_SIZE) as u64;
+            memory::unmap_page(virt);
+            match mapped {
+                Mapped::File(page) => page.unmapped(virt),
+                Mapped::Copy(frame) => memory::free_frames(frame, 1),
+            }
+        }
+    }
+}
+
+/// Maps `len` bytes of the regular file at `dentry` from byte `offset` on,
+/// which has to be page aligned. The first page is read in at once, if the
+/// file reaches it; that also tells whether its filesystem can map files.
+pub fn map(
+    dentry: &Arc<Dentry>,
+    offset: u64,
+    len: usize,
+    access: Access,
+    sharing: Sharing,
+) -> vfs::Result<Mapping> {
+    match dentry.kind() {
+        FileType::Regular => {}
+        FileType::Directory => return Err(VfsError::IsDirectory),
+        _ => return Err(VfsError::Unsupported),
+    }
+    if len == 0 || !offset.is_multiple_of(PAGE_SIZE as u64) {
+        return Err(VfsError::InvalidArgument);
+    }
+    if access == Access::ReadWrite && sharing == Sharing::Shared && dentry.filesystem().read_only()
+    {
+        return Err(VfsError::ReadOnly);
+    }
+    let inode = dentry.inode().clone();
+    let first = offset / PAGE_SIZE as u64;
+    if inode.metadata()?.size > offset {
+        inode.page(first)?;
+    }
+
+    let pages = len.div_ceil(PAGE_SIZE);
+    let mut regions = REGIONS.lock();
+    let start = regions.find_room(pages).ok_or(VfsError::OutOfMemory)?;
+    let region = Region {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        pages,
+        inode: inode.clone(),
+        first,
+        access,
+        sharing,
+        mapped: BTreeMap::new(),
+    };
+    regions.by_start.insert(start, region);
+    Ok(Mapping { start, len, inode })
+}
+
+/// A private copy of `page`, mapped writable at `virt`.
+fn map_copy(virt: u64, page: &Page) -> vfs::Result<Mapped> {
+    let frame = memory::allocate_frame().ok_or(VfsError::OutOfMemory)?;
+    page.read(0, unsafe { memory::phys_slice_mut(frame, PAGE_SIZE) });
+    if memory::map_page(virt, frame, true).is_none() {
+        memory::free_frames(frame, 1);
+        return Err(VfsError::OutOfMemory);
+    }
+    Ok(Mapped::Copy(frame))
+}
+
+/// Handles a page fault at `address`, a write if `write` is set. Returns
+/// false if the address is not in a mapping or the access is not allowed
+/// there.
+pub fn handle_fault(address: u64, write: bool) -> bool {
+    match fault(address & !(PAGE_SIZE as u64 - 1), write) {
+        Ok(handled) => handled,
+        Err(err) => {
+            log::error!("mmap: cannot fault in {:#x}: {}", address, err);
+            false
+        }
+    }
+}
+
+fn fault(virt: u64, write: bool) -> vfs::Result<bool> {
+    let (start, id, n, inode, index) = {
+        let mut regions = REGIONS.lock();
+        let Some((&start, region)) = regions.by_start.range_mut(..=virt).next_back() else {
+            return Ok(false);
+        };
+        let n = ((virt - start) / PAGE_SIZE as u64) as usize;
+        if n >= region.pages || (write && region.access == Access::ReadOnly) {
+            return Ok(false);
+        }
+        match region.mapped.get(&n) {
+            None => {}
+            // a write to a page mapped read-only
+            Some(Mapped::File(page)) if write => {
+                match region.sharing {
+                    Sharing::Shared => {
+                        page.mapped_writable(virt);
+                        memory::protect_page(virt, true);
+                    }
+                    Sharing::Private => {
+                        let copy = map_copy(virt, page)?;
+                        region.mapped.insert(n, copy);
+                    }
+                }
+                return Ok(true);
+            }
+            // faulted in meanwhile
+            Some(_) => return Ok(true),
+        }
+        let index = region.first + n as u64;
+        (start, region.id, n, region.inode.clone(), index)
+    };
+
+    // reading the page in may take filesystem locks and wait for the disk
+    if index * PAGE_SIZE as u64 >= inode.metadata()?.size {
+        log::error!("mmap: {:#x} is past the end of the file", virt);
+        return Ok(false);
+    }
+    let page = inode.page(index)?;
+
+    let mut regions = REGIONS.lock();
+    let Some(region) = regions
+        .by_start
+        .get_mut(&start)
+        .filter(|region| region.id == id)
+    else {
+        // unmapped meanwhile; the access faults again and fails
+        return Ok(true);
+    };
+    if region.mapped.contains_key(&n) {
+        return Ok(true);
+    }
+    let mapped = match (write, region.sharing) {
+        (true, Sharing::Private) => map_copy(virt, &page)?,
+        (writable, _) => {
+            memory::map_page(virt, page.frame(), writable).ok_or(VfsError::OutOfMemory)?;
+            if writable {
+                page.mapped_writable(virt);
+            }
+            Mapped::File(page)
+        }
+    };
+    region.mapped.insert(n, mapped);
+    Ok(true)
+}
@@ -0,0 +1,379 @@
+use core::fmt;
+use core::future::Future;
+use core::ops::BitOr;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use crate::i8042::{self, I8042Error, Ps2Port, response};
+use crate::interrupts;
+use crate::logger;
+use crate::ringbuf::RingBuffer;
+use crate::sync::{IrqSpinLock, WakerSlot};
+use crate::time;
+
+// PS/2 mouse on the second i8042 port. The IRQ handler assembles packets,
+// queues one event per packet and moves the pointer on the framebuffer
+// console.
+
+const PORT: Ps2Port = Ps2Port::Second;
+
+mod cmd {
+    pub const SET_SAMPLE_RATE: u8 = 0xF3;
+    pub const GET_DEVICE_ID: u8 = 0xF2;
+    pub const ENABLE_REPORTING: u8 = 0xF4;
+    pub const SET_DEFAULTS: u8 = 0xF6;
+    pub const RESET: u8 = 0xFF;
+}
+
+mod flags {
+    pub const LEFT: u8 = 1 << 0;
+    pub const RIGHT: u8 = 1 << 1;
+    pub const MIDDLE: u8 = 1 << 2;
+    /// Set in the first byte of every packet.
+    pub const ALWAYS_ONE: u8 = 1 << 3;
+    pub const X_SIGN: u8 = 1 << 4;
+    pub const Y_SIGN: u8 = 1 << 5;
+    pub const X_OVERFLOW: u8 = 1 << 6;
+    pub const Y_OVERFLOW: u8 = 1 << 7;
+}
+
+/// Fourth packet byte of a five-button mouse.
+mod extra {
+    pub const WHEEL: u8 = 0x0F;
+    pub const BUTTON4: u8 = 1 << 4;
+    pub const BUTTON5: u8 = 1 << 5;
+}
+
+/// Sample rate sequences that unlock the IntelliMouse extensions.
+const WHEEL_KNOCK: [u8; 3] = [200, 100, 80];
+const FIVE_BUTTON_KNOCK: [u8; 3] = [200, 200, 80];
+const SAMPLE_RATE: u8 = 100;
+
+/// A partial packet older than this is assumed to have lost a byte.
+const PACKET_TIMEOUT: Duration = Duration::from_millis(25);
+
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+const REPLY_TIMEOUT: Duration = Duration::from_millis(50);
+
+const EVENT_BUFFER_SIZE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKind {
+    /// Three buttons, no wheel.
+    Standard,
+    /// IntelliMouse: adds a scroll wheel.
+    Wheel,
+    /// IntelliMouse Explorer: adds a wheel and buttons 4 and 5.
+    FiveButton,
+}
+
+impl MouseKind {
+    fn from_id(id: u8) -> MouseKind {
+        match id {
+            3 => MouseKind::Wheel,
+            4 => MouseKind::FiveButton,
+            _ => MouseKind::Standard,
+        }
+    }
+
+    pub fn packet_len(self) -> usize {
+        match self {
+            MouseKind::Standard => 3,
+            MouseKind::Wheel | MouseKind::FiveButton => 4,
+        }
+    }
+}
+
+impl fmt::Display for MouseKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            MouseKind::Standard => "3 buttons",
+            MouseKind::Wheel => "3 buttons and wheel",
+            MouseKind::FiveButton => "5 buttons and wheel",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons(u8);
+
+impl MouseButtons {
+    pub const NONE: MouseButtons = MouseButtons(0);
+    pub const LEFT: MouseButtons = MouseButtons(1 << 0);
+    pub const RIGHT: MouseButtons = MouseButtons(1 << 1);
+    pub const MIDDLE: MouseButtons = MouseButtons(1 << 2);
+    pub const BUTTON4: MouseButtons = MouseButtons(1 << 3);
+    pub const BUTTON5: MouseButtons = MouseButtons(1 << 4);
+
+    pub const fn contains(self, other: MouseButtons) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    fn set(&mut self, other: MouseButtons, on: bool) {
+        if on {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+
+    fn intersection(self, other: MouseButtons) -> MouseButtons {
+        MouseButtons(self.0 & other.0)
+    }
+}
+
+impl BitOr for MouseButtons {
+    type Output = MouseButtons;
+
+    fn bitor(self, other: MouseButtons) -> MouseButtons {
+        MouseButtons(self.0 | other.0)
+    }
+}
+
+/// One packet's worth of input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Movement to the right.
+    pub dx: i16,
+    /// Movement downwards, i.e. in screen direction.
+    pub dy: i16,
+    /// Wheel clicks, positive when scrolling down.
+    pub wheel: i8,
+    /// Buttons held after this packet.
+    pub buttons: MouseButtons,
+    /// Buttons that went up or down with this packet.
+    pub changed: MouseButtons,
+}
+
+impl MouseEvent {
+    pub fn pressed(&self) -> MouseButtons {
+        self.buttons.intersection(self.changed)
+    }
+
+    pub fn released(&self) -> MouseButtons {
+        MouseButtons(!self.buttons.0).intersection(self.changed)
+    }
+}
+
+/// Collects packet bytes and turns complete packets into events.
+pub struct PacketAssembler {
+    kind: MouseKind,
+    bytes: [u8; 4],
+    len: usize,
+    last_byte: Duration,
+    buttons: MouseButtons,
+}
+
+impl PacketAssembler {
+    pub const fn new(kind: MouseKind) -> Self {
+        PacketAssembler {
+            kind,
+            bytes: [0; 4],
+            len: 0,
+            last_byte: Duration::ZERO,
+            buttons: MouseButtons::NONE,
+        }
+    }
+
+    pub fn kind(&self) -> MouseKind {
+        self.kind
+    }
+
+    /// Adds a byte received at `now`.
+    ///
+    /// Resynchronizes after lost bytes by dropping stale partial packets
+    /// and bytes that cannot start a packet.
+    pub fn feed(&mut self, byte: u8, now: Duration) -> Option<MouseEvent> {
+        if self.len > 0 && now.saturating_sub(self.last_byte) > PACKET_TIMEOUT {
+            self.len = 0;
+        }
+        self.last_byte = now;
+        if self.len == 0 && byte & flags::ALWAYS_ONE == 0 {
+            return None;
+        }
+
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.kind.packet_len() {
+            return None;
+        }
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&mut self) -> MouseEvent {
+        let [status, x, y, fourth] = self.bytes;
+
+        let mut buttons = MouseButtons::NONE;
+        buttons.set(MouseButtons::LEFT, status & flags::LEFT != 0);
+        buttons.set(MouseButtons::RIGHT, status & flags::RIGHT != 0);
+        buttons.set(MouseButtons::MIDDLE, status & flags::MIDDLE != 0);
+
+        let wheel = match self.kind {
+            MouseKind::Standard => 0,
+            MouseKind::Wheel => fourth as i8,
+            MouseKind::FiveButton => {
+                buttons.set(MouseButtons::BUTTON4, fourth & extra::BUTTON4 != 0);
+                buttons.set(MouseButtons::BUTTON5, fourth & extra::BUTTON5 != 0);
+                // sign-extend the low nibble
+                ((fourth & extra::WHEEL) << 4) as i8 >> 4
+            }
+        };
+
+        // movement is 9-bit two's complement; overflowed values are garbage
+        let delta = |low: u8, sign: u8, overflow: u8| match status & overflow {
+            0 => low as i16 - if status & sign != 0 { 256 } else { 0 },
+            _ => 0,
+        };
+        let dx = delta(x, flags::X_SIGN, flags::X_OVERFLOW);
+        let dy = -delta(y, flags::Y_SIGN, flags::Y_OVERFLOW);
+
+        let changed = MouseButtons(buttons.0 ^ self.buttons.0);
+        self.buttons = buttons;
+        MouseEvent {
+            dx,
+            dy,
+            wheel,
+            buttons,
+            changed,
+        }
+    }
+}
+
+struct State {
+    assembler: PacketAssembler,
+}
+
+static STATE: IrqSpinLock<State> = IrqSpinLock::new(State {
+    assembler: PacketAssembler::new(MouseKind::Standard),
+});
+
+static EVENTS: RingBuffer<MouseEvent, EVENT_BUFFER_SIZE> = RingBuffer::new();
+static WAKER: WakerSlot = WakerSlot::new();
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static IRQ_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// Resets the mouse, enables as many IntelliMouse extensions as it supports
+/// and starts interrupt-driven reporting. Needs [`i8042::init`] to have
+/// succeeded.
+pub fn init() -> Result<MouseKind, I8042Error> {
+    i8042::command(PORT, cmd::RESET)?;
+    match i8042::read(PORT, RESET_TIMEOUT)? {
+        response::SELF_TEST_PASSED => {}
+        code => return Err(I8042Error::DeviceSelfTestFailed(PORT, code)),
+    }
+    // device ID, always 0 after a reset
+    i8042::read(PORT, REPLY_TIMEOUT)?;
+    i8042::command(PORT, cmd::SET_DEFAULTS)?;
+
+    let mut kind = MouseKind::Standard;
+    set_sample_rates(&WHEEL_KNOCK)?;
+    if MouseKind::from_id(device_id()?) == MouseKind::Wheel {
+        kind = MouseKind::Wheel;
+        set_sample_rates(&FIVE_BUTTON_KNOCK)?;
+        if MouseKind::from_id(device_id()?) == MouseKind::FiveButton {
+            kind = MouseKind::FiveButton;
+        }
+    }
+    set_sample_rates(&[SAMPLE_RATE])?;
+
+    {
+        let mut state = STATE.lock();
+        state.assembler = PacketAssembler::new(kind);
+    }
+    EVENTS.clear();
+    i8042::command(PORT, cmd::ENABLE_REPORTING)?;
+
+    if !IRQ_REGISTERED.swap(true, Ordering::AcqRel) {
+        interrupts::register_irq(PORT.irq(), irq_handler);
+    }
+    i8042::enable_irq(PORT)?;
+    log::info!("PS/2 mouse: {}", kind);
+    Ok(kind)
+}
+
+fn set_sample_rates(rates: &[u8]) -> Result<(), I8042Error> {
+    for &rate in rates {
+        i8042::command(PORT, cmd::SET_SAMPLE_RATE)?;
+        i8042::command(PORT, rate)?;
+    }
+    Ok(())
+}
+
+fn device_id() -> Result<u8, I8042Error> {
+    i8042::command(PORT, cmd::GET_DEVICE_ID)?;
+    i8042::read(PORT, REPLY_TIMEOUT)
+}
+
+fn irq_handler() {
+    let mut queued = false;
+    while let Some(byte) = i8042::receive(PORT) {
+        let Some(event) = STATE.lock().assembler.feed(byte, time::uptime()) else {
+            continue;
+        };
+        if (event.dx, event.dy) != (0, 0) {
+            logger::move_pointer(event.dx as isize, event.dy as isize);
+        }
+
+        if EVENTS.push(event).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+        queued = true;
+    }
+    if queued {
+        WAKER.wake();
+    }
+}
+
+/// Events thrown away because the queue was full.
+pub fn dropped_events() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+// There must only be one consumer of the event queue at a time.
+
+/// Returns the next mouse event without blocking.
+pub fn read_event() -> Option<MouseEvent> {
+    EVENTS.pop()
+}
+
+/// Waits for the next mouse event, halting between interrupts.
+pub fn read_event_blocking() -> MouseEvent {
+    loop {
+        if let Some(event) = read_event() {
+            return event;
+        }
+        interrupts::enable_and_wait();
+    }
+}
+
+/// Resolves to the next mouse event.
+pub fn next_event() -> NextEvent {
+    NextEvent { _private: () }
+}
+
+pub struct NextEvent {
+    _private: (),
+}
+
+impl Future for NextEvent {
+    type Output = MouseEvent;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<MouseEvent> {
+        if let Some(event) = read_event() {
+            return Poll::Ready(event);
+        }
+        WAKER.register(cx.waker());
+        match read_event() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+}
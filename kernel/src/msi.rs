@@ -0,0 +1,464 @@
+use core::fmt;
+
+use crate::apic;
+use crate::interrupts::{self, VectorHandler};
+use crate::memory;
+use crate::pci::{self, PciAddress, PciDevice};
+
+// Message signalled interrupts are plain memory writes from the device to
+// the local APIC's address window, so they bypass the PICs and INTx routing
+// entirely. MSI gives a function up to 32 vectors in one aligned block and
+// lives in config space; MSI-X gives up to 2048 independently programmed and
+// maskable vectors through a table in one of the device's memory BARs.
+
+mod msi_reg {
+    pub const CONTROL: u16 = 0x02;
+    pub const ADDRESS_LOW: u16 = 0x04;
+    pub const ADDRESS_HIGH: u16 = 0x08;
+    pub const DATA_32: u16 = 0x08;
+    pub const DATA_64: u16 = 0x0C;
+    pub const MASK_32: u16 = 0x0C;
+    pub const MASK_64: u16 = 0x10;
+
+    pub const ENABLE: u16 = 1 << 0;
+    pub const MULTIPLE_CAPABLE_SHIFT: u16 = 1;
+    pub const MULTIPLE_ENABLE_SHIFT: u16 = 4;
+    pub const MULTIPLE_MASK: u16 = 0b111;
+    pub const ADDRESS_64: u16 = 1 << 7;
+    pub const PER_VECTOR_MASKING: u16 = 1 << 8;
+}
+
+mod msix_reg {
+    pub const CONTROL: u16 = 0x02;
+    pub const TABLE: u16 = 0x04;
+    pub const PBA: u16 = 0x08;
+
+    pub const TABLE_SIZE_MASK: u16 = 0x7FF;
+    pub const FUNCTION_MASK: u16 = 1 << 14;
+    pub const ENABLE: u16 = 1 << 15;
+    pub const BIR_MASK: u32 = 0b111;
+
+    pub const ENTRY_SIZE: usize = 16;
+    pub const ENTRY_ADDRESS_LOW: usize = 0x0;
+    pub const ENTRY_ADDRESS_HIGH: usize = 0x4;
+    pub const ENTRY_DATA: usize = 0x8;
+    pub const ENTRY_VECTOR_CONTROL: usize = 0xC;
+    pub const ENTRY_MASKED: u32 = 1 << 0;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The function has neither an MSI nor an MSI-X capability.
+    NotSupported,
+    /// Asked for zero vectors or more than the function can signal.
+    TooManyVectors(usize),
+    /// No free block of IDT vectors was left.
+    NoFreeVectors,
+    /// The MSI-X table is not inside a reachable memory BAR.
+    BadTable,
+}
+
+impl fmt::Display for MsiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MsiError::NotSupported => write!(f, "device supports neither MSI nor MSI-X"),
+            MsiError::TooManyVectors(count) => write!(f, "cannot signal {} vectors", count),
+            MsiError::NoFreeVectors => write!(f, "out of interrupt vectors"),
+            MsiError::BadTable => write!(f, "MSI-X table outside any mapped memory BAR"),
+        }
+    }
+}
+
+/// Parsed MSI capability.
+#[derive(Debug, Clone, Copy)]
+pub struct MsiCapability {
+    pub offset: u16,
+    /// Number of vectors the function can signal, a power of two up to 32.
+    pub max_vectors: usize,
+    pub is_64bit: bool,
+    pub per_vector_masking: bool,
+}
+
+impl MsiCapability {
+    pub fn read(address: PciAddress) -> Option<MsiCapability> {
+        let offset = address.find_capability(pci::capability::MSI)?;
+        let control = address.read_u16(offset + msi_reg::CONTROL);
+        let capable = (control >> msi_reg::MULTIPLE_CAPABLE_SHIFT) & msi_reg::MULTIPLE_MASK;
+        Some(MsiCapability {
+            offset,
+            max_vectors: 1 << capable.min(5),
+            is_64bit: control & msi_reg::ADDRESS_64 != 0,
+            per_vector_masking: control & msi_reg::PER_VECTOR_MASKING != 0,
+        })
+    }
+
+    fn data_offset(&self) -> u16 {
+        self.offset
+            + match self.is_64bit {
+                true => msi_reg::DATA_64,
+                false => msi_reg::DATA_32,
+            }
+    }
+
+    fn mask_offset(&self) -> u16 {
+        self.offset
+            + match self.is_64bit {
+                true => msi_reg::MASK_64,
+                false => msi_reg::MASK_32,
+            }
+    }
+}
+
+/// Parsed MSI-X capability.
+#[derive(Debug, Clone, Copy)]
+pub struct MsixCapability {
+    pub offset: u16,
+    pub table_size: usize,
+    pub table_bar: u8,
+    pub table_offset: u32,
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+impl MsixCapability {
+    pub fn read(address: PciAddress) -> Option<MsixCapability> {
+        let offset = address.find_capability(pci::capability::MSIX)?;
+        let control = address.read_u16(offset + msix_reg::CONTROL);
+        let table = address.read_u32(offset + msix_reg::TABLE);
+        let pba = address.read_u32(offset + msix_reg::PBA);
+        Some(MsixCapability {
+            offset,
+            table_size: (control & msix_reg::TABLE_SIZE_MASK) as usize + 1,
+            table_bar: (table & msix_reg::BIR_MASK) as u8,
+            table_offset: table & !msix_reg::BIR_MASK,
+            pba_bar: (pba & msix_reg::BIR_MASK) as u8,
+            pba_offset: pba & !msix_reg::BIR_MASK,
+        })
+    }
+
+    /// Maps the vector table, if it lies inside a memory BAR.
+    fn map_table(&self, device: &PciDevice) -> Option<*mut u32> {
+        let bar = device.bar(self.table_bar as usize)?;
+        let len = (self.table_size * msix_reg::ENTRY_SIZE) as u64;
+        if self.table_offset as u64 + len > bar.size() {
+            return None;
+        }
+        let table = memory::map_mmio(bar.memory_base()? + self.table_offset as u64, len)?;
+        Some(table.cast())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiKind {
+    Msi,
+    MsiX,
+}
+
+impl fmt::Display for MsiKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MsiKind::Msi => write!(f, "MSI"),
+            MsiKind::MsiX => write!(f, "MSI-X"),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Mode {
+    Msi(MsiCapability),
+    MsiX(MsixCapability, *mut u32),
+}
+
+/// A block of IDT vectors wired to one PCI function through MSI or MSI-X.
+///
+/// Vector `i` is signalled by MSI message `i` or MSI-X table entry `i`.
+/// Dropping it turns message signalled interrupts off again and frees the
+/// vectors.
+#[derive(Debug)]
+pub struct MsiVectors {
+    address: PciAddress,
+    mode: Mode,
+    first_vector: u8,
+    count: usize,
+    /// Vectors taken from the IDT, which for MSI is `count` rounded up to a
+    /// power of two.
+    reserved: usize,
+}
+
+// The MSI-X table pointer is MMIO that only this value touches.
+unsafe impl Send for MsiVectors {}
+
+/// Gives `device` `count` interrupt vectors, preferring MSI-X over MSI.
+///
+/// Turns on bus mastering, which message writes need, and turns INTx off.
+/// MSI-X entries start out masked; MSI vectors are live right away unless
+/// the function supports per-vector masking. Either way a vector only does
+/// something once a handler is set with [`MsiVectors::set_handler`].
+pub fn allocate(device: &PciDevice, count: usize) -> Result<MsiVectors, MsiError> {
+    if let Some(capability) = MsixCapability::read(device.address) {
+        return allocate_msix(device, capability, count);
+    }
+    if let Some(capability) = MsiCapability::read(device.address) {
+        return allocate_msi(device, capability, count);
+    }
+    Err(MsiError::NotSupported)
+}
+
+fn allocate_msi(
+    device: &PciDevice,
+    capability: MsiCapability,
+    count: usize,
+) -> Result<MsiVectors, MsiError> {
+    if count == 0 || count > capability.max_vectors {
+        return Err(MsiError::TooManyVectors(count));
+    }
+    let reserved = count.next_power_of_two();
+    let first_vector =
+        interrupts::allocate_vectors(reserved, reserved).ok_or(MsiError::NoFreeVectors)?;
+
+    let address = device.address;
+    let control_offset = capability.offset + msi_reg::CONTROL;
+    let mut control = address.read_u16(control_offset) & !msi_reg::ENABLE;
+    address.write_u16(control_offset, control);
+
+    let (message_address, data) = apic::msi_message(apic::id(), first_vector);
+    address.write_u32(
+        capability.offset + msi_reg::ADDRESS_LOW,
+        message_address as u32,
+    );
+    if capability.is_64bit {
+        address.write_u32(
+            capability.offset + msi_reg::ADDRESS_HIGH,
+            (message_address >> 32) as u32,
+        );
+    }
+    address.write_u16(capability.data_offset(), data as u16);
+    if capability.per_vector_masking {
+        address.write_u32(capability.mask_offset(), u32::MAX);
+    }
+
+    control &= !(msi_reg::MULTIPLE_MASK << msi_reg::MULTIPLE_ENABLE_SHIFT);
+    control |= (reserved.trailing_zeros() as u16) << msi_reg::MULTIPLE_ENABLE_SHIFT;
+    address.write_u16(control_offset, control | msi_reg::ENABLE);
+    address.enable(pci::command::BUS_MASTER | pci::command::INTX_DISABLE);
+
+    log::info!(
+        "PCI {}: {} MSI vector(s) at {:#x}",
+        address,
+        count,
+        first_vector
+    );
+    Ok(MsiVectors {
+        address,
+        mode: Mode::Msi(capability),
+        first_vector,
+        count,
+        reserved,
+    })
+}
+
+fn allocate_msix(
+    device: &PciDevice,
+    capability: MsixCapability,
+    count: usize,
+) -> Result<MsiVectors, MsiError> {
+    if count == 0 || count > capability.table_size {
+        return Err(MsiError::TooManyVectors(count));
+    }
+    let table = capability.map_table(device).ok_or(MsiError::BadTable)?;
+    let first_vector = interrupts::allocate_vectors(count, 1).ok_or(MsiError::NoFreeVectors)?;
+
+    let address = device.address;
+    let control_offset = capability.offset + msix_reg::CONTROL;
+    let control = address.read_u16(control_offset);
+    // the table is only writable with MSI-X enabled; the function mask keeps
+    // it quiet meanwhile
+    address.write_u16(
+        control_offset,
+        control | msix_reg::ENABLE | msix_reg::FUNCTION_MASK,
+    );
+    address.enable(pci::command::MEMORY_SPACE);
+
+    let vectors = MsiVectors {
+        address,
+        mode: Mode::MsiX(capability, table),
+        first_vector,
+        count,
+        reserved: count,
+    };
+    let destination = apic::id();
+    for index in 0..capability.table_size {
+        vectors.set_entry_masked(index, true);
+    }
+    for index in 0..count {
+        vectors.write_message(index, destination);
+    }
+
+    address.write_u16(
+        control_offset,
+        (control | msix_reg::ENABLE) & !msix_reg::FUNCTION_MASK,
+    );
+    address.enable(pci::command::BUS_MASTER | pci::command::INTX_DISABLE);
+
+    log::info!(
+        "PCI {}: {} of {} MSI-X vector(s) at {:#x}",
+        address,
+        count,
+        capability.table_size,
+        first_vector
+    );
+    Ok(vectors)
+}
+
+impl MsiVectors {
+    pub fn kind(&self) -> MsiKind {
+        match self.mode {
+            Mode::Msi(_) => MsiKind::Msi,
+            Mode::MsiX(..) => MsiKind::MsiX,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// IDT vector that message `index` arrives on.
+    pub fn vector(&self, index: usize) -> u8 {
+        assert!(index < self.count, "MSI vector {index} out of range");
+        self.first_vector + index as u8
+    }
+
+    /// Routes message `index` to `handler`, which is called with `context`.
+    pub fn set_handler(&self, index: usize, handler: VectorHandler, context: usize) {
+        interrupts::set_vector_handler(self.vector(index), handler, context);
+    }
+
+    /// Sends MSI-X message `index` to the CPU with APIC ID `destination`
+    /// instead of the one that allocated it. Plain MSI shares one address
+    /// between all messages and can't be split up; returns false then.
+    pub fn set_destination(&self, index: usize, destination: u8) -> bool {
+        assert!(index < self.count, "MSI vector {index} out of range");
+        match self.mode {
+            Mode::Msi(_) => false,
+            Mode::MsiX(..) => {
+                let masked = self.is_masked(index);
+                self.set_masked(index, true);
+                self.write_message(index, destination);
+                self.set_masked(index, masked);
+                true
+            }
+        }
+    }
+
+    fn write_message(&self, index: usize, destination: u8) {
+        let (message_address, data) = apic::msi_message(destination, self.vector(index));
+        self.write_entry(index, msix_reg::ENTRY_ADDRESS_LOW, message_address as u32);
+        self.write_entry(
+            index,
+            msix_reg::ENTRY_ADDRESS_HIGH,
+            (message_address >> 32) as u32,
+        );
+        self.write_entry(index, msix_reg::ENTRY_DATA, data);
+    }
+
+    /// Whether [`mask`](Self::mask) works on individual vectors. Plain MSI
+    /// without per-vector masking cannot mask at all.
+    pub fn can_mask(&self) -> bool {
+        match self.mode {
+            Mode::Msi(capability) => capability.per_vector_masking,
+            Mode::MsiX(..) => true,
+        }
+    }
+
+    /// Stops the device from signalling message `index`. Events that come up
+    /// meanwhile are kept pending and delivered on [`unmask`](Self::unmask).
+    pub fn mask(&self, index: usize) {
+        self.set_masked(index, true);
+    }
+
+    pub fn unmask(&self, index: usize) {
+        self.set_masked(index, false);
+    }
+
+    pub fn unmask_all(&self) {
+        for index in 0..self.count {
+            self.unmask(index);
+        }
+    }
+
+    pub fn is_masked(&self, index: usize) -> bool {
+        assert!(index < self.count, "MSI vector {index} out of range");
+        match self.mode {
+            Mode::Msi(capability) if capability.per_vector_masking => {
+                self.address.read_u32(capability.mask_offset()) & 1 << index != 0
+            }
+            Mode::Msi(_) => false,
+            Mode::MsiX(..) => {
+                self.read_entry(index, msix_reg::ENTRY_VECTOR_CONTROL) & msix_reg::ENTRY_MASKED != 0
+            }
+        }
+    }
+
+    fn set_masked(&self, index: usize, masked: bool) {
+        assert!(index < self.count, "MSI vector {index} out of range");
+        match self.mode {
+            Mode::Msi(capability) if capability.per_vector_masking => {
+                let mask = self.address.read_u32(capability.mask_offset());
+                let mask = match masked {
+                    true => mask | 1 << index,
+                    false => mask & !(1 << index),
+                };
+                self.address.write_u32(capability.mask_offset(), mask);
+            }
+            Mode::Msi(_) => {}
+            Mode::MsiX(..) => self.set_entry_masked(index, masked),
+        }
+    }
+
+    fn entry(&self, index: usize, field: usize) -> *mut u32 {
+        let Mode::MsiX(capability, table) = self.mode else {
+            unreachable!("not an MSI-X allocation");
+        };
+        assert!(index < capability.table_size);
+        unsafe { table.add((index * msix_reg::ENTRY_SIZE + field) / 4) }
+    }
+
+    fn read_entry(&self, index: usize, field: usize) -> u32 {
+        unsafe { self.entry(index, field).read_volatile() }
+    }
+
+    fn write_entry(&self, index: usize, field: usize, value: u32) {
+        unsafe { self.entry(index, field).write_volatile(value) }
+    }
+
+    fn set_entry_masked(&self, index: usize, masked: bool) {
+        let control = self.read_entry(index, msix_reg::ENTRY_VECTOR_CONTROL);
+        let control = match masked {
+            true => control | msix_reg::ENTRY_MASKED,
+            false => control & !msix_reg::ENTRY_MASKED,
+        };
+        self.write_entry(index, msix_reg::ENTRY_VECTOR_CONTROL, control);
+    }
+}
+
+impl Drop for MsiVectors {
+    fn drop(&mut self) {
+        match self.mode {
+            Mode::Msi(capability) => {
+                let offset = capability.offset + msi_reg::CONTROL;
+                let control = self.address.read_u16(offset);
+                self.address.write_u16(offset, control & !msi_reg::ENABLE);
+            }
+            Mode::MsiX(capability, _) => {
+                for index in 0..self.count {
+                    self.set_entry_masked(index, true);
+                }
+                let offset = capability.offset + msix_reg::CONTROL;
+                let control = self.address.read_u16(offset);
+                self.address.write_u16(offset, control & !msix_reg::ENABLE);
+            }
+        }
+        self.address.disable(pci::command::INTX_DISABLE);
+        interrupts::free_vectors(self.first_vector, self.reserved);
+    }
+}
@@ -0,0 +1,969 @@
+use core::fmt;
+use core::time::Duration;
+
+use crate::apic;
+use crate::ata::trimmed;
+use crate::block::{self, BlockDevice, BlockError, Bounce};
+use crate::memory::{self, FRAME_SIZE};
+use crate::msi::{self, MsiVectors};
+use crate::pci::{self, PciDevice};
+use crate::sync::{self, IrqSpinLock};
+use crate::time;
+
+// An NVMe controller takes commands through pairs of rings in host memory: a
+// submission queue the driver fills and a completion queue the controller
+// posts to, each with a doorbell register for the index the other side has
+// to learn about. Completions carry a phase bit that flips on every pass
+// through the ring, so new entries are told from stale ones without a
+// shared counter. Queue pair 0 is the admin queue that sets up the rest.
+
+/// Programming interface of NVM Express controllers.
+const PROG_IF_NVME: u8 = 0x02;
+
+mod reg {
+    pub const CAP: usize = 0x00;
+    pub const VS: usize = 0x08;
+    pub const CC: usize = 0x14;
+    pub const CSTS: usize = 0x1C;
+    pub const AQA: usize = 0x24;
+    pub const ASQ: usize = 0x28;
+    pub const ACQ: usize = 0x30;
+    pub const DOORBELLS: usize = 0x1000;
+}
+
+mod cap {
+    pub const MQES_MASK: u64 = 0xFFFF;
+    pub const TO_SHIFT: u32 = 24;
+    pub const DSTRD_SHIFT: u32 = 32;
+    pub const CSS_NVM: u64 = 1 << 37;
+    pub const MPSMIN_SHIFT: u32 = 48;
+}
+
+mod cc {
+    pub const EN: u32 = 1 << 0;
+    /// 64-byte submission entries.
+    pub const IOSQES: u32 = 6 << 16;
+    /// 16-byte completion entries.
+    pub const IOCQES: u32 = 4 << 20;
+}
+
+mod csts {
+    pub const RDY: u32 = 1 << 0;
+    pub const CFS: u32 = 1 << 1;
+}
+
+mod admin_opcode {
+    pub const DELETE_SQ: u8 = 0x00;
+    pub const CREATE_SQ: u8 = 0x01;
+    pub const CREATE_CQ: u8 = 0x05;
+    pub const IDENTIFY: u8 = 0x06;
+    pub const ABORT: u8 = 0x08;
+    pub const SET_FEATURES: u8 = 0x09;
+}
+
+mod io_opcode {
+    pub const FLUSH: u8 = 0x00;
+    pub const WRITE: u8 = 0x01;
+    pub const READ: u8 = 0x02;
+}
+
+mod identify {
+    pub const NAMESPACE: u32 = 0x00;
+    pub const CONTROLLER: u32 = 0x01;
+    pub const ACTIVE_NAMESPACES: u32 = 0x02;
+}
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+/// Create queue flags: physically contiguous, interrupts enabled.
+const QUEUE_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS: u32 = 1 << 1;
+/// Generic status of commands ended by deleting their submission queue.
+const STATUS_SQ_DELETED: u16 = 0x08;
+
+const PAGE_SIZE: usize = FRAME_SIZE as usize;
+const SUBMISSION_ENTRY_SIZE: usize = 64;
+const COMPLETION_ENTRY_SIZE: usize = 16;
+const QUEUE_ENTRIES: usize = 32;
+/// One entry stays empty so a full ring differs from an empty one.
+const MAX_COMMANDS: usize = QUEUE_ENTRIES - 1;
+const PRP_ENTRIES: usize = PAGE_SIZE / 8;
+const MAX_IO_QUEUES: usize = 16;
+const MAX_NAMESPACES: usize = 8;
+/// Largest transfer the blocking helpers put into one command.
+const MAX_TRANSFER: usize = 128 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeError {
+    /// The command completed with this status field.
+    Command(u16),
+    /// The controller reported a fatal status.
+    Fatal,
+    Timeout,
+    OutOfRange,
+    /// The queue has no free command identifier.
+    Busy,
+    OutOfMemory,
+}
+
+impl fmt::Display for NvmeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NvmeError::Command(status) => write!(
+                f,
+                "command failed, type {} code {:#04x}",
+                (status >> 8) & 0x7,
+                status & 0xFF
+            ),
+            NvmeError::Fatal => write!(f, "controller fatal status"),
+            NvmeError::Timeout => write!(f, "command timed out"),
+            NvmeError::OutOfRange => write!(f, "block out of range"),
+            NvmeError::Busy => write!(f, "queue full"),
+            NvmeError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+/// A request on a physically contiguous, dword aligned buffer; `lba` and
+/// `len` are in the namespace's blocks and bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvmeOp {
+    Read { lba: u64, phys: u64, len: u32 },
+    Write { lba: u64, phys: u64, len: u32 },
+    Flush,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerInfo {
+    pub model: [u8; 40],
+    pub serial: [u8; 20],
+    pub firmware: [u8; 8],
+    /// Largest transfer of a single command in bytes.
+    pub max_transfer: usize,
+    pub io_queues: usize,
+    pub volatile_write_cache: bool,
+}
+
+impl ControllerInfo {
+    pub fn model(&self) -> &str {
+        trimmed(&self.model)
+    }
+
+    pub fn serial(&self) -> &str {
+        trimmed(&self.serial)
+    }
+
+    pub fn firmware(&self) -> &str {
+        trimmed(&self.firmware)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NamespaceInfo {
+    pub nsid: u32,
+    pub blocks: u64,
+    pub block_size: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Registers {
+    base: *mut u8,
+}
+
+// The pointer is MMIO; registers are only touched under the queue locks or
+// during init.
+unsafe impl Send for Registers {}
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { self.base.add(offset).cast::<u32>().read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { self.base.add(offset).cast::<u32>().write_volatile(value) }
+    }
+
+    fn read_u64(&self, offset: usize) -> u64 {
+        self.read(offset) as u64 | (self.read(offset + 4) as u64) << 32
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+
+    fn wait_status(&self, mask: u32, value: u32, timeout: Duration) -> Result<(), NvmeError> {
+        let deadline = time::uptime() + timeout;
+        loop {
+            let status = self.read(reg::CSTS);
+            if status & csts::CFS != 0 {
+                return Err(NvmeError::Fatal);
+            }
+            if status & mask == value {
+                return Ok(());
+            }
+            if time::uptime() > deadline {
+                return Err(NvmeError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+}
+
+type Command = [u32; 16];
+
+fn command(opcode: u8, nsid: u32) -> Command {
+    let mut command = [0; 16];
+    command[0] = opcode as u32;
+    command[1] = nsid;
+    command
+}
+
+struct Queue {
+    sq_phys: u64,
+    cq_phys: u64,
+    /// One PRP list page per command identifier.
+    prp_phys: u64,
+    sq_tail: usize,
+    cq_head: usize,
+    phase: bool,
+    sq_doorbell: *mut u32,
+    cq_doorbell: *mut u32,
+    /// Command identifiers submitted or waiting to be collected.
+    busy: u32,
+    done: u32,
+    status: [u16; MAX_COMMANDS],
+    result: [u32; MAX_COMMANDS],
+}
+
+unsafe impl Send for Queue {}
+
+static ADMIN: IrqSpinLock<Option<Queue>> = IrqSpinLock::new(None);
+static IO_QUEUES: [IrqSpinLock<Option<Queue>>; MAX_IO_QUEUES] =
+    [const { IrqSpinLock::new(None) }; MAX_IO_QUEUES];
+static CONTROLLER: IrqSpinLock<Option<Controller>> = IrqSpinLock::new(None);
+static NAMESPACES: IrqSpinLock<[Option<NamespaceInfo>; MAX_NAMESPACES]> =
+    IrqSpinLock::new([None; MAX_NAMESPACES]);
+/// Keeps the MSI-X vectors alive; vector 0 belongs to the admin queue.
+static VECTORS: IrqSpinLock<Option<MsiVectors>> = IrqSpinLock::new(None);
+
+#[derive(Debug, Clone, Copy)]
+struct Controller {
+    regs: Registers,
+    info: ControllerInfo,
+    /// APIC ID of the CPU each I/O queue belongs to.
+    queue_cpus: [u8; MAX_IO_QUEUES],
+    interrupts: bool,
+    /// Disabled after it stopped answering; every command fails.
+    failed: bool,
+}
+
+impl Queue {
+    fn new(regs: Registers, id: usize, stride: usize) -> Option<Queue> {
+        let sq_phys = memory::allocate_frame()?;
+        let Some(cq_phys) = memory::allocate_frame() else {
+            memory::free_frames(sq_phys, 1);
+            return None;
+        };
+        let Some(prp_phys) = memory::allocate_frames(MAX_COMMANDS) else {
+            memory::free_frames(sq_phys, 1);
+            memory::free_frames(cq_phys, 1);
+            return None;
+        };
+        let doorbell =
+            |index: usize| unsafe { regs.base.add(reg::DOORBELLS + index * stride).cast::<u32>() };
+        Some(Queue {
+            sq_phys,
+            cq_phys,
+            prp_phys,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+            sq_doorbell: doorbell(2 * id),
+            cq_doorbell: doorbell(2 * id + 1),
+            busy: 0,
+            done: 0,
+            status: [0; MAX_COMMANDS],
+            result: [0; MAX_COMMANDS],
+        })
+    }
+
+    fn release(self) {
+        memory::free_frames(self.sq_phys, 1);
+        memory::free_frames(self.cq_phys, 1);
+        memory::free_frames(self.prp_phys, MAX_COMMANDS);
+    }
+
+    /// Points the command's PRP entries at `len` bytes from `phys`; more
+    /// than two pages go through the identifier's PRP list.
+    fn set_prps(&self, cid: usize, command: &mut Command, phys: u64, len: usize) {
+        let page = PAGE_SIZE as u64;
+        let first = (page - phys % page) as usize;
+        let second = (phys & !(page - 1)) + page;
+        let prp2 = if len <= first {
+            0
+        } else if len - first <= PAGE_SIZE {
+            second
+        } else {
+            let list = self.prp_phys + (cid * PAGE_SIZE) as u64;
+            let entries = (len - first).div_ceil(PAGE_SIZE);
+            let slots = unsafe { memory::phys_slice_mut(list, PAGE_SIZE) };
+            for (i, slot) in slots.as_chunks_mut::<8>().0[..entries]
+                .iter_mut()
+                .enumerate()
+            {
+                *slot = (second + i as u64 * page).to_le_bytes();
+            }
+            list
+        };
+        command[6] = phys as u32;
+        command[7] = (phys >> 32) as u32;
+        command[8] = prp2 as u32;
+        command[9] = (prp2 >> 32) as u32;
+    }
+
+    fn submit(
+        &mut self,
+        mut command: Command,
+        data: Option<(u64, usize)>,
+    ) -> Result<usize, NvmeError> {
+        let cid = (0..MAX_COMMANDS)
+            .find(|cid| self.busy & (1 << cid) == 0)
+            .ok_or(NvmeError::Busy)?;
+        command[0] |= (cid as u32) << 16;
+        if let Some((phys, len)) = data {
+            self.set_prps(cid, &mut command, phys, len);
+        }
+        let entry =
+            memory::phys_to_virt(self.sq_phys + (self.sq_tail * SUBMISSION_ENTRY_SIZE) as u64);
+        unsafe {
+            let entry = entry.cast::<u32>();
+            for (i, dword) in command.iter().enumerate() {
+                entry.add(i).write_volatile(*dword);
+            }
+        }
+        self.busy |= 1 << cid;
+        self.sq_tail = (self.sq_tail + 1) % QUEUE_ENTRIES;
+        unsafe { self.sq_doorbell.write_volatile(self.sq_tail as u32) };
+        Ok(cid)
+    }
+
+    /// Consumes posted completions and moves their identifiers to `done`.
+    fn update(&mut self) {
+        let mut consumed = false;
+        loop {
+            let entry =
+                memory::phys_to_virt(self.cq_phys + (self.cq_head * COMPLETION_ENTRY_SIZE) as u64)
+                    .cast::<u32>();
+            let last = unsafe { entry.add(3).read_volatile() };
+            if (last & (1 << 16) != 0) != self.phase {
+                break;
+            }
+            let cid = (last & 0xFFFF) as usize;
+            if cid < MAX_COMMANDS && self.busy & (1 << cid) != 0 {
+                self.status[cid] = (last >> 17) as u16;
+                self.result[cid] = unsafe { entry.read_volatile() };
+                self.done |= 1 << cid;
+            }
+            self.cq_head += 1;
+            if self.cq_head == QUEUE_ENTRIES {
+                self.cq_head = 0;
+                self.phase = !self.phase;
+            }
+            consumed = true;
+        }
+        if consumed {
+            unsafe { self.cq_doorbell.write_volatile(self.cq_head as u32) };
+        }
+    }
+
+    /// Takes the result of a completed command: its first completion dword.
+    fn collect(&mut self, cid: usize) -> Option<Result<u32, NvmeError>> {
+        let bit = 1 << cid;
+        if self.done & bit == 0 {
+            return None;
+        }
+        self.done &= !bit;
+        self.busy &= !bit;
+        Some(match self.status[cid] {
+            0 => Ok(self.result[cid]),
+            status => Err(NvmeError::Command(status)),
+        })
+    }
+
+    /// Once the submission queue is gone: picks up the completions it
+    /// posted and fails the commands that never got one.
+    fn drop_submissions(&mut self) {
+        self.update();
+        for cid in 0..MAX_COMMANDS {
+            let bit = 1 << cid;
+            if self.busy & !self.done & bit != 0 {
+                self.status[cid] = STATUS_SQ_DELETED;
+                self.done |= bit;
+            }
+        }
+        self.sq_tail = 0;
+    }
+}
+
+/// Runs an admin command to completion by polling.
+fn admin(command: Command, data: Option<(u64, usize)>) -> Result<u32, NvmeError> {
+    let cid = ADMIN
+        .lock()
+        .as_mut()
+        .expect("NVMe admin queue not set up")
+        .submit(command, data)?;
+    let deadline = time::uptime() + TIMEOUT;
+    loop {
+        let mut admin = ADMIN.lock();
+        let queue = admin.as_mut().unwrap();
+        queue.update();
+        if let Some(result) = queue.collect(cid) {
+            return result;
+        }
+        if time::uptime() > deadline {
+            return Err(NvmeError::Timeout);
+        }
+        drop(admin);
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs an Identify command into a fresh page, handing it to `parse`.
+fn identify<T>(cns: u32, nsid: u32, parse: impl FnOnce(&[u8]) -> T) -> Result<T, NvmeError> {
+    let page = memory::allocate_frame().ok_or(NvmeError::OutOfMemory)?;
+    let mut command = command(admin_opcode::IDENTIFY, nsid);
+    command[10] = cns;
+    let result = admin(command, Some((page, PAGE_SIZE)))
+        .map(|_| parse(unsafe { memory::phys_slice_mut(page, PAGE_SIZE) }));
+    memory::free_frames(page, 1);
+    result
+}
+
+fn queue_lock(id: usize) -> &'static IrqSpinLock<Option<Queue>> {
+    match id {
+        0 => &ADMIN,
+        id => &IO_QUEUES[id - 1],
+    }
+}
+
+fn handle_interrupt(queue: usize) {
+    if let Some(queue) = queue_lock(queue).lock().as_mut() {
+        queue.update();
+    }
+}
+
+fn enable(regs: Registers, timeout: Duration, stride: usize) -> Result<(), NvmeError> {
+    if regs.read(reg::CC) & cc::EN != 0 {
+        regs.write(reg::CC, regs.read(reg::CC) & !cc::EN);
+    }
+    regs.wait_status(csts::RDY, 0, timeout)?;
+
+    let admin = Queue::new(regs, 0, stride).ok_or(NvmeError::OutOfMemory)?;
+    let size = QUEUE_ENTRIES as u32 - 1;
+    regs.write(reg::AQA, size | size << 16);
+    regs.write_u64(reg::ASQ, admin.sq_phys);
+    regs.write_u64(reg::ACQ, admin.cq_phys);
+    *ADMIN.lock() = Some(admin);
+
+    regs.write(reg::CC, cc::EN | cc::IOSQES | cc::IOCQES);
+    regs.wait_status(csts::RDY, csts::RDY, timeout)
+}
+
+fn identify_controller() -> Result<ControllerInfo, NvmeError> {
+    identify(identify::CONTROLLER, 0, |data| {
+        // MDTS is a power of two in units of the minimum page size; one PRP
+        // list page caps what we can describe anyway
+        let prp_limit = PAGE_SIZE * PRP_ENTRIES;
+        let max_transfer = match data[77] as u32 {
+            0 => prp_limit,
+            mdts => PAGE_SIZE
+                .checked_shl(mdts)
+                .unwrap_or(usize::MAX)
+                .min(prp_limit),
+        };
+        ControllerInfo {
+            serial: data[4..24].try_into().unwrap(),
+            model: data[24..64].try_into().unwrap(),
+            firmware: data[64..72].try_into().unwrap(),
+            max_transfer,
+            io_queues: 0,
+            volatile_write_cache: data[525] & 1 != 0,
+        }
+    })
+}
+
+/// Creates I/O queue pair `id`, completing on interrupt vector `vector` if
+/// there is one.
+fn create_io_queue(
+    regs: Registers,
+    id: usize,
+    stride: usize,
+    vector: Option<u32>,
+) -> Result<(), NvmeError> {
+    let queue = Queue::new(regs, id, stride).ok_or(NvmeError::OutOfMemory)?;
+    let size = (QUEUE_ENTRIES as u32 - 1) << 16;
+
+    let mut create_cq = command(admin_opcode::CREATE_CQ, 0);
+    create_cq[6] = queue.cq_phys as u32;
+    create_cq[7] = (queue.cq_phys >> 32) as u32;
+    create_cq[10] = size | id as u32;
+    create_cq[11] = match vector {
+        Some(vector) => QUEUE_CONTIGUOUS | QUEUE_INTERRUPTS | vector << 16,
+        None => QUEUE_CONTIGUOUS,
+    };
+    match admin(create_cq, None).and_then(|_| admin(create_sq(&queue, id), None)) {
+        Ok(_) => {
+            *IO_QUEUES[id - 1].lock() = Some(queue);
+            Ok(())
+        }
+        Err(err) => {
+            queue.release();
+            Err(err)
+        }
+    }
+}
+
+/// Creates the submission queue of I/O queue pair `id` on `queue`'s ring.
+fn create_sq(queue: &Queue, id: usize) -> Command {
+    let mut create_sq = command(admin_opcode::CREATE_SQ, 0);
+    create_sq[6] = queue.sq_phys as u32;
+    create_sq[7] = (queue.sq_phys >> 32) as u32;
+    create_sq[10] = (QUEUE_ENTRIES as u32 - 1) << 16 | id as u32;
+    create_sq[11] = QUEUE_CONTIGUOUS | (id as u32) << 16;
+    create_sq
+}
+
+/// Takes back `cid` after it timed out on I/O queue `id`, so its buffer
+/// can be reused. Abort asks the controller to end the command early; if
+/// it doesn't, the submission queue is deleted, which ends every command
+/// on it, and created again. A controller that doesn't answer that either
+/// is disabled.
+fn abort(id: usize, cid: usize) {
+    let mut abort = command(admin_opcode::ABORT, 0);
+    abort[10] = id as u32 | (cid as u32) << 16;
+    if let Err(err) = admin(abort, None) {
+        log::warn!("NVMe queue {}: abort of command {}: {}", id, cid, err);
+    }
+    let deadline = time::uptime() + TIMEOUT;
+    while time::uptime() < deadline {
+        if let Some(queue) = queue_lock(id).lock().as_mut() {
+            queue.update();
+            if queue.collect(cid).is_some() {
+                return;
+            }
+        }
+        core::hint::spin_loop();
+    }
+
+    let mut lock = queue_lock(id).lock();
+    let queue = lock.as_mut().expect("NVMe queue vanished");
+    let mut delete_sq = command(admin_opcode::DELETE_SQ, 0);
+    delete_sq[10] = id as u32;
+    let deleted = admin(delete_sq, None);
+    if deleted.is_ok() {
+        queue.drop_submissions();
+    }
+    if let Err(err) = deleted.and_then(|_| admin(create_sq(queue, id), None)) {
+        log::error!("NVMe queue {}: cannot reset, disabling: {}", id, err);
+        let mut controller = CONTROLLER.lock();
+        let controller = controller.as_mut().expect("NVMe not initialised");
+        let regs = controller.regs;
+        regs.write(reg::CC, regs.read(reg::CC) & !cc::EN);
+        if let Err(err) = regs.wait_status(csts::RDY, 0, TIMEOUT) {
+            log::error!("NVMe: controller won't stop: {}", err);
+        }
+        controller.failed = true;
+        queue.drop_submissions();
+    }
+    queue.collect(cid);
+}
+
+/// Asks for `wanted` I/O queue pairs; the controller may grant fewer.
+fn negotiate_queues(wanted: usize) -> Result<usize, NvmeError> {
+    let mut set_features = command(admin_opcode::SET_FEATURES, 0);
+    set_features[10] = FEATURE_NUMBER_OF_QUEUES;
+    set_features[11] = (wanted as u32 - 1) | (wanted as u32 - 1) << 16;
+    let granted = admin(set_features, None)?;
+    let submission = (granted & 0xFFFF) as usize + 1;
+    let completion = (granted >> 16) as usize + 1;
+    Ok(wanted.min(submission).min(completion))
+}
+
+fn scan_namespaces() -> Result<(), NvmeError> {
+    let mut ids = [0u32; MAX_NAMESPACES];
+    identify(identify::ACTIVE_NAMESPACES, 0, |data| {
+        for (id, bytes) in ids.iter_mut().zip(data.as_chunks::<4>().0) {
+            *id = u32::from_le_bytes(*bytes);
+        }
+    })?;
+    let mut namespaces = [None; MAX_NAMESPACES];
+    for (slot, nsid) in namespaces
+        .iter_mut()
+        .zip(ids.into_iter().take_while(|id| *id != 0))
+    {
+        let (info, metadata) = identify(identify::NAMESPACE, nsid, |data| {
+            let blocks = u64::from_le_bytes(data[0..8].try_into().unwrap());
+            let format = (data[26] & 0xF) as usize;
+            let lbaf = u32::from_le_bytes(data[128 + 4 * format..][..4].try_into().unwrap());
+            let info = NamespaceInfo {
+                nsid,
+                blocks,
+                block_size: 1 << ((lbaf >> 16) & 0xFF),
+            };
+            (info, lbaf & 0xFFFF)
+        })?;
+        if metadata != 0 {
+            log::warn!("NVMe namespace {}: metadata not supported", nsid);
+            continue;
+        }
+        log::info!(
+            "NVMe namespace {}: {} blocks of {} bytes ({} MiB)",
+            nsid,
+            info.blocks,
+            info.block_size,
+            (info.blocks * info.block_size as u64) >> 20
+        );
+        *slot = Some(info);
+    }
+    *NAMESPACES.lock() = namespaces;
+    Ok(())
+}
+
+fn init_controller(device: PciDevice) -> Result<(), NvmeError> {
+    let bar = device.bar(0).ok_or(NvmeError::Fatal)?;
+    let base = bar.memory_base().ok_or(NvmeError::Fatal)?;
+    let regs = Registers {
+        base: memory::map_mmio(base, bar.size()).ok_or(NvmeError::OutOfMemory)?,
+    };
+    device
+        .address
+        .enable(pci::command::MEMORY_SPACE | pci::command::BUS_MASTER);
+
+    let capabilities = regs.read_u64(reg::CAP);
+    if capabilities & cap::CSS_NVM == 0 || (capabilities >> cap::MPSMIN_SHIFT) & 0xF != 0 {
+        log::warn!("NVMe {}: unsupported controller", device.address);
+        return Err(NvmeError::Fatal);
+    }
+    if ((capabilities & cap::MQES_MASK) as usize) < QUEUE_ENTRIES - 1 {
+        log::warn!("NVMe {}: queues too small", device.address);
+        return Err(NvmeError::Fatal);
+    }
+    let stride = 4 << ((capabilities >> cap::DSTRD_SHIFT) & 0xF);
+    let timeout = Duration::from_millis(500 * ((capabilities >> cap::TO_SHIFT) & 0xFF).max(1));
+    enable(regs, timeout, stride)?;
+    let mut info = identify_controller()?;
+
+    // one queue pair and vector per CPU, plus the admin vector
+    let mut queue_cpus = [0; MAX_IO_QUEUES];
+    let cpus = apic::cpu_ids()
+        .zip(queue_cpus.iter_mut())
+        .map(|(id, slot)| *slot = id)
+        .count();
+    let mut queues = negotiate_queues(cpus)?;
+    let vectors = msi::allocate(&device, queues + 1)
+        .or_else(|_| {
+            queues = 1;
+            msi::allocate(&device, 2)
+        })
+        .inspect_err(|err| log::info!("NVMe {}: polling, {}", device.address, err))
+        .ok();
+
+    for id in 1..=queues {
+        if let Some(vectors) = &vectors {
+            vectors.set_handler(id, handle_interrupt, id);
+            vectors.set_destination(id, queue_cpus[id - 1]);
+            vectors.unmask(id);
+        }
+        let vector = vectors.as_ref().map(|_| id as u32);
+        if let Err(err) = create_io_queue(regs, id, stride, vector) {
+            log::warn!("NVMe {}: I/O queue {}: {}", device.address, id, err);
+            queues = id - 1;
+            break;
+        }
+    }
+    if queues == 0 {
+        return Err(NvmeError::Fatal);
+    }
+    if let Some(vectors) = &vectors {
+        vectors.set_handler(0, handle_interrupt, 0);
+        vectors.unmask(0);
+    }
+    info.io_queues = queues;
+    let version = regs.read(reg::VS);
+    log::info!(
+        "NVMe {}: version {}.{}, {} ({}, firmware {}), {} I/O queue(s)",
+        device.address,
+        version >> 16,
+        (version >> 8) & 0xFF,
+        info.model(),
+        info.serial(),
+        info.firmware(),
+        queues
+    );
+    *CONTROLLER.lock() = Some(Controller {
+        regs,
+        info,
+        queue_cpus,
+        interrupts: vectors.is_some(),
+        failed: false,
+    });
+    *VECTORS.lock() = vectors;
+    scan_namespaces()
+}
+
+/// Brings up the first NVMe controller and its namespaces.
+pub fn init() {
+    let Some(device) = pci::find_class(
+        pci::class::MASS_STORAGE,
+        pci::subclass::NVM,
+        Some(PROG_IF_NVME),
+    )
+    .next() else {
+        return;
+    };
+    if let Err(err) = init_controller(device) {
+        log::warn!("NVMe {}: {}", device.address, err);
+    }
+}
+
+pub fn controller() -> Option<ControllerInfo> {
+    CONTROLLER.lock().map(|controller| controller.info)
+}
+
+/// Handle to an active namespace found by [`init`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Namespace {
+    index: usize,
+}
+
+pub fn namespaces() -> impl Iterator<Item = Namespace> {
+    let namespaces = *NAMESPACES.lock();
+    (0..MAX_NAMESPACES)
+        .filter(move |index| namespaces[*index].is_some())
+        .map(|index| Namespace { index })
+}
+
+/// An outstanding command, see [`Namespace::submit`].
+#[must_use = "commands hold their identifier until completed"]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    queue: usize,
+    cid: usize,
+}
+
+/// The I/O queue of the running CPU.
+fn current_queue() -> usize {
+    let controller = CONTROLLER.lock().expect("NVMe not initialised");
+    let queues = controller.info.io_queues;
+    let id = apic::id();
+    let index = controller.queue_cpus[..queues]
+        .iter()
+        .position(|cpu| *cpu == id)
+        .unwrap_or(id as usize % queues);
+    index + 1
+}
+
+impl Namespace {
+    pub fn info(&self) -> NamespaceInfo {
+        NAMESPACES.lock()[self.index].expect("NVMe namespace vanished")
+    }
+
+    /// Issues `op` on the running CPU's queue without waiting.
+    pub fn submit(&self, op: NvmeOp) -> Result<Request, NvmeError> {
+        if CONTROLLER
+            .lock()
+            .is_some_and(|controller| controller.failed)
+        {
+            return Err(NvmeError::Fatal);
+        }
+        let info = self.info();
+        let (opcode, lba, data) = match op {
+            NvmeOp::Read { lba, phys, len } => (io_opcode::READ, lba, Some((phys, len as usize))),
+            NvmeOp::Write { lba, phys, len } => (io_opcode::WRITE, lba, Some((phys, len as usize))),
+            NvmeOp::Flush => (io_opcode::FLUSH, 0, None),
+        };
+        let mut command = command(opcode, info.nsid);
+        if let Some((phys, len)) = data {
+            let blocks = len / info.block_size;
+            let max_transfer = controller().map_or(0, |controller| controller.max_transfer);
+            if !len.is_multiple_of(info.block_size)
+                || blocks == 0
+                || len > max_transfer
+                || !phys.is_multiple_of(4)
+                || lba + blocks as u64 > info.blocks
+            {
+                return Err(NvmeError::OutOfRange);
+            }
+            command[10] = lba as u32;
+            command[11] = (lba >> 32) as u32;
+            command[12] = blocks as u32 - 1;
+        }
+        let queue = current_queue();
+        let cid = queue_lock(queue)
+            .lock()
+            .as_mut()
+            .expect("NVMe queue vanished")
+            .submit(command, data)?;
+        Ok(Request { queue, cid })
+    }
+
+    /// Takes the result of a finished command; hands it back if it is still
+    /// running.
+    pub fn try_complete(&self, request: Request) -> Result<Result<(), NvmeError>, Request> {
+        let result = queue_lock(request.queue).lock().as_mut().and_then(|queue| {
+            queue.update();
+            queue.collect(request.cid)
+        });
+        match result {
+            Some(result) => Ok(result.map(|_| ())),
+            None => Err(request),
+        }
+    }
+
+    /// Blocks until `request` has finished.
+    pub fn wait(&self, request: Request) -> Result<(), NvmeError> {
+        let interrupts = CONTROLLER
+            .lock()
+            .is_some_and(|controller| controller.interrupts);
+        let mut request = Some(request);
+        block::wait_for(interrupts, Some(TIMEOUT), || {
+            match self.try_complete(request.take()?) {
+                Ok(result) => Some(result),
+                Err(pending) => {
+                    request = Some(pending);
+                    None
+                }
+            }
+        })
+        .unwrap_or_else(|| {
+            let request = request.expect("timed out without a request");
+            log::error!(
+                "NVMe queue {}: command {} timed out",
+                request.queue,
+                request.cid
+            );
+            abort(request.queue, request.cid);
+            Err(NvmeError::Timeout)
+        })
+    }
+
+    /// Issues `op` once an identifier is free and waits for it.
+    pub fn run(&self, op: NvmeOp) -> Result<(), NvmeError> {
+        let deadline = time::uptime() + TIMEOUT;
+        loop {
+            match self.submit(op) {
+                Ok(request) => return self.wait(request),
+                Err(NvmeError::Busy) if time::uptime() < deadline => {
+                    if let Some(queue) = queue_lock(current_queue()).lock().as_mut() {
+                        queue.update();
+                    }
+                    core::hint::spin_loop();
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Reads whole blocks into `buf` through a bounce buffer.
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), NvmeError> {
+        self.check(lba, buf.len())?;
+        let bounce = self.bounce(buf.len()).ok_or(NvmeError::OutOfMemory)?;
+        sync::block_on(bounce.read(lba, buf, async |lba, phys, len| {
+            self.run(NvmeOp::Read { lba, phys, len })
+        }))
+    }
+
+    /// Writes whole blocks from `buf` through a bounce buffer.
+    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<(), NvmeError> {
+        self.check(lba, buf.len())?;
+        let mut bounce = self.bounce(buf.len()).ok_or(NvmeError::OutOfMemory)?;
+        sync::block_on(bounce.write(lba, buf, async |lba, phys, len| {
+            self.run(NvmeOp::Write { lba, phys, len })
+        }))
+    }
+
+    fn check(&self, lba: u64, len: usize) -> Result<(), NvmeError> {
+        let info = self.info();
+        if !len.is_multiple_of(info.block_size)
+            || lba + (len / info.block_size) as u64 > info.blocks
+        {
+            return Err(NvmeError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Commits the volatile write cache, if the controller has one.
+    pub fn flush(&self) -> Result<(), NvmeError> {
+        self.run(NvmeOp::Flush)
+    }
+}
+
+impl From<NvmeError> for BlockError {
+    fn from(err: NvmeError) -> BlockError {
+        match err {
+            NvmeError::Command(_) | NvmeError::Fatal | NvmeError::Busy => BlockError::Io,
+            NvmeError::Timeout => BlockError::Timeout,
+            NvmeError::OutOfRange => BlockError::OutOfRange,
+            NvmeError::OutOfMemory => BlockError::OutOfMemory,
+        }
+    }
+}
+
+impl Namespace {
+    /// Issues `op` and resolves once its completion has been posted.
+    async fn execute(&self, op: NvmeOp) -> Result<(), NvmeError> {
+        let mut request = Some(self.submit(op)?);
+        block::poll_completion(|| {
+            match self.try_complete(request.take().expect("request completed twice")) {
+                Ok(result) => Some(result),
+                Err(pending) => {
+                    request = Some(pending);
+                    None
+                }
+            }
+        })
+        .await
+    }
+
+    /// A bounce buffer for `len` bytes, moved in chunks the controller
+    /// takes in one command.
+    fn bounce(&self, len: usize) -> Option<Bounce> {
+        let max_transfer = controller().map_or(PAGE_SIZE, |controller| controller.max_transfer);
+        Bounce::new(len, MAX_TRANSFER.min(max_transfer), self.info().block_size)
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn block_size(&self) -> usize {
+        self.info().block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info().blocks
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let bounce = self.bounce(buf.len()).ok_or(BlockError::OutOfMemory)?;
+        bounce
+            .read(lba, buf, async |lba, phys, len| {
+                self.execute(NvmeOp::Read { lba, phys, len }).await
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let mut bounce = self.bounce(buf.len()).ok_or(BlockError::OutOfMemory)?;
+        bounce
+            .write(lba, buf, async |lba, phys, len| {
+                self.execute(NvmeOp::Write { lba, phys, len }).await
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        Ok(self.execute(NvmeOp::Flush).await?)
+    }
+}
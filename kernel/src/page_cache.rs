@@ -0,0 +1,281 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::memory::{self, FRAME_SIZE};
+use crate::sync::SpinLock;
+use crate::vfs::{self, VfsError};
+
+// File data is cached a page at a time, each page a frame of its own, so
+// that the page read and written through the VFS is the very one a file
+// mapping maps. A filesystem keeps one cache per file and moves pages
+// between it and the disk through its `Backing`; file data then goes to
+// the block layer directly instead of through the buffer cache, which is
+// left with the metadata.
+//
+// A write marks its page dirty, and the page is written back when the file
+// or its filesystem is synced. A page mapped writable can be changed
+// without anyone noticing, so it stays writable only until it is written
+// back: cleaning a page write-protects it again, and the next write faults
+// and dirties it anew.
+//
+// Pages stay cached until the file is truncated or dropped; nothing
+// reclaims them when memory runs low yet. No lock is held while calling
+// into the backing, which takes the filesystem's own locks, or while
+// copying from or to a caller's buffer, which may be a mapping that faults.
+
+pub const PAGE_SIZE: usize = FRAME_SIZE as usize;
+
+/// Pages alive, cached or mapped.
+static PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes held in pages of file data, for `/proc/meminfo`.
+pub fn cached_bytes() -> u64 {
+    PAGES.load(Ordering::Relaxed) * PAGE_SIZE as u64
+}
+
+/// A page of file data.
+pub struct Page {
+    frame: u64,
+    dirty: AtomicBool,
+    /// Where the page is mapped writable, to write-protect when it is
+    /// cleaned.
+    writable_at: SpinLock<Vec<u64>>,
+}
+
+impl Page {
+    /// A zeroed page.
+    pub fn new() -> vfs::Result<Page> {
+        let frame = memory::allocate_frame().ok_or(VfsError::OutOfMemory)?;
+        PAGES.fetch_add(1, Ordering::Relaxed);
+        Ok(Page {
+            frame,
+            dirty: AtomicBool::new(false),
+            writable_at: SpinLock::new(Vec::new()),
+        })
+    }
+
+    /// Physical address of the page.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    fn data(&self) -> *mut u8 {
+        memory::phys_to_virt(self.frame)
+    }
+
+    /// Copies out of the page from byte `offset` on.
+    pub fn read(&self, offset: usize, buf: &mut [u8]) {
+        assert!(offset + buf.len() <= PAGE_SIZE);
+        unsafe { ptr::copy_nonoverlapping(self.data().add(offset), buf.as_mut_ptr(), buf.len()) };
+    }
+
+    /// Copies into the page from byte `offset` on. The page is not marked
+    /// dirty.
+    pub fn write(&self, offset: usize, data: &[u8]) {
+        assert!(offset + data.len() <= PAGE_SIZE);
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.data().add(offset), data.len()) };
+    }
+
+    /// Zeroes the page from byte `offset` on.
+    pub fn zero_from(&self, offset: usize) {
+        assert!(offset <= PAGE_SIZE);
+        unsafe { self.data().add(offset).write_bytes(0, PAGE_SIZE - offset) };
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Notes that the page is mapped writable at `virt`, which makes it
+    /// dirty.
+    pub fn mapped_writable(&self, virt: u64) {
+        self.writable_at.lock().push(virt);
+        self.mark_dirty();
+    }
+
+    /// Notes that the page is no longer mapped at `virt`.
+    pub fn unmapped(&self, virt: u64) {
+        self.writable_at.lock().retain(|at| *at != virt);
+    }
+
+    /// Marks the page clean, write-protecting its writable mappings, and
+    /// returns whether it was dirty.
+    fn clean(&self) -> bool {
+        let mut writable_at = self.writable_at.lock();
+        for virt in writable_at.drain(..) {
+            memory::protect_page(virt, false);
+        }
+        self.dirty.swap(false, Ordering::AcqRel)
+    }
+
+    /// Runs `f` on the whole page, for handing it to the block layer.
+    fn with_contents<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        f(unsafe { core::slice::from_raw_parts(self.data(), PAGE_SIZE) })
+    }
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        memory::free_frames(self.frame, 1);
+        PAGES.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Fills a page nobody else has yet from `backing`.
+fn fill(page: &Page, backing: &dyn Backing, index: u64) -> vfs::Result<bool> {
+    let buf = unsafe { core::slice::from_raw_parts_mut(page.data(), PAGE_SIZE) };
+    backing.read_page(index, buf)
+}
+
+/// How a filesystem moves the pages of one file to and from its disk.
+pub trait Backing {
+    /// Reads page `index` into `buf`, which comes zeroed. Returns false if
+    /// the file has no data there, a hole or past its end; such pages are
+    /// only cached once they are written or mapped.
+    fn read_page(&self, index: u64, buf: &mut [u8]) -> vfs::Result<bool>;
+
+    /// Writes page `index` back. Bytes past the end of the file are to be
+    /// left out.
+    fn write_page(&self, index: u64, data: &[u8]) -> vfs::Result<()>;
+}
+
+/// The cached pages of one file.
+pub struct PageCache {
+    pages: SpinLock<BTreeMap<u64, Arc<Page>>>,
+}
+
+impl Default for PageCache {
+    fn default() -> PageCache {
+        PageCache::new()
+    }
+}
+
+impl PageCache {
+    pub const fn new() -> PageCache {
+        PageCache {
+            pages: SpinLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Page `index`, read in through `backing` if it isn't cached yet.
+    pub fn get(&self, backing: &dyn Backing, index: u64) -> vfs::Result<Arc<Page>> {
+        if let Some(page) = self.pages.lock().get(&index) {
+            return Ok(page.clone());
+        }
+        let page = Page::new()?;
+        fill(&page, backing, index)?;
+        let page = Arc::new(page);
+        // someone may have read it in meanwhile
+        Ok(self.pages.lock().entry(index).or_insert(page).clone())
+    }
+
+    /// Copies bytes from `offset` on into `buf`. The caller has cut `buf`
+    /// to the size of the file.
+    pub fn read(&self, backing: &dyn Backing, offset: u64, buf: &mut [u8]) -> vfs::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let index = position / PAGE_SIZE as u64;
+            let within = position as usize % PAGE_SIZE;
+            let count = (PAGE_SIZE - within).min(buf.len() - done);
+            let chunk = &mut buf[done..done + count];
+            match self.find(index) {
+                Some(page) => page.read(within, chunk),
+                None => {
+                    let page = Page::new()?;
+                    let present = fill(&page, backing, index)?;
+                    page.read(within, chunk);
+                    if present {
+                        self.pages.lock().entry(index).or_insert(Arc::new(page));
+                    }
+                }
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Copies `data` to byte `offset` on, dirtying the pages. A page that is
+    /// only partly overwritten is read in first.
+    pub fn write(&self, backing: &dyn Backing, offset: u64, data: &[u8]) -> vfs::Result<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let position = offset + done as u64;
+            let index = position / PAGE_SIZE as u64;
+            let within = position as usize % PAGE_SIZE;
+            let count = (PAGE_SIZE - within).min(data.len() - done);
+            let page = match count == PAGE_SIZE {
+                true => {
+                    let page = Arc::new(Page::new()?);
+                    self.pages.lock().entry(index).or_insert(page).clone()
+                }
+                false => self.get(backing, index)?,
+            };
+            page.write(within, &data[done..done + count]);
+            page.mark_dirty();
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Page `index` if it is cached.
+    pub fn find(&self, index: u64) -> Option<Arc<Page>> {
+        self.pages.lock().get(&index).cloned()
+    }
+
+    /// Drops the pages past `size` and zeroes the rest of the last one, if
+    /// it is cached, so that it reads as zeroes if the file grows again.
+    pub fn truncate(&self, size: u64) {
+        let dropped = self
+            .pages
+            .lock()
+            .split_off(&size.div_ceil(PAGE_SIZE as u64));
+        drop(dropped);
+        let within = size as usize % PAGE_SIZE;
+        if within != 0
+            && let Some(page) = self.find(size / PAGE_SIZE as u64)
+        {
+            page.zero_from(within);
+            page.mark_dirty();
+        }
+    }
+
+    /// Drops every page, dirty or not, for a file that is gone.
+    pub fn discard(&self) {
+        self.pages.lock().clear();
+    }
+
+    /// Writes the dirty pages back through `backing`. A page that fails to
+    /// be written stays dirty.
+    pub fn write_back(&self, backing: &dyn Backing) -> vfs::Result<()> {
+        let dirty: Vec<_> = self
+            .pages
+            .lock()
+            .iter()
+            .filter(|(_, page)| page.is_dirty())
+            .map(|(index, page)| (*index, page.clone()))
+            .collect();
+        for (index, page) in dirty {
+            if !page.clean() {
+                continue;
+            }
+            if let Err(err) = page.with_contents(|data| backing.write_page(index, data)) {
+                page.mark_dirty();
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any page is dirty.
+    pub fn is_dirty(&self) -> bool {
+        self.pages.lock().values().any(|page| page.is_dirty())
+    }
+}
@@ -0,0 +1,271 @@
+use core::fmt;
+
+use crate::block::{self, BlockDevice, BlockError, Device, DeviceId, Partition};
+
+// Both partition tables the bootloader's disk images use: the MBR of BIOS
+// images, and the GPT of UEFI images behind its protective MBR. Entries
+// become block devices of their own; nested tables and MBR extended
+// partitions are left alone.
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+/// Partition type of the protective entry covering a GPT disk.
+const MBR_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN: usize = 92;
+const GPT_ENTRY_MIN: usize = 128;
+
+/// Largest block size the scanner reads tables with.
+const MAX_BLOCK_SIZE: usize = 4096;
+const MAX_PARTITIONS: usize = 16;
+
+/// A GUID in its on-disk byte order, the first three fields little endian.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+    pub const EFI_SYSTEM: Guid = Guid([
+        0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9,
+        0x3B,
+    ]);
+    /// 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+    pub const LINUX_FILESYSTEM: Guid = Guid([
+        0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+        0xE4,
+    ]);
+
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]])
+        )?;
+        b[8..10]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02X}", byte))?;
+        f.write_str("-")?;
+        b[10..]
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionType {
+    /// MBR system ID byte.
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+
+/// Continues a CRC-32 (IEEE) over `bytes`; start from and finish with an
+/// inverted value, as [`crc32`] does.
+fn crc32_update(crc: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(crc, |crc, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !crc32_update(!0, bytes)
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+/// A table entry before it is registered.
+#[derive(Debug, Clone, Copy)]
+struct Found {
+    number: usize,
+    start: u64,
+    blocks: u64,
+    kind: PartitionType,
+}
+
+/// Reads the partition table of `disk` and registers its entries, replacing
+/// those of an earlier scan. Returns how many it found.
+pub fn scan(disk: DeviceId) -> Result<usize, BlockError> {
+    block::remove_partitions(disk);
+    let Device::Disk(whole) = disk.device() else {
+        return Ok(0);
+    };
+    let block_size = whole.block_size();
+    if !(512..=MAX_BLOCK_SIZE).contains(&block_size) {
+        return Err(BlockError::Unsupported);
+    }
+    let mut sector = [0u8; MAX_BLOCK_SIZE];
+    block::read(&whole, 0, &mut sector[..block_size])?;
+    if sector[510..512] != MBR_SIGNATURE {
+        return Ok(0);
+    }
+
+    let mut found = [None; MAX_PARTITIONS];
+    let entries = sector[MBR_ENTRIES..MBR_ENTRIES + 4 * MBR_ENTRY_SIZE]
+        .as_chunks::<MBR_ENTRY_SIZE>()
+        .0;
+    if entries.iter().any(|entry| entry[4] == MBR_PROTECTIVE) {
+        if !scan_gpt(disk, block_size, &mut found)? {
+            return Ok(0);
+        }
+    } else {
+        for (index, entry) in entries.iter().enumerate() {
+            let kind = entry[4];
+            let start = le_u32(&entry[8..]) as u64;
+            let blocks = le_u32(&entry[12..]) as u64;
+            if kind == 0 || blocks == 0 {
+                continue;
+            }
+            if MBR_EXTENDED.contains(&kind) {
+                log::info!("{}: extended partition {} skipped", disk, index + 1);
+                continue;
+            }
+            found[index] = Some(Found {
+                number: index + 1,
+                start,
+                blocks,
+                kind: PartitionType::Mbr(kind),
+            });
+        }
+    }
+
+    let disk_blocks = whole.block_count();
+    let mut count = 0;
+    for found in found.into_iter().flatten() {
+        if found.start == 0 || found.start + found.blocks > disk_blocks {
+            log::warn!("{}: partition {} lies outside the disk", disk, found.number);
+            continue;
+        }
+        let partition = Partition {
+            disk: whole,
+            start: found.start,
+            blocks: found.blocks,
+            kind: found.kind,
+            number: found.number,
+        };
+        match block::register_partition(disk, partition) {
+            Some(id) => {
+                log::info!(
+                    "{}: blocks {}..{}, {:?}",
+                    id,
+                    found.start,
+                    found.start + found.blocks,
+                    found.kind
+                );
+                count += 1;
+            }
+            None => log::warn!("{}: no room for partition {}", disk, found.number),
+        }
+    }
+    Ok(count)
+}
+
+/// Collects the entries of the GPT behind a protective MBR. Returns false if
+/// the header or the entry array don't check out.
+fn scan_gpt(
+    disk: DeviceId,
+    block_size: usize,
+    found: &mut [Option<Found>; MAX_PARTITIONS],
+) -> Result<bool, BlockError> {
+    let mut block = [0u8; MAX_BLOCK_SIZE];
+    block::read(&disk, 1, &mut block[..block_size])?;
+    let header_size = le_u32(&block[12..]) as usize;
+    if &block[..8] != GPT_SIGNATURE || !(GPT_HEADER_MIN..=block_size).contains(&header_size) {
+        log::warn!("{}: protective MBR without a GPT header", disk);
+        return Ok(false);
+    }
+    let header_crc = le_u32(&block[16..]);
+    block[16..20].fill(0);
+    if crc32(&block[..header_size]) != header_crc {
+        log::warn!("{}: GPT header checksum mismatch", disk);
+        return Ok(false);
+    }
+
+    let entries_lba = le_u64(&block[72..]);
+    let entry_count = le_u32(&block[80..]) as usize;
+    let entry_size = le_u32(&block[84..]) as usize;
+    let entries_crc = le_u32(&block[88..]);
+    if entry_size < GPT_ENTRY_MIN || !block_size.is_multiple_of(entry_size) {
+        log::warn!("{}: unsupported GPT entry size {}", disk, entry_size);
+        return Ok(false);
+    }
+
+    let per_block = block_size / entry_size;
+    let mut crc = !0;
+    for index in 0..entry_count.div_ceil(per_block) {
+        block::read(&disk, entries_lba + index as u64, &mut block[..block_size])?;
+        let first = index * per_block;
+        let entries = block[..block_size].chunks_exact(entry_size);
+        for (number, entry) in (first + 1..=entry_count).zip(entries) {
+            crc = crc32_update(crc, entry);
+            let kind = Guid(entry[..16].try_into().unwrap());
+            if kind.is_zero() {
+                continue;
+            }
+            let start = le_u64(&entry[32..]);
+            let last = le_u64(&entry[40..]);
+            let Some(slot) = found.iter_mut().find(|slot| slot.is_none()) else {
+                log::warn!(
+                    "{}: GPT entry {} skipped, too many partitions",
+                    disk,
+                    number
+                );
+                continue;
+            };
+            *slot = Some(Found {
+                number,
+                start,
+                blocks: (last + 1).saturating_sub(start),
+                kind: PartitionType::Gpt(kind),
+            });
+        }
+    }
+    if !crc != entries_crc {
+        log::warn!("{}: GPT entry array checksum mismatch", disk);
+        return Ok(false);
+    }
+    Ok(true)
+}
@@ -0,0 +1,869 @@
+use core::fmt;
+
+use conquer_once::spin::OnceCell;
+
+use crate::acpi;
+use crate::memory;
+use crate::port::Port;
+use crate::sync::IrqSpinLock;
+
+// Configuration space is reached through PCIe ECAM where the ACPI MCFG table
+// describes it, which also covers the 4 KiB extended space of every
+// function. Everything else falls back to legacy mechanism #1: write the
+// function's address to CONFIG_ADDRESS, then access the selected dword
+// through CONFIG_DATA. Only segment 0 is supported.
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+static CONFIG_LOCK: IrqSpinLock<()> = IrqSpinLock::new(());
+
+pub const CONFIG_SPACE_SIZE: u16 = 256;
+pub const EXTENDED_CONFIG_SPACE_SIZE: u16 = 4096;
+
+pub mod offset {
+    pub const VENDOR_ID: u16 = 0x00;
+    pub const DEVICE_ID: u16 = 0x02;
+    pub const COMMAND: u16 = 0x04;
+    pub const STATUS: u16 = 0x06;
+    pub const REVISION: u16 = 0x08;
+    pub const PROG_IF: u16 = 0x09;
+    pub const SUBCLASS: u16 = 0x0A;
+    pub const CLASS: u16 = 0x0B;
+    pub const HEADER_TYPE: u16 = 0x0E;
+    pub const BAR0: u16 = 0x10;
+    pub const SECONDARY_BUS: u16 = 0x19;
+    pub const SUBSYSTEM_VENDOR_ID: u16 = 0x2C;
+    pub const SUBSYSTEM_ID: u16 = 0x2E;
+    pub const CAPABILITIES: u16 = 0x34;
+    pub const INTERRUPT_LINE: u16 = 0x3C;
+    pub const INTERRUPT_PIN: u16 = 0x3D;
+    /// First extended capability header.
+    pub const EXTENDED_CAPABILITIES: u16 = 0x100;
+}
+
+pub mod command {
+    pub const IO_SPACE: u16 = 1 << 0;
+    pub const MEMORY_SPACE: u16 = 1 << 1;
+    pub const BUS_MASTER: u16 = 1 << 2;
+    pub const INTX_DISABLE: u16 = 1 << 10;
+}
+
+pub mod status {
+    pub const CAPABILITIES_LIST: u16 = 1 << 4;
+    /// Parity, abort and SERR bits, cleared by writing ones.
+    pub const ERRORS: u16 = 0xF900;
+}
+
+pub mod capability {
+    pub const POWER_MANAGEMENT: u8 = 0x01;
+    pub const MSI: u8 = 0x05;
+    pub const VENDOR_SPECIFIC: u8 = 0x09;
+    pub const BRIDGE_SUBSYSTEM: u8 = 0x0D;
+    pub const PCI_EXPRESS: u8 = 0x10;
+    pub const MSIX: u8 = 0x11;
+    pub const SATA: u8 = 0x12;
+}
+
+pub mod class {
+    pub const MASS_STORAGE: u8 = 0x01;
+    pub const NETWORK: u8 = 0x02;
+    pub const DISPLAY: u8 = 0x03;
+    pub const BRIDGE: u8 = 0x06;
+}
+
+/// Mass storage subclasses.
+pub mod subclass {
+    pub const IDE: u8 = 0x01;
+    pub const SATA: u8 = 0x06;
+    pub const NVM: u8 = 0x08;
+}
+
+mod header_type {
+    pub const MASK: u8 = 0x7F;
+    pub const MULTIFUNCTION: u8 = 0x80;
+    pub const ENDPOINT: u8 = 0x00;
+    pub const PCI_BRIDGE: u8 = 0x01;
+    pub const CARDBUS_BRIDGE: u8 = 0x02;
+}
+
+mod bar {
+    pub const IO: u32 = 1 << 0;
+    pub const TYPE_MASK: u32 = 0b11 << 1;
+    pub const TYPE_64: u32 = 0b10 << 1;
+    pub const PREFETCHABLE: u32 = 1 << 3;
+    pub const MEMORY_MASK: u32 = !0xF;
+    pub const IO_MASK: u32 = !0x3;
+}
+
+/// An ECAM window from the MCFG table.
+#[derive(Debug, Clone, Copy)]
+struct EcamRegion {
+    base: u64,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+const MAX_ECAM_REGIONS: usize = 4;
+const MCFG_RESERVED: usize = 8;
+const MCFG_ENTRY_LEN: usize = 16;
+
+static ECAM: OnceCell<[Option<EcamRegion>; MAX_ECAM_REGIONS]> = OnceCell::uninit();
+
+fn ecam_regions() -> &'static [Option<EcamRegion>] {
+    ECAM.get().map(|regions| &regions[..]).unwrap_or(&[])
+}
+
+/// Reads the segment 0 ECAM windows out of the MCFG table.
+fn init_ecam() {
+    let mut regions = [None; MAX_ECAM_REGIONS];
+    if let Some(mcfg) = acpi::find_table(b"MCFG") {
+        let entries = mcfg.body().get(MCFG_RESERVED..).unwrap_or(&[]);
+        let mut count = 0;
+        let (entries, _) = entries.as_chunks::<MCFG_ENTRY_LEN>();
+        for entry in entries {
+            let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let segment = u16::from_le_bytes(entry[8..10].try_into().unwrap());
+            let (start_bus, end_bus) = (entry[10], entry[11]);
+            log::info!(
+                "PCI: ECAM segment {} buses {:02x}-{:02x} at {:#x}",
+                segment,
+                start_bus,
+                end_bus,
+                base
+            );
+            if segment != 0 || count == MAX_ECAM_REGIONS {
+                log::warn!("PCI: ignoring ECAM window for segment {}", segment);
+                continue;
+            }
+            regions[count] = Some(EcamRegion {
+                base,
+                start_bus,
+                end_bus,
+            });
+            count += 1;
+        }
+    }
+    let _ = ECAM.try_init_once(|| regions);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(bus: u8, device: u8, function: u8) -> Self {
+        PciAddress {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    fn config_address(&self, offset: u16) -> u32 {
+        (1 << 31)
+            | ((self.bus as u32) << 16)
+            | ((self.device as u32) << 11)
+            | ((self.function as u32) << 8)
+            | (offset as u32 & 0xFC)
+    }
+
+    /// Where this function's configuration space is memory-mapped, if it is.
+    fn ecam_ptr(&self, offset: u16) -> Option<*mut u32> {
+        let region = ecam_regions()
+            .iter()
+            .flatten()
+            .find(|region| (region.start_bus..=region.end_bus).contains(&self.bus))?;
+        let phys = region.base
+            + (((self.bus - region.start_bus) as u64) << 20)
+            + ((self.device as u64) << 15)
+            + ((self.function as u64) << 12)
+            + (offset & !3) as u64;
+        Some(memory::phys_to_virt(phys).cast())
+    }
+
+    /// Whether the 4 KiB extended configuration space is reachable.
+    pub fn has_extended_config(&self) -> bool {
+        self.ecam_ptr(0).is_some()
+    }
+
+    /// Reads a dword. Offsets past the 256 byte legacy space read as all
+    /// ones without ECAM.
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        if let Some(ptr) = self.ecam_ptr(offset) {
+            return unsafe { ptr.read_volatile() };
+        }
+        if offset >= CONFIG_SPACE_SIZE {
+            return u32::MAX;
+        }
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        if let Some(ptr) = self.ecam_ptr(offset) {
+            unsafe { ptr.write_volatile(value) };
+            return;
+        }
+        if offset >= CONFIG_SPACE_SIZE {
+            return;
+        }
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let dword = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, dword | ((value as u32) << shift));
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn write_u8(&self, offset: u16, value: u8) {
+        let shift = (offset & 3) * 8;
+        let dword = self.read_u32(offset) & !(0xFF << shift);
+        self.write_u32(offset, dword | ((value as u32) << shift));
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(offset::VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(offset::DEVICE_ID)
+    }
+
+    pub fn exists(&self) -> bool {
+        self.vendor_id() != 0xFFFF
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.read_u8(offset::HEADER_TYPE) & header_type::MULTIFUNCTION != 0
+    }
+
+    /// Writes the command register. STATUS shares its dword and has
+    /// write-one-to-clear error bits, so it gets zeroes rather than what
+    /// [`write_u16`](Self::write_u16) would read back.
+    pub fn write_command(&self, value: u16) {
+        self.write_u32(offset::COMMAND, value as u32);
+    }
+
+    pub fn enable(&self, bits: u16) {
+        let command = self.read_u16(offset::COMMAND);
+        self.write_command(command | bits);
+    }
+
+    pub fn disable(&self, bits: u16) {
+        let command = self.read_u16(offset::COMMAND);
+        self.write_command(command & !bits);
+    }
+
+    /// Decodes BAR `index` and sizes it by writing all ones and reading back
+    /// the address mask, with decoding turned off meanwhile.
+    ///
+    /// Returns the BAR and the number of slots it takes: two for the lower
+    /// half of a 64-bit memory BAR, otherwise one.
+    pub fn read_bar(&self, index: u8) -> (Option<Bar>, u8) {
+        let bar_offset = offset::BAR0 + index as u16 * 4;
+        let low = self.read_u32(bar_offset);
+        let is_io = low & bar::IO != 0;
+        let is_64 = !is_io && low & bar::TYPE_MASK == bar::TYPE_64;
+        let slots = if is_64 { 2 } else { 1 };
+
+        let command = self.read_u16(offset::COMMAND);
+        self.write_command(command & !(command::IO_SPACE | command::MEMORY_SPACE));
+        self.write_u32(bar_offset, u32::MAX);
+        let low_mask = self.read_u32(bar_offset);
+        self.write_u32(bar_offset, low);
+        let mut high = 0;
+        let mut high_mask = u32::MAX;
+        if is_64 {
+            high = self.read_u32(bar_offset + 4);
+            self.write_u32(bar_offset + 4, u32::MAX);
+            high_mask = self.read_u32(bar_offset + 4);
+            self.write_u32(bar_offset + 4, high);
+        }
+        self.write_command(command);
+
+        if is_io {
+            // the upper half of an I/O BAR may read back as zero
+            let mask = low_mask & bar::IO_MASK & 0xFFFF;
+            if mask == 0 {
+                return (None, slots);
+            }
+            let size = (!mask & 0xFFFF) + 1;
+            let port = (low & bar::IO_MASK) as u16;
+            return (Some(Bar::Io { port, size }), slots);
+        }
+
+        let mask = ((high_mask as u64) << 32) | (low_mask & bar::MEMORY_MASK) as u64;
+        // a 64-bit BAR of 4 GiB or more has no address bits in its low half
+        let decoded = if is_64 { mask } else { mask & 0xFFFF_FFFF };
+        if decoded == 0 {
+            return (None, slots);
+        }
+        let base = ((high as u64) << 32) | (low & bar::MEMORY_MASK) as u64;
+        let bar = Bar::Memory {
+            base,
+            size: (!mask).wrapping_add(1),
+            prefetchable: low & bar::PREFETCHABLE != 0,
+            is_64,
+        };
+        (Some(bar), slots)
+    }
+
+    /// Walks the capability list in the legacy configuration space.
+    pub fn capabilities(&self) -> Capabilities {
+        let next = match self.read_u16(offset::STATUS) & status::CAPABILITIES_LIST {
+            0 => 0,
+            _ => self.read_u8(offset::CAPABILITIES) as u16,
+        };
+        Capabilities {
+            address: *self,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    /// Offset of the first capability with the given ID.
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+
+    /// Walks the PCIe extended capability list, which needs ECAM.
+    pub fn extended_capabilities(&self) -> ExtendedCapabilities {
+        let next = match self.has_extended_config() {
+            true => offset::EXTENDED_CAPABILITIES,
+            false => 0,
+        };
+        ExtendedCapabilities {
+            address: *self,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Guards against malformed, looping capability lists.
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    pub offset: u16,
+}
+
+pub struct Capabilities {
+    address: PciAddress,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // pointers are dword aligned and past the standard header
+        let offset = self.next & !3;
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.address.read_u16(offset);
+        self.next = header >> 8;
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+pub struct ExtendedCapabilities {
+    address: PciAddress,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for ExtendedCapabilities {
+    type Item = ExtendedCapability;
+
+    fn next(&mut self) -> Option<ExtendedCapability> {
+        let offset = self.next & !3;
+        if offset < offset::EXTENDED_CAPABILITIES || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.address.read_u32(offset);
+        if header == 0 || header == u32::MAX {
+            return None;
+        }
+        self.next = (header >> 20) as u16;
+        Some(ExtendedCapability {
+            id: header as u16,
+            version: ((header >> 16) & 0xF) as u8,
+            offset,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        base: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+impl Bar {
+    pub fn size(&self) -> u64 {
+        match *self {
+            Bar::Memory { size, .. } => size,
+            Bar::Io { size, .. } => size as u64,
+        }
+    }
+
+    pub fn memory_base(&self) -> Option<u64> {
+        match *self {
+            Bar::Memory { base, .. } => Some(base),
+            Bar::Io { .. } => None,
+        }
+    }
+
+    pub fn io_port(&self) -> Option<u16> {
+        match *self {
+            Bar::Io { port, .. } => Some(port),
+            Bar::Memory { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bar::Memory {
+                base,
+                size,
+                prefetchable,
+                is_64,
+            } => {
+                write!(
+                    f,
+                    "memory at {:#x} ({}-bit, ",
+                    base,
+                    if is_64 { 64 } else { 32 }
+                )?;
+                if !prefetchable {
+                    write!(f, "non-")?;
+                }
+                write!(f, "prefetchable) [size={}]", Size(size))
+            }
+            Bar::Io { port, size } => {
+                write!(f, "I/O ports at {:#x} [size={}]", port, Size(size as u64))
+            }
+        }
+    }
+}
+
+/// Formats a byte count like lspci does.
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 5] = ["", "K", "M", "G", "T"];
+        let mut size = self.0;
+        let mut unit = 0;
+        while size >= 1024 && size.is_multiple_of(1024) && unit + 1 < UNITS.len() {
+            size /= 1024;
+            unit += 1;
+        }
+        write!(f, "{}{}", size, UNITS[unit])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderType {
+    Endpoint,
+    PciBridge,
+    CardBusBridge,
+    Unknown(u8),
+}
+
+/// Snapshot of one function taken while scanning the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: HeaderType,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    /// Legacy INTx pin, 1 = INTA#, 0 = none.
+    pub interrupt_pin: u8,
+    pub interrupt_line: u8,
+    /// Indexed by BAR slot; the upper half of a 64-bit BAR stays `None`.
+    pub bars: [Option<Bar>; 6],
+}
+
+impl PciDevice {
+    fn read(address: PciAddress) -> PciDevice {
+        let header_type = match address.read_u8(offset::HEADER_TYPE) & header_type::MASK {
+            header_type::ENDPOINT => HeaderType::Endpoint,
+            header_type::PCI_BRIDGE => HeaderType::PciBridge,
+            header_type::CARDBUS_BRIDGE => HeaderType::CardBusBridge,
+            other => HeaderType::Unknown(other),
+        };
+        let (subsystem_vendor_id, subsystem_id) = match header_type {
+            HeaderType::Endpoint => (
+                address.read_u16(offset::SUBSYSTEM_VENDOR_ID),
+                address.read_u16(offset::SUBSYSTEM_ID),
+            ),
+            _ => (0, 0),
+        };
+
+        let mut bars = [None; 6];
+        let bar_count = match header_type {
+            HeaderType::Endpoint => 6,
+            HeaderType::PciBridge => 2,
+            _ => 0,
+        };
+        let mut index = 0;
+        while index < bar_count {
+            let (bar, slots) = address.read_bar(index);
+            bars[index as usize] = bar;
+            index += slots;
+        }
+
+        PciDevice {
+            address,
+            vendor_id: address.vendor_id(),
+            device_id: address.device_id(),
+            class: address.read_u8(offset::CLASS),
+            subclass: address.read_u8(offset::SUBCLASS),
+            prog_if: address.read_u8(offset::PROG_IF),
+            revision: address.read_u8(offset::REVISION),
+            header_type,
+            subsystem_vendor_id,
+            subsystem_id,
+            interrupt_pin: address.read_u8(offset::INTERRUPT_PIN),
+            interrupt_line: address.read_u8(offset::INTERRUPT_LINE),
+            bars,
+        }
+    }
+
+    pub fn bar(&self, index: usize) -> Option<Bar> {
+        self.bars.get(index).copied().flatten()
+    }
+
+    /// Base address of memory BAR `index`, unless it is unassigned.
+    pub fn memory_bar(&self, index: usize) -> Option<u64> {
+        self.bar(index)?.memory_base().filter(|&base| base != 0)
+    }
+
+    pub fn io_bar(&self, index: usize) -> Option<u16> {
+        self.bar(index)?.io_port().filter(|&port| port != 0)
+    }
+
+    pub fn is_bridge(&self) -> bool {
+        self.header_type == HeaderType::PciBridge
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass, self.prog_if)
+    }
+}
+
+fn class_name(class: u8, subclass: u8, prog_if: u8) -> &'static str {
+    match (class, subclass, prog_if) {
+        (0x00, 0x01, _) => "VGA compatible unclassified device",
+        (0x01, 0x00, _) => "SCSI storage controller",
+        (0x01, 0x01, _) => "IDE interface",
+        (0x01, 0x05, _) => "ATA controller",
+        (0x01, 0x06, _) => "SATA controller",
+        (0x01, 0x08, _) => "Non-Volatile memory controller",
+        (0x01, _, _) => "Mass storage controller",
+        (0x02, 0x00, _) => "Ethernet controller",
+        (0x02, _, _) => "Network controller",
+        (0x03, 0x00, _) => "VGA compatible controller",
+        (0x03, _, _) => "Display controller",
+        (0x04, 0x01, _) => "Multimedia audio controller",
+        (0x04, 0x03, _) => "Audio device",
+        (0x04, _, _) => "Multimedia controller",
+        (0x05, _, _) => "Memory controller",
+        (0x06, 0x00, _) => "Host bridge",
+        (0x06, 0x01, _) => "ISA bridge",
+        (0x06, 0x04, _) => "PCI bridge",
+        (0x06, _, _) => "Bridge",
+        (0x07, 0x00, _) => "Serial controller",
+        (0x07, _, _) => "Communication controller",
+        (0x08, _, _) => "System peripheral",
+        (0x09, _, _) => "Input device controller",
+        (0x0C, 0x03, 0x00) => "USB controller (UHCI)",
+        (0x0C, 0x03, 0x10) => "USB controller (OHCI)",
+        (0x0C, 0x03, 0x20) => "USB controller (EHCI)",
+        (0x0C, 0x03, 0x30) => "USB controller (xHCI)",
+        (0x0C, 0x03, _) => "USB controller",
+        (0x0C, 0x05, _) => "SMBus",
+        (0x0C, _, _) => "Serial bus controller",
+        (0xFF, _, _) => "Unassigned class",
+        _ => "Unknown device",
+    }
+}
+
+fn capability_name(id: u8) -> &'static str {
+    match id {
+        capability::POWER_MANAGEMENT => "PM",
+        capability::MSI => "MSI",
+        capability::VENDOR_SPECIFIC => "Vendor",
+        capability::BRIDGE_SUBSYSTEM => "SSVID",
+        capability::PCI_EXPRESS => "PCIe",
+        capability::MSIX => "MSI-X",
+        capability::SATA => "SATA",
+        _ => "?",
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {:04x}:{:04x}",
+            self.address,
+            self.class_name(),
+            self.vendor_id,
+            self.device_id
+        )?;
+        if self.revision != 0 {
+            write!(f, " (rev {:02x})", self.revision)?;
+        }
+        Ok(())
+    }
+}
+
+const MAX_DEVICES: usize = 128;
+
+struct Registry {
+    devices: [Option<PciDevice>; MAX_DEVICES],
+    len: usize,
+}
+
+static REGISTRY: IrqSpinLock<Registry> = IrqSpinLock::new(Registry {
+    devices: [None; MAX_DEVICES],
+    len: 0,
+});
+
+/// Enumerates every function reachable from the host bridges, fills the
+/// registry and logs an `lspci`-like listing.
+pub fn init() {
+    init_ecam();
+    REGISTRY.lock().len = 0;
+
+    let root = PciAddress::new(0, 0, 0);
+    if root.is_multifunction() {
+        // several host bridges: function N decodes bus N
+        for function in 0..8 {
+            if PciAddress::new(0, 0, function).exists() {
+                scan_bus(function, 0);
+            }
+        }
+    } else {
+        scan_bus(0, 0);
+    }
+
+    let count = REGISTRY.lock().len;
+    log::info!("PCI: {} functions", count);
+    for device in devices() {
+        log_device(&device);
+    }
+}
+
+/// Bridges nest at most this deep, which also stops loops from firmware
+/// that left bus numbers misconfigured.
+const MAX_BRIDGE_DEPTH: usize = 16;
+
+fn scan_bus(bus: u8, depth: usize) {
+    for device in 0..32 {
+        let first = PciAddress::new(bus, device, 0);
+        if !first.exists() {
+            continue;
+        }
+        let functions = if first.is_multifunction() { 8 } else { 1 };
+        for function in 0..functions {
+            let address = PciAddress::new(bus, device, function);
+            if address.exists() {
+                scan_function(address, depth);
+            }
+        }
+    }
+}
+
+fn scan_function(address: PciAddress, depth: usize) {
+    let device = PciDevice::read(address);
+    {
+        let mut registry = REGISTRY.lock();
+        let len = registry.len;
+        if len == MAX_DEVICES {
+            log::warn!("PCI: registry full, skipping {}", address);
+            return;
+        }
+        registry.devices[len] = Some(device);
+        registry.len += 1;
+    }
+
+    if device.is_bridge() {
+        let secondary = address.read_u8(offset::SECONDARY_BUS);
+        if secondary <= address.bus || depth >= MAX_BRIDGE_DEPTH {
+            log::warn!(
+                "PCI: bridge {} has bad secondary bus {}",
+                address,
+                secondary
+            );
+            return;
+        }
+        scan_bus(secondary, depth + 1);
+    }
+}
+
+fn log_device(device: &PciDevice) {
+    log::info!("{}", device);
+    for (index, bar) in device.bars.iter().enumerate() {
+        if let Some(bar) = bar {
+            log::info!("        Region {}: {}", index, bar);
+        }
+    }
+    if device.interrupt_pin != 0 {
+        log::info!(
+            "        Interrupt: pin {} routed to IRQ {}",
+            (b'A' + device.interrupt_pin - 1) as char,
+            device.interrupt_line
+        );
+    }
+
+    let mut line = CapabilityLine::new();
+    for capability in device.address.capabilities() {
+        line.push(capability_name(capability.id));
+    }
+    if !line.is_empty() {
+        log::info!("        Capabilities: {}", line.as_str());
+    }
+    let extended = device.address.extended_capabilities().count();
+    if extended > 0 {
+        log::info!("        Extended capabilities: {}", extended);
+    }
+}
+
+/// Space-separated capability names, built without allocating.
+struct CapabilityLine {
+    buf: [u8; 96],
+    len: usize,
+}
+
+impl CapabilityLine {
+    fn new() -> Self {
+        CapabilityLine {
+            buf: [0; 96],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, name: &str) {
+        let separator = if self.len == 0 { "" } else { " " };
+        let needed = separator.len() + name.len();
+        if self.len + needed > self.buf.len() {
+            return;
+        }
+        for part in [separator, name] {
+            self.buf[self.len..self.len + part.len()].copy_from_slice(part.as_bytes());
+            self.len += part.len();
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+/// Every function found by [`init`], in scan order.
+pub fn devices() -> Devices {
+    Devices { index: 0 }
+}
+
+pub struct Devices {
+    index: usize,
+}
+
+impl Iterator for Devices {
+    type Item = PciDevice;
+
+    fn next(&mut self) -> Option<PciDevice> {
+        let registry = REGISTRY.lock();
+        let device = registry.devices[..registry.len]
+            .get(self.index)
+            .copied()??;
+        self.index += 1;
+        Some(device)
+    }
+}
+
+/// First function with the given vendor and device IDs.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    devices().find(|device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+/// Every function of the given class and subclass, and programming
+/// interface if one is given.
+pub fn find_class(class: u8, subclass: u8, prog_if: Option<u8>) -> impl Iterator<Item = PciDevice> {
+    devices().filter(move |device| {
+        device.class == class
+            && device.subclass == subclass
+            && prog_if.is_none_or(|prog_if| device.prog_if == prog_if)
+    })
+}
+
+pub fn device(address: PciAddress) -> Option<PciDevice> {
+    devices().find(|device| device.address == address)
+}
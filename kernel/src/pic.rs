@@ -0,0 +1,142 @@
+use crate::port::Port;
+use crate::sync::IrqSpinLock;
+
+// ```text
+//          master 8259                slave 8259
+//        ┌────────────┐            ┌────────────┐
+//  IRQ0 ─┤ 0          │      IRQ8 ─┤ 0          │
+//  IRQ1 ─┤ 1          │      IRQ9 ─┤ 1          │
+//        │ 2 ◄────────┼────────────┤ INT        │
+//  IRQ3 ─┤ 3          │     IRQ10 ─┤ 2          │
+//   ...  │ ...    INT ├─► CPU  ... │ ...        │
+//  IRQ7 ─┤ 7          │     IRQ15 ─┤ 7          │
+//        └────────────┘            └────────────┘
+// ```
+//
+// Both chips are remapped so that IRQ n arrives on vector PIC_OFFSET + n,
+// clear of the 32 exception vectors.
+
+pub const PIC_OFFSET: u8 = 32;
+pub const IRQ_LINES: u8 = 16;
+
+const CASCADE_IRQ: u8 = 2;
+
+const ICW1_ICW4: u8 = 0x01;
+const ICW1_INIT: u8 = 0x10;
+const ICW4_8086: u8 = 0x01;
+
+const CMD_END_OF_INTERRUPT: u8 = 0x20;
+const CMD_READ_ISR: u8 = 0x0B;
+
+struct Pic {
+    command: Port<u8>,
+    data: Port<u8>,
+}
+
+struct ChainedPics {
+    master: Pic,
+    slave: Pic,
+    mask: u16,
+}
+
+static PICS: IrqSpinLock<ChainedPics> = IrqSpinLock::new(ChainedPics {
+    master: Pic {
+        command: Port::new(0x20),
+        data: Port::new(0x21),
+    },
+    slave: Pic {
+        command: Port::new(0xA0),
+        data: Port::new(0xA1),
+    },
+    // everything masked except the cascade line
+    mask: !(1 << CASCADE_IRQ),
+});
+
+impl ChainedPics {
+    unsafe fn write_mask(&self) {
+        unsafe {
+            self.master.data.write(self.mask as u8);
+            self.slave.data.write((self.mask >> 8) as u8);
+        }
+    }
+}
+
+/// Writes a byte to an unused port, giving slow PICs time to settle.
+fn io_wait() {
+    unsafe { Port::<u8>::new(0x80).write(0) };
+}
+
+/// Remaps both PICs to [`PIC_OFFSET`] and masks every line.
+pub fn init() {
+    let pics = PICS.lock();
+    unsafe {
+        pics.master.command.write(ICW1_INIT | ICW1_ICW4);
+        io_wait();
+        pics.slave.command.write(ICW1_INIT | ICW1_ICW4);
+        io_wait();
+
+        pics.master.data.write(PIC_OFFSET);
+        io_wait();
+        pics.slave.data.write(PIC_OFFSET + 8);
+        io_wait();
+
+        // slave sits on master line 2; the slave's cascade identity is 2
+        pics.master.data.write(1 << CASCADE_IRQ);
+        io_wait();
+        pics.slave.data.write(CASCADE_IRQ);
+        io_wait();
+
+        pics.master.data.write(ICW4_8086);
+        io_wait();
+        pics.slave.data.write(ICW4_8086);
+        io_wait();
+
+        pics.write_mask();
+    }
+}
+
+pub fn unmask(irq: u8) {
+    assert!(irq < IRQ_LINES, "invalid IRQ line {irq}");
+    let mut pics = PICS.lock();
+    pics.mask &= !(1 << irq);
+    unsafe { pics.write_mask() };
+}
+
+pub fn mask(irq: u8) {
+    assert!(irq < IRQ_LINES, "invalid IRQ line {irq}");
+    let mut pics = PICS.lock();
+    pics.mask |= 1 << irq;
+    unsafe { pics.write_mask() };
+}
+
+/// Returns true if `irq` is a spurious interrupt that must not be acknowledged
+/// (apart from the cascade acknowledgement for a spurious IRQ15).
+pub fn is_spurious(irq: u8) -> bool {
+    let pics = PICS.lock();
+    let (pic, line) = match irq {
+        7 => (&pics.master, 7),
+        15 => (&pics.slave, 7),
+        _ => return false,
+    };
+    let in_service = unsafe {
+        pic.command.write(CMD_READ_ISR);
+        pic.command.read()
+    };
+    if in_service & (1 << line) != 0 {
+        return false;
+    }
+    if irq == 15 {
+        unsafe { pics.master.command.write(CMD_END_OF_INTERRUPT) };
+    }
+    true
+}
+
+pub fn end_of_interrupt(irq: u8) {
+    let pics = PICS.lock();
+    unsafe {
+        if irq >= 8 {
+            pics.slave.command.write(CMD_END_OF_INTERRUPT);
+        }
+        pics.master.command.write(CMD_END_OF_INTERRUPT);
+    }
+}
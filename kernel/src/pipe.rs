@@ -0,0 +1,200 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+
+use crate::devfs::{self, ioctl};
+use crate::sync::{self, SpinLock, WakerSlot};
+use crate::vfs::{self, File, FileFlags, FileType, Metadata, OpenFlags, VfsError};
+
+// Anonymous pipes: a bounded buffer with a read end and a write end, each an
+// open file that descriptors share like any other. A read waits for data
+// and a write for room, unless the end is non-blocking. Once the write end
+// is closed, reads drain what is left and then see end of file; once the
+// read end is closed, writes fail with BrokenPipe, as there are no signals
+// to send SIGPIPE with. Writes of up to PIPE_BUF bytes go in whole, never
+// split around another writer's, as POSIX asks.
+
+/// Bytes a pipe holds, as on Linux.
+pub const PIPE_SIZE: usize = 65536;
+/// Writes up to this size are atomic.
+pub const PIPE_BUF: usize = 4096;
+
+static NEXT_INO: AtomicU64 = AtomicU64::new(1);
+
+struct State {
+    buffer: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+struct Pipe {
+    ino: u64,
+    state: SpinLock<State>,
+    /// Woken when data comes in or the write end closes.
+    readable: WakerSlot,
+    /// Woken when room frees up or the read end closes.
+    writable: WakerSlot,
+}
+
+/// Makes a pipe and returns its read and write ends. NON_BLOCK in `flags`
+/// goes to both; like `pipe2`, CLOSE_ON_EXEC is for the caller's
+/// descriptors.
+pub fn pipe(flags: OpenFlags) -> (Arc<dyn File>, Arc<dyn File>) {
+    let pipe = Arc::new(Pipe {
+        ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+        state: SpinLock::new(State {
+            buffer: VecDeque::new(),
+            reader_open: true,
+            writer_open: true,
+        }),
+        readable: WakerSlot::new(),
+        writable: WakerSlot::new(),
+    });
+    let end = |access| {
+        let flags = match flags.contains(OpenFlags::NON_BLOCK) {
+            true => access | OpenFlags::NON_BLOCK,
+            false => access,
+        };
+        Arc::new(PipeEnd {
+            pipe: pipe.clone(),
+            flags: FileFlags::new(flags),
+        }) as Arc<dyn File>
+    };
+    (end(OpenFlags::READ), end(OpenFlags::WRITE))
+}
+
+/// One end of a pipe, READ or WRITE by its flags.
+struct PipeEnd {
+    pipe: Arc<Pipe>,
+    flags: FileFlags,
+}
+
+impl PipeEnd {
+    /// Runs `attempt` until it gets somewhere, waiting on `slot` in between,
+    /// or fails with WouldBlock if the end is non-blocking.
+    fn wait<T>(
+        &self,
+        slot: &WakerSlot,
+        mut attempt: impl FnMut() -> Option<vfs::Result<T>>,
+    ) -> vfs::Result<T> {
+        if let Some(result) = attempt() {
+            return result;
+        }
+        if self.flags.contains(OpenFlags::NON_BLOCK) {
+            return Err(VfsError::WouldBlock);
+        }
+        sync::block_on(poll_fn(|cx| {
+            slot.register(cx.waker());
+            match attempt() {
+                Some(result) => Poll::Ready(result),
+                None => Poll::Pending,
+            }
+        }))
+    }
+}
+
+impl File for PipeEnd {
+    fn read(&self, buf: &mut [u8]) -> vfs::Result<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(VfsError::BadDescriptor);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let read = self.wait(&self.pipe.readable, || {
+            let mut state = self.pipe.state.lock();
+            if state.buffer.is_empty() {
+                return (!state.writer_open).then_some(Ok(0));
+            }
+            let count = buf.len().min(state.buffer.len());
+            for (to, from) in buf.iter_mut().zip(state.buffer.drain(..count)) {
+                *to = from;
+            }
+            Some(Ok(count))
+        })?;
+        self.pipe.writable.wake();
+        Ok(read)
+    }
+
+    fn write(&self, data: &[u8]) -> vfs::Result<usize> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(VfsError::BadDescriptor);
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let mut written = 0;
+        while written < data.len() {
+            let step = self.wait(&self.pipe.writable, || {
+                let mut state = self.pipe.state.lock();
+                if !state.reader_open {
+                    return Some(Err(VfsError::BrokenPipe));
+                }
+                let room = PIPE_SIZE - state.buffer.len();
+                let rest = &data[written..];
+                if room == 0 || (data.len() <= PIPE_BUF && room < rest.len()) {
+                    return None;
+                }
+                let count = room.min(rest.len());
+                state.buffer.extend(&rest[..count]);
+                Some(Ok(count))
+            });
+            match step {
+                Ok(count) => {
+                    written += count;
+                    self.pipe.readable.wake();
+                }
+                // a write that got some of the way counts as a short one
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+
+    /// Answers FIONREAD with the bytes waiting in the pipe.
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> vfs::Result<usize> {
+        match request {
+            ioctl::FIONREAD => {
+                let waiting = self.pipe.state.lock().buffer.len() as i32;
+                devfs::put_arg(arg, &waiting.to_ne_bytes())
+            }
+            _ => Err(VfsError::NotTty),
+        }
+    }
+
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        Ok(Metadata {
+            ino: self.pipe.ino,
+            kind: FileType::Fifo,
+            mode: 0o600,
+            links: 1,
+            ..Metadata::default()
+        })
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        let mut state = self.pipe.state.lock();
+        if self.flags.contains(OpenFlags::READ) {
+            state.reader_open = false;
+            drop(state);
+            self.pipe.writable.wake();
+        } else {
+            state.writer_open = false;
+            drop(state);
+            self.pipe.readable.wake();
+        }
+    }
+}
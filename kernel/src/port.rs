@@ -0,0 +1,92 @@
+use core::arch::asm;
+use core::marker::PhantomData;
+
+/// A value that can be moved through an x86 I/O port.
+pub trait PortValue: Copy {
+    /// # Safety
+    ///
+    /// See [`Port::read`].
+    unsafe fn read_from(port: u16) -> Self;
+
+    /// # Safety
+    ///
+    /// See [`Port::write`].
+    unsafe fn write_to(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+    unsafe fn read_from(port: u16) -> u8 {
+        let value: u8;
+        unsafe {
+            asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags))
+        };
+        value
+    }
+
+    unsafe fn write_to(port: u16, value: u8) {
+        unsafe {
+            asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags))
+        };
+    }
+}
+
+impl PortValue for u16 {
+    unsafe fn read_from(port: u16) -> u16 {
+        let value: u16;
+        unsafe {
+            asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags))
+        };
+        value
+    }
+
+    unsafe fn write_to(port: u16, value: u16) {
+        unsafe {
+            asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags))
+        };
+    }
+}
+
+impl PortValue for u32 {
+    unsafe fn read_from(port: u16) -> u32 {
+        let value: u32;
+        unsafe {
+            asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags))
+        };
+        value
+    }
+
+    unsafe fn write_to(port: u16, value: u32) {
+        unsafe {
+            asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags))
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Port<T> {
+    port: u16,
+    phantom: PhantomData<T>,
+}
+
+impl<T: PortValue> Port<T> {
+    pub const fn new(port: u16) -> Self {
+        Port {
+            port,
+            phantom: PhantomData,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// Reading an I/O port can have side effects on the device behind it.
+    pub unsafe fn read(&self) -> T {
+        unsafe { T::read_from(self.port) }
+    }
+
+    /// # Safety
+    ///
+    /// Writing an I/O port can have arbitrary side effects on the device behind it.
+    pub unsafe fn write(&self, value: T) {
+        unsafe { T::write_to(self.port, value) }
+    }
+}
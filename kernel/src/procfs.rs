@@ -0,0 +1,461 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt::{self, Write};
+
+use crate::sync::SpinLock;
+use crate::vfs::{
+    self, DirEntry, File, FileFlags, FileSystem, FileType, Inode, Metadata, OpenFlags, SeekFrom,
+    VfsError,
+};
+use crate::{apic, dmesg, heap, interrupts, memory, page_cache, pci, pic, task, time};
+
+// A read-only directory of text files about the running kernel, usually
+// mounted on /proc. Nothing is stored: a file's text is generated when it is
+// opened, and reads of that open file see the same snapshot however they
+// split it up. As on Linux, the files report a size of 0.
+
+struct Entry {
+    name: &'static str,
+    generate: fn(&mut String) -> fmt::Result,
+}
+
+const ENTRIES: [Entry; 9] = [
+    Entry {
+        name: "cmdline",
+        generate: cmdline,
+    },
+    Entry {
+        name: "cpuinfo",
+        generate: cpuinfo,
+    },
+    Entry {
+        name: "dmesg",
+        generate: dmesg,
+    },
+    Entry {
+        name: "interrupts",
+        generate: interrupts,
+    },
+    Entry {
+        name: "meminfo",
+        generate: meminfo,
+    },
+    Entry {
+        name: "mounts",
+        generate: mounts,
+    },
+    Entry {
+        name: "pci",
+        generate: pci,
+    },
+    Entry {
+        name: "tasks",
+        generate: tasks,
+    },
+    Entry {
+        name: "uptime",
+        generate: uptime,
+    },
+];
+
+/// The bootloader passes no command line, so this is the one the kernel was
+/// built with, if any.
+fn cmdline(out: &mut String) -> fmt::Result {
+    writeln!(out, "{}", option_env!("KARKINOS_CMDLINE").unwrap_or(""))
+}
+
+/// Feature flags by CPUID leaf and register bit, under their Linux names.
+const EDX_1_FLAGS: [(u32, &str); 24] = [
+    (0, "fpu"),
+    (1, "vme"),
+    (2, "de"),
+    (3, "pse"),
+    (4, "tsc"),
+    (5, "msr"),
+    (6, "pae"),
+    (7, "mce"),
+    (8, "cx8"),
+    (9, "apic"),
+    (11, "sep"),
+    (12, "mtrr"),
+    (13, "pge"),
+    (14, "mca"),
+    (15, "cmov"),
+    (16, "pat"),
+    (17, "pse36"),
+    (19, "clflush"),
+    (23, "mmx"),
+    (24, "fxsr"),
+    (25, "sse"),
+    (26, "sse2"),
+    (27, "ss"),
+    (28, "ht"),
+];
+const ECX_1_FLAGS: [(u32, &str); 17] = [
+    (0, "pni"),
+    (1, "pclmulqdq"),
+    (3, "monitor"),
+    (9, "ssse3"),
+    (12, "fma"),
+    (13, "cx16"),
+    (19, "sse4_1"),
+    (20, "sse4_2"),
+    (21, "x2apic"),
+    (22, "movbe"),
+    (23, "popcnt"),
+    (25, "aes"),
+    (26, "xsave"),
+    (28, "avx"),
+    (29, "f16c"),
+    (30, "rdrand"),
+    (31, "hypervisor"),
+];
+const EDX_EXT_FLAGS: [(u32, &str); 5] = [
+    (11, "syscall"),
+    (20, "nx"),
+    (26, "pdpe1gb"),
+    (27, "rdtscp"),
+    (29, "lm"),
+];
+const EBX_7_FLAGS: [(u32, &str); 11] = [
+    (0, "fsgsbase"),
+    (3, "bmi1"),
+    (5, "avx2"),
+    (7, "smep"),
+    (8, "bmi2"),
+    (9, "erms"),
+    (10, "invpcid"),
+    (16, "avx512f"),
+    (18, "rdseed"),
+    (19, "adx"),
+    (20, "smap"),
+];
+
+fn write_flags(out: &mut String, register: u32, flags: &[(u32, &str)]) -> fmt::Result {
+    for (bit, name) in flags {
+        if register & (1 << bit) != 0 {
+            write!(out, " {}", name)?;
+        }
+    }
+    Ok(())
+}
+
+/// The boot CPU, the only one brought up.
+fn cpuinfo(out: &mut String) -> fmt::Result {
+    let leaf0 = __cpuid(0);
+    let mut vendor = [0; 12];
+    for (chunk, register) in vendor
+        .as_chunks_mut::<4>()
+        .0
+        .iter_mut()
+        .zip([leaf0.ebx, leaf0.edx, leaf0.ecx])
+    {
+        *chunk = register.to_le_bytes();
+    }
+
+    let leaf1 = __cpuid(1);
+    let mut family = (leaf1.eax >> 8) & 0xf;
+    let mut model = (leaf1.eax >> 4) & 0xf;
+    if family == 0xf {
+        family += (leaf1.eax >> 20) & 0xff;
+    }
+    if family == 6 || family >= 0xf {
+        model |= ((leaf1.eax >> 16) & 0xf) << 4;
+    }
+
+    let max_extended = __cpuid(0x8000_0000).eax;
+    let mut brand = [0; 48];
+    if max_extended >= 0x8000_0004 {
+        for (index, chunk) in brand.as_chunks_mut::<16>().0.iter_mut().enumerate() {
+            let leaf = __cpuid(0x8000_0002 + index as u32);
+            for (bytes, register) in chunk
+                .as_chunks_mut::<4>()
+                .0
+                .iter_mut()
+                .zip([leaf.eax, leaf.ebx, leaf.ecx, leaf.edx])
+            {
+                *bytes = register.to_le_bytes();
+            }
+        }
+    }
+    let brand = core::str::from_utf8(&brand).unwrap_or("");
+    let hz = time::tsc_frequency();
+
+    writeln!(out, "processor\t: 0")?;
+    writeln!(
+        out,
+        "vendor_id\t: {}",
+        core::str::from_utf8(&vendor).unwrap_or("unknown")
+    )?;
+    writeln!(out, "cpu family\t: {}", family)?;
+    writeln!(out, "model\t\t: {}", model)?;
+    writeln!(out, "model name\t: {}", brand.trim_matches(['\0', ' ']))?;
+    writeln!(out, "stepping\t: {}", leaf1.eax & 0xf)?;
+    writeln!(
+        out,
+        "cpu MHz\t\t: {}.{:03}",
+        hz / 1_000_000,
+        hz / 1000 % 1000
+    )?;
+    write!(out, "flags\t\t:")?;
+    write_flags(out, leaf1.edx, &EDX_1_FLAGS)?;
+    write_flags(out, leaf1.ecx, &ECX_1_FLAGS)?;
+    if max_extended >= 0x8000_0001 {
+        write_flags(out, __cpuid(0x8000_0001).edx, &EDX_EXT_FLAGS)?;
+    }
+    if leaf0.eax >= 7 {
+        write_flags(out, __cpuid_count(7, 0).ebx, &EBX_7_FLAGS)?;
+    }
+    writeln!(out)
+}
+
+fn dmesg(out: &mut String) -> fmt::Result {
+    dmesg::print_all(out)
+}
+
+/// Every vector with a fixed use, and the dynamic ones that have fired.
+fn interrupts(out: &mut String) -> fmt::Result {
+    writeln!(out, "vector       count  source")?;
+    let mut line = |vector: u8, source: fmt::Arguments| {
+        writeln!(
+            out,
+            "  {:#04x} {:>11}  {}",
+            vector,
+            interrupts::count(vector),
+            source
+        )
+    };
+    line(2, format_args!("NMI"))?;
+    line(3, format_args!("breakpoint"))?;
+    line(14, format_args!("page fault"))?;
+    for irq in 0..pic::IRQ_LINES {
+        line(pic::PIC_OFFSET + irq, format_args!("IRQ {}", irq))?;
+    }
+    let dynamic = interrupts::FIRST_DYNAMIC_VECTOR as usize;
+    for vector in dynamic..dynamic + interrupts::DYNAMIC_VECTORS {
+        if interrupts::count(vector as u8) != 0 {
+            line(vector as u8, format_args!("dynamic"))?;
+        }
+    }
+    line(apic::SPURIOUS_VECTOR, format_args!("spurious"))
+}
+
+fn meminfo(out: &mut String) -> fmt::Result {
+    let heap = heap::stats();
+    let fields = [
+        ("MemTotal:", memory::total_memory()),
+        ("MemFree:", memory::free_memory()),
+        ("Cached:", page_cache::cached_bytes()),
+        ("HeapSize:", heap.size as u64),
+        ("HeapUsed:", heap.used as u64),
+    ];
+    for (name, bytes) in fields {
+        writeln!(out, "{:<12}{:>10} kB", name, bytes / 1024)?;
+    }
+    Ok(())
+}
+
+/// In the layout of Linux's `/proc/mounts`.
+fn mounts(out: &mut String) -> fmt::Result {
+    for mount in vfs::mounts() {
+        let mode = if mount.read_only { "ro" } else { "rw" };
+        writeln!(
+            out,
+            "{} {} {} {} 0 0",
+            mount.source, mount.path, mount.fs_type, mode
+        )?;
+    }
+    Ok(())
+}
+
+fn pci(out: &mut String) -> fmt::Result {
+    for device in pci::devices() {
+        writeln!(out, "{}", device)?;
+    }
+    Ok(())
+}
+
+fn tasks(out: &mut String) -> fmt::Result {
+    writeln!(out, "   ID  STATE         STARTED  NAME")?;
+    for task in task::tasks() {
+        let started = task.started();
+        writeln!(
+            out,
+            "{:>5}  {:<8} {:>8}.{:03}  {}",
+            task.id(),
+            task.state(),
+            started.as_secs(),
+            started.subsec_millis(),
+            task.name()
+        )?;
+    }
+    Ok(())
+}
+
+/// Seconds since boot, to the hundredth.
+fn uptime(out: &mut String) -> fmt::Result {
+    let uptime = time::uptime();
+    writeln!(
+        out,
+        "{}.{:02}",
+        uptime.as_secs(),
+        uptime.subsec_millis() / 10
+    )
+}
+
+/// Inode numbers: 1 is the root, entries follow in table order.
+fn ino(index: usize) -> u64 {
+    index as u64 + 2
+}
+
+/// One of the generated files.
+struct ProcNode {
+    index: usize,
+}
+
+impl Inode for ProcNode {
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        Ok(Metadata {
+            ino: ino(self.index),
+            kind: FileType::Regular,
+            mode: 0o444,
+            links: 1,
+            ..Metadata::default()
+        })
+    }
+
+    fn open(&self, flags: OpenFlags) -> vfs::Result<Option<Arc<dyn File>>> {
+        let mut text = String::new();
+        (ENTRIES[self.index].generate)(&mut text).map_err(|_| VfsError::Io)?;
+        Ok(Some(Arc::new(ProcFile {
+            index: self.index,
+            flags: FileFlags::new(flags),
+            text,
+            position: SpinLock::new(0),
+        })))
+    }
+}
+
+/// An open file, with the text generated when it was opened.
+struct ProcFile {
+    index: usize,
+    flags: FileFlags,
+    text: String,
+    position: SpinLock<u64>,
+}
+
+impl File for ProcFile {
+    fn read(&self, buf: &mut [u8]) -> vfs::Result<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(VfsError::BadDescriptor);
+        }
+        let mut position = self.position.lock();
+        let rest = self
+            .text
+            .as_bytes()
+            .get(*position as usize..)
+            .unwrap_or(&[]);
+        let read = buf.len().min(rest.len());
+        buf[..read].copy_from_slice(&rest[..read]);
+        *position += read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, _buf: &[u8]) -> vfs::Result<usize> {
+        Err(VfsError::BadDescriptor)
+    }
+
+    fn seek(&self, from: SeekFrom) -> vfs::Result<u64> {
+        let mut position = self.position.lock();
+        let (base, delta) = match from {
+            SeekFrom::Start(offset) => (0, offset as i128),
+            SeekFrom::Current(delta) => (*position, delta as i128),
+            SeekFrom::End(delta) => (self.text.len() as u64, delta as i128),
+        };
+        let target = base as i128 + delta;
+        if !(0..=i64::MAX as i128).contains(&target) {
+            return Err(VfsError::InvalidArgument);
+        }
+        *position = target as u64;
+        Ok(*position)
+    }
+
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        ProcNode { index: self.index }.metadata()
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+}
+
+/// The one directory of procfs.
+struct Root;
+
+impl Inode for Root {
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        Ok(Metadata {
+            ino: 1,
+            kind: FileType::Directory,
+            mode: 0o555,
+            size: ENTRIES.len() as u64,
+            links: 2,
+            ..Metadata::default()
+        })
+    }
+
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let index = ENTRIES
+            .iter()
+            .position(|entry| entry.name == name)
+            .ok_or(VfsError::NotFound)?;
+        Ok(Arc::new(ProcNode { index }))
+    }
+
+    fn read_dir(&self, cursor: u64) -> vfs::Result<Option<(DirEntry, u64)>> {
+        let Some(entry) = ENTRIES.get(cursor as usize) else {
+            return Ok(None);
+        };
+        let entry = DirEntry {
+            ino: ino(cursor as usize),
+            kind: FileType::Regular,
+            name: entry.name.into(),
+        };
+        Ok(Some((entry, cursor + 1)))
+    }
+}
+
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+/// Mounts procfs on `/proc`.
+pub fn init() {
+    match vfs::mkdir("/proc", 0o555) {
+        Ok(()) | Err(VfsError::Exists) => {}
+        Err(err) => {
+            log::error!("procfs: cannot create /proc: {}", err);
+            return;
+        }
+    }
+    if let Err(err) = vfs::mount("/proc", "proc", Arc::new(ProcFs)) {
+        log::error!("procfs: cannot mount /proc: {}", err);
+    }
+}
@@ -0,0 +1,59 @@
+use core::arch::x86_64::{__cpuid, _rdrand64_step};
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use crate::time;
+
+// Random numbers come from RDRAND where the CPU has it. Elsewhere a
+// splitmix64 sequence stands in, stepped once per number and stirred with
+// the TSC, so that numbers differ between boots and between calls. There is
+// no entropy pool, so neither is fit for keys.
+
+/// Golden ratio increment of splitmix64.
+const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+/// CPUID leaf 1, ECX: RDRAND is available.
+const CPUID_RDRAND: u32 = 1 << 30;
+/// RDRAND can fail while the hardware reseeds; Intel suggests 10 tries.
+const RDRAND_RETRIES: usize = 10;
+
+static STATE: AtomicU64 = AtomicU64::new(0);
+/// 0 until checked, then 1 without RDRAND and 2 with it.
+static HAS_RDRAND: AtomicU8 = AtomicU8::new(0);
+
+fn has_rdrand() -> bool {
+    match HAS_RDRAND.load(Ordering::Relaxed) {
+        0 => {
+            let present = __cpuid(1).ecx & CPUID_RDRAND != 0;
+            HAS_RDRAND.store(if present { 2 } else { 1 }, Ordering::Relaxed);
+            present
+        }
+        value => value == 2,
+    }
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0;
+    (0..RDRAND_RETRIES).find_map(|_| (_rdrand64_step(&mut value) == 1).then_some(value))
+}
+
+fn splitmix() -> u64 {
+    let mut z = STATE.fetch_add(GAMMA, Ordering::Relaxed) ^ time::tsc() ^ time::now();
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+pub fn next_u64() -> u64 {
+    if has_rdrand()
+        && let Some(value) = unsafe { rdrand() }
+    {
+        return value;
+    }
+    splitmix()
+}
+
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&next_u64().to_le_bytes()[..chunk.len()]);
+    }
+}
@@ -0,0 +1,485 @@
+// Decoding of PS/2 scancode sets 1 and 2 into physical keys.
+//
+// Keys are named after their position on a US keyboard; which character
+// they produce is up to the keymap.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    PrintScreen,
+    ScrollLock,
+    Pause,
+
+    Grave,
+    Digit1,
+    Digit2,
+    Digit3,
+    Digit4,
+    Digit5,
+    Digit6,
+    Digit7,
+    Digit8,
+    Digit9,
+    Digit0,
+    Minus,
+    Equals,
+    Backspace,
+
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    LeftBracket,
+    RightBracket,
+    /// Above Enter on ANSI boards, left of it on ISO boards.
+    Backslash,
+
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    Semicolon,
+    Apostrophe,
+    Enter,
+
+    LeftShift,
+    /// The extra key between left shift and Z on ISO boards.
+    NonUsBackslash,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+
+    LeftCtrl,
+    LeftGui,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightGui,
+    Menu,
+    RightCtrl,
+
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Up,
+    Down,
+    Left,
+    Right,
+
+    NumLock,
+    KeypadDivide,
+    KeypadMultiply,
+    KeypadMinus,
+    KeypadPlus,
+    KeypadEnter,
+    KeypadPeriod,
+    Keypad0,
+    Keypad1,
+    Keypad2,
+    Keypad3,
+    Keypad4,
+    Keypad5,
+    Keypad6,
+    Keypad7,
+    Keypad8,
+    Keypad9,
+}
+
+impl KeyCode {
+    pub const COUNT: usize = KeyCode::Keypad9 as usize + 1;
+}
+
+const EXTENDED: u8 = 0xE0;
+const PAUSE: u8 = 0xE1;
+const SET1_RELEASE: u8 = 0x80;
+const SET2_RELEASE: u8 = 0xF0;
+
+/// Bytes following 0xE1 in the pause sequence, which has no release code:
+/// `E1 1D 45 E1 9D C5` in set 1 and `E1 14 77 E1 F0 14 F0 77` in set 2.
+const SET1_PAUSE_TAIL: u8 = 5;
+const SET2_PAUSE_TAIL: u8 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    Extended,
+    /// Set 2 only: after 0xF0.
+    Release,
+    /// Set 2 only: after 0xE0 0xF0.
+    ExtendedRelease,
+    /// Skipping the rest of a pause sequence.
+    Pause(u8),
+}
+
+/// Byte-at-a-time state machine for one scancode set.
+pub struct Decoder {
+    set: ScancodeSet,
+    state: State,
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Decoder {
+            set,
+            state: State::Start,
+        }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// Forgets any partial sequence, e.g. after bytes were lost.
+    pub fn reset(&mut self) {
+        self.state = State::Start;
+    }
+
+    /// Consumes one byte and reports the key events it completes.
+    ///
+    /// Most bytes complete at most one event, but the end of a pause sequence
+    /// reports both the press and the release. Fake shifts, which some
+    /// keyboards wrap around navigation keys, are dropped.
+    pub fn feed(&mut self, byte: u8, mut emit: impl FnMut(KeyCode, KeyState)) {
+        let (key, state) = match (self.set, self.state, byte) {
+            (_, State::Pause(remaining), _) => {
+                if remaining > 1 {
+                    self.state = State::Pause(remaining - 1);
+                } else {
+                    self.state = State::Start;
+                    emit(KeyCode::Pause, KeyState::Pressed);
+                    emit(KeyCode::Pause, KeyState::Released);
+                }
+                return;
+            }
+            (ScancodeSet::Set1, State::Start, PAUSE) => {
+                self.state = State::Pause(SET1_PAUSE_TAIL);
+                return;
+            }
+            (ScancodeSet::Set2, State::Start, PAUSE) => {
+                self.state = State::Pause(SET2_PAUSE_TAIL);
+                return;
+            }
+            (_, State::Start, EXTENDED) => {
+                self.state = State::Extended;
+                return;
+            }
+            (ScancodeSet::Set2, State::Start, SET2_RELEASE) => {
+                self.state = State::Release;
+                return;
+            }
+            (ScancodeSet::Set2, State::Extended, SET2_RELEASE) => {
+                self.state = State::ExtendedRelease;
+                return;
+            }
+            (ScancodeSet::Set1, state, byte) => {
+                let extended = state == State::Extended;
+                let key_state = match byte & SET1_RELEASE {
+                    0 => KeyState::Pressed,
+                    _ => KeyState::Released,
+                };
+                (set1_key(byte & !SET1_RELEASE, extended), key_state)
+            }
+            (ScancodeSet::Set2, State::Start, byte) => (set2_key(byte, false), KeyState::Pressed),
+            (ScancodeSet::Set2, State::Extended, byte) => (set2_key(byte, true), KeyState::Pressed),
+            (ScancodeSet::Set2, State::Release, byte) => {
+                (set2_key(byte, false), KeyState::Released)
+            }
+            (ScancodeSet::Set2, State::ExtendedRelease, byte) => {
+                (set2_key(byte, true), KeyState::Released)
+            }
+        };
+        self.state = State::Start;
+        if let Some(key) = key {
+            emit(key, state);
+        }
+    }
+}
+
+fn set1_key(code: u8, extended: bool) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    if extended {
+        return Some(match code {
+            0x1C => KeypadEnter,
+            0x1D => RightCtrl,
+            0x35 => KeypadDivide,
+            0x37 => PrintScreen,
+            0x38 => RightAlt,
+            // ctrl + pause
+            0x46 => Pause,
+            0x47 => Home,
+            0x48 => Up,
+            0x49 => PageUp,
+            0x4B => Left,
+            0x4D => Right,
+            0x4F => End,
+            0x50 => Down,
+            0x51 => PageDown,
+            0x52 => Insert,
+            0x53 => Delete,
+            0x5B => LeftGui,
+            0x5C => RightGui,
+            0x5D => Menu,
+            _ => return None,
+        });
+    }
+
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Digit1,
+        0x03 => Digit2,
+        0x04 => Digit3,
+        0x05 => Digit4,
+        0x06 => Digit5,
+        0x07 => Digit6,
+        0x08 => Digit7,
+        0x09 => Digit8,
+        0x0A => Digit9,
+        0x0B => Digit0,
+        0x0C => Minus,
+        0x0D => Equals,
+        0x0E => Backspace,
+        0x0F => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1A => LeftBracket,
+        0x1B => RightBracket,
+        0x1C => Enter,
+        0x1D => LeftCtrl,
+        0x1E => A,
+        0x1F => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => Semicolon,
+        0x28 => Apostrophe,
+        0x29 => Grave,
+        0x2A => LeftShift,
+        0x2B => Backslash,
+        0x2C => Z,
+        0x2D => X,
+        0x2E => C,
+        0x2F => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => KeypadMultiply,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3A => CapsLock,
+        0x3B => F1,
+        0x3C => F2,
+        0x3D => F3,
+        0x3E => F4,
+        0x3F => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Keypad7,
+        0x48 => Keypad8,
+        0x49 => Keypad9,
+        0x4A => KeypadMinus,
+        0x4B => Keypad4,
+        0x4C => Keypad5,
+        0x4D => Keypad6,
+        0x4E => KeypadPlus,
+        0x4F => Keypad1,
+        0x50 => Keypad2,
+        0x51 => Keypad3,
+        0x52 => Keypad0,
+        0x53 => KeypadPeriod,
+        0x56 => NonUsBackslash,
+        0x57 => F11,
+        0x58 => F12,
+        _ => return None,
+    })
+}
+
+fn set2_key(code: u8, extended: bool) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    if extended {
+        return Some(match code {
+            0x11 => RightAlt,
+            0x14 => RightCtrl,
+            0x1F => LeftGui,
+            0x27 => RightGui,
+            0x2F => Menu,
+            0x4A => KeypadDivide,
+            0x5A => KeypadEnter,
+            0x69 => End,
+            0x6B => Left,
+            0x6C => Home,
+            0x70 => Insert,
+            0x71 => Delete,
+            0x72 => Down,
+            0x74 => Right,
+            0x75 => Up,
+            0x7A => PageDown,
+            0x7C => PrintScreen,
+            0x7D => PageUp,
+            // ctrl + pause
+            0x7E => Pause,
+            _ => return None,
+        });
+    }
+
+    Some(match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => Grave,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x14 => LeftCtrl,
+        0x15 => Q,
+        0x16 => Digit1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Digit2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Digit4,
+        0x26 => Digit3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Digit5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Digit6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Digit7,
+        0x3E => Digit8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Digit0,
+        0x46 => Digit9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => Semicolon,
+        0x4D => P,
+        0x4E => Minus,
+        0x52 => Apostrophe,
+        0x54 => LeftBracket,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => RightBracket,
+        0x5D => Backslash,
+        0x61 => NonUsBackslash,
+        0x66 => Backspace,
+        0x69 => Keypad1,
+        0x6B => Keypad4,
+        0x6C => Keypad7,
+        0x70 => Keypad0,
+        0x71 => KeypadPeriod,
+        0x72 => Keypad2,
+        0x73 => Keypad5,
+        0x74 => Keypad6,
+        0x75 => Keypad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => KeypadPlus,
+        0x7A => Keypad3,
+        0x7B => KeypadMinus,
+        0x7C => KeypadMultiply,
+        0x7D => Keypad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    })
+}
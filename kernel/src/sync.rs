@@ -0,0 +1,245 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use crate::interrupts;
+
+/// A plain test-and-set spinlock.
+///
+/// Must not be taken from interrupt context if the same lock can be held by
+/// the interrupted code; use [`IrqSpinLock`] for that.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| SpinLockGuard { lock: self })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Releases the lock regardless of who holds it.
+    ///
+    /// # Safety
+    ///
+    /// The previous holder must never touch the data again, e.g. because
+    /// the kernel is panicking and it will never be resumed.
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A spinlock that keeps maskable interrupts disabled while it is held.
+///
+/// An IRQ handler can therefore never spin on a lock owned by the code it
+/// interrupted. NMIs and exceptions still can, so code reachable from them
+/// has to use [`IrqSpinLock::try_lock`].
+pub struct IrqSpinLock<T> {
+    inner: SpinLock<T>,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        IrqSpinLock {
+            inner: SpinLock::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSpinLockGuard {
+            guard: Some(self.inner.lock()),
+            were_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinLockGuard {
+                guard: Some(guard),
+                were_enabled,
+            }),
+            None => {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// # Safety
+    ///
+    /// See [`SpinLock::force_unlock`].
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() }
+    }
+}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    guard: Option<SpinLockGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // release the lock before interrupts can fire again
+        self.guard.take();
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Holds the waker of the one task waiting for an event raised from an
+/// interrupt handler.
+pub struct WakerSlot {
+    waker: IrqSpinLock<Option<Waker>>,
+}
+
+impl WakerSlot {
+    pub const fn new() -> Self {
+        WakerSlot {
+            waker: IrqSpinLock::new(None),
+        }
+    }
+
+    /// Replaces the stored waker. Callers have to re-check their condition
+    /// afterwards, as the event may have fired just before.
+    pub fn register(&self, waker: &Waker) {
+        let mut slot = self.waker.lock();
+        match &*slot {
+            Some(current) if current.will_wake(waker) => {}
+            _ => *slot = Some(waker.clone()),
+        }
+    }
+
+    pub fn wake(&self) {
+        // wake outside the lock, the waker may run arbitrary code
+        let waker = self.waker.lock().take();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for WakerSlot {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Set by the waker [`block_on`] hands out.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+const WOKEN_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_woken, wake_woken, wake_woken, drop_woken);
+
+fn clone_woken(_: *const ()) -> RawWaker {
+    RawWaker::new(core::ptr::null(), &WOKEN_VTABLE)
+}
+
+fn wake_woken(_: *const ()) {
+    WOKEN.store(true, Ordering::Release);
+}
+
+fn drop_woken(_: *const ()) {}
+
+/// Runs `future` to completion on the current CPU, halting between polls
+/// until an interrupt or a wakeup comes along.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = unsafe { Waker::from_raw(clone_woken(core::ptr::null())) };
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        WOKEN.store(false, Ordering::Relaxed);
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        // with interrupts off nothing could end the halt, so just poll again
+        if !interrupts::are_enabled() {
+            core::hint::spin_loop();
+            continue;
+        }
+        interrupts::disable();
+        match WOKEN.load(Ordering::Acquire) {
+            true => interrupts::enable(),
+            false => interrupts::enable_and_wait(),
+        }
+    }
+}
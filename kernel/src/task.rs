@@ -0,0 +1,94 @@
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::fd::FdTable;
+use crate::sync::SpinLock;
+use crate::time;
+
+// The list of tasks the kernel knows about. There is no scheduler yet, so
+// the only task is the thread the kernel booted on, which is always the
+// current one; this is where its state lives as it grows, such as its file
+// descriptors.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    Ready,
+    Blocked,
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            TaskState::Running => "running",
+            TaskState::Ready => "ready",
+            TaskState::Blocked => "blocked",
+        })
+    }
+}
+
+pub struct Task {
+    id: u64,
+    name: String,
+    /// Uptime when the task was created.
+    started: Duration,
+    state: SpinLock<TaskState>,
+    files: FdTable,
+}
+
+impl Task {
+    fn new(name: &str, state: TaskState) -> Arc<Task> {
+        Arc::new(Task {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            name: name.into(),
+            started: time::uptime(),
+            state: SpinLock::new(state),
+            files: FdTable::new(),
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn started(&self) -> Duration {
+        self.started
+    }
+
+    pub fn state(&self) -> TaskState {
+        *self.state.lock()
+    }
+
+    pub fn files(&self) -> &FdTable {
+        &self.files
+    }
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static TASKS: SpinLock<Vec<Arc<Task>>> = SpinLock::new(Vec::new());
+static CURRENT: SpinLock<Option<Arc<Task>>> = SpinLock::new(None);
+
+/// Makes the boot thread task 0.
+pub fn init() {
+    let kernel = Task::new("kernel", TaskState::Running);
+    TASKS.lock().push(kernel.clone());
+    *CURRENT.lock() = Some(kernel);
+}
+
+/// The task running on this CPU.
+pub fn current() -> Arc<Task> {
+    CURRENT.lock().clone().expect("task::init has not run")
+}
+
+/// Every task, by id.
+pub fn tasks() -> Vec<Arc<Task>> {
+    TASKS.lock().clone()
+}
@@ -0,0 +1,75 @@
+use crate::ahci::{self, AhciDisk, AhciError, AhciOp, SECTOR_SIZE};
+use crate::memory;
+use crate::tests::block::pattern;
+use crate::*;
+
+/// The disk `make test-q35` hangs off the second AHCI port; absent on the
+/// default PC machine.
+fn scratch_disk() -> Option<AhciDisk> {
+    let disk = ahci::disks().find(|disk| disk.info().serial() == "KTSCRATCH");
+    if disk.is_none() {
+        tests::skip("no AHCI scratch disk");
+    }
+    disk
+}
+
+ktest!(
+    fn read_after_write() {
+        let Some(disk) = scratch_disk() else {
+            return;
+        };
+        let info = disk.info();
+        assert!(info.sectors >= 64);
+
+        let mut data = [0u8; 8 * SECTOR_SIZE];
+        pattern(5, &mut data);
+        disk.write(5, &data).unwrap();
+        disk.flush().unwrap();
+        let mut back = [0u8; 8 * SECTOR_SIZE];
+        disk.read(5, &mut back).unwrap();
+        assert_eq!(data, back);
+
+        let last = info.sectors - 1;
+        disk.write(last, &data[..SECTOR_SIZE]).unwrap();
+        assert_eq!(disk.read(last, &mut back), Err(AhciError::OutOfRange));
+    }
+);
+
+ktest!(
+    fn queued_commands() {
+        let Some(disk) = scratch_disk() else {
+            return;
+        };
+        // NCQ drives take several at once, others queue them in the slots
+        const REQUESTS: usize = 4;
+        let buffer = memory::allocate_frames(REQUESTS).unwrap();
+        let page = memory::FRAME_SIZE as usize;
+        let bytes = unsafe { memory::phys_slice_mut(buffer, REQUESTS * page) };
+        for (i, chunk) in bytes.chunks_mut(page).enumerate() {
+            pattern(200 + i as u64, chunk);
+        }
+        let requests = core::array::from_fn::<_, REQUESTS, _>(|i| {
+            disk.submit(AhciOp::Write {
+                lba: 200 + (i * page / SECTOR_SIZE) as u64,
+                phys: buffer + (i * page) as u64,
+                len: page as u32,
+            })
+            .unwrap()
+        });
+        for request in requests {
+            disk.wait(request).unwrap();
+        }
+        assert_eq!(
+            disk.submit(AhciOp::Flush).map(|request| disk.wait(request)),
+            Ok(Ok(()))
+        );
+
+        let mut back = [0u8; REQUESTS * 4096];
+        disk.read(200, &mut back).unwrap();
+        let bytes = unsafe { memory::phys_slice_mut(buffer, REQUESTS * page) };
+        assert!(back[..] == bytes[..]);
+        memory::free_frames(buffer, REQUESTS);
+    }
+);
+
+register_tests!(read_after_write, queued_commands);
@@ -0,0 +1,53 @@
+use crate::ata::{self, AtaMode, Channel, Position, SECTOR_SIZE};
+use crate::*;
+
+ktest!(
+    fn read_boot_disk() {
+        // the test image sits on the primary master unless the machine has
+        // no IDE controller at all
+        let Some(disk) = ata::drives().next() else {
+            tests::skip("no IDE drive");
+            return;
+        };
+        let info = disk.info();
+        assert_eq!(
+            (info.channel, info.position),
+            (Channel::Primary, Position::Master)
+        );
+        assert!(info.sectors > 1);
+
+        let mut mbr = [0u8; SECTOR_SIZE];
+        disk.read(0, &mut mbr).unwrap();
+        assert_eq!(mbr[510..], [0x55, 0xAA]);
+
+        // both modes see the same data
+        let mut polled = [0u8; 4 * SECTOR_SIZE];
+        let mut irq = [0u8; 4 * SECTOR_SIZE];
+        assert!(ata::set_mode(Channel::Primary, AtaMode::Polling));
+        disk.read(0, &mut polled).unwrap();
+        assert!(ata::set_mode(Channel::Primary, AtaMode::Interrupt));
+        disk.read(0, &mut irq).unwrap();
+        assert!(polled == irq);
+        assert_eq!(polled[..SECTOR_SIZE], mbr);
+
+        assert!(disk.read(info.sectors, &mut mbr).is_err());
+    }
+);
+
+ktest!(
+    fn rewrite_sector_in_place() {
+        let Some(disk) = ata::drives().next() else {
+            return;
+        };
+        // write back what is already there, so the boot disk stays intact
+        let mut original = [0u8; SECTOR_SIZE];
+        disk.read(0, &mut original).unwrap();
+        disk.write(0, &original).unwrap();
+        disk.flush().unwrap();
+        let mut back = [0u8; SECTOR_SIZE];
+        disk.read(0, &mut back).unwrap();
+        assert_eq!(original, back);
+    }
+);
+
+register_tests!(read_boot_disk, rewrite_sector_in_place);
@@ -0,0 +1,43 @@
+use crate::bga::{self, Mode};
+use crate::framebuffer::{Color, Position};
+use crate::*;
+
+ktest!(
+    fn render_at_fixed_resolutions() {
+        let Some(bga) = bga::probe() else {
+            tests::skip("no BGA");
+            return;
+        };
+        let original = bga.current_mode();
+        // boot moved the console to the sensible mode
+        if logger::with_framebuffer(|_| ()).is_some() {
+            assert_eq!(Some(original), bga.sensible_mode());
+        }
+
+        let modes = [
+            Mode::new(640, 480, 32),
+            Mode::new(800, 600, 32),
+            Mode::new(1024, 768, 24),
+        ];
+        for mode in modes.into_iter().filter(|mode| bga.supports(*mode)) {
+            bga.switch_console_mode(mode).unwrap();
+            logger::with_framebuffer(|display| {
+                assert_eq!(display.width(), mode.width as usize);
+                assert_eq!(display.height(), mode.height as usize);
+
+                let corner = Position {
+                    x: display.width() - 1,
+                    y: display.height() - 1,
+                };
+                let color = Color::new(0x12, 0x34, 0x56);
+                display.set_pixel(corner, color);
+                assert_eq!(display.get_pixel(corner), Some(color));
+            })
+            .expect("console has no framebuffer");
+        }
+
+        bga.switch_console_mode(original).unwrap();
+    }
+);
+
+register_tests!(render_at_fixed_resolutions);
@@ -0,0 +1,123 @@
+use crate::block::{self, BlockDevice, BlockError, Device, DeviceId};
+use crate::buffer_cache::{self, CACHE_BLOCKS};
+use crate::partition::{self, Guid, PartitionType};
+use crate::*;
+
+/// The virtio scratch disk of the kernel-test runner.
+fn scratch_disk() -> DeviceId {
+    block::find("vda").expect("no vda scratch disk")
+}
+
+/// The first of `disks`: each driver's tests get one scratch disk from the
+/// kernel-test runner.
+pub fn scratch<D>(mut disks: impl Iterator<Item = D>, driver: &str) -> D {
+    disks
+        .next()
+        .unwrap_or_else(|| panic!("no {} scratch disk attached", driver))
+}
+
+/// Fills `buf` with bytes that differ from block to block, so a transfer
+/// that lands in the wrong place shows up.
+pub fn pattern(block: u64, buf: &mut [u8]) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = (block as usize * 7 + i * 13) as u8;
+    }
+}
+
+ktest!(
+    fn boot_disk_partitions() {
+        // the UEFI test image is a GPT disk with the ESP on it
+        let esp = block::devices()
+            .find(|id| match id.device() {
+                Device::Partition(partition) => {
+                    partition.kind == PartitionType::Gpt(Guid::EFI_SYSTEM)
+                }
+                Device::Disk(_) => false,
+            })
+            .expect("no EFI system partition registered");
+        assert!(esp.parent().is_some());
+        assert!(esp.name().as_str().ends_with('1'));
+
+        let mut boot_sector = [0u8; 512];
+        block::read(&esp, 0, &mut boot_sector).unwrap();
+        assert_eq!(boot_sector[510..], [0x55, 0xAA]);
+        let blocks = esp.block_count();
+        assert_eq!(
+            block::read(&esp, blocks, &mut boot_sector),
+            Err(BlockError::OutOfRange)
+        );
+    }
+);
+
+ktest!(
+    fn mbr_partitions() {
+        let disk = scratch_disk();
+        let mut mbr = [0u8; 512];
+        for (index, (kind, start, count)) in [(0x83u8, 1024u32, 2048u32), (0x0C, 4096, 4096)]
+            .into_iter()
+            .enumerate()
+        {
+            let entry = &mut mbr[446 + 16 * index..][..16];
+            entry[4] = kind;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&count.to_le_bytes());
+        }
+        mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+        block::write(&disk, 0, &mbr).unwrap();
+        assert_eq!(partition::scan(disk), Ok(2));
+
+        let second = block::find("vda2").unwrap();
+        assert_eq!(second.parent(), Some(disk));
+        assert_eq!(second.block_count(), 4096);
+        let data = [0x5Au8; 512];
+        block::write(&second, 7, &data).unwrap();
+        let mut back = [0u8; 512];
+        block::read(&disk, 4096 + 7, &mut back).unwrap();
+        assert_eq!(back, data);
+        assert_eq!(
+            block::write(&second, 4096, &data),
+            Err(BlockError::OutOfRange)
+        );
+
+        block::write(&disk, 0, &[0; 512]).unwrap();
+        assert_eq!(partition::scan(disk), Ok(0));
+        assert!(block::find("vda1").is_none());
+    }
+);
+
+ktest!(
+    fn cache_write_back_and_eviction() {
+        let disk = scratch_disk();
+        let lba = 9000;
+        buffer_cache::write(disk, lba, |data| data.fill(0xC3)).unwrap();
+        let mut raw = [0u8; 512];
+        block::read(&disk, lba, &mut raw).unwrap();
+        assert_ne!(raw, [0xC3; 512], "write went straight to the disk");
+        buffer_cache::sync(disk).unwrap();
+        block::read(&disk, lba, &mut raw).unwrap();
+        assert_eq!(raw, [0xC3; 512]);
+
+        // a dirty block reaches the disk when it ages out
+        buffer_cache::overwrite(disk, lba + 1, |data| data.fill(0x3C)).unwrap();
+        let before = buffer_cache::stats();
+        for other in 0..CACHE_BLOCKS as u64 {
+            buffer_cache::read(disk, 10000 + other, |_| ()).unwrap();
+        }
+        assert!(buffer_cache::stats().writebacks > before.writebacks);
+        block::read(&disk, lba + 1, &mut raw).unwrap();
+        assert_eq!(raw, [0x3C; 512]);
+
+        let hits = buffer_cache::stats().hits;
+        assert_eq!(
+            buffer_cache::read(disk, 10000 + CACHE_BLOCKS as u64 - 1, |data| data[0]),
+            Ok(0)
+        );
+        assert_eq!(buffer_cache::stats().hits, hits + 1);
+    }
+);
+
+register_tests!(
+    boot_disk_partitions,
+    mbr_partitions,
+    cache_write_back_and_eviction
+);
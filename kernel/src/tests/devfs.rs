@@ -0,0 +1,163 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::block::{self, BlockDevice};
+use crate::devfs::{self, Device, DeviceNumber, ioctl};
+use crate::sync::SpinLock;
+use crate::vfs::{self, FileType, OpenFlags, SeekFrom, VfsError};
+use crate::*;
+
+/// Remembers what was written to it and echoes it back.
+struct Echo(SpinLock<Vec<u8>>);
+
+impl Device for Echo {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let mut data = self.0.lock();
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        data.drain(..len);
+        Ok(len)
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> vfs::Result<usize> {
+        match request {
+            ioctl::FIONREAD => devfs::put_arg(arg, &(self.0.lock().len() as i32).to_le_bytes()),
+            _ => Err(VfsError::NotTty),
+        }
+    }
+}
+
+fn echo() -> Arc<Echo> {
+    Arc::new(Echo(SpinLock::new(Vec::new())))
+}
+
+ktest!(
+    fn memory_devices() {
+        let null = vfs::stat("/dev/null").unwrap();
+        assert_eq!((null.kind, null.mode), (FileType::CharDevice, 0o666));
+        assert_eq!(null.device, 0x103);
+
+        let file = vfs::open("/dev/null", OpenFlags::READ_WRITE).unwrap();
+        assert_eq!(file.write(b"gone").unwrap(), 4);
+        assert_eq!(file.read(&mut [0; 16]).unwrap(), 0);
+        assert_eq!(
+            file.ioctl(ioctl::FIONREAD, &mut [0; 4]),
+            Err(VfsError::NotTty)
+        );
+
+        let mut zeroes = [0xAA; 100];
+        let zero = vfs::open("/dev/zero", OpenFlags::READ).unwrap();
+        assert_eq!(zero.read(&mut zeroes).unwrap(), 100);
+        assert!(zeroes.iter().all(|byte| *byte == 0));
+
+        let random = vfs::open("/dev/urandom", OpenFlags::READ).unwrap();
+        let (mut first, mut second) = ([0; 33], [0; 33]);
+        random.read(&mut first).unwrap();
+        random.read(&mut second).unwrap();
+        assert_ne!(first, second);
+        assert!(first.iter().any(|byte| *byte != 0));
+    }
+);
+
+ktest!(
+    fn registered_devices() {
+        let number = DeviceNumber::new(240, 300);
+        devfs::register("echo", FileType::CharDevice, number, 0o600, echo());
+        let stat = vfs::stat("/dev/echo").unwrap();
+        assert_eq!(stat.device, 0x10_f02c);
+
+        let file = vfs::open("/dev/echo", OpenFlags::READ_WRITE).unwrap();
+        file.write(b"hello").unwrap();
+        let mut count = [0; 4];
+        assert_eq!(file.ioctl(ioctl::FIONREAD, &mut count), Ok(0));
+        assert_eq!(i32::from_le_bytes(count), 5);
+        let mut buf = [0; 8];
+        assert_eq!(file.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        let dev = vfs::open("/dev", OpenFlags::READ).unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = dev.read_dir().unwrap() {
+            names.push(entry.name);
+        }
+        assert!(names.iter().any(|name| name == "echo"));
+        assert!(names.iter().any(|name| name == "null"));
+
+        // the name goes at once; the open file stays but has no device
+        devfs::unregister("echo");
+        assert_eq!(vfs::stat("/dev/echo").err(), Some(VfsError::NotFound));
+        assert_eq!(file.write(b"x"), Err(VfsError::NoDevice));
+
+        devfs::register("echo", FileType::CharDevice, number, 0o600, echo());
+        let again = vfs::open("/dev/echo", OpenFlags::READ_WRITE).unwrap();
+        again.write(b"new").unwrap();
+        assert_eq!(file.read(&mut buf), Err(VfsError::NoDevice));
+        assert_eq!(again.read(&mut buf).unwrap(), 3);
+        devfs::unregister("echo");
+    }
+);
+
+ktest!(
+    fn block_device_node() {
+        let Some(disk) = block::find("vdb") else {
+            tests::skip("no second disk");
+            return;
+        };
+        let path = alloc::format!("/dev/{}", disk);
+        assert_eq!(vfs::stat(&path).unwrap().kind, FileType::BlockDevice);
+        let file = vfs::open(&path, OpenFlags::READ).unwrap();
+
+        let mut size = [0; 8];
+        file.ioctl(ioctl::BLKGETSIZE64, &mut size).unwrap();
+        let size = u64::from_le_bytes(size);
+        assert_eq!(size, disk.block_count() * disk.block_size() as u64);
+        assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), size);
+
+        // across a block boundary, and against the driver
+        let mut direct = alloc::vec![0; 2 * disk.block_size()];
+        block::read(&disk, 0, &mut direct).unwrap();
+        let offset = disk.block_size() - 7;
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        let mut through = [0; 20];
+        assert_eq!(file.read(&mut through).unwrap(), 20);
+        assert_eq!(through, direct[offset..offset + 20]);
+
+        file.seek(SeekFrom::Start(size - 4)).unwrap();
+        assert_eq!(file.read(&mut through).unwrap(), 4);
+        assert_eq!(file.read(&mut through).unwrap(), 0);
+    }
+);
+
+ktest!(
+    fn non_blocking_reads() {
+        let flags = OpenFlags::READ | OpenFlags::NON_BLOCK;
+        let mut buf = [0; 4 * keyboard::EVENT_SIZE];
+        if let Ok(file) = vfs::open("/dev/keyboard", flags) {
+            while keyboard::read_event().is_some() {}
+            assert_eq!(file.read(&mut buf), Err(VfsError::WouldBlock));
+            assert_eq!(file.read(&mut buf[..1]), Err(VfsError::InvalidArgument));
+        }
+
+        // whatever arrived on the console is drained, then nothing is left
+        let serial = vfs::open("/dev/ttyS0", flags).unwrap();
+        let drained = loop {
+            if let Err(err) = serial.read(&mut buf) {
+                break err;
+            }
+        };
+        assert_eq!(drained, VfsError::WouldBlock);
+        assert_eq!(serial.read(&mut []), Ok(0));
+    }
+);
+
+register_tests!(
+    memory_devices,
+    registered_devices,
+    block_device_node,
+    non_blocking_reads
+);
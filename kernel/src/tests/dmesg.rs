@@ -0,0 +1,36 @@
+use crate::*;
+use core::time::Duration;
+
+ktest!(
+    fn records_are_kept() {
+        log::info!("dmesg marker 7f3a");
+        let record = dmesg::records()
+            .filter(|record| record.text() == "dmesg marker 7f3a")
+            .last()
+            .expect("record missing from the log buffer");
+        assert_eq!(record.level, log::Level::Info);
+    }
+);
+
+ktest!(
+    fn oldest_records_are_evicted() {
+        let bytes = [b'x'; 200];
+        let text = core::str::from_utf8(&bytes).unwrap();
+        let mut last = 0;
+        // several times the buffer size, so the ring wraps a few times
+        for _ in 0..1000 {
+            last = dmesg::try_push(Duration::ZERO, log::Level::Trace, text).unwrap();
+        }
+
+        let mut seqs = dmesg::records().map(|record| record.seq);
+        let mut previous = seqs.next().unwrap();
+        assert!(previous > 0, "nothing was evicted");
+        for seq in seqs {
+            assert_eq!(seq, previous + 1);
+            previous = seq;
+        }
+        assert_eq!(previous, last);
+    }
+);
+
+register_tests!(records_are_kept, oldest_records_are_evicted);
@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+
+use crate::block::{self, DeviceId};
+use crate::ext2::Ext2Fs;
+use crate::tests::vfs::{read_all, scratch_dir};
+use crate::vfs::{self, FileType, OpenFlags, VfsError};
+use crate::*;
+
+/// The image the test runner formats with mkfs.ext2, if it could; skips
+/// the test otherwise.
+pub fn test_disk() -> Option<(DeviceId, alloc::sync::Arc<Ext2Fs>)> {
+    let Some(disk) = block::find("vdb") else {
+        tests::skip("no ext2 test disk");
+        return None;
+    };
+    match Ext2Fs::new(disk) {
+        Ok(fs) => Some((disk, fs)),
+        Err(err) => {
+            log::warn!("vdb is not an ext2 volume: {}", err);
+            tests::skip("no ext2 test disk");
+            None
+        }
+    }
+}
+
+fn big_pattern() -> Vec<u8> {
+    (0..300 * 1024u32).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+ktest!(
+    fn host_built_image() {
+        let Some((_, fs)) = test_disk() else {
+            return;
+        };
+        let mnt = scratch_dir("ext2-read");
+        vfs::mount(&mnt, "vdb", fs).unwrap();
+        let path = |rest: &str| alloc::format!("{}/{}", mnt, rest);
+
+        let root = vfs::stat(&mnt).unwrap();
+        assert_eq!(
+            (root.kind, root.uid, root.gid),
+            (FileType::Directory, 1000, 1000)
+        );
+        assert_eq!(read_all(&path("hello.txt")), b"hello from ext2\n");
+        assert_eq!(read_all(&path("dir/nested/deep.txt")), b"deep\n");
+        assert_eq!(read_all(&path("big.bin")), big_pattern());
+        assert_eq!(vfs::stat(&path("secret")).unwrap().mode, 0o600);
+
+        assert_eq!(
+            vfs::read_link(&path("short-link")).unwrap(),
+            "dir/nested/deep.txt"
+        );
+        assert_eq!(read_all(&path("short-link")), b"deep\n");
+        assert!(vfs::read_link(&path("long-link")).unwrap().len() > 60);
+        assert_eq!(read_all(&path("long-link")), b"hello from ext2\n");
+
+        let dir = vfs::open(&mnt, OpenFlags::READ).unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = dir.read_dir().unwrap() {
+            names.push(entry.name);
+        }
+        for name in ["hello.txt", "dir", "big.bin", "short-link", "lost+found"] {
+            assert!(names.iter().any(|entry| entry == name), "{} missing", name);
+        }
+        assert!(!names.iter().any(|entry| entry == "." || entry == ".."));
+        vfs::unmount(&mnt).unwrap();
+    }
+);
+
+ktest!(
+    fn create_and_remove() {
+        let Some((disk, fs)) = test_disk() else {
+            return;
+        };
+        let free = fs.free_counts().unwrap();
+        let mnt = scratch_dir("ext2-write");
+        vfs::mount(&mnt, "vdb", fs).unwrap();
+        let path = |rest: &str| alloc::format!("{}/{}", mnt, rest);
+
+        vfs::mkdir(&path("new"), 0o750).unwrap();
+        for i in 0..100 {
+            let name = path(&alloc::format!("new/a file called {}", i));
+            vfs::open(&name, OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        }
+        // past the double indirect range of 1 KiB blocks, into the triple
+        let far = path("new/sparse");
+        let file = vfs::open(&far, OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        let offset = (12 + 256 + 256 * 256 + 10) * 1024;
+        file.seek(vfs::SeekFrom::Start(offset)).unwrap();
+        file.write(b"far away").unwrap();
+        vfs::symlink(&"x".repeat(100), &path("new/long-link")).unwrap();
+        let data = big_pattern();
+        vfs::open(&path("copy"), OpenFlags::WRITE | OpenFlags::CREATE)
+            .unwrap()
+            .write(&data)
+            .unwrap();
+        assert_eq!(vfs::rmdir(&path("new")).err(), Some(VfsError::NotEmpty));
+
+        vfs::unmount(&mnt).unwrap();
+        vfs::mount(&mnt, "vdb", Ext2Fs::new(disk).unwrap()).unwrap();
+        assert_eq!(vfs::stat(&path("new")).unwrap().mode, 0o750);
+        assert_eq!(read_all(&path("copy")), data);
+        let sparse = read_all(&far);
+        assert_eq!(sparse.len() as u64, offset + 8);
+        assert!(sparse[..offset as usize].iter().all(|byte| *byte == 0));
+        assert_eq!(vfs::read_link(&path("new/long-link")).unwrap().len(), 100);
+
+        vfs::open(&path("copy"), OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+        let dir = vfs::open(&path("new"), OpenFlags::READ).unwrap();
+        let mut count = 0;
+        while let Some(entry) = dir.read_dir().unwrap() {
+            vfs::unlink(&path(&alloc::format!("new/{}", entry.name))).unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 102);
+        vfs::rmdir(&path("new")).unwrap();
+        vfs::unlink(&path("copy")).unwrap();
+
+        // an unlinked file lives on until its last handle goes
+        let open = vfs::open(&path("open"), OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+        open.write(&data[..5000]).unwrap();
+        vfs::unlink(&path("open")).unwrap();
+        assert_eq!(vfs::stat(&path("open")).err(), Some(VfsError::NotFound));
+        open.write(b"more").unwrap();
+        vfs::create(&path("reuse"), 0o644).unwrap();
+        open.seek(vfs::SeekFrom::Start(4998)).unwrap();
+        let mut tail = [0u8; 6];
+        assert_eq!(open.read(&mut tail).unwrap(), 6);
+        assert_eq!(tail[2..], *b"more");
+        assert_eq!(tail[..2], data[4998..5000]);
+        drop(open);
+        vfs::unlink(&path("reuse")).unwrap();
+
+        // one still open when the volume goes is freed by the next mount
+        let kept = vfs::open(&path("kept"), OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        kept.write(&data).unwrap();
+        vfs::unlink(&path("kept")).unwrap();
+        core::mem::forget(kept);
+        vfs::unmount(&mnt).unwrap();
+
+        // every block and inode taken went back to the bitmaps
+        assert_eq!(Ext2Fs::new(disk).unwrap().free_counts().unwrap(), free);
+    }
+);
+
+register_tests!(host_built_image, create_and_remove);
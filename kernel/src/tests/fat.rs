@@ -0,0 +1,147 @@
+use alloc::vec::Vec;
+
+use crate::block::{self, Device, DeviceId, Partition};
+use crate::buffer_cache;
+use crate::fat::{FatFs, FatType};
+use crate::partition::{Guid, PartitionType};
+use crate::tests::vfs::{read_all, scratch_dir};
+use crate::vfs::{self, OpenFlags, VfsError};
+use crate::*;
+
+fn boot_partition() -> DeviceId {
+    block::devices()
+        .find(|id| match id.device() {
+            Device::Partition(partition) => partition.kind == PartitionType::Gpt(Guid::EFI_SYSTEM),
+            Device::Disk(_) => false,
+        })
+        .expect("no EFI system partition registered")
+}
+
+/// Lays a blank FAT16 volume of 8192 sectors, one per cluster, over a
+/// partition registered on the virtio scratch disk.
+fn scratch_volume() -> DeviceId {
+    let disk = block::find("vda").expect("no vda scratch disk");
+    let Device::Disk(whole) = disk.device() else {
+        unreachable!();
+    };
+    let partition = Partition {
+        disk: whole,
+        start: 16384,
+        blocks: 8192,
+        kind: PartitionType::Mbr(0x06),
+        number: 9,
+    };
+    let volume = block::register_partition(disk, partition).unwrap();
+    // 1 reserved sector, 2 FATs of 33 sectors and a 512 entry root
+    for lba in 0..100 {
+        buffer_cache::overwrite(volume, lba, |sector| {
+            if lba == 0 {
+                sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+                sector[13] = 1;
+                sector[14..16].copy_from_slice(&1u16.to_le_bytes());
+                sector[16] = 2;
+                sector[17..19].copy_from_slice(&512u16.to_le_bytes());
+                sector[19..21].copy_from_slice(&8192u16.to_le_bytes());
+                sector[21] = 0xF8;
+                sector[22..24].copy_from_slice(&33u16.to_le_bytes());
+                sector[510..].copy_from_slice(&[0x55, 0xAA]);
+            }
+            if lba == 1 || lba == 34 {
+                sector[..4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+            }
+        })
+        .unwrap();
+    }
+    volume
+}
+
+ktest!(
+    fn boot_partition_files() {
+        let mnt = scratch_dir("fat-boot");
+        vfs::mount(&mnt, "esp", FatFs::new(boot_partition()).unwrap()).unwrap();
+        let path = |rest: &str| alloc::format!("{}/{}", mnt, rest);
+
+        let kernel = read_all(&path("kernel-x86_64"));
+        assert_eq!(kernel[..4], *b"\x7fELF");
+        assert_eq!(
+            vfs::stat(&path("kernel-x86_64")).unwrap().size,
+            kernel.len() as u64
+        );
+        let loader = vfs::open(&path("EFI/BOOT/BOOTX64.EFI"), OpenFlags::READ).unwrap();
+        let mut magic = [0u8; 2];
+        loader.read(&mut magic).unwrap();
+        assert_eq!(&magic, b"MZ");
+
+        let root = vfs::open(&mnt, OpenFlags::READ).unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = root.read_dir().unwrap() {
+            names.push(entry.name);
+        }
+        assert!(names.iter().any(|name| name == "kernel-x86_64"));
+        assert!(names.iter().any(|name| name.eq_ignore_ascii_case("efi")));
+        vfs::unmount(&mnt).unwrap();
+    }
+);
+
+ktest!(
+    fn create_write_truncate_delete() {
+        let volume = scratch_volume();
+        let fs = FatFs::new(volume).unwrap();
+        assert_eq!(fs.fat_type(), FatType::Fat16);
+        let mnt = scratch_dir("fat-scratch");
+        vfs::mount(&mnt, "vda9", fs).unwrap();
+        let path = |rest: &str| alloc::format!("{}/{}", mnt, rest);
+
+        let data: Vec<u8> = (0..5000u32).map(|i| (i * 7) as u8).collect();
+        let long = path("A file with a long name.data");
+        let file = vfs::open(&long, OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        assert_eq!(file.write(&data).unwrap(), data.len());
+        vfs::mkdir(&path("dir"), 0o755).unwrap();
+        for i in 0..40 {
+            let name = path(&alloc::format!("dir/entry number {}", i));
+            vfs::open(&name, OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        }
+        vfs::open(&path("SHORT.TXT"), OpenFlags::WRITE | OpenFlags::CREATE)
+            .unwrap()
+            .write(b"short")
+            .unwrap();
+        assert_eq!(vfs::rmdir(&path("dir")).err(), Some(VfsError::NotEmpty));
+        assert_eq!(
+            vfs::mkdir(&path("a:b"), 0o755).err(),
+            Some(VfsError::InvalidArgument)
+        );
+
+        // a fresh mount sees everything through the disk
+        vfs::unmount(&mnt).unwrap();
+        vfs::mount(&mnt, "vda9", FatFs::new(volume).unwrap()).unwrap();
+        assert_eq!(read_all(&path("a file with a LONG name.data")), data);
+        assert_eq!(read_all(&path("short.txt")), b"short");
+        // stamped from the clock, to the two seconds DOS counts
+        let modified = vfs::stat(&path("short.txt")).unwrap().modified;
+        assert!(time::now().abs_diff(modified) <= 4, "modified {}", modified);
+        let file = vfs::open(&long, OpenFlags::WRITE).unwrap();
+        vfs::open(&long, OpenFlags::WRITE | OpenFlags::TRUNCATE).unwrap();
+        assert_eq!(vfs::stat(&long).unwrap().size, 0);
+        file.seek(vfs::SeekFrom::Start(1000)).unwrap();
+        file.write(b"end").unwrap();
+        let sparse = read_all(&long);
+        assert_eq!(sparse.len(), 1003);
+        assert!(sparse[..1000].iter().all(|byte| *byte == 0));
+
+        let dir = vfs::open(&path("dir"), OpenFlags::READ).unwrap();
+        let mut count = 0;
+        while let Some(entry) = dir.read_dir().unwrap() {
+            vfs::unlink(&path(&alloc::format!("dir/{}", entry.name))).unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 40);
+        vfs::rmdir(&path("dir")).unwrap();
+        vfs::unlink(&long).unwrap();
+        assert_eq!(vfs::stat(&long).err(), Some(VfsError::NotFound));
+        vfs::unmount(&mnt).unwrap();
+        buffer_cache::invalidate(volume).unwrap();
+        block::remove_partitions(block::find("vda").unwrap());
+    }
+);
+
+register_tests!(boot_partition_files, create_write_truncate_delete);
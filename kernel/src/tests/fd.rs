@@ -0,0 +1,89 @@
+use alloc::vec::Vec;
+
+use crate::fd::{self, FdTable, MAX_FDS};
+use crate::pipe;
+use crate::task;
+use crate::tests::vfs::scratch_dir;
+use crate::vfs::{self, OpenFlags, VfsError};
+use crate::*;
+
+ktest!(
+    fn dup_and_close_on_exec() {
+        let path = alloc::format!("{}/file", scratch_dir("fd-dup"));
+        let task = task::current();
+        let files = task.files();
+        let before = files.count();
+
+        let first = fd::open(
+            &path,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::CLOSE_ON_EXEC,
+        )
+        .unwrap();
+        assert!(files.close_on_exec(first).unwrap());
+        let second = files.dup(first).unwrap();
+        assert!(!files.close_on_exec(second).unwrap());
+
+        // the two share the open file, position and flags included
+        files.get(first).unwrap().write(b"hello").unwrap();
+        files.get(second).unwrap().write(b" world").unwrap();
+        let file = files.get(second).unwrap();
+        assert!(!file.flags().contains(OpenFlags::CLOSE_ON_EXEC));
+        files
+            .get(first)
+            .unwrap()
+            .set_flags(OpenFlags::APPEND | OpenFlags::READ);
+        assert!(file.flags().contains(OpenFlags::APPEND | OpenFlags::WRITE));
+        assert!(!file.flags().contains(OpenFlags::READ));
+
+        let far = files.dup2(first, 100).unwrap();
+        assert_eq!(far, 100);
+        assert_eq!(files.dup2(far, far), Ok(far));
+        files.close(first).unwrap();
+        assert_eq!(files.close(first), Err(VfsError::BadDescriptor));
+        assert!(files.get(first).is_err());
+        // the lowest free descriptor is taken again
+        assert_eq!(files.dup(far).unwrap(), first);
+        assert_eq!(files.dup_from(far, 50).unwrap(), 50);
+
+        files.set_close_on_exec(far, true).unwrap();
+        files.set_close_on_exec(first, true).unwrap();
+        files.close_for_exec();
+        assert_eq!(files.get(far).err(), Some(VfsError::BadDescriptor));
+        files.get(second).unwrap();
+        for descriptor in [second, 50] {
+            files.close(descriptor).unwrap();
+        }
+        assert_eq!(files.count(), before);
+
+        let file = vfs::open(&path, OpenFlags::READ).unwrap();
+        let mut data = [0; 32];
+        let read = file.read(&mut data).unwrap();
+        assert_eq!(&data[..read], b"hello world");
+    }
+);
+
+ktest!(
+    fn table_limits() {
+        let table = FdTable::new();
+        let (reader, _writer) = pipe::pipe(OpenFlags::default());
+        let fds: Vec<_> = (0..MAX_FDS)
+            .map(|_| table.insert(reader.clone(), false).unwrap())
+            .collect();
+        assert_eq!(fds[MAX_FDS - 1], MAX_FDS - 1);
+        assert_eq!(
+            table.insert(reader.clone(), false),
+            Err(VfsError::TooManyFiles)
+        );
+        assert_eq!(table.dup(0), Err(VfsError::TooManyFiles));
+        assert_eq!(table.dup2(0, MAX_FDS), Err(VfsError::BadDescriptor));
+
+        // a duplicated table shares the open files but not the descriptors
+        let copy = table.duplicate();
+        table.close(3).unwrap();
+        assert_eq!(copy.count(), MAX_FDS);
+        assert_eq!(table.count(), MAX_FDS - 1);
+        assert_eq!(copy.close_on_exec(3), Ok(false));
+    }
+);
+
+register_tests!(dup_and_close_on_exec, table_limits);
@@ -0,0 +1,81 @@
+use alloc::vec::Vec;
+
+use crate::initramfs::{Archive, CpioError};
+use crate::tests::vfs::read_all;
+use crate::vfs::{self, FileType};
+use crate::*;
+
+/// A newc member header with the given mode, data size and name size.
+fn header(mode: u32, size: usize, name_len: usize) -> alloc::string::String {
+    let mut header = alloc::string::String::from("070701");
+    let fields = [
+        1,
+        mode,
+        0,
+        0,
+        1,
+        0,
+        size as u32,
+        0,
+        0,
+        0,
+        0,
+        name_len as u32,
+        0,
+    ];
+    for field in fields {
+        header.push_str(&alloc::format!("{:08X}", field));
+    }
+    header
+}
+
+ktest!(
+    fn boot_archive_unpacked() {
+        assert_eq!(read_all("/test/hello.txt"), b"hello from the initramfs\n");
+        assert_eq!(vfs::read_link("/test/link").unwrap(), "hello.txt");
+        assert_eq!(read_all("/test/link"), read_all("/test/hello.txt"));
+
+        let run = vfs::stat("/test/bin/run").unwrap();
+        assert_eq!((run.kind, run.mode), (FileType::Regular, 0o755));
+        assert_eq!(vfs::stat("/test/bin").unwrap().kind, FileType::Directory);
+
+        let large = read_all("/test/large.bin");
+        assert_eq!(large.len(), 100_000);
+        assert!(
+            large
+                .iter()
+                .enumerate()
+                .all(|(i, byte)| *byte == (i % 253) as u8)
+        );
+        assert_eq!(vfs::stat("/test/large.bin").unwrap().mode, 0o600);
+        assert_eq!(read_all("/etc/hostname"), b"karkinos\n");
+    }
+);
+
+ktest!(
+    fn malformed_archives() {
+        // "hi" with its NUL pads the name to 116 bytes, the data to 120
+        let mut archive = header(0o100644, 3, 3);
+        archive.push_str("hi\0\0\0\0abc\0");
+        archive.push_str(&header(0, 0, 11));
+        archive.push_str("TRAILER!!!\0\0\0\0");
+        let entries: Vec<_> = Archive::new(archive.as_bytes()).collect();
+        assert_eq!(entries.len(), 1);
+        let entry = entries[0].unwrap();
+        assert_eq!((entry.name, entry.data), ("hi", &b"abc"[..]));
+
+        let truncated = &archive.as_bytes()[..118];
+        let mut entries = Archive::new(truncated);
+        assert_eq!(entries.next(), Some(Err(CpioError::Truncated)));
+        assert_eq!(entries.next(), None);
+
+        let mut bad = archive.clone().into_bytes();
+        bad[5] = b'7';
+        assert_eq!(Archive::new(&bad).next(), Some(Err(CpioError::BadMagic)));
+        bad[5] = b'1';
+        bad[6] = b'x';
+        assert_eq!(Archive::new(&bad).next(), Some(Err(CpioError::BadHeader)));
+    }
+);
+
+register_tests!(boot_archive_unpacked, malformed_archives);
@@ -0,0 +1,141 @@
+use crate::keyboard::{KeyCode, KeyState, Keymap, Modifiers, ScancodeSet};
+use crate::scancode::Decoder;
+use crate::*;
+
+fn decode(set: ScancodeSet, bytes: &[u8]) -> ([Option<(KeyCode, KeyState)>; 4], usize) {
+    let mut decoder = Decoder::new(set);
+    let mut events = [None; 4];
+    let mut count = 0;
+    for &byte in bytes {
+        decoder.feed(byte, |key, state| {
+            events[count] = Some((key, state));
+            count += 1;
+        });
+    }
+    (events, count)
+}
+
+ktest!(
+    fn decode_scancode_set1() {
+        use KeyCode::*;
+        use KeyState::*;
+
+        let (events, count) = decode(ScancodeSet::Set1, &[0x1E, 0x9E]);
+        assert_eq!(count, 2);
+        assert_eq!(events[..2], [Some((A, Pressed)), Some((A, Released))]);
+
+        let (events, count) = decode(ScancodeSet::Set1, &[0xE0, 0x48, 0xE0, 0xC8]);
+        assert_eq!(count, 2);
+        assert_eq!(events[..2], [Some((Up, Pressed)), Some((Up, Released))]);
+
+        // print screen with its fake shifts
+        let (events, count) = decode(
+            ScancodeSet::Set1,
+            &[0xE0, 0x2A, 0xE0, 0x37, 0xE0, 0xB7, 0xE0, 0xAA],
+        );
+        assert_eq!(count, 2);
+        assert_eq!(
+            events[..2],
+            [Some((PrintScreen, Pressed)), Some((PrintScreen, Released))]
+        );
+
+        let (events, count) = decode(
+            ScancodeSet::Set1,
+            &[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5, 0x10],
+        );
+        assert_eq!(count, 3);
+        assert_eq!(
+            events[..3],
+            [
+                Some((Pause, Pressed)),
+                Some((Pause, Released)),
+                Some((Q, Pressed))
+            ]
+        );
+    }
+);
+
+ktest!(
+    fn decode_scancode_set2() {
+        use KeyCode::*;
+        use KeyState::*;
+
+        let (events, count) = decode(ScancodeSet::Set2, &[0x1C, 0xF0, 0x1C]);
+        assert_eq!(count, 2);
+        assert_eq!(events[..2], [Some((A, Pressed)), Some((A, Released))]);
+
+        let (events, count) = decode(ScancodeSet::Set2, &[0xE0, 0x11, 0xE0, 0xF0, 0x11]);
+        assert_eq!(count, 2);
+        assert_eq!(
+            events[..2],
+            [Some((RightAlt, Pressed)), Some((RightAlt, Released))]
+        );
+
+        let (events, count) = decode(
+            ScancodeSet::Set2,
+            &[0xE0, 0x12, 0xE0, 0x7C, 0xE0, 0xF0, 0x7C, 0xE0, 0xF0, 0x12],
+        );
+        assert_eq!(count, 2);
+        assert_eq!(
+            events[..2],
+            [Some((PrintScreen, Pressed)), Some((PrintScreen, Released))]
+        );
+
+        let (events, count) = decode(
+            ScancodeSet::Set2,
+            &[0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77, 0x83],
+        );
+        assert_eq!(count, 3);
+        assert_eq!(
+            events[..3],
+            [
+                Some((Pause, Pressed)),
+                Some((Pause, Released)),
+                Some((F7, Pressed))
+            ]
+        );
+    }
+);
+
+ktest!(
+    fn translate_keymaps() {
+        let none = Modifiers::NONE;
+        let shift = Modifiers::LEFT_SHIFT;
+        let altgr = Modifiers::RIGHT_ALT;
+        let caps = Modifiers::CAPS_LOCK;
+
+        assert_eq!(Keymap::Us.translate(KeyCode::A, none), Some('a'));
+        assert_eq!(Keymap::Us.translate(KeyCode::A, shift), Some('A'));
+        assert_eq!(Keymap::Us.translate(KeyCode::A, caps), Some('A'));
+        assert_eq!(Keymap::Us.translate(KeyCode::A, caps | shift), Some('a'));
+        assert_eq!(Keymap::Us.translate(KeyCode::Digit1, caps), Some('1'));
+        assert_eq!(Keymap::Us.translate(KeyCode::Digit2, shift), Some('@'));
+        assert_eq!(
+            Keymap::Us.translate(KeyCode::C, Modifiers::LEFT_CTRL),
+            Some('\x03')
+        );
+        assert_eq!(Keymap::Us.translate(KeyCode::LeftShift, none), None);
+
+        assert_eq!(Keymap::Uk.translate(KeyCode::Digit3, shift), Some('£'));
+        assert_eq!(Keymap::Uk.translate(KeyCode::Apostrophe, shift), Some('@'));
+        assert_eq!(Keymap::Uk.translate(KeyCode::Backslash, none), Some('#'));
+
+        assert_eq!(Keymap::De.translate(KeyCode::Y, none), Some('z'));
+        assert_eq!(Keymap::De.translate(KeyCode::Z, shift), Some('Y'));
+        assert_eq!(Keymap::De.translate(KeyCode::Semicolon, caps), Some('Ö'));
+        assert_eq!(Keymap::De.translate(KeyCode::Minus, caps), Some('ß'));
+        assert_eq!(Keymap::De.translate(KeyCode::Q, altgr), Some('@'));
+        assert_eq!(Keymap::De.translate(KeyCode::Digit7, shift), Some('/'));
+
+        let num = Modifiers::NUM_LOCK;
+        assert_eq!(Keymap::Us.translate(KeyCode::Keypad7, num), Some('7'));
+        assert_eq!(Keymap::Us.translate(KeyCode::Keypad7, none), None);
+        assert_eq!(Keymap::De.translate(KeyCode::KeypadPeriod, num), Some(','));
+    }
+);
+
+register_tests!(
+    decode_scancode_set1,
+    decode_scancode_set2,
+    translate_keymaps
+);
@@ -0,0 +1,339 @@
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::block::{self, BlockDevice, Device, DeviceId, Partition};
+use crate::buffer_cache;
+use crate::kfs::{CrashPoint, KfsFs};
+use crate::kfs_layout::{self, BLOCK_SIZE, EXTENT_SIZE, Extent, INODE_SIZE, RawInode, Superblock};
+use crate::partition::PartitionType;
+use crate::tests::vfs::{read_all, read_inode, scratch_dir};
+use crate::vfs::{self, FileSystem, FileType, Inode, OpenFlags, VfsError};
+use crate::*;
+
+/// The volume the test runner makes with mkfs-kfs, whichever disk it is;
+/// skips the test without one.
+fn test_disk() -> Option<(DeviceId, Arc<KfsFs>)> {
+    let disk = block::devices().find_map(|device| {
+        let fs = KfsFs::new(device).ok()?;
+        (fs.label() == "ktest-kfs").then_some((device, fs))
+    });
+    if disk.is_none() {
+        tests::skip("no kfs test disk");
+    }
+    disk
+}
+
+/// A partition of 3 MiB on the virtio scratch disk, past the FAT tests'.
+fn scratch_volume() -> DeviceId {
+    let disk = block::find("vda").expect("no vda scratch disk");
+    let Device::Disk(whole) = disk.device() else {
+        unreachable!();
+    };
+    let partition = Partition {
+        disk: whole,
+        start: 24576,
+        blocks: 6144,
+        kind: PartitionType::Mbr(0x83),
+        number: 10,
+    };
+    block::register_partition(disk, partition).unwrap()
+}
+
+fn release(volume: DeviceId) {
+    buffer_cache::invalidate(volume).unwrap();
+    block::remove_partitions(block::find("vda").unwrap());
+}
+
+/// Contents of the n-th file of a test, different for every n.
+fn pattern(n: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 7 + n * 13) % 251) as u8).collect()
+}
+
+/// Writes numbered files and syncs after each until QEMU is killed, for the
+/// runner's crash test.
+fn crash_writer(fs: &Arc<KfsFs>) -> ! {
+    let root = fs.root();
+    root.create("crash-writing", FileType::Regular, 0o644)
+        .unwrap();
+    root.unlink("crash-test").unwrap();
+    fs.sync().unwrap();
+    log::info!("kfs crash writer running");
+    let mut n = 0;
+    loop {
+        let file = root
+            .create(&format!("file-{}", n), FileType::Regular, 0o644)
+            .unwrap();
+        file.write_at(0, &pattern(n, 10000 + n % 5 * 3000)).unwrap();
+        drop(file);
+        if n >= 40 {
+            root.unlink(&format!("file-{}", n - 40)).unwrap();
+        }
+        fs.sync().unwrap();
+        n += 1;
+    }
+}
+
+/// Checks what the crash writer left: every file it synced is whole, and
+/// the one it was writing is whole, zeroes, or missing.
+fn check_crash_writer(root: &Arc<dyn Inode>) {
+    let mut numbers = Vec::new();
+    let mut cursor = 0;
+    while let Some((entry, next)) = root.read_dir(cursor).unwrap() {
+        if let Some(n) = entry.name.strip_prefix("file-") {
+            numbers.push(n.parse::<usize>().unwrap());
+        }
+        cursor = next;
+    }
+    numbers.sort();
+    assert!(!numbers.is_empty(), "the crash writer wrote nothing");
+    let last = *numbers.last().unwrap();
+    for n in numbers {
+        let data = read_inode(&root.lookup(&format!("file-{}", n)).unwrap());
+        let expected = pattern(n, 10000 + n % 5 * 3000);
+        if n == last && data != expected {
+            assert!(data.iter().all(|byte| *byte == 0), "file-{} is torn", n);
+        } else {
+            assert_eq!(data, expected, "file-{} is damaged", n);
+        }
+    }
+    log::info!("kfs recovered the crash writer's files up to file-{}", last);
+}
+
+ktest!(
+    fn host_built_image() {
+        let Some((device, fs)) = test_disk() else {
+            return;
+        };
+        let root = fs.root();
+        if root.lookup("crash-test").is_ok() {
+            crash_writer(&fs);
+        }
+        if root.lookup("crash-writing").is_ok() {
+            check_crash_writer(&root);
+        }
+
+        let mnt = scratch_dir("kfs-read");
+        vfs::mount(&mnt, device.name().as_str(), fs).unwrap();
+        let path = |rest: &str| format!("{}/{}", mnt, rest);
+        let read = |rest: &str| read_all(&path(rest));
+        assert_eq!(read("hello.txt"), b"hello from kfs\n");
+        assert_eq!(read("dir/nested/deep.txt"), b"deep\n");
+        assert_eq!(read("big.bin"), pattern(0, 300 * 1024));
+        assert_eq!(vfs::stat(&path("secret")).unwrap().mode, 0o600);
+        assert_eq!(
+            vfs::read_link(&path("short-link")).unwrap(),
+            "dir/nested/deep.txt"
+        );
+        assert_eq!(read("long-link"), b"hello from kfs\n");
+        vfs::unmount(&mnt).unwrap();
+    }
+);
+
+ktest!(
+    fn create_remove_and_remount() {
+        let volume = scratch_volume();
+        KfsFs::format(volume, "scratch").unwrap();
+        let fs = KfsFs::new(volume).unwrap();
+        let empty = fs.free_counts();
+        let mnt = scratch_dir("kfs-write");
+        vfs::mount(&mnt, "vda10", fs.clone()).unwrap();
+        let path = |rest: &str| format!("{}/{}", mnt, rest);
+
+        // enough names for the directory to take several blocks
+        vfs::mkdir(&path("many"), 0o755).unwrap();
+        for n in 0..150 {
+            let name = path(&format!("many/a-rather-long-file-name-{}", n));
+            let file = vfs::open(&name, OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+            file.write(&pattern(n, n * 50)).unwrap();
+        }
+        let big = vfs::open(&path("big"), OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        big.write(&pattern(1, 200 * 1024)).unwrap();
+        drop(big);
+        vfs::symlink(&"x/".repeat(150), &path("long-link")).unwrap();
+        vfs::link(&path("big"), &path("big-again")).unwrap();
+        vfs::sync().unwrap();
+        assert!(fs.free_counts().0 < empty.0 - 50);
+
+        for n in 0..150 {
+            vfs::unlink(&path(&format!("many/a-rather-long-file-name-{}", n))).unwrap();
+        }
+        vfs::rmdir(&path("many")).unwrap();
+        for name in ["big", "big-again", "long-link"] {
+            vfs::unlink(&path(name)).unwrap();
+        }
+        vfs::sync().unwrap();
+        assert_eq!(fs.free_counts(), empty);
+
+        // an unlinked file lives on until its last handle goes
+        let open = vfs::open(&path("open"), OpenFlags::READ_WRITE | OpenFlags::CREATE).unwrap();
+        open.write(&pattern(3, 5000)).unwrap();
+        vfs::unlink(&path("open")).unwrap();
+        assert_eq!(vfs::stat(&path("open")).err(), Some(VfsError::NotFound));
+        open.write(b"more").unwrap();
+        vfs::create(&path("reuse"), 0o644).unwrap();
+        vfs::sync().unwrap();
+        open.seek(vfs::SeekFrom::Start(4998)).unwrap();
+        let mut tail = [0u8; 6];
+        assert_eq!(open.read(&mut tail).unwrap(), 6);
+        assert_eq!(tail[..2], pattern(3, 5000)[4998..]);
+        assert_eq!(tail[2..], *b"more");
+        drop(open);
+        vfs::unlink(&path("reuse")).unwrap();
+        vfs::sync().unwrap();
+        assert_eq!(fs.free_counts(), empty);
+
+        let kept = vfs::open(&path("kept"), OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        kept.write(&pattern(2, 9000)).unwrap();
+        drop(kept);
+        vfs::mkdir(&path("dir"), 0o700).unwrap();
+        // one still open when the volume goes is freed by the next mount
+        let gone = vfs::open(&path("gone"), OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        gone.write(&pattern(4, 9000)).unwrap();
+        vfs::unlink(&path("gone")).unwrap();
+        core::mem::forget(gone);
+        vfs::unmount(&mnt).unwrap();
+        drop(fs);
+
+        let fs = KfsFs::new(volume).unwrap();
+        assert_eq!(fs.label(), "scratch");
+        assert_eq!(fs.free_counts().1, empty.1 - 2);
+        let root = fs.root();
+        assert_eq!(read_inode(&root.lookup("kept").unwrap()), pattern(2, 9000));
+        let dir = root.lookup("dir").unwrap().metadata().unwrap();
+        assert_eq!((dir.kind, dir.mode), (FileType::Directory, 0o700));
+        assert_eq!(root.lookup("big").err(), Some(VfsError::NotFound));
+        drop(root);
+        drop(fs);
+        release(volume);
+    }
+);
+
+ktest!(
+    fn recovery_and_checksums() {
+        let volume = scratch_volume();
+        KfsFs::format(volume, "crash").unwrap();
+        let points = [
+            CrashPoint::BeforeCommitBlock,
+            CrashPoint::AfterCommitBlock,
+            CrashPoint::MidCheckpoint,
+        ];
+        for (n, point) in points.into_iter().enumerate() {
+            let fs = KfsFs::new(volume).unwrap();
+            let before = fs.free_counts();
+            let root = fs.root();
+            let file = root
+                .create(&format!("file-{}", n), FileType::Regular, 0o644)
+                .unwrap();
+            file.write_at(0, &pattern(n, 20000)).unwrap();
+            root.create(&format!("dir-{}", n), FileType::Directory, 0o755)
+                .unwrap();
+            fs.crash_at(Some(point));
+            assert_eq!(fs.sync().err(), Some(VfsError::Io));
+            drop((file, root, fs));
+
+            let fs = KfsFs::new(volume).unwrap();
+            let root = fs.root();
+            match point {
+                CrashPoint::BeforeCommitBlock => {
+                    assert_eq!(fs.free_counts(), before);
+                    assert!(root.lookup(&format!("file-{}", n)).is_err());
+                }
+                _ => {
+                    let file = root.lookup(&format!("file-{}", n)).unwrap();
+                    assert_eq!(read_inode(&file), pattern(n, 20000));
+                    assert!(root.lookup(&format!("dir-{}", n)).is_ok());
+                }
+            }
+        }
+
+        // a flipped byte in the inode table is caught, not believed
+        let mut block = alloc::vec![0u8; BLOCK_SIZE];
+        block::read(&volume, 0, &mut block).unwrap();
+        let superblock = Superblock::decode(&block).unwrap();
+        let sector = superblock.inode_table * (BLOCK_SIZE / volume.block_size()) as u64;
+        buffer_cache::write(volume, sector, |data| data[40] ^= 1).unwrap();
+        let fs = KfsFs::new(volume).unwrap();
+        assert_eq!(fs.root().lookup("file-1").err(), Some(VfsError::Corrupted));
+        drop(fs);
+        release(volume);
+    }
+);
+
+ktest!(
+    fn failed_write_gives_back_space() {
+        let volume = scratch_volume();
+        KfsFs::format(volume, "full").unwrap();
+        let fs = KfsFs::new(volume).unwrap();
+        let file = fs.root().create("bad", FileType::Regular, 0o644).unwrap();
+        file.write_at(0, &pattern(0, BLOCK_SIZE)).unwrap();
+        let ino = file.metadata().unwrap().ino;
+        fs.sync().unwrap();
+        drop((file, fs));
+
+        // point the file's block past the end of the volume, so that
+        // reading it in fails
+        let mut block = alloc::vec![0u8; BLOCK_SIZE];
+        block::read(&volume, 0, &mut block).unwrap();
+        let superblock = Superblock::decode(&block).unwrap();
+        let (number, offset) = superblock.inode_location(ino).unwrap();
+        let lba = number * (BLOCK_SIZE / volume.block_size()) as u64;
+        block::read(&volume, lba, &mut block).unwrap();
+        let mut inode = RawInode::decode(&block[offset..offset + INODE_SIZE]);
+        let mut extent = Extent::decode(&inode.slots);
+        extent.physical = superblock.block_count;
+        extent.encode(&mut inode.slots[..EXTENT_SIZE]);
+        inode.encode(&mut block[offset..offset + INODE_SIZE]);
+        kfs_layout::seal(&mut block, number, kfs_layout::kind::INODES);
+        block::write(&volume, lba, &block).unwrap();
+        buffer_cache::invalidate(volume).unwrap();
+
+        // the write sets a block aside for its second page, then fails
+        // reading in the first
+        let fs = KfsFs::new(volume).unwrap();
+        let root = fs.root();
+        let before = fs.free_counts();
+        let file = root.lookup("bad").unwrap();
+        assert!(file.write_at(100, &pattern(1, BLOCK_SIZE)).is_err());
+        assert_eq!(fs.free_counts(), before);
+
+        // every free block is still there to be written
+        let fill = root.create("fill", FileType::Regular, 0o644).unwrap();
+        let end = before.0 * BLOCK_SIZE as u64;
+        for offset in (0..end).step_by(BLOCK_SIZE) {
+            fill.write_at(offset, &[0; BLOCK_SIZE]).unwrap();
+        }
+        assert_eq!(fill.write_at(end, &[0]), Err(VfsError::NoSpace));
+        fill.truncate(0).unwrap();
+
+        // a file in pieces sets aside the extent block it will need, so
+        // a write that fits its pages but not that fails, not the writeback
+        fs.sync().unwrap();
+        let end = (fs.free_counts().0 - 13) * BLOCK_SIZE as u64;
+        for offset in (0..end).step_by(BLOCK_SIZE) {
+            fill.write_at(offset, &[0; BLOCK_SIZE]).unwrap();
+        }
+        let pieces = root.create("pieces", FileType::Regular, 0o644).unwrap();
+        for n in 0..12 {
+            pieces
+                .write_at(n * 2 * BLOCK_SIZE as u64, &[1; BLOCK_SIZE])
+                .unwrap();
+        }
+        let last = 24 * BLOCK_SIZE as u64;
+        assert_eq!(
+            pieces.write_at(last, &[1; BLOCK_SIZE]),
+            Err(VfsError::NoSpace)
+        );
+        fs.sync().unwrap();
+        assert_eq!(fs.free_counts().0, 1);
+        drop((pieces, fill, file, root, fs));
+        release(volume);
+    }
+);
+
+register_tests!(
+    host_built_image,
+    create_remove_and_remount,
+    recovery_and_checksums,
+    failed_write_gives_back_space
+);
@@ -0,0 +1,24 @@
+use crate::*;
+
+ktest!(
+    fn log_from_nested_interrupt() {
+        let before = logger::stats();
+
+        // the breakpoint handler logs while we sit on the sink lock
+        logger::with_sinks_locked(interrupts::int3);
+
+        let after = logger::stats();
+        assert_eq!(after.staged, before.staged + 1);
+        assert_eq!(after.dropped, before.dropped);
+        log::info!("Staged record drained.")
+    }
+);
+
+ktest!(
+    fn log_with_interrupts_disabled() {
+        interrupts::without_interrupts(|| log::info!("Logged with IF=0."));
+        assert_eq!(logger::stats().dropped, 0);
+    }
+);
+
+register_tests!(log_from_nested_interrupt, log_with_interrupts_disabled);
@@ -0,0 +1,117 @@
+use alloc::vec::Vec;
+
+use crate::ext2::Ext2Fs;
+use crate::interrupts;
+use crate::mmap::{self, Access, Sharing};
+use crate::page_cache::PAGE_SIZE;
+use crate::tests::vfs::scratch_dir;
+use crate::tmpfs::TmpFs;
+use crate::vfs::{self, OpenFlags, SeekFrom, VfsError};
+use crate::*;
+
+fn read_at(path: &str, offset: u64, len: usize) -> Vec<u8> {
+    let file = vfs::open(path, OpenFlags::READ).unwrap();
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    let mut data = alloc::vec![0; len];
+    assert_eq!(file.read(&mut data).unwrap(), len);
+    data
+}
+
+ktest!(
+    fn tmpfs_mappings() {
+        let mnt = scratch_dir("mmap-tmpfs");
+        vfs::mount(&mnt, "tmpfs", TmpFs::new()).unwrap();
+        let path = alloc::format!("{}/file", mnt);
+        let file = vfs::open(&path, OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        file.write(&[b'a'; 2 * PAGE_SIZE]).unwrap();
+        let dentry = vfs::resolve(&path, true).unwrap();
+
+        let faults = interrupts::count(14);
+        let shared = mmap::map(
+            &dentry,
+            0,
+            2 * PAGE_SIZE,
+            Access::ReadWrite,
+            Sharing::Shared,
+        )
+        .unwrap();
+        let private = mmap::map(
+            &dentry,
+            0,
+            2 * PAGE_SIZE,
+            Access::ReadWrite,
+            Sharing::Private,
+        )
+        .unwrap();
+        unsafe {
+            assert_eq!(shared.as_ptr().add(PAGE_SIZE).read_volatile(), b'a');
+            shared.as_ptr().add(PAGE_SIZE + 1).write_volatile(b'S');
+            private.as_ptr().add(PAGE_SIZE + 2).write_volatile(b'P');
+            // the private page was copied, but still saw the shared write
+            assert_eq!(private.as_ptr().add(PAGE_SIZE + 1).read_volatile(), b'S');
+        }
+        assert!(interrupts::count(14) > faults);
+        assert_eq!(read_at(&path, PAGE_SIZE as u64, 3), b"aSa");
+
+        // writes through the file show in the shared mapping only
+        file.seek(SeekFrom::Start(PAGE_SIZE as u64 + 1)).unwrap();
+        file.write(b"W").unwrap();
+        unsafe {
+            assert_eq!(shared.as_ptr().add(PAGE_SIZE + 1).read_volatile(), b'W');
+            assert_eq!(private.as_ptr().add(PAGE_SIZE + 1).read_volatile(), b'S');
+        }
+        drop((shared, private));
+
+        assert_eq!(
+            mmap::map(&dentry, 1, PAGE_SIZE, Access::ReadOnly, Sharing::Shared).err(),
+            Some(VfsError::InvalidArgument)
+        );
+        let root = vfs::resolve(&mnt, true).unwrap();
+        assert_eq!(
+            mmap::map(&root, 0, PAGE_SIZE, Access::ReadOnly, Sharing::Shared).err(),
+            Some(VfsError::IsDirectory)
+        );
+        vfs::unmount(&mnt).unwrap();
+    }
+);
+
+ktest!(
+    fn ext2_shared_mapping_persists() {
+        let Some((disk, fs)) = tests::ext2::test_disk() else {
+            return;
+        };
+        let mnt = scratch_dir("mmap-ext2");
+        vfs::mount(&mnt, "vdb", fs).unwrap();
+        let path = alloc::format!("{}/mapped", mnt);
+        vfs::open(&path, OpenFlags::WRITE | OpenFlags::CREATE)
+            .unwrap()
+            .write(&[0; 3 * PAGE_SIZE])
+            .unwrap();
+
+        let dentry = vfs::resolve(&path, true).unwrap();
+        let mapping = mmap::map(
+            &dentry,
+            PAGE_SIZE as u64,
+            2 * PAGE_SIZE,
+            Access::ReadWrite,
+            Sharing::Shared,
+        )
+        .unwrap();
+        drop(dentry);
+        let data = unsafe { core::slice::from_raw_parts_mut(mapping.as_ptr(), mapping.len()) };
+        data[PAGE_SIZE - 4..PAGE_SIZE + 4].copy_from_slice(b"mapped!!");
+        mapping.sync().unwrap();
+        // a write after the sync dirties the page again
+        data[0] = b'x';
+        drop(mapping);
+
+        vfs::unmount(&mnt).unwrap();
+        vfs::mount(&mnt, "vdb", Ext2Fs::new(disk).unwrap()).unwrap();
+        assert_eq!(read_at(&path, PAGE_SIZE as u64, 1), b"x");
+        assert_eq!(read_at(&path, 2 * PAGE_SIZE as u64 - 4, 8), b"mapped!!");
+        vfs::unlink(&path).unwrap();
+        vfs::unmount(&mnt).unwrap();
+    }
+);
+
+register_tests!(tmpfs_mappings, ext2_shared_mapping_persists);
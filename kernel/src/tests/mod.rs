@@ -1,8 +1,37 @@
 use crate::*;
 
+pub mod ahci;
+pub mod ata;
+pub mod bga;
+pub mod block;
+pub mod devfs;
+pub mod dmesg;
+pub mod ext2;
+pub mod fat;
+pub mod fd;
+pub mod initramfs;
+pub mod keyboard;
+pub mod kfs;
+pub mod logger;
 pub mod math;
+pub mod mmap;
+pub mod mouse;
+pub mod msi;
+pub mod nvme;
+pub mod pci;
+pub mod pipe;
+pub mod procfs;
+pub mod ringbuf;
+pub mod tmpfs;
+pub mod uart;
+pub mod vfs;
+pub mod virtio;
+pub mod virtio_blk;
 
-collect_tests!(math);
+collect_tests!(
+    ahci, ata, bga, block, devfs, dmesg, ext2, fat, fd, initramfs, keyboard, kfs, logger, math,
+    mmap, mouse, msi, nvme, pci, pipe, procfs, ringbuf, tmpfs, uart, vfs, virtio, virtio_blk
+);
 
 pub use self::_init_tests as init_tests;
 pub use self::_run_all as run_all;
@@ -11,7 +40,7 @@ pub use self::_run_all as run_all;
 //                          IMPL HERE FOR NOW                          //
 // ====================================================================//
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use paste;
 
 #[derive(Copy, Clone)]
@@ -23,6 +52,8 @@ pub struct TestEntry {
 const MAX: usize = 128;
 static mut TESTS: [Option<TestEntry>; MAX] = [None; MAX];
 static NEXT: AtomicUsize = AtomicUsize::new(0);
+/// Set by [`skip`] while a test runs.
+static SKIPPED: AtomicBool = AtomicBool::new(false);
 
 pub fn add(name: &'static str, f: fn()) {
     let idx = NEXT.fetch_add(1, Ordering::Relaxed);
@@ -30,14 +61,31 @@ pub fn add(name: &'static str, f: fn()) {
     unsafe { TESTS[idx] = Some(TestEntry { name, func: f }) };
 }
 
+/// Marks the running test as skipped because the machine lacks what it
+/// needs; the test returns right after.
+pub fn skip(reason: &str) {
+    log::info!("skipping: {}", reason);
+    SKIPPED.store(true, Ordering::Relaxed);
+}
+
 pub fn _run_all() {
+    let mut skipped = 0;
     for slot in unsafe { &TESTS[..NEXT.load(Ordering::Relaxed)] } {
         if let Some(t) = slot {
             log::info!("Running test: {}", t.name);
+            SKIPPED.store(false, Ordering::Relaxed);
             (t.func)();
-            log::info!("Test '{}'    [ok]", t.name);
+            if SKIPPED.load(Ordering::Relaxed) {
+                log::info!("Test '{}'    [skipped]", t.name);
+                skipped += 1;
+            } else {
+                log::info!("Test '{}'    [ok]", t.name);
+            }
         }
     }
+    if skipped > 0 {
+        log::warn!("{} test(s) skipped", skipped);
+    }
 }
 
 #[macro_export]
@@ -0,0 +1,148 @@
+use core::time::Duration;
+
+use crate::cursor::{self, Cursor};
+use crate::framebuffer::{Color, FramebufferDisplay, Position};
+use crate::mouse::{MouseButtons, MouseKind, PacketAssembler};
+use crate::*;
+
+/// Colours a square big enough for the cursor, each pixel differently.
+fn paint(display: &mut FramebufferDisplay, size: usize) -> impl Fn(Position) -> Color + use<> {
+    let color = |position: Position| Color::new(position.x as u8, position.y as u8, 0x80);
+    for y in 0..size {
+        for x in 0..size {
+            display.set_pixel(Position { x, y }, color(Position { x, y }));
+        }
+    }
+    color
+}
+
+ktest!(
+    fn assemble_standard_packets() {
+        let mut assembler = PacketAssembler::new(MouseKind::Standard);
+        let now = Duration::from_millis(1);
+
+        // left button down, x = +5, y = -3 (towards the user)
+        assert_eq!(assembler.feed(0x29, now), None);
+        assert_eq!(assembler.feed(0x05, now), None);
+        let event = assembler.feed(0xFD, now).unwrap();
+        assert_eq!((event.dx, event.dy, event.wheel), (5, 3, 0));
+        assert_eq!(event.pressed(), MouseButtons::LEFT);
+
+        let event = [0x18, 0xFE, 0x00]
+            .into_iter()
+            .find_map(|byte| assembler.feed(byte, now))
+            .unwrap();
+        assert_eq!((event.dx, event.dy), (-2, 0));
+        assert_eq!(event.released(), MouseButtons::LEFT);
+        assert!(event.buttons.is_empty());
+    }
+);
+
+ktest!(
+    fn assemble_five_button_packets() {
+        let mut assembler = PacketAssembler::new(MouseKind::FiveButton);
+        let now = Duration::from_millis(1);
+
+        let event = [0x08, 0x00, 0x00, 0x1F]
+            .into_iter()
+            .find_map(|byte| assembler.feed(byte, now))
+            .unwrap();
+        assert_eq!(event.wheel, -1);
+        assert_eq!(event.pressed(), MouseButtons::BUTTON4);
+
+        let event = [0x0C, 0x00, 0x00, 0x21]
+            .into_iter()
+            .find_map(|byte| assembler.feed(byte, now))
+            .unwrap();
+        assert_eq!(event.wheel, 1);
+        assert_eq!(
+            event.pressed(),
+            MouseButtons::MIDDLE | MouseButtons::BUTTON5
+        );
+        assert_eq!(event.released(), MouseButtons::BUTTON4);
+    }
+);
+
+ktest!(
+    fn resync_after_lost_bytes() {
+        let mut assembler = PacketAssembler::new(MouseKind::Standard);
+
+        // a packet that lost its last byte, then a long pause
+        assert_eq!(assembler.feed(0x08, Duration::from_millis(1)), None);
+        assert_eq!(assembler.feed(0x10, Duration::from_millis(2)), None);
+        let later = Duration::from_millis(100);
+        assert_eq!(assembler.feed(0x08, later), None);
+        assert_eq!(assembler.feed(0x01, later), None);
+        let event = assembler.feed(0x02, later).unwrap();
+        assert_eq!((event.dx, event.dy), (1, -2));
+
+        // bytes that cannot start a packet are skipped
+        assert_eq!(assembler.feed(0x01, later), None);
+        assert_eq!(assembler.feed(0x08, later), None);
+        assert_eq!(assembler.feed(0x04, later), None);
+        let event = assembler.feed(0x00, later).unwrap();
+        assert_eq!((event.dx, event.dy), (4, 0));
+    }
+);
+
+ktest!(
+    fn cursor_restores_what_it_covered() {
+        logger::with_framebuffer(|display| {
+            let size = 2 * cursor::HEIGHT;
+            let color = paint(display, size);
+            let painted = |display: &FramebufferDisplay| {
+                (0..size).all(|y| {
+                    (0..size).all(|x| {
+                        let position = Position { x, y };
+                        display.get_pixel(position) == Some(color(position))
+                    })
+                })
+            };
+
+            let mut pointer = Cursor::new();
+            pointer.move_to(display, Position { x: 3, y: 4 });
+            pointer.show(display);
+            assert_eq!(
+                display.get_pixel(Position { x: 3, y: 4 }),
+                Some(Color::BLACK)
+            );
+            assert_eq!(
+                display.get_pixel(Position { x: 4, y: 6 }),
+                Some(Color::WHITE)
+            );
+            assert!(!painted(display));
+
+            // moving puts the old spot back before drawing the new one
+            pointer.move_to(display, Position { x: 10, y: 12 });
+            assert_eq!(
+                display.get_pixel(Position { x: 3, y: 4 }),
+                Some(color(Position { x: 3, y: 4 }))
+            );
+            pointer.hide(display);
+            assert!(painted(display));
+        });
+    }
+);
+
+ktest!(
+    fn pointer_moves_once_the_console_is_free() {
+        if logger::with_framebuffer(|_| ()).is_none() {
+            return;
+        }
+        // the first move shows it
+        logger::move_pointer(1, 1);
+        let start = logger::pointer_position().unwrap();
+        logger::with_sinks_locked(|| logger::move_pointer(-3, -2));
+        let moved = logger::pointer_position().unwrap();
+        assert_eq!(moved.x, start.x.saturating_sub(3));
+        assert_eq!(moved.y, start.y.saturating_sub(2));
+    }
+);
+
+register_tests!(
+    assemble_standard_packets,
+    assemble_five_button_packets,
+    resync_after_lost_bytes,
+    cursor_restores_what_it_covered,
+    pointer_moves_once_the_console_is_free
+);
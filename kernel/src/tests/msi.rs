@@ -0,0 +1,42 @@
+use crate::interrupts::{self, DYNAMIC_VECTORS, FIRST_DYNAMIC_VECTOR};
+use crate::msi::{MsiCapability, MsixCapability};
+use crate::pci;
+use crate::*;
+
+ktest!(
+    fn allocate_aligned_vector_blocks() {
+        let single = interrupts::allocate_vectors(1, 1).unwrap();
+        let block = interrupts::allocate_vectors(8, 8).unwrap();
+        assert_eq!(block % 8, 0);
+        assert!(block >= FIRST_DYNAMIC_VECTOR);
+        assert!(!(block..block + 8).contains(&single));
+
+        interrupts::set_vector_handler(block + 3, |_| {}, 3);
+        interrupts::clear_vector_handler(block + 3);
+
+        // freed vectors are handed out again
+        interrupts::free_vectors(block, 8);
+        assert_eq!(interrupts::allocate_vectors(8, 8), Some(block));
+        interrupts::free_vectors(block, 8);
+        interrupts::free_vectors(single, 1);
+
+        assert_eq!(interrupts::allocate_vectors(DYNAMIC_VECTORS + 1, 1), None);
+    }
+);
+
+ktest!(
+    fn parse_msi_capabilities() {
+        for device in pci::devices() {
+            if let Some(msi) = MsiCapability::read(device.address) {
+                assert!(msi.max_vectors.is_power_of_two() && msi.max_vectors <= 32);
+            }
+            if let Some(msix) = MsixCapability::read(device.address) {
+                assert!(msix.table_size <= 2048);
+                assert!(msix.table_bar < 6 && msix.pba_bar < 6);
+                assert!(device.memory_bar(msix.table_bar as usize).is_some());
+            }
+        }
+    }
+);
+
+register_tests!(allocate_aligned_vector_blocks, parse_msi_capabilities);
@@ -0,0 +1,60 @@
+use crate::memory;
+use crate::nvme::{self, Namespace, NvmeError, NvmeOp};
+use crate::tests::block::{pattern, scratch};
+use crate::*;
+
+/// The namespace of the `-device nvme` the kernel-test runner attaches.
+fn scratch_namespace() -> Namespace {
+    scratch(nvme::namespaces(), "NVMe")
+}
+
+ktest!(
+    fn read_after_write() {
+        let namespace = scratch_namespace();
+        let info = namespace.info();
+        assert!(info.blocks >= 64);
+        assert!(nvme::controller().unwrap().io_queues >= 1);
+
+        let mut data = [0u8; 8192];
+        pattern(2, &mut data);
+        namespace.write(2, &data).unwrap();
+        namespace.flush().unwrap();
+        let mut back = [0u8; 8192];
+        namespace.read(2, &mut back).unwrap();
+        assert_eq!(data, back);
+
+        let last = info.blocks - 1;
+        assert_eq!(namespace.read(last, &mut back), Err(NvmeError::OutOfRange));
+    }
+);
+
+ktest!(
+    fn prp_list_transfers() {
+        let namespace = scratch_namespace();
+        let block_size = namespace.info().block_size;
+        // an unaligned start spreads five pages over six, which needs a
+        // PRP list behind PRP2
+        const PAGES: usize = 6;
+        let page = memory::FRAME_SIZE as usize;
+        let buffer = memory::allocate_frames(PAGES).unwrap();
+        let len = 5 * page;
+        let bytes = unsafe { memory::phys_slice_mut(buffer, PAGES * page) };
+        pattern(40, &mut bytes[block_size..block_size + len]);
+        let request = namespace
+            .submit(NvmeOp::Write {
+                lba: 40,
+                phys: buffer + block_size as u64,
+                len: len as u32,
+            })
+            .unwrap();
+        namespace.wait(request).unwrap();
+
+        let mut back = [0u8; 5 * 4096];
+        namespace.read(40, &mut back).unwrap();
+        let bytes = unsafe { memory::phys_slice_mut(buffer, PAGES * page) };
+        assert!(back[..] == bytes[block_size..block_size + len]);
+        memory::free_frames(buffer, PAGES);
+    }
+);
+
+register_tests!(read_after_write, prp_list_transfers);
@@ -0,0 +1,58 @@
+use crate::pci::{self, PciAddress};
+use crate::*;
+
+ktest!(
+    fn registry_matches_config_space() {
+        let host = pci::device(PciAddress::new(0, 0, 0)).expect("no host bridge");
+        assert_eq!(host.class, pci::class::BRIDGE);
+
+        let mut count = 0;
+        for device in pci::devices() {
+            assert!(device.address.exists());
+            assert_eq!(device.address.vendor_id(), device.vendor_id);
+            assert_eq!(device.address.device_id(), device.device_id);
+            assert_eq!(
+                pci::find(device.vendor_id, device.device_id).map(|found| found.vendor_id),
+                Some(device.vendor_id)
+            );
+            // capability walks terminate and stay in bounds
+            for capability in device.address.capabilities() {
+                assert!(capability.offset >= 0x40 && capability.offset < 0x100);
+            }
+            count += 1;
+        }
+        assert!(count > 1);
+    }
+);
+
+ktest!(
+    fn bar_sizes_are_powers_of_two() {
+        for device in pci::devices() {
+            for bar in device.bars.iter().flatten() {
+                assert!(bar.size().is_power_of_two(), "{}: {}", device, bar);
+                if let Some(base) = bar.memory_base() {
+                    assert!(base.is_multiple_of(bar.size().min(4096)));
+                }
+            }
+        }
+    }
+);
+
+ktest!(
+    fn command_writes_keep_status() {
+        for device in pci::devices() {
+            let address = device.address;
+            let errors = || address.read_u16(pci::offset::STATUS) & pci::status::ERRORS;
+            let before = errors();
+            address.disable(0);
+            address.read_bar(0);
+            assert_eq!(errors(), before, "{}", device);
+        }
+    }
+);
+
+register_tests!(
+    registry_matches_config_space,
+    bar_sizes_are_powers_of_two,
+    command_writes_keep_status
+);
@@ -0,0 +1,70 @@
+use alloc::vec;
+
+use crate::devfs::ioctl;
+use crate::fd;
+use crate::pipe::{self, PIPE_BUF, PIPE_SIZE};
+use crate::task;
+use crate::vfs::{FileType, OpenFlags, VfsError};
+use crate::*;
+
+ktest!(
+    fn data_and_end_of_file() {
+        let [read_fd, write_fd] = fd::pipe(OpenFlags::CLOSE_ON_EXEC).unwrap();
+        let task = task::current();
+        let files = task.files();
+        let (reader, writer) = (files.get(read_fd).unwrap(), files.get(write_fd).unwrap());
+        assert!(files.close_on_exec(write_fd).unwrap());
+        assert_eq!(reader.metadata().unwrap().kind, FileType::Fifo);
+        assert_eq!(reader.write(b"x"), Err(VfsError::BadDescriptor));
+        assert_eq!(writer.read(&mut [0; 4]), Err(VfsError::BadDescriptor));
+
+        assert_eq!(writer.write(b"hello"), Ok(5));
+        let mut buf = [0; 3];
+        assert_eq!(reader.read(&mut buf), Ok(3));
+        assert_eq!(&buf, b"hel");
+        let mut waiting = [0; 4];
+        reader.ioctl(ioctl::FIONREAD, &mut waiting).unwrap();
+        assert_eq!(i32::from_ne_bytes(waiting), 2);
+
+        // what is left is still read once the write end is closed, then EOF
+        files.close(write_fd).unwrap();
+        drop(writer);
+        assert_eq!(reader.read(&mut buf), Ok(2));
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(reader.read(&mut buf), Ok(0));
+        files.close(read_fd).unwrap();
+    }
+);
+
+ktest!(
+    fn non_blocking_and_broken_pipe() {
+        let (reader, writer) = pipe::pipe(OpenFlags::NON_BLOCK);
+        assert!(
+            reader
+                .flags()
+                .contains(OpenFlags::READ | OpenFlags::NON_BLOCK)
+        );
+        assert_eq!(reader.read(&mut [0; 8]), Err(VfsError::WouldBlock));
+
+        // a large write takes what fits, then would block
+        let data = vec![7; PIPE_SIZE + 100];
+        assert_eq!(writer.write(&data), Ok(PIPE_SIZE));
+        assert_eq!(writer.write(b"x"), Err(VfsError::WouldBlock));
+
+        // a small write goes in whole or not at all
+        let mut buf = vec![0; PIPE_BUF];
+        assert_eq!(reader.read(&mut buf[..10]), Ok(10));
+        assert_eq!(writer.write(&[1; 20]), Err(VfsError::WouldBlock));
+        assert_eq!(writer.write(&[1; 10]), Ok(10));
+
+        // with data waiting, a blocking read doesn't wait
+        reader.set_flags(OpenFlags::default());
+        assert!(!reader.flags().contains(OpenFlags::NON_BLOCK));
+        assert_eq!(reader.read(&mut buf), Ok(PIPE_BUF));
+
+        drop(reader);
+        assert_eq!(writer.write(b"x"), Err(VfsError::BrokenPipe));
+    }
+);
+
+register_tests!(data_and_end_of_file, non_blocking_and_broken_pipe);
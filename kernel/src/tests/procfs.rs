@@ -0,0 +1,86 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::interrupts;
+use crate::vfs::{self, File, OpenFlags, SeekFrom, VfsError};
+use crate::*;
+
+fn read_to_string(file: &dyn File) -> String {
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 100];
+    loop {
+        match file.read(&mut chunk).unwrap() {
+            0 => return String::from_utf8(data).unwrap(),
+            read => data.extend_from_slice(&chunk[..read]),
+        }
+    }
+}
+
+fn read_file(path: &str) -> String {
+    read_to_string(&*vfs::open(path, OpenFlags::READ).unwrap())
+}
+
+ktest!(
+    fn generated_files() {
+        let proc = vfs::open("/proc", OpenFlags::READ).unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = proc.read_dir().unwrap() {
+            names.push(entry.name);
+        }
+        for name in ["cpuinfo", "interrupts", "meminfo", "mounts", "tasks"] {
+            assert!(names.iter().any(|entry| entry == name), "{}", name);
+        }
+
+        let meminfo = read_file("/proc/meminfo");
+        assert!(meminfo.starts_with("MemTotal:"));
+        assert!(meminfo.lines().any(|line| line.starts_with("HeapUsed:")));
+        assert!(read_file("/proc/cpuinfo").contains("flags\t\t: fpu"));
+        assert!(read_file("/proc/mounts").contains("proc /proc procfs ro 0 0"));
+        assert!(
+            read_file("/proc/tasks")
+                .lines()
+                .any(|line| line.ends_with("  kernel"))
+        );
+
+        // the breakpoint counter moves with every int3
+        let before = interrupts::count(3);
+        interrupts::int3();
+        assert_eq!(interrupts::count(3), before + 1);
+        let text = read_file("/proc/interrupts");
+        let line = text
+            .lines()
+            .find(|line| line.ends_with("breakpoint"))
+            .unwrap();
+        let shown: u64 = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+        assert_eq!(shown, before + 1);
+
+        assert_eq!(
+            vfs::open("/proc/meminfo", OpenFlags::WRITE).err(),
+            Some(VfsError::ReadOnly)
+        );
+        assert_eq!(
+            vfs::open("/proc/new", OpenFlags::WRITE | OpenFlags::CREATE).err(),
+            Some(VfsError::ReadOnly)
+        );
+    }
+);
+
+ktest!(
+    fn snapshot_per_open() {
+        let early = vfs::open("/proc/dmesg", OpenFlags::READ).unwrap();
+        log::info!("procfs snapshot marker");
+        let late = read_file("/proc/dmesg");
+        assert!(late.contains("procfs snapshot marker"));
+
+        // the open file keeps the text it was opened with, and can seek in it
+        let text = read_to_string(&*early);
+        assert!(!text.contains("procfs snapshot marker"));
+        let end = early.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(end, text.len() as u64);
+        early.seek(SeekFrom::Start(end - 10)).unwrap();
+        assert_eq!(read_to_string(&*early), text[text.len() - 10..]);
+        assert_eq!(vfs::stat("/proc/dmesg").unwrap().size, 0);
+    }
+);
+
+register_tests!(generated_files, snapshot_per_open);
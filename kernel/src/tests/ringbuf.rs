@@ -0,0 +1,20 @@
+use crate::ringbuf::RingBuffer;
+use crate::*;
+
+ktest!(
+    fn ring_buffer_wraps() {
+        let ring: RingBuffer<u8, 4> = RingBuffer::new();
+        for round in 0..3u8 {
+            for i in 0..4 {
+                assert!(ring.push(round * 4 + i).is_ok());
+            }
+            assert_eq!(ring.push(0xFF), Err(0xFF));
+            for i in 0..4 {
+                assert_eq!(ring.pop(), Some(round * 4 + i));
+            }
+            assert_eq!(ring.pop(), None);
+        }
+    }
+);
+
+register_tests!(ring_buffer_wraps);
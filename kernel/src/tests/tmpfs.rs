@@ -0,0 +1,105 @@
+use crate::tests::vfs::{read_all, scratch_dir};
+use crate::tmpfs::TmpFs;
+use crate::vfs::{self, Attributes, FileType, OpenFlags, SeekFrom, VfsError};
+use crate::*;
+
+ktest!(
+    fn links_and_attributes() {
+        let mnt = scratch_dir("tmpfs-links");
+        vfs::mount(&mnt, "tmpfs", TmpFs::new()).unwrap();
+        let path = |rest: &str| alloc::format!("{}/{}", mnt, rest);
+
+        vfs::mkdir(&path("dir"), 0o755).unwrap();
+        vfs::open(&path("a"), OpenFlags::WRITE | OpenFlags::CREATE)
+            .unwrap()
+            .write(b"shared")
+            .unwrap();
+        vfs::link(&path("a"), &path("dir/b")).unwrap();
+        let (a, b) = (
+            vfs::stat(&path("a")).unwrap(),
+            vfs::stat(&path("dir/b")).unwrap(),
+        );
+        assert_eq!((a.ino, a.links), (b.ino, 2));
+        assert_eq!(vfs::stat(&mnt).unwrap().links, 3);
+        vfs::unlink(&path("a")).unwrap();
+        assert_eq!(read_all(&path("dir/b")), b"shared");
+        assert_eq!(vfs::stat(&path("dir/b")).unwrap().links, 1);
+
+        assert_eq!(
+            vfs::link(&path("dir"), &path("dir2")).err(),
+            Some(VfsError::NotPermitted)
+        );
+        vfs::open(&path("../outside"), OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        assert_eq!(
+            vfs::link(&path("../outside"), &path("inside")).err(),
+            Some(VfsError::CrossDevice)
+        );
+
+        vfs::chmod(&path("dir/b"), 0o4711).unwrap();
+        vfs::chown(&path("dir/b"), 1000, 100).unwrap();
+        let times = Attributes {
+            accessed: Some(1_000_000),
+            modified: Some(2_000_000),
+            ..Attributes::default()
+        };
+        vfs::set_attributes(&path("dir/b"), &times).unwrap();
+        let b = vfs::stat(&path("dir/b")).unwrap();
+        assert_eq!(
+            (b.kind, b.mode, b.uid, b.gid),
+            (FileType::Regular, 0o4711, 1000, 100)
+        );
+        assert_eq!((b.accessed, b.modified), (1_000_000, 2_000_000));
+        assert!(b.changed >= 2_000_000);
+        vfs::unmount(&mnt).unwrap();
+    }
+);
+
+ktest!(
+    fn sparse_files_and_limit() {
+        let fs = TmpFs::with_limit(4 * 4096);
+        let mnt = scratch_dir("tmpfs-limit");
+        vfs::mount(&mnt, "tmpfs", fs.clone()).unwrap();
+        let path = |rest: &str| alloc::format!("{}/{}", mnt, rest);
+
+        // a byte a megabyte in takes one page, and the hole reads as zeroes
+        let sparse = vfs::open(&path("sparse"), OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        sparse.seek(SeekFrom::Start(1 << 20)).unwrap();
+        sparse.write(b"x").unwrap();
+        assert_eq!(fs.used(), 4096);
+        let data = read_all(&path("sparse"));
+        assert_eq!(data.len(), (1 << 20) + 1);
+        assert!(data[..1 << 20].iter().all(|byte| *byte == 0));
+        assert_eq!(vfs::stat(&path("sparse")).unwrap().blocks, 8);
+
+        // three pages are left; a longer write stops where they run out
+        let full = vfs::open(&path("full"), OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+        assert_eq!(full.write(&[7; 5 * 4096]).unwrap(), 3 * 4096);
+        assert_eq!(full.write(b"more").err(), Some(VfsError::NoSpace));
+        assert_eq!(
+            vfs::symlink("target", &path("link")).err(),
+            Some(VfsError::NoSpace)
+        );
+
+        // truncating gives pages back and zeroes the rest of the last one
+        let inode = vfs::resolve(&path("full"), true).unwrap().inode().clone();
+        inode.truncate(100).unwrap();
+        assert_eq!(fs.used(), 2 * 4096);
+        full.seek(SeekFrom::Start(200)).unwrap();
+        full.write(b"end").unwrap();
+        let data = read_all(&path("full"));
+        assert!(data[..100].iter().all(|byte| *byte == 7));
+        assert!(data[100..200].iter().all(|byte| *byte == 0));
+
+        // an unlinked file keeps its pages until it is closed
+        vfs::unlink(&path("full")).unwrap();
+        assert_eq!(fs.used(), 2 * 4096);
+        drop((full, inode));
+        assert_eq!(fs.used(), 4096);
+        drop(sparse);
+        vfs::unlink(&path("sparse")).unwrap();
+        assert_eq!(fs.used(), 0);
+        vfs::unmount(&mnt).unwrap();
+    }
+);
+
+register_tests!(links_and_attributes, sparse_files_and_limit);
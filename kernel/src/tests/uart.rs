@@ -0,0 +1,39 @@
+use crate::uart::{self, LineConfig, UartError};
+use crate::*;
+
+ktest!(
+    fn loopback() {
+        // the console is interrupt-driven by now; the probe has to get its
+        // byte back all the same, and the console keep working after
+        assert!(uart::COM1.is_interrupt_driven());
+        assert_eq!(uart::COM1.probe(), Ok(()));
+        assert!(uart::COM1.is_interrupt_driven());
+        log::info!("COM1 passed its loopback test.");
+
+        for port in [&uart::COM2, &uart::COM3, &uart::COM4] {
+            if !port.is_present() {
+                assert_eq!(port.init(LineConfig::default()), Err(UartError::NotPresent));
+            }
+        }
+    }
+);
+
+ktest!(
+    fn rejects_bad_line_settings() {
+        let config = LineConfig {
+            baud: 100_000,
+            ..LineConfig::default()
+        };
+        assert_eq!(
+            uart::COM4.init(config),
+            Err(UartError::InvalidBaudRate(100_000))
+        );
+        let config = LineConfig {
+            data_bits: 9,
+            ..LineConfig::default()
+        };
+        assert_eq!(uart::COM4.init(config), Err(UartError::InvalidDataBits(9)));
+    }
+);
+
+register_tests!(loopback, rejects_bad_line_settings);
@@ -0,0 +1,282 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::sync::SpinLock;
+use crate::vfs::{
+    self, DirEntry, FileSystem, FileType, Inode, Metadata, OpenFlags, SeekFrom, VfsError,
+};
+use crate::*;
+
+/// Just enough of an in-memory filesystem to drive the VFS with.
+struct Node {
+    ino: u64,
+    kind: FileType,
+    data: SpinLock<Vec<u8>>,
+    children: SpinLock<BTreeMap<String, Arc<Node>>>,
+}
+
+impl Node {
+    fn new(kind: FileType, data: &[u8]) -> Arc<Node> {
+        static NEXT_INO: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(1);
+        Arc::new(Node {
+            ino: NEXT_INO.fetch_add(1, core::sync::atomic::Ordering::Relaxed),
+            kind,
+            data: SpinLock::new(data.into()),
+            children: SpinLock::new(BTreeMap::new()),
+        })
+    }
+
+    fn insert(&self, name: &str, node: Arc<Node>) -> vfs::Result<Arc<dyn Inode>> {
+        let mut children = self.children.lock();
+        if children.contains_key(name) {
+            return Err(VfsError::Exists);
+        }
+        children.insert(name.into(), node.clone());
+        Ok(node)
+    }
+}
+
+impl Inode for Node {
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        Ok(Metadata {
+            ino: self.ino,
+            kind: self.kind,
+            size: self.data.lock().len() as u64,
+            links: 1,
+            ..Metadata::default()
+        })
+    }
+
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let child = self.children.lock().get(name).cloned();
+        child
+            .map(|node| node as Arc<dyn Inode>)
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn create(&self, name: &str, kind: FileType, _mode: u16) -> vfs::Result<Arc<dyn Inode>> {
+        self.insert(name, Node::new(kind, &[]))
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> vfs::Result<Arc<dyn Inode>> {
+        self.insert(name, Node::new(FileType::Symlink, target.as_bytes()))
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.children
+            .lock()
+            .remove(name)
+            .map(drop)
+            .ok_or(VfsError::NotFound)
+    }
+
+    fn rmdir(&self, name: &str) -> vfs::Result<()> {
+        let mut children = self.children.lock();
+        let child = children.get(name).ok_or(VfsError::NotFound)?;
+        if !child.children.lock().is_empty() {
+            return Err(VfsError::NotEmpty);
+        }
+        children.remove(name);
+        Ok(())
+    }
+
+    fn read_dir(&self, cursor: u64) -> vfs::Result<Option<(DirEntry, u64)>> {
+        let children = self.children.lock();
+        let entry = children.iter().nth(cursor as usize).map(|(name, node)| {
+            let entry = DirEntry {
+                ino: node.ino,
+                kind: node.kind,
+                name: name.clone(),
+            };
+            (entry, cursor + 1)
+        });
+        Ok(entry)
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let data = self.data.lock();
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        let mut data = self.data.lock();
+        let end = offset as usize + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[offset as usize..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> vfs::Result<()> {
+        self.data.lock().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn read_link(&self) -> vfs::Result<String> {
+        String::from_utf8(self.data.lock().clone()).map_err(|_| VfsError::InvalidArgument)
+    }
+}
+
+struct TestFs {
+    root: Arc<Node>,
+}
+
+impl FileSystem for TestFs {
+    fn name(&self) -> &'static str {
+        "testfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+fn test_fs() -> Arc<dyn FileSystem> {
+    Arc::new(TestFs {
+        root: Node::new(FileType::Directory, &[]),
+    })
+}
+
+/// The whole file at `path`, read in pieces that straddle block and page
+/// boundaries.
+pub fn read_all(path: &str) -> Vec<u8> {
+    let file = vfs::open(path, OpenFlags::READ).unwrap();
+    read_until_end(|_, chunk| file.read(chunk).unwrap())
+}
+
+/// The whole of `inode`, read as [`read_all`] does but without a mount.
+pub fn read_inode(inode: &Arc<dyn Inode>) -> Vec<u8> {
+    read_until_end(|offset, chunk| inode.read_at(offset, chunk).unwrap())
+}
+
+fn read_until_end(mut read: impl FnMut(u64, &mut [u8]) -> usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 700];
+    loop {
+        match read(data.len() as u64, &mut chunk) {
+            0 => return data,
+            len => data.extend_from_slice(&chunk[..len]),
+        }
+    }
+}
+
+/// A fresh directory to work in, mounting a root filesystem first if the
+/// kernel booted without one.
+pub fn scratch_dir(name: &str) -> String {
+    if vfs::root().is_err() {
+        vfs::mount("/", "test", test_fs()).unwrap();
+    }
+    match vfs::mkdir("/tmp", 0o1777) {
+        Ok(()) | Err(VfsError::Exists) => {}
+        Err(err) => panic!("cannot create /tmp: {}", err),
+    }
+    let path = alloc::format!("/tmp/{}", name);
+    vfs::mkdir(&path, 0o755).unwrap();
+    path
+}
+
+ktest!(
+    fn path_resolution() {
+        let base = scratch_dir("vfs-paths");
+        let path = |rest: &str| alloc::format!("{}/{}", base, rest);
+        vfs::mkdir(&path("a"), 0o755).unwrap();
+        vfs::mkdir(&path("a/b"), 0o755).unwrap();
+        vfs::open(&path("a/b/f"), OpenFlags::WRITE | OpenFlags::CREATE).unwrap();
+
+        let file = vfs::resolve(&path("a/./b/../b//f"), true).unwrap();
+        assert_eq!(file.path(), path("a/b/f"));
+        assert_eq!(vfs::resolve("/../..", true).unwrap().path(), "/");
+        assert_eq!(
+            vfs::resolve(&path("a/b/f/g"), true).err(),
+            Some(VfsError::NotDirectory)
+        );
+
+        vfs::symlink("b", &path("a/link")).unwrap();
+        vfs::symlink(&path("a/b/f"), &path("absolute")).unwrap();
+        assert_eq!(
+            vfs::resolve(&path("a/link/f"), true).unwrap().path(),
+            path("a/b/f")
+        );
+        assert_eq!(
+            vfs::resolve(&path("absolute"), true).unwrap().path(),
+            path("a/b/f")
+        );
+        assert_eq!(vfs::lstat(&path("a/link")).unwrap().kind, FileType::Symlink);
+        assert_eq!(
+            vfs::stat(&path("a/link")).unwrap().kind,
+            FileType::Directory
+        );
+        assert_eq!(vfs::read_link(&path("a/link")).unwrap(), "b");
+
+        vfs::symlink("loop", &path("loop")).unwrap();
+        assert_eq!(vfs::stat(&path("loop")).err(), Some(VfsError::SymlinkLoop));
+        assert_eq!(
+            vfs::open(&path("absolute"), OpenFlags::READ | OpenFlags::NO_FOLLOW).err(),
+            Some(VfsError::SymlinkLoop)
+        );
+
+        assert_eq!(vfs::rmdir(&path("a/b")).err(), Some(VfsError::NotEmpty));
+        vfs::unlink(&path("a/b/f")).unwrap();
+        assert_eq!(vfs::stat(&path("absolute")).err(), Some(VfsError::NotFound));
+        vfs::rmdir(&path("a/b")).unwrap();
+        assert_eq!(vfs::stat(&path("a/link")).err(), Some(VfsError::NotFound));
+    }
+);
+
+ktest!(
+    fn mounts_and_files() {
+        let base = scratch_dir("vfs-mounts");
+        let mnt = alloc::format!("{}/mnt", base);
+        vfs::mkdir(&mnt, 0o755).unwrap();
+        vfs::mount(&mnt, "second", test_fs()).unwrap();
+        assert!(vfs::mounts().iter().any(|mount| mount.path == mnt));
+
+        let name = alloc::format!("{}/file", mnt);
+        let flags = OpenFlags::READ_WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+        let file = vfs::open(&name, flags).unwrap();
+        assert_eq!(vfs::open(&name, flags).err(), Some(VfsError::Exists));
+        assert_eq!(file.write(b"hello, world").unwrap(), 12);
+        assert_eq!(file.seek(SeekFrom::Current(-5)).unwrap(), 7);
+        let mut buf = [0u8; 16];
+        assert_eq!(file.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(file.seek(SeekFrom::End(-12)).unwrap(), 0);
+        assert_eq!(
+            file.seek(SeekFrom::Current(-1)).err(),
+            Some(VfsError::InvalidArgument)
+        );
+
+        let appender = vfs::open(&name, OpenFlags::WRITE | OpenFlags::APPEND).unwrap();
+        appender.write(b"!").unwrap();
+        assert_eq!(vfs::stat(&name).unwrap().size, 13);
+        let reader = vfs::open(&name, OpenFlags::READ).unwrap();
+        assert_eq!(reader.write(b"x").err(), Some(VfsError::BadDescriptor));
+
+        // `..` leaves the mounted filesystem through its mountpoint
+        let up = alloc::format!("{}/../mnt/file", mnt);
+        assert_eq!(vfs::resolve(&up, true).unwrap().path(), name);
+
+        let dir = vfs::open(&mnt, OpenFlags::READ | OpenFlags::DIRECTORY).unwrap();
+        let entry = dir.read_dir().unwrap().unwrap();
+        assert_eq!(
+            (entry.name.as_str(), entry.kind),
+            ("file", FileType::Regular)
+        );
+        assert_eq!(dir.read_dir().unwrap(), None);
+        assert_eq!(dir.read(&mut buf).err(), Some(VfsError::IsDirectory));
+
+        assert_eq!(vfs::rmdir(&mnt).err(), Some(VfsError::Busy));
+        vfs::unmount(&mnt).unwrap();
+        assert_eq!(vfs::stat(&name).err(), Some(VfsError::NotFound));
+        assert!(!vfs::mounts().iter().any(|mount| mount.path == mnt));
+        vfs::rmdir(&mnt).unwrap();
+    }
+);
+
+register_tests!(path_resolution, mounts_and_files);
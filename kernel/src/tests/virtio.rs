@@ -0,0 +1,91 @@
+use crate::memory;
+use crate::virtqueue::{self, MAX_INDIRECT, Segment, Used, Virtqueue};
+use crate::*;
+
+/// Plays the device: takes the next available chain and returns it as used.
+fn complete_next(queue: &Virtqueue, consumed: &mut u16, len: u32) -> (u16, u16) {
+    let size = queue.size() as usize;
+    let avail: *mut u16 = memory::phys_to_virt(queue.avail_phys()).cast();
+    let used: *mut u16 = memory::phys_to_virt(queue.used_phys()).cast();
+    let desc: *mut u16 = memory::phys_to_virt(queue.desc_phys()).cast();
+    unsafe {
+        let head = avail.add(2 + *consumed as usize % size).read_volatile();
+        // flags of the head descriptor sit at byte 12
+        let flags = desc.add(head as usize * 8 + 6).read_volatile();
+        let used_idx = used.add(1).read_volatile();
+        let element = used.add(2 + 4 * (used_idx as usize % size)).cast::<u32>();
+        element.write_volatile(head as u32);
+        element.add(1).write_volatile(len);
+        used.add(1).write_volatile(used_idx.wrapping_add(1));
+        *consumed += 1;
+        (head, flags)
+    }
+}
+
+ktest!(
+    fn split_queue_round_trip() {
+        let mut doorbell = 0u16;
+        let mut queue = Virtqueue::new(3, 8, false, false, &mut doorbell).unwrap();
+        let mut consumed = 0;
+        let segments = [
+            Segment::readable(0x1000, 16),
+            Segment::writable(0x2000, 512),
+            Segment::writable(0x3000, 1),
+        ];
+
+        queue.push(&segments, 7).unwrap();
+        queue.push(&segments[..1], 8).unwrap();
+        assert_eq!(queue.free_descriptors(), 4);
+        queue.push(&segments, 9).unwrap();
+        assert!(queue.push(&segments, 10).is_err());
+        assert!(queue.kick());
+        assert_eq!(unsafe { core::ptr::read_volatile(&doorbell) }, 3);
+
+        let (_, flags) = complete_next(&queue, &mut consumed, 513);
+        assert_eq!(flags & 1, 1, "chain head lacks NEXT");
+        assert_eq!(queue.pop_used(), Some(Used { token: 7, len: 513 }));
+        complete_next(&queue, &mut consumed, 0);
+        assert_eq!(queue.pop_used(), Some(Used { token: 8, len: 0 }));
+        complete_next(&queue, &mut consumed, 0);
+        assert_eq!(queue.pop_used().map(|used| used.token), Some(9));
+        assert_eq!(queue.pop_used(), None);
+        assert!(queue.is_empty());
+
+        // descriptors are recycled across many wraps of the ring
+        for token in 0..100 {
+            queue.push(&segments, token).unwrap();
+            complete_next(&queue, &mut consumed, 1);
+            assert_eq!(queue.pop_used().map(|used| used.token), Some(token));
+        }
+        assert!(queue.is_empty());
+    }
+);
+
+ktest!(
+    fn indirect_chains_and_event_idx() {
+        let mut doorbell = 0u16;
+        let mut queue = Virtqueue::new(0, 4, true, true, &mut doorbell).unwrap();
+        let mut consumed = 0;
+        let segments = [Segment::writable(0x4000, 8); MAX_INDIRECT];
+
+        // an indirect chain takes a single slot however long it is
+        for token in 0..4 {
+            queue.push(&segments, token).unwrap();
+        }
+        assert_eq!(queue.free_descriptors(), 0);
+        let (_, flags) = complete_next(&queue, &mut consumed, 8);
+        assert_eq!(flags, 4, "expected a lone INDIRECT descriptor");
+        assert_eq!(queue.pop_used().map(|used| used.token), Some(0));
+
+        // the device asked to be notified once index 2 is published
+        let used: *mut u16 = memory::phys_to_virt(queue.used_phys()).cast();
+        unsafe { used.add(2 + 4 * 4).write_volatile(5) };
+        assert!(!queue.kick());
+        queue.push(&segments[..1], 4).unwrap();
+        assert!(!queue.kick());
+        assert!(virtqueue::need_event(5, 6, 5));
+        assert!(!virtqueue::need_event(5, 5, 4));
+    }
+);
+
+register_tests!(split_queue_round_trip, indirect_chains_and_event_idx);
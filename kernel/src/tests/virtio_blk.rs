@@ -0,0 +1,106 @@
+use crate::memory;
+use crate::tests::block::{pattern, scratch};
+use crate::virtio_blk::{self, BlkError, BlkOp, MAX_IN_FLIGHT, SECTOR_SIZE, VirtioBlk};
+use crate::*;
+
+/// The scratch image the kernel-test runner attaches with `-drive if=virtio`.
+fn scratch_disk() -> VirtioBlk {
+    scratch(virtio_blk::disks(), "virtio-blk")
+}
+
+ktest!(
+    fn read_after_write() {
+        let disk = scratch_disk();
+        let info = disk.info();
+        assert!(info.sectors >= 64 && !info.read_only);
+
+        let mut data = [0u8; 8 * SECTOR_SIZE];
+        pattern(3, &mut data);
+        disk.write(3, &data).unwrap();
+        let mut back = [0u8; 8 * SECTOR_SIZE];
+        disk.read(3, &mut back).unwrap();
+        assert_eq!(data, back);
+
+        let last = info.sectors - 1;
+        disk.write(last, &data[..SECTOR_SIZE]).unwrap();
+        assert_eq!(disk.read(last, &mut back), Err(BlkError::OutOfRange));
+        if info.flush {
+            disk.flush().unwrap();
+        }
+    }
+);
+
+ktest!(
+    fn requests_in_flight() {
+        let disk = scratch_disk();
+        const REQUESTS: usize = 8;
+        let buffer = memory::allocate_frames(REQUESTS).unwrap();
+        let page = memory::FRAME_SIZE as usize;
+        let bytes = unsafe { memory::phys_slice_mut(buffer, REQUESTS * page) };
+        for (i, chunk) in bytes.chunks_mut(page).enumerate() {
+            pattern(100 + i as u64, chunk);
+        }
+
+        // all writes are outstanding before the first is waited for
+        let sectors_per_page = (page / SECTOR_SIZE) as u64;
+        let requests = core::array::from_fn::<_, REQUESTS, _>(|i| {
+            disk.submit(BlkOp::Write {
+                sector: 64 + i as u64 * sectors_per_page,
+                phys: buffer + (i * page) as u64,
+                len: page as u32,
+            })
+            .unwrap()
+        });
+        for request in requests.into_iter().rev() {
+            disk.wait(request).unwrap();
+        }
+
+        let mut back = [0u8; 4096];
+        let mut expected = [0u8; 4096];
+        for i in 0..REQUESTS {
+            disk.read(64 + i as u64 * sectors_per_page, &mut back)
+                .unwrap();
+            pattern(100 + i as u64, &mut expected);
+            assert_eq!(back, expected, "page {}", i);
+        }
+        memory::free_frames(buffer, REQUESTS);
+
+        if disk.info().discard {
+            disk.discard(64, (REQUESTS as u64 * sectors_per_page) as u32)
+                .unwrap();
+        }
+    }
+);
+
+ktest!(
+    fn dropped_completions_give_back_their_slots() {
+        let disk = scratch_disk();
+        let buffer = memory::allocate_frames(1).unwrap();
+        for _ in 0..2 * MAX_IN_FLIGHT {
+            let request = disk
+                .submit(BlkOp::Read {
+                    sector: 0,
+                    phys: buffer,
+                    len: SECTOR_SIZE as u32,
+                })
+                .unwrap();
+            drop(disk.complete(request));
+        }
+        memory::free_frames(buffer, 1);
+
+        // transfers bigger than one request are split up
+        let len = 2 * disk.max_transfer() + SECTOR_SIZE;
+        let mut data = alloc::vec![0u8; len];
+        pattern(200, &mut data);
+        disk.write(200, &data).unwrap();
+        let mut back = alloc::vec![0u8; len];
+        disk.read(200, &mut back).unwrap();
+        assert!(data == back);
+    }
+);
+
+register_tests!(
+    read_after_write,
+    requests_in_flight,
+    dropped_completions_give_back_their_slots
+);
@@ -0,0 +1,180 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::port::Port;
+
+/// Input frequency of the 8254 PIT in Hz.
+const PIT_FREQUENCY: u64 = 1_193_182;
+const CALIBRATION_MS: u64 = 10;
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Keyboard controller port B: bit 0 gates PIT channel 2, bit 1 drives the
+/// speaker, bit 5 mirrors the channel 2 output.
+const PORT_B: u16 = 0x61;
+
+/// The CMOS RTC, reached by writing a register number to the index port.
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+/// Status A: the clock is updating and its registers are inconsistent.
+const RTC_UPDATING: u8 = 1 << 7;
+/// Status B: hours count to 24 rather than 12.
+const RTC_24_HOUR: u8 = 1 << 1;
+/// Status B: values are binary rather than BCD.
+const RTC_BINARY: u8 = 1 << 2;
+
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// Wall clock time at boot, in seconds since the epoch.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+pub fn tsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Marks the boot time and calibrates the TSC against PIT channel 2.
+///
+/// Busy-waits for about 10ms.
+pub fn init() {
+    BOOT_TSC.store(tsc(), Ordering::Relaxed);
+    BOOT_TIME.store(read_rtc(), Ordering::Relaxed);
+
+    let command = Port::<u8>::new(PIT_COMMAND);
+    let channel2 = Port::<u8>::new(PIT_CHANNEL2);
+    let port_b = Port::<u8>::new(PORT_B);
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    let elapsed = crate::interrupts::without_interrupts(|| unsafe {
+        // gate off, speaker off
+        let saved = port_b.read();
+        port_b.write(saved & !0b11);
+
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        command.write(0b1011_0000);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        // raising the gate starts the countdown
+        port_b.write((saved & !0b10) | 0b01);
+        let start = tsc();
+        while port_b.read() & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+        let end = tsc();
+
+        port_b.write(saved);
+        end - start
+    });
+
+    TSC_HZ.store(elapsed * 1000 / CALIBRATION_MS, Ordering::Relaxed);
+}
+
+/// Calibrated TSC frequency, or 0 before [`init`].
+pub fn tsc_frequency() -> u64 {
+    TSC_HZ.load(Ordering::Relaxed)
+}
+
+fn read_cmos(register: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(CMOS_INDEX).write(register);
+        Port::<u8>::new(CMOS_DATA).read()
+    }
+}
+
+/// Seconds, minutes, hours, day, month and year as the RTC counts them.
+fn read_rtc_registers() -> [u8; 6] {
+    while read_cmos(RTC_STATUS_A) & RTC_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    [0x00, 0x02, 0x04, 0x07, 0x08, 0x09].map(read_cmos)
+}
+
+/// Days from 1970-01-01 to the given date of the proleptic Gregorian
+/// calendar.
+pub fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Year, month and day of the date `days` after 1970-01-01; the inverse of
+/// [`days_from_civil`].
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (year_of_era * 365 + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = match month_from_march {
+        0..10 => month_from_march + 3,
+        _ => month_from_march - 9,
+    };
+    let year = era * 400 + year_of_era + (month <= 2) as u64;
+    (year, month, day)
+}
+
+/// Reads the RTC as seconds since the epoch. The RTC keeps UTC here, as
+/// QEMU's does by default, and the century is taken to be the 21st.
+fn read_rtc() -> u64 {
+    // an update may land between two registers; read until nothing moved
+    let mut registers = read_rtc_registers();
+    loop {
+        let again = read_rtc_registers();
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+
+    let status = read_cmos(RTC_STATUS_B);
+    let decode = |value: u8| match status & RTC_BINARY {
+        0 => (value & 0x0F) + (value >> 4) * 10,
+        _ => value,
+    } as u64;
+    let [second, minute, hour, day, month, year] = registers;
+    let mut hours = decode(hour & 0x7F);
+    if status & RTC_24_HOUR == 0 {
+        // 12 AM is 0 and PM is flagged in the top bit
+        hours = hours % 12 + if hour & 0x80 != 0 { 12 } else { 0 };
+    }
+    let (day, month) = (decode(day), decode(month));
+    if !(1..=31).contains(&day) || !(1..=12).contains(&month) {
+        return 0;
+    }
+    let days = days_from_civil(2000 + decode(year), month, day);
+    days * 86_400 + hours * 3600 + decode(minute) * 60 + decode(second)
+}
+
+/// Seconds since the epoch, counted from the RTC reading at boot.
+pub fn now() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + uptime().as_secs()
+}
+
+/// Time since [`init`], or zero before the TSC has been calibrated.
+pub fn uptime() -> Duration {
+    let hz = tsc_frequency();
+    if hz == 0 {
+        return Duration::ZERO;
+    }
+    let ticks = tsc().saturating_sub(BOOT_TSC.load(Ordering::Relaxed));
+    let secs = ticks / hz;
+    let nanos = (ticks % hz) * 1_000_000_000 / hz;
+    Duration::new(secs, nanos as u32)
+}
+
+/// Busy-waits for at least `duration`; needs the TSC calibrated by [`init`].
+pub fn delay(duration: Duration) {
+    debug_assert!(tsc_frequency() != 0, "delay before the TSC is calibrated");
+    let deadline = uptime() + duration;
+    while uptime() < deadline {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,521 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::memory;
+use crate::page_cache::{PAGE_SIZE, Page};
+use crate::sync::SpinLock;
+use crate::time;
+use crate::vfs::{self, Attributes, DirEntry, FileSystem, FileType, Inode, Metadata, VfsError};
+
+// A filesystem that lives in kernel memory and goes with it. Every node
+// keeps its contents under its own lock: the pages of a file, the target of
+// a symlink or the entries of a directory. File data is held in the pages
+// the page cache uses, allocated on first write or when mapped, so holes
+// take no memory and read as zeroes, and mappings share the pages that
+// reads and writes go to. There is no disk behind them, so tmpfs has no
+// page cache of its own and nothing to write back. Pages and symlink
+// targets count against the size limit of the filesystem until the last
+// name and the last open file of their node are gone.
+//
+// Entries are numbered in the order they were made, and the number is the
+// read_dir cursor, so that removing entries while reading a directory
+// doesn't skip any.
+
+/// State shared by the nodes of one filesystem.
+struct Shared {
+    next_ino: AtomicU64,
+    /// Bytes taken by file pages and symlink targets.
+    used: AtomicU64,
+    limit: u64,
+    /// Every node still alive, so that a hard link can find the node behind
+    /// an inode.
+    nodes: SpinLock<BTreeMap<u64, Weak<TmpNode>>>,
+}
+
+impl Shared {
+    fn charge(&self, bytes: u64) -> vfs::Result<()> {
+        self.used
+            .try_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|total| *total <= self.limit)
+            })
+            .map(drop)
+            .map_err(|_| VfsError::NoSpace)
+    }
+
+    fn release(&self, bytes: u64) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+struct Entry {
+    /// Position in the directory.
+    index: u64,
+    node: Arc<TmpNode>,
+}
+
+#[derive(Default)]
+struct Directory {
+    entries: BTreeMap<String, Entry>,
+    /// Names by position.
+    order: BTreeMap<u64, String>,
+    next_index: u64,
+}
+
+enum Contents {
+    File {
+        /// Pages by index; missing ones are holes.
+        pages: BTreeMap<u64, Arc<Page>>,
+        size: u64,
+    },
+    Directory(Directory),
+    Symlink(String),
+}
+
+impl Contents {
+    /// Bytes charged to the filesystem for these contents.
+    fn charged(&self) -> u64 {
+        match self {
+            Contents::File { pages, .. } => (pages.len() * PAGE_SIZE) as u64,
+            Contents::Directory(_) => 0,
+            Contents::Symlink(target) => target.len() as u64,
+        }
+    }
+}
+
+struct State {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    /// Names of a file; for a directory 2 plus its subdirectories, or 0 once
+    /// it is removed.
+    links: u32,
+    accessed: u64,
+    modified: u64,
+    changed: u64,
+    contents: Contents,
+}
+
+impl State {
+    fn directory(&mut self) -> vfs::Result<&mut Directory> {
+        match &mut self.contents {
+            Contents::Directory(_) if self.links == 0 => Err(VfsError::NotFound),
+            Contents::Directory(directory) => Ok(directory),
+            _ => Err(VfsError::NotDirectory),
+        }
+    }
+
+    fn touch(&mut self) {
+        let now = time::now();
+        self.modified = now;
+        self.changed = now;
+    }
+}
+
+/// A file, directory or symlink of a [`TmpFs`].
+pub struct TmpNode {
+    shared: Arc<Shared>,
+    ino: u64,
+    kind: FileType,
+    state: SpinLock<State>,
+}
+
+impl TmpNode {
+    fn new(shared: &Arc<Shared>, mode: u16, contents: Contents) -> Arc<TmpNode> {
+        let kind = match contents {
+            Contents::File { .. } => FileType::Regular,
+            Contents::Directory(_) => FileType::Directory,
+            Contents::Symlink(_) => FileType::Symlink,
+        };
+        let now = time::now();
+        let node = Arc::new(TmpNode {
+            shared: shared.clone(),
+            ino: shared.next_ino.fetch_add(1, Ordering::Relaxed),
+            kind,
+            state: SpinLock::new(State {
+                mode: mode & 0o7777,
+                uid: 0,
+                gid: 0,
+                links: if kind == FileType::Directory { 2 } else { 1 },
+                accessed: now,
+                modified: now,
+                changed: now,
+                contents,
+            }),
+        });
+        shared.nodes.lock().insert(node.ino, Arc::downgrade(&node));
+        node
+    }
+
+    /// Links `node` into this directory as `name`.
+    fn insert(&self, name: &str, node: &Arc<TmpNode>) -> vfs::Result<()> {
+        if name.len() > vfs::MAX_NAME {
+            return Err(VfsError::NameTooLong);
+        }
+        let mut state = self.state.lock();
+        let directory = state.directory()?;
+        if directory.entries.contains_key(name) {
+            return Err(VfsError::Exists);
+        }
+        let index = directory.next_index;
+        directory.next_index += 1;
+        directory.order.insert(index, name.into());
+        let entry = Entry {
+            index,
+            node: node.clone(),
+        };
+        directory.entries.insert(name.into(), entry);
+        if node.kind == FileType::Directory {
+            state.links += 1;
+        }
+        state.touch();
+        Ok(())
+    }
+
+    fn insert_new(&self, name: &str, mode: u16, contents: Contents) -> vfs::Result<Arc<dyn Inode>> {
+        let node = TmpNode::new(&self.shared, mode, contents);
+        self.insert(name, &node)?;
+        Ok(node)
+    }
+
+    fn remove(&self, name: &str, rmdir: bool) -> vfs::Result<()> {
+        let mut state = self.state.lock();
+        let entries = &mut state.directory()?.entries;
+        let entry = entries.get(name).ok_or(VfsError::NotFound)?;
+        let mut child = entry.node.state.lock();
+        match (rmdir, &child.contents) {
+            (true, Contents::Directory(directory)) if !directory.entries.is_empty() => {
+                return Err(VfsError::NotEmpty);
+            }
+            (true, Contents::Directory(_)) => child.links = 0,
+            (true, _) => return Err(VfsError::NotDirectory),
+            (false, Contents::Directory(_)) => return Err(VfsError::IsDirectory),
+            (false, _) => child.links -= 1,
+        }
+        child.changed = time::now();
+        drop(child);
+        let index = entry.index;
+        let entry = entries.remove(name);
+        state.directory()?.order.remove(&index);
+        if rmdir {
+            state.links -= 1;
+        }
+        state.touch();
+        drop(state);
+        // the node may go with its last entry, taking the nodes lock
+        drop(entry);
+        Ok(())
+    }
+
+    /// Finds the node of this filesystem that `inode` is, if it is one.
+    fn find(&self, inode: &Arc<dyn Inode>) -> vfs::Result<Arc<TmpNode>> {
+        let ino = inode.metadata()?.ino;
+        let node = self.shared.nodes.lock().get(&ino).and_then(Weak::upgrade);
+        node.filter(|node| core::ptr::addr_eq(Arc::as_ptr(node), Arc::as_ptr(inode)))
+            .ok_or(VfsError::CrossDevice)
+    }
+
+    /// A zeroed page of file data, charged to the filesystem.
+    fn new_page(&self) -> vfs::Result<Arc<Page>> {
+        self.shared.charge(PAGE_SIZE as u64)?;
+        match Page::new() {
+            Ok(page) => Ok(Arc::new(page)),
+            Err(err) => {
+                self.shared.release(PAGE_SIZE as u64);
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Drop for TmpNode {
+    fn drop(&mut self) {
+        self.shared.release(self.state.lock().contents.charged());
+        self.shared.nodes.lock().remove(&self.ino);
+    }
+}
+
+impl Inode for TmpNode {
+    fn metadata(&self) -> vfs::Result<Metadata> {
+        let state = self.state.lock();
+        let size = match &state.contents {
+            Contents::File { size, .. } => *size,
+            Contents::Directory(directory) => directory.entries.len() as u64,
+            Contents::Symlink(target) => target.len() as u64,
+        };
+        Ok(Metadata {
+            ino: self.ino,
+            kind: self.kind,
+            mode: state.mode,
+            uid: state.uid,
+            gid: state.gid,
+            size,
+            links: state.links,
+            blocks: state.contents.charged().div_ceil(512),
+            device: 0,
+            accessed: state.accessed,
+            modified: state.modified,
+            changed: state.changed,
+        })
+    }
+
+    fn lookup(&self, name: &str) -> vfs::Result<Arc<dyn Inode>> {
+        let mut state = self.state.lock();
+        match state.directory()?.entries.get(name) {
+            Some(entry) => Ok(entry.node.clone()),
+            None => Err(VfsError::NotFound),
+        }
+    }
+
+    fn create(&self, name: &str, kind: FileType, mode: u16) -> vfs::Result<Arc<dyn Inode>> {
+        let contents = match kind {
+            FileType::Regular => Contents::File {
+                pages: BTreeMap::new(),
+                size: 0,
+            },
+            FileType::Directory => Contents::Directory(Directory::default()),
+            _ => return Err(VfsError::Unsupported),
+        };
+        self.insert_new(name, mode, contents)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> vfs::Result<Arc<dyn Inode>> {
+        // charged here and given back when the node goes, even if the
+        // insert fails
+        self.shared.charge(target.len() as u64)?;
+        self.insert_new(name, 0o777, Contents::Symlink(target.into()))
+    }
+
+    fn link(&self, name: &str, inode: &Arc<dyn Inode>) -> vfs::Result<()> {
+        let node = self.find(inode)?;
+        if node.kind == FileType::Directory {
+            return Err(VfsError::NotPermitted);
+        }
+        if node.state.lock().links == 0 {
+            return Err(VfsError::NotFound);
+        }
+        self.insert(name, &node)?;
+        let mut state = node.state.lock();
+        state.links += 1;
+        state.changed = time::now();
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> vfs::Result<()> {
+        self.remove(name, false)
+    }
+
+    fn rmdir(&self, name: &str) -> vfs::Result<()> {
+        self.remove(name, true)
+    }
+
+    fn read_dir(&self, cursor: u64) -> vfs::Result<Option<(DirEntry, u64)>> {
+        let mut state = self.state.lock();
+        let directory = state.directory()?;
+        let Some((index, name)) = directory.order.range(cursor..).next() else {
+            return Ok(None);
+        };
+        let node = &directory.entries[name].node;
+        let entry = DirEntry {
+            ino: node.ino,
+            kind: node.kind,
+            name: name.clone(),
+        };
+        Ok(Some((entry, index + 1)))
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let mut state = self.state.lock();
+        let Contents::File { pages, size } = &state.contents else {
+            return Err(match self.kind {
+                FileType::Directory => VfsError::IsDirectory,
+                _ => VfsError::InvalidArgument,
+            });
+        };
+        let len = buf.len().min(size.saturating_sub(offset) as usize);
+        let first = offset / PAGE_SIZE as u64;
+        let found: Vec<_> = (first..(offset + len as u64).div_ceil(PAGE_SIZE as u64))
+            .map(|index| pages.get(&index).cloned())
+            .collect();
+        state.accessed = time::now();
+        // `buf` may be a mapping of this very file, which faults
+        drop(state);
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let within = position as usize % PAGE_SIZE;
+            let count = (PAGE_SIZE - within).min(len - done);
+            let chunk = &mut buf[done..done + count];
+            match &found[(position / PAGE_SIZE as u64 - first) as usize] {
+                Some(page) => page.read(within, chunk),
+                None => chunk.fill(0),
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        offset
+            .checked_add(buf.len() as u64)
+            .ok_or(VfsError::InvalidArgument)?;
+        let mut state = self.state.lock();
+        let Contents::File { pages, size } = &mut state.contents else {
+            return Err(match self.kind {
+                FileType::Directory => VfsError::IsDirectory,
+                _ => VfsError::InvalidArgument,
+            });
+        };
+        let mut targets = Vec::new();
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let index = position / PAGE_SIZE as u64;
+            let within = position as usize % PAGE_SIZE;
+            let count = (PAGE_SIZE - within).min(buf.len() - done);
+            let page = match pages.get(&index) {
+                Some(page) => page.clone(),
+                None => match self.new_page() {
+                    Ok(page) => pages.entry(index).or_insert(page).clone(),
+                    // keep what fitted, like a short write to a full disk
+                    Err(_) if done > 0 => break,
+                    Err(err) => return Err(err),
+                },
+            };
+            targets.push((page, within, done..done + count));
+            done += count;
+        }
+        *size = (*size).max(offset + done as u64);
+        state.touch();
+        // `buf` may be a mapping of this very file, which faults
+        drop(state);
+        for (page, within, range) in targets {
+            page.write(within, &buf[range]);
+        }
+        Ok(done)
+    }
+
+    fn truncate(&self, new_size: u64) -> vfs::Result<()> {
+        let mut state = self.state.lock();
+        let Contents::File { pages, size } = &mut state.contents else {
+            return Err(VfsError::IsDirectory);
+        };
+        if new_size < *size {
+            let dropped = pages.split_off(&new_size.div_ceil(PAGE_SIZE as u64));
+            self.shared.release((dropped.len() * PAGE_SIZE) as u64);
+            // the rest of the last page must read as zeroes if the file
+            // grows again
+            let within = new_size as usize % PAGE_SIZE;
+            if within != 0
+                && let Some(page) = pages.get(&(new_size / PAGE_SIZE as u64))
+            {
+                page.zero_from(within);
+            }
+        }
+        *size = new_size;
+        state.touch();
+        Ok(())
+    }
+
+    fn page(&self, index: u64) -> vfs::Result<Arc<Page>> {
+        let mut state = self.state.lock();
+        let Contents::File { pages, .. } = &mut state.contents else {
+            return Err(VfsError::InvalidArgument);
+        };
+        if let Some(page) = pages.get(&index) {
+            return Ok(page.clone());
+        }
+        let page = self.new_page()?;
+        pages.insert(index, page.clone());
+        Ok(page)
+    }
+
+    fn read_link(&self) -> vfs::Result<String> {
+        match &self.state.lock().contents {
+            Contents::Symlink(target) => Ok(target.clone()),
+            _ => Err(VfsError::InvalidArgument),
+        }
+    }
+
+    fn set_attributes(&self, attributes: &Attributes) -> vfs::Result<()> {
+        let mut state = self.state.lock();
+        if let Some(mode) = attributes.mode {
+            state.mode = mode & 0o7777;
+        }
+        if let Some(uid) = attributes.uid {
+            state.uid = uid;
+        }
+        if let Some(gid) = attributes.gid {
+            state.gid = gid;
+        }
+        if let Some(accessed) = attributes.accessed {
+            state.accessed = accessed;
+        }
+        if let Some(modified) = attributes.modified {
+            state.modified = modified;
+        }
+        state.changed = time::now();
+        Ok(())
+    }
+}
+
+/// A filesystem kept entirely in memory.
+pub struct TmpFs {
+    root: Arc<TmpNode>,
+}
+
+impl TmpFs {
+    /// A tmpfs limited only by the memory there is.
+    pub fn new() -> Arc<TmpFs> {
+        TmpFs::with_limit(u64::MAX)
+    }
+
+    /// A tmpfs whose files may take up to `limit` bytes.
+    pub fn with_limit(limit: u64) -> Arc<TmpFs> {
+        let shared = Arc::new(Shared {
+            next_ino: AtomicU64::new(1),
+            used: AtomicU64::new(0),
+            limit,
+            nodes: SpinLock::new(BTreeMap::new()),
+        });
+        let root = TmpNode::new(&shared, 0o755, Contents::Directory(Directory::default()));
+        Arc::new(TmpFs { root })
+    }
+
+    /// Bytes taken by file data and symlink targets.
+    pub fn used(&self) -> u64 {
+        self.root.shared.used.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.root.shared.limit
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
+
+/// Mounts a tmpfs at `/tmp` that may take up to half of memory, as Linux
+/// does by default.
+pub fn init() {
+    match vfs::mkdir("/tmp", 0o1777) {
+        Ok(()) | Err(VfsError::Exists) => {}
+        Err(err) => {
+            log::error!("tmpfs: cannot create /tmp: {}", err);
+            return;
+        }
+    }
+    let fs = TmpFs::with_limit(memory::total_memory() / 2);
+    if let Err(err) = vfs::mount("/tmp", "tmpfs", fs).and_then(|()| vfs::chmod("/tmp", 0o1777)) {
+        log::error!("tmpfs: cannot mount /tmp: {}", err);
+    }
+}
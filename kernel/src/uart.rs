@@ -0,0 +1,627 @@
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+use crate::devfs::{self, Device, DeviceNumber, ioctl};
+use crate::interrupts;
+use crate::port::Port;
+use crate::ringbuf::RingBuffer;
+use crate::sync::IrqSpinLock;
+use crate::vfs::{self, FileType, VfsError};
+
+const RX_BUFFER_SIZE: usize = 1024;
+const TX_BUFFER_SIZE: usize = 1024;
+
+/// Depth of the 16550 transmit FIFO.
+const TX_FIFO_DEPTH: usize = 16;
+
+/// Input clock divided by 16; the divisor latch counts in units of this.
+const BASE_BAUD: u32 = 115_200;
+
+mod reg {
+    pub const DATA: u16 = 0; // RBR/THR, DLL with DLAB set
+    pub const INT_ENABLE: u16 = 1; // IER, DLM with DLAB set
+    pub const INT_ID: u16 = 2; // IIR on read, FCR on write
+    pub const LINE_CONTROL: u16 = 3;
+    pub const MODEM_CONTROL: u16 = 4;
+    pub const LINE_STATUS: u16 = 5;
+    pub const SCRATCH: u16 = 7;
+}
+
+mod ier {
+    pub const RX_AVAILABLE: u8 = 1 << 0;
+    pub const TX_EMPTY: u8 = 1 << 1;
+    pub const LINE_STATUS: u8 = 1 << 2;
+}
+
+mod iir {
+    pub const NO_INTERRUPT: u8 = 1 << 0;
+    pub const ID_MASK: u8 = 0b1110;
+    pub const LINE_STATUS: u8 = 0b0110;
+    pub const RX_AVAILABLE: u8 = 0b0100;
+    pub const RX_TIMEOUT: u8 = 0b1100;
+    pub const TX_EMPTY: u8 = 0b0010;
+}
+
+mod fcr {
+    pub const ENABLE: u8 = 1 << 0;
+    pub const CLEAR_RX: u8 = 1 << 1;
+    pub const CLEAR_TX: u8 = 1 << 2;
+    pub const TRIGGER_14: u8 = 0b11 << 6;
+}
+
+mod lcr {
+    pub const DLAB: u8 = 1 << 7;
+}
+
+mod mcr {
+    pub const DTR: u8 = 1 << 0;
+    pub const RTS: u8 = 1 << 1;
+    pub const OUT1: u8 = 1 << 2;
+    // gates the UART's interrupt line on PC hardware
+    pub const OUT2: u8 = 1 << 3;
+    pub const LOOPBACK: u8 = 1 << 4;
+}
+
+mod lsr {
+    pub const DATA_READY: u8 = 1 << 0;
+    pub const OVERRUN: u8 = 1 << 1;
+    pub const TX_EMPTY: u8 = 1 << 5;
+    /// Both the holding and the shift register are empty.
+    pub const TX_IDLE: u8 = 1 << 6;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    pub const fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3F8,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    /// COM1/COM3 and COM2/COM4 share a line.
+    pub const fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+
+    pub fn uart(self) -> &'static Uart {
+        match self {
+            ComPort::Com1 => &COM1,
+            ComPort::Com2 => &COM2,
+            ComPort::Com3 => &COM3,
+            ComPort::Com4 => &COM4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None = 0b000,
+    Odd = 0b001,
+    Even = 0b011,
+    Mark = 0b101,
+    Space = 0b111,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    pub baud: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for LineConfig {
+    /// 115200 8N1.
+    fn default() -> Self {
+        LineConfig {
+            baud: BASE_BAUD,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl LineConfig {
+    fn divisor(&self) -> Result<u16, UartError> {
+        if self.baud == 0 || !BASE_BAUD.is_multiple_of(self.baud) {
+            return Err(UartError::InvalidBaudRate(self.baud));
+        }
+        Ok((BASE_BAUD / self.baud) as u16)
+    }
+
+    fn line_control(&self) -> Result<u8, UartError> {
+        if !(5..=8).contains(&self.data_bits) {
+            return Err(UartError::InvalidDataBits(self.data_bits));
+        }
+        let stop = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        Ok((self.data_bits - 5) | stop | ((self.parity as u8) << 3))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartError {
+    /// Nothing answered at the port's base address.
+    NotPresent,
+    /// The chip did not echo a byte in loopback mode.
+    LoopbackFailed,
+    InvalidBaudRate(u32),
+    InvalidDataBits(u8),
+}
+
+impl fmt::Display for UartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UartError::NotPresent => write!(f, "no UART present"),
+            UartError::LoopbackFailed => write!(f, "loopback self-test failed"),
+            UartError::InvalidBaudRate(baud) => write!(f, "unsupported baud rate {baud}"),
+            UartError::InvalidDataBits(bits) => write!(f, "unsupported data width {bits}"),
+        }
+    }
+}
+
+const STATE_UNINIT: u8 = 0;
+const STATE_ABSENT: u8 = 1;
+const STATE_POLLED: u8 = 2;
+const STATE_INTERRUPT: u8 = 3;
+
+static IRQ3_REGISTERED: AtomicBool = AtomicBool::new(false);
+static IRQ4_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+pub static COM1: Uart = Uart::new(ComPort::Com1);
+pub static COM2: Uart = Uart::new(ComPort::Com2);
+pub static COM3: Uart = Uart::new(ComPort::Com3);
+pub static COM4: Uart = Uart::new(ComPort::Com4);
+
+/// A 16550-compatible UART.
+///
+/// Until [`Uart::enable_interrupts`] is called every transfer is polled.
+/// Afterwards received bytes are buffered by the IRQ handler and transmitted
+/// bytes are queued and fed to the FIFO on transmit-empty interrupts.
+pub struct Uart {
+    port: ComPort,
+    state: AtomicU8,
+    rx: RingBuffer<u8, RX_BUFFER_SIZE>,
+    tx: RingBuffer<u8, TX_BUFFER_SIZE>,
+    /// Serializes transmit queue producers and the IRQ-side consumer.
+    tx_lock: IrqSpinLock<()>,
+    rx_dropped: AtomicUsize,
+    overruns: AtomicUsize,
+}
+
+impl Uart {
+    const fn new(port: ComPort) -> Self {
+        Uart {
+            port,
+            state: AtomicU8::new(STATE_UNINIT),
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            tx_lock: IrqSpinLock::new(()),
+            rx_dropped: AtomicUsize::new(0),
+            overruns: AtomicUsize::new(0),
+        }
+    }
+
+    fn reg(&self, offset: u16) -> Port<u8> {
+        Port::new(self.port.base() + offset)
+    }
+
+    pub fn port(&self) -> ComPort {
+        self.port
+    }
+
+    pub fn is_present(&self) -> bool {
+        matches!(
+            self.state.load(Ordering::Acquire),
+            STATE_POLLED | STATE_INTERRUPT
+        )
+    }
+
+    pub fn is_interrupt_driven(&self) -> bool {
+        self.state.load(Ordering::Acquire) == STATE_INTERRUPT
+    }
+
+    /// Checks for a working UART using the scratch register and loopback
+    /// mode, at the line settings the port already has.
+    pub fn probe(&self) -> Result<(), UartError> {
+        let _tx = self.tx_lock.lock();
+        self.self_test()
+    }
+
+    /// [`Uart::probe`]. Caller holds `tx_lock`, which also keeps the
+    /// receive interrupt off the echoed byte.
+    fn self_test(&self) -> Result<(), UartError> {
+        unsafe {
+            let scratch = self.reg(reg::SCRATCH);
+            scratch.write(0x5A);
+            if scratch.read() != 0x5A {
+                return Err(UartError::NotPresent);
+            }
+
+            // what is still going out would come back first
+            for _ in 0..100_000 {
+                if self.line_status() & lsr::TX_IDLE != 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            let modem_control = self.reg(reg::MODEM_CONTROL);
+            let saved = modem_control.read();
+            modem_control.write(mcr::LOOPBACK | mcr::RTS | mcr::OUT1 | mcr::OUT2);
+            // drain anything left in the receiver
+            while self.reg(reg::LINE_STATUS).read() & lsr::DATA_READY != 0 {
+                self.reg(reg::DATA).read();
+            }
+            self.reg(reg::DATA).write(0xAE);
+            // a byte takes about a millisecond at 9600 baud
+            let mut echoed = None;
+            for _ in 0..100_000 {
+                if self.reg(reg::LINE_STATUS).read() & lsr::DATA_READY != 0 {
+                    echoed = Some(self.reg(reg::DATA).read());
+                    break;
+                }
+            }
+            modem_control.write(saved & !mcr::LOOPBACK);
+
+            match echoed {
+                Some(0xAE) => Ok(()),
+                _ => Err(UartError::LoopbackFailed),
+            }
+        }
+    }
+
+    /// Programs baud rate, framing and FIFOs, then probes the port at
+    /// those settings.
+    ///
+    /// Leaves the port in polled mode; interrupts stay off until
+    /// [`Uart::enable_interrupts`].
+    pub fn init(&self, config: LineConfig) -> Result<(), UartError> {
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+
+        let _tx = self.tx_lock.lock();
+        unsafe {
+            self.reg(reg::INT_ENABLE).write(0);
+
+            self.reg(reg::LINE_CONTROL).write(lcr::DLAB);
+            self.reg(reg::DATA).write(divisor as u8);
+            self.reg(reg::INT_ENABLE).write((divisor >> 8) as u8);
+            self.reg(reg::LINE_CONTROL).write(line_control);
+
+            self.reg(reg::INT_ID)
+                .write(fcr::ENABLE | fcr::CLEAR_RX | fcr::CLEAR_TX | fcr::TRIGGER_14);
+        }
+        if let Err(err) = self.self_test() {
+            self.state.store(STATE_ABSENT, Ordering::Release);
+            return Err(err);
+        }
+        unsafe {
+            self.reg(reg::MODEM_CONTROL)
+                .write(mcr::DTR | mcr::RTS | mcr::OUT1 | mcr::OUT2);
+        }
+        self.rx.clear();
+        self.tx.clear();
+        self.state.store(STATE_POLLED, Ordering::Release);
+        Ok(())
+    }
+
+    /// Switches to interrupt-driven receive and buffered transmit.
+    pub fn enable_interrupts(&self) {
+        assert!(self.is_present(), "{:?} is not initialized", self.port);
+        let registered = match self.port.irq() {
+            4 => &IRQ4_REGISTERED,
+            _ => &IRQ3_REGISTERED,
+        };
+        if !registered.swap(true, Ordering::AcqRel) {
+            interrupts::register_irq(self.port.irq(), irq_handler_for(self.port));
+        }
+
+        let _tx = self.tx_lock.lock();
+        self.state.store(STATE_INTERRUPT, Ordering::Release);
+        unsafe {
+            self.reg(reg::INT_ENABLE)
+                .write(ier::RX_AVAILABLE | ier::LINE_STATUS);
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.reg(reg::LINE_STATUS).read() }
+    }
+
+    fn send_polled(&self, byte: u8) {
+        while self.line_status() & lsr::TX_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        unsafe { self.reg(reg::DATA).write(byte) };
+    }
+
+    /// Moves queued bytes into the FIFO if the transmitter is idle.
+    /// Caller holds `tx_lock`.
+    fn fill_fifo(&self) {
+        if self.line_status() & lsr::TX_EMPTY == 0 {
+            return;
+        }
+        for _ in 0..TX_FIFO_DEPTH {
+            match self.tx.pop() {
+                Some(byte) => unsafe { self.reg(reg::DATA).write(byte) },
+                None => break,
+            }
+        }
+        let mut enable = ier::RX_AVAILABLE | ier::LINE_STATUS;
+        if !self.tx.is_empty() {
+            enable |= ier::TX_EMPTY;
+        }
+        unsafe { self.reg(reg::INT_ENABLE).write(enable) };
+    }
+
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        if !self.is_present() {
+            return;
+        }
+        let _tx = self.tx_lock.lock();
+        for &byte in bytes {
+            if !self.is_interrupt_driven() {
+                self.send_polled(byte);
+                continue;
+            }
+            while self.tx.push(byte).is_err() {
+                // queue full and we may be running with interrupts off: push
+                // the oldest bytes out by hand to keep the output in order
+                while let Some(queued) = self.tx.pop() {
+                    self.send_polled(queued);
+                }
+            }
+        }
+        if self.is_interrupt_driven() {
+            self.fill_fifo();
+        }
+    }
+
+    /// Writes without taking any lock or touching the transmit queue.
+    ///
+    /// Intended for the panic path only: queued output that has not reached
+    /// the FIFO yet is skipped.
+    pub fn panic_writer(&self) -> PolledWriter<'_> {
+        PolledWriter { uart: self }
+    }
+
+    /// Returns a reader over the received byte stream.
+    ///
+    /// There must only be one reader per port at a time.
+    pub fn reader(&self) -> UartReader<'_> {
+        UartReader { uart: self }
+    }
+
+    /// Bytes thrown away because the receive buffer was full, and hardware
+    /// overruns reported by the chip.
+    pub fn rx_errors(&self) -> (usize, usize) {
+        (
+            self.rx_dropped.load(Ordering::Relaxed),
+            self.overruns.load(Ordering::Relaxed),
+        )
+    }
+
+    fn receive_pending(&self) {
+        loop {
+            let status = self.line_status();
+            if status & lsr::OVERRUN != 0 {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+            if status & lsr::DATA_READY == 0 {
+                return;
+            }
+            let byte = unsafe { self.reg(reg::DATA).read() };
+            if self.rx.push(byte).is_err() {
+                self.rx_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn handle_interrupt(&self) {
+        if !self.is_interrupt_driven() {
+            return;
+        }
+        loop {
+            let id = unsafe { self.reg(reg::INT_ID).read() };
+            if id & iir::NO_INTERRUPT != 0 {
+                return;
+            }
+            match id & iir::ID_MASK {
+                iir::LINE_STATUS | iir::RX_AVAILABLE | iir::RX_TIMEOUT => self.receive_pending(),
+                iir::TX_EMPTY => {
+                    // a writer holding the lock refills the FIFO itself
+                    if let Some(_tx) = self.tx_lock.try_lock() {
+                        self.fill_fifo();
+                    } else {
+                        return;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+}
+
+fn irq_handler_for(port: ComPort) -> interrupts::IrqHandler {
+    match port.irq() {
+        4 => || {
+            COM1.handle_interrupt();
+            COM3.handle_interrupt();
+        },
+        _ => || {
+            COM2.handle_interrupt();
+            COM4.handle_interrupt();
+        },
+    }
+}
+
+impl fmt::Write for &Uart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            match line.strip_suffix('\n') {
+                Some(text) => {
+                    self.write_bytes(text.as_bytes());
+                    self.write_bytes(b"\r\n");
+                }
+                None => self.write_bytes(line.as_bytes()),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct PolledWriter<'a> {
+    uart: &'a Uart,
+}
+
+impl fmt::Write for PolledWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.uart.send_polled(b'\r');
+            }
+            self.uart.send_polled(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Byte-stream view of everything the UART has received.
+pub struct UartReader<'a> {
+    uart: &'a Uart,
+}
+
+impl UartReader<'_> {
+    /// Returns the next received byte without blocking.
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if self.uart.is_interrupt_driven() {
+            return self.uart.rx.pop();
+        }
+        if self.uart.is_present() && self.uart.line_status() & lsr::DATA_READY != 0 {
+            return Some(unsafe { self.uart.reg(reg::DATA).read() });
+        }
+        None
+    }
+
+    /// Waits for the next byte, halting between interrupts.
+    pub fn read_byte_blocking(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.read_byte() {
+                return byte;
+            }
+            if self.uart.is_interrupt_driven() {
+                interrupts::enable_and_wait();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Copies as many buffered bytes as fit into `buf` and returns the count.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        while count < buf.len() {
+            match self.read_byte() {
+                Some(byte) => {
+                    buf[count] = byte;
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+        count
+    }
+}
+
+impl Iterator for UartReader<'_> {
+    type Item = u8;
+
+    /// Yields buffered bytes and stops once the buffer is empty.
+    fn next(&mut self) -> Option<u8> {
+        self.read_byte()
+    }
+}
+
+/// `/dev/ttyS0` to `ttyS3`. A read waits for the first byte and returns
+/// whatever has arrived by then; a non-blocking one does not wait.
+impl Device for ComPort {
+    fn read(&self, _offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        let Some((first, rest)) = buf.split_first_mut() else {
+            return Ok(0);
+        };
+        let mut reader = self.uart().reader();
+        *first = reader.read_byte_blocking();
+        Ok(1 + reader.read(rest))
+    }
+
+    fn try_read(&self, _offset: u64, buf: &mut [u8]) -> vfs::Result<usize> {
+        match self.uart().reader().read(buf) {
+            0 if !buf.is_empty() => Err(VfsError::WouldBlock),
+            read => Ok(read),
+        }
+    }
+
+    fn write(&self, _offset: u64, buf: &[u8]) -> vfs::Result<usize> {
+        self.uart().write_bytes(buf);
+        Ok(buf.len())
+    }
+
+    fn ioctl(&self, request: u32, arg: &mut [u8]) -> vfs::Result<usize> {
+        match request {
+            ioctl::FIONREAD => devfs::put_arg(arg, &(self.uart().rx.len() as i32).to_le_bytes()),
+            _ => Err(VfsError::NotTty),
+        }
+    }
+}
+
+/// Probes COM1 to COM4 and switches every present port to interrupt mode.
+///
+/// Ports that were already configured (COM1 by the logger) keep their line
+/// settings.
+pub fn init() {
+    for port in ComPort::ALL {
+        let uart = port.uart();
+        if !uart.is_present()
+            && let Err(err) = uart.init(LineConfig::default())
+        {
+            log::debug!("{:?} at {:#x}: {}", port, port.base(), err);
+            continue;
+        }
+        uart.enable_interrupts();
+        log::info!("{:?} at {:#x}, IRQ {}", port, port.base(), port.irq());
+        let index = port as u32;
+        devfs::register(
+            &alloc::format!("ttyS{}", index),
+            FileType::CharDevice,
+            DeviceNumber::new(4, 64 + index),
+            0o660,
+            Arc::new(port),
+        );
+    }
+}
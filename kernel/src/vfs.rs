@@ -0,0 +1,998 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::block::BlockError;
+use crate::page_cache::Page;
+use crate::sync::SpinLock;
+
+// Filesystems hand out inodes; the VFS keeps the ones it has looked up in a
+// tree of dentries, one per name, so that walking a path again doesn't ask
+// the filesystem. A mount hangs the root dentry of a filesystem off a
+// directory dentry, and the walk steps across it in both directions. Cached
+// dentries live until their name is removed or their filesystem unmounted.
+//
+// There are no processes yet, so relative paths start at the root, and no
+// users, so permissions and owners are kept but not checked.
+
+pub const MAX_NAME: usize = 255;
+pub const MAX_PATH: usize = 4096;
+/// Symlinks one resolution follows before it gives up, as on Linux.
+pub const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    NotDirectory,
+    IsDirectory,
+    Exists,
+    NotEmpty,
+    InvalidArgument,
+    NameTooLong,
+    /// Too many symlinks, or one where none may be followed.
+    SymlinkLoop,
+    ReadOnly,
+    NoSpace,
+    /// The file was not opened for this kind of access.
+    BadDescriptor,
+    NotSeekable,
+    /// A mountpoint, or a filesystem something is mounted in.
+    Busy,
+    /// Not allowed for this kind of node, such as hard linking a directory.
+    NotPermitted,
+    /// A hard link to a file on another filesystem.
+    CrossDevice,
+    /// A device node whose device is gone.
+    NoDevice,
+    /// An ioctl the file doesn't know.
+    NotTty,
+    /// A non-blocking file that would have had to wait.
+    WouldBlock,
+    /// A write to a pipe nobody reads.
+    BrokenPipe,
+    /// The descriptor table is full.
+    TooManyFiles,
+    Unsupported,
+    OutOfMemory,
+    /// A filesystem found its own metadata damaged.
+    Corrupted,
+    Io,
+}
+
+pub type Result<T> = core::result::Result<T, VfsError>;
+
+impl VfsError {
+    /// The matching Linux errno.
+    pub fn errno(self) -> i32 {
+        match self {
+            VfsError::NotPermitted => 1,
+            VfsError::NotFound => 2,
+            VfsError::Io => 5,
+            VfsError::NoDevice => 6,
+            VfsError::BadDescriptor => 9,
+            VfsError::WouldBlock => 11,
+            VfsError::OutOfMemory => 12,
+            VfsError::Busy => 16,
+            VfsError::Exists => 17,
+            VfsError::CrossDevice => 18,
+            VfsError::NotDirectory => 20,
+            VfsError::IsDirectory => 21,
+            VfsError::InvalidArgument => 22,
+            VfsError::TooManyFiles => 24,
+            VfsError::NotTty => 25,
+            VfsError::NoSpace => 28,
+            VfsError::NotSeekable => 29,
+            VfsError::ReadOnly => 30,
+            VfsError::BrokenPipe => 32,
+            VfsError::NameTooLong => 36,
+            VfsError::NotEmpty => 39,
+            VfsError::SymlinkLoop => 40,
+            VfsError::Unsupported => 95,
+            VfsError::Corrupted => 117,
+        }
+    }
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            VfsError::NotFound => "no such file or directory",
+            VfsError::NotDirectory => "not a directory",
+            VfsError::IsDirectory => "is a directory",
+            VfsError::Exists => "file exists",
+            VfsError::NotEmpty => "directory not empty",
+            VfsError::InvalidArgument => "invalid argument",
+            VfsError::NameTooLong => "name too long",
+            VfsError::SymlinkLoop => "too many levels of symbolic links",
+            VfsError::ReadOnly => "read-only filesystem",
+            VfsError::NoSpace => "no space left",
+            VfsError::BadDescriptor => "bad file descriptor",
+            VfsError::NotSeekable => "illegal seek",
+            VfsError::Busy => "busy",
+            VfsError::NotPermitted => "operation not permitted",
+            VfsError::CrossDevice => "cross-device link",
+            VfsError::NoDevice => "no such device or address",
+            VfsError::NotTty => "inappropriate ioctl for device",
+            VfsError::WouldBlock => "resource temporarily unavailable",
+            VfsError::BrokenPipe => "broken pipe",
+            VfsError::TooManyFiles => "too many open files",
+            VfsError::Unsupported => "not supported",
+            VfsError::OutOfMemory => "out of memory",
+            VfsError::Corrupted => "structure needs cleaning",
+            VfsError::Io => "I/O error",
+        };
+        f.write_str(text)
+    }
+}
+
+impl From<BlockError> for VfsError {
+    fn from(err: BlockError) -> VfsError {
+        match err {
+            BlockError::ReadOnly => VfsError::ReadOnly,
+            BlockError::OutOfMemory => VfsError::OutOfMemory,
+            BlockError::Unsupported => VfsError::Unsupported,
+            BlockError::Io | BlockError::Timeout | BlockError::OutOfRange => VfsError::Io,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileType {
+    #[default]
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub ino: u64,
+    pub kind: FileType,
+    /// Permission bits.
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub links: u32,
+    /// Space used, in 512-byte units.
+    pub blocks: u64,
+    /// Device number of a device node.
+    pub device: u32,
+    /// Last access, in seconds since the epoch.
+    pub accessed: u64,
+    /// Last modification of the contents.
+    pub modified: u64,
+    /// Last change of the contents or the metadata.
+    pub changed: u64,
+}
+
+/// Metadata to change; None leaves a field as it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// Permission bits.
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub accessed: Option<u64>,
+    pub modified: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    pub const READ_WRITE: OpenFlags = OpenFlags(Self::READ.0 | Self::WRITE.0);
+    /// Create the file if it doesn't exist.
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// With CREATE, fail if the file exists.
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(1 << 3);
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 4);
+    /// Every write goes to the end of the file.
+    pub const APPEND: OpenFlags = OpenFlags(1 << 5);
+    /// Fail unless the path names a directory.
+    pub const DIRECTORY: OpenFlags = OpenFlags(1 << 6);
+    /// Fail if the last component is a symlink.
+    pub const NO_FOLLOW: OpenFlags = OpenFlags(1 << 7);
+    /// Fail with WouldBlock instead of waiting, on files that can wait.
+    pub const NON_BLOCK: OpenFlags = OpenFlags(1 << 8);
+    /// Set close-on-exec on the descriptor. Not kept by the open file.
+    pub const CLOSE_ON_EXEC: OpenFlags = OpenFlags(1 << 9);
+
+    /// The flags [`File::set_flags`] can change.
+    const CHANGEABLE: OpenFlags = OpenFlags(Self::APPEND.0 | Self::NON_BLOCK.0);
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, flags: OpenFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+/// The flags of an open file, as [`File::flags`] reports them.
+pub struct FileFlags(AtomicU32);
+
+impl FileFlags {
+    pub const fn new(flags: OpenFlags) -> FileFlags {
+        FileFlags(AtomicU32::new(flags.0 & !OpenFlags::CLOSE_ON_EXEC.0))
+    }
+
+    pub fn get(&self) -> OpenFlags {
+        OpenFlags(self.0.load(Ordering::Relaxed))
+    }
+
+    /// Takes APPEND and NON_BLOCK from `flags`, keeping the rest.
+    pub fn set(&self, flags: OpenFlags) {
+        let changeable = OpenFlags::CHANGEABLE.0;
+        let old = self.0.load(Ordering::Relaxed);
+        self.0
+            .store(old & !changeable | flags.0 & changeable, Ordering::Relaxed);
+    }
+
+    pub fn contains(&self, flags: OpenFlags) -> bool {
+        self.get().contains(flags)
+    }
+}
+
+/// A mounted or mountable filesystem.
+pub trait FileSystem: Send + Sync {
+    /// Filesystem type, as the mount table lists it.
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Writes back whatever the filesystem has cached.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A file, directory or other node of a filesystem. The defaults are those
+/// of a node that supports nothing beyond [`metadata`](Self::metadata); the
+/// VFS checks the node type and the mount's writability before calling the
+/// others.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Result<Metadata>;
+
+    /// Finds `name` in this directory. Never asked for `.` or `..`.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>> {
+        Err(VfsError::NotDirectory)
+    }
+
+    /// Creates `name` in this directory as an empty file, directory, or
+    /// other node of type `kind`.
+    fn create(&self, _name: &str, _kind: FileType, _mode: u16) -> Result<Arc<dyn Inode>> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn Inode>> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Adds `name` to this directory as another name of `inode`, a
+    /// non-directory of the same filesystem.
+    fn link(&self, _name: &str, _inode: &Arc<dyn Inode>) -> Result<()> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Removes the non-directory `name` from this directory.
+    fn unlink(&self, _name: &str) -> Result<()> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Removes the empty directory `name` from this directory.
+    fn rmdir(&self, _name: &str) -> Result<()> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Returns the entry at `cursor`, 0 being the first, along with the
+    /// cursor of the one after it; None past the last.
+    fn read_dir(&self, _cursor: u64) -> Result<Option<(DirEntry, u64)>> {
+        Err(VfsError::NotDirectory)
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn truncate(&self, _size: u64) -> Result<()> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn read_link(&self) -> Result<String> {
+        Err(VfsError::InvalidArgument)
+    }
+
+    fn set_attributes(&self, _attributes: &Attributes) -> Result<()> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Whether the cached dentry of `name` in this directory, whose inode
+    /// is `inode`, still stands. For filesystems whose names come and go
+    /// without the VFS doing it, such as devfs.
+    fn revalidate(&self, _name: &str, _inode: &Arc<dyn Inode>) -> bool {
+        true
+    }
+
+    /// Returns the file object for an open of this node, for nodes such as
+    /// devices that don't go through `read_at`/`write_at`. None makes the
+    /// VFS use its own.
+    fn open(&self, _flags: OpenFlags) -> Result<Option<Arc<dyn File>>> {
+        Ok(None)
+    }
+
+    /// Page `index` of a regular file, read in or made if need be, for
+    /// filesystems that keep file data in pages. Only such files can be
+    /// mapped.
+    fn page(&self, _index: u64) -> Result<Arc<Page>> {
+        Err(VfsError::Unsupported)
+    }
+
+    /// Writes the dirty pages of a file back.
+    fn write_back(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An open file. Its position, where it has one, is shared by everyone
+/// holding it.
+pub trait File: Send + Sync {
+    fn read(&self, buf: &mut [u8]) -> Result<usize>;
+
+    fn write(&self, buf: &[u8]) -> Result<usize>;
+
+    fn seek(&self, _from: SeekFrom) -> Result<u64> {
+        Err(VfsError::NotSeekable)
+    }
+
+    /// Returns the next directory entry, None after the last.
+    fn read_dir(&self) -> Result<Option<DirEntry>> {
+        Err(VfsError::NotDirectory)
+    }
+
+    /// Carries out a device-specific `request`. `arg` holds the argument
+    /// structure, which the caller copies in and back out; returns the
+    /// result of the call.
+    fn ioctl(&self, _request: u32, _arg: &mut [u8]) -> Result<usize> {
+        Err(VfsError::NotTty)
+    }
+
+    fn metadata(&self) -> Result<Metadata>;
+
+    /// The flags the file was opened with, less CLOSE_ON_EXEC, like
+    /// `fcntl(F_GETFL)`.
+    fn flags(&self) -> OpenFlags;
+
+    /// Changes APPEND and NON_BLOCK to what `flags` has, like
+    /// `fcntl(F_SETFL)`.
+    fn set_flags(&self, flags: OpenFlags);
+}
+
+/// A name in the dentry tree.
+pub struct Dentry {
+    name: String,
+    kind: FileType,
+    inode: Arc<dyn Inode>,
+    fs: Arc<dyn FileSystem>,
+    /// None at the root of a filesystem.
+    parent: Option<Arc<Dentry>>,
+    /// The directory a filesystem root is mounted on.
+    mountpoint: Option<Arc<Dentry>>,
+    children: SpinLock<BTreeMap<String, Arc<Dentry>>>,
+    /// Root of the filesystem mounted on this directory.
+    mounted: SpinLock<Option<Arc<Dentry>>>,
+}
+
+impl Dentry {
+    fn new(
+        name: &str,
+        inode: Arc<dyn Inode>,
+        fs: Arc<dyn FileSystem>,
+        parent: Option<Arc<Dentry>>,
+        mountpoint: Option<Arc<Dentry>>,
+    ) -> Result<Arc<Dentry>> {
+        Ok(Arc::new(Dentry {
+            name: name.into(),
+            kind: inode.metadata()?.kind,
+            inode,
+            fs,
+            parent,
+            mountpoint,
+            children: SpinLock::new(BTreeMap::new()),
+            mounted: SpinLock::new(None),
+        }))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> FileType {
+        self.kind
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn filesystem(&self) -> &Arc<dyn FileSystem> {
+        &self.fs
+    }
+
+    /// Absolute path of the dentry.
+    pub fn path(self: &Arc<Self>) -> String {
+        let mut names = Vec::new();
+        let mut current = self.clone();
+        loop {
+            if let Some(parent) = &current.parent {
+                names.push(current.name.clone());
+                current = parent.clone();
+            } else if let Some(mountpoint) = &current.mountpoint {
+                current = mountpoint.clone();
+            } else {
+                break;
+            }
+        }
+        if names.is_empty() {
+            return "/".into();
+        }
+        names.iter().rev().fold(String::new(), |mut path, name| {
+            path.push('/');
+            path.push_str(name);
+            path
+        })
+    }
+
+    /// The dentry of `name` in this directory, from the cache or else the
+    /// filesystem.
+    fn child(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>> {
+        if self.kind != FileType::Directory {
+            return Err(VfsError::NotDirectory);
+        }
+        let cached = self.children.lock().get(name).cloned();
+        if let Some(child) = cached {
+            if child.mounted.lock().is_some() || self.inode.revalidate(name, &child.inode) {
+                return Ok(child);
+            }
+            self.children.lock().remove(name);
+            child.prune();
+        }
+        let inode = self.inode.lookup(name)?;
+        let child = Dentry::new(name, inode, self.fs.clone(), Some(self.clone()), None)?;
+        Ok(self
+            .children
+            .lock()
+            .entry(name.into())
+            .or_insert(child)
+            .clone())
+    }
+
+    fn create_child(
+        self: &Arc<Self>,
+        name: &str,
+        create: impl FnOnce(&dyn Inode) -> Result<Arc<dyn Inode>>,
+    ) -> Result<Arc<Dentry>> {
+        if self.fs.read_only() {
+            return Err(VfsError::ReadOnly);
+        }
+        if self.children.lock().contains_key(name) {
+            return Err(VfsError::Exists);
+        }
+        let inode = create(&*self.inode)?;
+        let child = Dentry::new(name, inode, self.fs.clone(), Some(self.clone()), None)?;
+        self.children.lock().insert(name.into(), child.clone());
+        Ok(child)
+    }
+
+    /// Drops the cached subtree, breaking the parent/child reference cycles.
+    fn prune(&self) {
+        let children = core::mem::take(&mut *self.children.lock());
+        for child in children.values() {
+            child.prune();
+        }
+    }
+}
+
+/// The root of the filesystem `dentry` belongs to.
+fn fs_root(dentry: &Arc<Dentry>) -> Arc<Dentry> {
+    let mut current = dentry.clone();
+    while let Some(parent) = &current.parent {
+        current = parent.clone();
+    }
+    current
+}
+
+/// Steps onto whatever is mounted on `dentry`.
+fn cross_mounts(mut dentry: Arc<Dentry>) -> Arc<Dentry> {
+    loop {
+        let mounted = dentry.mounted.lock().clone();
+        match mounted {
+            Some(root) => dentry = root,
+            None => return dentry,
+        }
+    }
+}
+
+/// Where `..` of `dentry` leads: out of a filesystem root through its
+/// mountpoint, and nowhere from the root of everything.
+fn parent_of(dentry: &Arc<Dentry>) -> Arc<Dentry> {
+    let mut current = dentry.clone();
+    loop {
+        if let Some(parent) = &current.parent {
+            return parent.clone();
+        }
+        match &current.mountpoint {
+            Some(mountpoint) => current = mountpoint.clone(),
+            None => return current,
+        }
+    }
+}
+
+struct Mount {
+    source: String,
+    root: Arc<Dentry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub path: String,
+    pub source: String,
+    pub fs_type: &'static str,
+    pub read_only: bool,
+}
+
+static ROOT: SpinLock<Option<Arc<Dentry>>> = SpinLock::new(None);
+static MOUNTS: SpinLock<Vec<Mount>> = SpinLock::new(Vec::new());
+
+/// The dentry of `/`.
+pub fn root() -> Result<Arc<Dentry>> {
+    ROOT.lock().clone().ok_or(VfsError::NotFound)
+}
+
+/// Looks `path` up starting at `base` if it is relative. A symlink in the
+/// last component is followed only if `follow` is set.
+pub fn resolve_at(base: &Arc<Dentry>, path: &str, follow: bool) -> Result<Arc<Dentry>> {
+    let mut links = 0;
+    walk(base.clone(), path, follow, &mut links)
+}
+
+pub fn resolve(path: &str, follow: bool) -> Result<Arc<Dentry>> {
+    resolve_at(&root()?, path, follow)
+}
+
+fn walk(base: Arc<Dentry>, path: &str, follow: bool, links: &mut usize) -> Result<Arc<Dentry>> {
+    if path.is_empty() {
+        return Err(VfsError::NotFound);
+    }
+    if path.len() > MAX_PATH {
+        return Err(VfsError::NameTooLong);
+    }
+    // a trailing slash asks for a directory, through a symlink if need be
+    let wants_directory = path.ends_with('/');
+    let follow = follow || wants_directory;
+    let mut current = match path.starts_with('/') {
+        true => root()?,
+        false => base,
+    };
+    let mut components = path
+        .split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .peekable();
+    while let Some(name) = components.next() {
+        if name == ".." {
+            current = parent_of(&current);
+            continue;
+        }
+        if name.len() > MAX_NAME {
+            return Err(VfsError::NameTooLong);
+        }
+        let child = cross_mounts(current.child(name)?);
+        let last = components.peek().is_none();
+        if child.kind == FileType::Symlink && (follow || !last) {
+            *links += 1;
+            if *links > MAX_SYMLINKS {
+                return Err(VfsError::SymlinkLoop);
+            }
+            let target = child.inode.read_link()?;
+            current = walk(current, &target, true, links)?;
+        } else {
+            current = child;
+        }
+    }
+    if wants_directory && current.kind != FileType::Directory {
+        return Err(VfsError::NotDirectory);
+    }
+    Ok(current)
+}
+
+/// Resolves the directory part of `path` and returns it with the last
+/// component.
+fn resolve_parent(path: &str) -> Result<(Arc<Dentry>, &str)> {
+    let trimmed = path.trim_end_matches('/');
+    let (directory, name) = match trimmed.rfind('/') {
+        Some(0) => ("/", &trimmed[1..]),
+        Some(slash) => (&trimmed[..slash], &trimmed[slash + 1..]),
+        None => (".", trimmed),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(VfsError::InvalidArgument);
+    }
+    if name.len() > MAX_NAME {
+        return Err(VfsError::NameTooLong);
+    }
+    let parent = resolve(directory, true)?;
+    if parent.kind != FileType::Directory {
+        return Err(VfsError::NotDirectory);
+    }
+    Ok((parent, name))
+}
+
+/// The file object the VFS uses for inodes without their own.
+struct OpenFile {
+    dentry: Arc<Dentry>,
+    flags: FileFlags,
+    /// Byte offset, or the directory cursor.
+    position: SpinLock<u64>,
+}
+
+impl File for OpenFile {
+    fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(VfsError::BadDescriptor);
+        }
+        if self.dentry.kind == FileType::Directory {
+            return Err(VfsError::IsDirectory);
+        }
+        let mut position = self.position.lock();
+        let read = self.dentry.inode.read_at(*position, buf)?;
+        *position += read as u64;
+        Ok(read)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<usize> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(VfsError::BadDescriptor);
+        }
+        let mut position = self.position.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *position = self.dentry.inode.metadata()?.size;
+        }
+        let written = self.dentry.inode.write_at(*position, buf)?;
+        *position += written as u64;
+        Ok(written)
+    }
+
+    fn seek(&self, from: SeekFrom) -> Result<u64> {
+        let mut position = self.position.lock();
+        let (base, delta) = match from {
+            SeekFrom::Start(offset) => (0, offset as i128),
+            SeekFrom::Current(delta) => (*position, delta as i128),
+            SeekFrom::End(delta) => (self.dentry.inode.metadata()?.size, delta as i128),
+        };
+        let target = base as i128 + delta;
+        if !(0..=i64::MAX as i128).contains(&target) {
+            return Err(VfsError::InvalidArgument);
+        }
+        *position = target as u64;
+        Ok(*position)
+    }
+
+    fn read_dir(&self) -> Result<Option<DirEntry>> {
+        if self.dentry.kind != FileType::Directory {
+            return Err(VfsError::NotDirectory);
+        }
+        let mut position = self.position.lock();
+        let Some((entry, next)) = self.dentry.inode.read_dir(*position)? else {
+            return Ok(None);
+        };
+        *position = next;
+        Ok(Some(entry))
+    }
+
+    fn metadata(&self) -> Result<Metadata> {
+        self.dentry.inode.metadata()
+    }
+
+    fn flags(&self) -> OpenFlags {
+        self.flags.get()
+    }
+
+    fn set_flags(&self, flags: OpenFlags) {
+        self.flags.set(flags);
+    }
+}
+
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<dyn File>> {
+    let follow = !flags.contains(OpenFlags::NO_FOLLOW);
+    let dentry = match resolve(path, follow) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(VfsError::Exists);
+        }
+        Ok(dentry) => dentry,
+        Err(VfsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = resolve_parent(path)?;
+            parent.create_child(name, |inode| inode.create(name, FileType::Regular, 0o644))?
+        }
+        Err(err) => return Err(err),
+    };
+
+    let writes = flags.contains(OpenFlags::WRITE) || flags.contains(OpenFlags::TRUNCATE);
+    match dentry.kind {
+        FileType::Symlink => return Err(VfsError::SymlinkLoop),
+        FileType::Directory if writes => return Err(VfsError::IsDirectory),
+        FileType::Directory => {}
+        _ if flags.contains(OpenFlags::DIRECTORY) => return Err(VfsError::NotDirectory),
+        _ => {}
+    }
+    if writes && dentry.kind == FileType::Regular {
+        if dentry.fs.read_only() {
+            return Err(VfsError::ReadOnly);
+        }
+        if flags.contains(OpenFlags::TRUNCATE) {
+            dentry.inode.truncate(0)?;
+        }
+    }
+
+    if let Some(file) = dentry.inode.open(flags)? {
+        return Ok(file);
+    }
+    Ok(Arc::new(OpenFile {
+        dentry,
+        flags: FileFlags::new(flags),
+        position: SpinLock::new(0),
+    }))
+}
+
+/// Metadata of the file at `path`, following a final symlink.
+pub fn stat(path: &str) -> Result<Metadata> {
+    resolve(path, true)?.inode.metadata()
+}
+
+/// Metadata of `path` itself, even if it is a symlink.
+pub fn lstat(path: &str) -> Result<Metadata> {
+    resolve(path, false)?.inode.metadata()
+}
+
+/// Creates an empty regular file at `path`.
+pub fn create(path: &str, mode: u16) -> Result<()> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.create_child(name, |inode| inode.create(name, FileType::Regular, mode))?;
+    Ok(())
+}
+
+pub fn mkdir(path: &str, mode: u16) -> Result<()> {
+    let (parent, name) = resolve_parent(path)?;
+    parent.create_child(name, |inode| inode.create(name, FileType::Directory, mode))?;
+    Ok(())
+}
+
+/// Creates a symlink at `path` pointing at `target`.
+pub fn symlink(target: &str, path: &str) -> Result<()> {
+    if target.is_empty() || target.len() > MAX_PATH {
+        return Err(VfsError::InvalidArgument);
+    }
+    let (parent, name) = resolve_parent(path)?;
+    parent.create_child(name, |inode| inode.symlink(name, target))?;
+    Ok(())
+}
+
+/// Makes `path` another name of the file at `existing`, which is not
+/// followed if it is a symlink.
+pub fn link(existing: &str, path: &str) -> Result<()> {
+    let target = resolve(existing, false)?;
+    if target.kind == FileType::Directory {
+        return Err(VfsError::NotPermitted);
+    }
+    let (parent, name) = resolve_parent(path)?;
+    if !Arc::ptr_eq(&parent.fs, &target.fs) {
+        return Err(VfsError::CrossDevice);
+    }
+    parent.create_child(name, |inode| {
+        inode.link(name, &target.inode)?;
+        Ok(target.inode.clone())
+    })?;
+    Ok(())
+}
+
+/// Changes the metadata of the file at `path`, following a final symlink.
+pub fn set_attributes(path: &str, attributes: &Attributes) -> Result<()> {
+    let dentry = resolve(path, true)?;
+    if dentry.fs.read_only() {
+        return Err(VfsError::ReadOnly);
+    }
+    dentry.inode.set_attributes(attributes)
+}
+
+pub fn chmod(path: &str, mode: u16) -> Result<()> {
+    let attributes = Attributes {
+        mode: Some(mode & 0o7777),
+        ..Attributes::default()
+    };
+    set_attributes(path, &attributes)
+}
+
+pub fn chown(path: &str, uid: u32, gid: u32) -> Result<()> {
+    let attributes = Attributes {
+        uid: Some(uid),
+        gid: Some(gid),
+        ..Attributes::default()
+    };
+    set_attributes(path, &attributes)
+}
+
+pub fn read_link(path: &str) -> Result<String> {
+    resolve(path, false)?.inode.read_link()
+}
+
+pub fn unlink(path: &str) -> Result<()> {
+    remove(path, false)
+}
+
+pub fn rmdir(path: &str) -> Result<()> {
+    remove(path, true)
+}
+
+fn remove(path: &str, directory: bool) -> Result<()> {
+    let (parent, name) = resolve_parent(path)?;
+    let child = parent.child(name)?;
+    if child.mounted.lock().is_some() {
+        return Err(VfsError::Busy);
+    }
+    match (directory, child.kind == FileType::Directory) {
+        (true, false) => return Err(VfsError::NotDirectory),
+        (false, true) => return Err(VfsError::IsDirectory),
+        _ => {}
+    }
+    if parent.fs.read_only() {
+        return Err(VfsError::ReadOnly);
+    }
+    match directory {
+        true => parent.inode.rmdir(name)?,
+        false => parent.inode.unlink(name)?,
+    }
+    parent.children.lock().remove(name);
+    child.prune();
+    Ok(())
+}
+
+/// Mounts `fs` on the directory at `path`. The first mount has to be the
+/// one of `/`. `source` is what the mount table shows it came from.
+pub fn mount(path: &str, source: &str, fs: Arc<dyn FileSystem>) -> Result<()> {
+    let mountpoint = match root() {
+        Err(_) if path.starts_with('/') && path.trim_matches('/').is_empty() => None,
+        Err(err) => return Err(err),
+        Ok(_) => {
+            let mountpoint = resolve(path, true)?;
+            if mountpoint.kind != FileType::Directory {
+                return Err(VfsError::NotDirectory);
+            }
+            if mountpoint.parent.is_none() && mountpoint.mountpoint.is_none() {
+                return Err(VfsError::Busy);
+            }
+            Some(mountpoint)
+        }
+    };
+    let fs_root = Dentry::new("", fs.root(), fs, None, mountpoint.clone())?;
+    if fs_root.kind != FileType::Directory {
+        return Err(VfsError::NotDirectory);
+    }
+    match &mountpoint {
+        Some(mountpoint) => {
+            let mut mounted = mountpoint.mounted.lock();
+            if mounted.is_some() {
+                return Err(VfsError::Busy);
+            }
+            *mounted = Some(fs_root.clone());
+        }
+        None => {
+            let mut root = ROOT.lock();
+            if root.is_some() {
+                return Err(VfsError::Busy);
+            }
+            *root = Some(fs_root.clone());
+        }
+    }
+    log::info!(
+        "VFS: {} ({}) mounted on {}",
+        source,
+        fs_root.fs.name(),
+        fs_root.path()
+    );
+    MOUNTS.lock().push(Mount {
+        source: source.into(),
+        root: fs_root,
+    });
+    Ok(())
+}
+
+/// Unmounts the filesystem whose root is at `path`, after syncing it.
+pub fn unmount(path: &str) -> Result<()> {
+    let target = resolve(path, true)?;
+    let Some(mountpoint) = target.mountpoint.clone() else {
+        return Err(match target.parent {
+            Some(_) => VfsError::InvalidArgument,
+            None => VfsError::Busy,
+        });
+    };
+    let mut mounts = MOUNTS.lock();
+    let nested = mounts.iter().any(|mount| {
+        mount
+            .root
+            .mountpoint
+            .as_ref()
+            .is_some_and(|under| Arc::ptr_eq(&fs_root(under), &target))
+    });
+    if nested || target.mounted.lock().is_some() {
+        return Err(VfsError::Busy);
+    }
+    target.fs.sync()?;
+    *mountpoint.mounted.lock() = None;
+    mounts.retain(|mount| !Arc::ptr_eq(&mount.root, &target));
+    drop(mounts);
+    target.prune();
+    Ok(())
+}
+
+/// The mount table, in mount order.
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| MountInfo {
+            path: mount.root.path(),
+            source: mount.source.clone(),
+            fs_type: mount.root.fs.name(),
+            read_only: mount.root.fs.read_only(),
+        })
+        .collect()
+}
+
+/// Syncs every mounted filesystem.
+pub fn sync() -> Result<()> {
+    let filesystems: Vec<_> = MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| mount.root.fs.clone())
+        .collect();
+    filesystems.iter().try_for_each(|fs| fs.sync())
+}
@@ -0,0 +1,541 @@
+use core::fmt;
+
+use crate::interrupts::VectorHandler;
+use crate::memory;
+use crate::msi::{self, MsiError, MsiKind, MsiVectors};
+use crate::pci::{self, PciDevice};
+use crate::virtqueue::{MAX_QUEUE_SIZE, Virtqueue};
+
+// Modern (virtio 1.x) PCI transport. The device describes where its register
+// blocks live through vendor-specific PCI capabilities, each pointing into a
+// memory BAR:
+//
+//   common config   feature bits, device status and per-queue setup
+//   notify          doorbells, one per queue at notify_off * multiplier
+//   ISR status      interrupt cause, read to acknowledge (INTx only)
+//   device config   device-type specific fields
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+/// Modern-only devices use 0x1040 + device type.
+const MODERN_DEVICE_ID_BASE: u16 = 0x1040;
+/// Transitional devices carry the type in the subsystem ID instead.
+const TRANSITIONAL_DEVICE_IDS: core::ops::RangeInclusive<u16> = 0x1000..=0x103F;
+
+pub mod device_type {
+    pub const NET: u16 = 1;
+    pub const BLOCK: u16 = 2;
+    pub const CONSOLE: u16 = 3;
+    pub const ENTROPY: u16 = 4;
+    pub const GPU: u16 = 16;
+    pub const INPUT: u16 = 18;
+}
+
+pub mod status {
+    pub const ACKNOWLEDGE: u8 = 1;
+    pub const DRIVER: u8 = 2;
+    pub const DRIVER_OK: u8 = 4;
+    pub const FEATURES_OK: u8 = 8;
+    pub const DEVICE_NEEDS_RESET: u8 = 64;
+    pub const FAILED: u8 = 128;
+}
+
+/// Device-independent feature bits.
+pub mod feature {
+    pub const INDIRECT_DESC: u64 = 1 << 28;
+    pub const EVENT_IDX: u64 = 1 << 29;
+    pub const VERSION_1: u64 = 1 << 32;
+    pub const ACCESS_PLATFORM: u64 = 1 << 33;
+    pub const RING_PACKED: u64 = 1 << 34;
+}
+
+mod cfg_type {
+    pub const COMMON: u8 = 1;
+    pub const NOTIFY: u8 = 2;
+    pub const ISR: u8 = 3;
+    pub const DEVICE: u8 = 4;
+}
+
+mod common {
+    pub const DEVICE_FEATURE_SELECT: usize = 0x00;
+    pub const DEVICE_FEATURE: usize = 0x04;
+    pub const DRIVER_FEATURE_SELECT: usize = 0x08;
+    pub const DRIVER_FEATURE: usize = 0x0C;
+    pub const MSIX_CONFIG: usize = 0x10;
+    pub const NUM_QUEUES: usize = 0x12;
+    pub const DEVICE_STATUS: usize = 0x14;
+    pub const CONFIG_GENERATION: usize = 0x15;
+    pub const QUEUE_SELECT: usize = 0x16;
+    pub const QUEUE_SIZE: usize = 0x18;
+    pub const QUEUE_MSIX_VECTOR: usize = 0x1A;
+    pub const QUEUE_ENABLE: usize = 0x1C;
+    pub const QUEUE_NOTIFY_OFF: usize = 0x1E;
+    pub const QUEUE_DESC: usize = 0x20;
+    pub const QUEUE_DRIVER: usize = 0x28;
+    pub const QUEUE_DEVICE: usize = 0x30;
+    pub const LEN: u32 = 0x38;
+}
+
+/// MSI-X vector number meaning "no interrupt".
+const NO_VECTOR: u16 = 0xFFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// A register block capability is missing or points outside its BAR.
+    MissingCapability(&'static str),
+    /// The device does not offer VIRTIO_F_VERSION_1.
+    LegacyOnly,
+    /// The device cleared FEATURES_OK after feature negotiation.
+    FeaturesRejected,
+    /// The queue does not exist or is already enabled.
+    QueueUnavailable(u16),
+    /// The device did not accept an MSI-X vector.
+    VectorRejected(u16),
+    /// Interrupts were requested without MSI-X vectors.
+    NoVectors,
+    Msi(MsiError),
+    OutOfMemory,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VirtioError::MissingCapability(name) => write!(f, "no usable {} capability", name),
+            VirtioError::LegacyOnly => write!(f, "device lacks VIRTIO_F_VERSION_1"),
+            VirtioError::FeaturesRejected => write!(f, "device rejected the negotiated features"),
+            VirtioError::QueueUnavailable(queue) => write!(f, "queue {} unavailable", queue),
+            VirtioError::VectorRejected(vector) => {
+                write!(f, "device rejected MSI-X vector {}", vector)
+            }
+            VirtioError::NoVectors => write!(f, "no MSI-X vectors allocated"),
+            VirtioError::Msi(err) => write!(f, "{}", err),
+            VirtioError::OutOfMemory => write!(f, "out of memory for virtqueue"),
+        }
+    }
+}
+
+impl From<MsiError> for VirtioError {
+    fn from(err: MsiError) -> Self {
+        VirtioError::Msi(err)
+    }
+}
+
+/// How a queue reports finished requests.
+#[derive(Debug, Clone, Copy)]
+pub enum QueueMode {
+    /// No interrupts; the driver polls the used ring.
+    Polling,
+    /// MSI-X vector `vector` (an index into [`VirtioDevice::enable_msix`]'s
+    /// allocation) calls `handler` with `context`.
+    Interrupt {
+        vector: u16,
+        handler: VectorHandler,
+        context: usize,
+    },
+}
+
+/// Virtio device type of a PCI function, if it is a virtio device at all.
+pub fn device_type_of(device: &PciDevice) -> Option<u16> {
+    if device.vendor_id != VENDOR_ID {
+        return None;
+    }
+    match device.device_id {
+        id if TRANSITIONAL_DEVICE_IDS.contains(&id) => Some(device.subsystem_id),
+        id if id >= MODERN_DEVICE_ID_BASE => Some(id - MODERN_DEVICE_ID_BASE),
+        _ => None,
+    }
+}
+
+/// Every virtio PCI function of the given device type.
+pub fn find(device_type: u16) -> impl Iterator<Item = PciDevice> {
+    pci::devices().filter(move |device| device_type_of(device) == Some(device_type))
+}
+
+pub fn device_type_name(device_type: u16) -> &'static str {
+    match device_type {
+        device_type::NET => "network",
+        device_type::BLOCK => "block",
+        device_type::CONSOLE => "console",
+        device_type::ENTROPY => "entropy",
+        device_type::GPU => "GPU",
+        device_type::INPUT => "input",
+        _ => "unknown",
+    }
+}
+
+/// A register block found through a virtio capability.
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    base: *mut u8,
+    len: u32,
+}
+
+impl Region {
+    /// Accesses have to match the field width, up to 32 bits.
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(size_of::<T>() <= 4 && offset + size_of::<T>() <= self.len as usize);
+        unsafe { self.base.add(offset).cast::<T>().read_volatile() }
+    }
+
+    fn write<T: Copy>(&self, offset: usize, value: T) {
+        assert!(size_of::<T>() <= 4 && offset + size_of::<T>() <= self.len as usize);
+        unsafe { self.base.add(offset).cast::<T>().write_volatile(value) }
+    }
+
+    /// 64-bit fields are accessed as two 32-bit halves, low half first.
+    fn read_u64(&self, offset: usize) -> u64 {
+        let low: u32 = self.read(offset);
+        let high: u32 = self.read(offset + 4);
+        (high as u64) << 32 | low as u64
+    }
+
+    fn write_u64(&self, offset: usize, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// A modern virtio PCI function, from reset through feature negotiation to
+/// running queues.
+///
+/// Dropping it resets the device, which stops all DMA, so queues may be
+/// dropped afterwards.
+pub struct VirtioDevice {
+    pci: PciDevice,
+    device_type: u16,
+    common: Region,
+    notify: Region,
+    notify_multiplier: u32,
+    isr: Region,
+    config: Option<Region>,
+    features: u64,
+    vectors: Option<MsiVectors>,
+}
+
+// The register blocks are MMIO that only this value touches.
+unsafe impl Send for VirtioDevice {}
+
+impl VirtioDevice {
+    /// Maps the register blocks of `pci`, resets the device and announces a
+    /// driver.
+    pub fn new(pci: PciDevice) -> Result<VirtioDevice, VirtioError> {
+        let device_type = device_type_of(&pci).unwrap_or(0);
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut config = None;
+        let address = pci.address;
+        for capability in address.capabilities() {
+            if capability.id != pci::capability::VENDOR_SPECIFIC {
+                continue;
+            }
+            let offset = capability.offset;
+            let kind = address.read_u8(offset + 3);
+            let slot = match kind {
+                cfg_type::COMMON => &mut common,
+                cfg_type::NOTIFY => &mut notify,
+                cfg_type::ISR => &mut isr,
+                cfg_type::DEVICE => &mut config,
+                _ => continue,
+            };
+            // the first capability of each type is the preferred one
+            if slot.is_some() {
+                continue;
+            }
+            let bar = address.read_u8(offset + 4) as usize;
+            let region_offset = address.read_u32(offset + 8) as u64;
+            let len = address.read_u32(offset + 12);
+            let Some(bar) = pci
+                .bar(bar)
+                .filter(|bar| region_offset + len as u64 <= bar.size())
+            else {
+                continue;
+            };
+            let Some(base) = bar
+                .memory_base()
+                .and_then(|base| memory::map_mmio(base + region_offset, len as u64))
+            else {
+                continue;
+            };
+            let multiplier = match kind {
+                cfg_type::NOTIFY => address.read_u32(offset + 16),
+                _ => 0,
+            };
+            *slot = Some((Region { base, len }, multiplier));
+        }
+
+        let (common, _) = common.ok_or(VirtioError::MissingCapability("common config"))?;
+        if common.len < common::LEN {
+            return Err(VirtioError::MissingCapability("common config"));
+        }
+        let (notify, notify_multiplier) = notify.ok_or(VirtioError::MissingCapability("notify"))?;
+        let (isr, _) = isr.ok_or(VirtioError::MissingCapability("ISR"))?;
+        address.enable(pci::command::MEMORY_SPACE | pci::command::BUS_MASTER);
+
+        let device = VirtioDevice {
+            pci,
+            device_type,
+            common,
+            notify,
+            notify_multiplier,
+            isr,
+            config: config.map(|(region, _)| region),
+            features: 0,
+            vectors: None,
+        };
+        device.reset();
+        device.add_status(status::ACKNOWLEDGE | status::DRIVER);
+        Ok(device)
+    }
+
+    pub fn pci(&self) -> &PciDevice {
+        &self.pci
+    }
+
+    pub fn device_type(&self) -> u16 {
+        self.device_type
+    }
+
+    pub fn status(&self) -> u8 {
+        self.common.read(common::DEVICE_STATUS)
+    }
+
+    fn add_status(&self, bits: u8) {
+        self.common
+            .write(common::DEVICE_STATUS, self.status() | bits);
+    }
+
+    /// Resets the device and waits until it has finished resetting.
+    pub fn reset(&self) {
+        self.common.write(common::DEVICE_STATUS, 0u8);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Tells the device that something went wrong and the driver gave up.
+    pub fn fail(&self) {
+        self.add_status(status::FAILED);
+    }
+
+    pub fn device_features(&self) -> u64 {
+        let mut features = 0;
+        for half in 0..2u32 {
+            self.common.write(common::DEVICE_FEATURE_SELECT, half);
+            let bits: u32 = self.common.read(common::DEVICE_FEATURE);
+            features |= (bits as u64) << (32 * half);
+        }
+        features
+    }
+
+    /// Accepts the subset of `wanted` the device offers, plus
+    /// VIRTIO_F_VERSION_1, and returns it.
+    ///
+    /// Ring features the transport handles itself (indirect descriptors,
+    /// event-idx) have to be in `wanted` as well to be used.
+    pub fn negotiate(&mut self, wanted: u64) -> Result<u64, VirtioError> {
+        let offered = self.device_features();
+        if offered & feature::VERSION_1 == 0 {
+            self.fail();
+            return Err(VirtioError::LegacyOnly);
+        }
+        let features = offered & (wanted | feature::VERSION_1);
+        for half in 0..2u32 {
+            self.common.write(common::DRIVER_FEATURE_SELECT, half);
+            self.common
+                .write(common::DRIVER_FEATURE, (features >> (32 * half)) as u32);
+        }
+        self.add_status(status::FEATURES_OK);
+        if self.status() & status::FEATURES_OK == 0 {
+            self.fail();
+            return Err(VirtioError::FeaturesRejected);
+        }
+        self.features = features;
+        log::info!(
+            "virtio {}: {} device, features {:#x} of {:#x}",
+            self.pci.address,
+            device_type_name(self.device_type),
+            features,
+            offered
+        );
+        Ok(features)
+    }
+
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.features & feature != 0
+    }
+
+    pub fn num_queues(&self) -> u16 {
+        self.common.read(common::NUM_QUEUES)
+    }
+
+    /// Allocates `count` MSI-X vectors for [`QueueMode::Interrupt`] and
+    /// [`set_config_handler`](Self::set_config_handler).
+    pub fn enable_msix(&mut self, count: usize) -> Result<(), VirtioError> {
+        let vectors = msi::allocate(&self.pci, count)?;
+        // virtio only routes interrupts through MSI-X vector numbers
+        if vectors.kind() != MsiKind::MsiX {
+            return Err(VirtioError::NoVectors);
+        }
+        self.vectors = Some(vectors);
+        Ok(())
+    }
+
+    pub fn vectors(&self) -> Option<&MsiVectors> {
+        self.vectors.as_ref()
+    }
+
+    fn route_vector(
+        &self,
+        register: usize,
+        vector: u16,
+        handler: VectorHandler,
+        context: usize,
+    ) -> Result<(), VirtioError> {
+        let vectors = self.vectors.as_ref().ok_or(VirtioError::NoVectors)?;
+        if vector as usize >= vectors.count() {
+            return Err(VirtioError::VectorRejected(vector));
+        }
+        self.common.write(register, vector);
+        if self.common.read::<u16>(register) != vector {
+            return Err(VirtioError::VectorRejected(vector));
+        }
+        vectors.set_handler(vector as usize, handler, context);
+        vectors.unmask(vector as usize);
+        Ok(())
+    }
+
+    /// Routes configuration change interrupts to MSI-X vector `vector`.
+    pub fn set_config_handler(
+        &self,
+        vector: u16,
+        handler: VectorHandler,
+        context: usize,
+    ) -> Result<(), VirtioError> {
+        self.route_vector(common::MSIX_CONFIG, vector, handler, context)
+    }
+
+    /// Sets up and enables queue `index` with at most `max_size` entries,
+    /// rounded down to a power of two.
+    ///
+    /// Has to happen after [`negotiate`](Self::negotiate) and before
+    /// [`driver_ok`](Self::driver_ok).
+    pub fn setup_queue(
+        &mut self,
+        index: u16,
+        max_size: u16,
+        mode: QueueMode,
+    ) -> Result<Virtqueue, VirtioError> {
+        if index >= self.num_queues() {
+            return Err(VirtioError::QueueUnavailable(index));
+        }
+        self.common.write(common::QUEUE_SELECT, index);
+        let offered: u16 = self.common.read(common::QUEUE_SIZE);
+        let enabled: u16 = self.common.read(common::QUEUE_ENABLE);
+        if offered == 0 || enabled != 0 {
+            return Err(VirtioError::QueueUnavailable(index));
+        }
+        let size = 1 << offered.clamp(1, max_size.clamp(1, MAX_QUEUE_SIZE)).ilog2();
+        self.common.write(common::QUEUE_SIZE, size);
+
+        let notify_off: u16 = self.common.read(common::QUEUE_NOTIFY_OFF);
+        let notify_offset = notify_off as usize * self.notify_multiplier as usize;
+        if notify_offset + 2 > self.notify.len as usize {
+            return Err(VirtioError::MissingCapability("notify"));
+        }
+        let notify = unsafe { self.notify.base.add(notify_offset) }.cast();
+        let mut queue = Virtqueue::new(
+            index,
+            size,
+            self.has_feature(feature::INDIRECT_DESC),
+            self.has_feature(feature::EVENT_IDX),
+            notify,
+        )
+        .ok_or(VirtioError::OutOfMemory)?;
+
+        self.common.write_u64(common::QUEUE_DESC, queue.desc_phys());
+        self.common
+            .write_u64(common::QUEUE_DRIVER, queue.avail_phys());
+        self.common
+            .write_u64(common::QUEUE_DEVICE, queue.used_phys());
+        match mode {
+            QueueMode::Polling => {
+                self.common.write(common::QUEUE_MSIX_VECTOR, NO_VECTOR);
+                queue.disable_interrupts();
+            }
+            QueueMode::Interrupt {
+                vector,
+                handler,
+                context,
+            } => {
+                self.route_vector(common::QUEUE_MSIX_VECTOR, vector, handler, context)?;
+                queue.enable_interrupts();
+            }
+        }
+        self.common.write(common::QUEUE_ENABLE, 1u16);
+        Ok(queue)
+    }
+
+    /// Finishes initialisation; the device starts processing queues.
+    pub fn driver_ok(&self) {
+        self.add_status(status::DRIVER_OK);
+    }
+
+    /// Reads and thereby acknowledges the ISR status: bit 0 for queue
+    /// interrupts, bit 1 for configuration changes. Only meaningful for INTx.
+    pub fn read_isr(&self) -> u8 {
+        self.isr.read(0)
+    }
+
+    pub fn config_len(&self) -> usize {
+        self.config.map_or(0, |config| config.len as usize)
+    }
+
+    fn config(&self) -> Region {
+        self.config.expect("device has no configuration space")
+    }
+
+    /// Reads fields of the device-specific configuration through `f`,
+    /// retrying if the device changed them halfway through.
+    fn read_config_consistent<T>(&self, f: impl Fn(Region) -> T) -> T {
+        loop {
+            let generation: u8 = self.common.read(common::CONFIG_GENERATION);
+            let value = f(self.config());
+            if self.common.read::<u8>(common::CONFIG_GENERATION) == generation {
+                return value;
+            }
+        }
+    }
+
+    /// Reads an 8, 16 or 32-bit field of the device-specific configuration.
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        self.read_config_consistent(|config| config.read(offset))
+    }
+
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        self.read_config_consistent(|config| config.read_u64(offset))
+    }
+
+    pub fn write_config<T: Copy>(&self, offset: usize, value: T) {
+        self.config().write(offset, value);
+    }
+}
+
+impl Drop for VirtioDevice {
+    fn drop(&mut self) {
+        self.reset();
+    }
+}
+
+impl fmt::Debug for VirtioDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("VirtioDevice")
+            .field("address", &self.pci.address)
+            .field("device_type", &self.device_type)
+            .field("features", &self.features)
+            .finish()
+    }
+}
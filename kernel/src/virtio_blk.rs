@@ -0,0 +1,653 @@
+use core::fmt;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use crate::block::{self, BlockDevice, BlockError, Bounce};
+use crate::memory;
+use crate::sync::{self, IrqSpinLock, WakerSlot};
+use crate::virtio::{self, QueueMode, VirtioDevice, VirtioError, device_type, feature};
+use crate::virtqueue::{Segment, Virtqueue};
+
+// Every request is one chain: a 16-byte header the device reads, the data
+// buffers, and one status byte the device writes. Headers, status bytes and
+// discard ranges of all request slots share a single DMA frame.
+
+pub const SECTOR_SIZE: usize = 512;
+
+mod blk_feature {
+    pub const SIZE_MAX: u64 = 1 << 1;
+    pub const SEG_MAX: u64 = 1 << 2;
+    pub const RO: u64 = 1 << 5;
+    pub const BLK_SIZE: u64 = 1 << 6;
+    pub const FLUSH: u64 = 1 << 9;
+    pub const DISCARD: u64 = 1 << 13;
+}
+
+mod config {
+    pub const CAPACITY: usize = 0;
+    pub const SIZE_MAX: usize = 8;
+    pub const SEG_MAX: usize = 12;
+    pub const BLK_SIZE: usize = 20;
+    pub const MAX_DISCARD_SECTORS: usize = 36;
+}
+
+mod request_type {
+    pub const IN: u32 = 0;
+    pub const OUT: u32 = 1;
+    pub const FLUSH: u32 = 4;
+    pub const DISCARD: u32 = 11;
+}
+
+mod request_status {
+    pub const OK: u8 = 0;
+    pub const UNSUPP: u8 = 2;
+}
+
+const MAX_DISKS: usize = 4;
+/// Requests one disk can have in flight.
+pub const MAX_IN_FLIGHT: usize = 32;
+/// Per-slot area in the request frame: header, discard range, status.
+const SLOT_SIZE: usize = 64;
+const HEADER_LEN: u32 = 16;
+const DISCARD_OFFSET: usize = 16;
+const DISCARD_LEN: u32 = 16;
+const STATUS_OFFSET: usize = 48;
+/// Largest transfer the blocking helpers put into one request.
+const MAX_TRANSFER: usize = 64 * 1024;
+/// Data segments a request is split into at most.
+const MAX_DATA_SEGMENTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlkError {
+    /// The device reported an I/O error.
+    Io,
+    /// The device does not support the operation.
+    Unsupported,
+    ReadOnly,
+    OutOfRange,
+    /// All request slots are taken.
+    Busy,
+    /// The buffer needs more segments than the device takes.
+    TooLarge,
+    OutOfMemory,
+    Virtio(VirtioError),
+}
+
+impl fmt::Display for BlkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlkError::Io => write!(f, "I/O error"),
+            BlkError::Unsupported => write!(f, "operation not supported"),
+            BlkError::ReadOnly => write!(f, "device is read-only"),
+            BlkError::OutOfRange => write!(f, "sector out of range"),
+            BlkError::Busy => write!(f, "too many requests in flight"),
+            BlkError::TooLarge => write!(f, "request too large for the device"),
+            BlkError::OutOfMemory => write!(f, "out of memory"),
+            BlkError::Virtio(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<VirtioError> for BlkError {
+    fn from(err: VirtioError) -> Self {
+        BlkError::Virtio(err)
+    }
+}
+
+/// A request on physically addressed buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlkOp {
+    /// Reads `len` bytes, a multiple of [`SECTOR_SIZE`], into `phys`.
+    Read {
+        sector: u64,
+        phys: u64,
+        len: u32,
+    },
+    Write {
+        sector: u64,
+        phys: u64,
+        len: u32,
+    },
+    /// Makes completed writes durable.
+    Flush,
+    /// Tells the device `count` sectors no longer hold useful data.
+    Discard {
+        sector: u64,
+        count: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskInfo {
+    /// Size in 512-byte sectors, whatever the block size.
+    pub sectors: u64,
+    /// Preferred I/O granularity in bytes.
+    pub block_size: u32,
+    pub read_only: bool,
+    pub flush: bool,
+    pub discard: bool,
+    pub interrupts: bool,
+}
+
+impl fmt::Display for DiskInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} sectors ({} MiB), {}-byte blocks, {}",
+            self.sectors,
+            (self.sectors * SECTOR_SIZE as u64) >> 20,
+            self.block_size,
+            if self.read_only { "ro" } else { "rw" }
+        )?;
+        if self.flush {
+            write!(f, ", flush")?;
+        }
+        if self.discard {
+            write!(f, ", discard")?;
+        }
+        write!(f, ", {}", if self.interrupts { "MSI-X" } else { "polled" })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Free,
+    InFlight,
+    Done(Result<u32, BlkError>),
+}
+
+struct Disk {
+    device: VirtioDevice,
+    queue: Virtqueue,
+    info: DiskInfo,
+    max_discard_sectors: u32,
+    /// Data segments the device takes per request.
+    max_segments: usize,
+    /// Longest segment the device takes.
+    max_segment_len: u32,
+    /// Headers, discard ranges and status bytes, [`SLOT_SIZE`] per slot.
+    slots_phys: u64,
+    slots: [SlotState; MAX_IN_FLIGHT],
+}
+
+static DISKS: [IrqSpinLock<Option<Disk>>; MAX_DISKS] =
+    [const { IrqSpinLock::new(None) }; MAX_DISKS];
+static WAKERS: [[WakerSlot; MAX_IN_FLIGHT]; MAX_DISKS] =
+    [const { [const { WakerSlot::new() }; MAX_IN_FLIGHT] }; MAX_DISKS];
+
+impl Disk {
+    fn slot_area(&self, slot: usize) -> *mut u8 {
+        memory::phys_to_virt(self.slots_phys + (slot * SLOT_SIZE) as u64)
+    }
+
+    /// Moves finished requests to `Done` and returns a bitmap of their slots.
+    fn drain(&mut self) -> u32 {
+        let mut finished = 0;
+        while let Some(used) = self.queue.pop_used() {
+            let slot = used.token;
+            let status = unsafe { self.slot_area(slot).add(STATUS_OFFSET).read_volatile() };
+            let result = match status {
+                request_status::OK => Ok(used.len.saturating_sub(1)),
+                request_status::UNSUPP => Err(BlkError::Unsupported),
+                // IOERR, or a status byte the device never wrote
+                _ => Err(BlkError::Io),
+            };
+            self.slots[slot] = SlotState::Done(result);
+            finished |= 1 << slot;
+        }
+        finished
+    }
+
+    /// Largest read or write one request can carry.
+    fn max_transfer(&self) -> usize {
+        let segments = self.max_segments.min(MAX_DATA_SEGMENTS);
+        let len = (self.max_segment_len as usize).saturating_mul(segments);
+        len.min(MAX_TRANSFER) / SECTOR_SIZE * SECTOR_SIZE
+    }
+
+    /// Splits a data buffer into segments no longer than the device takes.
+    fn data_segments(
+        &self,
+        phys: u64,
+        len: u32,
+        writable: bool,
+        segments: &mut [Segment],
+    ) -> Result<usize, BlkError> {
+        let count = len.div_ceil(self.max_segment_len) as usize;
+        if count > self.max_segments.min(segments.len()) {
+            return Err(BlkError::TooLarge);
+        }
+        for (index, segment) in segments[..count].iter_mut().enumerate() {
+            let offset = index as u32 * self.max_segment_len;
+            let len = self.max_segment_len.min(len - offset);
+            *segment = match writable {
+                true => Segment::writable(phys + offset as u64, len),
+                false => Segment::readable(phys + offset as u64, len),
+            };
+        }
+        Ok(count)
+    }
+
+    fn submit(&mut self, op: BlkOp) -> Result<usize, BlkError> {
+        let (kind, sector) = match op {
+            BlkOp::Read { sector, len, .. } | BlkOp::Write { sector, len, .. } => {
+                let kind = match op {
+                    BlkOp::Read { .. } => request_type::IN,
+                    _ => request_type::OUT,
+                };
+                if !(len as usize).is_multiple_of(SECTOR_SIZE)
+                    || sector + (len as usize / SECTOR_SIZE) as u64 > self.info.sectors
+                {
+                    return Err(BlkError::OutOfRange);
+                }
+                (kind, sector)
+            }
+            BlkOp::Flush if !self.info.flush => return Err(BlkError::Unsupported),
+            BlkOp::Flush => (request_type::FLUSH, 0),
+            BlkOp::Discard { .. } if !self.info.discard => return Err(BlkError::Unsupported),
+            BlkOp::Discard { sector, count } => {
+                if count > self.max_discard_sectors {
+                    return Err(BlkError::Unsupported);
+                }
+                if sector + count as u64 > self.info.sectors {
+                    return Err(BlkError::OutOfRange);
+                }
+                (request_type::DISCARD, 0)
+            }
+        };
+        if self.info.read_only && matches!(op, BlkOp::Write { .. } | BlkOp::Discard { .. }) {
+            return Err(BlkError::ReadOnly);
+        }
+        let slot = self
+            .slots
+            .iter()
+            .position(|slot| *slot == SlotState::Free)
+            .ok_or(BlkError::Busy)?;
+
+        let area = self.slot_area(slot);
+        let area_phys = self.slots_phys + (slot * SLOT_SIZE) as u64;
+        unsafe {
+            area.cast::<u32>().write_volatile(kind);
+            area.add(4).cast::<u32>().write_volatile(0);
+            area.add(8).cast::<u64>().write_volatile(sector);
+            area.add(STATUS_OFFSET).write_volatile(0xFF);
+        }
+        let header = Segment::readable(area_phys, HEADER_LEN);
+        let status = Segment::writable(area_phys + STATUS_OFFSET as u64, 1);
+        let pushed = match op {
+            BlkOp::Read { phys, len, .. } | BlkOp::Write { phys, len, .. } => {
+                let mut chain = [header; MAX_DATA_SEGMENTS + 2];
+                let writable = matches!(op, BlkOp::Read { .. });
+                let count =
+                    self.data_segments(phys, len, writable, &mut chain[1..=MAX_DATA_SEGMENTS])?;
+                chain[count + 1] = status;
+                self.queue.push(&chain[..count + 2], slot)
+            }
+            BlkOp::Flush => self.queue.push(&[header, status], slot),
+            BlkOp::Discard { sector, count } => {
+                unsafe {
+                    let range = area.add(DISCARD_OFFSET);
+                    range.cast::<u64>().write_volatile(sector);
+                    range.add(8).cast::<u32>().write_volatile(count);
+                    range.add(12).cast::<u32>().write_volatile(0);
+                }
+                let range = Segment::readable(area_phys + DISCARD_OFFSET as u64, DISCARD_LEN);
+                self.queue.push(&[header, range, status], slot)
+            }
+        };
+        pushed.map_err(|_| BlkError::Busy)?;
+        self.slots[slot] = SlotState::InFlight;
+        self.queue.kick();
+        Ok(slot)
+    }
+}
+
+fn handle_interrupt(index: usize) {
+    let finished = match DISKS[index].lock().as_mut() {
+        Some(disk) => disk.drain(),
+        None => 0,
+    };
+    wake_finished(index, finished);
+}
+
+fn wake_finished(index: usize, mut finished: u32) {
+    while finished != 0 {
+        let slot = finished.trailing_zeros() as usize;
+        WAKERS[index][slot].wake();
+        finished &= finished - 1;
+    }
+}
+
+fn probe(index: usize, pci: crate::pci::PciDevice) -> Result<Disk, BlkError> {
+    let mut device = VirtioDevice::new(pci)?;
+    let features = device.negotiate(
+        feature::INDIRECT_DESC
+            | feature::EVENT_IDX
+            | blk_feature::SIZE_MAX
+            | blk_feature::SEG_MAX
+            | blk_feature::RO
+            | blk_feature::BLK_SIZE
+            | blk_feature::FLUSH
+            | blk_feature::DISCARD,
+    )?;
+
+    let interrupts = device.enable_msix(1).is_ok();
+    let mode = match interrupts {
+        true => QueueMode::Interrupt {
+            vector: 0,
+            handler: handle_interrupt,
+            context: index,
+        },
+        false => QueueMode::Polling,
+    };
+    let queue = device.setup_queue(0, MAX_IN_FLIGHT as u16 * 4, mode)?;
+    let slots_phys = memory::allocate_frames(1).ok_or(BlkError::OutOfMemory)?;
+
+    let block_size = match features & blk_feature::BLK_SIZE {
+        0 => SECTOR_SIZE as u32,
+        _ => device.read_config::<u32>(config::BLK_SIZE),
+    };
+    let max_segments = match features & blk_feature::SEG_MAX {
+        0 => 1,
+        _ => device.read_config::<u32>(config::SEG_MAX).max(1) as usize,
+    };
+    let max_segment_len = match features & blk_feature::SIZE_MAX {
+        0 => u32::MAX,
+        _ => device
+            .read_config::<u32>(config::SIZE_MAX)
+            .max(SECTOR_SIZE as u32),
+    };
+    let discard = features & blk_feature::DISCARD != 0;
+    let max_discard_sectors = match discard {
+        true => device.read_config::<u32>(config::MAX_DISCARD_SECTORS),
+        false => 0,
+    };
+    let info = DiskInfo {
+        sectors: device.read_config_u64(config::CAPACITY),
+        block_size,
+        read_only: features & blk_feature::RO != 0,
+        flush: features & blk_feature::FLUSH != 0,
+        discard,
+        interrupts,
+    };
+    device.driver_ok();
+    Ok(Disk {
+        device,
+        queue,
+        info,
+        max_discard_sectors,
+        max_segments,
+        max_segment_len,
+        slots_phys,
+        slots: [SlotState::Free; MAX_IN_FLIGHT],
+    })
+}
+
+/// Brings up every virtio block device found on the PCI bus.
+pub fn init() {
+    let mut index = 0;
+    for pci in virtio::find(device_type::BLOCK) {
+        if index == MAX_DISKS {
+            log::warn!("virtio-blk: ignoring {} and beyond", pci.address);
+            break;
+        }
+        match probe(index, pci) {
+            Ok(disk) => {
+                log::info!("virtio-blk {}: {}", pci.address, disk.info);
+                *DISKS[index].lock() = Some(disk);
+                index += 1;
+            }
+            Err(err) => log::warn!("virtio-blk {}: {}", pci.address, err),
+        }
+    }
+}
+
+/// Handle to a virtio block device brought up by [`init`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtioBlk {
+    index: usize,
+}
+
+/// Every disk [`init`] found, in PCI order.
+pub fn disks() -> impl Iterator<Item = VirtioBlk> {
+    (0..MAX_DISKS)
+        .filter(|index| DISKS[*index].lock().is_some())
+        .map(|index| VirtioBlk { index })
+}
+
+/// An outstanding request, see [`VirtioBlk::submit`].
+#[must_use = "requests hold their slot until completed"]
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+    disk: VirtioBlk,
+    slot: usize,
+}
+
+impl VirtioBlk {
+    fn with_disk<R>(&self, f: impl FnOnce(&mut Disk) -> R) -> R {
+        f(DISKS[self.index]
+            .lock()
+            .as_mut()
+            .expect("virtio-blk disk vanished"))
+    }
+
+    pub fn info(&self) -> DiskInfo {
+        self.with_disk(|disk| disk.info)
+    }
+
+    pub fn pci_address(&self) -> crate::pci::PciAddress {
+        self.with_disk(|disk| disk.device.pci().address)
+    }
+
+    /// Largest number of data segments per request.
+    pub fn max_segments(&self) -> usize {
+        self.with_disk(|disk| disk.max_segments)
+    }
+
+    /// Largest read or write that fits one request, given how many
+    /// segments the device takes and how long each may be.
+    pub fn max_transfer(&self) -> usize {
+        self.with_disk(|disk| disk.max_transfer())
+    }
+
+    /// Queues `op` without waiting for it. Up to [`MAX_IN_FLIGHT`] requests
+    /// can be outstanding at once.
+    pub fn submit(&self, op: BlkOp) -> Result<Request, BlkError> {
+        let slot = self.with_disk(|disk| disk.submit(op))?;
+        Ok(Request { disk: *self, slot })
+    }
+
+    /// Takes the result of a finished request; returns it back if the
+    /// request is still running.
+    pub fn try_complete(&self, request: Request) -> Result<Result<u32, BlkError>, Request> {
+        assert_eq!(request.disk, *self, "request belongs to another disk");
+        // the interrupt handler drains too, but may not get to run
+        let (result, finished) = self.with_disk(|disk| {
+            let finished = disk.drain();
+            match disk.slots[request.slot] {
+                SlotState::Done(result) => {
+                    disk.slots[request.slot] = SlotState::Free;
+                    (Some(result), finished)
+                }
+                _ => (None, finished),
+            }
+        });
+        wake_finished(self.index, finished);
+        result.ok_or(request)
+    }
+
+    /// Blocks until `request` has finished and returns the number of bytes
+    /// the device wrote.
+    pub fn wait(&self, request: Request) -> Result<u32, BlkError> {
+        let mut request = Some(request);
+        block::wait_for(self.info().interrupts, None, || {
+            match self.try_complete(request.take()?) {
+                Ok(result) => Some(result),
+                Err(pending) => {
+                    request = Some(pending);
+                    None
+                }
+            }
+        })
+        .expect("waited without a timeout")
+    }
+
+    /// Resolves once `request` has finished.
+    pub fn complete(&self, request: Request) -> Completion {
+        Completion {
+            request: Some(request),
+        }
+    }
+
+    /// Submits `op` and waits for it.
+    pub fn run(&self, op: BlkOp) -> Result<u32, BlkError> {
+        let request = self.submit(op)?;
+        self.wait(request)
+    }
+
+    /// Reads whole sectors into `buf` through a bounce buffer.
+    pub fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlkError> {
+        let bounce = self.bounce(sector, buf.len())?;
+        sync::block_on(bounce.read(sector, buf, async |sector, phys, len| {
+            self.run(BlkOp::Read { sector, phys, len }).map(|_| ())
+        }))
+    }
+
+    /// Writes whole sectors from `buf` through a bounce buffer.
+    pub fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlkError> {
+        let mut bounce = self.bounce(sector, buf.len())?;
+        sync::block_on(bounce.write(sector, buf, async |sector, phys, len| {
+            self.run(BlkOp::Write { sector, phys, len }).map(|_| ())
+        }))
+    }
+
+    /// A bounce buffer for `len` bytes from `sector` on, if they are on
+    /// the disk.
+    fn bounce(&self, sector: u64, len: usize) -> Result<Bounce, BlkError> {
+        if !len.is_multiple_of(SECTOR_SIZE)
+            || sector + (len / SECTOR_SIZE) as u64 > self.info().sectors
+        {
+            return Err(BlkError::OutOfRange);
+        }
+        Bounce::new(len, self.max_transfer(), SECTOR_SIZE).ok_or(BlkError::OutOfMemory)
+    }
+
+    pub fn flush(&self) -> Result<(), BlkError> {
+        self.run(BlkOp::Flush).map(|_| ())
+    }
+
+    pub fn discard(&self, sector: u64, count: u32) -> Result<(), BlkError> {
+        self.run(BlkOp::Discard { sector, count }).map(|_| ())
+    }
+}
+
+/// Future returned by [`VirtioBlk::complete`].
+///
+/// Dropping it before it resolves waits for the request, so that its slot
+/// comes back and the device is done with the buffers before they are
+/// reused.
+pub struct Completion {
+    request: Option<Request>,
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        if let Some(request) = self.request.take() {
+            let disk = request.disk;
+            let _ = disk.wait(request);
+        }
+    }
+}
+
+impl Future for Completion {
+    type Output = Result<u32, BlkError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let request = self
+            .request
+            .take()
+            .expect("completion polled after it resolved");
+        let disk = request.disk;
+        let slot = request.slot;
+        let request = match disk.try_complete(request) {
+            Ok(result) => return Poll::Ready(result),
+            Err(request) => request,
+        };
+        if !disk.info().interrupts {
+            // nothing will wake us, so ask to be polled again
+            cx.waker().wake_by_ref();
+            self.request = Some(request);
+            return Poll::Pending;
+        }
+        WAKERS[disk.index][slot].register(cx.waker());
+        match disk.try_complete(request) {
+            Ok(result) => Poll::Ready(result),
+            Err(request) => {
+                self.request = Some(request);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl From<BlkError> for BlockError {
+    fn from(err: BlkError) -> BlockError {
+        match err {
+            BlkError::Io | BlkError::Virtio(_) => BlockError::Io,
+            BlkError::Unsupported => BlockError::Unsupported,
+            BlkError::ReadOnly => BlockError::ReadOnly,
+            BlkError::OutOfRange => BlockError::OutOfRange,
+            BlkError::Busy => BlockError::Io,
+            BlkError::TooLarge => BlockError::Unsupported,
+            BlkError::OutOfMemory => BlockError::OutOfMemory,
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info().sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.info().read_only
+    }
+
+    async fn read(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let bounce = Bounce::new(buf.len(), self.max_transfer(), SECTOR_SIZE)
+            .ok_or(BlockError::OutOfMemory)?;
+        bounce
+            .read(sector, buf, async |sector, phys, len| {
+                let request = self.submit(BlkOp::Read { sector, phys, len })?;
+                self.complete(request).await.map(|_| ())
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn write(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let mut bounce = Bounce::new(buf.len(), self.max_transfer(), SECTOR_SIZE)
+            .ok_or(BlockError::OutOfMemory)?;
+        bounce
+            .write(sector, buf, async |sector, phys, len| {
+                let request = self.submit(BlkOp::Write { sector, phys, len })?;
+                self.complete(request).await.map(|_| ())
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<(), BlockError> {
+        // without the feature there is no write cache to flush
+        if !self.info().flush {
+            return Ok(());
+        }
+        let request = self.submit(BlkOp::Flush)?;
+        self.complete(request).await?;
+        Ok(())
+    }
+}
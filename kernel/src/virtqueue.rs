@@ -0,0 +1,454 @@
+use core::fmt;
+use core::sync::atomic::{Ordering, fence};
+
+use crate::memory::{self, FRAME_SIZE};
+
+// A split virtqueue is three areas shared with the device:
+//
+// ```text
+//  descriptor table   16 bytes each: addr, len, flags, next
+//  available ring     flags, idx, ring[size] (chain heads), used_event
+//  used ring          flags, idx, ring[size] (head, written len), avail_event
+// ```
+//
+// The driver links descriptors into chains, publishes chain heads in the
+// available ring and bumps its idx; the device hands finished chains back
+// through the used ring. Free descriptors are kept on a list threaded
+// through their `next` fields, so a direct chain is simply the first n
+// entries of that list.
+
+/// Largest ring the driver sets up, even if the device offers more.
+pub const MAX_QUEUE_SIZE: u16 = 256;
+/// Segments that fit one indirect descriptor table.
+pub const MAX_INDIRECT: usize = 16;
+
+mod desc_flags {
+    pub const NEXT: u16 = 1 << 0;
+    pub const WRITE: u16 = 1 << 1;
+    pub const INDIRECT: u16 = 1 << 2;
+}
+
+/// Available ring flag asking the device not to interrupt.
+const AVAIL_NO_INTERRUPT: u16 = 1 << 0;
+/// Used ring flag asking the driver not to notify.
+const USED_NO_NOTIFY: u16 = 1 << 0;
+
+const DESCRIPTOR_SIZE: usize = 16;
+const USED_ELEMENT_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// One physically contiguous buffer of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub phys: u64,
+    pub len: u32,
+    /// Whether the device writes into the buffer rather than reading it.
+    pub device_writes: bool,
+}
+
+impl Segment {
+    /// A buffer the device reads from.
+    pub fn readable(phys: u64, len: u32) -> Segment {
+        Segment {
+            phys,
+            len,
+            device_writes: false,
+        }
+    }
+
+    /// A buffer the device writes to.
+    pub fn writable(phys: u64, len: u32) -> Segment {
+        Segment {
+            phys,
+            len,
+            device_writes: true,
+        }
+    }
+}
+
+/// A chain the device has finished with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Used {
+    /// Token passed to [`Virtqueue::push`].
+    pub token: usize,
+    /// Bytes the device wrote into the chain's writable segments.
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "virtqueue full")
+    }
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    /// Descriptor table, followed by the available and used rings.
+    phys: u64,
+    frames: usize,
+    desc: *mut Descriptor,
+    avail: *mut u16,
+    used: *mut u8,
+    used_offset: usize,
+    /// One table of [`MAX_INDIRECT`] descriptors per ring slot, if indirect
+    /// descriptors were negotiated.
+    indirect: Option<(u64, usize)>,
+    event_idx: bool,
+    notify: *mut u16,
+    free_head: u16,
+    num_free: u16,
+    /// Shadow of the available ring's idx.
+    avail_idx: u16,
+    /// Available idx at the last notification.
+    kicked_idx: u16,
+    last_used: u16,
+    tokens: [usize; MAX_QUEUE_SIZE as usize],
+    chain_len: [u16; MAX_QUEUE_SIZE as usize],
+}
+
+// The rings are DMA memory owned by the queue.
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// Allocates a ring of `size` descriptors for queue `index`.
+    ///
+    /// `notify` is the doorbell the queue index is written to. The rings are
+    /// freed on drop, so the device must be reset or the queue disabled
+    /// before the queue goes away.
+    pub fn new(
+        index: u16,
+        size: u16,
+        indirect: bool,
+        event_idx: bool,
+        notify: *mut u16,
+    ) -> Option<Virtqueue> {
+        assert!(size > 0 && size <= MAX_QUEUE_SIZE, "bad queue size {size}");
+        let slots = size as usize;
+        let avail_offset = slots * DESCRIPTOR_SIZE;
+        let avail_len = 2 * (3 + slots);
+        let used_offset = (avail_offset + avail_len).next_multiple_of(4);
+        let used_len = 6 + USED_ELEMENT_SIZE * slots;
+        let frames = (used_offset + used_len).div_ceil(FRAME_SIZE as usize);
+        let phys = memory::allocate_frames(frames)?;
+        let base = memory::phys_to_virt(phys);
+
+        let indirect = match indirect {
+            true => {
+                let len = slots * MAX_INDIRECT * DESCRIPTOR_SIZE;
+                let table_frames = len.div_ceil(FRAME_SIZE as usize);
+                match memory::allocate_frames(table_frames) {
+                    Some(table) => Some((table, table_frames)),
+                    None => {
+                        memory::free_frames(phys, frames);
+                        return None;
+                    }
+                }
+            }
+            false => None,
+        };
+
+        let queue = Virtqueue {
+            index,
+            size,
+            phys,
+            frames,
+            desc: base.cast(),
+            avail: unsafe { base.add(avail_offset) }.cast(),
+            used: unsafe { base.add(used_offset) },
+            used_offset,
+            indirect,
+            event_idx,
+            notify,
+            free_head: 0,
+            num_free: size,
+            avail_idx: 0,
+            kicked_idx: 0,
+            last_used: 0,
+            tokens: [0; MAX_QUEUE_SIZE as usize],
+            chain_len: [0; MAX_QUEUE_SIZE as usize],
+        };
+        for id in 0..size {
+            queue.write_desc(
+                id,
+                Descriptor {
+                    next: (id + 1) % size,
+                    ..Descriptor::default()
+                },
+            );
+        }
+        Some(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn desc_phys(&self) -> u64 {
+        self.phys
+    }
+
+    pub fn avail_phys(&self) -> u64 {
+        self.phys + (self.size as usize * DESCRIPTOR_SIZE) as u64
+    }
+
+    pub fn used_phys(&self) -> u64 {
+        self.phys + self.used_offset as u64
+    }
+
+    /// Descriptors not tied up in outstanding chains.
+    pub fn free_descriptors(&self) -> u16 {
+        self.num_free
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_free == self.size
+    }
+
+    fn read_desc(&self, id: u16) -> Descriptor {
+        unsafe { self.desc.add(id as usize).read_volatile() }
+    }
+
+    fn write_desc(&self, id: u16, desc: Descriptor) {
+        unsafe { self.desc.add(id as usize).write_volatile(desc) }
+    }
+
+    fn avail_field(&self, index: usize) -> *mut u16 {
+        unsafe { self.avail.add(index) }
+    }
+
+    fn used_u16(&self, offset: usize) -> u16 {
+        unsafe { self.used.add(offset).cast::<u16>().read_volatile() }
+    }
+
+    fn used_idx(&self) -> u16 {
+        self.used_u16(2)
+    }
+
+    /// Makes `segments` available to the device as one chain, with `token`
+    /// returned once the device is done with it.
+    ///
+    /// Chains of several segments go through an indirect table when that
+    /// was negotiated, so they take up a single ring slot. The device is
+    /// not told until [`kick`](Self::kick).
+    pub fn push(&mut self, segments: &[Segment], token: usize) -> Result<u16, QueueFull> {
+        assert!(!segments.is_empty(), "empty virtqueue chain");
+        let indirect = self
+            .indirect
+            .filter(|_| segments.len() > 1 && segments.len() <= MAX_INDIRECT);
+        let needed = match indirect {
+            Some(_) => 1,
+            None => segments.len(),
+        };
+        if needed > self.num_free as usize {
+            return Err(QueueFull);
+        }
+
+        let head = self.free_head;
+        match indirect {
+            Some((tables, _)) => {
+                let table_phys = tables + (head as usize * MAX_INDIRECT * DESCRIPTOR_SIZE) as u64;
+                let table: *mut Descriptor = memory::phys_to_virt(table_phys).cast();
+                for (i, segment) in segments.iter().enumerate() {
+                    let desc = chain_descriptor(segment, i + 1 < segments.len(), i as u16 + 1);
+                    unsafe { table.add(i).write_volatile(desc) };
+                }
+                let slot = self.read_desc(head);
+                self.write_desc(
+                    head,
+                    Descriptor {
+                        addr: table_phys,
+                        len: (segments.len() * DESCRIPTOR_SIZE) as u32,
+                        flags: desc_flags::INDIRECT,
+                        next: slot.next,
+                    },
+                );
+                self.free_head = slot.next;
+            }
+            None => {
+                let mut id = head;
+                for (i, segment) in segments.iter().enumerate() {
+                    let next = self.read_desc(id).next;
+                    self.write_desc(id, chain_descriptor(segment, i + 1 < segments.len(), next));
+                    id = next;
+                }
+                self.free_head = id;
+            }
+        }
+        self.num_free -= needed as u16;
+        self.tokens[head as usize] = token;
+        self.chain_len[head as usize] = needed as u16;
+
+        let slot = 2 + (self.avail_idx % self.size) as usize;
+        unsafe { self.avail_field(slot).write_volatile(head) };
+        // the ring entry has to be visible before the index that covers it
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe { self.avail_field(1).write_volatile(self.avail_idx) };
+        Ok(head)
+    }
+
+    /// Notifies the device of everything pushed since the last kick, unless
+    /// it suppressed notifications. Returns whether it was notified.
+    pub fn kick(&mut self) -> bool {
+        // pairs with the device publishing its suppression state
+        fence(Ordering::SeqCst);
+        let (old, new) = (self.kicked_idx, self.avail_idx);
+        self.kicked_idx = new;
+        let needed = match self.event_idx {
+            true => {
+                let avail_event = self.used_u16(4 + USED_ELEMENT_SIZE * self.size as usize);
+                need_event(avail_event, new, old)
+            }
+            false => self.used_u16(0) & USED_NO_NOTIFY == 0,
+        };
+        if needed {
+            unsafe { self.notify.write_volatile(self.index) };
+        }
+        needed
+    }
+
+    /// Whether the device has handed back chains not popped yet.
+    pub fn has_used(&self) -> bool {
+        self.used_idx() != self.last_used
+    }
+
+    /// Takes the next finished chain off the used ring and frees its
+    /// descriptors.
+    pub fn pop_used(&mut self) -> Option<Used> {
+        if !self.has_used() {
+            return None;
+        }
+        // read the element only after seeing the index that covers it
+        fence(Ordering::SeqCst);
+        let element = 4 + USED_ELEMENT_SIZE * (self.last_used % self.size) as usize;
+        let (id, len) = unsafe {
+            let element = self.used.add(element).cast::<u32>();
+            (element.read_volatile(), element.add(1).read_volatile())
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+        if self.event_idx {
+            // a no-op while interrupts are off, see disable_interrupts
+            self.set_used_event();
+        }
+
+        let head = id as u16;
+        assert!(head < self.size, "device returned bad descriptor {id}");
+        let mut last = head;
+        for _ in 1..self.chain_len[head as usize] {
+            last = self.read_desc(last).next;
+        }
+        let mut tail = self.read_desc(last);
+        tail.next = self.free_head;
+        self.write_desc(last, tail);
+        self.free_head = head;
+        self.num_free += self.chain_len[head as usize];
+        self.chain_len[head as usize] = 0;
+
+        Some(Used {
+            token: self.tokens[head as usize],
+            len,
+        })
+    }
+
+    /// Spins until the device hands back a chain.
+    pub fn pop_used_blocking(&mut self) -> Used {
+        loop {
+            if let Some(used) = self.pop_used() {
+                return used;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn interrupts_disabled(&self) -> bool {
+        let flags = unsafe { self.avail_field(0).read_volatile() };
+        flags & AVAIL_NO_INTERRUPT != 0
+    }
+
+    fn set_used_event(&self) {
+        if !self.interrupts_disabled() {
+            let slot = 2 + self.size as usize;
+            unsafe { self.avail_field(slot).write_volatile(self.last_used) };
+        }
+    }
+
+    /// Asks the device not to interrupt for finished chains, for polling.
+    ///
+    /// With event-idx the flag is ignored by the device, which then stops
+    /// interrupting because the used event index is no longer advanced.
+    pub fn disable_interrupts(&mut self) {
+        unsafe { self.avail_field(0).write_volatile(AVAIL_NO_INTERRUPT) };
+    }
+
+    /// Asks for an interrupt on the next finished chain.
+    ///
+    /// Returns whether chains were already waiting, which the caller has to
+    /// pop itself since no interrupt will announce them.
+    pub fn enable_interrupts(&mut self) -> bool {
+        unsafe { self.avail_field(0).write_volatile(0) };
+        if self.event_idx {
+            self.set_used_event();
+        }
+        fence(Ordering::SeqCst);
+        self.has_used()
+    }
+}
+
+fn chain_descriptor(segment: &Segment, has_next: bool, next: u16) -> Descriptor {
+    let mut flags = 0;
+    if segment.device_writes {
+        flags |= desc_flags::WRITE;
+    }
+    if has_next {
+        flags |= desc_flags::NEXT;
+    }
+    Descriptor {
+        addr: segment.phys,
+        len: segment.len,
+        flags,
+        next: if has_next { next } else { 0 },
+    }
+}
+
+/// Whether moving an index from `old` to `new` crossed `event`, the
+/// event-idx rule for both notifications and interrupts.
+pub fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        memory::free_frames(self.phys, self.frames);
+        if let Some((tables, frames)) = self.indirect {
+            memory::free_frames(tables, frames);
+        }
+    }
+}
+
+impl fmt::Debug for Virtqueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Virtqueue")
+            .field("index", &self.index)
+            .field("size", &self.size)
+            .field("free", &self.num_free)
+            .field("avail_idx", &self.avail_idx)
+            .field("last_used", &self.last_used)
+            .finish()
+    }
+}
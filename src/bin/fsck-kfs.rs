@@ -0,0 +1,42 @@
+use std::{env, fs, process};
+
+#[path = "../../kernel/src/kfs_layout.rs"]
+#[allow(dead_code)]
+mod kfs_layout;
+
+#[path = "../kfs.rs"]
+#[allow(dead_code)]
+mod kfs;
+
+// exit codes as e2fsck has them
+const ERRORS_LEFT: i32 = 4;
+const OPERATIONAL_ERROR: i32 = 8;
+
+/// Checks a kfs image without changing it: `fsck-kfs image`.
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let [image] = args.as_slice() else {
+        eprintln!("usage: fsck-kfs image");
+        process::exit(OPERATIONAL_ERROR);
+    };
+    let report = match fs::File::open(image).and_then(|file| kfs::check(&file)) {
+        Ok(report) => report,
+        Err(err) => {
+            eprintln!("fsck-kfs: {}: {}", image, err);
+            process::exit(OPERATIONAL_ERROR);
+        }
+    };
+    for note in &report.notes {
+        println!("{}", note);
+    }
+    for error in &report.errors {
+        println!("error: {}", error);
+    }
+    println!(
+        "{}: {}/{} files, {}/{} blocks",
+        report.label, report.files, report.inodes, report.used_blocks, report.blocks
+    );
+    if !report.is_clean() {
+        process::exit(ERRORS_LEFT);
+    }
+}
@@ -1,17 +1,196 @@
 use bootloader::DiskImageBuilder;
 use std::{
-    env,
-    path::PathBuf,
-    process::{self, Command},
+    env, fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    thread,
+    time::Duration,
 };
 
+#[path = "../cpio.rs"]
+mod cpio;
+
+#[path = "../../kernel/src/kfs_layout.rs"]
+#[allow(dead_code)]
+mod kfs_layout;
+
+#[path = "../kfs.rs"]
+#[allow(dead_code)]
+mod kfs;
+
+/// Size of the blank disk the block driver tests scribble on.
+const SCRATCH_DISK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Serial number the AHCI tests look for on their scratch disk.
+const AHCI_SCRATCH_SERIAL: &str = "KTSCRATCH";
+
+fn create_scratch_disk(path: &Path) {
+    fs::File::create(path)
+        .and_then(|file| file.set_len(SCRATCH_DISK_SIZE))
+        .expect("Failed to create scratch disk");
+}
+
+/// Formats an ext2 image with the files the ext2 tests expect, owned by
+/// 1000:1000. Needs `mkfs.ext2` from e2fsprogs; without it the tests that
+/// look for the disk are skipped.
+fn create_ext2_disk(path: &Path) -> bool {
+    let root = env::temp_dir().join("kernel_test_ext2_root");
+    let _ = fs::remove_dir_all(&root);
+    let populate = || -> std::io::Result<()> {
+        use std::os::unix::fs::{PermissionsExt, symlink};
+        fs::create_dir_all(root.join("dir/nested"))?;
+        fs::write(root.join("hello.txt"), "hello from ext2\n")?;
+        fs::write(root.join("dir/nested/deep.txt"), "deep\n")?;
+        // large enough to need the double indirect block with 1 KiB blocks
+        let big: Vec<u8> = (0..300 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+        fs::write(root.join("big.bin"), big)?;
+        symlink("dir/nested/deep.txt", root.join("short-link"))?;
+        // too long to fit in the inode, so it gets a block
+        symlink("./".repeat(40) + "hello.txt", root.join("long-link"))?;
+        fs::write(root.join("secret"), "")?;
+        fs::set_permissions(root.join("secret"), fs::Permissions::from_mode(0o600))
+    };
+    if let Err(err) = populate() {
+        eprintln!("Failed to populate ext2 test image: {}", err);
+        return false;
+    }
+    let status = Command::new("mkfs.ext2")
+        .args([
+            "-q",
+            "-F",
+            "-b",
+            "1024",
+            "-L",
+            "ktest",
+            "-E",
+            "root_owner=1000:1000",
+            "-d",
+        ])
+        .arg(&root)
+        .arg(path)
+        .arg("8M")
+        .status();
+    match status {
+        Ok(status) if status.success() => true,
+        _ => {
+            eprintln!("mkfs.ext2 failed, running without the ext2 test disk");
+            false
+        }
+    }
+}
+
+/// Size of the kfs disk, made by the same code as mkfs-kfs.
+const KFS_DISK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// The line the kfs crash writer logs once it is under way.
+const CRASH_WRITER_RUNNING: &str = "kfs crash writer running";
+
+/// Contents of the n-th file of the kfs tests.
+fn kfs_pattern(n: usize, len: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 7 + n * 13) % 251) as u8).collect()
+}
+
+/// Makes the kfs image the kfs tests expect. With `crash`, it also holds
+/// the marker that turns the tests into the crash writer.
+fn create_kfs_disk(path: &Path, crash: bool) {
+    let build = || -> std::io::Result<()> {
+        let mut builder = kfs::ImageBuilder::new(KFS_DISK_SIZE, "ktest-kfs")?;
+        builder.add_file("hello.txt", 0o644, b"hello from kfs\n")?;
+        builder.add_dir("dir", 0o755)?;
+        builder.add_dir("dir/nested", 0o755)?;
+        builder.add_file("dir/nested/deep.txt", 0o644, b"deep\n")?;
+        builder.add_file("big.bin", 0o644, &kfs_pattern(0, 300 * 1024))?;
+        builder.add_file("secret", 0o600, b"")?;
+        builder.add_symlink("short-link", "dir/nested/deep.txt")?;
+        // too long to fit in the inode, so it gets a block
+        builder.add_symlink("long-link", &("./".repeat(120) + "hello.txt"))?;
+        if crash {
+            builder.add_file("crash-test", 0o644, b"")?;
+        }
+        builder.write(&fs::File::create(path)?)
+    };
+    build().expect("Failed to create the kfs test disk");
+}
+
+/// Boots the tests with the kfs disk in crash mode and kills QEMU while
+/// the crash writer is in the middle of its work.
+fn run_crash_writer(mut qemu: Command) {
+    let mut child = qemu
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute QEMU");
+    let mut serial = BufReader::new(child.stdout.take().unwrap());
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match serial.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => {
+                eprintln!("QEMU stopped before the kfs crash writer started");
+                process::exit(1);
+            }
+            Ok(_) => {}
+        }
+        let text = String::from_utf8_lossy(&line);
+        print!("{}", text);
+        if text.contains(CRASH_WRITER_RUNNING) {
+            break;
+        }
+    }
+    // let it get some files written, then pull the plug
+    thread::sleep(Duration::from_secs(3));
+    let _ = child.kill();
+    let _ = child.wait();
+    println!("Killed QEMU in the middle of the kfs crash writer");
+}
+
+/// Checks the kfs disk left by the crash writer with fsck-kfs's checker.
+fn check_kfs_disk(path: &Path) {
+    let report = fs::File::open(path)
+        .and_then(|file| kfs::check(&file))
+        .expect("Failed to check the kfs test disk");
+    for note in &report.notes {
+        println!("kfs check: {}", note);
+    }
+    if !report.is_clean() {
+        for error in &report.errors {
+            eprintln!("kfs check: error: {}", error);
+        }
+        process::exit(1);
+    }
+    println!(
+        "kfs check: clean, {} files, {}/{} blocks",
+        report.files, report.used_blocks, report.blocks
+    );
+}
+
+/// The initramfs build.rs packs, plus the files the initramfs tests look
+/// for under `test/`.
+fn create_initramfs(path: &Path) {
+    let mut archive = cpio::CpioWriter::default();
+    archive
+        .add_tree(&Path::new(env!("CARGO_MANIFEST_DIR")).join("initramfs"), "")
+        .expect("Failed to pack the initramfs");
+    archive.add_dir("test", 0o755);
+    archive.add_file("test/hello.txt", 0o644, b"hello from the initramfs\n");
+    archive.add_symlink("test/link", "hello.txt");
+    // a file in a directory the archive doesn't list
+    archive.add_file("test/bin/run", 0o755, b"#!/bin/sh\n");
+    let large: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+    archive.add_file("test/large.bin", 0o600, &large);
+    fs::write(path, archive.finish()).expect("Failed to write the initramfs");
+}
+
 fn main() {
     let test_kernel_bin = env::var("KERNEL_TEST_BIN")
         .expect("Please set KERNEL_TEST_BIN to point to your raw test kernel binary");
 
-    let disk_builder = DiskImageBuilder::new(PathBuf::from(&test_kernel_bin));
+    let mut disk_builder = DiskImageBuilder::new(PathBuf::from(&test_kernel_bin));
 
     let temp_dir = env::temp_dir();
+    let initramfs_path = temp_dir.join("kernel_test_initramfs.cpio");
+    create_initramfs(&initramfs_path);
+    disk_builder.set_ramdisk(initramfs_path);
     let uefi_image_path = temp_dir.join("kernel_test_uefi.img");
 
     disk_builder
@@ -20,13 +199,77 @@ fn main() {
 
     println!("Created test image: {}", uefi_image_path.display());
 
+    // recreated on every run so tests always start from zeroes
+    let scratch_path = temp_dir.join("kernel_test_scratch.img");
+    create_scratch_disk(&scratch_path);
+
     let mut qemu = Command::new("qemu-system-x86_64");
 
+    // q35 puts the boot disk on the ICH9 AHCI controller instead of IDE
+    let q35 = env::var_os("KERNEL_TEST_Q35").is_some();
+    if q35 {
+        qemu.arg("-machine").arg("q35");
+    }
+
     qemu.arg("-serial").arg("stdio");
     qemu.arg("-drive")
         .arg(format!("format=raw,file={}", uefi_image_path.display()));
 
     qemu.arg("-bios").arg(ovmf_prebuilt::ovmf_pure_efi());
+    qemu.arg("-drive").arg(format!(
+        "if=virtio,format=raw,file={}",
+        scratch_path.display()
+    ));
+
+    // vdb, after the scratch disk
+    let ext2_path = temp_dir.join("kernel_test_ext2.img");
+    if create_ext2_disk(&ext2_path) {
+        qemu.arg("-drive")
+            .arg(format!("if=virtio,format=raw,file={}", ext2_path.display()));
+    }
+
+    // the next virtio disk; the tests find it by its label
+    let crash = env::var_os("KERNEL_TEST_CRASH").is_some();
+    let kfs_path = temp_dir.join("kernel_test_kfs.img");
+    create_kfs_disk(&kfs_path, crash);
+    qemu.arg("-drive")
+        .arg(format!("if=virtio,format=raw,file={}", kfs_path.display()));
+
+    let nvme_scratch_path = temp_dir.join("kernel_test_nvme_scratch.img");
+    create_scratch_disk(&nvme_scratch_path);
+    qemu.arg("-drive").arg(format!(
+        "if=none,id=nvme-scratch,format=raw,file={}",
+        nvme_scratch_path.display()
+    ));
+    qemu.arg("-device")
+        .arg("nvme,drive=nvme-scratch,serial=KTNVME");
+
+    if q35 {
+        let ahci_scratch_path = temp_dir.join("kernel_test_ahci_scratch.img");
+        create_scratch_disk(&ahci_scratch_path);
+        qemu.arg("-drive").arg(format!(
+            "if=none,id=ahci-scratch,format=raw,file={}",
+            ahci_scratch_path.display()
+        ));
+        qemu.arg("-device").arg(format!(
+            "ide-hd,drive=ahci-scratch,bus=ide.1,serial={}",
+            AHCI_SCRATCH_SERIAL
+        ));
+    }
+
+    // CI has no display; the kernel falls back to a serial-only console
+    if env::var_os("KERNEL_TEST_HEADLESS").is_some() {
+        qemu.arg("-display").arg("none");
+        qemu.arg("-vga").arg("none");
+    }
+
+    // a first boot dies mid-write; the second recovers and checks the files
+    if crash {
+        let mut writer = Command::new(qemu.get_program());
+        writer.args(qemu.get_args());
+        run_crash_writer(writer);
+        check_kfs_disk(&kfs_path);
+    }
 
     let exit_status = qemu.status().expect("Failed to execute QEMU");
     process::exit(exit_status.code().unwrap_or(-1));
@@ -0,0 +1,61 @@
+use std::{env, fs, path::Path, process};
+
+#[path = "../../kernel/src/kfs_layout.rs"]
+#[allow(dead_code)]
+mod kfs_layout;
+
+#[path = "../kfs.rs"]
+#[allow(dead_code)]
+mod kfs;
+
+fn usage() -> ! {
+    eprintln!("usage: mkfs-kfs [-L label] [-d dir] image size[K|M|G]");
+    process::exit(1);
+}
+
+/// Bytes in a size like `64M`.
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.as_bytes().last()? {
+        b'K' | b'k' => (&size[..size.len() - 1], 10),
+        b'M' | b'm' => (&size[..size.len() - 1], 20),
+        b'G' | b'g' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let (mut label, mut dir) = (String::new(), None);
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-L" => label = args.next().unwrap_or_else(|| usage()),
+            "-d" => dir = Some(args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with('-') => usage(),
+            _ => positional.push(arg),
+        }
+    }
+    let [image, size] = positional.as_slice() else {
+        usage();
+    };
+    let size = parse_size(size).unwrap_or_else(|| usage());
+
+    let build = || -> std::io::Result<()> {
+        let mut builder = kfs::ImageBuilder::new(size, &label)?;
+        if let Some(dir) = &dir {
+            builder.add_tree(Path::new(dir), "")?;
+        }
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(image)?;
+        builder.write(&file)
+    };
+    if let Err(err) = build() {
+        eprintln!("mkfs-kfs: {}: {}", image, err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,104 @@
+//! Writes cpio archives in the "new ASCII" (newc) format, which the kernel
+//! unpacks its initramfs from. Shared by build.rs and the test runner.
+
+use std::{
+    fs, io,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::Path,
+};
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Default)]
+pub struct CpioWriter {
+    out: Vec<u8>,
+    members: u32,
+}
+
+impl CpioWriter {
+    fn member(&mut self, name: &str, mode: u32, links: u32, data: &[u8]) {
+        self.members += 1;
+        let ino = self.members;
+        let fields = [
+            ino,
+            mode,
+            0, // uid
+            0, // gid
+            links,
+            0, // mtime, left out so the image is reproducible
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+            name.len() as u32 + 1,
+            0, // check
+        ];
+        self.out.extend_from_slice(b"070701");
+        for field in fields {
+            self.out
+                .extend_from_slice(format!("{:08X}", field).as_bytes());
+        }
+        self.out.extend_from_slice(name.as_bytes());
+        self.out.push(0);
+        self.pad();
+        self.out.extend_from_slice(data);
+        self.pad();
+    }
+
+    fn pad(&mut self) {
+        self.out.resize(self.out.len().next_multiple_of(4), 0);
+    }
+
+    pub fn add_dir(&mut self, name: &str, permissions: u32) {
+        self.member(name, S_IFDIR | permissions & 0o7777, 2, &[]);
+    }
+
+    pub fn add_file(&mut self, name: &str, permissions: u32, data: &[u8]) {
+        self.member(name, S_IFREG | permissions & 0o7777, 1, data);
+    }
+
+    pub fn add_symlink(&mut self, name: &str, target: &str) {
+        self.member(name, S_IFLNK | 0o777, 1, target.as_bytes());
+    }
+
+    /// Adds everything below `dir` under `prefix`, in name order.
+    pub fn add_tree(&mut self, dir: &Path, prefix: &str) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_str().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "file name is not UTF-8")
+            })?;
+            let name = match prefix {
+                "" => file_name.to_string(),
+                _ => format!("{}/{}", prefix, file_name),
+            };
+            let path = entry.path();
+            let metadata = fs::symlink_metadata(&path)?;
+            let permissions = metadata.permissions().mode();
+            if metadata.is_dir() {
+                self.add_dir(&name, permissions);
+                self.add_tree(&path, &name)?;
+            } else if metadata.is_symlink() {
+                let target = fs::read_link(&path)?;
+                let target = target.to_str().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "link target is not UTF-8")
+                })?;
+                self.add_symlink(&name, target);
+            } else if metadata.is_file() {
+                self.add_file(&name, metadata.mode(), &fs::read(&path)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the archive and returns its bytes.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.member("TRAILER!!!", 0, 1, &[]);
+        self.out
+    }
+}
@@ -0,0 +1,794 @@
+//! Builds and checks kfs volumes on the host, for mkfs-kfs, fsck-kfs and
+//! the test runner. The format is kernel/src/kfs_layout.rs, which has to be
+//! included next to this module as `kfs_layout`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    os::unix::fs::{FileExt, MetadataExt, PermissionsExt},
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::kfs_layout::{
+    self as layout, BLOCK_SIZE, DirRecord, EXTENT_SIZE, EXTENTS_PER_BLOCK, Extent, INLINE_EXTENTS,
+    INLINE_SYMLINK, INODE_SIZE, JournalHeader, MAX_EXTENTS, PAYLOAD, ROOT_INO, RawInode,
+    Superblock, entry_kind, kind, mode,
+};
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+enum Contents {
+    File(Vec<u8>),
+    /// Entries by name, and the parent directory.
+    Directory(BTreeMap<String, u64>, u64),
+    Symlink(String),
+}
+
+struct Node {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    modified: u64,
+    contents: Contents,
+}
+
+/// A volume put together in memory and written out in one go, every file
+/// in one piece.
+pub struct ImageBuilder {
+    superblock: Superblock,
+    /// Inode n is `nodes[n - 1]`.
+    nodes: Vec<Node>,
+}
+
+impl ImageBuilder {
+    /// An empty volume of `size` bytes.
+    pub fn new(size: u64, label: &str) -> io::Result<ImageBuilder> {
+        let blocks = size / BLOCK_SIZE as u64;
+        let created = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let superblock = Superblock::new(
+            blocks,
+            Superblock::default_inode_count(blocks),
+            Superblock::default_journal_blocks(blocks),
+            label,
+            created,
+        )
+        .ok_or_else(|| invalid(format!("no kfs volume fits in {} bytes", size)))?;
+        let root = Node {
+            mode: mode::DIRECTORY | 0o755,
+            uid: 0,
+            gid: 0,
+            modified: created,
+            contents: Contents::Directory(BTreeMap::new(), ROOT_INO),
+        };
+        Ok(ImageBuilder {
+            superblock,
+            nodes: vec![root],
+        })
+    }
+
+    fn add(&mut self, path: &str, mode: u16, contents: Contents) -> io::Result<u64> {
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name.len() > layout::MAX_NAME || name == "." || name == ".." {
+            return Err(invalid(format!("bad name {:?}", path)));
+        }
+        let mut parent = ROOT_INO;
+        for component in parent_path.split('/').filter(|c| !c.is_empty()) {
+            parent = match &self.nodes[parent as usize - 1].contents {
+                Contents::Directory(entries, _) => *entries
+                    .get(component)
+                    .ok_or_else(|| invalid(format!("no directory for {:?}", path)))?,
+                _ => return Err(invalid(format!("no directory for {:?}", path))),
+            };
+        }
+        let ino = self.nodes.len() as u64 + 1;
+        let Contents::Directory(entries, _) = &mut self.nodes[parent as usize - 1].contents else {
+            return Err(invalid(format!("no directory for {:?}", path)));
+        };
+        if entries.insert(name.into(), ino).is_some() {
+            return Err(invalid(format!("{:?} added twice", path)));
+        }
+        let contents = match contents {
+            Contents::Directory(entries, _) => Contents::Directory(entries, parent),
+            other => other,
+        };
+        self.nodes.push(Node {
+            mode,
+            uid: 0,
+            gid: 0,
+            modified: self.superblock.created,
+            contents,
+        });
+        Ok(ino)
+    }
+
+    pub fn add_dir(&mut self, path: &str, permissions: u32) -> io::Result<()> {
+        let contents = Contents::Directory(BTreeMap::new(), 0);
+        self.add(
+            path,
+            mode::DIRECTORY | permissions as u16 & 0o7777,
+            contents,
+        )?;
+        Ok(())
+    }
+
+    pub fn add_file(&mut self, path: &str, permissions: u32, data: &[u8]) -> io::Result<()> {
+        let contents = Contents::File(data.to_vec());
+        self.add(path, mode::REGULAR | permissions as u16 & 0o7777, contents)?;
+        Ok(())
+    }
+
+    pub fn add_symlink(&mut self, path: &str, target: &str) -> io::Result<()> {
+        if target.is_empty() || target.len() > BLOCK_SIZE {
+            return Err(invalid(format!("bad symlink target for {:?}", path)));
+        }
+        self.add(
+            path,
+            mode::SYMLINK | 0o777,
+            Contents::Symlink(target.into()),
+        )?;
+        Ok(())
+    }
+
+    /// Adds everything under `dir` to the directory `prefix` ("" for the
+    /// root), with its permissions, owners and modification times.
+    pub fn add_tree(&mut self, dir: &Path, prefix: &str) -> io::Result<()> {
+        let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|name| invalid(format!("{:?} is not UTF-8", name)))?;
+            let path = match prefix {
+                "" => name,
+                _ => format!("{}/{}", prefix, name),
+            };
+            let metadata = fs::symlink_metadata(entry.path())?;
+            let permissions = metadata.permissions().mode();
+            let file_type = metadata.file_type();
+            if file_type.is_dir() {
+                self.add_dir(&path, permissions)?;
+                self.add_tree(&entry.path(), &path)?;
+            } else if file_type.is_symlink() {
+                let target = fs::read_link(entry.path())?;
+                let target = target
+                    .to_str()
+                    .ok_or_else(|| invalid(format!("{:?} is not UTF-8", target)))?;
+                self.add_symlink(&path, target)?;
+            } else if file_type.is_file() {
+                self.add_file(&path, permissions, &fs::read(entry.path())?)?;
+            } else {
+                eprintln!("skipping {}, not a file, directory or symlink", path);
+                continue;
+            }
+            let node = self.nodes.last_mut().unwrap();
+            node.uid = metadata.uid();
+            node.gid = metadata.gid();
+            node.modified = metadata.mtime().max(0) as u64;
+        }
+        Ok(())
+    }
+
+    /// The directory blocks of `dir`, its entries packed in order.
+    fn directory_blocks(
+        &self,
+        ino: u64,
+        entries: &BTreeMap<String, u64>,
+        parent: u64,
+    ) -> Vec<Vec<u8>> {
+        let mut blocks = Vec::new();
+        let mut block = vec![0u8; BLOCK_SIZE];
+        layout::init_dir_block(&mut block, ino, parent);
+        // the last record, which runs to the end of the block, and the
+        // length it needs
+        let (mut last, mut used) = (layout::entry_len(1), layout::entry_len(2));
+        for (name, child) in entries {
+            let len = layout::entry_len(name.len());
+            let kind = entry_kind::of_mode(self.nodes[*child as usize - 1].mode);
+            let name = name.as_bytes();
+            if last + used + len > PAYLOAD {
+                blocks.push(block);
+                block = vec![0u8; BLOCK_SIZE];
+                layout::put_dir_record(&mut block, 0, *child, PAYLOAD, kind, name);
+                (last, used) = (0, len);
+                continue;
+            }
+            layout::set_dir_record_len(&mut block, last, used);
+            let rest = PAYLOAD - last - used;
+            layout::put_dir_record(&mut block, last + used, *child, rest, kind, name);
+            (last, used) = (last + used, len);
+        }
+        blocks.push(block);
+        blocks
+    }
+
+    /// Writes the volume to `file`, which it takes up all of.
+    pub fn write(&self, file: &fs::File) -> io::Result<()> {
+        let superblock = &self.superblock;
+        if self.nodes.len() as u64 > superblock.inode_count {
+            return Err(invalid(format!(
+                "{} files don't fit in {} inodes",
+                self.nodes.len(),
+                superblock.inode_count
+            )));
+        }
+        let mut next = superblock.data_start;
+        let mut data: Vec<(u64, Vec<u8>)> = Vec::new();
+        let mut inodes = Vec::with_capacity(self.nodes.len());
+        for (n, node) in self.nodes.iter().enumerate() {
+            let ino = n as u64 + 1;
+            let mut inode = RawInode {
+                mode: node.mode,
+                links: 1,
+                uid: node.uid,
+                gid: node.gid,
+                accessed: node.modified,
+                modified: node.modified,
+                changed: node.modified,
+                ..RawInode::EMPTY
+            };
+            let blocks = match &node.contents {
+                Contents::File(contents) => {
+                    inode.size = contents.len() as u64;
+                    contents
+                        .chunks(BLOCK_SIZE)
+                        .map(|chunk| {
+                            let mut block = vec![0u8; BLOCK_SIZE];
+                            block[..chunk.len()].copy_from_slice(chunk);
+                            block
+                        })
+                        .collect()
+                }
+                Contents::Directory(entries, parent) => {
+                    let subdirectories = entries
+                        .values()
+                        .filter(|child| {
+                            self.nodes[**child as usize - 1].mode & mode::TYPE_MASK
+                                == mode::DIRECTORY
+                        })
+                        .count();
+                    inode.links = 2 + subdirectories as u16;
+                    let mut blocks = self.directory_blocks(ino, entries, *parent);
+                    for (n, block) in blocks.iter_mut().enumerate() {
+                        layout::seal(block, next + n as u64, kind::DIRECTORY);
+                    }
+                    inode.size = (blocks.len() * BLOCK_SIZE) as u64;
+                    blocks
+                }
+                Contents::Symlink(target) if target.len() <= INLINE_SYMLINK => {
+                    inode.size = target.len() as u64;
+                    inode.slots[..target.len()].copy_from_slice(target.as_bytes());
+                    Vec::new()
+                }
+                Contents::Symlink(target) => {
+                    inode.size = target.len() as u64;
+                    let mut block = vec![0u8; BLOCK_SIZE];
+                    block[..target.len()].copy_from_slice(target.as_bytes());
+                    vec![block]
+                }
+            };
+            let count = blocks.len() as u64;
+            if next + count > superblock.block_count {
+                return Err(invalid("the files don't fit on the volume".into()));
+            }
+            if count > 0 {
+                let extent = Extent {
+                    logical: 0,
+                    length: count as u32,
+                    physical: next,
+                };
+                extent.encode(&mut inode.slots);
+                inode.extent_count = 1;
+                inode.blocks = count;
+            }
+            for block in blocks {
+                data.push((next, block));
+                next += 1;
+            }
+            inodes.push(inode);
+        }
+
+        file.set_len(0)?;
+        file.set_len(superblock.block_count * BLOCK_SIZE as u64)?;
+        let put = |number: u64, block: &[u8]| file.write_all_at(block, number * BLOCK_SIZE as u64);
+        let mut block = vec![0u8; BLOCK_SIZE];
+        superblock.encode(&mut block);
+        put(0, &block)?;
+        JournalHeader { sequence: 1 }.encode(&mut block);
+        put(superblock.journal_start, &block)?;
+
+        let bitmap = |start: u64, blocks: u64, used: u64| -> io::Result<()> {
+            for n in 0..blocks {
+                let mut block = vec![0u8; BLOCK_SIZE];
+                let first = n * layout::BITS_PER_BLOCK;
+                for index in first..used.min(first + layout::BITS_PER_BLOCK) {
+                    layout::set_bit(&mut block, (index - first) as usize, true);
+                }
+                layout::seal(&mut block, start + n, kind::BITMAP);
+                put(start + n, &block)?;
+            }
+            Ok(())
+        };
+        bitmap(
+            superblock.block_bitmap,
+            superblock.block_bitmap_blocks(),
+            next,
+        )?;
+        let used_inodes = inodes.len() as u64;
+        bitmap(
+            superblock.inode_bitmap,
+            superblock.inode_bitmap_blocks(),
+            used_inodes,
+        )?;
+        for n in 0..superblock.inode_table_blocks() {
+            block.fill(0);
+            let per_block = layout::INODES_PER_BLOCK as usize;
+            for (slot, inode) in inodes
+                .iter()
+                .skip(n as usize * per_block)
+                .take(per_block)
+                .enumerate()
+            {
+                inode.encode(&mut block[slot * INODE_SIZE..]);
+            }
+            layout::seal(&mut block, superblock.inode_table + n, kind::INODES);
+            put(superblock.inode_table + n, &block)?;
+        }
+        for (number, block) in &data {
+            put(*number, block)?;
+        }
+        file.sync_all()
+    }
+}
+
+/// What a check of a volume found.
+#[derive(Default)]
+pub struct Report {
+    pub errors: Vec<String>,
+    /// Things worth knowing that aren't wrong, like a journal waiting to be
+    /// replayed.
+    pub notes: Vec<String>,
+    pub label: String,
+    pub files: u64,
+    pub inodes: u64,
+    pub used_blocks: u64,
+    pub blocks: u64,
+}
+
+impl Report {
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// A volume being checked, read as the kernel would see it after
+/// replaying the journal.
+struct Volume<'a> {
+    file: &'a fs::File,
+    superblock: Superblock,
+    replayed: HashMap<u64, Vec<u8>>,
+    report: Report,
+}
+
+impl Volume<'_> {
+    fn raw_block(&self, number: u64) -> io::Result<Vec<u8>> {
+        let mut block = vec![0u8; BLOCK_SIZE];
+        self.file
+            .read_exact_at(&mut block, number * BLOCK_SIZE as u64)?;
+        Ok(block)
+    }
+
+    fn block(&self, number: u64) -> io::Result<Vec<u8>> {
+        match self.replayed.get(&number) {
+            Some(block) => Ok(block.clone()),
+            None => self.raw_block(number),
+        }
+    }
+
+    fn error(&mut self, message: String) {
+        self.report.errors.push(message);
+    }
+
+    /// Metadata block `number`, noting an error if it isn't intact.
+    fn metadata(&mut self, number: u64, kind: u32, what: &str) -> io::Result<Vec<u8>> {
+        let block = self.block(number)?;
+        if !layout::verify(&block, number, kind) {
+            self.error(format!("{} block {} fails its checksum", what, number));
+        }
+        Ok(block)
+    }
+
+    /// Loads the transaction the journal holds, if it committed.
+    fn load_journal(&mut self) -> io::Result<()> {
+        let start = self.superblock.journal_start;
+        let Some(header) = JournalHeader::decode(&self.raw_block(start)?) else {
+            self.error("journal header is corrupt".into());
+            return Ok(());
+        };
+        let descriptor = self.raw_block(start + 1)?;
+        if !layout::verify(&descriptor, start + 1, kind::DESCRIPTOR) {
+            return Ok(());
+        }
+        let Some((sequence, count)) = layout::decode_descriptor(&descriptor) else {
+            return Ok(());
+        };
+        if sequence != header.sequence || count > self.superblock.journal_capacity() {
+            return Ok(());
+        }
+        let mut checksum = layout::crc32c(0, &descriptor);
+        let mut logged = HashMap::new();
+        for n in 0..count {
+            let target = layout::descriptor_target(&descriptor, n);
+            if target < self.superblock.block_bitmap || target >= self.superblock.block_count {
+                return Ok(());
+            }
+            let block = self.raw_block(start + 2 + n as u64)?;
+            checksum = layout::crc32c(checksum, &block);
+            logged.insert(target, block);
+        }
+        let commit_at = start + 2 + count as u64;
+        let commit = self.raw_block(commit_at)?;
+        if layout::verify(&commit, commit_at, kind::COMMIT)
+            && layout::decode_commit(&commit) == (sequence, checksum)
+        {
+            self.report.notes.push(format!(
+                "journal holds transaction {} of {} blocks, replayed at the next mount",
+                sequence, count
+            ));
+            self.replayed = logged;
+        }
+        Ok(())
+    }
+
+    fn bit(&self, start: u64, index: u64) -> io::Result<bool> {
+        let (number, bit) = layout::bit_location(start, index);
+        Ok(layout::test_bit(&self.block(number)?, bit))
+    }
+
+    fn inode(&self, ino: u64) -> io::Result<Option<RawInode>> {
+        let Some((number, offset)) = self.superblock.inode_location(ino) else {
+            return Ok(None);
+        };
+        let block = self.block(number)?;
+        Ok(Some(RawInode::decode(&block[offset..offset + INODE_SIZE])))
+    }
+
+    /// The extents of inode `ino`, and its extent blocks.
+    fn extents(&mut self, ino: u64, inode: &RawInode) -> io::Result<(Vec<Extent>, Vec<u64>)> {
+        let count = inode.extent_count as usize;
+        let slot = |n: usize| Extent::decode(&inode.slots[n * EXTENT_SIZE..]);
+        if inode.is_inline_symlink() {
+            return Ok((Vec::new(), Vec::new()));
+        }
+        if inode.flags & RawInode::EXTENT_BLOCKS == 0 {
+            if count > INLINE_EXTENTS {
+                self.error(format!("inode {} has {} extents in the inode", ino, count));
+                return Ok((Vec::new(), Vec::new()));
+            }
+            return Ok(((0..count).map(slot).collect(), Vec::new()));
+        }
+        if count > MAX_EXTENTS {
+            self.error(format!("inode {} has {} extents", ino, count));
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let (mut extents, mut leaves) = (Vec::new(), Vec::new());
+        for n in 0..inode.slot_count() {
+            let pointer = slot(n);
+            if pointer.physical >= self.superblock.block_count {
+                self.error(format!("inode {} has an extent block past the end", ino));
+                continue;
+            }
+            let block = self.metadata(pointer.physical, kind::EXTENTS, "extent")?;
+            leaves.push(pointer.physical);
+            let held = (pointer.length as usize).min(EXTENTS_PER_BLOCK);
+            extents.extend((0..held).map(|e| Extent::decode(&block[e * EXTENT_SIZE..])));
+        }
+        if extents.len() != count {
+            self.error(format!(
+                "inode {} has {} extents but says {}",
+                ino,
+                extents.len(),
+                count
+            ));
+        }
+        Ok((extents, leaves))
+    }
+}
+
+/// What the walk of the tree has found so far.
+#[derive(Default)]
+struct Walk {
+    /// Blocks in use, by the inode using them.
+    owners: HashMap<u64, u64>,
+    /// Names each inode was found under.
+    references: HashMap<u64, u32>,
+    subdirectories: HashMap<u64, u32>,
+    /// Inodes found, whether checked yet or not.
+    queued: HashSet<u64>,
+    /// Inodes to check, with the directory they were found in.
+    queue: Vec<(u64, u64)>,
+    /// Inodes in use with no links, which the next mount frees.
+    orphans: HashSet<u64>,
+}
+
+impl Volume<'_> {
+    fn claim(&mut self, walk: &mut Walk, block: u64, ino: u64) {
+        if let Some(other) = walk.owners.insert(block, ino) {
+            self.error(format!(
+                "block {} belongs to inodes {} and {}",
+                block, other, ino
+            ));
+        }
+    }
+
+    /// Checks inode `ino`, found in directory `parent`, and queues what it
+    /// holds if it is a directory.
+    fn check_inode(&mut self, walk: &mut Walk, ino: u64, parent: u64) -> io::Result<()> {
+        let Some(inode) = self.inode(ino)? else {
+            self.error(format!("inode {} is out of range", ino));
+            return Ok(());
+        };
+        if !self.bit(self.superblock.inode_bitmap, ino - 1)? {
+            self.error(format!("inode {} is in use but marked free", ino));
+        }
+        let (extents, leaves) = self.extents(ino, &inode)?;
+        for leaf in &leaves {
+            self.claim(walk, *leaf, ino);
+        }
+        let mut claimed = leaves.len() as u64;
+        let mut end = 0;
+        for extent in &extents {
+            if extent.logical < end || extent.length == 0 {
+                self.error(format!("inode {} has overlapping or empty extents", ino));
+            }
+            end = extent.end();
+            let physical_end = extent.physical + extent.length as u64;
+            if extent.physical < self.superblock.data_start
+                || physical_end > self.superblock.block_count
+            {
+                self.error(format!("inode {} has an extent outside the data area", ino));
+                continue;
+            }
+            for block in extent.physical..physical_end {
+                self.claim(walk, block, ino);
+            }
+            claimed += extent.length as u64;
+        }
+        if claimed != inode.blocks {
+            self.error(format!(
+                "inode {} holds {} blocks but says {}",
+                ino, claimed, inode.blocks
+            ));
+        }
+        let blocks = inode.size.div_ceil(BLOCK_SIZE as u64);
+        if end > blocks {
+            self.error(format!("inode {} has blocks past its size", ino));
+        }
+        if !inode.is_directory() {
+            return Ok(());
+        }
+
+        let mapped: u64 = extents.iter().map(|extent| extent.length as u64).sum();
+        if !inode.size.is_multiple_of(BLOCK_SIZE as u64) || mapped != blocks {
+            self.error(format!("directory {} has holes or a ragged size", ino));
+            return Ok(());
+        }
+        walk.subdirectories.insert(ino, 0);
+        for extent in &extents {
+            for n in 0..extent.length as u64 {
+                let number = extent.physical + n;
+                let block = self.metadata(number, kind::DIRECTORY, "directory")?;
+                let mut offset = 0;
+                let mut position = 0;
+                while offset < PAYLOAD {
+                    let Some(record) = layout::dir_record(&block, offset) else {
+                        self.error(format!(
+                            "directory {} has a broken record in block {}",
+                            ino, number
+                        ));
+                        break;
+                    };
+                    let dots = match (extent.logical + n, position) {
+                        (0, 0) => Some((&b"."[..], ino)),
+                        (0, 1) => Some((&b".."[..], parent)),
+                        _ => None,
+                    };
+                    match dots {
+                        Some((name, target)) if record.name != name || record.ino != target => {
+                            self.error(format!(
+                                "directory {} has a bad {:?} entry",
+                                ino,
+                                String::from_utf8_lossy(name)
+                            ));
+                        }
+                        Some(_) => {}
+                        None if record.ino == 0 => {}
+                        None => self.check_entry(walk, ino, &record)?,
+                    }
+                    offset += record.len;
+                    position += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a name in directory `dir` and queues the inode it names.
+    fn check_entry(&mut self, walk: &mut Walk, dir: u64, record: &DirRecord) -> io::Result<()> {
+        let name = String::from_utf8_lossy(record.name);
+        if record.name.is_empty() || record.name == b"." || record.name == b".." {
+            self.error(format!("directory {} has a bad name {:?}", dir, name));
+        }
+        let Some(child) = self.inode(record.ino)? else {
+            self.error(format!(
+                "{:?} in directory {} names inode {}, which is out of range",
+                name, dir, record.ino
+            ));
+            return Ok(());
+        };
+        if child.links == 0 || child.mode == 0 {
+            self.error(format!(
+                "{:?} in directory {} names free inode {}",
+                name, dir, record.ino
+            ));
+            return Ok(());
+        }
+        if entry_kind::of_mode(child.mode) != record.kind {
+            self.error(format!(
+                "{:?} in directory {} has the wrong type",
+                name, dir
+            ));
+        }
+        *walk.references.entry(record.ino).or_default() += 1;
+        let first = walk.queued.insert(record.ino);
+        if child.is_directory() {
+            *walk.subdirectories.get_mut(&dir).unwrap() += 1;
+            if !first {
+                self.error(format!("directory {} has more than one name", record.ino));
+                return Ok(());
+            }
+        }
+        if first {
+            walk.queue.push((record.ino, dir));
+        }
+        Ok(())
+    }
+}
+
+/// Checks the kfs volume in `file` without changing it.
+pub fn check(file: &fs::File) -> io::Result<Report> {
+    let mut block = vec![0u8; BLOCK_SIZE];
+    file.read_exact_at(&mut block, 0)?;
+    let superblock =
+        Superblock::decode(&block).ok_or_else(|| invalid("no kfs superblock".into()))?;
+    if file.metadata()?.len() < superblock.block_count * BLOCK_SIZE as u64 {
+        return Err(invalid("the volume is bigger than the image".into()));
+    }
+    let mut volume = Volume {
+        file,
+        superblock,
+        replayed: HashMap::new(),
+        report: Report {
+            label: superblock.label().into(),
+            inodes: superblock.inode_count,
+            blocks: superblock.block_count,
+            ..Report::default()
+        },
+    };
+    volume.load_journal()?;
+    for n in 0..superblock.block_bitmap_blocks() {
+        volume.metadata(superblock.block_bitmap + n, kind::BITMAP, "block bitmap")?;
+    }
+    for n in 0..superblock.inode_bitmap_blocks() {
+        volume.metadata(superblock.inode_bitmap + n, kind::BITMAP, "inode bitmap")?;
+    }
+    for n in 0..superblock.inode_table_blocks() {
+        volume.metadata(superblock.inode_table + n, kind::INODES, "inode table")?;
+    }
+
+    let mut walk = Walk::default();
+    walk.queued.insert(ROOT_INO);
+    walk.queue.push((ROOT_INO, ROOT_INO));
+    walk.references.insert(ROOT_INO, 1);
+    while let Some((ino, parent)) = walk.queue.pop() {
+        volume.check_inode(&mut walk, ino, parent)?;
+    }
+    // inodes still open when their last link went stay allocated until
+    // the next mount frees them
+    for ino in 1..=superblock.inode_count {
+        if !volume.bit(superblock.inode_bitmap, ino - 1)? || walk.queued.contains(&ino) {
+            continue;
+        }
+        let inode = volume.inode(ino)?.unwrap();
+        if inode.links != 0 || inode.mode == 0 {
+            continue;
+        }
+        let (extents, leaves) = volume.extents(ino, &inode)?;
+        for block in extents
+            .iter()
+            .flat_map(|extent| extent.physical..extent.physical + extent.length as u64)
+            .chain(leaves)
+        {
+            volume.claim(&mut walk, block, ino);
+        }
+        walk.orphans.insert(ino);
+    }
+    if !walk.orphans.is_empty() {
+        volume.report.notes.push(format!(
+            "{} unlinked inodes were still open, freed at the next mount",
+            walk.orphans.len()
+        ));
+    }
+    let Walk {
+        owners,
+        references,
+        subdirectories,
+        queued,
+        orphans,
+        ..
+    } = walk;
+    let mut reachable: Vec<u64> = queued.into_iter().collect();
+    reachable.sort();
+    for ino in &reachable {
+        let inode = volume.inode(*ino)?.unwrap();
+        let expected = match inode.is_directory() {
+            true => 2 + subdirectories.get(ino).copied().unwrap_or(0),
+            false => references.get(ino).copied().unwrap_or(0),
+        };
+        if inode.links as u32 != expected {
+            volume.error(format!(
+                "inode {} has {} links but {} names",
+                ino, inode.links, expected
+            ));
+        }
+    }
+    volume.report.files = reachable.len() as u64;
+
+    let reachable: HashSet<u64> = reachable.into_iter().collect();
+    let mut marked_free = Vec::new();
+    for ino in 1..=superblock.inode_count {
+        if volume.bit(superblock.inode_bitmap, ino - 1)?
+            && !reachable.contains(&ino)
+            && !orphans.contains(&ino)
+        {
+            marked_free.push(ino);
+        }
+    }
+    if !marked_free.is_empty() {
+        volume.error(format!(
+            "{} inodes marked in use are not in the tree, the first {}",
+            marked_free.len(),
+            marked_free[0]
+        ));
+    }
+    let (mut leaked, mut unmarked) = (Vec::new(), Vec::new());
+    for number in 0..superblock.block_count {
+        let used = number < superblock.data_start || owners.contains_key(&number);
+        match (used, volume.bit(superblock.block_bitmap, number)?) {
+            (true, false) => unmarked.push(number),
+            (false, true) => leaked.push(number),
+            _ => {}
+        }
+    }
+    if !unmarked.is_empty() {
+        volume.error(format!(
+            "{} blocks in use are marked free, the first {}",
+            unmarked.len(),
+            unmarked[0]
+        ));
+    }
+    if !leaked.is_empty() {
+        volume.error(format!(
+            "{} blocks marked in use belong to nothing, the first {}",
+            leaked.len(),
+            leaked[0]
+        ));
+    }
+    volume.report.used_blocks = superblock.data_start + owners.len() as u64;
+    Ok(volume.report)
+}